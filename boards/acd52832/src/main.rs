@@ -462,6 +462,7 @@ unsafe fn start() -> (
         nrf52832::rtc::Rtc,
         nrf52832::ble_radio::Radio
     ));
+    ble_radio.set_connection_radio(&base_peripherals.ble_radio);
 
     //
     // Temperature
@@ -633,6 +634,14 @@ unsafe fn start() -> (
     nrf52832_peripherals.gpio_port[Pin::P0_31].make_output();
     nrf52832_peripherals.gpio_port[Pin::P0_31].clear();
 
+    // Lock the kernel flash until the next reset.
+    components::flash::KernelFlashProtectionComponent::new(
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    )
+    .finalize(())
+    .unwrap_or_else(|err| debug!("Unable to protect kernel flash: {:?}", err));
+
     debug!("Initialization complete. Entering main loop\r");
     debug!("{}", &*addr_of!(nrf52832::ficr::FICR_INSTANCE));
 
@@ -668,6 +677,13 @@ unsafe fn start() -> (
         debug!("{:?}", err);
     });
 
+    // Lock the flash of the loaded processes until the next reset.
+    kernel::process::protect_loaded_processes(
+        &*addr_of!(PROCESSES),
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     (board_kernel, platform, chip)
 }
 
@@ -708,6 +708,7 @@ unsafe fn start() -> (
         nrf52840::rtc::Rtc,
         nrf52840::ble_radio::Radio
     ));
+    ble_radio.set_connection_radio(&base_peripherals.ble_radio);
 
     let aes_mux = static_init!(
         MuxAES128CCM<'static, nrf52840::aes::AesECB>,
@@ -798,6 +799,14 @@ unsafe fn start() -> (
     cdc.enable();
     cdc.attach();
 
+    // Lock the kernel flash until the next reset.
+    components::flash::KernelFlashProtectionComponent::new(
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    )
+    .finalize(())
+    .unwrap_or_else(|err| debug!("Unable to protect kernel flash: {:?}", err));
+
     debug!("Initialization complete. Entering main loop.");
 
     //--------------------------------------------------------------------------
@@ -836,6 +845,13 @@ unsafe fn start() -> (
         debug!("{:?}", err);
     });
 
+    // Lock the flash of the loaded processes until the next reset.
+    kernel::process::protect_loaded_processes(
+        &*addr_of!(PROCESSES),
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     (board_kernel, platform, chip)
 }
 
@@ -8,6 +8,9 @@
 //! -----
 //! ```rust
 //! let ble_radio = BLEComponent::new(board_kernel, &nrf52::ble_radio::RADIO, mux_alarm).finalize();
+//! // Only on radios that implement `BleConnectionRadio`, for scan responses,
+//! // active scanning and extended advertising:
+//! ble_radio.set_connection_radio(&nrf52::ble_radio::RADIO);
 //! ```
 
 use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
@@ -0,0 +1,141 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Component for a Bluetooth Low Energy GATT server on a radio that supports
+//! connections.
+//!
+//! A radio can be used either by this component or by the advertising
+//! driver of `BLEComponent`, not by both.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let ble_gatt = components::ble_gatt::BleGattComponent::new(
+//!     board_kernel,
+//!     capsules_extra::ble_gatt_driver::DRIVER_NUM,
+//!     &nrf52840_peripherals.ble_radio,
+//!     mux_alarm,
+//!     device_address,
+//!     b"Tock",
+//! )
+//! .finalize(components::ble_gatt_component_static!(
+//!     nrf52840::rtc::Rtc,
+//!     nrf52840::ble_radio::Radio,
+//!     32
+//! ));
+//! ```
+
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::ble::att::{Attribute, AttributeDatabase};
+use capsules_extra::ble::link_layer::LinkLayer;
+use capsules_extra::ble_gatt_driver::BleGatt;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::deferred_call::DeferredCallClient;
+use kernel::hil::ble_connection::BleConnectionRadio;
+use kernel::hil::time::Alarm;
+
+#[macro_export]
+macro_rules! ble_gatt_component_static {
+    ($A:ty, $R:ty, $ATTRIBUTES:expr $(,)?) => {{
+        let alarm = kernel::static_buf!(
+            capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>
+        );
+        let link = kernel::static_buf!(
+            capsules_extra::ble::link_layer::LinkLayer<
+                'static,
+                $R,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        let attributes = kernel::static_buf!([capsules_extra::ble::att::Attribute; $ATTRIBUTES]);
+        let gatt = kernel::static_buf!(
+            capsules_extra::ble_gatt_driver::BleGatt<
+                'static,
+                $R,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        (alarm, link, attributes, gatt)
+    }};
+}
+
+pub struct BleGattComponent<
+    A: Alarm<'static> + 'static,
+    R: BleConnectionRadio<'static> + 'static,
+    const ATTRIBUTES: usize,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    radio: &'static R,
+    mux_alarm: &'static MuxAlarm<'static, A>,
+    address: [u8; 6],
+    device_name: &'static [u8],
+}
+
+impl<
+        A: Alarm<'static> + 'static,
+        R: BleConnectionRadio<'static> + 'static,
+        const ATTRIBUTES: usize,
+    > BleGattComponent<A, R, ATTRIBUTES>
+{
+    /// `address` is the random static device address, least significant
+    /// byte first.
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        radio: &'static R,
+        mux_alarm: &'static MuxAlarm<'static, A>,
+        address: [u8; 6],
+        device_name: &'static [u8],
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            radio,
+            mux_alarm,
+            address,
+            device_name,
+        }
+    }
+}
+
+impl<
+        A: Alarm<'static> + 'static,
+        R: BleConnectionRadio<'static> + 'static,
+        const ATTRIBUTES: usize,
+    > Component for BleGattComponent<A, R, ATTRIBUTES>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<LinkLayer<'static, R, VirtualMuxAlarm<'static, A>>>,
+        &'static mut MaybeUninit<[Attribute; ATTRIBUTES]>,
+        &'static mut MaybeUninit<BleGatt<'static, R, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static BleGatt<'static, R, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let alarm = s.0.write(VirtualMuxAlarm::new(self.mux_alarm));
+        alarm.setup();
+
+        let link = s.1.write(LinkLayer::new(self.radio, alarm, self.address));
+        link.register();
+        alarm.set_alarm_client(link);
+        self.radio.set_connection_client(link);
+
+        let attributes = s.2.write([Attribute::EMPTY; ATTRIBUTES]);
+        let gatt = s.3.write(BleGatt::new(
+            link,
+            AttributeDatabase::new(attributes, self.device_name),
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        link.set_client(gatt);
+
+        gatt
+    }
+}
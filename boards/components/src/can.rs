@@ -5,18 +5,29 @@
 //
 // Author: Teona Severin <teona.severin@oxidos.io>
 
-//! Component for CAN syscall interface.
+//! Components for the CAN virtualizer and the CAN syscall interface.
 //!
-//! This provides one Component, `CanComponent`, which implements a
-//! userspace syscall interface to the Can peripheral.
+//! This provides two Components:
+//!
+//! - `CanMuxComponent` shares a CAN peripheral between several users.
+//! - `CanComponent` implements a userspace syscall interface to the CAN
+//!   peripheral on top of a `CanMuxComponent`.
+//!
+//! Both macros take the maximum data length of a message as an optional
+//! second argument, which defaults to `STANDARD_CAN_PACKET_SIZE`. Use
+//! `FD_CAN_PACKET_SIZE` for a CAN FD peripheral.
 //!
 //! Usage
 //! -----
 //! ```rust
+//! let can_mux = components::can::CanMuxComponent::new(&peripherals.can1)
+//!     .finalize(components::can_mux_component_static!(
+//!         stm32f429zi::can::Can<'static>
+//!     ));
 //! let can = components::can::CanComponent::new(
 //!     board_kernel,
 //!     capsules_extra::can::DRIVER_NUM,
-//!     &peripherals.can1
+//!     can_mux,
 //! ).finalize(components::can_component_static!(
 //!     stm32f429zi::can::Can<'static>
 //! ));
@@ -24,67 +35,144 @@
 //!
 
 use capsules_extra::can::CanCapsule;
+use capsules_extra::virtual_can::{MuxCan, VirtualCan};
 use core::mem::MaybeUninit;
 use kernel::component::Component;
+use kernel::deferred_call::DeferredCallClient;
 use kernel::hil::can;
 use kernel::{capabilities, create_capability};
 
+#[macro_export]
+macro_rules! can_mux_component_static {
+    ($C:ty $(,)?) => {{
+        $crate::can_mux_component_static!($C, kernel::hil::can::STANDARD_CAN_PACKET_SIZE)
+    };};
+    ($C:ty, $N:expr $(,)?) => {{
+        use kernel::static_buf;
+
+        let CAN_RX_BUF = static_buf!([u8; $N]);
+        let mux_can = static_buf!(capsules_extra::virtual_can::MuxCan<'static, $C, { $N }>);
+        (mux_can, CAN_RX_BUF)
+    };};
+}
+
 #[macro_export]
 macro_rules! can_component_static {
     ($C:ty $(,)?) => {{
-        use capsules_extra::can::CanCapsule;
-        use core::mem::MaybeUninit;
-        use kernel::hil::can;
+        $crate::can_component_static!($C, kernel::hil::can::STANDARD_CAN_PACKET_SIZE)
+    };};
+    ($C:ty, $N:expr $(,)?) => {{
+        use capsules_extra::virtual_can::VirtualCan;
         use kernel::static_buf;
 
-        let CAN_TX_BUF = static_buf!([u8; can::STANDARD_CAN_PACKET_SIZE]);
-        let CAN_RX_BUF = static_buf!([u8; can::STANDARD_CAN_PACKET_SIZE]);
-        let can = static_buf!(capsules_extra::can::CanCapsule<'static, $C>);
-        (can, CAN_TX_BUF, CAN_RX_BUF)
+        let CAN_TX_BUF = static_buf!([u8; $N]);
+        let CAN_RX_BUF = static_buf!([u8; $N]);
+        let can_device = static_buf!(VirtualCan<'static, $C, { $N }>);
+        let can = static_buf!(
+            capsules_extra::can::CanCapsule<'static, VirtualCan<'static, $C, { $N }>, { $N }>
+        );
+        (can_device, can, CAN_TX_BUF, CAN_RX_BUF)
     };};
 }
 
-pub struct CanComponent<A: 'static + can::Can> {
+pub struct CanMuxComponent<
+    A: 'static + can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+    const N: usize,
+> {
+    can: &'static A,
+}
+
+impl<
+        A: 'static + can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+        const N: usize,
+    > CanMuxComponent<A, N>
+{
+    pub fn new(can: &'static A) -> Self {
+        CanMuxComponent { can }
+    }
+}
+
+impl<
+        A: 'static + can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+        const N: usize,
+    > Component for CanMuxComponent<A, N>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<MuxCan<'static, A, N>>,
+        &'static mut MaybeUninit<[u8; N]>,
+    );
+    type Output = &'static MuxCan<'static, A, N>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let mux_can = static_buffer
+            .0
+            .write(MuxCan::new(self.can, static_buffer.1.write([0; N])));
+        mux_can.register();
+
+        can::Controller::set_client(self.can, Some(mux_can));
+        can::Transmit::set_client(self.can, Some(mux_can));
+        can::Receive::set_client(self.can, Some(mux_can));
+
+        mux_can
+    }
+}
+
+pub struct CanComponent<
+    A: 'static + can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+    const N: usize,
+> {
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
-    can: &'static A,
+    mux_can: &'static MuxCan<'static, A, N>,
 }
 
-impl<A: 'static + can::Can> CanComponent<A> {
+impl<
+        A: 'static + can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+        const N: usize,
+    > CanComponent<A, N>
+{
     pub fn new(
         board_kernel: &'static kernel::Kernel,
         driver_num: usize,
-        can: &'static A,
-    ) -> CanComponent<A> {
+        mux_can: &'static MuxCan<'static, A, N>,
+    ) -> CanComponent<A, N> {
         CanComponent {
             board_kernel,
             driver_num,
-            can,
+            mux_can,
         }
     }
 }
 
-impl<A: 'static + can::Can> Component for CanComponent<A> {
+impl<
+        A: 'static + can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+        const N: usize,
+    > Component for CanComponent<A, N>
+{
     type StaticInput = (
-        &'static mut MaybeUninit<CanCapsule<'static, A>>,
-        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
-        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
+        &'static mut MaybeUninit<VirtualCan<'static, A, N>>,
+        &'static mut MaybeUninit<CanCapsule<'static, VirtualCan<'static, A, N>, N>>,
+        &'static mut MaybeUninit<[u8; N]>,
+        &'static mut MaybeUninit<[u8; N]>,
     );
-    type Output = &'static CanCapsule<'static, A>;
+    type Output = &'static CanCapsule<'static, VirtualCan<'static, A, N>, N>;
 
     fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
         let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
         let grant_can = self.board_kernel.create_grant(self.driver_num, &grant_cap);
 
-        let can = static_buffer.0.write(capsules_extra::can::CanCapsule::new(
-            self.can,
+        let can_device = static_buffer.0.write(VirtualCan::new(self.mux_can));
+        can_device.setup();
+
+        let can = static_buffer.1.write(CanCapsule::new(
+            can_device,
             grant_can,
-            static_buffer.1.write([0; can::STANDARD_CAN_PACKET_SIZE]),
-            static_buffer.2.write([0; can::STANDARD_CAN_PACKET_SIZE]),
+            static_buffer.2.write([0; N]),
+            static_buffer.3.write([0; N]),
         ));
-        can::Controller::set_client(self.can, Some(can));
-        can::Transmit::set_client(self.can, Some(can));
-        can::Receive::set_client(self.can, Some(can));
+        can::Controller::set_client(can_device, Some(can));
+        can::Transmit::set_client(can_device, Some(can));
+        can::Receive::set_client(can_device, Some(can));
 
         can
     }
@@ -23,6 +23,21 @@
 //!     STRINGS)
 //! .finalize(components::cdc_acm_component_static!(nrf52::usbd::Usbd));
 //! ```
+//!
+//! As one function of a composite USB device (see `usb_composite`), using
+//! interfaces 0 and 1 and endpoints 2 (IN), 3 (OUT) and 4 (notifications):
+//!
+//! ```rust
+//! let cdc_acm = components::cdc::CdcAcmFunctionComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     0,
+//!     2,
+//!     3,
+//!     4,
+//!     mux_alarm,
+//!     None)
+//! .finalize(components::cdc_acm_component_static!(nrf52::usbd::Usbd, nrf52::rtc::Rtc));
+//! ```
 
 use core::mem::MaybeUninit;
 
@@ -119,3 +134,74 @@ impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
         cdc
     }
 }
+
+/// Component for a CDC-ACM function of a composite USB device. Uses the same
+/// static input as `CdcAcmComponent`.
+pub struct CdcAcmFunctionComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    A: 'static + Alarm<'static>,
+> {
+    usb: &'static U,
+    interface: u8,
+    endpoint_in: usize,
+    endpoint_out: usize,
+    endpoint_notify: usize,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+    host_initiated_function: Option<&'static (dyn Fn() + 'static)>,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>>
+    CdcAcmFunctionComponent<U, A>
+{
+    pub fn new(
+        usb: &'static U,
+        interface: u8,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        endpoint_notify: usize,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+        host_initiated_function: Option<&'static (dyn Fn() + 'static)>,
+    ) -> Self {
+        Self {
+            usb,
+            interface,
+            endpoint_in,
+            endpoint_out,
+            endpoint_notify,
+            alarm_mux,
+            host_initiated_function,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, A: 'static + Alarm<'static>> Component
+    for CdcAcmFunctionComponent<U, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<
+            capsules_extra::usb::cdc::CdcAcm<'static, U, VirtualMuxAlarm<'static, A>>,
+        >,
+    );
+    type Output =
+        &'static capsules_extra::usb::cdc::CdcAcm<'static, U, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let cdc_alarm = s.0.write(VirtualMuxAlarm::new(self.alarm_mux));
+        cdc_alarm.setup();
+
+        let cdc = s.1.write(capsules_extra::usb::cdc::CdcAcm::new_function(
+            self.usb,
+            self.interface,
+            self.endpoint_in,
+            self.endpoint_out,
+            self.endpoint_notify,
+            cdc_alarm,
+            self.host_initiated_function,
+        ));
+        kernel::deferred_call::DeferredCallClient::register(cdc);
+        cdc_alarm.set_alarm_client(cdc);
+
+        cdc
+    }
+}
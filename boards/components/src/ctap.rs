@@ -31,11 +31,20 @@
 //!     ctap.attach();
 //! ```
 
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::ctap_attestation::{CtapAttestation, HASH_LEN, SIGNATURE_LEN};
+use capsules_extra::ctap_driver::CtapDriver;
+use capsules_extra::usb::ctap::CtapHid;
+use capsules_extra::usb::ctaphid::{CtapHidTransport, CtapTransport};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil;
+use kernel::hil::public_key_crypto::keys::PubKey;
+use kernel::hil::public_key_crypto::signature::SignatureSign;
+use kernel::hil::time::Alarm;
+use kernel::process::ShortId;
 
 // Setup static space for the objects.
 #[macro_export]
@@ -130,3 +139,304 @@ impl<U: 'static + hil::usb::UsbController<'static>> Component for CtapComponent<
         (ctap, ctap_driver)
     }
 }
+
+/// Component for a CTAP HID function of a composite USB device. Uses the same
+/// static input as `CtapComponent`.
+pub struct CtapFunctionComponent<U: 'static + hil::usb::UsbController<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    usb: &'static U,
+    interface: u8,
+    endpoint: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> CtapFunctionComponent<U> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        usb: &'static U,
+        interface: u8,
+        endpoint: usize,
+    ) -> CtapFunctionComponent<U> {
+        CtapFunctionComponent {
+            board_kernel,
+            driver_num,
+            usb,
+            interface,
+            endpoint,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for CtapFunctionComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<capsules_extra::usb::ctap::CtapHid<'static, U>>,
+        &'static mut MaybeUninit<
+            capsules_extra::usb_hid_driver::UsbHidDriver<
+                'static,
+                capsules_extra::usb::ctap::CtapHid<'static, U>,
+            >,
+        >,
+        &'static mut MaybeUninit<[u8; 64]>,
+        &'static mut MaybeUninit<[u8; 64]>,
+    );
+    type Output = (
+        &'static capsules_extra::usb::ctap::CtapHid<'static, U>,
+        &'static capsules_extra::usb_hid_driver::UsbHidDriver<
+            'static,
+            capsules_extra::usb::ctap::CtapHid<'static, U>,
+        >,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let ctap = s.0.write(capsules_extra::usb::ctap::CtapHid::new_function(
+            self.usb,
+            self.interface,
+            self.endpoint,
+        ));
+
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let send_buffer = s.2.write([0; 64]);
+        let recv_buffer = s.3.write([0; 64]);
+
+        let ctap_driver = s.1.write(capsules_extra::usb_hid_driver::UsbHidDriver::new(
+            ctap,
+            send_buffer,
+            recv_buffer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+
+        ctap.set_client(ctap_driver);
+
+        (ctap, ctap_driver)
+    }
+}
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ctap_transport_component_static {
+    ($U:ty, $A:ty, $MESSAGE_LEN:expr $(,)?) => {{
+        let alarm = kernel::static_buf!(
+            capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>
+        );
+        let transport = kernel::static_buf!(
+            capsules_extra::usb::ctaphid::CtapHidTransport<
+                'static,
+                capsules_extra::usb::ctap::CtapHid<'static, $U>,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        let driver = kernel::static_buf!(
+            capsules_extra::ctap_driver::CtapDriver<
+                'static,
+                capsules_extra::usb::ctaphid::CtapHidTransport<
+                    'static,
+                    capsules_extra::usb::ctap::CtapHid<'static, $U>,
+                    capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
+                >,
+            >
+        );
+        let send_buffer = kernel::static_buf!([u8; 64]);
+        let recv_buffer = kernel::static_buf!([u8; 64]);
+        let message = kernel::static_buf!([u8; $MESSAGE_LEN]);
+        let response = kernel::static_buf!([u8; $MESSAGE_LEN]);
+
+        (
+            alarm,
+            transport,
+            driver,
+            send_buffer,
+            recv_buffer,
+            message,
+            response,
+        )
+    };};
+}
+
+pub type CtapTransportComponentType<U, A> =
+    CtapHidTransport<'static, CtapHid<'static, U>, VirtualMuxAlarm<'static, A>>;
+
+/// Component for the CTAPHID transport on top of a `CtapHid` device, and the
+/// userspace driver that passes whole CTAP2 messages to an app.
+///
+/// `MESSAGE_LEN` is the longest request and response the board supports, at
+/// most `capsules_extra::usb::ctaphid::MAX_MESSAGE_LEN`. The board starts
+/// the transport with `enable()` once the USB device is enabled.
+///
+/// ```rust
+/// let (ctap_transport, ctap_driver) = components::ctap::CtapTransportComponent::new(
+///     board_kernel,
+///     capsules_extra::ctap_driver::DRIVER_NUM,
+///     ctap,
+///     mux_alarm,
+/// )
+/// .finalize(components::ctap_transport_component_static!(
+///     nrf52::usbd::Usbd<'static>,
+///     nrf52::rtc::Rtc,
+///     1024,
+/// ));
+/// ```
+pub struct CtapTransportComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    A: 'static + Alarm<'static>,
+    const MESSAGE_LEN: usize,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    hid: &'static CtapHid<'static, U>,
+    alarm_mux: &'static MuxAlarm<'static, A>,
+}
+
+impl<
+        U: 'static + hil::usb::UsbController<'static>,
+        A: 'static + Alarm<'static>,
+        const MESSAGE_LEN: usize,
+    > CtapTransportComponent<U, A, MESSAGE_LEN>
+{
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        hid: &'static CtapHid<'static, U>,
+        alarm_mux: &'static MuxAlarm<'static, A>,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            hid,
+            alarm_mux,
+        }
+    }
+}
+
+impl<U, A, const MESSAGE_LEN: usize> Component for CtapTransportComponent<U, A, MESSAGE_LEN>
+where
+    U: 'static + hil::usb::UsbController<'static>,
+    A: 'static + Alarm<'static>,
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<CtapTransportComponentType<U, A>>,
+        &'static mut MaybeUninit<CtapDriver<'static, CtapTransportComponentType<U, A>>>,
+        &'static mut MaybeUninit<[u8; 64]>,
+        &'static mut MaybeUninit<[u8; 64]>,
+        &'static mut MaybeUninit<[u8; MESSAGE_LEN]>,
+        &'static mut MaybeUninit<[u8; MESSAGE_LEN]>,
+    );
+    type Output = (
+        &'static CtapTransportComponentType<U, A>,
+        &'static CtapDriver<'static, CtapTransportComponentType<U, A>>,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let alarm = s.0.write(VirtualMuxAlarm::new(self.alarm_mux));
+        alarm.setup();
+
+        let transport = s.1.write(CtapHidTransport::new(
+            self.hid,
+            alarm,
+            s.3.write([0; 64]),
+            s.4.write([0; 64]),
+            s.5.write([0; MESSAGE_LEN]),
+        ));
+        alarm.set_alarm_client(transport);
+        self.hid.set_client(transport);
+
+        let driver = s.2.write(CtapDriver::new(
+            transport,
+            s.6.write([0; MESSAGE_LEN]),
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        transport.set_client(driver);
+
+        (transport, driver)
+    }
+}
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! ctap_attestation_component_static {
+    ($K:ty $(,)?) => {{
+        let attestation =
+            kernel::static_buf!(capsules_extra::ctap_attestation::CtapAttestation<'static, $K>);
+        let hash = kernel::static_buf!([u8; capsules_extra::ctap_attestation::HASH_LEN]);
+        let signature = kernel::static_buf!([u8; capsules_extra::ctap_attestation::SIGNATURE_LEN]);
+
+        (attestation, hash, signature)
+    };};
+}
+
+/// Component for the CTAP attestation key driver.
+///
+/// `signers` lists the apps that may sign with the attestation key. The
+/// public key of `key` must be available before apps use the driver.
+///
+/// ```rust
+/// static SIGNERS: [kernel::process::ShortId; 1] =
+///     [kernel::process::ShortId::Fixed(core::num::NonZeroU32::new(0x1234).unwrap())];
+///
+/// atecc508a.read_public_key(0, public_key_buffer);
+/// let ctap_attestation = components::ctap::CtapAttestationComponent::new(
+///     board_kernel,
+///     capsules_extra::ctap_attestation::DRIVER_NUM,
+///     atecc508a,
+///     &SIGNERS,
+/// )
+/// .finalize(components::ctap_attestation_component_static!(
+///     capsules_extra::atecc508a::Atecc508a<'static>
+/// ));
+/// ```
+pub struct CtapAttestationComponent<
+    K: 'static + PubKey + SignatureSign<'static, HASH_LEN, SIGNATURE_LEN>,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    key: &'static K,
+    signers: &'static [ShortId],
+}
+
+impl<K: 'static + PubKey + SignatureSign<'static, HASH_LEN, SIGNATURE_LEN>>
+    CtapAttestationComponent<K>
+{
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        key: &'static K,
+        signers: &'static [ShortId],
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            key,
+            signers,
+        }
+    }
+}
+
+impl<K: 'static + PubKey + SignatureSign<'static, HASH_LEN, SIGNATURE_LEN>> Component
+    for CtapAttestationComponent<K>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<CtapAttestation<'static, K>>,
+        &'static mut MaybeUninit<[u8; HASH_LEN]>,
+        &'static mut MaybeUninit<[u8; SIGNATURE_LEN]>,
+    );
+    type Output = &'static CtapAttestation<'static, K>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let attestation = s.0.write(CtapAttestation::new(
+            self.key,
+            self.signers,
+            s.1.write([0; HASH_LEN]),
+            s.2.write([0; SIGNATURE_LEN]),
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        self.key.set_sign_client(attestation);
+
+        attestation
+    }
+}
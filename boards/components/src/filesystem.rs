@@ -0,0 +1,310 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Components for FAT file systems on block storage, file systems on flash and
+//! userspace access to files.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let fat = components::filesystem::FatComponent::new(sdcard).finalize(
+//!     components::fat_component_static!(capsules_extra::sdcard::SDCard<'static, ...>),
+//! );
+//! let filesystem_driver = components::filesystem::FileSystemDriverComponent::new(
+//!     fat,
+//!     board_kernel,
+//!     capsules_extra::filesystem_driver::DRIVER_NUM,
+//! )
+//! .finalize(components::filesystem_driver_component_static!(
+//!     capsules_extra::fat::Fat<'static, capsules_extra::sdcard::SDCard<'static, ...>>,
+//!     512
+//! ));
+//! ```
+//!
+//! A file system on 64 pages of flash, starting at page 192:
+//!
+//! ```rust
+//! let flash_fs = components::filesystem::FlashFsComponent::new(flash_ctrl, 192, 64)
+//!     .finalize(components::flash_fs_component_static!(nrf52840::nvmc::Nvmc));
+//! ```
+//!
+//! A block device with 512 byte blocks on the same pages, for example to
+//! export over USB mass storage:
+//!
+//! ```rust
+//! let storage = components::filesystem::FlashBlockStorageComponent::new(flash_ctrl, 192, 64, 512)
+//!     .finalize(components::flash_block_storage_component_static!(nrf52840::nvmc::Nvmc));
+//! ```
+
+use capsules_extra::fat::{Fat, SECTOR_SIZE};
+use capsules_extra::filesystem_driver::FileSystemDriver;
+use capsules_extra::flash_block_storage::FlashBlockStorage;
+use capsules_extra::flash_fs::FlashFs;
+use capsules_extra::ram_disk::RamDisk;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::deferred_call::DeferredCallClient;
+use kernel::hil::block_storage::BlockStorage;
+use kernel::hil::filesystem::FileSystem;
+use kernel::hil::flash::{self, Flash};
+
+//////////////
+// FAT
+//////////////
+
+#[macro_export]
+macro_rules! fat_component_static {
+    ($B:ty $(,)?) => {{
+        let fat = kernel::static_buf!(capsules_extra::fat::Fat<'static, $B>);
+        let buffer = kernel::static_buf!([u8; capsules_extra::fat::SECTOR_SIZE]);
+
+        (fat, buffer)
+    };};
+}
+
+pub type FatComponentType<B> = capsules_extra::fat::Fat<'static, B>;
+
+pub struct FatComponent<B: BlockStorage<'static> + 'static> {
+    block: &'static B,
+}
+
+impl<B: BlockStorage<'static>> FatComponent<B> {
+    pub fn new(block: &'static B) -> Self {
+        Self { block }
+    }
+}
+
+impl<B: BlockStorage<'static>> Component for FatComponent<B> {
+    type StaticInput = (
+        &'static mut MaybeUninit<Fat<'static, B>>,
+        &'static mut MaybeUninit<[u8; SECTOR_SIZE]>,
+    );
+    type Output = &'static Fat<'static, B>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let buffer = static_buffer.1.write([0; SECTOR_SIZE]);
+        let fat = static_buffer.0.write(Fat::new(self.block, buffer));
+        self.block.set_client(fat);
+        fat.register();
+        fat
+    }
+}
+
+//////////////
+// Flash File System
+//////////////
+
+#[macro_export]
+macro_rules! flash_fs_component_static {
+    ($F:ty $(,)?) => {{
+        let flash_fs = kernel::static_buf!(capsules_extra::flash_fs::FlashFs<'static, $F>);
+        let page = kernel::static_buf!(<$F as kernel::hil::flash::Flash>::Page);
+
+        (flash_fs, page)
+    };};
+}
+
+pub type FlashFsComponentType<F> = capsules_extra::flash_fs::FlashFs<'static, F>;
+
+pub struct FlashFsComponent<F: Flash + flash::HasClient<'static, FlashFs<'static, F>> + 'static> {
+    flash: &'static F,
+    first_page: usize,
+    pages: usize,
+}
+
+impl<F: Flash + flash::HasClient<'static, FlashFs<'static, F>>> FlashFsComponent<F> {
+    pub fn new(flash: &'static F, first_page: usize, pages: usize) -> Self {
+        Self {
+            flash,
+            first_page,
+            pages,
+        }
+    }
+}
+
+impl<F: Flash + flash::HasClient<'static, FlashFs<'static, F>>> Component for FlashFsComponent<F> {
+    type StaticInput = (
+        &'static mut MaybeUninit<FlashFs<'static, F>>,
+        &'static mut MaybeUninit<<F as Flash>::Page>,
+    );
+    type Output = &'static FlashFs<'static, F>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let page = static_buffer.1.write(<F as Flash>::Page::default());
+        let flash_fs =
+            static_buffer
+                .0
+                .write(FlashFs::new(self.flash, page, self.first_page, self.pages));
+        self.flash.set_client(flash_fs);
+        flash_fs
+    }
+}
+
+/////////////////////////////
+// File System Userspace Driver
+/////////////////////////////
+
+// The buffer limits how much is read or written at once, larger transfers
+// are split.
+#[macro_export]
+macro_rules! filesystem_driver_component_static {
+    ($FS:ty, $BUF_LEN:expr $(,)?) => {{
+        let driver =
+            kernel::static_buf!(capsules_extra::filesystem_driver::FileSystemDriver<'static, $FS>);
+        let buffer = kernel::static_buf!([u8; $BUF_LEN]);
+
+        (driver, buffer)
+    };};
+}
+
+pub type FileSystemDriverComponentType<FS> =
+    capsules_extra::filesystem_driver::FileSystemDriver<'static, FS>;
+
+pub struct FileSystemDriverComponent<FS: FileSystem<'static> + 'static, const BUF_LEN: usize> {
+    fs: &'static FS,
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+}
+
+impl<FS: FileSystem<'static>, const BUF_LEN: usize> FileSystemDriverComponent<FS, BUF_LEN> {
+    pub fn new(fs: &'static FS, board_kernel: &'static kernel::Kernel, driver_num: usize) -> Self {
+        Self {
+            fs,
+            board_kernel,
+            driver_num,
+        }
+    }
+}
+
+impl<FS: FileSystem<'static>, const BUF_LEN: usize> Component
+    for FileSystemDriverComponent<FS, BUF_LEN>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<FileSystemDriver<'static, FS>>,
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+    );
+    type Output = &'static FileSystemDriver<'static, FS>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let buffer = static_buffer.1.write([0; BUF_LEN]);
+        let driver = static_buffer.0.write(FileSystemDriver::new(
+            self.fs,
+            buffer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        self.fs.set_client(driver);
+        driver
+    }
+}
+
+//////////////
+// RAM Disk
+//////////////
+
+#[macro_export]
+macro_rules! ram_disk_component_static {
+    ($SIZE:expr $(,)?) => {{
+        let ram_disk = kernel::static_buf!(capsules_extra::ram_disk::RamDisk<'static>);
+        let storage = kernel::static_buf!([u8; $SIZE]);
+
+        (ram_disk, storage)
+    };};
+}
+
+pub type RamDiskComponentType = capsules_extra::ram_disk::RamDisk<'static>;
+
+pub struct RamDiskComponent<const SIZE: usize> {
+    block_size: usize,
+}
+
+impl<const SIZE: usize> RamDiskComponent<SIZE> {
+    pub fn new(block_size: usize) -> Self {
+        Self { block_size }
+    }
+}
+
+impl<const SIZE: usize> Component for RamDiskComponent<SIZE> {
+    type StaticInput = (
+        &'static mut MaybeUninit<RamDisk<'static>>,
+        &'static mut MaybeUninit<[u8; SIZE]>,
+    );
+    type Output = &'static RamDisk<'static>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let storage = static_buffer.1.write([0; SIZE]);
+        let ram_disk = static_buffer
+            .0
+            .write(RamDisk::new(storage, self.block_size));
+        ram_disk.register();
+        ram_disk
+    }
+}
+
+//////////////
+// Flash Block Storage
+//////////////
+
+#[macro_export]
+macro_rules! flash_block_storage_component_static {
+    ($F:ty $(,)?) => {{
+        let storage = kernel::static_buf!(
+            capsules_extra::flash_block_storage::FlashBlockStorage<'static, $F>
+        );
+        let page = kernel::static_buf!(<$F as kernel::hil::flash::Flash>::Page);
+
+        (storage, page)
+    };};
+}
+
+pub type FlashBlockStorageComponentType<F> =
+    capsules_extra::flash_block_storage::FlashBlockStorage<'static, F>;
+
+pub struct FlashBlockStorageComponent<
+    F: Flash + flash::HasClient<'static, FlashBlockStorage<'static, F>> + 'static,
+> {
+    flash: &'static F,
+    first_page: usize,
+    pages: usize,
+    block_size: usize,
+}
+
+impl<F: Flash + flash::HasClient<'static, FlashBlockStorage<'static, F>>>
+    FlashBlockStorageComponent<F>
+{
+    pub fn new(flash: &'static F, first_page: usize, pages: usize, block_size: usize) -> Self {
+        Self {
+            flash,
+            first_page,
+            pages,
+            block_size,
+        }
+    }
+}
+
+impl<F: Flash + flash::HasClient<'static, FlashBlockStorage<'static, F>>> Component
+    for FlashBlockStorageComponent<F>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<FlashBlockStorage<'static, F>>,
+        &'static mut MaybeUninit<<F as Flash>::Page>,
+    );
+    type Output = &'static FlashBlockStorage<'static, F>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let page = static_buffer.1.write(<F as Flash>::Page::default());
+        let storage = static_buffer.0.write(FlashBlockStorage::new(
+            self.flash,
+            page,
+            self.first_page,
+            self.pages,
+            self.block_size,
+        ));
+        self.flash.set_client(storage);
+        storage
+    }
+}
@@ -4,7 +4,8 @@
 
 //! Component for Flash
 //!
-//! Provides `FlashMux` and `FlashUser` (virtual flash).
+//! Provides `FlashMux` and `FlashUser` (virtual flash), and
+//! `KernelFlashProtectionComponent` to lock the flash of the kernel.
 //!
 //! Usage
 //! -----
@@ -16,13 +17,21 @@
 //!    let virtual_app_flash = components::flash::FlashUserComponent::new(mux_flash).finalize(
 //!       components::flash_user_component_static!(nrf52833::nvmc::Nvmc),
 //!    );
+//!
+//!    components::flash::KernelFlashProtectionComponent::new(
+//!        &base_peripherals.nvmc,
+//!        kernel::hil::flash::ProtectionLifetime::UntilReset,
+//!    )
+//!    .finalize(())
+//!    .unwrap();
 //! ```
 
 use capsules_core::virtualizers::virtual_flash::FlashUser;
 use capsules_core::virtualizers::virtual_flash::MuxFlash;
 use core::mem::MaybeUninit;
 use kernel::component::Component;
-use kernel::hil::flash::{Flash, HasClient};
+use kernel::hil::flash::{Flash, FlashProtection, HasClient, ProtectionLifetime};
+use kernel::ErrorCode;
 
 // Setup static space for the objects.
 #[macro_export]
@@ -83,3 +92,83 @@ impl<F: 'static + Flash + HasClient<'static, MuxFlash<'static, F>>> Component
         s.write(FlashUser::new(self.mux_flash))
     }
 }
+
+// These symbols are defined in the linker script.
+extern "C" {
+    /// Beginning of the kernel code.
+    static _stext: u8;
+    /// Beginning of the kernel non-volatile storage.
+    static _sstorage: u8;
+    /// End of the kernel non-volatile storage.
+    static _estorage: u8;
+    /// End of the kernel attributes, the last part of the kernel image in
+    /// flash.
+    static _eattributes: u8;
+}
+
+/// Locks the kernel image in flash.
+///
+/// This covers the code, read-only data, initial values of the data and the
+/// kernel attributes. The non-volatile storage of the kernel (`.storage`) is
+/// not locked, so it can still be used by capsules.
+///
+/// The locked range is extended to whole protection blocks of the chip (for
+/// example the 4 kB pages of the nRF52), and only the blocks fully inside of
+/// the storage stay unlocked. Boards with kernel storage should align it to
+/// the protection blocks by setting `PAGE_SIZE` in their linker script.
+///
+/// The nRF52 boards lock the kernel until reset. The STM32F4 boards do not use
+/// this component, because their flash can only be locked in the option bytes,
+/// which keep the lock across resets until they are reprogrammed.
+///
+/// Returns an error if the flash could not be locked.
+pub struct KernelFlashProtectionComponent<P: 'static + FlashProtection> {
+    protection: &'static P,
+    lifetime: ProtectionLifetime,
+}
+
+impl<P: 'static + FlashProtection> KernelFlashProtectionComponent<P> {
+    pub fn new(protection: &'static P, lifetime: ProtectionLifetime) -> Self {
+        Self {
+            protection,
+            lifetime,
+        }
+    }
+
+    /// Start of the protection block containing `address`.
+    fn block_start(&self, address: usize) -> usize {
+        self.protection
+            .protection_block(address)
+            .map_or(address, |block| block.start)
+    }
+
+    /// `address` rounded up to the end of a protection block.
+    fn block_end(&self, address: usize) -> usize {
+        self.protection
+            .protection_block(address - 1)
+            .map_or(address, |block| block.end)
+    }
+}
+
+impl<P: 'static + FlashProtection> Component for KernelFlashProtectionComponent<P> {
+    type StaticInput = ();
+    type Output = Result<(), ErrorCode>;
+
+    fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let kernel_start = self.block_start(core::ptr::addr_of!(_stext) as usize);
+        let kernel_end = self.block_end(core::ptr::addr_of!(_eattributes) as usize);
+        let storage_start = self.block_end(core::ptr::addr_of!(_sstorage) as usize);
+        let storage_end = self.block_start(core::ptr::addr_of!(_estorage) as usize);
+
+        let locked = if storage_start < storage_end {
+            [kernel_start..storage_start, storage_end..kernel_end]
+        } else {
+            [kernel_start..kernel_end, kernel_end..kernel_end]
+        };
+        for range in locked.into_iter().filter(|range| !range.is_empty()) {
+            self.protection
+                .protect(range.start, range.len(), self.lifetime)?;
+        }
+        Ok(())
+    }
+}
@@ -138,3 +138,78 @@ impl<U: 'static + hil::usb::UsbController<'static>> Component for KeyboardHidCom
         (keyboard_hid, usb_hid_driver)
     }
 }
+
+/// Component for a keyboard HID function of a composite USB device. Uses the same
+/// static input as `KeyboardHidComponent`.
+pub struct KeyboardHidFunctionComponent<U: 'static + hil::usb::UsbController<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    usb: &'static U,
+    interface: u8,
+    endpoint: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> KeyboardHidFunctionComponent<U> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        usb: &'static U,
+        interface: u8,
+        endpoint: usize,
+    ) -> KeyboardHidFunctionComponent<U> {
+        KeyboardHidFunctionComponent {
+            board_kernel,
+            driver_num,
+            usb,
+            interface,
+            endpoint,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for KeyboardHidFunctionComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<capsules_extra::usb::keyboard_hid::KeyboardHid<'static, U>>,
+        &'static mut MaybeUninit<
+            capsules_extra::usb_hid_driver::UsbHidDriver<
+                'static,
+                capsules_extra::usb::keyboard_hid::KeyboardHid<'static, U>,
+            >,
+        >,
+        &'static mut MaybeUninit<[u8; 64]>,
+        &'static mut MaybeUninit<[u8; 64]>,
+    );
+    type Output = (
+        &'static capsules_extra::usb::keyboard_hid::KeyboardHid<'static, U>,
+        &'static capsules_extra::usb_hid_driver::UsbHidDriver<
+            'static,
+            capsules_extra::usb::keyboard_hid::KeyboardHid<'static, U>,
+        >,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let keyboard_hid = s.0.write(
+            capsules_extra::usb::keyboard_hid::KeyboardHid::new_function(
+                self.usb,
+                self.interface,
+                self.endpoint,
+            ),
+        );
+
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let send_buffer = s.2.write([0; 64]);
+        let recv_buffer = s.3.write([0; 64]);
+
+        let usb_hid_driver = s.1.write(capsules_extra::usb_hid_driver::UsbHidDriver::new(
+            keyboard_hid,
+            send_buffer,
+            recv_buffer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+
+        keyboard_hid.set_client(usb_hid_driver);
+
+        (keyboard_hid, usb_hid_driver)
+    }
+}
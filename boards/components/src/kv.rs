@@ -5,6 +5,7 @@
 //! Components for KV stack capsules.
 
 use capsules_extra::kv_driver::KVStoreDriver;
+use capsules_extra::kv_store_encrypted::{DeviceKey, KVStoreEncrypted};
 use capsules_extra::kv_store_permissions::KVStorePermissions;
 use capsules_extra::tickv::{KVSystem, KeyType};
 use capsules_extra::tickv_kv_store::TicKVKVStore;
@@ -14,6 +15,7 @@ use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil;
+use kernel::hil::symmetric_encryption::AES128CCM;
 
 ///////////////////////
 // KV Userspace Driver
@@ -199,6 +201,83 @@ impl<V: hil::kv::KV<'static> + 'static> Component for KVStorePermissionsComponen
     }
 }
 
+/////////////////////
+// Encrypted KV Store
+/////////////////////
+
+#[macro_export]
+macro_rules! kv_store_encrypted_component_static {
+    ($V:ty, $A:ty $(,)?) => {{
+        let buffer = kernel::static_buf!([u8; capsules_extra::kv_store_encrypted::BUF_LEN]);
+        let kv_store = kernel::static_buf!(
+            capsules_extra::kv_store_encrypted::KVStoreEncrypted<'static, $V, $A>
+        );
+
+        (kv_store, buffer)
+    };};
+}
+
+pub type KVStoreEncryptedComponentType<V, A> =
+    capsules_extra::kv_store_encrypted::KVStoreEncrypted<'static, V, A>;
+
+pub struct KVStoreEncryptedComponent<
+    V: hil::kv::KV<'static> + 'static,
+    A: AES128CCM<'static> + 'static,
+> {
+    kv: &'static V,
+    aes: &'static A,
+    rng: &'static dyn hil::rng::Rng<'static>,
+    device_key: &'static dyn DeviceKey,
+}
+
+impl<V: hil::kv::KV<'static> + 'static, A: AES128CCM<'static> + 'static>
+    KVStoreEncryptedComponent<V, A>
+{
+    pub fn new(
+        kv: &'static V,
+        aes: &'static A,
+        rng: &'static dyn hil::rng::Rng<'static>,
+        device_key: &'static dyn DeviceKey,
+    ) -> Self {
+        Self {
+            kv,
+            aes,
+            rng,
+            device_key,
+        }
+    }
+}
+
+impl<V: hil::kv::KV<'static> + 'static, A: AES128CCM<'static> + 'static> Component
+    for KVStoreEncryptedComponent<V, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<KVStoreEncrypted<'static, V, A>>,
+        &'static mut MaybeUninit<[u8; capsules_extra::kv_store_encrypted::BUF_LEN]>,
+    );
+    type Output = &'static KVStoreEncrypted<'static, V, A>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let buffer = static_buffer
+            .1
+            .write([0; capsules_extra::kv_store_encrypted::BUF_LEN]);
+
+        let kv_store_encrypted = static_buffer.0.write(KVStoreEncrypted::new(
+            self.kv,
+            self.aes,
+            self.rng,
+            self.device_key,
+            buffer,
+        ));
+
+        self.kv.set_client(kv_store_encrypted);
+        self.aes.set_client(kv_store_encrypted);
+        self.rng.set_client(kv_store_encrypted);
+
+        kv_store_encrypted
+    }
+}
+
 /////////////////////
 // TicKV KV Store
 /////////////////////
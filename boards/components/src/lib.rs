@@ -15,6 +15,7 @@ pub mod app_flash_driver;
 pub mod appid;
 pub mod atecc508a;
 pub mod ble;
+pub mod ble_gatt;
 pub mod bme280;
 pub mod bmm150;
 pub mod bmp280;
@@ -24,6 +25,7 @@ pub mod can;
 pub mod ccs811;
 pub mod cdc;
 pub mod chirp_i2c_moisture;
+pub mod coap;
 pub mod console;
 pub mod crc;
 pub mod ctap;
@@ -32,6 +34,7 @@ pub mod date_time;
 pub mod debug_writer;
 pub mod dfrobot_rainfall_sensor;
 pub mod eui64;
+pub mod filesystem;
 pub mod flash;
 pub mod fm25cl;
 pub mod ft6x06;
@@ -52,6 +55,7 @@ pub mod led;
 pub mod led_matrix;
 pub mod lldb;
 pub mod loader;
+pub mod log;
 pub mod lpm013m126;
 pub mod lps22hb;
 pub mod lps25hb;
@@ -99,4 +103,10 @@ pub mod tickv;
 pub mod touch;
 pub mod udp_driver;
 pub mod udp_mux;
+pub mod uds;
 pub mod usb;
+pub mod usb_cdc_ecm;
+pub mod usb_composite;
+pub mod usb_dfu;
+pub mod usb_generic_hid;
+pub mod usb_msc;
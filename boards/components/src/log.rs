@@ -0,0 +1,210 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Components for sharing a log between logical logs and userspace.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let mux_log = components::log::MuxLogComponent::new(log).finalize(
+//!     components::mux_log_component_static!(capsules_extra::log::Log<'static, FlashCtrl>, 256),
+//! );
+//! let events = components::log::VirtualLogComponent::new(mux_log, 1, Some(uptime)).finalize(
+//!     components::virtual_log_component_static!(capsules_extra::log::Log<'static, FlashCtrl>),
+//! );
+//! let log_driver = components::log::LogDriverComponent::new(
+//!     mux_log,
+//!     Some(uptime),
+//!     board_kernel,
+//!     capsules_extra::log_driver::DRIVER_NUM,
+//! )
+//! .finalize(components::log_driver_component_static!(
+//!     capsules_extra::log::Log<'static, FlashCtrl>,
+//!     256
+//! ));
+//! ```
+
+use capsules_extra::log_driver::LogDriver;
+use capsules_extra::virtual_log::{LogTimestamp, MuxLog, VirtualLog};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::log::{LogRead, LogWrite};
+
+///////////////////////
+// Log Userspace Driver
+///////////////////////
+
+// The buffer should hold the largest entries of the mux, the driver cannot read
+// past entries that do not fit.
+#[macro_export]
+macro_rules! log_driver_component_static {
+    ($L:ty, $BUF_LEN:expr $(,)?) => {{
+        let virtual_log = kernel::static_buf!(capsules_extra::virtual_log::VirtualLog<'static, $L>);
+        let driver = kernel::static_buf!(capsules_extra::log_driver::LogDriver<'static, $L>);
+        let buffer = kernel::static_buf!([u8; $BUF_LEN]);
+
+        (virtual_log, driver, buffer)
+    };};
+}
+
+pub type LogDriverComponentType<L> = capsules_extra::log_driver::LogDriver<'static, L>;
+
+pub struct LogDriverComponent<
+    L: LogRead<'static, EntryID = usize> + LogWrite<'static> + 'static,
+    const BUF_LEN: usize,
+> {
+    mux_log: &'static MuxLog<'static, L>,
+    timestamp: Option<&'static dyn LogTimestamp>,
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+}
+
+impl<L: LogRead<'static, EntryID = usize> + LogWrite<'static>, const BUF_LEN: usize>
+    LogDriverComponent<L, BUF_LEN>
+{
+    pub fn new(
+        mux_log: &'static MuxLog<'static, L>,
+        timestamp: Option<&'static dyn LogTimestamp>,
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+    ) -> Self {
+        Self {
+            mux_log,
+            timestamp,
+            board_kernel,
+            driver_num,
+        }
+    }
+}
+
+impl<L: LogRead<'static, EntryID = usize> + LogWrite<'static>, const BUF_LEN: usize> Component
+    for LogDriverComponent<L, BUF_LEN>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualLog<'static, L>>,
+        &'static mut MaybeUninit<LogDriver<'static, L>>,
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+    );
+    type Output = &'static LogDriver<'static, L>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        // The log ID is replaced with the write ID of each app on append.
+        let virtual_log = static_buffer
+            .0
+            .write(VirtualLog::new(self.mux_log, 0, self.timestamp));
+        virtual_log.setup();
+
+        let buffer = static_buffer.2.write([0; BUF_LEN]);
+        let driver = static_buffer.1.write(LogDriver::new(
+            virtual_log,
+            buffer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        virtual_log.set_read_client(driver);
+        virtual_log.set_append_client(driver);
+        driver
+    }
+}
+
+//////////////
+// Log Mux
+//////////////
+
+#[macro_export]
+macro_rules! mux_log_component_static {
+    ($L:ty, $BUF_LEN:expr $(,)?) => {{
+        let mux = kernel::static_buf!(capsules_extra::virtual_log::MuxLog<'static, $L>);
+        let buffer = kernel::static_buf!([u8; $BUF_LEN]);
+
+        (mux, buffer)
+    };};
+}
+
+pub type MuxLogComponentType<L> = capsules_extra::virtual_log::MuxLog<'static, L>;
+
+pub struct MuxLogComponent<
+    L: LogRead<'static, EntryID = usize> + LogWrite<'static> + 'static,
+    const BUF_LEN: usize,
+> {
+    log: &'static L,
+}
+
+impl<L: LogRead<'static, EntryID = usize> + LogWrite<'static>, const BUF_LEN: usize>
+    MuxLogComponent<L, BUF_LEN>
+{
+    pub fn new(log: &'static L) -> Self {
+        Self { log }
+    }
+}
+
+impl<L: LogRead<'static, EntryID = usize> + LogWrite<'static>, const BUF_LEN: usize> Component
+    for MuxLogComponent<L, BUF_LEN>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<MuxLog<'static, L>>,
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+    );
+    type Output = &'static MuxLog<'static, L>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let buffer = static_buffer.1.write([0; BUF_LEN]);
+        let mux = static_buffer.0.write(MuxLog::new(self.log, buffer));
+        self.log.set_read_client(mux);
+        self.log.set_append_client(mux);
+        mux
+    }
+}
+
+/////////////////////
+// Virtual Log
+/////////////////////
+
+#[macro_export]
+macro_rules! virtual_log_component_static {
+    ($L:ty $(,)?) => {{
+        let virtual_log = kernel::static_buf!(capsules_extra::virtual_log::VirtualLog<'static, $L>);
+
+        virtual_log
+    };};
+}
+
+pub type VirtualLogComponentType<L> = capsules_extra::virtual_log::VirtualLog<'static, L>;
+
+pub struct VirtualLogComponent<L: LogRead<'static, EntryID = usize> + LogWrite<'static> + 'static> {
+    mux_log: &'static MuxLog<'static, L>,
+    log_id: u32,
+    timestamp: Option<&'static dyn LogTimestamp>,
+}
+
+impl<L: LogRead<'static, EntryID = usize> + LogWrite<'static>> VirtualLogComponent<L> {
+    pub fn new(
+        mux_log: &'static MuxLog<'static, L>,
+        log_id: u32,
+        timestamp: Option<&'static dyn LogTimestamp>,
+    ) -> Self {
+        Self {
+            mux_log,
+            log_id,
+            timestamp,
+        }
+    }
+}
+
+impl<L: LogRead<'static, EntryID = usize> + LogWrite<'static>> Component
+    for VirtualLogComponent<L>
+{
+    type StaticInput = &'static mut MaybeUninit<VirtualLog<'static, L>>;
+    type Output = &'static VirtualLog<'static, L>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let virtual_log =
+            static_buffer.write(VirtualLog::new(self.mux_log, self.log_id, self.timestamp));
+        virtual_log.setup();
+        virtual_log
+    }
+}
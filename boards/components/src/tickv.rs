@@ -44,10 +44,31 @@
 //!    ));
 //!    hil::flash::HasClient::set_client(&peripherals.flash_ctrl, mux_flash);
 //! ```
+//!
+//! Each app can also get its own TicKV store on a separate region of flash,
+//! with a quota of keys and bytes. Every store is set up with a
+//! `TicKVComponent` as above, and then assigned to the write ID of an app:
+//!
+//! ```rust
+//!    let namespaces = static_init!(
+//!        [capsules_extra::tickv_app_driver::Namespace<'static, TicKVType>; 2],
+//!        [
+//!            capsules_extra::tickv_app_driver::Namespace::new(tickv_app1, 1, 32, 4096),
+//!            capsules_extra::tickv_app_driver::Namespace::new(tickv_app2, 2, 16, 2048),
+//!        ]
+//!    );
+//!    let tickv_app_driver = components::tickv::TicKVAppDriverComponent::new(
+//!        namespaces,
+//!        board_kernel,
+//!        capsules_extra::tickv_app_driver::DRIVER_NUM,
+//!    )
+//!    .finalize(components::tickv_app_driver_component_static!(TicKVType, 256));
+//! ```
 
 use capsules_core::virtualizers::virtual_flash::FlashUser;
 use capsules_core::virtualizers::virtual_flash::MuxFlash;
-use capsules_extra::tickv::TicKVSystem;
+use capsules_extra::tickv::{KVSystem, TicKVKeyType, TicKVSystem};
+use capsules_extra::tickv_app_driver::{Namespace, TicKVAppDriver};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
@@ -230,3 +251,70 @@ impl<
         tickv
     }
 }
+
+// The value buffer limits the length of the values apps can store.
+#[macro_export]
+macro_rules! tickv_app_driver_component_static {
+    ($K:ty, $BUF_LEN:expr $(,)?) => {{
+        let driver =
+            kernel::static_buf!(capsules_extra::tickv_app_driver::TicKVAppDriver<'static, $K>);
+        let key_buffer = kernel::static_buf!(capsules_extra::tickv::TicKVKeyType);
+        let value_buffer = kernel::static_buf!([u8; $BUF_LEN]);
+
+        (driver, key_buffer, value_buffer)
+    };};
+}
+
+pub type TicKVAppDriverComponentType<K> =
+    capsules_extra::tickv_app_driver::TicKVAppDriver<'static, K>;
+
+pub struct TicKVAppDriverComponent<
+    K: 'static + KVSystem<'static, K = TicKVKeyType>,
+    const BUF_LEN: usize,
+> {
+    namespaces: &'static [Namespace<'static, K>],
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+}
+
+impl<K: 'static + KVSystem<'static, K = TicKVKeyType>, const BUF_LEN: usize>
+    TicKVAppDriverComponent<K, BUF_LEN>
+{
+    pub fn new(
+        namespaces: &'static [Namespace<'static, K>],
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+    ) -> Self {
+        Self {
+            namespaces,
+            board_kernel,
+            driver_num,
+        }
+    }
+}
+
+impl<K: 'static + KVSystem<'static, K = TicKVKeyType>, const BUF_LEN: usize> Component
+    for TicKVAppDriverComponent<K, BUF_LEN>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<TicKVAppDriver<'static, K>>,
+        &'static mut MaybeUninit<TicKVKeyType>,
+        &'static mut MaybeUninit<[u8; BUF_LEN]>,
+    );
+    type Output = &'static TicKVAppDriver<'static, K>;
+
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let key_buffer = static_buffer.1.write([0; 8]);
+        let value_buffer = static_buffer.2.write([0; BUF_LEN]);
+        let driver = static_buffer.0.write(TicKVAppDriver::new(
+            self.namespaces,
+            key_buffer,
+            value_buffer,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+        driver.set_clients();
+        driver
+    }
+}
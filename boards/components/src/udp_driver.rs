@@ -20,10 +20,33 @@
 //!     )
 //!     .finalize(components::udp_driver_component_static!());
 //! ```
+//!
+//! When the UDP stack runs on another IPv6 sender, for example over Ethernet
+//! with `UDPMuxEthernetComponent`, the static space is created for that
+//! sender:
+//!
+//! ```rust
+//!     .finalize(components::udp_driver_sender_component_static!(
+//!         capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetInterface<'static, E>
+//!     ));
+//! ```
+//!
+//! To restrict which ports each app may bind, the board sets the port ranges
+//! of its apps on the port table:
+//!
+//! ```rust
+//!    static APP_PORTS: [AppPortRange; 1] = [AppPortRange::new(
+//!        ShortId::Fixed(NonZeroU32::new(0x1234).unwrap()),
+//!        PortRange::Range(16000, 16099),
+//!    )];
+//!    udp_port_table.set_app_port_ranges(
+//!        &APP_PORTS,
+//!        &create_capability!(capabilities::CreatePortTableCapability),
+//!    );
+//! ```
 
-use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
 use capsules_extra::net::ipv6::ip_utils::IPAddr;
-use capsules_extra::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules_extra::net::ipv6::ipv6_send::IP6Sender;
 use capsules_extra::net::network_capabilities::{
     AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
 };
@@ -36,7 +59,6 @@ use kernel::capabilities;
 use kernel::capabilities::NetworkCapabilityCreationCapability;
 use kernel::component::Component;
 use kernel::create_capability;
-use kernel::hil::time::Alarm;
 
 const MAX_PAYLOAD_LEN: usize = super::udp_mux::MAX_PAYLOAD_LEN;
 
@@ -44,17 +66,23 @@ const MAX_PAYLOAD_LEN: usize = super::udp_mux::MAX_PAYLOAD_LEN;
 #[macro_export]
 macro_rules! udp_driver_component_static {
     ($A:ty $(,)?) => {{
-        use components::udp_mux::MAX_PAYLOAD_LEN;
-
-        let udp_send = kernel::static_buf!(
-            capsules_extra::net::udp::udp_send::UDPSendStruct<
+        $crate::udp_driver_sender_component_static!(
+            capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
                 'static,
-                capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
-                    'static,
-                    capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
-                >,
+                capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm<'static, $A>,
             >
-        );
+        )
+    };};
+}
+
+// Setup static space for the objects, for a UDP stack on the IPv6 sender `$S`.
+#[macro_export]
+macro_rules! udp_driver_sender_component_static {
+    ($S:ty $(,)?) => {{
+        use components::udp_mux::MAX_PAYLOAD_LEN;
+
+        let udp_send =
+            kernel::static_buf!(capsules_extra::net::udp::udp_send::UDPSendStruct<'static, $S>);
         let udp_vis_cap =
             kernel::static_buf!(capsules_extra::net::network_capabilities::UdpVisibilityCapability);
         let net_cap =
@@ -68,24 +96,20 @@ macro_rules! udp_driver_component_static {
     };};
 }
 
-pub struct UDPDriverComponent<A: Alarm<'static> + 'static> {
+pub struct UDPDriverComponent<S: IP6Sender<'static> + 'static> {
     board_kernel: &'static kernel::Kernel,
     driver_num: usize,
-    udp_send_mux:
-        &'static MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>>,
+    udp_send_mux: &'static MuxUdpSender<'static, S>,
     udp_recv_mux: &'static MuxUdpReceiver<'static>,
     port_table: &'static UdpPortManager,
     interface_list: &'static [IPAddr],
 }
 
-impl<A: Alarm<'static>> UDPDriverComponent<A> {
+impl<S: IP6Sender<'static>> UDPDriverComponent<S> {
     pub fn new(
         board_kernel: &'static kernel::Kernel,
         driver_num: usize,
-        udp_send_mux: &'static MuxUdpSender<
-            'static,
-            IP6SendStruct<'static, VirtualMuxAlarm<'static, A>>,
-        >,
+        udp_send_mux: &'static MuxUdpSender<'static, S>,
         udp_recv_mux: &'static MuxUdpReceiver<'static>,
         port_table: &'static UdpPortManager,
         interface_list: &'static [IPAddr],
@@ -101,17 +125,9 @@ impl<A: Alarm<'static>> UDPDriverComponent<A> {
     }
 }
 
-impl<A: Alarm<'static>> Component for UDPDriverComponent<A> {
+impl<S: IP6Sender<'static>> Component for UDPDriverComponent<S> {
     type StaticInput = (
-        &'static mut MaybeUninit<
-            UDPSendStruct<
-                'static,
-                capsules_extra::net::ipv6::ipv6_send::IP6SendStruct<
-                    'static,
-                    VirtualMuxAlarm<'static, A>,
-                >,
-            >,
-        >,
+        &'static mut MaybeUninit<UDPSendStruct<'static, S>>,
         &'static mut MaybeUninit<
             capsules_extra::net::network_capabilities::UdpVisibilityCapability,
         >,
@@ -23,6 +23,20 @@
 //!    )
 //!    .finalize(components::udp_mux_component_static!());
 //! ```
+//!
+//! On boards without a radio, `UDPMuxEthernetComponent` runs the same UDP
+//! stack over an Ethernet adapter, for example a USB CDC-ECM function:
+//!
+//! ```rust
+//!    let (udp_mux, udp_recv, udp_port_table) = UDPMuxEthernetComponent::new(
+//!        ecm,
+//!        [0x02, 0x00, 0x00, 0x00, 0x00, 0x02],
+//!        local_ip_ifaces,
+//!    )
+//!    .finalize(components::udp_mux_ethernet_component_static!(
+//!        capsules_extra::usb::cdc_ecm::CdcEcm<'static, nrf52::usbd::Usbd>
+//!    ));
+//! ```
 
 // Author: Hudson Ayers <hayers@stanford.edu>
 // Last Modified: 5/21/2019
@@ -31,6 +45,7 @@ use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules_extra::ieee802154::device::MacDevice;
 use capsules_extra::net::ieee802154::MacAddress;
 use capsules_extra::net::ipv6::ip_utils::IPAddr;
+use capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetInterface;
 use capsules_extra::net::ipv6::ipv6_recv::IP6Receiver;
 use capsules_extra::net::ipv6::ipv6_recv::IP6RecvStruct;
 use capsules_extra::net::ipv6::ipv6_send::IP6SendStruct;
@@ -48,6 +63,7 @@ use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
+use kernel::hil::ethernet::{self, EthernetAdapterDatapath};
 use kernel::hil::radio;
 use kernel::hil::time::Alarm;
 
@@ -318,3 +334,140 @@ impl<A: Alarm<'static> + 'static, M: MacDevice<'static>> Component for UDPMuxCom
         (udp_send_mux, udp_recv_mux, udp_port_table)
     }
 }
+
+// Setup static space for the objects of the UDP stack over Ethernet.
+#[macro_export]
+macro_rules! udp_mux_ethernet_component_static {
+    ($E:ty $(,)?) => {{
+        use capsules_extra::net::ipv6::ipv6_ethernet::IP6EthernetInterface;
+        use capsules_extra::net::udp::udp_send::MuxUdpSender;
+        use components::udp_mux::MAX_PAYLOAD_LEN;
+
+        let ip6_ethernet = kernel::static_buf!(IP6EthernetInterface<'static, $E>);
+        let mux_udp_send =
+            kernel::static_buf!(MuxUdpSender<'static, IP6EthernetInterface<'static, $E>>);
+        let mux_udp_recv =
+            kernel::static_buf!(capsules_extra::net::udp::udp_recv::MuxUdpReceiver<'static>);
+        let udp_port_manager =
+            kernel::static_buf!(capsules_extra::net::udp::udp_port_table::UdpPortManager);
+        let ip6_packet = kernel::static_buf!(capsules_extra::net::ipv6::IP6Packet<'static>);
+        let used_ports = kernel::static_buf!(
+            [Option<capsules_extra::net::udp::udp_port_table::SocketBindingEntry>;
+                capsules_extra::net::udp::udp_port_table::MAX_NUM_BOUND_PORTS]
+        );
+        let tx_buf = kernel::static_buf!([u8; kernel::hil::ethernet::MAX_FRAME_LEN]);
+        let reply_buf = kernel::static_buf!([u8; kernel::hil::ethernet::MAX_FRAME_LEN]);
+        let udp_dgram = kernel::static_buf!([u8; MAX_PAYLOAD_LEN]);
+        let udp_vis_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::UdpVisibilityCapability);
+        let ip_vis_cap =
+            kernel::static_buf!(capsules_extra::net::network_capabilities::IpVisibilityCapability);
+
+        (
+            ip6_ethernet,
+            mux_udp_send,
+            mux_udp_recv,
+            udp_port_manager,
+            ip6_packet,
+            used_ports,
+            tx_buf,
+            reply_buf,
+            udp_dgram,
+            udp_vis_cap,
+            ip_vis_cap,
+        )
+    };};
+}
+
+pub struct UDPMuxEthernetComponent<E: EthernetAdapterDatapath<'static> + 'static> {
+    ethernet: &'static E,
+    mac: [u8; ethernet::MAC_ADDRESS_LEN],
+    interface_list: &'static [IPAddr],
+}
+
+impl<E: EthernetAdapterDatapath<'static>> UDPMuxEthernetComponent<E> {
+    /// Create the UDP stack on `ethernet`, with the MAC address `mac`. Apart
+    /// from the addresses in `interface_list`, the interface has a link-local
+    /// address derived from `mac`.
+    pub fn new(
+        ethernet: &'static E,
+        mac: [u8; ethernet::MAC_ADDRESS_LEN],
+        interface_list: &'static [IPAddr],
+    ) -> Self {
+        Self {
+            ethernet,
+            mac,
+            interface_list,
+        }
+    }
+}
+
+impl<E: EthernetAdapterDatapath<'static>> Component for UDPMuxEthernetComponent<E> {
+    type StaticInput = (
+        &'static mut MaybeUninit<IP6EthernetInterface<'static, E>>,
+        &'static mut MaybeUninit<MuxUdpSender<'static, IP6EthernetInterface<'static, E>>>,
+        &'static mut MaybeUninit<MuxUdpReceiver<'static>>,
+        &'static mut MaybeUninit<UdpPortManager>,
+        &'static mut MaybeUninit<IP6Packet<'static>>,
+        &'static mut MaybeUninit<[Option<SocketBindingEntry>; MAX_NUM_BOUND_PORTS]>,
+        &'static mut MaybeUninit<[u8; ethernet::MAX_FRAME_LEN]>,
+        &'static mut MaybeUninit<[u8; ethernet::MAX_FRAME_LEN]>,
+        &'static mut MaybeUninit<[u8; MAX_PAYLOAD_LEN]>,
+        &'static mut MaybeUninit<UdpVisibilityCapability>,
+        &'static mut MaybeUninit<IpVisibilityCapability>,
+    );
+    type Output = (
+        &'static MuxUdpSender<'static, IP6EthernetInterface<'static, E>>,
+        &'static MuxUdpReceiver<'static>,
+        &'static UdpPortManager,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = s.9.write(UdpVisibilityCapability::new(&create_cap));
+        let ip_vis = s.10.write(IpVisibilityCapability::new(&create_cap));
+
+        let udp_dgram_buffer = s.8.write([0; MAX_PAYLOAD_LEN]);
+        let ip_pyld: IPPayload = IPPayload {
+            header: TransportHeader::UDP(UDPHeader::new()),
+            payload: udp_dgram_buffer,
+        };
+        let ip6_dg = s.4.write(IP6Packet::new(ip_pyld));
+
+        let tx_buf = s.6.write([0; ethernet::MAX_FRAME_LEN]);
+        let reply_buf = s.7.write([0; ethernet::MAX_FRAME_LEN]);
+        let ip6_ethernet = s.0.write(IP6EthernetInterface::new(
+            self.ethernet,
+            self.mac,
+            self.interface_list,
+            ip6_dg,
+            tx_buf,
+            reply_buf,
+            ip_vis,
+        ));
+
+        // Send from the first address in the interface list, or from the
+        // link-local address if the list is empty.
+        if let Some(addr) = self.interface_list.first() {
+            ip6_ethernet.set_addr(*addr);
+        }
+        self.ethernet.set_client(ip6_ethernet);
+        self.ethernet.enable_receive();
+
+        let udp_recv_mux = s.2.write(MuxUdpReceiver::new());
+        IP6Receiver::set_client(ip6_ethernet, udp_recv_mux);
+
+        let udp_send_mux = s.1.write(MuxUdpSender::new(ip6_ethernet));
+        IP6Sender::set_client(ip6_ethernet, udp_send_mux);
+
+        let kernel_ports = s.5.write([None; MAX_NUM_BOUND_PORTS]);
+        let create_table_cap = create_capability!(capabilities::CreatePortTableCapability);
+        let udp_port_table = s.3.write(UdpPortManager::new(
+            &create_table_cap,
+            kernel_ports,
+            udp_vis,
+        ));
+
+        (udp_send_mux, udp_recv_mux, udp_port_table)
+    }
+}
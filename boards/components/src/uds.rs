@@ -0,0 +1,186 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Component for a UDS diagnostic server over ISO-TP on a shared CAN
+//! peripheral.
+//!
+//! The server receives requests with the CAN identifier `rx_id` and sends
+//! responses with `tx_id`, for example the physical request and response
+//! identifiers of the ECU.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let uds = components::uds::UdsComponent::new(
+//!     board_kernel,
+//!     capsules_extra::uds_driver::DRIVER_NUM,
+//!     can_mux,
+//!     mux_alarm,
+//!     kernel::hil::can::Id::Standard(0x7E8),
+//!     kernel::hil::can::Id::Standard(0x7E0),
+//! )
+//! .finalize(components::uds_component_static!(
+//!     stm32f429zi::can::Can<'static>,
+//!     stm32f429zi::tim2::Tim2
+//! ));
+//! ```
+
+use capsules_core::virtualizers::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules_extra::isotp::IsoTp;
+use capsules_extra::uds_driver::{self, Uds};
+use capsules_extra::virtual_can::{MuxCan, VirtualCan};
+use core::mem::MaybeUninit;
+use kernel::component::Component;
+use kernel::hil::can;
+use kernel::hil::time::Alarm;
+use kernel::{capabilities, create_capability};
+
+#[macro_export]
+macro_rules! uds_component_static {
+    ($C:ty, $A:ty $(,)?) => {{
+        use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
+        use capsules_extra::virtual_can::VirtualCan;
+        use kernel::hil::can;
+        use kernel::static_buf;
+
+        let can_device = static_buf!(VirtualCan<'static, $C>);
+        let isotp_alarm = static_buf!(VirtualMuxAlarm<'static, $A>);
+        let uds_alarm = static_buf!(VirtualMuxAlarm<'static, $A>);
+        let tx_frame = static_buf!([u8; can::STANDARD_CAN_PACKET_SIZE]);
+        let rx_frame = static_buf!([u8; can::STANDARD_CAN_PACKET_SIZE]);
+        let isotp = static_buf!(
+            capsules_extra::isotp::IsoTp<
+                'static,
+                VirtualCan<'static, $C>,
+                VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        let tx_buffer = static_buf!([u8; capsules_extra::uds_driver::MAX_MESSAGE_LEN]);
+        let rx_buffer = static_buf!([u8; capsules_extra::uds_driver::MAX_MESSAGE_LEN]);
+        let uds = static_buf!(
+            capsules_extra::uds_driver::Uds<
+                'static,
+                VirtualCan<'static, $C>,
+                VirtualMuxAlarm<'static, $A>,
+            >
+        );
+        (
+            can_device,
+            isotp_alarm,
+            uds_alarm,
+            tx_frame,
+            rx_frame,
+            isotp,
+            tx_buffer,
+            rx_buffer,
+            uds,
+        )
+    };};
+}
+
+pub struct UdsComponent<
+    C: 'static
+        + can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+        + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>
+        + can::Configure
+        + can::Controller,
+    A: 'static + Alarm<'static>,
+> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    mux_can: &'static MuxCan<'static, C>,
+    mux_alarm: &'static MuxAlarm<'static, A>,
+    tx_id: can::Id,
+    rx_id: can::Id,
+}
+
+impl<
+        C: 'static
+            + can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+            + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>
+            + can::Configure
+            + can::Controller,
+        A: 'static + Alarm<'static>,
+    > UdsComponent<C, A>
+{
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        mux_can: &'static MuxCan<'static, C>,
+        mux_alarm: &'static MuxAlarm<'static, A>,
+        tx_id: can::Id,
+        rx_id: can::Id,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            mux_can,
+            mux_alarm,
+            tx_id,
+            rx_id,
+        }
+    }
+}
+
+impl<
+        C: 'static
+            + can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+            + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>
+            + can::Configure
+            + can::Controller,
+        A: 'static + Alarm<'static>,
+    > Component for UdsComponent<C, A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualCan<'static, C>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, A>>,
+        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
+        &'static mut MaybeUninit<[u8; can::STANDARD_CAN_PACKET_SIZE]>,
+        &'static mut MaybeUninit<
+            IsoTp<'static, VirtualCan<'static, C>, VirtualMuxAlarm<'static, A>>,
+        >,
+        &'static mut MaybeUninit<[u8; uds_driver::MAX_MESSAGE_LEN]>,
+        &'static mut MaybeUninit<[u8; uds_driver::MAX_MESSAGE_LEN]>,
+        &'static mut MaybeUninit<Uds<'static, VirtualCan<'static, C>, VirtualMuxAlarm<'static, A>>>,
+    );
+    type Output = &'static Uds<'static, VirtualCan<'static, C>, VirtualMuxAlarm<'static, A>>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let can_device = s.0.write(VirtualCan::new(self.mux_can));
+        can_device.setup();
+
+        let isotp_alarm = s.1.write(VirtualMuxAlarm::new(self.mux_alarm));
+        isotp_alarm.setup();
+        let uds_alarm = s.2.write(VirtualMuxAlarm::new(self.mux_alarm));
+        uds_alarm.setup();
+
+        let isotp = s.5.write(IsoTp::new(
+            can_device,
+            isotp_alarm,
+            s.3.write([0; can::STANDARD_CAN_PACKET_SIZE]),
+            s.4.write([0; can::STANDARD_CAN_PACKET_SIZE]),
+            self.tx_id,
+            self.rx_id,
+        ));
+        isotp_alarm.set_alarm_client(isotp);
+        can::Controller::set_client(can_device, Some(isotp));
+        can::Transmit::set_client(can_device, Some(isotp));
+        can::Receive::set_client(can_device, Some(isotp));
+
+        let uds = s.8.write(Uds::new(
+            isotp,
+            uds_alarm,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+            s.6.write([0; uds_driver::MAX_MESSAGE_LEN]),
+        ));
+        uds_alarm.set_alarm_client(uds);
+        isotp.set_client(uds);
+        isotp.receive(s.7.write([0; uds_driver::MAX_MESSAGE_LEN]));
+
+        uds
+    }
+}
@@ -0,0 +1,99 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Component for a USB CDC-ECM Ethernet function.
+//!
+//! The CDC-ECM function is one function of a composite device, see
+//! `usb_composite`. It uses two interfaces, starting at `interface`, and
+//! exports the MAC address of the host side of the link as the string with
+//! `mac_string_index`. The function is an Ethernet adapter, usually for
+//! `UDPMuxEthernetComponent`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let ecm = components::usb_cdc_ecm::CdcEcmComponent::new(
+//!     &nrf52840_peripherals.usbd,
+//!     0,
+//!     1,
+//!     2,
+//!     3,
+//!     [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+//!     4,
+//! )
+//! .finalize(components::usb_cdc_ecm_component_static!(nrf52::usbd::Usbd));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::cdc_ecm::{CdcEcm, RX_BUFFER_LEN};
+use kernel::component::Component;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_cdc_ecm_component_static {
+    ($U:ty $(,)?) => {{
+        let ecm = kernel::static_buf!(capsules_extra::usb::cdc_ecm::CdcEcm<'static, $U>);
+        let rx_buffer = kernel::static_buf!([u8; capsules_extra::usb::cdc_ecm::RX_BUFFER_LEN]);
+
+        (ecm, rx_buffer)
+    };};
+}
+
+pub type CdcEcmComponentType<U> = CdcEcm<'static, U>;
+
+pub struct CdcEcmComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    interface: u8,
+    endpoint_notify: usize,
+    endpoint_in: usize,
+    endpoint_out: usize,
+    host_mac: [u8; 6],
+    mac_string_index: u8,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> CdcEcmComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        interface: u8,
+        endpoint_notify: usize,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        host_mac: [u8; 6],
+        mac_string_index: u8,
+    ) -> Self {
+        Self {
+            usb,
+            interface,
+            endpoint_notify,
+            endpoint_in,
+            endpoint_out,
+            host_mac,
+            mac_string_index,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for CdcEcmComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<CdcEcm<'static, U>>,
+        &'static mut MaybeUninit<[u8; RX_BUFFER_LEN]>,
+    );
+    type Output = &'static CdcEcm<'static, U>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let rx_buffer = s.1.write([0; RX_BUFFER_LEN]);
+        s.0.write(CdcEcm::new_function(
+            self.usb,
+            self.interface,
+            self.endpoint_notify,
+            self.endpoint_in,
+            self.endpoint_out,
+            self.host_mac,
+            self.mac_string_index,
+            rx_buffer,
+        ))
+    }
+}
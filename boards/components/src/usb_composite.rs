@@ -0,0 +1,107 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Component for a composite USB device.
+//!
+//! The functions of the device are created with their `*FunctionComponent`,
+//! which does not make them the client of the USB controller. The composite
+//! device then combines them.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpinator", // Product
+//!     "Serial No. 5",   // Serial number
+//! ];
+//!
+//! let cdc = components::cdc::CdcAcmFunctionComponent::new(
+//!     &nrf52::usbd::USBD, 0, 2, 3, 4, mux_alarm, None,
+//! )
+//! .finalize(components::cdc_acm_component_static!(nrf52::usbd::Usbd, nrf52::rtc::Rtc));
+//! let (ctap, ctap_driver) = components::ctap::CtapFunctionComponent::new(
+//!     board_kernel, capsules_core::driver::NUM::CtapHid as usize, &nrf52::usbd::USBD, 2, 1,
+//! )
+//! .finalize(components::ctap_component_static!(nrf52::usbd::Usbd));
+//!
+//! let functions = static_init!(
+//!     [&'static dyn capsules_extra::usb::composite::UsbFunction<'static>; 2],
+//!     [cdc, ctap]
+//! );
+//! let composite = components::usb_composite::CompositeDeviceComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     capsules_extra::usb::cdc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x2341,
+//!     0x005a,
+//!     STRINGS,
+//!     functions,
+//! )
+//! .finalize(components::usb_composite_component_static!(nrf52::usbd::Usbd));
+//!
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::composite::{CompositeDevice, UsbFunction};
+use kernel::component::Component;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_composite_component_static {
+    ($U:ty $(,)?) => {{
+        kernel::static_buf!(capsules_extra::usb::composite::CompositeDevice<'static, $U>)
+    };};
+}
+
+pub struct CompositeDeviceComponent<U: 'static + hil::usb::UsbController<'static>> {
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+    functions: &'static [&'static dyn UsbFunction<'static>],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> CompositeDeviceComponent<U> {
+    pub fn new(
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        functions: &'static [&'static dyn UsbFunction<'static>],
+    ) -> Self {
+        Self {
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            functions,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for CompositeDeviceComponent<U> {
+    type StaticInput = &'static mut MaybeUninit<CompositeDevice<'static, U>>;
+    type Output = &'static CompositeDevice<'static, U>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let composite = s.write(CompositeDevice::new(
+            self.usb,
+            self.max_ctrl_packet_size,
+            self.vendor_id,
+            self.product_id,
+            self.strings,
+            self.functions,
+        ));
+        self.usb.set_client(composite);
+
+        composite
+    }
+}
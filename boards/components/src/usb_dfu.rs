@@ -0,0 +1,108 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Component for a USB Device Firmware Upgrade function.
+//!
+//! The DFU function is one function of a composite device, see
+//! `usb_composite`. Downloaded images are checked with `checker`, which
+//! becomes the client of the DFU function. The checker used by the process
+//! loader can be reused once processes are loaded, or the board can create a
+//! separate one.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! extern "C" {
+//!     static _sstaging: u8;
+//!     static _estaging: u8;
+//! }
+//!
+//! let staging = core::slice::from_raw_parts(
+//!     core::ptr::addr_of!(_sstaging),
+//!     core::ptr::addr_of!(_estaging) as usize - core::ptr::addr_of!(_sstaging) as usize,
+//! );
+//! let dfu = components::usb_dfu::DfuComponent::new(
+//!     &base_peripherals.nvmc,
+//!     checker,
+//!     staging,
+//!     staging.as_ptr() as usize / 4096,
+//!     Some(app_flash_page),
+//!     2,
+//! )
+//! .finalize(components::usb_dfu_component_static!(nrf52840::nvmc::Nvmc));
+//! dfu.set_client(board_reset);
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::dfu::Dfu;
+use kernel::component::Component;
+use kernel::hil::flash::{self, Flash};
+use kernel::process_checker::AppCredentialsPolicy;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_dfu_component_static {
+    ($F:ty $(,)?) => {{
+        let dfu = kernel::static_buf!(capsules_extra::usb::dfu::Dfu<'static, $F>);
+        let page = kernel::static_buf!(<$F as kernel::hil::flash::Flash>::Page);
+
+        (dfu, page)
+    };};
+}
+
+pub type DfuComponentType<F> = Dfu<'static, F>;
+
+pub struct DfuComponent<F: Flash + flash::HasClient<'static, Dfu<'static, F>> + 'static> {
+    flash: &'static F,
+    checker: &'static dyn AppCredentialsPolicy<'static>,
+    region: &'static [u8],
+    first_page: usize,
+    destination: Option<usize>,
+    interface: u8,
+}
+
+impl<F: Flash + flash::HasClient<'static, Dfu<'static, F>>> DfuComponent<F> {
+    pub fn new(
+        flash: &'static F,
+        checker: &'static dyn AppCredentialsPolicy<'static>,
+        region: &'static [u8],
+        first_page: usize,
+        destination: Option<usize>,
+        interface: u8,
+    ) -> Self {
+        Self {
+            flash,
+            checker,
+            region,
+            first_page,
+            destination,
+            interface,
+        }
+    }
+}
+
+impl<F: Flash + flash::HasClient<'static, Dfu<'static, F>>> Component for DfuComponent<F> {
+    type StaticInput = (
+        &'static mut MaybeUninit<Dfu<'static, F>>,
+        &'static mut MaybeUninit<<F as Flash>::Page>,
+    );
+    type Output = &'static Dfu<'static, F>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let page = s.1.write(<F as Flash>::Page::default());
+        let dfu = s.0.write(Dfu::new(
+            self.flash,
+            page,
+            self.checker,
+            self.region,
+            self.first_page,
+            self.destination,
+            self.interface,
+        ));
+        self.flash.set_client(dfu);
+        self.checker.set_client(dfu);
+        dfu
+    }
+}
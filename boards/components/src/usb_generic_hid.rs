@@ -0,0 +1,138 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Component for a USB HID function whose report descriptor apps register
+//! at runtime.
+//!
+//! The function is one function of a composite device, see `usb_composite`.
+//! Apps use it through the `UsbHidDriver` syscall driver, which also lets
+//! them register the report descriptor and exchange feature and output
+//! reports with control transfers.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (hid, hid_driver) = components::usb_generic_hid::GenericHidComponent::new(
+//!     board_kernel,
+//!     capsules_core::driver::NUM::UsbHid as usize,
+//!     &nrf52840_peripherals.usbd,
+//!     2,
+//!     3,
+//! )
+//! .finalize(components::usb_generic_hid_component_static!(
+//!     nrf52840::usbd::Usbd
+//! ));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::generic_hid::{GenericHid, HidReports};
+use capsules_extra::usb::hid_report::MAX_REPORT_LEN;
+use capsules_extra::usb_hid_driver::{UsbHidDriver, MAX_DESCRIPTOR_LEN};
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_generic_hid_component_static {
+    ($U:ty $(,)?) => {{
+        let hid = kernel::static_buf!(capsules_extra::usb::generic_hid::GenericHid<'static, $U>);
+        let driver = kernel::static_buf!(
+            capsules_extra::usb_hid_driver::UsbHidDriver<
+                'static,
+                capsules_extra::usb::generic_hid::GenericHid<'static, $U>,
+            >
+        );
+        let send_buffer = kernel::static_buf!([u8; 64]);
+        let recv_buffer = kernel::static_buf!([u8; 64]);
+        let descriptor =
+            kernel::static_buf!([u8; capsules_extra::usb_hid_driver::MAX_DESCRIPTOR_LEN]);
+        let feature_report =
+            kernel::static_buf!([u8; capsules_extra::usb::hid_report::MAX_REPORT_LEN]);
+        let ctrl_report =
+            kernel::static_buf!([u8; capsules_extra::usb::hid_report::MAX_REPORT_LEN]);
+
+        (
+            hid,
+            driver,
+            send_buffer,
+            recv_buffer,
+            descriptor,
+            feature_report,
+            ctrl_report,
+        )
+    };};
+}
+
+pub type GenericHidComponentType<U> = UsbHidDriver<'static, GenericHid<'static, U>>;
+
+pub struct GenericHidComponent<U: 'static + hil::usb::UsbController<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    usb: &'static U,
+    interface: u8,
+    endpoint: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> GenericHidComponent<U> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        driver_num: usize,
+        usb: &'static U,
+        interface: u8,
+        endpoint: usize,
+    ) -> Self {
+        Self {
+            board_kernel,
+            driver_num,
+            usb,
+            interface,
+            endpoint,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for GenericHidComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<GenericHid<'static, U>>,
+        &'static mut MaybeUninit<UsbHidDriver<'static, GenericHid<'static, U>>>,
+        &'static mut MaybeUninit<[u8; 64]>,
+        &'static mut MaybeUninit<[u8; 64]>,
+        &'static mut MaybeUninit<[u8; MAX_DESCRIPTOR_LEN]>,
+        &'static mut MaybeUninit<[u8; MAX_REPORT_LEN]>,
+        &'static mut MaybeUninit<[u8; MAX_REPORT_LEN]>,
+    );
+    type Output = (
+        &'static GenericHid<'static, U>,
+        &'static UsbHidDriver<'static, GenericHid<'static, U>>,
+    );
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let hid = s.0.write(GenericHid::new_function(
+            self.usb,
+            self.interface,
+            self.endpoint,
+            s.4.write([0; MAX_DESCRIPTOR_LEN]),
+            s.5.write([0; MAX_REPORT_LEN]),
+            s.6.write([0; MAX_REPORT_LEN]),
+        ));
+
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let driver = s.1.write(UsbHidDriver::new(
+            hid,
+            s.2.write([0; 64]),
+            s.3.write([0; 64]),
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ));
+
+        hid.set_client(driver);
+        hid.set_reports_client(driver);
+        driver.set_reports(hid);
+
+        (hid, driver)
+    }
+}
@@ -0,0 +1,167 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Components for a USB mass storage device.
+//!
+//! `MassStorageComponent` makes the mass storage device the client of the USB
+//! controller. `MassStorageFunctionComponent` creates it as a function of a
+//! composite device, see `usb_composite`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",     // Manufacturer
+//!     "Data Logger",   // Product
+//!     "Serial No. 5",  // Serial number
+//! ];
+//!
+//! let msc = components::usb_msc::MassStorageComponent::new(
+//!     &nrf52::usbd::USBD,
+//!     sdcard,
+//!     0x1915,
+//!     0x503a,
+//!     STRINGS,
+//! )
+//! .finalize(components::usb_msc_component_static!(
+//!     nrf52::usbd::Usbd,
+//!     capsules_extra::sdcard::SDCard<'static, ...>
+//! ));
+//!
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules_extra::usb::msc::{MassStorage, DATA_BUFFER_LEN};
+use kernel::component::Component;
+use kernel::hil;
+use kernel::hil::block_storage::BlockStorage;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_msc_component_static {
+    ($U:ty, $B:ty $(,)?) => {{
+        let msc = kernel::static_buf!(capsules_extra::usb::msc::MassStorage<'static, $U, $B>);
+        let data_buffer = kernel::static_buf!([u8; capsules_extra::usb::msc::DATA_BUFFER_LEN]);
+
+        (msc, data_buffer)
+    };};
+}
+
+pub type MassStorageComponentType<U, B> = MassStorage<'static, U, B>;
+
+pub struct MassStorageComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    B: 'static + BlockStorage<'static>,
+> {
+    usb: &'static U,
+    storage: &'static B,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, B: 'static + BlockStorage<'static>>
+    MassStorageComponent<U, B>
+{
+    pub fn new(
+        usb: &'static U,
+        storage: &'static B,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        Self {
+            usb,
+            storage,
+            vendor_id,
+            product_id,
+            strings,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, B: 'static + BlockStorage<'static>> Component
+    for MassStorageComponent<U, B>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<MassStorage<'static, U, B>>,
+        &'static mut MaybeUninit<[u8; DATA_BUFFER_LEN]>,
+    );
+    type Output = &'static MassStorage<'static, U, B>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let data_buffer = s.1.write([0; DATA_BUFFER_LEN]);
+        let msc = s.0.write(MassStorage::new(
+            self.usb,
+            self.storage,
+            data_buffer,
+            self.vendor_id,
+            self.product_id,
+            self.strings,
+        ));
+        self.usb.set_client(msc);
+        self.storage.set_client(msc);
+        msc
+    }
+}
+
+/// Component for a mass storage function of a composite USB device. Uses the
+/// same static input as `MassStorageComponent`.
+pub struct MassStorageFunctionComponent<
+    U: 'static + hil::usb::UsbController<'static>,
+    B: 'static + BlockStorage<'static>,
+> {
+    usb: &'static U,
+    storage: &'static B,
+    interface: u8,
+    endpoint_in: usize,
+    endpoint_out: usize,
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, B: 'static + BlockStorage<'static>>
+    MassStorageFunctionComponent<U, B>
+{
+    pub fn new(
+        usb: &'static U,
+        storage: &'static B,
+        interface: u8,
+        endpoint_in: usize,
+        endpoint_out: usize,
+    ) -> Self {
+        Self {
+            usb,
+            storage,
+            interface,
+            endpoint_in,
+            endpoint_out,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>, B: 'static + BlockStorage<'static>> Component
+    for MassStorageFunctionComponent<U, B>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<MassStorage<'static, U, B>>,
+        &'static mut MaybeUninit<[u8; DATA_BUFFER_LEN]>,
+    );
+    type Output = &'static MassStorage<'static, U, B>;
+
+    fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let data_buffer = s.1.write([0; DATA_BUFFER_LEN]);
+        let msc = s.0.write(MassStorage::new_function(
+            self.usb,
+            self.storage,
+            data_buffer,
+            self.interface,
+            self.endpoint_in,
+            self.endpoint_out,
+        ));
+        self.storage.set_client(msc);
+        msc
+    }
+}
@@ -564,6 +564,7 @@ pub unsafe fn start() -> (
         nrf52840::rtc::Rtc,
         nrf52840::ble_radio::Radio
     ));
+    ble_radio.set_connection_radio(&base_peripherals.ble_radio);
 
     use capsules_extra::net::ieee802154::MacAddress;
 
@@ -669,7 +670,7 @@ pub unsafe fn start() -> (
     //--------------------------------------------------------------------------
 
     // Create and start the asynchronous process loader.
-    let _loader = components::loader::sequential::ProcessLoaderSequentialComponent::new(
+    let loader = components::loader::sequential::ProcessLoaderSequentialComponent::new(
         checker,
         &mut *addr_of_mut!(PROCESSES),
         board_kernel,
@@ -684,6 +685,23 @@ pub unsafe fn start() -> (
         NUM_PROCS
     ));
 
+    //--------------------------------------------------------------------------
+    // FLASH PROTECTION
+    //--------------------------------------------------------------------------
+
+    // Lock the kernel and the verified applications until the next reset, the
+    // bootloader can still update them.
+    components::flash::KernelFlashProtectionComponent::new(
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    )
+    .finalize(())
+    .unwrap_or_else(|err| debug!("Unable to protect kernel flash: {:?}", err));
+    loader.set_flash_protection(
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     //--------------------------------------------------------------------------
     // FINAL SETUP AND BOARD BOOT
     //--------------------------------------------------------------------------
@@ -622,6 +622,7 @@ unsafe fn start() -> (
         nrf52833::rtc::Rtc,
         nrf52833::ble_radio::Radio
     ));
+    ble_radio.set_connection_radio(&base_peripherals.ble_radio);
 
     //--------------------------------------------------------------------------
     // LED Matrix
@@ -772,6 +773,14 @@ unsafe fn start() -> (
     );
     CHIP = Some(chip);
 
+    // Lock the kernel flash until the next reset.
+    components::flash::KernelFlashProtectionComponent::new(
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    )
+    .finalize(())
+    .unwrap_or_else(|err| debug!("Unable to protect kernel flash: {:?}", err));
+
     debug!("Initialization complete. Entering main loop.");
 
     //--------------------------------------------------------------------------
@@ -810,6 +819,13 @@ unsafe fn start() -> (
         debug!("{:?}", err);
     });
 
+    // Lock the flash of the loaded processes until the next reset.
+    kernel::process::protect_loaded_processes(
+        &*addr_of!(PROCESSES),
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     (board_kernel, microbit, chip)
 }
 
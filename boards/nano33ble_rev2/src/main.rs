@@ -557,6 +557,7 @@ pub unsafe fn start() -> (
         nrf52840::rtc::Rtc,
         nrf52840::ble_radio::Radio
     ));
+    ble_radio.set_connection_radio(&base_peripherals.ble_radio);
 
     use capsules_extra::net::ieee802154::MacAddress;
 
@@ -684,6 +685,14 @@ pub unsafe fn start() -> (
     //     &nrf52840_peripherals.nrf52.nvmc,
     // );
 
+    // Lock the kernel flash until the next reset.
+    components::flash::KernelFlashProtectionComponent::new(
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    )
+    .finalize(())
+    .unwrap_or_else(|err| debug!("Unable to protect kernel flash: {:?}", err));
+
     debug!("Initialization complete. Entering main loop.");
     let _ = platform.pconsole.start();
 
@@ -723,6 +732,13 @@ pub unsafe fn start() -> (
         debug!("{:?}", err);
     });
 
+    // Lock the flash of the loaded processes until the next reset.
+    kernel::process::protect_loaded_processes(
+        &*addr_of!(PROCESSES),
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     (board_kernel, platform, chip)
 }
 
@@ -407,6 +407,7 @@ pub unsafe fn start() -> (
         nrf52832::rtc::Rtc,
         nrf52832::ble_radio::Radio
     ));
+    ble_radio.set_connection_radio(&base_peripherals.ble_radio);
 
     let temp = components::temperature::TemperatureComponent::new(
         board_kernel,
@@ -465,6 +466,15 @@ pub unsafe fn start() -> (
     };
 
     let _ = platform.pconsole.start();
+
+    // Lock the kernel flash until the next reset.
+    components::flash::KernelFlashProtectionComponent::new(
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    )
+    .finalize(())
+    .unwrap_or_else(|err| debug!("Unable to protect kernel flash: {:?}", err));
+
     debug!("Initialization complete. Entering main loop\r");
     debug!("{}", &*addr_of!(nrf52832::ficr::FICR_INSTANCE));
 
@@ -500,6 +510,13 @@ pub unsafe fn start() -> (
         debug!("{:?}", err);
     });
 
+    // Lock the flash of the loaded processes until the next reset.
+    kernel::process::protect_loaded_processes(
+        &*addr_of!(PROCESSES),
+        &base_peripherals.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     (board_kernel, platform, chip)
 }
 
@@ -88,7 +88,10 @@ struct NucleoF429ZI {
 
     scheduler: &'static RoundRobinSched<'static>,
     systick: cortexm4::systick::SysTick,
-    can: &'static capsules_extra::can::CanCapsule<'static, stm32f429zi::can::Can<'static>>,
+    can: &'static capsules_extra::can::CanCapsule<
+        'static,
+        capsules_extra::virtual_can::VirtualCan<'static, stm32f429zi::can::Can<'static>>,
+    >,
     date_time: &'static capsules_extra::date_time::DateTimeCapsule<
         'static,
         stm32f429zi::rtc::Rtc<'static>,
@@ -607,14 +610,14 @@ unsafe fn start() -> (
     .finalize(components::rng_component_static!(stm32f429zi::trng::Trng));
 
     // CAN
-    let can = components::can::CanComponent::new(
-        board_kernel,
-        capsules_extra::can::DRIVER_NUM,
-        &peripherals.can1,
-    )
-    .finalize(components::can_component_static!(
-        stm32f429zi::can::Can<'static>
-    ));
+    let can_mux = components::can::CanMuxComponent::new(&peripherals.can1).finalize(
+        components::can_mux_component_static!(stm32f429zi::can::Can<'static>),
+    );
+    let can =
+        components::can::CanComponent::new(board_kernel, capsules_extra::can::DRIVER_NUM, can_mux)
+            .finalize(components::can_component_static!(
+                stm32f429zi::can::Can<'static>
+            ));
 
     // RTC DATE TIME
     match peripherals.rtc.rtc_init() {
@@ -10,7 +10,7 @@
 #![cfg_attr(not(doc), no_main)]
 #![deny(missing_docs)]
 
-use core::ptr::addr_of_mut;
+use core::ptr::{addr_of, addr_of_mut};
 use kernel::component::Component;
 use kernel::debug;
 use kernel::hil::usb::Client;
@@ -240,6 +240,13 @@ pub unsafe fn main() {
         debug!("{:?}", err);
     });
 
+    // Lock the flash of the loaded processes until the next reset.
+    kernel::process::protect_loaded_processes(
+        &*addr_of!(PROCESSES),
+        &nrf52840_peripherals.nrf52.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     board_kernel.kernel_loop(
         &platform,
         chip,
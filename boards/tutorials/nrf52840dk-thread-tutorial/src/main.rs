@@ -226,6 +226,13 @@ pub unsafe fn main() {
         debug!("{:?}", err);
     });
 
+    // Lock the flash of the loaded processes until the next reset.
+    kernel::process::protect_loaded_processes(
+        &*addr_of!(PROCESSES),
+        &nrf52840_peripherals.nrf52.nvmc,
+        kernel::hil::flash::ProtectionLifetime::UntilReset,
+    );
+
     board_kernel.kernel_loop(
         &platform,
         chip,
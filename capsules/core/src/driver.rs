@@ -34,6 +34,7 @@ pub enum NUM {
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
     Can                   = 0x20007,
+    Uds                   = 0x20008,
 
     // Radio
     BleAdvertising        = 0x30000,
@@ -43,6 +44,8 @@ pub enum NUM {
     LoRaPhyGPIO           = 0x30004,
     Thread                = 0x30005,
     Eui64                 = 0x30006,
+    Coap                  = 0x30007,
+    BleGatt               = 0x30008,
 
     // Cryptography
     Rng                   = 0x40001,
@@ -51,12 +54,17 @@ pub enum NUM {
     CtapHid               = 0x40004,
     Sha                   = 0x40005,
     Aes                   = 0x40006,
+    Ctap                  = 0x40007,
+    CtapAttestation       = 0x40008,
 
     // Storage
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
     Kv                    = 0x50003,
+    Log                   = 0x50004,
+    FileSystem            = 0x50005,
+    TicKvApp              = 0x50006,
 
     // Sensors
     Temperature           = 0x60000,
@@ -94,6 +102,7 @@ pub enum NUM {
     TextScreen            = 0x90003,
     SevenSegment          = 0x90004,
     KeyboardHid           = 0x90005,
+    UsbHid                = 0x90006,
     DateTime              = 0x90007,
     CycleCount            = 0x90008,
     Servo                 = 0x90009,
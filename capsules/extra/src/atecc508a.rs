@@ -22,11 +22,19 @@
 //!
 //! Look at the `setup_and_lock_tock_config()` function for an example of
 //! setting up the device.
+//!
+//! The device signs hashes with the private key in a key slot (slot 0 by
+//! default, see `set_sign_key_slot()`), which never leaves the device. The
+//! `PubKey` implementation returns the public half of that key once it has
+//! been read with `read_public_key()` or created with `create_key_pair()`.
 
 use core::cell::Cell;
 use kernel::debug;
 use kernel::hil::i2c::{self, I2CClient, I2CDevice};
-use kernel::hil::public_key_crypto::signature::{ClientVerify, SignatureVerify};
+use kernel::hil::public_key_crypto::keys::PubKey;
+use kernel::hil::public_key_crypto::signature::{
+    ClientSign, ClientVerify, SignatureSign, SignatureVerify,
+};
 use kernel::hil::{digest, entropy, entropy::Entropy32};
 use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::utilities::leasable_buffer::{SubSlice, SubSliceMut, SubSliceMutImmut};
@@ -98,7 +106,6 @@ const COMMAND_OPCODE_SHA: u8 = 0x47; // Computes a SHA-256 or HMAC/SHA digest fo
 const COMMAND_OPCODE_GENKEY: u8 = 0x40; // Creates a key (public and/or private) and stores it in a memory key slot
 #[allow(dead_code)]
 const COMMAND_OPCODE_NONCE: u8 = 0x16; //
-#[allow(dead_code)]
 const COMMAND_OPCODE_SIGN: u8 = 0x41; // Create an ECC signature with contents of TempKey and designated key slot
 #[allow(dead_code)]
 const COMMAND_OPCODE_VERIFY: u8 = 0x45; // takes an ECDSA <R,S> signature and verifies that it is correctly generated from a given message and public key
@@ -110,6 +117,7 @@ const VERIFY_PARAM2_KEYTYPE_ECC: u8 = 0x0004; // When verify mode external, para
 #[allow(dead_code)]
 const VERIFY_PARAM2_KEYTYPE_NONECC: u8 = 0x0007; // When verify mode external, param2 should be KeyType, ds pg 89
 const NONCE_MODE_PASSTHROUGH: u8 = 0b00000011; // Operate in pass-through mode and Write TempKey with NumIn. datasheet pg 79
+const SIGN_MODE_EXTERNAL: u8 = 0b10000000; // Sign the external message loaded into TempKey, param2 = keyID
 
 const LOCK_MODE_ZONE_CONFIG: u8 = 0b10000000;
 const LOCK_MODE_ZONE_DATA_AND_OTP: u8 = 0b10000001;
@@ -125,7 +133,6 @@ const SHA_END: u8 = 2;
 #[allow(dead_code)]
 const SHA256_SIZE: usize = 32;
 const PUBLIC_KEY_SIZE: usize = 64;
-#[allow(dead_code)]
 const SIGNATURE_SIZE: usize = 64;
 #[allow(dead_code)]
 const BUFFER_SIZE: usize = 128;
@@ -146,7 +153,6 @@ const ATRCC508A_PROTOCOL_OVERHEAD: usize = ATRCC508A_PROTOCOL_FIELD_SIZE_COMMAND
     + ATRCC508A_PROTOCOL_FIELD_SIZE_PARAM2
     + ATRCC508A_PROTOCOL_FIELD_SIZE_CRC;
 
-#[allow(dead_code)]
 const GENKEY_MODE_PUBLIC: u8 = 0b00000000;
 const GENKEY_MODE_NEW_PRIVATE: u8 = 0b00000100;
 
@@ -165,6 +171,7 @@ enum Operation {
     LockZoneConfig(usize),
     LockResponse(usize),
     CreateKeyPair(usize, u16),
+    GenPublicKey(usize, u16),
     ReadKeyPair(usize),
     LockDataOtp(usize),
     LockSlot0(usize),
@@ -178,6 +185,8 @@ enum Operation {
     LoadTempKeyCheckNonce(usize),
     VerifySubmitData(usize),
     CompleteVerify(usize),
+    SignSubmitData(usize),
+    CompleteSign(usize),
 }
 
 pub struct Atecc508a<'a> {
@@ -202,6 +211,14 @@ pub struct Atecc508a<'a> {
     signature_data: TakeCell<'static, [u8; 64]>,
     ext_public_key: TakeCell<'static, [u8; 64]>,
 
+    sign_client: OptionalCell<&'a dyn ClientSign<32, 64>>,
+    // Whether the hash loaded into TempKey is signed rather than verified.
+    signing: Cell<bool>,
+    sign_key_slot: Cell<u16>,
+    // Public key of `sign_key_slot`, and the buffer it is read into.
+    sign_public_key: OptionalCell<&'static [u8]>,
+    sign_public_key_buffer: TakeCell<'static, [u8; PUBLIC_KEY_SIZE]>,
+
     wakeup_device: fn(),
 
     config_lock: Cell<bool>,
@@ -235,6 +252,11 @@ impl<'a> Atecc508a<'a> {
             message_data: TakeCell::empty(),
             signature_data: TakeCell::empty(),
             ext_public_key: TakeCell::empty(),
+            sign_client: OptionalCell::empty(),
+            signing: Cell::new(false),
+            sign_key_slot: Cell::new(0),
+            sign_public_key: OptionalCell::empty(),
+            sign_public_key_buffer: TakeCell::empty(),
             wakeup_device,
             config_lock: Cell::new(false),
             data_lock: Cell::new(false),
@@ -431,6 +453,37 @@ impl<'a> Atecc508a<'a> {
         Ok(())
     }
 
+    /// Read the public key of the private key in `slot` into `buffer`.
+    ///
+    /// Once the key has been read it is returned by `PubKey::pub_key()`, and
+    /// `slot` is used to sign hashes. If the key can't be read `buffer` is
+    /// dropped and `pub_key()` keeps returning `NODEVICE`.
+    pub fn read_public_key(
+        &self,
+        slot: u16,
+        buffer: &'static mut [u8; PUBLIC_KEY_SIZE],
+    ) -> Result<(), ErrorCode> {
+        if self.op.get() != Operation::Ready {
+            return Err(ErrorCode::BUSY);
+        }
+        self.sign_key_slot.set(slot);
+        self.sign_public_key.clear();
+        self.sign_public_key_buffer.replace(buffer);
+
+        self.op.set(Operation::GenPublicKey(0, slot));
+
+        (self.wakeup_device)();
+
+        self.send_command(COMMAND_OPCODE_GENKEY, GENKEY_MODE_PUBLIC, slot, 0);
+
+        Ok(())
+    }
+
+    /// Set the key slot whose private key is used by `sign()`.
+    pub fn set_sign_key_slot(&self, slot: u16) {
+        self.sign_key_slot.set(slot);
+    }
+
     /// Retrieve the public key from the slot.
     ///
     /// This can be called only after `create_key_pair()` has completed
@@ -490,6 +543,20 @@ impl<'a> Atecc508a<'a> {
         self.config_lock.get() && self.data_lock.get()
     }
 
+    /// Finish a `sign()` operation and return the buffers to the client.
+    fn sign_done(&self, result: Result<(), ErrorCode>) {
+        self.op.set(Operation::Ready);
+        self.signing.set(false);
+
+        if let (Some(hash), Some(signature)) =
+            (self.message_data.take(), self.signature_data.take())
+        {
+            self.sign_client.map(|client| {
+                client.signing_done(result, hash, signature);
+            });
+        }
+    }
+
     fn sha_update(&self) -> bool {
         let op_len = (self.hash_data.map_or(0, |buf| match buf {
             SubSliceMutImmut::Mutable(b) => {
@@ -851,6 +918,28 @@ impl I2CClient for Atecc508a<'_> {
                     .i2c
                     .read(buffer, RESPONSE_COUNT_SIZE + PUBLIC_KEY_SIZE + CRC_SIZE);
             }
+            Operation::GenPublicKey(run, slot) => {
+                if status == Err(i2c::Error::DataNak) || status == Err(i2c::Error::AddressNak) {
+                    self.buffer.replace(buffer);
+
+                    // The device isn't ready yet, try again
+                    if run == 10 {
+                        self.op.set(Operation::Ready);
+                        self.sign_public_key_buffer.take();
+                        return;
+                    }
+
+                    self.op.set(Operation::GenPublicKey(run + 1, slot));
+                    self.send_command(COMMAND_OPCODE_GENKEY, GENKEY_MODE_PUBLIC, slot, 0);
+                    return;
+                }
+
+                self.op.set(Operation::ReadKeyPair(0));
+
+                let _ = self
+                    .i2c
+                    .read(buffer, RESPONSE_COUNT_SIZE + PUBLIC_KEY_SIZE + CRC_SIZE);
+            }
             Operation::ReadKeyPair(run) => {
                 if status == Err(i2c::Error::DataNak) || status == Err(i2c::Error::AddressNak) {
                     // The device isn't ready yet, try again
@@ -858,6 +947,7 @@ impl I2CClient for Atecc508a<'_> {
                     if run == 5000 {
                         self.buffer.replace(buffer);
                         self.op.set(Operation::Ready);
+                        self.sign_public_key_buffer.take();
                         return;
                     }
 
@@ -875,6 +965,19 @@ impl I2CClient for Atecc508a<'_> {
                     self.public_key.set(pub_key);
                 });
 
+                // A response with only a status byte is an error.
+                let key_read = status.is_ok()
+                    && usize::from(buffer[0]) == RESPONSE_COUNT_SIZE + PUBLIC_KEY_SIZE + CRC_SIZE;
+                self.sign_public_key_buffer
+                    .take()
+                    .filter(|_| key_read)
+                    .map(|pub_key| {
+                        pub_key.copy_from_slice(
+                            &buffer[RESPONSE_COUNT_SIZE..(RESPONSE_COUNT_SIZE + PUBLIC_KEY_SIZE)],
+                        );
+                        self.sign_public_key.set(pub_key);
+                    });
+
                 self.op.set(Operation::Ready);
                 self.buffer.replace(buffer);
             }
@@ -1048,6 +1151,9 @@ impl I2CClient for Atecc508a<'_> {
                     // The device isn't ready yet, try again
                     if run == 10 {
                         self.op.set(Operation::Ready);
+                        if self.signing.get() {
+                            self.sign_done(Err(ErrorCode::FAIL));
+                        }
                         return;
                     }
 
@@ -1068,6 +1174,10 @@ impl I2CClient for Atecc508a<'_> {
                     // The device isn't ready yet, try again
                     if run == 10 {
                         self.op.set(Operation::Ready);
+                        self.buffer.replace(buffer);
+                        if self.signing.get() {
+                            self.sign_done(Err(ErrorCode::FAIL));
+                        }
                         return;
                     }
 
@@ -1079,6 +1189,24 @@ impl I2CClient for Atecc508a<'_> {
                     return;
                 }
 
+                if self.signing.get() {
+                    let loaded = buffer[RESPONSE_SIGNAL_INDEX] == ATRCC508A_SUCCESSFUL_TEMPKEY;
+                    self.buffer.replace(buffer);
+
+                    if loaded {
+                        self.op.set(Operation::SignSubmitData(0));
+                        self.send_command(
+                            COMMAND_OPCODE_SIGN,
+                            SIGN_MODE_EXTERNAL,
+                            self.sign_key_slot.get(),
+                            0,
+                        );
+                    } else {
+                        self.sign_done(Err(ErrorCode::FAIL));
+                    }
+                    return;
+                }
+
                 if buffer[RESPONSE_SIGNAL_INDEX] != ATRCC508A_SUCCESSFUL_TEMPKEY {
                     self.buffer.replace(buffer);
 
@@ -1185,6 +1313,65 @@ impl I2CClient for Atecc508a<'_> {
                     }
                 });
             }
+            Operation::SignSubmitData(run) => {
+                if status == Err(i2c::Error::DataNak) || status == Err(i2c::Error::AddressNak) {
+                    self.buffer.replace(buffer);
+
+                    // The device isn't ready yet, try again
+                    if run == 10 {
+                        self.sign_done(Err(ErrorCode::FAIL));
+                        return;
+                    }
+
+                    self.op.set(Operation::SignSubmitData(run + 1));
+                    self.send_command(
+                        COMMAND_OPCODE_SIGN,
+                        SIGN_MODE_EXTERNAL,
+                        self.sign_key_slot.get(),
+                        0,
+                    );
+                    return;
+                }
+
+                self.op.set(Operation::CompleteSign(0));
+                let _ = self
+                    .i2c
+                    .read(buffer, RESPONSE_COUNT_SIZE + SIGNATURE_SIZE + CRC_SIZE);
+            }
+            Operation::CompleteSign(run) => {
+                if status == Err(i2c::Error::DataNak) || status == Err(i2c::Error::AddressNak) {
+                    // The device isn't ready yet, try again
+                    // Signing takes up to 115ms
+                    if run == 1000 {
+                        self.buffer.replace(buffer);
+                        self.sign_done(Err(ErrorCode::FAIL));
+                        return;
+                    }
+
+                    self.op.set(Operation::CompleteSign(run + 1));
+                    let _ = self
+                        .i2c
+                        .read(buffer, RESPONSE_COUNT_SIZE + SIGNATURE_SIZE + CRC_SIZE);
+                    return;
+                }
+
+                // A response with only a status byte is an error.
+                let result = if status.is_ok()
+                    && usize::from(buffer[0]) == RESPONSE_COUNT_SIZE + SIGNATURE_SIZE + CRC_SIZE
+                {
+                    self.signature_data.map(|signature_data| {
+                        signature_data.copy_from_slice(
+                            &buffer[RESPONSE_COUNT_SIZE..(RESPONSE_COUNT_SIZE + SIGNATURE_SIZE)],
+                        );
+                    });
+                    Ok(())
+                } else {
+                    Err(ErrorCode::FAIL)
+                };
+
+                self.buffer.replace(buffer);
+                self.sign_done(result);
+            }
         }
     }
 }
@@ -1379,6 +1566,7 @@ impl<'a> SignatureVerify<'a, 32, 64> for Atecc508a<'a> {
         (self.wakeup_device)();
 
         self.op.set(Operation::LoadTempKeyNonce(0));
+        self.signing.set(false);
 
         self.buffer.map(|buffer| {
             buffer[ATRCC508A_PROTOCOL_FIELD_DATA..(ATRCC508A_PROTOCOL_FIELD_DATA + 32)]
@@ -1393,3 +1581,66 @@ impl<'a> SignatureVerify<'a, 32, 64> for Atecc508a<'a> {
         Ok(())
     }
 }
+
+impl<'a> SignatureSign<'a, 32, 64> for Atecc508a<'a> {
+    fn set_sign_client(&self, client: &'a dyn ClientSign<32, 64>) {
+        self.sign_client.set(client);
+    }
+
+    /// Sign the hash with the private key in the key slot set with
+    /// `set_sign_key_slot()` or `read_public_key()`. The signature is the
+    /// raw `r || s` P-256 signature.
+    fn sign(
+        &self,
+        hash: &'static mut [u8; 32],
+        signature: &'static mut [u8; 64],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 32], &'static mut [u8; 64])> {
+        if self.op.get() != Operation::Ready {
+            return Err((ErrorCode::BUSY, hash, signature));
+        }
+
+        (self.wakeup_device)();
+
+        self.op.set(Operation::LoadTempKeyNonce(0));
+        self.signing.set(true);
+
+        self.buffer.map(|buffer| {
+            buffer[ATRCC508A_PROTOCOL_FIELD_DATA..(ATRCC508A_PROTOCOL_FIELD_DATA + 32)]
+                .copy_from_slice(hash);
+        });
+
+        self.message_data.replace(hash);
+        self.signature_data.replace(signature);
+
+        self.send_command(COMMAND_OPCODE_NONCE, NONCE_MODE_PASSTHROUGH, 0x0000, 32);
+
+        Ok(())
+    }
+}
+
+/// The public key of the key slot used by `sign()`.
+impl PubKey for Atecc508a<'_> {
+    /// Use a public key that is known to belong to the signing key slot
+    /// instead of reading it from the device.
+    fn import_public_key(
+        &self,
+        public_key: &'static [u8],
+    ) -> Result<(), (ErrorCode, &'static [u8])> {
+        if public_key.len() != PUBLIC_KEY_SIZE {
+            return Err((ErrorCode::SIZE, public_key));
+        }
+        if self.sign_public_key.is_some() {
+            return Err((ErrorCode::BUSY, public_key));
+        }
+        self.sign_public_key.set(public_key);
+        Ok(())
+    }
+
+    fn pub_key(&self) -> Result<&'static [u8], ErrorCode> {
+        self.sign_public_key.get().ok_or(ErrorCode::NODEVICE)
+    }
+
+    fn len(&self) -> usize {
+        self.sign_public_key.map_or(0, |key| key.len())
+    }
+}
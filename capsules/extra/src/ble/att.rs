@@ -0,0 +1,728 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Attribute protocol server and GATT attribute database.
+//!
+//! The database holds the attributes of the GATT services this device
+//! offers. It always starts with the Generic Access service, which carries
+//! the device name, and the Generic Attribute service. Further primary
+//! services and their characteristics are appended at runtime with
+//! `add_service()` and `add_characteristic()`; attribute handles are their
+//! position in the database, starting at 1.
+//!
+//! Characteristic values are stored in the database, so the server answers
+//! reads without asking the owner of the characteristic. Characteristics
+//! that support notifications get a client characteristic configuration
+//! descriptor. The server keeps the default ATT_MTU of 23 bytes, so every
+//! attribute protocol PDU fits in a single link layer data PDU.
+
+use kernel::ErrorCode;
+
+/// The ATT_MTU this server uses.
+pub const ATT_MTU: usize = 23;
+
+/// Longest attribute value.
+pub const MAX_VALUE_LEN: usize = ATT_MTU - 3;
+
+/// Number of attributes of the services every database starts with.
+pub const BUILTIN_ATTRIBUTES: usize = 6;
+
+/// Characteristic properties, as in the characteristic declaration.
+pub mod properties {
+    pub const READ: u8 = 0x02;
+    pub const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+    pub const WRITE: u8 = 0x08;
+    pub const NOTIFY: u8 = 0x10;
+}
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part G], section 3 Service Interoperability Requirements
+const PRIMARY_SERVICE: u16 = 0x2800;
+const SECONDARY_SERVICE: u16 = 0x2801;
+const CHARACTERISTIC: u16 = 0x2803;
+const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+const GENERIC_ACCESS: u16 = 0x1800;
+const GENERIC_ATTRIBUTE: u16 = 0x1801;
+const DEVICE_NAME: u16 = 0x2A00;
+const APPEARANCE: u16 = 0x2A01;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4.8 Attribute Opcode Summary
+const ERROR_RSP: u8 = 0x01;
+const EXCHANGE_MTU_REQ: u8 = 0x02;
+const EXCHANGE_MTU_RSP: u8 = 0x03;
+const FIND_INFORMATION_REQ: u8 = 0x04;
+const FIND_INFORMATION_RSP: u8 = 0x05;
+const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+const READ_BY_TYPE_REQ: u8 = 0x08;
+const READ_BY_TYPE_RSP: u8 = 0x09;
+const READ_REQ: u8 = 0x0A;
+const READ_RSP: u8 = 0x0B;
+const READ_BLOB_REQ: u8 = 0x0C;
+const READ_BLOB_RSP: u8 = 0x0D;
+const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+const WRITE_REQ: u8 = 0x12;
+const WRITE_RSP: u8 = 0x13;
+const HANDLE_VALUE_NTF: u8 = 0x1B;
+const HANDLE_VALUE_CFM: u8 = 0x1E;
+const WRITE_CMD: u8 = 0x52;
+const COMMAND_FLAG: u8 = 0x40;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part F], section 3.4.1.1 Error Response
+const INVALID_HANDLE: u8 = 0x01;
+const READ_NOT_PERMITTED: u8 = 0x02;
+const WRITE_NOT_PERMITTED: u8 = 0x03;
+const INVALID_PDU: u8 = 0x04;
+const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+const INVALID_OFFSET: u8 = 0x07;
+const ATTRIBUTE_NOT_FOUND: u8 = 0x0A;
+const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0D;
+const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+
+/// The Bluetooth base UUID, least significant byte first.
+const BASE_UUID: [u8; 16] = [
+    0xFB, 0x34, 0x9B, 0x5F, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Uuid {
+    Uuid16(u16),
+    /// Least significant byte first, as on air.
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Parse a UUID in its on air format.
+    pub fn from_le_bytes(bytes: &[u8]) -> Option<Uuid> {
+        match bytes.len() {
+            2 => Some(Uuid::Uuid16(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(bytes);
+                Some(Uuid::Uuid128(uuid))
+            }
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    fn write_le_bytes(&self, buffer: &mut [u8]) -> usize {
+        match self {
+            Uuid::Uuid16(uuid) => buffer[..2].copy_from_slice(&uuid.to_le_bytes()),
+            Uuid::Uuid128(uuid) => buffer[..16].copy_from_slice(uuid),
+        }
+        self.len()
+    }
+
+    fn to_uuid128(self) -> [u8; 16] {
+        match self {
+            Uuid::Uuid16(uuid) => {
+                let mut full = BASE_UUID;
+                full[12..14].copy_from_slice(&uuid.to_le_bytes());
+                full
+            }
+            Uuid::Uuid128(uuid) => uuid,
+        }
+    }
+
+    /// Whether both are the same UUID, even if one of them is written in
+    /// its 128-bit form.
+    fn same_as(&self, other: Uuid) -> bool {
+        self.to_uuid128() == other.to_uuid128()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Service,
+    Characteristic,
+    Value,
+    ClientConfiguration,
+}
+
+#[derive(Copy, Clone)]
+pub struct Attribute {
+    uuid: Uuid,
+    kind: Kind,
+    /// Properties of the characteristic, for characteristic values.
+    properties: u8,
+    value: [u8; MAX_VALUE_LEN],
+    len: u8,
+}
+
+impl Attribute {
+    pub const EMPTY: Attribute = Attribute {
+        uuid: Uuid::Uuid16(0),
+        kind: Kind::Value,
+        properties: 0,
+        value: [0; MAX_VALUE_LEN],
+        len: 0,
+    };
+
+    fn new(uuid: Uuid, kind: Kind, properties: u8, value: &[u8]) -> Attribute {
+        let len = value.len().min(MAX_VALUE_LEN);
+        let mut attribute = Attribute {
+            uuid,
+            kind,
+            properties,
+            value: [0; MAX_VALUE_LEN],
+            len: len as u8,
+        };
+        attribute.value[..len].copy_from_slice(&value[..len]);
+        attribute
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.value[..self.len as usize]
+    }
+
+    fn readable(&self) -> bool {
+        match self.kind {
+            Kind::Value => self.properties & properties::READ != 0,
+            Kind::Service | Kind::Characteristic | Kind::ClientConfiguration => true,
+        }
+    }
+
+    fn writable(&self) -> bool {
+        match self.kind {
+            Kind::Value => {
+                self.properties & (properties::WRITE | properties::WRITE_WITHOUT_RESPONSE) != 0
+            }
+            Kind::ClientConfiguration => true,
+            Kind::Service | Kind::Characteristic => false,
+        }
+    }
+}
+
+/// What handling an attribute protocol PDU did.
+#[derive(Debug, PartialEq)]
+pub struct Handled {
+    /// Length of the response to send, 0 if there is none.
+    pub response_len: usize,
+    /// Handle of the characteristic value the client wrote, if any.
+    pub written: Option<u16>,
+}
+
+/// An error response to send, as the handle it is about and the error code.
+type AttError = (u16, u8);
+
+pub struct AttributeDatabase<'b> {
+    attributes: &'b mut [Attribute],
+    len: usize,
+}
+
+impl<'b> AttributeDatabase<'b> {
+    /// Create a database in `attributes`, which must hold at least
+    /// `BUILTIN_ATTRIBUTES` attributes, with the device name `device_name`.
+    /// Names longer than `MAX_VALUE_LEN` are truncated.
+    pub fn new(attributes: &'b mut [Attribute], device_name: &[u8]) -> AttributeDatabase<'b> {
+        let mut database = AttributeDatabase { attributes, len: 0 };
+        let _ = database.add_service(Uuid::Uuid16(GENERIC_ACCESS));
+        let _ = database.push_characteristic(Uuid::Uuid16(DEVICE_NAME), properties::READ);
+        let name_len = device_name.len().min(MAX_VALUE_LEN);
+        let _ = database.set_value_unchecked(3, &device_name[..name_len]);
+        // Appearance: unknown.
+        let _ = database.push_characteristic(Uuid::Uuid16(APPEARANCE), properties::READ);
+        let _ = database.set_value_unchecked(5, &[0, 0]);
+        let _ = database.add_service(Uuid::Uuid16(GENERIC_ATTRIBUTE));
+        database
+    }
+
+    fn push(&mut self, attribute: Attribute) -> Result<u16, ErrorCode> {
+        let slot = self.attributes.get_mut(self.len).ok_or(ErrorCode::NOMEM)?;
+        *slot = attribute;
+        self.len += 1;
+        Ok(self.len as u16)
+    }
+
+    fn get(&self, handle: u16) -> Option<&Attribute> {
+        match handle {
+            0 => None,
+            _ => self.attributes[..self.len].get(handle as usize - 1),
+        }
+    }
+
+    /// The handles of the attributes from `start` to `end`, inclusive.
+    fn handles(&self, start: u16, end: u16) -> impl Iterator<Item = (u16, &Attribute)> {
+        let first = start.max(1) as usize;
+        let last = (end as usize).min(self.len);
+        (first..=last).filter_map(|handle| {
+            self.get(handle as u16)
+                .map(|attribute| (handle as u16, attribute))
+        })
+    }
+
+    /// Handle of the last attribute of the service declared at `handle`.
+    fn group_end(&self, handle: u16) -> u16 {
+        self.handles(handle + 1, u16::MAX)
+            .find(|(_, attribute)| attribute.kind == Kind::Service)
+            .map_or(self.len as u16, |(next, _)| next - 1)
+    }
+
+    /// Remove every service but the builtin ones.
+    pub fn clear_services(&mut self) {
+        self.len = self.len.min(BUILTIN_ATTRIBUTES);
+    }
+
+    /// Append a primary service, and return the handle of its declaration.
+    pub fn add_service(&mut self, uuid: Uuid) -> Result<u16, ErrorCode> {
+        let mut value = [0; 16];
+        let len = uuid.write_le_bytes(&mut value);
+        self.push(Attribute::new(
+            Uuid::Uuid16(PRIMARY_SERVICE),
+            Kind::Service,
+            0,
+            &value[..len],
+        ))
+    }
+
+    fn push_characteristic(&mut self, uuid: Uuid, properties: u8) -> Result<u16, ErrorCode> {
+        let needed = if properties & properties::NOTIFY != 0 {
+            3
+        } else {
+            2
+        };
+        if self.len + needed > self.attributes.len() {
+            return Err(ErrorCode::NOMEM);
+        }
+        let value_handle = self.len as u16 + 2;
+        let mut declaration = [0; 19];
+        declaration[0] = properties;
+        declaration[1..3].copy_from_slice(&value_handle.to_le_bytes());
+        let len = 3 + uuid.write_le_bytes(&mut declaration[3..]);
+        self.push(Attribute::new(
+            Uuid::Uuid16(CHARACTERISTIC),
+            Kind::Characteristic,
+            0,
+            &declaration[..len],
+        ))?;
+        self.push(Attribute::new(uuid, Kind::Value, properties, &[]))?;
+        if properties & properties::NOTIFY != 0 {
+            self.push(Attribute::new(
+                Uuid::Uuid16(CLIENT_CHARACTERISTIC_CONFIGURATION),
+                Kind::ClientConfiguration,
+                0,
+                &[0, 0],
+            ))?;
+        }
+        Ok(value_handle)
+    }
+
+    /// Append a characteristic with `properties` to the last service, and
+    /// return the handle of its value.
+    pub fn add_characteristic(&mut self, uuid: Uuid, properties: u8) -> Result<u16, ErrorCode> {
+        let supported = properties::READ
+            | properties::WRITE_WITHOUT_RESPONSE
+            | properties::WRITE
+            | properties::NOTIFY;
+        if self.len <= BUILTIN_ATTRIBUTES || properties == 0 || properties & !supported != 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        self.push_characteristic(uuid, properties)
+    }
+
+    fn set_value_unchecked(&mut self, handle: u16, value: &[u8]) -> Result<(), ErrorCode> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let attribute = match handle {
+            0 => None,
+            _ => self.attributes[..self.len].get_mut(handle as usize - 1),
+        }
+        .ok_or(ErrorCode::INVAL)?;
+        attribute.value[..value.len()].copy_from_slice(value);
+        attribute.len = value.len() as u8;
+        Ok(())
+    }
+
+    /// Set the value of the characteristic value attribute at `handle`.
+    pub fn set_value(&mut self, handle: u16, value: &[u8]) -> Result<(), ErrorCode> {
+        match self.get(handle) {
+            Some(attribute) if attribute.kind == Kind::Value => {
+                if (handle as usize) <= BUILTIN_ATTRIBUTES {
+                    return Err(ErrorCode::INVAL);
+                }
+                self.set_value_unchecked(handle, value)
+            }
+            _ => Err(ErrorCode::INVAL),
+        }
+    }
+
+    /// The value of the attribute at `handle`.
+    pub fn value(&self, handle: u16) -> Option<&[u8]> {
+        self.get(handle).map(Attribute::value)
+    }
+
+    /// Whether the client enabled notifications for the characteristic
+    /// whose value is at `handle`.
+    pub fn notifications_enabled(&self, handle: u16) -> bool {
+        let configuration = handle.checked_add(1).and_then(|handle| self.get(handle));
+        configuration.is_some_and(|attribute| {
+            attribute.kind == Kind::ClientConfiguration && attribute.value[0] & 0x01 != 0
+        })
+    }
+
+    /// Forget the configuration of the client, when it disconnects.
+    pub fn reset_client_configurations(&mut self) {
+        self.attributes[..self.len]
+            .iter_mut()
+            .filter(|attribute| attribute.kind == Kind::ClientConfiguration)
+            .for_each(|attribute| attribute.value = [0; MAX_VALUE_LEN]);
+    }
+
+    /// Write a notification of the value at `handle` to `pdu`, and return
+    /// its length.
+    pub fn notification(&self, handle: u16, pdu: &mut [u8]) -> Result<usize, ErrorCode> {
+        let attribute = self.get(handle).ok_or(ErrorCode::INVAL)?;
+        if attribute.properties & properties::NOTIFY == 0 {
+            return Err(ErrorCode::INVAL);
+        }
+        let value = attribute.value();
+        pdu[0] = HANDLE_VALUE_NTF;
+        pdu[1..3].copy_from_slice(&handle.to_le_bytes());
+        pdu[3..3 + value.len()].copy_from_slice(value);
+        Ok(3 + value.len())
+    }
+
+    /// Handle the attribute protocol PDU `request` from the client, and
+    /// write the response to `response`, which must hold `ATT_MTU` bytes.
+    pub fn handle_request(&mut self, request: &[u8], response: &mut [u8]) -> Handled {
+        let mut handled = Handled {
+            response_len: 0,
+            written: None,
+        };
+        let Some(&opcode) = request.first() else {
+            return handled;
+        };
+        let u16_at = |i: usize| u16::from_le_bytes([request[i], request[i + 1]]);
+
+        let result = match (opcode, request.len()) {
+            (EXCHANGE_MTU_REQ, 3) => {
+                response[0] = EXCHANGE_MTU_RSP;
+                response[1..3].copy_from_slice(&(ATT_MTU as u16).to_le_bytes());
+                Ok(3)
+            }
+            (FIND_INFORMATION_REQ, 5) => self.find_information(u16_at(1), u16_at(3), response),
+            (FIND_BY_TYPE_VALUE_REQ, 7..) => {
+                self.find_by_type_value(u16_at(1), u16_at(3), u16_at(5), &request[7..], response)
+            }
+            (READ_BY_TYPE_REQ, 7 | 21) => Uuid::from_le_bytes(&request[5..])
+                .map_or(Err((0, INVALID_PDU)), |uuid| {
+                    self.read_by_type(u16_at(1), u16_at(3), uuid, response)
+                }),
+            (READ_REQ, 3) => self.read(u16_at(1), 0, READ_RSP, response),
+            (READ_BLOB_REQ, 5) => self.read(u16_at(1), u16_at(3) as usize, READ_BLOB_RSP, response),
+            (READ_BY_GROUP_TYPE_REQ, 7 | 21) => Uuid::from_le_bytes(&request[5..])
+                .map_or(Err((0, INVALID_PDU)), |uuid| {
+                    self.read_by_group_type(u16_at(1), u16_at(3), uuid, response)
+                }),
+            (WRITE_REQ, 3..) => self.write(u16_at(1), &request[3..]).map(|written| {
+                handled.written = written;
+                response[0] = WRITE_RSP;
+                1
+            }),
+            (WRITE_CMD, 3..) => {
+                handled.written = self.write(u16_at(1), &request[3..]).unwrap_or(None);
+                Ok(0)
+            }
+            (HANDLE_VALUE_CFM, _) => Ok(0),
+            _ if opcode & COMMAND_FLAG != 0 => Ok(0),
+            (
+                EXCHANGE_MTU_REQ
+                | FIND_INFORMATION_REQ
+                | FIND_BY_TYPE_VALUE_REQ
+                | READ_BY_TYPE_REQ
+                | READ_REQ
+                | READ_BLOB_REQ
+                | READ_BY_GROUP_TYPE_REQ
+                | WRITE_REQ,
+                _,
+            ) => Err((0, INVALID_PDU)),
+            _ => Err((0, REQUEST_NOT_SUPPORTED)),
+        };
+
+        handled.response_len = result.unwrap_or_else(|(handle, error)| {
+            response[0] = ERROR_RSP;
+            response[1] = opcode;
+            response[2..4].copy_from_slice(&handle.to_le_bytes());
+            response[4] = error;
+            5
+        });
+        handled
+    }
+
+    fn check_range(start: u16, end: u16) -> Result<(), AttError> {
+        if start == 0 || start > end {
+            Err((start, INVALID_HANDLE))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn find_information(
+        &self,
+        start: u16,
+        end: u16,
+        response: &mut [u8],
+    ) -> Result<usize, AttError> {
+        Self::check_range(start, end)?;
+        response[0] = FIND_INFORMATION_RSP;
+        let mut len = 2;
+        let mut uuid_len = None;
+        for (handle, attribute) in self.handles(start, end) {
+            if *uuid_len.get_or_insert(attribute.uuid.len()) != attribute.uuid.len()
+                || len + 2 + attribute.uuid.len() > ATT_MTU
+            {
+                break;
+            }
+            response[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+            len += 2 + attribute.uuid.write_le_bytes(&mut response[len + 2..]);
+        }
+        response[1] = match uuid_len {
+            Some(2) => 0x01,
+            Some(_) => 0x02,
+            None => return Err((start, ATTRIBUTE_NOT_FOUND)),
+        };
+        Ok(len)
+    }
+
+    fn find_by_type_value(
+        &self,
+        start: u16,
+        end: u16,
+        attribute_type: u16,
+        value: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, AttError> {
+        Self::check_range(start, end)?;
+        response[0] = FIND_BY_TYPE_VALUE_RSP;
+        let mut len = 1;
+        let found = self.handles(start, end).filter(|(_, attribute)| {
+            attribute.uuid.same_as(Uuid::Uuid16(attribute_type)) && attribute.value() == value
+        });
+        for (handle, attribute) in found {
+            if len + 4 > ATT_MTU {
+                break;
+            }
+            let group_end = match attribute.kind {
+                Kind::Service => self.group_end(handle),
+                _ => handle,
+            };
+            response[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+            response[len + 2..len + 4].copy_from_slice(&group_end.to_le_bytes());
+            len += 4;
+        }
+        if len == 1 {
+            return Err((start, ATTRIBUTE_NOT_FOUND));
+        }
+        Ok(len)
+    }
+
+    fn read_by_type(
+        &self,
+        start: u16,
+        end: u16,
+        uuid: Uuid,
+        response: &mut [u8],
+    ) -> Result<usize, AttError> {
+        Self::check_range(start, end)?;
+        response[0] = READ_BY_TYPE_RSP;
+        let mut len = 2;
+        let mut entry_len = None;
+        for (handle, attribute) in self
+            .handles(start, end)
+            .filter(|(_, attribute)| attribute.uuid.same_as(uuid))
+        {
+            if !attribute.readable() {
+                if entry_len.is_none() {
+                    return Err((handle, READ_NOT_PERMITTED));
+                }
+                break;
+            }
+            let value = &attribute.value()[..attribute.value().len().min(ATT_MTU - 4)];
+            if *entry_len.get_or_insert(2 + value.len()) != 2 + value.len()
+                || len + 2 + value.len() > ATT_MTU
+            {
+                break;
+            }
+            response[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+            response[len + 2..len + 2 + value.len()].copy_from_slice(value);
+            len += 2 + value.len();
+        }
+        response[1] = entry_len.ok_or((start, ATTRIBUTE_NOT_FOUND))? as u8;
+        Ok(len)
+    }
+
+    fn read_by_group_type(
+        &self,
+        start: u16,
+        end: u16,
+        uuid: Uuid,
+        response: &mut [u8],
+    ) -> Result<usize, AttError> {
+        Self::check_range(start, end)?;
+        if uuid.same_as(Uuid::Uuid16(SECONDARY_SERVICE)) {
+            return Err((start, ATTRIBUTE_NOT_FOUND));
+        }
+        if !uuid.same_as(Uuid::Uuid16(PRIMARY_SERVICE)) {
+            return Err((start, UNSUPPORTED_GROUP_TYPE));
+        }
+        response[0] = READ_BY_GROUP_TYPE_RSP;
+        let mut len = 2;
+        let mut entry_len = None;
+        for (handle, attribute) in self
+            .handles(start, end)
+            .filter(|(_, attribute)| attribute.kind == Kind::Service)
+        {
+            let value = attribute.value();
+            if *entry_len.get_or_insert(4 + value.len()) != 4 + value.len()
+                || len + 4 + value.len() > ATT_MTU
+            {
+                break;
+            }
+            response[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+            response[len + 2..len + 4].copy_from_slice(&self.group_end(handle).to_le_bytes());
+            response[len + 4..len + 4 + value.len()].copy_from_slice(value);
+            len += 4 + value.len();
+        }
+        response[1] = entry_len.ok_or((start, ATTRIBUTE_NOT_FOUND))? as u8;
+        Ok(len)
+    }
+
+    fn read(
+        &self,
+        handle: u16,
+        offset: usize,
+        opcode: u8,
+        response: &mut [u8],
+    ) -> Result<usize, AttError> {
+        let attribute = self.get(handle).ok_or((handle, INVALID_HANDLE))?;
+        if !attribute.readable() {
+            return Err((handle, READ_NOT_PERMITTED));
+        }
+        let value = attribute
+            .value()
+            .get(offset..)
+            .ok_or((handle, INVALID_OFFSET))?;
+        let len = value.len().min(ATT_MTU - 1);
+        response[0] = opcode;
+        response[1..1 + len].copy_from_slice(&value[..len]);
+        Ok(1 + len)
+    }
+
+    /// Write `value` to the attribute at `handle`. Returns the handle if it
+    /// is a characteristic value.
+    fn write(&mut self, handle: u16, value: &[u8]) -> Result<Option<u16>, AttError> {
+        let attribute = self.get(handle).ok_or((handle, INVALID_HANDLE))?;
+        if !attribute.writable() {
+            return Err((handle, WRITE_NOT_PERMITTED));
+        }
+        let kind = attribute.kind;
+        if kind == Kind::ClientConfiguration && value.len() != 2 {
+            return Err((handle, INVALID_ATTRIBUTE_VALUE_LENGTH));
+        }
+        self.set_value_unchecked(handle, value)
+            .map_err(|_| (handle, INVALID_ATTRIBUTE_VALUE_LENGTH))?;
+        Ok((kind == Kind::Value).then_some(handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(attributes: &mut [Attribute]) -> AttributeDatabase<'_> {
+        let mut database = AttributeDatabase::new(attributes, b"Tock");
+        database.add_service(Uuid::Uuid16(0x180F)).unwrap();
+        let value = database
+            .add_characteristic(Uuid::Uuid16(0x2A19), properties::READ | properties::NOTIFY)
+            .unwrap();
+        database.set_value(value, &[87]).unwrap();
+        database
+    }
+
+    #[test]
+    fn discovers_primary_services() {
+        let mut attributes = [Attribute::EMPTY; 16];
+        let mut database = database(&mut attributes);
+        let mut response = [0; ATT_MTU];
+        let handled =
+            database.handle_request(&[0x10, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28], &mut response);
+        assert_eq!(handled.response_len, 20);
+        assert_eq!(
+            response[..20],
+            [
+                0x11, 6, // response, entry length
+                1, 0, 5, 0, 0x00, 0x18, // Generic Access
+                6, 0, 6, 0, 0x01, 0x18, // Generic Attribute
+                7, 0, 10, 0, 0x0F, 0x18, // Battery
+            ]
+        );
+    }
+
+    #[test]
+    fn discovers_and_reads_characteristics() {
+        let mut attributes = [Attribute::EMPTY; 16];
+        let mut database = database(&mut attributes);
+        let mut response = [0; ATT_MTU];
+        let handled =
+            database.handle_request(&[0x08, 0x07, 0x00, 0x0A, 0x00, 0x03, 0x28], &mut response);
+        assert_eq!(handled.response_len, 9);
+        assert_eq!(response[..9], [0x09, 7, 8, 0, 0x12, 9, 0, 0x19, 0x2A]);
+
+        let handled = database.handle_request(&[0x0A, 9, 0], &mut response);
+        assert_eq!(handled.response_len, 2);
+        assert_eq!(response[..2], [0x0B, 87]);
+
+        let handled = database.handle_request(&[0x0A, 3, 0], &mut response);
+        assert_eq!(response[..handled.response_len], *b"\x0BTock");
+    }
+
+    #[test]
+    fn enables_notifications() {
+        let mut attributes = [Attribute::EMPTY; 16];
+        let mut database = database(&mut attributes);
+        let mut response = [0; ATT_MTU];
+        assert!(!database.notifications_enabled(9));
+        let handled = database.handle_request(&[0x12, 10, 0, 0x01, 0x00], &mut response);
+        assert_eq!(
+            handled,
+            Handled {
+                response_len: 1,
+                written: None
+            }
+        );
+        assert!(database.notifications_enabled(9));
+        assert_eq!(database.notification(9, &mut response), Ok(4));
+        assert_eq!(response[..4], [0x1B, 9, 0, 87]);
+
+        database.reset_client_configurations();
+        assert!(!database.notifications_enabled(9));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut attributes = [Attribute::EMPTY; 16];
+        let mut database = database(&mut attributes);
+        let mut response = [0; ATT_MTU];
+        // Reading a handle that does not exist.
+        database.handle_request(&[0x0A, 0x20, 0], &mut response);
+        assert_eq!(response[..5], [0x01, 0x0A, 0x20, 0, INVALID_HANDLE]);
+        // Writing a read only characteristic.
+        database.handle_request(&[0x12, 9, 0, 1], &mut response);
+        assert_eq!(response[..5], [0x01, 0x12, 9, 0, WRITE_NOT_PERMITTED]);
+        // A request the server does not know.
+        database.handle_request(&[0x20, 0], &mut response);
+        assert_eq!(response[..5], [0x01, 0x20, 0, 0, REQUEST_NOT_SUPPORTED]);
+        // Commands are never answered.
+        let handled = database.handle_request(&[0x52, 9, 0, 1], &mut response);
+        assert_eq!(handled.response_len, 0);
+    }
+}
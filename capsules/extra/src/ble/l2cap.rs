@@ -0,0 +1,76 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Logical Link Control and Adaptation Protocol for Bluetooth Low Energy.
+//!
+//! Only the fixed channels of an LE link exist: the attribute protocol
+//! channel, the signaling channel and the security manager channel. This
+//! device offers no signaling commands and does not pair, so requests on the
+//! latter two channels are rejected with `reject()`.
+
+/// Length of the basic L2CAP header.
+pub const HEADER_LEN: usize = 4;
+
+/// Channel of the attribute protocol.
+pub const ATT_CID: u16 = 0x0004;
+/// Channel of the LE signaling commands.
+pub const SIGNALING_CID: u16 = 0x0005;
+/// Channel of the security manager protocol.
+pub const SMP_CID: u16 = 0x0006;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part A], section 4 Signaling Packet Formats
+const COMMAND_REJECT: u8 = 0x01;
+const CONNECTION_PARAMETER_UPDATE_RESPONSE: u8 = 0x13;
+const LE_CREDIT_BASED_CONNECTION_RESPONSE: u8 = 0x15;
+const COMMAND_NOT_UNDERSTOOD: u16 = 0x0000;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 3, Part H], section 3.5 Pairing Methods
+const PAIRING_REQUEST: u8 = 0x01;
+const PAIRING_FAILED: u8 = 0x05;
+const PAIRING_NOT_SUPPORTED: u8 = 0x05;
+
+/// Parse the basic header at the start of `pdu` into the payload length and
+/// the channel.
+pub fn parse_header(pdu: &[u8]) -> Option<(usize, u16)> {
+    if pdu.len() < HEADER_LEN {
+        return None;
+    }
+    let len = u16::from_le_bytes([pdu[0], pdu[1]]) as usize;
+    let cid = u16::from_le_bytes([pdu[2], pdu[3]]);
+    Some((len, cid))
+}
+
+/// Write the basic header for a `payload_len` byte payload on channel `cid`
+/// to the start of `pdu`.
+pub fn write_header(pdu: &mut [u8], payload_len: usize, cid: u16) {
+    pdu[0..2].copy_from_slice(&(payload_len as u16).to_le_bytes());
+    pdu[2..4].copy_from_slice(&cid.to_le_bytes());
+}
+
+/// Build the payload that rejects the request `payload` received on the
+/// signaling or security manager channel `cid`. Returns its length, or
+/// `None` if nothing should be sent.
+pub fn reject(cid: u16, payload: &[u8], response: &mut [u8]) -> Option<usize> {
+    match cid {
+        SIGNALING_CID if payload.len() >= 4 => match payload[0] {
+            // Responses to requests this device never sends.
+            COMMAND_REJECT
+            | CONNECTION_PARAMETER_UPDATE_RESPONSE
+            | LE_CREDIT_BASED_CONNECTION_RESPONSE => None,
+            _ => {
+                response[0] = COMMAND_REJECT;
+                response[1] = payload[1];
+                response[2..4].copy_from_slice(&2u16.to_le_bytes());
+                response[4..6].copy_from_slice(&COMMAND_NOT_UNDERSTOOD.to_le_bytes());
+                Some(6)
+            }
+        },
+        SMP_CID if payload.first() == Some(&PAIRING_REQUEST) => {
+            response[0] = PAIRING_FAILED;
+            response[1] = PAIRING_NOT_SUPPORTED;
+            Some(2)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,985 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Bluetooth Low Energy link layer for the peripheral (slave) role.
+//!
+//! The link layer sends connectable undirected advertisements (`ADV_IND`),
+//! answers scan requests and accepts one connection at a time. While
+//! connected it follows the central's connection events, hops channels with
+//! channel selection algorithm #1, acknowledges and retransmits data PDUs,
+//! and handles the link layer control procedures a Bluetooth 4.0 peripheral
+//! has to support: connection parameter updates, channel map updates,
+//! feature and version exchange, and termination. Encryption is rejected.
+//!
+//! ```text
+//!          +------------------------+
+//!          | L2CAP (LinkLayerClient)|
+//!          +------------------------+
+//!                      |
+//!          +------------------------+
+//!          | LinkLayer (this file)  |
+//!          +------------------------+
+//!                |            |
+//!   BleConnectionRadio      Alarm
+//! ```
+//!
+//! Data PDUs carry at most 27 bytes, as without the data length extension.
+//! Only one data PDU is queued for transmission and only one received data
+//! PDU is buffered. While the received PDU has not been handed to the client
+//! yet, new data PDUs are not acknowledged, so the central retransmits them
+//! later. Received data, acknowledgements and connections are reported from
+//! a deferred call, to keep the radio interrupt short.
+//!
+//! The advertising and connection timing is derived from the alarm. The link
+//! layer widens its receive windows by the sleep clock accuracy of both
+//! devices and one tick of a 32 kHz clock, so a low frequency alarm works.
+//!
+//! The device address is always reported as a random static address.
+
+use core::cell::Cell;
+
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_connection::{self, BleConnectionRadio, ConnectionClient};
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks, Ticks};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+/// Longest payload of a data channel PDU.
+pub const MAX_DATA_PAYLOAD: usize = 27;
+
+/// Longest advertising data.
+pub const MAX_ADVERTISING_DATA: usize = 31;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 2, Part D], Error Codes
+/// The connection was terminated by the central.
+pub const REMOTE_USER_TERMINATED: u8 = 0x13;
+/// The connection was terminated by this device.
+pub const LOCAL_HOST_TERMINATED: u8 = 0x16;
+/// The supervision timeout expired.
+pub const CONNECTION_TIMEOUT: u8 = 0x08;
+/// The central never sent a packet after the connection request.
+pub const CONNECTION_FAILED_TO_BE_ESTABLISHED: u8 = 0x3E;
+/// The central scheduled a procedure for an event that already passed.
+pub const INSTANT_PASSED: u8 = 0x28;
+const UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1A;
+
+// Advertising channel PDU header
+const ADV_IND: u8 = 0x00;
+const SCAN_REQ: u8 = 0x03;
+const SCAN_RSP: u8 = 0x04;
+const CONNECT_IND: u8 = 0x05;
+const PDU_TYPE_MASK: u8 = 0x0F;
+const TX_ADD: u8 = 1 << 6;
+const RX_ADD: u8 = 1 << 7;
+
+// Data channel PDU header
+const LLID_MASK: u8 = 0x03;
+const LLID_CONTINUATION: u8 = 0x01;
+const LLID_START: u8 = 0x02;
+const LLID_CONTROL: u8 = 0x03;
+const NESN: u8 = 1 << 2;
+const SN: u8 = 1 << 3;
+const MD: u8 = 1 << 4;
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.4.2 LL Control PDU
+const LL_CONNECTION_UPDATE_IND: u8 = 0x00;
+const LL_CHANNEL_MAP_IND: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_ENC_REQ: u8 = 0x03;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0C;
+const LL_REJECT_IND: u8 = 0x0D;
+
+/// Longest control PDU this link layer sends (`LL_FEATURE_RSP`).
+const MAX_CONTROL_PAYLOAD: usize = 9;
+
+/// Bluetooth 4.0, the version whose link layer this implements.
+const VERSION_4_0: u8 = 0x06;
+/// Company identifier reserved for devices without one.
+const COMPANY_ID: u16 = 0xFFFF;
+
+/// How long the link layer listens for requests after an advertisement.
+const ADVERTISING_CHANNEL_WINDOW_US: u32 = 1500;
+/// Upper bound of the random delay added to each advertising interval.
+const ADVERTISING_DELAY_MAX_US: u32 = 10_000;
+/// Time between the end of `CONNECT_IND` and the first transmit window.
+const TRANSMIT_WINDOW_DELAY_US: u32 = 1250;
+/// Unit of the connection timing parameters.
+const TIMING_UNIT_US: u32 = 1250;
+/// Unit of the supervision timeout.
+const TIMEOUT_UNIT_US: u32 = 10_000;
+/// How early the radio is started before a receive window opens.
+const RAMP_UP_US: u32 = 200;
+/// Accuracy of the clock driving the alarm.
+const SLEEP_CLOCK_PPM: u32 = 50;
+/// Added to every receive window for the resolution of a 32 kHz alarm.
+const CLOCK_JITTER_US: u32 = 32;
+/// How long to wait for the next PDU of a connection event.
+const NEXT_PDU_TIMEOUT_US: u32 = 600;
+/// Connection events end this long before the next one starts.
+const EVENT_END_GUARD_US: u32 = 1250;
+/// Sleep clock accuracy in ppm, indexed by the `SCA` field of `CONNECT_IND`.
+const SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+
+/// Number of data channels.
+const DATA_CHANNELS: u8 = 37;
+
+/// Time it takes to transmit a PDU with a `payload_len` byte payload at
+/// 1 Mbit/s, including preamble, access address and CRC.
+const fn airtime_us(payload_len: usize) -> u32 {
+    (1 + 4 + 2 + payload_len as u32 + 3) * 8
+}
+
+/// Data channels a connection uses.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct ChannelMap {
+    map: [u8; 5],
+}
+
+impl ChannelMap {
+    fn is_used(&self, channel: u8) -> bool {
+        channel < DATA_CHANNELS && self.map[channel as usize / 8] & (1 << (channel % 8)) != 0
+    }
+
+    fn used_channels(&self) -> u8 {
+        (0..DATA_CHANNELS).filter(|&ch| self.is_used(ch)).count() as u8
+    }
+
+    /// Channel selection algorithm #1.
+    ///
+    /// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.5.8.2.
+    /// Returns the next unmapped channel and the channel to use.
+    fn select(&self, last_unmapped: u8, hop: u8) -> (u8, u8) {
+        let unmapped = (last_unmapped + hop) % DATA_CHANNELS;
+        if self.is_used(unmapped) {
+            return (unmapped, unmapped);
+        }
+        let remapping_index = unmapped % self.used_channels();
+        let channel = (0..DATA_CHANNELS)
+            .filter(|&ch| self.is_used(ch))
+            .nth(remapping_index as usize)
+            .unwrap_or(0);
+        (unmapped, channel)
+    }
+}
+
+/// The LLData of a `CONNECT_IND` PDU.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct ConnectionRequest {
+    access_address: u32,
+    crc_init: u32,
+    win_size: u8,
+    win_offset: u16,
+    interval: u16,
+    timeout: u16,
+    channel_map: ChannelMap,
+    hop: u8,
+    sca: u8,
+}
+
+impl ConnectionRequest {
+    fn parse(ll_data: &[u8]) -> Option<ConnectionRequest> {
+        if ll_data.len() != 22 {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([ll_data[i], ll_data[i + 1]]);
+        let mut map = [0; 5];
+        map.copy_from_slice(&ll_data[16..21]);
+        map[4] &= 0x1F;
+        let request = ConnectionRequest {
+            access_address: u32::from_le_bytes([ll_data[0], ll_data[1], ll_data[2], ll_data[3]]),
+            crc_init: u32::from_le_bytes([ll_data[4], ll_data[5], ll_data[6], 0]),
+            win_size: ll_data[7],
+            win_offset: u16_at(8),
+            interval: u16_at(10),
+            timeout: u16_at(14),
+            channel_map: ChannelMap { map },
+            hop: ll_data[21] & 0x1F,
+            sca: ll_data[21] >> 5,
+        };
+        let valid = (6..=3200).contains(&request.interval)
+            && (10..=3200).contains(&request.timeout)
+            && (5..=16).contains(&request.hop)
+            && request.channel_map.used_channels() >= 2;
+        valid.then_some(request)
+    }
+}
+
+/// A connection parameter update waiting for its instant.
+#[derive(Copy, Clone)]
+struct ConnectionUpdate {
+    win_size: u8,
+    win_offset: u16,
+    interval: u16,
+    timeout: u16,
+    instant: u16,
+}
+
+/// The PDU the link layer sent last and waits to be acknowledged.
+#[derive(Copy, Clone, PartialEq)]
+enum InFlight {
+    Empty,
+    Control,
+    Data,
+}
+
+#[derive(Copy, Clone)]
+struct Connection<T: Ticks> {
+    access_address: u32,
+    crc_init: u32,
+    channel_map: ChannelMap,
+    hop: u8,
+    last_unmapped: u8,
+    interval_us: u32,
+    timeout_us: u32,
+    central_ppm: u32,
+    event_counter: u16,
+    /// Start of the first packet of the last event a packet was received in.
+    anchor: T,
+    /// Time from `anchor` to the start of the next connection event.
+    next_event_us: u32,
+    /// How long the central may delay the next event (the transmit window).
+    window_us: u32,
+    /// Whether the central sent a packet since the connection was created.
+    established: bool,
+    /// Whether the central sent a packet in the current event.
+    synchronized: bool,
+    crc_errors: u8,
+    more_data: bool,
+    /// Whether a response is being transmitted.
+    responding: bool,
+    sn: bool,
+    nesn: bool,
+    in_flight: Option<InFlight>,
+    pending_update: Option<ConnectionUpdate>,
+    pending_channel_map: Option<(ChannelMap, u16)>,
+    /// Reason to report once the current event ends.
+    terminate: Option<u8>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Advertised on the channel and listens for requests.
+    Advertising(u8),
+    /// Waits for the next advertising event.
+    AdvertisingDelay,
+    /// Connected, waits for the next connection event.
+    Connected,
+    /// In a connection event.
+    ConnectionEvent,
+}
+
+pub trait LinkLayerClient {
+    /// A central connected.
+    fn connected(&self);
+
+    /// The connection ended, `reason` is a Bluetooth error code.
+    fn disconnected(&self, reason: u8);
+
+    /// A data PDU was received. `start` is true if it starts an L2CAP PDU,
+    /// false if it continues one.
+    fn data_received(&self, start: bool, data: &[u8]);
+
+    /// The data PDU passed to `LinkLayer::send` was acknowledged.
+    fn data_sent(&self);
+}
+
+pub struct LinkLayer<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn LinkLayerClient>,
+    deferred_call: DeferredCall,
+    address: [u8; 6],
+    state: Cell<State>,
+
+    advertising_pdu: Cell<[u8; 2 + 6 + MAX_ADVERTISING_DATA]>,
+    advertising_pdu_len: Cell<usize>,
+    advertising_interval_us: Cell<u32>,
+
+    connection: OptionalCell<Connection<A::Ticks>>,
+    control_tx: Cell<[u8; MAX_CONTROL_PAYLOAD]>,
+    control_tx_len: Cell<usize>,
+    data_tx: Cell<[u8; MAX_DATA_PAYLOAD]>,
+    data_tx_len: Cell<usize>,
+    data_tx_llid: Cell<u8>,
+    data_rx: Cell<[u8; MAX_DATA_PAYLOAD]>,
+    data_rx_len: Cell<usize>,
+    data_rx_llid: Cell<u8>,
+
+    // Events reported from the deferred call.
+    connected_pending: Cell<bool>,
+    data_rx_pending: Cell<bool>,
+    data_sent_pending: Cell<bool>,
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> LinkLayer<'a, R, A> {
+    /// Create a link layer that advertises with the random static device
+    /// address `address`, least significant byte first.
+    pub fn new(radio: &'a R, alarm: &'a A, address: [u8; 6]) -> LinkLayer<'a, R, A> {
+        LinkLayer {
+            radio,
+            alarm,
+            client: OptionalCell::empty(),
+            deferred_call: DeferredCall::new(),
+            address,
+            state: Cell::new(State::Idle),
+            advertising_pdu: Cell::new([0; 2 + 6 + MAX_ADVERTISING_DATA]),
+            advertising_pdu_len: Cell::new(0),
+            advertising_interval_us: Cell::new(0),
+            connection: OptionalCell::empty(),
+            control_tx: Cell::new([0; MAX_CONTROL_PAYLOAD]),
+            control_tx_len: Cell::new(0),
+            data_tx: Cell::new([0; MAX_DATA_PAYLOAD]),
+            data_tx_len: Cell::new(0),
+            data_tx_llid: Cell::new(LLID_START),
+            data_rx: Cell::new([0; MAX_DATA_PAYLOAD]),
+            data_rx_len: Cell::new(0),
+            data_rx_llid: Cell::new(LLID_START),
+            connected_pending: Cell::new(false),
+            data_rx_pending: Cell::new(false),
+            data_sent_pending: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn LinkLayerClient) {
+        self.client.set(client);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Start sending connectable advertisements with `data` every
+    /// `interval_ms` milliseconds, until a central connects or advertising
+    /// is stopped.
+    pub fn start_advertising(&self, data: &[u8], interval_ms: u32) -> Result<(), ErrorCode> {
+        if data.len() > MAX_ADVERTISING_DATA {
+            return Err(ErrorCode::SIZE);
+        }
+        if !(20..=10_240).contains(&interval_ms) {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.connection.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        let mut pdu = [0; 2 + 6 + MAX_ADVERTISING_DATA];
+        pdu[0] = ADV_IND | TX_ADD;
+        pdu[1] = (6 + data.len()) as u8;
+        pdu[2..8].copy_from_slice(&self.address);
+        pdu[8..8 + data.len()].copy_from_slice(data);
+        self.advertising_pdu.set(pdu);
+        self.advertising_pdu_len.set(8 + data.len());
+        self.advertising_interval_us.set(interval_ms * 1000);
+
+        self.radio.stop();
+        self.advertise(37);
+        Ok(())
+    }
+
+    pub fn stop_advertising(&self) -> Result<(), ErrorCode> {
+        match self.state.get() {
+            State::Advertising(_) | State::AdvertisingDelay => {
+                self.radio.stop();
+                let _ = self.alarm.disarm();
+                self.state.set(State::Idle);
+                Ok(())
+            }
+            _ => Err(ErrorCode::ALREADY),
+        }
+    }
+
+    /// Ask the central to end the connection. `LinkLayerClient::disconnected`
+    /// is called once it acknowledged the request.
+    pub fn disconnect(&self) -> Result<(), ErrorCode> {
+        if self.connection.is_none() {
+            return Err(ErrorCode::OFF);
+        }
+        if self.queue_control(&[LL_TERMINATE_IND, REMOTE_USER_TERMINATED]) {
+            Ok(())
+        } else {
+            Err(ErrorCode::BUSY)
+        }
+    }
+
+    /// Queue a data PDU for transmission. `start` is true if `data` starts
+    /// an L2CAP PDU, false if it continues one.
+    pub fn send(&self, start: bool, data: &[u8]) -> Result<(), ErrorCode> {
+        if self.connection.is_none() {
+            return Err(ErrorCode::OFF);
+        }
+        if data.is_empty() || data.len() > MAX_DATA_PAYLOAD {
+            return Err(ErrorCode::SIZE);
+        }
+        if self.data_tx_len.get() != 0 {
+            return Err(ErrorCode::BUSY);
+        }
+        let mut buffer = [0; MAX_DATA_PAYLOAD];
+        buffer[..data.len()].copy_from_slice(data);
+        self.data_tx.set(buffer);
+        self.data_tx_llid
+            .set(if start { LLID_START } else { LLID_CONTINUATION });
+        self.data_tx_len.set(data.len());
+        Ok(())
+    }
+
+    fn advertise(&self, channel: u8) {
+        self.state.set(State::Advertising(channel));
+        let pdu = self.advertising_pdu.get();
+        let _ = RadioChannel::from_channel_index(channel).map(|channel| {
+            self.radio.transmit(
+                channel,
+                ble_connection::ADVERTISING_ACCESS_ADDRESS,
+                ble_connection::ADVERTISING_CRC_INIT,
+                &pdu[..self.advertising_pdu_len.get()],
+            )
+        });
+        self.alarm.set_alarm(
+            self.alarm.now(),
+            self.alarm.ticks_from_us(ADVERTISING_CHANNEL_WINDOW_US),
+        );
+    }
+
+    fn advertising_pdu_received(&self, pdu: &[u8], response: &mut [u8]) -> Option<usize> {
+        let payload = &pdu[2..];
+        let to_us = pdu[0] & RX_ADD != 0 && payload.len() >= 12 && payload[6..12] == self.address;
+        if !to_us {
+            return None;
+        }
+        match pdu[0] & PDU_TYPE_MASK {
+            SCAN_REQ if payload.len() == 12 => {
+                response[0] = SCAN_RSP | TX_ADD;
+                response[1] = 6;
+                response[2..8].copy_from_slice(&self.address);
+                Some(8)
+            }
+            CONNECT_IND if payload.len() == 34 => {
+                if let Some(request) = ConnectionRequest::parse(&payload[12..]) {
+                    self.radio.stop();
+                    self.connect(&request);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn connect(&self, request: &ConnectionRequest) {
+        // The request just ended, which is what the first transmit window
+        // is relative to.
+        let connection = Connection {
+            access_address: request.access_address,
+            crc_init: request.crc_init,
+            channel_map: request.channel_map,
+            hop: request.hop,
+            last_unmapped: 0,
+            interval_us: u32::from(request.interval) * TIMING_UNIT_US,
+            timeout_us: u32::from(request.timeout) * TIMEOUT_UNIT_US,
+            central_ppm: SCA_PPM[request.sca as usize],
+            event_counter: 0,
+            anchor: self.alarm.now(),
+            next_event_us: TRANSMIT_WINDOW_DELAY_US
+                + u32::from(request.win_offset) * TIMING_UNIT_US,
+            window_us: u32::from(request.win_size) * TIMING_UNIT_US,
+            established: false,
+            synchronized: false,
+            crc_errors: 0,
+            more_data: false,
+            responding: false,
+            sn: false,
+            nesn: false,
+            in_flight: None,
+            pending_update: None,
+            pending_channel_map: None,
+            terminate: None,
+        };
+        self.control_tx_len.set(0);
+        self.data_tx_len.set(0);
+        self.data_rx_pending.set(false);
+        self.connected_pending.set(true);
+        self.deferred_call.set();
+        self.schedule_event(&connection);
+        self.connection.set(connection);
+    }
+
+    /// How much earlier than expected the central may start the next event
+    /// due to the clock drift of both devices.
+    fn widening_us(connection: &Connection<A::Ticks>) -> u32 {
+        let drift = u64::from(connection.central_ppm + SLEEP_CLOCK_PPM)
+            * u64::from(connection.next_event_us)
+            / 1_000_000;
+        drift as u32 + CLOCK_JITTER_US
+    }
+
+    fn schedule_event(&self, connection: &Connection<A::Ticks>) {
+        self.state.set(State::Connected);
+        let lead_us = Self::widening_us(connection) + RAMP_UP_US;
+        self.alarm.set_alarm(
+            connection.anchor,
+            self.alarm
+                .ticks_from_us(connection.next_event_us.saturating_sub(lead_us)),
+        );
+    }
+
+    fn open_event(&self, mut connection: Connection<A::Ticks>) {
+        let (unmapped, channel) = connection
+            .channel_map
+            .select(connection.last_unmapped, connection.hop);
+        connection.last_unmapped = unmapped;
+        connection.synchronized = false;
+        connection.crc_errors = 0;
+        connection.more_data = false;
+        connection.responding = false;
+
+        let listening = RadioChannel::from_channel_index(channel).is_some_and(|channel| {
+            self.radio
+                .listen(channel, connection.access_address, connection.crc_init)
+                .is_ok()
+        });
+        if !listening {
+            self.close_event(connection);
+            return;
+        }
+
+        self.state.set(State::ConnectionEvent);
+        let close_us = connection.next_event_us
+            + Self::widening_us(&connection)
+            + connection.window_us
+            + airtime_us(MAX_DATA_PAYLOAD);
+        self.alarm
+            .set_alarm(connection.anchor, self.alarm.ticks_from_us(close_us));
+        self.connection.set(connection);
+    }
+
+    fn close_event(&self, mut connection: Connection<A::Ticks>) {
+        if let Some(reason) = connection.terminate {
+            self.end_connection(reason);
+            return;
+        }
+
+        connection.event_counter = connection.event_counter.wrapping_add(1);
+        if connection.synchronized {
+            connection.next_event_us = connection.interval_us;
+            connection.window_us = 0;
+        } else {
+            connection.next_event_us += connection.interval_us;
+        }
+
+        if !connection.established && connection.next_event_us >= 6 * connection.interval_us {
+            self.end_connection(CONNECTION_FAILED_TO_BE_ESTABLISHED);
+            return;
+        }
+        if connection.established && connection.next_event_us >= connection.timeout_us {
+            self.end_connection(CONNECTION_TIMEOUT);
+            return;
+        }
+
+        // Procedures take effect in the event with their instant. An instant
+        // more than half the counter range ahead lies in the past.
+        let counter = connection.event_counter;
+        let reached = |instant: u16| instant.wrapping_sub(counter) == 0;
+        let passed = |instant: u16| instant.wrapping_sub(counter) >= 0x8000;
+        if let Some((map, instant)) = connection.pending_channel_map {
+            if passed(instant) {
+                self.end_connection(INSTANT_PASSED);
+                return;
+            }
+            if reached(instant) {
+                connection.channel_map = map;
+                connection.pending_channel_map = None;
+            }
+        }
+        if let Some(update) = connection.pending_update {
+            if passed(update.instant) {
+                self.end_connection(INSTANT_PASSED);
+                return;
+            }
+            if reached(update.instant) {
+                connection.next_event_us += u32::from(update.win_offset) * TIMING_UNIT_US;
+                connection.window_us = u32::from(update.win_size) * TIMING_UNIT_US;
+                connection.interval_us = u32::from(update.interval) * TIMING_UNIT_US;
+                connection.timeout_us = u32::from(update.timeout) * TIMEOUT_UNIT_US;
+                connection.pending_update = None;
+            }
+        }
+
+        self.schedule_event(&connection);
+        self.connection.set(connection);
+    }
+
+    fn end_connection(&self, reason: u8) {
+        self.radio.stop();
+        let _ = self.alarm.disarm();
+        self.connection.clear();
+        self.state.set(State::Idle);
+        self.control_tx_len.set(0);
+        self.data_tx_len.set(0);
+        self.data_rx_pending.set(false);
+        self.data_sent_pending.set(false);
+        // A connection the client never heard of does not need to end.
+        if !self.connected_pending.replace(false) {
+            self.client.map(|client| client.disconnected(reason));
+        }
+    }
+
+    fn queue_control(&self, payload: &[u8]) -> bool {
+        if self.control_tx_len.get() != 0 {
+            return false;
+        }
+        let mut buffer = [0; MAX_CONTROL_PAYLOAD];
+        buffer[..payload.len()].copy_from_slice(payload);
+        self.control_tx.set(buffer);
+        self.control_tx_len.set(payload.len());
+        true
+    }
+
+    /// Handle a received control PDU. Returns false if it cannot be handled
+    /// now and must not be acknowledged.
+    fn control_received(&self, connection: &mut Connection<A::Ticks>, payload: &[u8]) -> bool {
+        let Some(&opcode) = payload.first() else {
+            return true;
+        };
+        let u16_at = |i: usize| u16::from_le_bytes([payload[i], payload[i + 1]]);
+        match opcode {
+            LL_CONNECTION_UPDATE_IND if payload.len() == 12 => {
+                connection.pending_update = Some(ConnectionUpdate {
+                    win_size: payload[1],
+                    win_offset: u16_at(2),
+                    interval: u16_at(4),
+                    timeout: u16_at(8),
+                    instant: u16_at(10),
+                });
+                true
+            }
+            LL_CHANNEL_MAP_IND if payload.len() == 8 => {
+                let mut map = [0; 5];
+                map.copy_from_slice(&payload[1..6]);
+                map[4] &= 0x1F;
+                let map = ChannelMap { map };
+                if map.used_channels() >= 2 {
+                    connection.pending_channel_map = Some((map, u16_at(6)));
+                }
+                true
+            }
+            LL_TERMINATE_IND if payload.len() == 2 => {
+                connection.terminate = Some(payload[1]);
+                true
+            }
+            LL_UNKNOWN_RSP | LL_FEATURE_RSP | LL_REJECT_IND => true,
+            LL_ENC_REQ => self.queue_control(&[LL_REJECT_IND, UNSUPPORTED_REMOTE_FEATURE]),
+            LL_FEATURE_REQ => self.queue_control(&[LL_FEATURE_RSP, 0, 0, 0, 0, 0, 0, 0, 0]),
+            LL_VERSION_IND => {
+                let [company_low, company_high] = COMPANY_ID.to_le_bytes();
+                self.queue_control(&[LL_VERSION_IND, VERSION_4_0, company_low, company_high, 0, 0])
+            }
+            _ => self.queue_control(&[LL_UNKNOWN_RSP, opcode]),
+        }
+    }
+
+    /// Handle a new data channel PDU. Returns false if it cannot be handled
+    /// now and must not be acknowledged.
+    fn data_pdu_received(
+        &self,
+        connection: &mut Connection<A::Ticks>,
+        header: u8,
+        payload: &[u8],
+    ) -> bool {
+        match header & LLID_MASK {
+            LLID_CONTROL => self.control_received(connection, payload),
+            LLID_CONTINUATION if payload.is_empty() => true,
+            llid @ (LLID_CONTINUATION | LLID_START) if payload.len() <= MAX_DATA_PAYLOAD => {
+                if self.data_rx_pending.get() {
+                    return false;
+                }
+                let mut buffer = [0; MAX_DATA_PAYLOAD];
+                buffer[..payload.len()].copy_from_slice(payload);
+                self.data_rx.set(buffer);
+                self.data_rx_len.set(payload.len());
+                self.data_rx_llid.set(llid);
+                self.data_rx_pending.set(true);
+                self.deferred_call.set();
+                true
+            }
+            // Drop what this link layer cannot handle.
+            _ => true,
+        }
+    }
+
+    /// The last PDU the link layer sent was acknowledged.
+    fn acknowledged(&self, connection: &mut Connection<A::Ticks>) {
+        match connection.in_flight.take() {
+            Some(InFlight::Control) => {
+                if self.control_tx.get()[0] == LL_TERMINATE_IND {
+                    connection.terminate = Some(LOCAL_HOST_TERMINATED);
+                }
+                self.control_tx_len.set(0);
+            }
+            Some(InFlight::Data) => {
+                self.data_tx_len.set(0);
+                self.data_sent_pending.set(true);
+                self.deferred_call.set();
+            }
+            Some(InFlight::Empty) | None => {}
+        }
+    }
+
+    /// Write the next PDU to send to `response`, and return its length.
+    fn build_response(&self, connection: &mut Connection<A::Ticks>, response: &mut [u8]) -> usize {
+        // Unacknowledged PDUs are sent again unchanged.
+        let in_flight = connection
+            .in_flight
+            .unwrap_or(if self.control_tx_len.get() != 0 {
+                InFlight::Control
+            } else if self.data_tx_len.get() != 0 {
+                InFlight::Data
+            } else {
+                InFlight::Empty
+            });
+        connection.in_flight = Some(in_flight);
+
+        let (llid, len) = match in_flight {
+            InFlight::Control => {
+                let len = self.control_tx_len.get();
+                response[2..2 + len].copy_from_slice(&self.control_tx.get()[..len]);
+                (LLID_CONTROL, len)
+            }
+            InFlight::Data => {
+                let len = self.data_tx_len.get();
+                response[2..2 + len].copy_from_slice(&self.data_tx.get()[..len]);
+                (self.data_tx_llid.get(), len)
+            }
+            InFlight::Empty => (LLID_CONTINUATION, 0),
+        };
+        let more_data = match in_flight {
+            InFlight::Control => self.data_tx_len.get() != 0,
+            InFlight::Data | InFlight::Empty => false,
+        };
+        connection.more_data |= more_data;
+
+        response[0] = llid
+            | if connection.nesn { NESN } else { 0 }
+            | if connection.sn { SN } else { 0 }
+            | if more_data { MD } else { 0 };
+        response[1] = len as u8;
+        2 + len
+    }
+
+    fn connection_pdu_received(
+        &self,
+        mut connection: Connection<A::Ticks>,
+        pdu: &[u8],
+        crc_ok: bool,
+        response: &mut [u8],
+    ) -> Option<usize> {
+        let end = self.alarm.now();
+        let header = pdu[0];
+        let payload = &pdu[2..];
+
+        if crc_ok {
+            connection.crc_errors = 0;
+            if !connection.synchronized {
+                connection.synchronized = true;
+                connection.established = true;
+                connection.anchor =
+                    end.wrapping_sub(self.alarm.ticks_from_us(airtime_us(payload.len())));
+            }
+            if (header & NESN != 0) != connection.sn {
+                connection.sn = !connection.sn;
+                self.acknowledged(&mut connection);
+            }
+            if (header & SN != 0) == connection.nesn
+                && self.data_pdu_received(&mut connection, header, payload)
+            {
+                connection.nesn = !connection.nesn;
+            }
+            connection.more_data = header & MD != 0;
+        } else {
+            // The event ends after two CRC errors in a row.
+            connection.crc_errors += 1;
+            if connection.crc_errors >= 2 {
+                self.radio.stop();
+                self.close_event(connection);
+                return None;
+            }
+            connection.more_data = true;
+        }
+
+        let len = self.build_response(&mut connection, response);
+        connection.responding = true;
+        self.connection.set(connection);
+        Some(len)
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> ConnectionClient for LinkLayer<'a, R, A> {
+    fn pdu_received(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> Option<usize> {
+        if pdu.len() < 2 {
+            return None;
+        }
+        match self.state.get() {
+            State::Advertising(_) if crc_ok => self.advertising_pdu_received(pdu, response),
+            State::ConnectionEvent => self.connection.get().and_then(|connection| {
+                self.connection_pdu_received(connection, pdu, crc_ok, response)
+            }),
+            _ => None,
+        }
+    }
+
+    fn pdu_transmitted(&self) {
+        if self.state.get() != State::ConnectionEvent {
+            return;
+        }
+        let Some(mut connection) = self.connection.get() else {
+            return;
+        };
+        connection.responding = false;
+
+        let now = self.alarm.now();
+        let elapsed_us = self.alarm.ticks_to_us(now.wrapping_sub(connection.anchor));
+        if connection.terminate.is_some()
+            || !connection.more_data
+            || elapsed_us + EVENT_END_GUARD_US > connection.interval_us
+        {
+            self.radio.stop();
+            self.close_event(connection);
+        } else {
+            self.alarm
+                .set_alarm(now, self.alarm.ticks_from_us(NEXT_PDU_TIMEOUT_US));
+            self.connection.set(connection);
+        }
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> AlarmClient for LinkLayer<'a, R, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Idle => {}
+            State::Advertising(channel) => {
+                self.radio.stop();
+                if channel < 39 {
+                    self.advertise(channel + 1);
+                } else {
+                    self.state.set(State::AdvertisingDelay);
+                    let now = self.alarm.now();
+                    let delay_us = now.into_u32() % ADVERTISING_DELAY_MAX_US;
+                    self.alarm.set_alarm(
+                        now,
+                        self.alarm
+                            .ticks_from_us(self.advertising_interval_us.get() + delay_us),
+                    );
+                }
+            }
+            State::AdvertisingDelay => self.advertise(37),
+            State::Connected => {
+                self.connection
+                    .get()
+                    .map(|connection| self.open_event(connection));
+            }
+            State::ConnectionEvent => {
+                self.connection.get().map(|connection| {
+                    // A response in flight ends the event when it was sent.
+                    if !connection.responding {
+                        self.radio.stop();
+                        self.close_event(connection);
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> DeferredCallClient for LinkLayer<'a, R, A> {
+    fn handle_deferred_call(&self) {
+        if self.connected_pending.replace(false) {
+            self.client.map(|client| client.connected());
+        }
+        if self.data_rx_pending.get() {
+            let data = self.data_rx.get();
+            let len = self.data_rx_len.get();
+            let start = self.data_rx_llid.get() == LLID_START;
+            self.data_rx_pending.set(false);
+            self.client
+                .map(|client| client.data_received(start, &data[..len]));
+        }
+        if self.data_sent_pending.replace(false) {
+            self.client.map(|client| client.data_sent());
+        }
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_selection_uses_hop_increment() {
+        let all = ChannelMap {
+            map: [0xFF, 0xFF, 0xFF, 0xFF, 0x1F],
+        };
+        assert_eq!(all.select(0, 7), (7, 7));
+        assert_eq!(all.select(35, 7), (5, 5));
+    }
+
+    #[test]
+    fn channel_selection_remaps_unused_channels() {
+        // Only channels 1, 9 and 17 are used.
+        let map = ChannelMap {
+            map: [0x02, 0x02, 0x02, 0x00, 0x00],
+        };
+        assert_eq!(map.used_channels(), 3);
+        assert_eq!(map.select(0, 9), (9, 9));
+        // Unmapped channel 18 is unused, 18 % 3 selects the first used one.
+        assert_eq!(map.select(9, 9), (18, 1));
+        assert_eq!(map.select(18, 9), (27, 1));
+        assert_eq!(map.select(27, 9), (36, 1));
+        assert_eq!(map.select(36, 9), (8, 17));
+    }
+
+    #[test]
+    fn connection_request_parses() {
+        let ll_data = [
+            0x78, 0x56, 0x34, 0x12, // access address
+            0xAA, 0xBB, 0xCC, // CRC init
+            0x02, // window size
+            0x05, 0x00, // window offset
+            0x18, 0x00, // interval
+            0x00, 0x00, // latency, which a peripheral may ignore
+            0x48, 0x00, // timeout
+            0xFF, 0xFF, 0xFF, 0xFF, 0x1F, // channel map
+            0xA9, // hop 9, SCA 5
+        ];
+        let request = ConnectionRequest::parse(&ll_data).unwrap();
+        assert_eq!(request.access_address, 0x12345678);
+        assert_eq!(request.crc_init, 0xCCBBAA);
+        assert_eq!(request.win_size, 2);
+        assert_eq!(request.win_offset, 5);
+        assert_eq!(request.interval, 24);
+        assert_eq!(request.timeout, 72);
+        assert_eq!(request.hop, 9);
+        assert_eq!(request.sca, 5);
+        assert_eq!(request.channel_map.used_channels(), 37);
+    }
+
+    #[test]
+    fn connection_request_rejects_invalid_parameters() {
+        let mut ll_data = [0; 22];
+        ll_data[10] = 0x18; // interval
+        ll_data[14] = 0x48; // timeout
+        ll_data[16] = 0xFF; // channel map
+        ll_data[21] = 0x02; // hop 2 is too small
+        assert_eq!(ConnectionRequest::parse(&ll_data), None);
+        ll_data[21] = 0x05;
+        assert!(ConnectionRequest::parse(&ll_data).is_some());
+        ll_data[16] = 0x01; // a single channel
+        assert_eq!(ConnectionRequest::parse(&ll_data), None);
+    }
+}
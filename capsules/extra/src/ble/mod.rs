@@ -0,0 +1,10 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Bluetooth Low Energy connections: a peripheral link layer, L2CAP and an
+//! attribute protocol server, used by `ble_gatt_driver`.
+
+pub mod att;
+pub mod l2cap;
+pub mod link_layer;
@@ -14,14 +14,32 @@
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
 //! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
 //!
+//! Scannable advertisements answer scan requests with a scan response, which
+//! carries another 31 bytes of data. Answering scan requests and scanning
+//! actively requires a radio that also implements
+//! `kernel::hil::ble_connection::BleConnectionRadio`, passed to the driver
+//! with `set_connection_radio()`; on other radios advertisements go
+//! unanswered and scanning stays passive.
+//!
+//! Extended advertisements (Bluetooth 5) carry up to 245 bytes of data. Each
+//! `ADV_EXT_IND` on the primary advertising channels points to an
+//! `AUX_ADV_IND` with the data, which the driver sends shortly after it on a
+//! random secondary channel. Extended advertisements are non-connectable and
+//! non-scannable, use the LE 1M PHY, and also need a `BleConnectionRadio`.
+//!
 //! ### Allow system calls
 //!
-//! There is one ReadWrite and one ReadOnly allow buffers, both at index `0`.
+//! There is one ReadWrite allow buffer at index `0` and two ReadOnly allow
+//! buffers at index `0` and `1`.
 //!
-//! * ReadOnly: Advertising data, containing the full _payload_ (i.e. excluding the header) the
-//!             process wishes to advertise.
-//! * ReadWrite: Passive scanning buffer, which is populated during BLE scans with complete (i.e.
-//!              including headers) advertising packets received on channels 37, 38 and 39.
+//! * ReadOnly 0: Advertising data, containing the full _payload_ (i.e. excluding the header) the
+//!               process wishes to advertise. Extended advertisements send up to 245 bytes of
+//!               it, the others up to 31 bytes.
+//! * ReadOnly 1: Scan response data, the payload after the address of the scan response sent to
+//!               scan requests for scannable advertisements.
+//! * ReadWrite: Scanning buffer, which is populated during BLE scans with complete (i.e.
+//!              including headers) advertising packets received on channels 37, 38 and 39,
+//!              including scan responses to the scan requests of active scanning.
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -51,9 +69,18 @@
 //! `command number` is used to specify the specific operation, currently
 //! the following commands are supported:
 //!
-//! * 0: start advertisement
+//! * 0: start advertisement with the PDU type in `data` (0 `ADV_IND`, 2 `ADV_NONCONN_IND`,
+//!      6 `ADV_SCAN_IND` or 7 `ADV_EXT_IND` for extended advertising) and the interval in ms
+//!      in `interval`
 //! * 1: stop advertisement or scanning
-//! * 5: start scanning
+//! * 2: set the transmit power
+//! * 5: start passive scanning
+//! * 6: start active scanning, which sends scan requests to scannable advertisers
+//! * 7: report every packet (`data` 0), or each advertiser and packet type only once per scan
+//!      (`data` 1)
+//! * 8: add the address in `data` (bytes 0-3) and `interval` (bytes 4-5) to the allowlist; while
+//!      the allowlist is not empty, only packets from advertisers on it are reported
+//! * 9: clear the allowlist
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -65,7 +92,8 @@
 //! -----
 //!
 //! You need a device that provides the `kernel::BleAdvertisementDriver` trait along with a virtual
-//! timer to perform events and not block the entire kernel
+//! timer to perform events and not block the entire kernel. If the device also implements
+//! `BleConnectionRadio`, pass it to `set_connection_radio()` as well.
 //!
 //! ```rust,ignore
 //! # use kernel::static_init;
@@ -101,6 +129,12 @@
 // payload, generated address and PDU type) and perform one advertising event (on each of three
 // channels).
 //
+// An extended advertising event sends an ADV_EXT_IND on each primary channel, each followed by an
+// AUX_ADV_IND. The AuxPtr of an ADV_EXT_IND gives the time from its start to the start of the
+// AUX_ADV_IND in 300 us units, and the AUX_ADV_IND must start within one unit after that. The
+// driver sends the AUX_ADV_IND half a unit later, counted from when it started sending the
+// ADV_EXT_IND, so that the radio ramp-up time before both packets cancels out.
+//
 // This means that advertising events can collide. In this case, we just defer one of the
 // advertisements. Because we add a pseudo random pad to the timer interval each time (as required
 // by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
@@ -112,7 +146,9 @@ use kernel::debug;
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
-use kernel::hil::time::{Frequency, Ticks};
+use kernel::hil::ble_connection;
+use kernel::hil::ble_connection::{ADVERTISING_ACCESS_ADDRESS, ADVERTISING_CRC_INIT};
+use kernel::hil::time::{ConvertTicks, Frequency, Ticks};
 use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::OptionalCell;
@@ -126,8 +162,9 @@ pub const DRIVER_NUM: usize = driver::NUM::BleAdvertising as usize;
 /// Ids for read-only allow buffers
 mod ro_allow {
     pub const ADV_DATA: usize = 0;
+    pub const SCAN_RESPONSE_DATA: usize = 1;
     /// The number of allow buffers the kernel stores for this grant
-    pub const COUNT: u8 = 1;
+    pub const COUNT: u8 = 2;
 }
 
 /// Ids for read-write allow buffers
@@ -140,6 +177,37 @@ mod rw_allow {
 const PACKET_ADDR_LEN: usize = 6;
 pub const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const ADV_HEADER_RXADD_OFFSET: usize = 7;
+const ADV_HEADER_PDU_TYPE_MASK: u8 = 0x0f;
+
+/// Number of advertisers a process can put on its allowlist.
+pub const ALLOWLIST_LEN: usize = 8;
+/// Number of advertisers and packet types the duplicate filter remembers.
+const DUPLICATE_FILTER_LEN: usize = 16;
+
+// A scannable advertisement, a scan request and a scan response each
+// separated by T_IFS take 1.4 ms including ramp-up.
+const SCAN_RESPONSE_WINDOW_US: u32 = 1500;
+const ACTIVE_SCAN_WINDOW_US: u32 = 10_000;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.4
+const EXT_HEADER_ADVA: u8 = 1 << 0;
+const EXT_HEADER_ADI: u8 = 1 << 3;
+const EXT_HEADER_AUX_PTR: u8 = 1 << 4;
+const ADI_LEN: usize = 2;
+const AUX_PTR_LEN: usize = 3;
+const AUX_PTR_OFFSET_UNITS_300US: u8 = 1 << 7;
+const AUX_OFFSET_UNIT_US: u32 = 300;
+// Offset of an AUX_ADV_IND after its ADV_EXT_IND, in offset units. This
+// leaves time for the ADV_EXT_IND to end and the alarm to fire.
+const AUX_OFFSET_UNITS: u16 = 4;
+// The secondary advertising channels are the data channels 0 to 36.
+const SECONDARY_CHANNEL_COUNT: u32 = 37;
+const EXT_ADV_IND_LENGTH: usize = 2 + 2 + ADI_LEN + AUX_PTR_LEN;
+// Extended header length and AdvMode, extended header flags, AdvA and ADI.
+const AUX_ADV_HEADER_LEN: usize = 2 + PACKET_ADDR_LEN + ADI_LEN;
+/// Maximum advertising data length of an extended advertisement.
+pub const EXT_ADV_DATA_LENGTH: usize = 255 - AUX_ADV_HEADER_LEN;
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
@@ -156,6 +224,34 @@ enum Expiration {
     Enabled(u32, u32),
 }
 
+/// What the radio does while the driver uses it through `BleConnectionRadio`.
+#[derive(Copy, Clone)]
+enum WindowKind {
+    /// Answering scan requests to an advertisement of the sending app.
+    ScanResponse,
+    /// Scanning actively for the receiving app.
+    ActiveScan,
+    /// Waiting to send the `AUX_ADV_IND` of the sending app on a secondary
+    /// channel.
+    AuxAdvertisement(RadioChannel),
+}
+
+/// The extended advertising packet the radio is sending.
+#[derive(Copy, Clone, PartialEq)]
+enum ExtendedPacket {
+    None,
+    Primary,
+    Auxiliary,
+}
+
+/// A radio event the driver ends with its own timer.
+#[derive(Copy, Clone)]
+struct RadioWindow {
+    kind: WindowKind,
+    reference: u32,
+    dt: u32,
+}
+
 #[derive(Copy, Clone)]
 struct AlarmData {
     expiration: Expiration,
@@ -176,13 +272,12 @@ const ADV_IND: AdvPduType = 0b0000;
 #[allow(dead_code)]
 const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
-#[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
-#[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3
+const ADV_EXT_IND: AdvPduType = 0b0111;
 
 /// Process specific memory
 pub struct App {
@@ -200,6 +295,14 @@ pub struct App {
     /// It should be read using the `random_number` method, which updates it as
     /// well.
     random_nonce: u32,
+
+    // Scanning meta-data
+    active_scanning: bool,
+    filter_duplicates: bool,
+    seen: [Option<(AdvPduType, [u8; PACKET_ADDR_LEN])>; DUPLICATE_FILTER_LEN],
+    next_seen: usize,
+    allowlist: [[u8; PACKET_ADDR_LEN]; ALLOWLIST_LEN],
+    allowlist_len: usize,
 }
 
 impl Default for App {
@@ -213,6 +316,12 @@ impl Default for App {
             advertisement_interval_ms: 200,
             // Just use any non-zero starting value by default
             random_nonce: 0xdeadbeef,
+            active_scanning: false,
+            filter_duplicates: false,
+            seen: [None; DUPLICATE_FILTER_LEN],
+            next_seen: 0,
+            allowlist: [[0; PACKET_ADDR_LEN]; ALLOWLIST_LEN],
+            allowlist_len: 0,
         }
     }
 }
@@ -259,6 +368,13 @@ impl App {
     {
         // Ensure we have an address set before advertisement
         self.generate_random_address(processid)?;
+        if self.pdu_type == ADV_EXT_IND {
+            return self.send_extended_advertisement(kernel_data, ble, channel);
+        }
+        let scannable = self.pdu_type == ADV_IND || self.pdu_type == ADV_SCAN_IND;
+        if scannable {
+            self.prepare_scan_response(kernel_data, ble);
+        }
         kernel_data
             .get_readonly_processbuffer(ro_allow::ADV_DATA)
             .and_then(|adv_data| {
@@ -290,8 +406,16 @@ impl App {
                                 adv_data_corrected.copy_to_slice(&mut data[..adv_data_len]);
                             }
                             let total_len = cmp::min(PACKET_LENGTH, payload_len + 2);
-                            ble.radio
-                                .transmit_advertisement(kernel_tx, total_len, channel);
+                            if scannable
+                                && ble
+                                    .transmit_scannable(&kernel_tx[..total_len], channel)
+                                    .is_ok()
+                            {
+                                ble.kernel_tx.replace(kernel_tx);
+                            } else {
+                                ble.radio
+                                    .transmit_advertisement(kernel_tx, total_len, channel);
+                            }
                             Ok(())
                         })
                 })
@@ -299,6 +423,136 @@ impl App {
             .unwrap_or(Err(ErrorCode::FAIL))
     }
 
+    // Send an ADV_EXT_IND on the primary `channel` that points to an
+    // AUX_ADV_IND on a random secondary channel, and wait to send that.
+    fn send_extended_advertisement<'a, B, A>(
+        &mut self,
+        kernel_data: &GrantKernelData,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+    ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        let radio = ble.connection_radio.get().ok_or(ErrorCode::NOSUPPORT)?;
+        let aux_channel_index = (self.random_nonce() % SECONDARY_CHANNEL_COUNT) as u8;
+        let aux_channel =
+            RadioChannel::from_channel_index(aux_channel_index).ok_or(ErrorCode::FAIL)?;
+        let mut pdu = [0; EXT_ADV_IND_LENGTH];
+        build_ext_adv_ind(&mut pdu, adv_data_info(kernel_data), aux_channel_index);
+
+        ble.open_window(
+            WindowKind::AuxAdvertisement(aux_channel),
+            u32::from(AUX_OFFSET_UNITS) * AUX_OFFSET_UNIT_US + AUX_OFFSET_UNIT_US / 2,
+        );
+        ble.extended_packet.set(ExtendedPacket::Primary);
+        radio
+            .transmit(
+                channel,
+                ADVERTISING_ACCESS_ADDRESS,
+                ADVERTISING_CRC_INIT,
+                &pdu,
+            )
+            .inspect_err(|_| {
+                ble.window.clear();
+                ble.extended_packet.set(ExtendedPacket::None);
+            })
+    }
+
+    // Send the AUX_ADV_IND with this app's advertising data on `channel`.
+    fn send_auxiliary_advertisement<'a, B, A>(
+        &self,
+        kernel_data: &GrantKernelData,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+    ) -> Result<(), ErrorCode>
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        let radio = ble.connection_radio.get().ok_or(ErrorCode::NOSUPPORT)?;
+        let mut pdu = [0; 2 + AUX_ADV_HEADER_LEN + EXT_ADV_DATA_LENGTH];
+        let data_len = kernel_data
+            .get_readonly_processbuffer(ro_allow::ADV_DATA)
+            .and_then(|adv_data| {
+                adv_data.enter(|adv_data| {
+                    let len = cmp::min(EXT_ADV_DATA_LENGTH, adv_data.len());
+                    adv_data[..len].copy_to_slice(&mut pdu[2 + AUX_ADV_HEADER_LEN..][..len]);
+                    len
+                })
+            })
+            .unwrap_or(0);
+        let len = build_aux_adv_ind(
+            &mut pdu,
+            &self.address,
+            adv_data_info(kernel_data),
+            data_len,
+        );
+
+        ble.extended_packet.set(ExtendedPacket::Auxiliary);
+        radio
+            .transmit(
+                channel,
+                ADVERTISING_ACCESS_ADDRESS,
+                ADVERTISING_CRC_INIT,
+                &pdu[..len],
+            )
+            .inspect_err(|_| ble.extended_packet.set(ExtendedPacket::None))
+    }
+
+    // Build the scan response to this app's advertisements from the scan
+    // response data, for the driver to answer scan requests with.
+    fn prepare_scan_response<'a, B, A>(&self, kernel_data: &GrantKernelData, ble: &BLE<'a, B, A>)
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        let mut pdu = [0; PACKET_LENGTH];
+        let (header, payload) = pdu.split_at_mut(2);
+        let (adva, data) = payload.split_at_mut(PACKET_ADDR_LEN);
+        let data_len = kernel_data
+            .get_readonly_processbuffer(ro_allow::SCAN_RESPONSE_DATA)
+            .and_then(|scan_response_data| {
+                scan_response_data.enter(|scan_response_data| {
+                    let len = cmp::min(data.len(), scan_response_data.len());
+                    scan_response_data[..len].copy_to_slice(&mut data[..len]);
+                    len
+                })
+            })
+            .unwrap_or(0);
+        header[0] = SCAN_RESP | 1 << ADV_HEADER_TXADD_OFFSET;
+        header[1] = (PACKET_ADDR_LEN + data_len) as u8;
+        adva.copy_from_slice(&self.address);
+        ble.scan_response.set(pdu);
+        ble.scan_response_len.set(2 + PACKET_ADDR_LEN + data_len);
+    }
+
+    // Whether a received packet passes the allowlist and the duplicate
+    // filter. Accepted packets are remembered by the duplicate filter.
+    fn accept(&mut self, pdu: &[u8]) -> bool {
+        let Some(address) = advertiser_address(pdu) else {
+            return false;
+        };
+        let pdu_type = pdu[0] & ADV_HEADER_PDU_TYPE_MASK;
+        if !self.allows(&address) || self.is_duplicate(pdu_type, &address) {
+            return false;
+        }
+        if self.filter_duplicates {
+            self.seen[self.next_seen] = Some((pdu_type, address));
+            self.next_seen = (self.next_seen + 1) % DUPLICATE_FILTER_LEN;
+        }
+        true
+    }
+
+    fn allows(&self, address: &[u8; PACKET_ADDR_LEN]) -> bool {
+        self.allowlist_len == 0 || self.allowlist[..self.allowlist_len].contains(address)
+    }
+
+    fn is_duplicate(&self, pdu_type: AdvPduType, address: &[u8; PACKET_ADDR_LEN]) -> bool {
+        self.filter_duplicates && self.seen.contains(&Some((pdu_type, *address)))
+    }
+
     // Returns a new pseudo-random number and updates the randomness state.
     //
     // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
@@ -313,6 +567,12 @@ impl App {
         self.random_nonce
     }
 
+    // End the advertising event of this app and schedule the next one.
+    fn end_advertising_event<F: Frequency>(&mut self, now: u32) {
+        self.process_status = Some(BLEState::AdvertisingIdle);
+        self.set_next_alarm::<F>(now);
+    }
+
     // Set the next alarm for this app using the period and provided start time.
     fn set_next_alarm<F: Frequency>(&mut self, now: u32) {
         let nonce = self.random_nonce() % 10;
@@ -322,12 +582,94 @@ impl App {
     }
 }
 
+// The AdvDataInfo of an extended advertisement of advertising set 0, with a
+// data ID that changes with the advertising data.
+fn adv_data_info(kernel_data: &GrantKernelData) -> u16 {
+    kernel_data
+        .get_readonly_processbuffer(ro_allow::ADV_DATA)
+        .and_then(|adv_data| {
+            adv_data.enter(|adv_data| {
+                adv_data.iter().fold(adv_data.len() as u16, |id, byte| {
+                    id.rotate_left(3) ^ u16::from(byte.get())
+                })
+            })
+        })
+        .unwrap_or(0)
+        & 0x0fff
+}
+
+// Build an ADV_EXT_IND with the AdvDataInfo `adi` that points to an
+// AUX_ADV_IND on the secondary channel `aux_channel`. Extended
+// advertisements are non-connectable and non-scannable (AdvMode 0).
+fn build_ext_adv_ind(pdu: &mut [u8; EXT_ADV_IND_LENGTH], adi: u16, aux_channel: u8) {
+    pdu[0] = ADV_EXT_IND;
+    pdu[1] = (EXT_ADV_IND_LENGTH - 2) as u8;
+    pdu[2] = (1 + ADI_LEN + AUX_PTR_LEN) as u8;
+    pdu[3] = EXT_HEADER_ADI | EXT_HEADER_AUX_PTR;
+    pdu[4..6].copy_from_slice(&adi.to_le_bytes());
+    // The clock accuracy bit stays 0 (51 to 500 ppm) and the PHY 0 (LE 1M).
+    pdu[6] = aux_channel | AUX_PTR_OFFSET_UNITS_300US;
+    pdu[7..9].copy_from_slice(&AUX_OFFSET_UNITS.to_le_bytes());
+}
+
+// Build the header of an AUX_ADV_IND from the advertiser `address` in front
+// of the `data_len` bytes of advertising data already in `pdu`, and return
+// the length of the PDU.
+fn build_aux_adv_ind(
+    pdu: &mut [u8],
+    address: &[u8; PACKET_ADDR_LEN],
+    adi: u16,
+    data_len: usize,
+) -> usize {
+    pdu[0] = ADV_EXT_IND | 1 << ADV_HEADER_TXADD_OFFSET;
+    pdu[1] = (AUX_ADV_HEADER_LEN + data_len) as u8;
+    pdu[2] = (1 + PACKET_ADDR_LEN + ADI_LEN) as u8;
+    pdu[3] = EXT_HEADER_ADVA | EXT_HEADER_ADI;
+    pdu[4..10].copy_from_slice(address);
+    pdu[10..12].copy_from_slice(&adi.to_le_bytes());
+    2 + AUX_ADV_HEADER_LEN + data_len
+}
+
+// The address of the advertiser that sent `pdu`, or that a scan or
+// connection request in `pdu` is for.
+fn advertiser_address(pdu: &[u8]) -> Option<[u8; PACKET_ADDR_LEN]> {
+    let offset = match pdu.first()? & ADV_HEADER_PDU_TYPE_MASK {
+        SCAN_REQ | CONNECT_IND => 2 + PACKET_ADDR_LEN,
+        _ => 2,
+    };
+    pdu.get(offset..offset + PACKET_ADDR_LEN)?.try_into().ok()
+}
+
+// Copy a received packet to the scanning buffer of a process and notify it.
+fn report_packet(kernel_data: &GrantKernelData, packet: &[u8]) {
+    let success = kernel_data
+        .get_readwrite_processbuffer(rw_allow::SCAN_BUFFER)
+        .and_then(|scan_buffer| {
+            scan_buffer.mut_enter(|userland| {
+                userland
+                    .get(..packet.len())
+                    .is_some_and(|userland| userland.copy_from_slice_or_err(packet).is_ok())
+            })
+        })
+        .unwrap_or(false);
+
+    if success {
+        kernel_data
+            .schedule_upcall(
+                0,
+                (kernel::errorcode::into_statuscode(Ok(())), packet.len(), 0),
+            )
+            .ok();
+    }
+}
+
 pub struct BLE<'a, B, A>
 where
     B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
     A: kernel::hil::time::Alarm<'a>,
 {
     radio: &'a B,
+    connection_radio: OptionalCell<&'a dyn ble_connection::BleConnectionRadio<'a>>,
     busy: Cell<bool>,
     app: Grant<
         App,
@@ -339,6 +681,13 @@ where
     alarm: &'a A,
     sending_app: OptionalCell<kernel::ProcessId>,
     receiving_app: OptionalCell<kernel::ProcessId>,
+    window: OptionalCell<RadioWindow>,
+    // Scan response of the sending app, which also holds its address.
+    scan_response: Cell<[u8; PACKET_LENGTH]>,
+    scan_response_len: Cell<usize>,
+    // Address of the receiving app, which its scan requests are sent from.
+    scanner_address: Cell<[u8; PACKET_ADDR_LEN]>,
+    extended_packet: Cell<ExtendedPacket>,
 }
 
 impl<'a, B, A> BLE<'a, B, A>
@@ -359,15 +708,195 @@ where
     ) -> BLE<'a, B, A> {
         BLE {
             radio,
+            connection_radio: OptionalCell::empty(),
             busy: Cell::new(false),
             app: container,
             kernel_tx: kernel::utilities::cells::TakeCell::new(tx_buf),
             alarm,
             sending_app: OptionalCell::empty(),
             receiving_app: OptionalCell::empty(),
+            window: OptionalCell::empty(),
+            scan_response: Cell::new([0; PACKET_LENGTH]),
+            scan_response_len: Cell::new(0),
+            scanner_address: Cell::new([0; PACKET_ADDR_LEN]),
+            extended_packet: Cell::new(ExtendedPacket::None),
         }
     }
 
+    /// Answer scan requests, scan actively and send extended advertisements
+    /// with `radio`, which must be the advertising radio.
+    pub fn set_connection_radio(&'a self, radio: &'a dyn ble_connection::BleConnectionRadio<'a>) {
+        radio.set_connection_client(self);
+        self.connection_radio.set(radio);
+    }
+
+    // Transmit a scannable advertisement and answer scan requests to it
+    // until the window closes.
+    fn transmit_scannable(&self, pdu: &[u8], channel: RadioChannel) -> Result<(), ErrorCode> {
+        self.connection_radio
+            .get()
+            .ok_or(ErrorCode::NOSUPPORT)?
+            .transmit(
+                channel,
+                ADVERTISING_ACCESS_ADDRESS,
+                ADVERTISING_CRC_INIT,
+                pdu,
+            )?;
+        self.open_window(WindowKind::ScanResponse, SCAN_RESPONSE_WINDOW_US);
+        Ok(())
+    }
+
+    // Listen for advertisements on `channel` for the receiving app. Active
+    // scanning falls back to passive scanning if the radio cannot answer
+    // advertisements.
+    fn scan(&self, active: bool, channel: RadioChannel) {
+        if active
+            && self.connection_radio.get().is_some_and(|radio| {
+                radio
+                    .listen(channel, ADVERTISING_ACCESS_ADDRESS, ADVERTISING_CRC_INIT)
+                    .is_ok()
+            })
+        {
+            self.open_window(WindowKind::ActiveScan, ACTIVE_SCAN_WINDOW_US);
+        } else {
+            self.radio.receive_advertisement(channel);
+        }
+    }
+
+    // The caller must call `reset_active_alarm()` afterwards for the window
+    // to close.
+    fn open_window(&self, kind: WindowKind, duration_us: u32) {
+        self.window.set(RadioWindow {
+            kind,
+            reference: self.alarm.now().into_u32(),
+            dt: self.alarm.ticks_from_us(duration_us).into_u32(),
+        });
+    }
+
+    // Continue the advertising event of the sending app on the next channel,
+    // or end it.
+    fn continue_advertising(&self) {
+        self.sending_app.map(|processid| {
+            let _ = self.app.enter(processid, |app, kernel_data| {
+                if let Some(BLEState::Advertising(channel)) = app.process_status {
+                    match channel.next_advertising_channel() {
+                        Some(next) => {
+                            app.process_status = Some(BLEState::Advertising(next));
+                            let _ = self.radio.set_tx_power(app.tx_power);
+                            if app
+                                .send_advertisement(processid, kernel_data, self, next)
+                                .is_err()
+                                && app.pdu_type == ADV_EXT_IND
+                            {
+                                // Nothing on the air ends the event.
+                                self.busy.set(false);
+                                app.end_advertising_event::<A::Frequency>(
+                                    self.alarm.now().into_u32(),
+                                );
+                            }
+                        }
+                        None => {
+                            self.busy.set(false);
+                            app.end_advertising_event::<A::Frequency>(self.alarm.now().into_u32());
+                        }
+                    }
+                }
+            });
+            self.reset_active_alarm();
+        });
+    }
+
+    // Continue the scanning event of the receiving app on the next channel,
+    // or end it.
+    fn continue_scanning(&self) {
+        self.receiving_app.map(|processid| {
+            let _ = self.app.enter(processid, |app, _| {
+                if let Some(BLEState::Scanning(channel)) = app.process_status {
+                    match channel.next_advertising_channel() {
+                        Some(next) => {
+                            app.process_status = Some(BLEState::Scanning(next));
+                            self.scan(app.active_scanning, next);
+                        }
+                        None => {
+                            self.busy.set(false);
+                            app.process_status = Some(BLEState::ScanningIdle);
+                            app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                        }
+                    }
+                }
+            });
+            self.reset_active_alarm();
+        });
+    }
+
+    // Send the AUX_ADV_IND of the sending app, or go on with its advertising
+    // event if that fails.
+    fn send_auxiliary(&self, channel: RadioChannel) {
+        let sent = self.sending_app.map_or(false, |processid| {
+            self.app
+                .enter(processid, |app, kernel_data| {
+                    app.send_auxiliary_advertisement(kernel_data, self, channel)
+                        .is_ok()
+                })
+                .unwrap_or(false)
+        });
+        if !sent {
+            self.continue_advertising();
+        }
+    }
+
+    // Answer a scan request for the sending app's address with its scan
+    // response.
+    fn scan_request_received(&self, pdu: &[u8], response: &mut [u8]) -> Option<usize> {
+        let scan_response = self.scan_response.get();
+        let for_us = pdu[0] & ADV_HEADER_PDU_TYPE_MASK == SCAN_REQ
+            && pdu[0] & 1 << ADV_HEADER_RXADD_OFFSET != 0
+            && pdu.len() == 2 + 2 * PACKET_ADDR_LEN
+            && advertiser_address(pdu).is_some_and(|address| address[..] == scan_response[2..8]);
+        if !for_us {
+            return None;
+        }
+        let len = self.scan_response_len.get();
+        response[..len].copy_from_slice(&scan_response[..len]);
+        Some(len)
+    }
+
+    // Report a packet received while scanning actively, and answer scannable
+    // advertisements with a scan request.
+    fn advertisement_received(&self, pdu: &[u8], response: &mut [u8]) -> Option<usize> {
+        if pdu.len() > PACKET_LENGTH {
+            return None;
+        }
+        let address = advertiser_address(pdu)?;
+        let pdu_type = pdu[0] & ADV_HEADER_PDU_TYPE_MASK;
+        let request = self.receiving_app.and_then(|processid| {
+            self.app
+                .enter(processid, |app, kernel_data| {
+                    if app.accept(pdu) {
+                        report_packet(kernel_data, pdu);
+                    }
+                    // With the duplicate filter, each advertiser is only
+                    // asked until it answered once.
+                    (pdu_type == ADV_IND || pdu_type == ADV_SCAN_IND)
+                        && app.allows(&address)
+                        && !app.is_duplicate(SCAN_RESP, &address)
+                })
+                .ok()
+        })?;
+        if !request {
+            return None;
+        }
+
+        // The advertiser address has the type given by TxAdd of the
+        // advertisement, which becomes RxAdd of the request.
+        let rx_add = (pdu[0] >> ADV_HEADER_TXADD_OFFSET & 1) << ADV_HEADER_RXADD_OFFSET;
+        response[0] = SCAN_REQ | 1 << ADV_HEADER_TXADD_OFFSET | rx_add;
+        response[1] = (2 * PACKET_ADDR_LEN) as u8;
+        response[2..8].copy_from_slice(&self.scanner_address.get());
+        response[8..14].copy_from_slice(&address);
+        Some(2 + 2 * PACKET_ADDR_LEN)
+    }
+
     // Determines which app timer will expire next and sets the underlying alarm
     // to it.
     //
@@ -380,17 +909,21 @@ where
         let mut next_ref = u32::MAX;
         let mut next_dt = u32::MAX;
         let mut next_dist = u32::MAX;
+        let mut consider = |reference: u32, dt: u32| {
+            let exp = reference.wrapping_add(dt);
+            let t_dist = exp.wrapping_sub(now.into_u32());
+            if next_dist > t_dist {
+                next_ref = reference;
+                next_dt = dt;
+                next_dist = t_dist;
+            }
+        };
+        if let Some(window) = self.window.get() {
+            consider(window.reference, window.dt);
+        }
         for app in self.app.iter() {
             app.enter(|app, _| match app.alarm_data.expiration {
-                Expiration::Enabled(reference, dt) => {
-                    let exp = reference.wrapping_add(dt);
-                    let t_dist = exp.wrapping_sub(now.into_u32());
-                    if next_dist > t_dist {
-                        next_ref = reference;
-                        next_dt = dt;
-                        next_dist = t_dist;
-                    }
-                }
+                Expiration::Enabled(reference, dt) => consider(reference, dt),
                 Expiration::Disabled => {}
             });
         }
@@ -422,6 +955,25 @@ where
     fn alarm(&self) {
         let now = self.alarm.now();
 
+        if let Some(window) = self.window.get() {
+            let exp = A::Ticks::from(window.reference.wrapping_add(window.dt));
+            let t0 = A::Ticks::from(window.reference);
+            if !now.within_range(t0, exp) {
+                self.window.clear();
+                match window.kind {
+                    WindowKind::ScanResponse => {
+                        self.connection_radio.map(|radio| radio.stop());
+                        self.continue_advertising();
+                    }
+                    WindowKind::ActiveScan => {
+                        self.connection_radio.map(|radio| radio.stop());
+                        self.continue_scanning();
+                    }
+                    WindowKind::AuxAdvertisement(channel) => self.send_auxiliary(channel),
+                }
+            }
+        }
+
         self.app.each(|processid, app, kernel_data| {
             if let Expiration::Enabled(reference, dt) = app.alarm_data.expiration {
                 let exp = A::Ticks::from(reference.wrapping_add(dt));
@@ -447,12 +999,22 @@ where
                                 Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
                             self.sending_app.set(processid);
                             let _ = self.radio.set_tx_power(app.tx_power);
-                            let _ = app.send_advertisement(
-                                processid,
-                                kernel_data,
-                                self,
-                                RadioChannel::AdvertisingChannel37,
-                            );
+                            if app
+                                .send_advertisement(
+                                    processid,
+                                    kernel_data,
+                                    self,
+                                    RadioChannel::AdvertisingChannel37,
+                                )
+                                .is_err()
+                                && app.pdu_type == ADV_EXT_IND
+                            {
+                                // Nothing on the air ends the event.
+                                self.busy.set(false);
+                                app.end_advertising_event::<A::Frequency>(
+                                    self.alarm.now().into_u32(),
+                                );
+                            }
                         }
                         Some(BLEState::ScanningIdle) => {
                             self.busy.set(true);
@@ -460,8 +1022,11 @@ where
                                 Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
                             self.receiving_app.set(processid);
                             let _ = self.radio.set_tx_power(app.tx_power);
-                            self.radio
-                                .receive_advertisement(RadioChannel::AdvertisingChannel37);
+                            if app.active_scanning {
+                                let _ = app.generate_random_address(processid);
+                                self.scanner_address.set(app.address);
+                            }
+                            self.scan(app.active_scanning, RadioChannel::AdvertisingChannel37);
                         }
                         _ => debug!(
                             "app: {:?} \t invalid state {:?}",
@@ -494,56 +1059,15 @@ where
                 // only be sent on the other 37 RadioChannel channels.
 
                 if len <= PACKET_LENGTH as u8 && result == Ok(()) {
-                    // write to buffer in userland
-
-                    let success = kernel_data
-                        .get_readwrite_processbuffer(rw_allow::SCAN_BUFFER)
-                        .and_then(|scan_buffer| {
-                            scan_buffer.mut_enter(|userland| {
-                                userland[0..len as usize]
-                                    .copy_from_slice_or_err(&buf[0..len as usize])
-                                    .is_ok()
-                            })
-                        })
-                        .unwrap_or(false);
-
-                    if success {
-                        kernel_data
-                            .schedule_upcall(
-                                0,
-                                (kernel::errorcode::into_statuscode(result), len as usize, 0),
-                            )
-                            .ok();
+                    let packet = &buf[0..len as usize];
+                    if app.accept(packet) {
+                        // write to buffer in userland
+                        report_packet(kernel_data, packet);
                     }
                 }
-
-                match app.process_status {
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
-                        self.receiving_app.set(processid);
-                        let _ = self.radio.set_tx_power(app.tx_power);
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel38);
-                    }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
-                        self.receiving_app.set(processid);
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel39);
-                    }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::ScanningIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
-                    }
-                    // Invalid state => don't care
-                    _ => (),
-                }
             });
-            self.reset_active_alarm();
         });
+        self.continue_scanning();
     }
 }
 
@@ -557,45 +1081,37 @@ where
     // re-transmissions for invalid CRCs
     fn transmit_event(&self, buf: &'static mut [u8], _crc_ok: Result<(), ErrorCode>) {
         self.kernel_tx.replace(buf);
-        self.sending_app.map(|processid| {
-            let _ = self.app.enter(processid, |app, kernel_data| {
-                match app.process_status {
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
-                        self.sending_app.set(processid);
-                        let _ = self.radio.set_tx_power(app.tx_power);
-                        let _ = app.send_advertisement(
-                            processid,
-                            kernel_data,
-                            self,
-                            RadioChannel::AdvertisingChannel38,
-                        );
-                    }
+        self.continue_advertising();
+    }
+}
 
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
-                        self.sending_app.set(processid);
-                        let _ = app.send_advertisement(
-                            processid,
-                            kernel_data,
-                            self,
-                            RadioChannel::AdvertisingChannel39,
-                        );
-                    }
+// Callback from the radio while it answers packets on its own
+impl<'a, B, A> ble_connection::ConnectionClient for BLE<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    A: kernel::hil::time::Alarm<'a>,
+{
+    fn pdu_received(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> Option<usize> {
+        if !crc_ok || pdu.len() < 2 {
+            return None;
+        }
+        match self.window.get()?.kind {
+            WindowKind::ScanResponse => self.scan_request_received(pdu, response),
+            WindowKind::ActiveScan => self.advertisement_received(pdu, response),
+            WindowKind::AuxAdvertisement(_) => None,
+        }
+    }
 
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::AdvertisingIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
-                    }
-                    // Invalid state => don't care
-                    _ => (),
-                }
-            });
-            self.reset_active_alarm();
-        });
+    // Nothing answers extended advertisements, so the radio stops listening
+    // as soon as one is sent.
+    fn pdu_transmitted(&self) {
+        let packet = self.extended_packet.replace(ExtendedPacket::None);
+        if packet != ExtendedPacket::None {
+            self.connection_radio.map(|radio| radio.stop());
+        }
+        if packet == ExtendedPacket::Auxiliary {
+            self.continue_advertising();
+        }
     }
 }
 
@@ -620,7 +1136,10 @@ where
                         if let Some(BLEState::Idle) = app.process_status {
                             let pdu_type = data as AdvPduType;
                             match pdu_type {
-                                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
+                                ADV_EXT_IND if self.connection_radio.is_none() => {
+                                    Err(ErrorCode::NOSUPPORT)
+                                }
+                                ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | ADV_EXT_IND => {
                                     app.pdu_type = pdu_type;
                                     app.process_status = Some(BLEState::AdvertisingIdle);
                                     app.random_nonce = self.alarm.now().into_u32();
@@ -690,12 +1209,14 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
-            // Passive scanning mode
-            5 => {
+            // Passive or active scanning mode
+            5 | 6 => {
                 self.app
                     .enter(processid, |app, _| {
                         if let Some(BLEState::Idle) = app.process_status {
                             app.process_status = Some(BLEState::ScanningIdle);
+                            app.active_scanning = command_num == 6;
+                            app.seen = [None; DUPLICATE_FILTER_LEN];
                             app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
                             Ok(())
                         } else {
@@ -715,6 +1236,51 @@ where
                     )
             }
 
+            // Configure duplicate filtering
+            //
+            // data - 1 to report each advertiser and packet type once per
+            //        scan, 0 to report every packet
+            7 => self
+                .app
+                .enter(processid, |app, _| {
+                    app.filter_duplicates = data != 0;
+                    app.seen = [None; DUPLICATE_FILTER_LEN];
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Add an advertiser to the allowlist
+            //
+            // data     - address bytes 0 to 3, least significant byte first
+            // interval - address bytes 4 and 5
+            8 => self
+                .app
+                .enter(processid, |app, _| {
+                    let mut address = [0; PACKET_ADDR_LEN];
+                    address[..4].copy_from_slice(&(data as u32).to_le_bytes());
+                    address[4..].copy_from_slice(&(interval as u16).to_le_bytes());
+                    if app.allowlist[..app.allowlist_len].contains(&address) {
+                        CommandReturn::success()
+                    } else if app.allowlist_len < ALLOWLIST_LEN {
+                        let index = app.allowlist_len;
+                        app.allowlist[index] = address;
+                        app.allowlist_len = index + 1;
+                        CommandReturn::success()
+                    } else {
+                        CommandReturn::failure(ErrorCode::NOMEM)
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Clear the allowlist
+            9 => self
+                .app
+                .enter(processid, |app, _| {
+                    app.allowlist_len = 0;
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -723,3 +1289,94 @@ where
         self.app.enter(processid, |_, _| {})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADVERTISER: [u8; PACKET_ADDR_LEN] = [1, 2, 3, 4, 5, 0xc6];
+
+    fn packet(pdu_type: AdvPduType, address: &[u8; PACKET_ADDR_LEN]) -> [u8; 8] {
+        let mut packet = [pdu_type, PACKET_ADDR_LEN as u8, 0, 0, 0, 0, 0, 0];
+        packet[2..].copy_from_slice(address);
+        packet
+    }
+
+    #[test]
+    fn duplicate_filter_reports_each_packet_type_once() {
+        let mut app = App::default();
+        assert!(app.accept(&packet(ADV_IND, &ADVERTISER)));
+        assert!(app.accept(&packet(ADV_IND, &ADVERTISER)));
+
+        app.filter_duplicates = true;
+        assert!(app.accept(&packet(ADV_IND, &ADVERTISER)));
+        assert!(!app.accept(&packet(ADV_IND, &ADVERTISER)));
+        assert!(app.accept(&packet(SCAN_RESP, &ADVERTISER)));
+        assert!(app.is_duplicate(SCAN_RESP, &ADVERTISER));
+        assert!(app.accept(&packet(ADV_IND, &[6; PACKET_ADDR_LEN])));
+    }
+
+    #[test]
+    fn ext_adv_ind_points_to_aux_adv_ind() {
+        let mut pdu = [0; EXT_ADV_IND_LENGTH];
+        build_ext_adv_ind(&mut pdu, 0x0abc, 36);
+        assert_eq!(
+            pdu,
+            [
+                ADV_EXT_IND,
+                7,
+                // Extended header length 6, AdvMode 0
+                6,
+                EXT_HEADER_ADI | EXT_HEADER_AUX_PTR,
+                0xbc,
+                0x0a,
+                // Channel 36 with 300 us offset units, offset 4 on LE 1M
+                36 | 0x80,
+                4,
+                0,
+            ]
+        );
+    }
+
+    #[test]
+    fn aux_adv_ind_carries_address_and_data() {
+        let mut pdu = [0; 2 + AUX_ADV_HEADER_LEN + EXT_ADV_DATA_LENGTH];
+        pdu[12..15].copy_from_slice(&[2, 1, 6]);
+        let len = build_aux_adv_ind(&mut pdu, &ADVERTISER, 0x0123, 3);
+        assert_eq!(len, 15);
+        assert_eq!(
+            pdu[..len],
+            [
+                ADV_EXT_IND | 1 << ADV_HEADER_TXADD_OFFSET,
+                13,
+                9,
+                EXT_HEADER_ADVA | EXT_HEADER_ADI,
+                1,
+                2,
+                3,
+                4,
+                5,
+                0xc6,
+                0x23,
+                0x01,
+                2,
+                1,
+                6,
+            ]
+        );
+
+        let len = build_aux_adv_ind(&mut pdu, &ADVERTISER, 0, EXT_ADV_DATA_LENGTH);
+        assert_eq!(len, 2 + 255);
+        assert_eq!(pdu[1], 255);
+    }
+
+    #[test]
+    fn allowlist_limits_reported_advertisers() {
+        let mut app = App::default();
+        app.allowlist[0] = ADVERTISER;
+        app.allowlist_len = 1;
+        assert!(app.accept(&packet(ADV_NONCONN_IND, &ADVERTISER)));
+        assert!(!app.accept(&packet(ADV_NONCONN_IND, &[6; PACKET_ADDR_LEN])));
+        assert!(!app.accept(&[ADV_NONCONN_IND, 3, 1, 2, 3]));
+    }
+}
@@ -0,0 +1,474 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Bluetooth Low Energy GATT server userspace driver.
+//!
+//! Lets an app offer GATT services over a Bluetooth Low Energy connection
+//! in the peripheral role. The app registers its services and
+//! characteristics, starts connectable advertising, keeps the values of its
+//! characteristics up to date, and is told when a central writes one.
+//!
+//! ```rust,ignore
+//! +===============+
+//! ||  Userspace  ||
+//! +===============+
+//!
+//! -----Syscall Interface-----
+//!
+//! +---------------------------------+
+//! |  BLE GATT Driver (this file)    |
+//! |  L2CAP, attribute database      |
+//! +---------------------------------+
+//!
+//!      ble::link_layer::LinkLayerClient
+//!
+//! +---------------------------------+
+//! |  ble::link_layer::LinkLayer     |
+//! +---------------------------------+
+//!
+//!    hil::ble_connection::BleConnectionRadio
+//! ```
+//!
+//! Only one app can own the GATT server. The first app that uses any
+//! command but 0 owns it until it exits; the services of an app that exited
+//! are removed when the next app claims the server. Services can only be
+//! added while no central is connected.
+//!
+//! The upcall passes `(kind, arg1, arg2)`, where `kind` is:
+//!
+//! - 0: A central connected.
+//! - 1: The central disconnected, `arg1` is the Bluetooth error code that
+//!   says why.
+//! - 2: The central wrote the characteristic value at handle `arg1`. Its
+//!   `arg2` bytes were copied to the written value buffer.
+//! - 3: A notification was sent.
+
+use capsules_core::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::BleGatt as usize;
+
+use core::cell::Cell;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::ble_connection::BleConnectionRadio;
+use kernel::hil::time::Alarm;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{MapCell, OptionalCell};
+use kernel::{ErrorCode, ProcessId};
+
+use crate::ble::att::{self, AttributeDatabase, Uuid};
+use crate::ble::l2cap;
+use crate::ble::link_layer::{self, LinkLayer, LinkLayerClient};
+
+/// IDs for read-only allow buffers.
+mod ro_allow {
+    /// UUID of the service or characteristic to add.
+    pub const UUID: usize = 0;
+    /// New value of a characteristic.
+    pub const VALUE: usize = 1;
+    /// Data to advertise.
+    pub const ADVERTISING_DATA: usize = 2;
+    /// The number of RO allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 3;
+}
+
+/// IDs for read-write allow buffers.
+mod rw_allow {
+    /// Value the central wrote.
+    pub const WRITTEN: usize = 0;
+    /// The number of RW allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for upcalls.
+mod upcalls {
+    /// Single upcall.
+    pub const EVENT: usize = 0;
+    /// The number of upcalls the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+// Upcall kinds.
+const EVENT_CONNECTED: usize = 0;
+const EVENT_DISCONNECTED: usize = 1;
+const EVENT_WRITTEN: usize = 2;
+const EVENT_NOTIFIED: usize = 3;
+
+/// Longest L2CAP PDU the server sends or receives.
+const PDU_LEN: usize = l2cap::HEADER_LEN + att::ATT_MTU;
+
+#[derive(Default)]
+pub struct App {}
+
+pub struct BleGatt<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> {
+    link: &'a LinkLayer<'a, R, A>,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcalls::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// The app that owns the GATT server.
+    owner: OptionalCell<ProcessId>,
+    database: MapCell<AttributeDatabase<'static>>,
+    /// L2CAP PDU being reassembled from data PDUs.
+    rx: Cell<[u8; PDU_LEN]>,
+    rx_len: Cell<usize>,
+    /// L2CAP PDU waiting for the link layer to send the previous one.
+    tx: Cell<[u8; PDU_LEN]>,
+    tx_len: Cell<usize>,
+    tx_notification: Cell<bool>,
+    /// Whether the PDU the link layer sends is a notification.
+    sending_notification: Cell<bool>,
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> BleGatt<'a, R, A> {
+    pub fn new(
+        link: &'a LinkLayer<'a, R, A>,
+        database: AttributeDatabase<'static>,
+        grant: Grant<
+            App,
+            UpcallCount<{ upcalls::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> BleGatt<'a, R, A> {
+        BleGatt {
+            link,
+            apps: grant,
+            owner: OptionalCell::empty(),
+            database: MapCell::new(database),
+            rx: Cell::new([0; PDU_LEN]),
+            rx_len: Cell::new(0),
+            tx: Cell::new([0; PDU_LEN]),
+            tx_len: Cell::new(0),
+            tx_notification: Cell::new(false),
+            sending_notification: Cell::new(false),
+        }
+    }
+
+    /// Make `processid` the owner of the GATT server, unless another live
+    /// app owns it.
+    fn claim(&self, processid: ProcessId) -> Result<(), ErrorCode> {
+        if self.owner.contains(&processid) {
+            return Ok(());
+        }
+        let owner_alive = self
+            .owner
+            .map_or(false, |owner| self.apps.enter(owner, |_, _| {}).is_ok());
+        if owner_alive {
+            return Err(ErrorCode::RESERVE);
+        }
+        if self.owner.is_some() {
+            let _ = self.link.stop_advertising();
+            let _ = self.link.disconnect();
+            self.database.map(|database| database.clear_services());
+        }
+        self.owner.set(processid);
+        Ok(())
+    }
+
+    fn schedule_upcall(&self, kind: usize, arg1: usize, arg2: usize) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(owner, |_, kernel_data| {
+                kernel_data
+                    .schedule_upcall(upcalls::EVENT, (kind, arg1, arg2))
+                    .ok();
+            });
+        });
+    }
+
+    /// Copy the first `len` bytes of the RO allow buffer `allow_num` of
+    /// `processid` to `buffer`.
+    fn copy_from_app(
+        &self,
+        processid: ProcessId,
+        allow_num: usize,
+        len: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), ErrorCode> {
+        if len > buffer.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        self.apps
+            .enter(processid, |_, kernel_data| {
+                kernel_data
+                    .get_readonly_processbuffer(allow_num)
+                    .and_then(|allowed| {
+                        allowed.enter(|allowed| {
+                            if len > allowed.len() {
+                                Err(ErrorCode::SIZE)
+                            } else {
+                                allowed[..len].copy_to_slice(&mut buffer[..len]);
+                                Ok(())
+                            }
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::RESERVE))
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
+    fn uuid_from_app(&self, processid: ProcessId, len: usize) -> Result<Uuid, ErrorCode> {
+        let mut uuid = [0; 16];
+        self.copy_from_app(processid, ro_allow::UUID, len, &mut uuid)?;
+        Uuid::from_le_bytes(&uuid[..len]).ok_or(ErrorCode::INVAL)
+    }
+
+    /// Send `payload` on channel `cid`, after the PDU the link layer sends
+    /// now if there is one.
+    fn send_pdu(&self, cid: u16, payload: &[u8], notification: bool) -> Result<(), ErrorCode> {
+        let mut pdu = [0; PDU_LEN];
+        l2cap::write_header(&mut pdu, payload.len(), cid);
+        pdu[l2cap::HEADER_LEN..l2cap::HEADER_LEN + payload.len()].copy_from_slice(payload);
+        let len = l2cap::HEADER_LEN + payload.len();
+
+        match self.link.send(true, &pdu[..len]) {
+            Ok(()) => {
+                self.sending_notification.set(notification);
+                Ok(())
+            }
+            Err(ErrorCode::BUSY) if self.tx_len.get() == 0 => {
+                self.tx.set(pdu);
+                self.tx_len.set(len);
+                self.tx_notification.set(notification);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn pdu_received(&self, cid: u16, payload: &[u8]) {
+        let mut response = [0; att::ATT_MTU];
+        if cid != l2cap::ATT_CID {
+            if let Some(len) = l2cap::reject(cid, payload, &mut response) {
+                let _ = self.send_pdu(cid, &response[..len], false);
+            }
+            return;
+        }
+
+        let handled = self
+            .database
+            .map(|database| database.handle_request(payload, &mut response));
+        let Some(handled) = handled else {
+            return;
+        };
+        if handled.response_len > 0 {
+            let _ = self.send_pdu(cid, &response[..handled.response_len], false);
+        }
+        if let Some(handle) = handled.written {
+            self.value_written(handle);
+        }
+    }
+
+    /// Pass the value the central wrote at `handle` to the owner.
+    fn value_written(&self, handle: u16) {
+        let mut value = [0; att::MAX_VALUE_LEN];
+        let len = self
+            .database
+            .map(|database| {
+                let written = database.value(handle).unwrap_or(&[]);
+                value[..written.len()].copy_from_slice(written);
+                written.len()
+            })
+            .unwrap_or(0);
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(owner, |_, kernel_data| {
+                let copied = kernel_data
+                    .get_readwrite_processbuffer(rw_allow::WRITTEN)
+                    .and_then(|buffer| {
+                        buffer.mut_enter(|appslice| {
+                            let copied = len.min(appslice.len());
+                            appslice[..copied].copy_from_slice(&value[..copied]);
+                            copied
+                        })
+                    })
+                    .unwrap_or(0);
+                kernel_data
+                    .schedule_upcall(upcalls::EVENT, (EVENT_WRITTEN, handle as usize, copied))
+                    .ok();
+            });
+        });
+    }
+
+    fn add_service(&self, processid: ProcessId, uuid_len: usize) -> Result<u16, ErrorCode> {
+        if self.link.is_connected() {
+            return Err(ErrorCode::BUSY);
+        }
+        let uuid = self.uuid_from_app(processid, uuid_len)?;
+        self.database
+            .map(|database| database.add_service(uuid))
+            .unwrap_or(Err(ErrorCode::FAIL))
+    }
+
+    fn add_characteristic(
+        &self,
+        processid: ProcessId,
+        uuid_len: usize,
+        properties: usize,
+    ) -> Result<u16, ErrorCode> {
+        if self.link.is_connected() {
+            return Err(ErrorCode::BUSY);
+        }
+        let uuid = self.uuid_from_app(processid, uuid_len)?;
+        let properties = u8::try_from(properties).map_err(|_| ErrorCode::INVAL)?;
+        self.database
+            .map(|database| database.add_characteristic(uuid, properties))
+            .unwrap_or(Err(ErrorCode::FAIL))
+    }
+
+    fn set_value(&self, processid: ProcessId, handle: usize, len: usize) -> Result<(), ErrorCode> {
+        let handle = u16::try_from(handle).map_err(|_| ErrorCode::INVAL)?;
+        let mut value = [0; att::MAX_VALUE_LEN];
+        self.copy_from_app(processid, ro_allow::VALUE, len, &mut value)?;
+        self.database
+            .map(|database| database.set_value(handle, &value[..len]))
+            .unwrap_or(Err(ErrorCode::FAIL))
+    }
+
+    fn notify(&self, handle: usize) -> Result<(), ErrorCode> {
+        let handle = u16::try_from(handle).map_err(|_| ErrorCode::INVAL)?;
+        if !self.link.is_connected() {
+            return Err(ErrorCode::OFF);
+        }
+        let mut notification = [0; att::ATT_MTU];
+        let len = self
+            .database
+            .map(|database| {
+                if database.notifications_enabled(handle) {
+                    database.notification(handle, &mut notification)
+                } else {
+                    Err(ErrorCode::OFF)
+                }
+            })
+            .unwrap_or(Err(ErrorCode::FAIL))?;
+        self.send_pdu(l2cap::ATT_CID, &notification[..len], true)
+    }
+
+    fn start_advertising(
+        &self,
+        processid: ProcessId,
+        len: usize,
+        interval_ms: usize,
+    ) -> Result<(), ErrorCode> {
+        let mut data = [0; link_layer::MAX_ADVERTISING_DATA];
+        self.copy_from_app(processid, ro_allow::ADVERTISING_DATA, len, &mut data)?;
+        let interval_ms = u32::try_from(interval_ms).map_err(|_| ErrorCode::INVAL)?;
+        self.link.start_advertising(&data[..len], interval_ms)
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> LinkLayerClient for BleGatt<'a, R, A> {
+    fn connected(&self) {
+        self.rx_len.set(0);
+        self.tx_len.set(0);
+        self.schedule_upcall(EVENT_CONNECTED, 0, 0);
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.rx_len.set(0);
+        self.tx_len.set(0);
+        self.database
+            .map(|database| database.reset_client_configurations());
+        self.schedule_upcall(EVENT_DISCONNECTED, reason as usize, 0);
+    }
+
+    fn data_received(&self, start: bool, data: &[u8]) {
+        let mut rx = self.rx.get();
+        let offset = if start { 0 } else { self.rx_len.get() };
+        if (!start && offset == 0) || offset + data.len() > PDU_LEN {
+            // A continuation without a start, or a PDU longer than ATT_MTU
+            // allows.
+            self.rx_len.set(0);
+            return;
+        }
+        rx[offset..offset + data.len()].copy_from_slice(data);
+        let len = offset + data.len();
+        self.rx.set(rx);
+        self.rx_len.set(len);
+
+        if let Some((payload_len, cid)) = l2cap::parse_header(&rx[..len]) {
+            if len == l2cap::HEADER_LEN + payload_len {
+                self.rx_len.set(0);
+                self.pdu_received(cid, &rx[l2cap::HEADER_LEN..len]);
+            } else if len > l2cap::HEADER_LEN + payload_len {
+                self.rx_len.set(0);
+            }
+        }
+    }
+
+    fn data_sent(&self) {
+        if self.sending_notification.replace(false) {
+            self.schedule_upcall(EVENT_NOTIFIED, 0, 0);
+        }
+        let len = self.tx_len.replace(0);
+        if len > 0 && self.link.send(true, &self.tx.get()[..len]).is_ok() {
+            self.sending_notification.set(self.tx_notification.get());
+        }
+    }
+}
+
+impl<'a, R: BleConnectionRadio<'a>, A: Alarm<'a>> SyscallDriver for BleGatt<'a, R, A> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Add a primary service whose `data1` byte UUID (2 or 16 bytes)
+    ///   is in the UUID buffer. Returns the handle of the service.
+    /// - `2`: Add a characteristic to the last service, with the `data1`
+    ///   byte UUID in the UUID buffer and the properties `data2` (read 0x02,
+    ///   write without response 0x04, write 0x08, notify 0x10). Returns the
+    ///   handle of its value.
+    /// - `3`: Set the value at handle `data1` to the first `data2` bytes of
+    ///   the value buffer.
+    /// - `4`: Notify the central of the value at handle `data1`, if it
+    ///   enabled notifications.
+    /// - `5`: Start connectable advertising with the first `data1` bytes of
+    ///   the advertising data buffer every `data2` milliseconds.
+    /// - `6`: Stop advertising.
+    /// - `7`: Disconnect from the central.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        if command_num == 0 {
+            return CommandReturn::success();
+        }
+        if let Err(e) = self.claim(processid) {
+            return CommandReturn::failure(e);
+        }
+
+        match command_num {
+            1 => match self.add_service(processid, data1) {
+                Ok(handle) => CommandReturn::success_u32(handle as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            2 => match self.add_characteristic(processid, data1, data2) {
+                Ok(handle) => CommandReturn::success_u32(handle as u32),
+                Err(e) => CommandReturn::failure(e),
+            },
+
+            3 => self.set_value(processid, data1, data2).into(),
+
+            4 => self.notify(data1).into(),
+
+            5 => self.start_advertising(processid, data1, data2).into(),
+
+            6 => self.link.stop_advertising().into(),
+
+            7 => self.link.disconnect().into(),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
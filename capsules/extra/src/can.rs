@@ -10,45 +10,67 @@
 //! This module has a CAN syscall driver capsule implementation.
 //!
 //! This capsule sends commands from the userspace to a driver that
-//! implements the Can trait.
+//! implements the CAN traits, usually a `virtual_can::VirtualCan` device so
+//! that the bus can be shared with kernel users. Frames carry up to `N`
+//! bytes, which is `STANDARD_CAN_PACKET_SIZE` for classic CAN and
+//! `FD_CAN_PACKET_SIZE` for CAN FD.
 //!
-//! The capsule shares 2 buffers with the userspace: one RO that is used
+//! Any number of processes can use the capsule at the same time:
+//!
+//! - The first process to configure, enable or disable the peripheral owns
+//!   its configuration until it disables the peripheral again. The other
+//!   processes get `RESERVE` for these commands.
+//! - Every process can send messages. The messages of all processes are
+//!   queued and sent one after another, and every process is told when its
+//!   own message was sent.
+//! - Every process can receive messages. A process may set identifier
+//!   filters, in which case it only receives messages matching one of them.
+//!   The peripheral's filter banks cannot select identifiers while receiving
+//!   (see `hil::can::Receive`), so the filters are applied in software.
+//!
+//! The capsule shares 2 buffers with each process: one RO that is used
 //! for transmitting messages and one RW that is used for receiving
 //! messages.
 //!
-//! The RO buffer uses the first 4 bytes as a counter of how many messages
-//! the userspace must read, at the time the upcall was sent. If the
-//! userspace is slower and in the meantime there were other messages
-//! that were received, the userspace reads them all and sends to the
-//! capsule a new buffer that has the counter on the first 4 bytes 0.
-//! Because of that, when receiving a callback from the driver regarding
-//! a received message, the capsule checks the counter:
-//! - if it's 0, the message will be copied to the RW buffer, the counter
-//!   will be incremented and an upcall will be sent
-//! - if it's greater the 0, the message will be copied to the RW buffer
-//!   but no upcall will be done
+//! The RW buffer is a `StreamingProcessSlice`: the process's own queue of
+//! received messages. The process swaps in a new buffer to read the received
+//! messages, and messages that do not fit anymore are lost. By default each
+//! message is appended as its `STANDARD_CAN_PACKET_SIZE` data bytes, as in
+//! the single process version of this capsule, and messages with more data
+//! are lost. A process that selects the record format (command 14) instead
+//! gets each message as a record of
+//!
+//! - the identifier, 4 bytes little endian, with bit 31 set for an extended
+//!   identifier,
+//! - the data length, 1 byte,
+//! - the data.
+//!
+//! Command 8 stops receiving for all processes, as it did when a single
+//! process used the capsule. Command 13 only stops receiving for the calling
+//! process.
 //!
 //! Usage
 //! -----
 //!
-//! You need a driver that implements the Can trait.
+//! You need a driver that implements the CAN traits.
 //! ```rust,ignore
 //! let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
 //! let grant_can = self.board_kernel.create_grant(
 //!     capsules::can::CanCapsule::DRIVER_NUM, &grant_cap);
 //! let can = capsules::can::CanCapsule::new(
-//!    can_peripheral,
+//!    can_device,
 //!    grant_can,
 //!    tx_buffer,
 //!    rx_buffer,
 //! );
 //!
-//! kernel::hil::can::Controller::set_client(can_peripheral, Some(can));
-//! kernel::hil::can::Transmit::set_client(can_peripheral, Some(can));
-//! kernel::hil::can::Receive::set_client(can_peripheral, Some(can));
+//! kernel::hil::can::Controller::set_client(can_device, Some(can));
+//! kernel::hil::can::Transmit::set_client(can_device, Some(can));
+//! kernel::hil::can::Receive::set_client(can_device, Some(can));
 //! ```
 //!
 
+use core::cell::Cell;
 use core::mem::size_of;
 
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
@@ -67,6 +89,16 @@ pub const BYTE3_MASK: usize = 0xff0000;
 pub const BYTE2_MASK: usize = 0xff00;
 pub const BYTE1_MASK: usize = 0xff;
 
+/// Bit of an identifier passed to or from userspace that marks it as
+/// extended.
+pub const EXTENDED_ID_FLAG: u32 = 1 << 31;
+
+/// Number of identifier filters each process can set.
+pub const FILTER_COUNT: usize = 8;
+
+/// Length of the identifier and length fields of a received message record.
+const RECORD_HEADER_LEN: usize = size_of::<u32>() + 1;
+
 mod error_upcalls {
     pub const ERROR_TX: usize = 100;
     pub const ERROR_RX: usize = 101;
@@ -92,13 +124,51 @@ mod rw_allow {
     pub const COUNT: u8 = 1;
 }
 
-pub struct CanCapsule<'a, Can: can::Can> {
+/// Layout of the messages a process receives in its RW buffer.
+#[derive(Copy, Clone, Default, PartialEq)]
+enum RxFormat {
+    /// The `STANDARD_CAN_PACKET_SIZE` data bytes of each message.
+    #[default]
+    Data,
+    /// Identifier, length and data of each message.
+    Record,
+}
+
+/// Accepts the messages whose identifier matches `id` in all bits set in
+/// `mask`.
+#[derive(Copy, Clone)]
+struct IdFilter {
+    id: u32,
+    mask: u32,
+    extended: bool,
+}
+
+impl IdFilter {
+    fn matches(&self, id: can::Id) -> bool {
+        match id {
+            can::Id::Standard(id) => !self.extended && (u32::from(id) ^ self.id) & self.mask == 0,
+            can::Id::Extended(id) => self.extended && (id ^ self.id) & self.mask == 0,
+        }
+    }
+}
+
+fn id_to_user(id: can::Id) -> u32 {
+    match id {
+        can::Id::Standard(id) => u32::from(id),
+        can::Id::Extended(id) => id | EXTENDED_ID_FLAG,
+    }
+}
+
+pub struct CanCapsule<'a, Can, const N: usize = { can::STANDARD_CAN_PACKET_SIZE }>
+where
+    Can: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
     // CAN driver
     can: &'a Can,
 
     // CAN buffers
-    can_tx: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
-    can_rx: TakeCell<'static, [u8; can::STANDARD_CAN_PACKET_SIZE]>,
+    can_tx: TakeCell<'static, [u8; N]>,
+    can_rx: TakeCell<'static, [u8; N]>,
 
     // Process
     processes: Grant<
@@ -107,7 +177,12 @@ pub struct CanCapsule<'a, Can: can::Can> {
         AllowRoCount<{ ro_allow::COUNT }>,
         AllowRwCount<{ rw_allow::COUNT }>,
     >,
+    // Process that owns the configuration of the peripheral
     processid: OptionalCell<ProcessId>,
+    // Process whose message is being sent
+    sending: OptionalCell<ProcessId>,
+    // Whether receiving was stopped and `can_rx` is on its way back
+    stopping: Cell<bool>,
 
     // Variable used to store the current state of the CAN peripheral
     // during an `enable` or `disable` command.
@@ -117,9 +192,25 @@ pub struct CanCapsule<'a, Can: can::Can> {
 #[derive(Default)]
 pub struct App {
     lost_messages: u32,
+    receiving: bool,
+    // Whether the process is told when the peripheral stopped receiving
+    stop_pending: bool,
+    rx_format: RxFormat,
+    filters: [Option<IdFilter>; FILTER_COUNT],
+    pending_message: Option<(can::Id, usize)>,
 }
 
-impl<'a, Can: can::Can> CanCapsule<'a, Can> {
+impl App {
+    fn accepts(&self, id: can::Id) -> bool {
+        let mut filters = self.filters.iter().flatten().peekable();
+        filters.peek().is_none() || filters.any(|filter| filter.matches(id))
+    }
+}
+
+impl<'a, Can, const N: usize> CanCapsule<'a, Can, N>
+where
+    Can: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
     pub fn new(
         can: &'a Can,
         grant: Grant<
@@ -128,9 +219,9 @@ impl<'a, Can: can::Can> CanCapsule<'a, Can> {
             AllowRoCount<{ ro_allow::COUNT }>,
             AllowRwCount<{ rw_allow::COUNT }>,
         >,
-        can_tx: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
-        can_rx: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
-    ) -> CanCapsule<'a, Can> {
+        can_tx: &'static mut [u8; N],
+        can_rx: &'static mut [u8; N],
+    ) -> CanCapsule<'a, Can, N> {
         CanCapsule {
             can,
             can_tx: TakeCell::new(can_tx),
@@ -138,16 +229,27 @@ impl<'a, Can: can::Can> CanCapsule<'a, Can> {
             processes: grant,
             peripheral_state: OptionalCell::empty(),
             processid: OptionalCell::empty(),
+            sending: OptionalCell::empty(),
+            stopping: Cell::new(false),
         }
     }
 
     fn schedule_callback(&self, callback_number: usize, data: (usize, usize, usize)) {
         self.processid.map(|processid| {
-            let _ = self.processes.enter(processid, |_app, kernel_data| {
-                kernel_data
-                    .schedule_upcall(callback_number, (data.0, data.1, data.2))
-                    .ok();
-            });
+            self.schedule_callback_for(processid, callback_number, data);
+        });
+    }
+
+    fn schedule_callback_for(
+        &self,
+        processid: ProcessId,
+        callback_number: usize,
+        data: (usize, usize, usize),
+    ) {
+        let _ = self.processes.enter(processid, |_app, kernel_data| {
+            kernel_data
+                .schedule_upcall(callback_number, (data.0, data.1, data.2))
+                .ok();
         });
     }
 
@@ -171,9 +273,11 @@ impl<'a, Can: can::Can> CanCapsule<'a, Can> {
                                     self.can_tx.take().map_or(
                                         Err(ErrorCode::NOMEM),
                                         |dest_buffer| {
-                                            for i in 0..length {
-                                                dest_buffer[i] = buffer[i].get();
-                                            }
+                                            let Some(source) = buffer.get(..length) else {
+                                                self.can_tx.replace(dest_buffer);
+                                                return Err(ErrorCode::SIZE);
+                                            };
+                                            source.copy_to_slice(&mut dest_buffer[..length]);
                                             match self.can.send(id, dest_buffer, length) {
                                                 Ok(()) => Ok(()),
                                                 Err((err, buf)) => {
@@ -191,6 +295,149 @@ impl<'a, Can: can::Can> CanCapsule<'a, Can> {
             .unwrap_or_else(|err| err.into())
     }
 
+    /// Send the next queued message of any process, if no message is being
+    /// sent.
+    fn send_next(&self) {
+        while self.sending.is_none() {
+            let mut next = None;
+            for app in self.processes.iter() {
+                let processid = app.processid();
+                if let Some(message) = app.enter(|app, _| app.pending_message.take()) {
+                    next = Some((processid, message));
+                    break;
+                }
+            }
+            let Some((processid, (id, length))) = next else {
+                return;
+            };
+            match self.process_send_command(processid, id, length) {
+                Ok(()) => self.sending.set(processid),
+                Err(err) => self.schedule_callback_for(
+                    processid,
+                    up_calls::UPCALL_TRANSMISSION_ERROR,
+                    (error_upcalls::ERROR_TX, err as usize, 0),
+                ),
+            }
+        }
+    }
+
+    fn queue_message(&self, processid: ProcessId, id: can::Id, length: usize) -> CommandReturn {
+        if length > N {
+            return CommandReturn::failure(ErrorCode::SIZE);
+        }
+        let queued = self
+            .processes
+            .enter(processid, |app, _| {
+                if app.pending_message.is_some() || self.sending.contains(&processid) {
+                    Err(ErrorCode::BUSY)
+                } else {
+                    app.pending_message = Some((id, length));
+                    Ok(())
+                }
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        if queued.is_ok() {
+            self.send_next();
+        }
+        queued.into()
+    }
+
+    fn start_receiving(&self, processid: ProcessId) -> CommandReturn {
+        let result = self
+            .processes
+            .enter(processid, |app, kernel| {
+                kernel
+                    .get_readwrite_processbuffer(rw_allow::RW_ALLOW_BUFFER)
+                    .map_or_else(
+                        |err| err.into(),
+                        |buffer_ref| {
+                            buffer_ref
+                                .enter(|buffer| {
+                                    // make sure that the receiving buffer can have at least
+                                    // 2 messages and the counter
+                                    let min_len = match app.rx_format {
+                                        RxFormat::Data => {
+                                            2 * can::STANDARD_CAN_PACKET_SIZE + size_of::<u32>()
+                                        }
+                                        RxFormat::Record => 2 * (RECORD_HEADER_LEN + N) + 8,
+                                    };
+                                    if buffer.len() >= min_len {
+                                        Ok(())
+                                    } else {
+                                        Err(ErrorCode::SIZE)
+                                    }
+                                })
+                                .unwrap_or_else(|err| err.into())
+                        },
+                    )
+                    .map(|()| app.receiving = true)
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        if let Err(err) = result {
+            return CommandReturn::failure(err);
+        }
+
+        // Receiving that is being stopped restarts when `can_rx` is back.
+        match self.can_rx.take() {
+            Some(buffer) => match self.can.start_receive_process(buffer) {
+                Ok(()) => CommandReturn::success(),
+                Err((err, buffer)) => {
+                    self.can_rx.replace(buffer);
+                    let _ = self
+                        .processes
+                        .enter(processid, |app, _| app.receiving = false);
+                    CommandReturn::failure(err)
+                }
+            },
+            None => CommandReturn::success(),
+        }
+    }
+
+    /// Stop receiving for all processes. Each of them is told once the
+    /// peripheral has stopped.
+    fn stop_all_receiving(&self) -> CommandReturn {
+        if !self.stopping.get() {
+            if let Err(err) = self.can.stop_receive() {
+                return CommandReturn::failure(err);
+            }
+            self.stopping.set(true);
+        }
+        self.processes.each(|_, app, _| {
+            if app.receiving {
+                app.receiving = false;
+                app.stop_pending = true;
+            }
+        });
+        CommandReturn::success()
+    }
+
+    fn stop_receiving(&self, processid: ProcessId) -> CommandReturn {
+        let was_receiving = self
+            .processes
+            .enter(processid, |app, _| {
+                core::mem::replace(&mut app.receiving, false)
+            })
+            .unwrap_or(false);
+        if !was_receiving {
+            return CommandReturn::failure(ErrorCode::OFF);
+        }
+        self.schedule_callback_for(processid, up_calls::UPCALL_RECEIVED_STOPPED, (0, 0, 0));
+
+        if !self.any_receiving() && self.can_rx.is_none() && !self.stopping.get() {
+            match self.can.stop_receive() {
+                Ok(()) => self.stopping.set(true),
+                Err(err) => return CommandReturn::failure(err),
+            }
+        }
+        CommandReturn::success()
+    }
+
+    fn any_receiving(&self) -> bool {
+        self.processes
+            .iter()
+            .any(|app| app.enter(|app, _| app.receiving))
+    }
+
     pub fn is_valid_process(&self, processid: ProcessId) -> bool {
         self.processid.map_or(true, |owning_process| {
             self.processes
@@ -200,7 +447,10 @@ impl<'a, Can: can::Can> CanCapsule<'a, Can> {
     }
 }
 
-impl<Can: can::Can> SyscallDriver for CanCapsule<'_, Can> {
+impl<Can, const N: usize> SyscallDriver for CanCapsule<'_, Can, N>
+where
+    Can: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
     fn command(
         &self,
         command_num: usize,
@@ -214,12 +464,14 @@ impl<Can: can::Can> SyscallDriver for CanCapsule<'_, Can> {
         }
 
         // Check to see if the process or no process at all
-        // owns the capsule. Only one application can use the
-        // capsule at a time.
-        if !self.is_valid_process(processid) {
-            return CommandReturn::failure(ErrorCode::RESERVE);
-        } else {
-            self.processid.set(processid);
+        // owns the configuration of the peripheral. Only one
+        // application can configure it at a time.
+        if matches!(command_num, 1..=4 | 8 | 9) {
+            if !self.is_valid_process(processid) {
+                return CommandReturn::failure(ErrorCode::RESERVE);
+            } else {
+                self.processid.set(processid);
+            }
         }
 
         match command_num {
@@ -255,73 +507,16 @@ impl<Can: can::Can> SyscallDriver for CanCapsule<'_, Can> {
             },
 
             // Send a message with a 16-bit identifier
-            5 => {
-                let id = can::Id::Standard(arg1 as u16);
-                self.processid
-                    .map_or(
-                        CommandReturn::failure(ErrorCode::BUSY),
-                        |processid| match self.process_send_command(processid, id, arg2) {
-                            Ok(()) => CommandReturn::success(),
-                            Err(err) => CommandReturn::failure(err),
-                        },
-                    )
-            }
+            5 => self.queue_message(processid, can::Id::Standard(arg1 as u16), arg2),
 
             // Send a message with a 32-bit identifier
-            6 => {
-                let id = can::Id::Extended(arg1 as u32);
-                self.processid
-                    .map_or(
-                        CommandReturn::failure(ErrorCode::BUSY),
-                        |processid| match self.process_send_command(processid, id, arg2) {
-                            Ok(()) => CommandReturn::success(),
-                            Err(err) => CommandReturn::failure(err),
-                        },
-                    )
-            }
+            6 => self.queue_message(processid, can::Id::Extended(arg1 as u32), arg2),
 
             // Start receiving messages
-            7 => {
-                self.can_rx
-                    .take()
-                    .map_or(CommandReturn::failure(ErrorCode::NOMEM), |dest_buffer| {
-                        self.processes
-                            .enter(processid, |_, kernel| {
-                                match kernel.get_readwrite_processbuffer(0).map_or_else(
-                                    |err| err.into(),
-                                    |buffer_ref| {
-                                        buffer_ref
-                                            .enter(|buffer| {
-                                                // make sure that the receiving buffer can have at least
-                                                // 2 messages of 8 bytes each and 4 another bytes for the counter
-                                                if buffer.len()
-                                                    >= 2 * can::STANDARD_CAN_PACKET_SIZE
-                                                        + size_of::<u32>()
-                                                {
-                                                    Ok(())
-                                                } else {
-                                                    Err(ErrorCode::SIZE)
-                                                }
-                                            })
-                                            .unwrap_or_else(|err| err.into())
-                                    },
-                                ) {
-                                    Ok(()) => match self.can.start_receive_process(dest_buffer) {
-                                        Ok(()) => CommandReturn::success(),
-                                        Err((err, _)) => CommandReturn::failure(err),
-                                    },
-                                    Err(err) => CommandReturn::failure(err),
-                                }
-                            })
-                            .unwrap_or_else(|err| err.into())
-                    })
-            }
+            7 => self.start_receiving(processid),
 
-            // Stop receiving messages
-            8 => match self.can.stop_receive() {
-                Ok(()) => CommandReturn::success(),
-                Err(err) => CommandReturn::failure(err),
-            },
+            // Stop receiving messages for all processes
+            8 => self.stop_all_receiving(),
 
             // Set the timing parameters
             9 => {
@@ -337,6 +532,65 @@ impl<Can: can::Can> SyscallDriver for CanCapsule<'_, Can> {
                 }
             }
 
+            // Add an identifier filter
+            //
+            // arg1 - identifier, with `EXTENDED_ID_FLAG` set for an
+            //        extended identifier
+            // arg2 - mask of the identifier bits that must match
+            10 => self
+                .processes
+                .enter(processid, |app, _| {
+                    let filter = IdFilter {
+                        id: arg1 as u32 & !EXTENDED_ID_FLAG,
+                        mask: arg2 as u32,
+                        extended: arg1 as u32 & EXTENDED_ID_FLAG != 0,
+                    };
+                    match app.filters.iter_mut().find(|slot| slot.is_none()) {
+                        Some(slot) => {
+                            *slot = Some(filter);
+                            CommandReturn::success()
+                        }
+                        None => CommandReturn::failure(ErrorCode::NOMEM),
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Remove all identifier filters
+            11 => self
+                .processes
+                .enter(processid, |app, _| {
+                    app.filters = [None; FILTER_COUNT];
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Maximum data length of a message
+            12 => CommandReturn::success_u32(N as u32),
+
+            // Stop receiving messages for this process
+            13 => self.stop_receiving(processid),
+
+            // Select the layout of received messages
+            //
+            // arg1 - 0 for the data only, 1 for records
+            14 => {
+                let format = match arg1 {
+                    0 => RxFormat::Data,
+                    1 => RxFormat::Record,
+                    _ => return CommandReturn::failure(ErrorCode::INVAL),
+                };
+                self.processes
+                    .enter(processid, |app, _| {
+                        if app.receiving {
+                            CommandReturn::failure(ErrorCode::BUSY)
+                        } else {
+                            app.rx_format = format;
+                            CommandReturn::success()
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -346,7 +600,10 @@ impl<Can: can::Can> SyscallDriver for CanCapsule<'_, Can> {
     }
 }
 
-impl<Can: can::Can> can::ControllerClient for CanCapsule<'_, Can> {
+impl<Can, const N: usize> can::ControllerClient for CanCapsule<'_, Can, N>
+where
+    Can: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
     // This callback must be called after an `enable` or `disable` command was sent.
     // It stores the new state of the peripheral.
     fn state_changed(&self, state: can::State) {
@@ -411,93 +668,144 @@ impl<Can: can::Can> can::ControllerClient for CanCapsule<'_, Can> {
     }
 }
 
-impl<Can: can::Can> can::TransmitClient<{ can::STANDARD_CAN_PACKET_SIZE }> for CanCapsule<'_, Can> {
+impl<Can, const N: usize> can::TransmitClient<N> for CanCapsule<'_, Can, N>
+where
+    Can: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
     // This callback is called when the hardware acknowledges that a message
-    // was sent. This callback also makes an upcall to the userspace.
-    fn transmit_complete(
-        &self,
-        status: Result<(), can::Error>,
-        buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE],
-    ) {
+    // was sent. This callback also makes an upcall to the process that sent it.
+    fn transmit_complete(&self, status: Result<(), can::Error>, buffer: &'static mut [u8; N]) {
         self.can_tx.replace(buffer);
-        match status {
-            Ok(()) => self.schedule_callback(up_calls::UPCALL_MESSAGE_SENT, (0, 0, 0)),
+        self.sending.take().map(|processid| match status {
+            Ok(()) => {
+                self.schedule_callback_for(processid, up_calls::UPCALL_MESSAGE_SENT, (0, 0, 0))
+            }
             Err(err) => {
-                self.schedule_callback(
+                self.schedule_callback_for(
+                    processid,
                     up_calls::UPCALL_TRANSMISSION_ERROR,
                     (error_upcalls::ERROR_TX, err as usize, 0),
                 );
             }
-        }
+        });
+        self.send_next();
     }
 }
 
-impl<Can: can::Can> can::ReceiveClient<{ can::STANDARD_CAN_PACKET_SIZE }> for CanCapsule<'_, Can> {
+impl<Can, const N: usize> can::ReceiveClient<N> for CanCapsule<'_, Can, N>
+where
+    Can: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
     // This callback is called when a new message is received on any receiving
-    // fifo.
+    // fifo. The message is added to the queue of every receiving process whose
+    // filters accept it.
     fn message_received(
         &self,
         id: can::Id,
-        buffer: &mut [u8; can::STANDARD_CAN_PACKET_SIZE],
-        _len: usize,
+        buffer: &mut [u8; N],
+        len: usize,
         status: Result<(), can::Error>,
     ) {
-        match status {
-            Ok(()) => {
-                let res: Result<(bool, u32), ErrorCode> =
-                    self.processid.map_or(Err(ErrorCode::NOMEM), |processid| {
-                        self.processes
-                            .enter(processid, |app_data, kernel_data| {
-                                kernel_data
-                                    .get_readwrite_processbuffer(rw_allow::RW_ALLOW_BUFFER)
-                                    .map_or_else(
-                                        |err| Err(err.into()),
-                                        |buffer_ref| {
-                                            buffer_ref
-                                                .mut_enter(|user_slice| {
-                                                    StreamingProcessSlice::new(user_slice)
-                                                        .append_chunk(buffer)
-                                                        .inspect_err(|_err| {
-                                                            app_data.lost_messages += 1;
-                                                        })
-                                                })
-                                                .unwrap_or_else(|err| Err(err.into()))
-                                        },
-                                    )
-                            })
-                            .unwrap_or_else(|err| Err(err.into()))
-                    });
+        let len = len.min(N);
+        let mut record = [0; RECORD_HEADER_LEN + can::FD_CAN_PACKET_SIZE];
+        record[..4].copy_from_slice(&id_to_user(id).to_le_bytes());
+        record[4] = len as u8;
+        record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len].copy_from_slice(&buffer[..len]);
+        let record = &record[..RECORD_HEADER_LEN + len];
+        let data = &buffer[..can::STANDARD_CAN_PACKET_SIZE];
+
+        self.processes.each(|_, app, kernel_data| {
+            if !app.receiving {
+                return;
+            }
+            let (chunk, user_id) = match app.rx_format {
+                RxFormat::Data => (data, id_to_user(id) & !EXTENDED_ID_FLAG),
+                RxFormat::Record => (record, id_to_user(id)),
+            };
+            let res = match status {
+                Ok(()) if !app.accepts(id) => return,
+                Ok(()) if app.rx_format == RxFormat::Data && len > data.len() => {
+                    app.lost_messages += 1;
+                    return;
+                }
+                Ok(()) => kernel_data
+                    .get_readwrite_processbuffer(rw_allow::RW_ALLOW_BUFFER)
+                    .map_or_else(
+                        |err| Err(err.into()),
+                        |buffer_ref| {
+                            buffer_ref
+                                .mut_enter(|user_slice| {
+                                    StreamingProcessSlice::new(user_slice)
+                                        .append_chunk(chunk)
+                                        .inspect_err(|_err| {
+                                            app.lost_messages += 1;
+                                        })
+                                })
+                                .unwrap_or_else(|err| Err(err.into()))
+                        },
+                    ),
+                Err(err) => Err(err.into()),
+            };
 
-                match res {
-                    Err(err) => self.schedule_callback(
+            match res {
+                Err(err) => kernel_data
+                    .schedule_upcall(
                         up_calls::UPCALL_TRANSMISSION_ERROR,
                         (error_upcalls::ERROR_RX, err as usize, 0),
-                    ),
-                    Ok((_first_chunk, new_offset)) => self.schedule_callback(
+                    )
+                    .ok(),
+                Ok((_first_chunk, new_offset)) => kernel_data
+                    .schedule_upcall(
                         up_calls::UPCALL_MESSAGE_RECEIVED,
-                        (
-                            0,
-                            new_offset as usize,
-                            match id {
-                                can::Id::Standard(u16) => u16 as usize,
-                                can::Id::Extended(u32) => u32 as usize,
-                            },
-                        ),
-                    ),
-                }
+                        (0, new_offset as usize, user_id as usize),
+                    )
+                    .ok(),
+            };
+        });
+    }
+
+    fn stopped(&self, buffer: &'static mut [u8; N]) {
+        self.stopping.set(false);
+        self.processes.each(|_, app, kernel_data| {
+            if core::mem::take(&mut app.stop_pending) {
+                kernel_data
+                    .schedule_upcall(up_calls::UPCALL_RECEIVED_STOPPED, (0, 0, 0))
+                    .ok();
             }
-            Err(err) => {
-                let kernel_err: ErrorCode = err.into();
-                self.schedule_callback(
-                    up_calls::UPCALL_TRANSMISSION_ERROR,
-                    (error_upcalls::ERROR_RX, kernel_err.into(), 0),
-                )
+        });
+        if self.any_receiving() {
+            if let Err((_, buffer)) = self.can.start_receive_process(buffer) {
+                self.can_rx.replace(buffer);
             }
-        };
+        } else {
+            self.can_rx.replace(buffer);
+        }
     }
+}
 
-    fn stopped(&self, buffer: &'static mut [u8; can::STANDARD_CAN_PACKET_SIZE]) {
-        self.can_rx.replace(buffer);
-        self.schedule_callback(up_calls::UPCALL_RECEIVED_STOPPED, (0, 0, 0));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_select_identifiers() {
+        let mut app = App::default();
+        assert!(app.accepts(can::Id::Standard(0x123)));
+
+        app.filters[0] = Some(IdFilter {
+            id: 0x120,
+            mask: 0x7f0,
+            extended: false,
+        });
+        app.filters[3] = Some(IdFilter {
+            id: 0x18DA_F110,
+            mask: 0x1fff_ffff,
+            extended: true,
+        });
+        assert!(app.accepts(can::Id::Standard(0x12f)));
+        assert!(!app.accepts(can::Id::Standard(0x130)));
+        assert!(!app.accepts(can::Id::Extended(0x120)));
+        assert!(app.accepts(can::Id::Extended(0x18DA_F110)));
+        assert!(!app.accepts(can::Id::Extended(0x18DA_F111)));
     }
 }
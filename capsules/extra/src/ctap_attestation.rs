@@ -0,0 +1,303 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! CTAP Attestation Key Userspace Driver.
+//!
+//! Gives a CTAP2 authenticator app the public half of the attestation key
+//! and signs attestation statements with the private half, which never
+//! leaves the key device (for example the `Atecc508a` secure element). This
+//! is a reference flow for a P-256 ECDSA key, with 32 byte SHA-256 hashes
+//! and 64 byte raw `r || s` signatures.
+//!
+//! The board makes the public key available before the driver is used, for
+//! example with `Atecc508a::read_public_key()` or `import_public_key()`.
+//!
+//! Any app can read the public key, but only the apps whose `ShortId` is in
+//! the list of signers passed to `new()` can sign with the attestation key.
+//! Apps without a fixed `ShortId` can never sign.
+//!
+//! ```rust,ignore
+//! +===============+
+//! ||  Userspace  ||
+//! +===============+
+//!
+//! -----Syscall Interface-----
+//!
+//! +---------------------------------+
+//! |  CTAP Attestation (this file)   |
+//! +---------------------------------+
+//!
+//!    hil::public_key_crypto::keys::PubKey
+//!    hil::public_key_crypto::signature::SignatureSign
+//! ```
+
+use capsules_core::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::CtapAttestation as usize;
+
+use core::cmp;
+use kernel::errorcode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
+use kernel::hil::public_key_crypto::keys::PubKey;
+use kernel::hil::public_key_crypto::signature::{ClientSign, SignatureSign};
+use kernel::process::ShortId;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+/// Length of the hashes to sign.
+pub const HASH_LEN: usize = 32;
+/// Length of the signatures.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// IDs for read-only allow buffers.
+mod ro_allow {
+    /// Hash to sign.
+    pub const HASH: usize = 0;
+    /// The number of RO allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for read-write allow buffers.
+mod rw_allow {
+    /// Output public key.
+    pub const PUBLIC_KEY: usize = 0;
+    /// Output signature.
+    pub const SIGNATURE: usize = 1;
+    /// The number of RW allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 2;
+}
+
+/// IDs for upcalls.
+mod upcalls {
+    /// Single upcall.
+    pub const DONE: usize = 0;
+    /// The number of upcalls the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum UserSpaceOp {
+    PublicKey,
+    Sign,
+}
+
+#[derive(Default)]
+pub struct App {}
+
+pub struct CtapAttestation<'a, K: PubKey + SignatureSign<'a, HASH_LEN, SIGNATURE_LEN>> {
+    key: &'a K,
+    /// The apps that may sign with the attestation key.
+    signers: &'a [ShortId],
+    apps: Grant<
+        App,
+        UpcallCount<{ upcalls::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// The app whose operation is in progress, and the operation.
+    processid: OptionalCell<ProcessId>,
+    op: OptionalCell<UserSpaceOp>,
+    hash: TakeCell<'static, [u8; HASH_LEN]>,
+    signature: TakeCell<'static, [u8; SIGNATURE_LEN]>,
+}
+
+impl<'a, K: PubKey + SignatureSign<'a, HASH_LEN, SIGNATURE_LEN>> CtapAttestation<'a, K> {
+    pub fn new(
+        key: &'a K,
+        signers: &'a [ShortId],
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+        grant: Grant<
+            App,
+            UpcallCount<{ upcalls::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> CtapAttestation<'a, K> {
+        CtapAttestation {
+            key,
+            signers,
+            apps: grant,
+            processid: OptionalCell::empty(),
+            op: OptionalCell::empty(),
+            hash: TakeCell::new(hash),
+            signature: TakeCell::new(signature),
+        }
+    }
+
+    /// Whether `processid` may sign with the attestation key.
+    fn is_signer(&self, processid: ProcessId) -> bool {
+        let short_id = processid.short_app_id();
+        self.signers.iter().any(|signer| *signer == short_id)
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        let processid = self.processid.get().ok_or(ErrorCode::RESERVE)?;
+        match self.op.get().ok_or(ErrorCode::FAIL)? {
+            UserSpaceOp::PublicKey => {
+                let public_key = self.key.pub_key()?;
+                self.apps
+                    .enter(processid, |_, kernel_data| {
+                        let ret = copy_out(kernel_data, rw_allow::PUBLIC_KEY, public_key);
+                        kernel_data
+                            .schedule_upcall(
+                                upcalls::DONE,
+                                (errorcode::into_statuscode(ret), public_key.len(), 0),
+                            )
+                            .ok();
+                    })
+                    .map_err(ErrorCode::from)?;
+                self.processid.clear();
+                self.op.clear();
+                Ok(())
+            }
+            UserSpaceOp::Sign => {
+                let hash = self.hash.take().ok_or(ErrorCode::BUSY)?;
+                let signature = match self.signature.take() {
+                    Some(buffer) => buffer,
+                    None => {
+                        self.hash.replace(hash);
+                        return Err(ErrorCode::BUSY);
+                    }
+                };
+                self.key
+                    .sign(hash, signature)
+                    .map_err(|(e, hash, signature)| {
+                        self.hash.replace(hash);
+                        self.signature.replace(signature);
+                        e
+                    })
+            }
+        }
+    }
+}
+
+/// Copy `data` to the RW allow buffer `allow_num`. Returns `SIZE` if only
+/// part of it fits.
+fn copy_out(kernel_data: &GrantKernelData, allow_num: usize, data: &[u8]) -> Result<(), ErrorCode> {
+    kernel_data
+        .get_readwrite_processbuffer(allow_num)
+        .and_then(|buffer| {
+            buffer.mut_enter(|appslice| {
+                let copy_len = cmp::min(data.len(), appslice.len());
+                appslice[..copy_len].copy_from_slice(&data[..copy_len]);
+                if copy_len < data.len() {
+                    Err(ErrorCode::SIZE)
+                } else {
+                    Ok(())
+                }
+            })
+        })
+        .unwrap_or(Err(ErrorCode::RESERVE))
+}
+
+impl<'a, K: PubKey + SignatureSign<'a, HASH_LEN, SIGNATURE_LEN>> ClientSign<HASH_LEN, SIGNATURE_LEN>
+    for CtapAttestation<'a, K>
+{
+    fn signing_done(
+        &self,
+        result: Result<(), ErrorCode>,
+        hash: &'static mut [u8; HASH_LEN],
+        signature: &'static mut [u8; SIGNATURE_LEN],
+    ) {
+        self.op.clear();
+        self.processid.take().map(|processid| {
+            let _ = self.apps.enter(processid, |_, kernel_data| {
+                let ret = result
+                    .and_then(|()| copy_out(kernel_data, rw_allow::SIGNATURE, &signature[..]));
+                kernel_data
+                    .schedule_upcall(
+                        upcalls::DONE,
+                        (errorcode::into_statuscode(ret), SIGNATURE_LEN, 0),
+                    )
+                    .ok();
+            });
+        });
+        // Do not keep signatures around.
+        signature.fill(0);
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+    }
+}
+
+impl<'a, K: PubKey + SignatureSign<'a, HASH_LEN, SIGNATURE_LEN>> SyscallDriver
+    for CtapAttestation<'a, K>
+{
+    /// Command interface.
+    ///
+    /// The upcall passes the status code and the length of the public key or
+    /// signature.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Copy the public attestation key to the public key buffer.
+    /// - `2`: Sign the 32 byte hash in the hash buffer with the private
+    ///   attestation key, and copy the signature to the signature buffer.
+    ///   Returns `NOSUPPORT` if the app is not allowed to sign.
+    fn command(
+        &self,
+        command_num: usize,
+        _data1: usize,
+        _data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let op = match command_num {
+            0 => return CommandReturn::success(),
+            1 => UserSpaceOp::PublicKey,
+            2 => UserSpaceOp::Sign,
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+
+        if self.processid.is_some() {
+            return CommandReturn::failure(ErrorCode::BUSY);
+        }
+
+        if op == UserSpaceOp::Sign {
+            if !self.is_signer(processid) {
+                return CommandReturn::failure(ErrorCode::NOSUPPORT);
+            }
+
+            let copied = self.hash.map_or(Err(ErrorCode::BUSY), |hash| {
+                self.apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readonly_processbuffer(ro_allow::HASH)
+                            .and_then(|buffer| {
+                                buffer.enter(|data| {
+                                    if data.len() < HASH_LEN {
+                                        Err(ErrorCode::SIZE)
+                                    } else {
+                                        data[..HASH_LEN].copy_to_slice(hash);
+                                        Ok(())
+                                    }
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    })
+                    .unwrap_or_else(|err| Err(err.into()))
+            });
+            if let Err(e) = copied {
+                return CommandReturn::failure(e);
+            }
+        }
+
+        self.processid.set(processid);
+        self.op.set(op);
+        match self.run() {
+            Ok(()) => CommandReturn::success(),
+            Err(e) => {
+                self.processid.clear();
+                self.op.clear();
+                CommandReturn::failure(e)
+            }
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -0,0 +1,295 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! CTAP2 Authenticator Userspace Driver.
+//!
+//! Passes whole CTAP2 CBOR requests, as reassembled by a CTAP transport such
+//! as `usb::ctaphid::CtapHidTransport`, to the app that implements the
+//! authenticator, and sends its responses back to the host.
+//!
+//! ```rust,ignore
+//! +===============+
+//! ||  Userspace  ||
+//! +===============+
+//!
+//! -----Syscall Interface-----
+//!
+//! +---------------------------------+
+//! |  CTAP Driver (this file)        |
+//! +---------------------------------+
+//!
+//!    usb::ctaphid::CtapTransport
+//!
+//! +---------------------------------+
+//! |  CTAPHID transport              |
+//! +---------------------------------+
+//! ```
+//!
+//! Only one app can be the authenticator. An app claims the authenticator
+//! with command 1, and keeps it until it exits. While no app has claimed it,
+//! or if a request does not fit in the app's buffer, the driver answers
+//! requests itself with a CTAP2 error status.
+//!
+//! The upcall passes `(kind, arg, 0)`, where `kind` is:
+//!
+//! - 0: A request was copied to the request buffer, `arg` is its length.
+//! - 1: The response was sent, `arg` is the status code.
+//! - 2: The host cancelled the request that is being processed.
+
+use capsules_core::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Ctap as usize;
+
+use core::cell::Cell;
+use kernel::errorcode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+use crate::usb::ctaphid::{CtapHidClient, CtapTransport};
+
+/// IDs for read-only allow buffers.
+mod ro_allow {
+    /// Response to send.
+    pub const RESPONSE: usize = 0;
+    /// The number of RO allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for read-write allow buffers.
+mod rw_allow {
+    /// Received request.
+    pub const REQUEST: usize = 0;
+    /// The number of RW allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for upcalls.
+mod upcalls {
+    /// Single upcall.
+    pub const EVENT: usize = 0;
+    /// The number of upcalls the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+// Upcall kinds.
+const EVENT_REQUEST: usize = 0;
+const EVENT_RESPONSE_SENT: usize = 1;
+const EVENT_CANCELLED: usize = 2;
+
+// CTAP2 status codes for the responses the driver sends itself.
+const CTAP2_ERR_REQUEST_TOO_LARGE: u8 = 0x39;
+const CTAP1_ERR_OTHER: u8 = 0x7F;
+
+#[derive(Default)]
+pub struct App {}
+
+pub struct CtapDriver<'a, T: CtapTransport<'a>> {
+    transport: &'a T,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcalls::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// The app that implements the authenticator.
+    owner: OptionalCell<ProcessId>,
+    /// Whether the response in flight was sent by the driver rather than by
+    /// the app.
+    internal_response: Cell<bool>,
+    response_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, T: CtapTransport<'a>> CtapDriver<'a, T> {
+    pub fn new(
+        transport: &'a T,
+        response_buffer: &'static mut [u8],
+        grant: Grant<
+            App,
+            UpcallCount<{ upcalls::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> CtapDriver<'a, T> {
+        CtapDriver {
+            transport,
+            apps: grant,
+            owner: OptionalCell::empty(),
+            internal_response: Cell::new(false),
+            response_buffer: TakeCell::new(response_buffer),
+        }
+    }
+
+    /// Answer the current request with a bare CTAP2 status code.
+    fn respond_with_status(&self, status: u8) {
+        self.response_buffer.take().map(|buffer| {
+            buffer[0] = status;
+            self.internal_response.set(true);
+            if let Err((_, buffer)) = self.transport.send_response(buffer, 1) {
+                self.internal_response.set(false);
+                self.response_buffer.replace(buffer);
+            }
+        });
+    }
+
+    /// Whether `processid` is the authenticator app, or can become it as no
+    /// live app is.
+    fn can_claim(&self, processid: ProcessId) -> bool {
+        self.owner.map_or(true, |owner| {
+            owner == processid || self.apps.enter(owner, |_, _| {}).is_err()
+        })
+    }
+
+    fn send_response(&self, processid: ProcessId, len: usize) -> Result<(), ErrorCode> {
+        if !self.owner.contains(&processid) {
+            return Err(ErrorCode::RESERVE);
+        }
+        let buffer = self.response_buffer.take().ok_or(ErrorCode::BUSY)?;
+        let copied = self
+            .apps
+            .enter(processid, |_, kernel_data| {
+                kernel_data
+                    .get_readonly_processbuffer(ro_allow::RESPONSE)
+                    .and_then(|response| {
+                        response.enter(|response| {
+                            if len > response.len() || len > buffer.len() {
+                                Err(ErrorCode::SIZE)
+                            } else {
+                                response[..len].copy_to_slice(&mut buffer[..len]);
+                                Ok(())
+                            }
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::RESERVE))
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        if let Err(e) = copied {
+            self.response_buffer.replace(buffer);
+            return Err(e);
+        }
+
+        self.transport
+            .send_response(buffer, len)
+            .map_err(|(e, buffer)| {
+                self.response_buffer.replace(buffer);
+                e
+            })
+    }
+}
+
+impl<'a, T: CtapTransport<'a>> CtapHidClient for CtapDriver<'a, T> {
+    fn request_received(&self, request: &[u8]) {
+        let delivered = self.owner.map_or(Err(ErrorCode::RESERVE), |owner| {
+            self.apps
+                .enter(owner, |_, kernel_data| {
+                    kernel_data
+                        .get_readwrite_processbuffer(rw_allow::REQUEST)
+                        .and_then(|buffer| {
+                            buffer.mut_enter(|appslice| {
+                                if request.len() > appslice.len() {
+                                    Err(ErrorCode::SIZE)
+                                } else {
+                                    appslice[..request.len()].copy_from_slice(request);
+                                    Ok(())
+                                }
+                            })
+                        })
+                        .unwrap_or(Err(ErrorCode::RESERVE))
+                        .map(|()| {
+                            kernel_data
+                                .schedule_upcall(upcalls::EVENT, (EVENT_REQUEST, request.len(), 0))
+                                .ok();
+                        })
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        });
+
+        match delivered {
+            Ok(()) => {}
+            Err(ErrorCode::SIZE) => self.respond_with_status(CTAP2_ERR_REQUEST_TOO_LARGE),
+            Err(_) => self.respond_with_status(CTAP1_ERR_OTHER),
+        }
+    }
+
+    fn request_cancelled(&self) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(owner, |_, kernel_data| {
+                kernel_data
+                    .schedule_upcall(upcalls::EVENT, (EVENT_CANCELLED, 0, 0))
+                    .ok();
+            });
+        });
+    }
+
+    fn response_sent(&self, result: Result<(), ErrorCode>, response: &'static mut [u8]) {
+        self.response_buffer.replace(response);
+        if self.internal_response.replace(false) {
+            return;
+        }
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(owner, |_, kernel_data| {
+                kernel_data
+                    .schedule_upcall(
+                        upcalls::EVENT,
+                        (EVENT_RESPONSE_SENT, errorcode::into_statuscode(result), 0),
+                    )
+                    .ok();
+            });
+        });
+    }
+}
+
+impl<'a, T: CtapTransport<'a>> SyscallDriver for CtapDriver<'a, T> {
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Claim the authenticator. Fails with `RESERVE` if another app
+    ///   has claimed it.
+    /// - `2`: Send the first `data1` bytes of the response buffer as the
+    ///   response to the current request.
+    /// - `3`: Report whether the app waits for the user to confirm their
+    ///   presence (`data1` != 0), which the transport reports to the host
+    ///   in its keepalive messages.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => {
+                if self.can_claim(processid) {
+                    self.owner.set(processid);
+                    CommandReturn::success()
+                } else {
+                    CommandReturn::failure(ErrorCode::RESERVE)
+                }
+            }
+
+            2 => self.send_response(processid, data1).into(),
+
+            3 => {
+                if self.owner.contains(&processid) {
+                    self.transport.set_user_presence_needed(data1 != 0);
+                    CommandReturn::success()
+                } else {
+                    CommandReturn::failure(ErrorCode::RESERVE)
+                }
+            }
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -0,0 +1,2307 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! FAT12, FAT16 and FAT32 file system.
+//!
+//! Provides `hil::filesystem::FileSystem` over a block storage device, such as
+//! an SD card, so files written by Tock can be read on a PC and the other way
+//! around. The device is either formatted without a partition table, or the
+//! first FAT partition of its MBR partition table is used. Only devices with
+//! 512 byte blocks are supported.
+//!
+//! Namespace 0 is the root directory. Other namespaces are directories in the
+//! root directory named by the namespace as eight hexadecimal digits, for
+//! example `0000002A` for namespace 42. They are created with their first
+//! file.
+//!
+//! File names are restricted to 8.3 short names, like `DATA0001.CSV`, and
+//! case insensitive. Long file names written by other systems are not shown,
+//! their files are listed with their short names. Modification times are not
+//! maintained.
+//!
+//! The file system caches a single sector. All changes are written to the
+//! device before an operation completes. The directory entry of a file is
+//! updated after its data is written, so losing power while writing can lose
+//! the data of that write but leaves the file system consistent, apart from
+//! clusters that are allocated but not used by any file.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//!
+//! let fat = static_init!(
+//!     capsules_extra::fat::Fat<'static, SDCardType>,
+//!     capsules_extra::fat::Fat::new(sdcard, static_init!([u8; 512], [0; 512]))
+//! );
+//! kernel::hil::block_storage::BlockStorage::set_client(sdcard, fat);
+//! kernel::deferred_call::DeferredCallClient::register(fat);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+use kernel::hil::filesystem::{FileInfo, FileSystem, FileSystemClient, OpenMode};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Size of the buffer `Fat` needs.
+pub const SECTOR_SIZE: usize = 512;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+
+/// First byte of the name of a deleted directory entry.
+const DELETED: u8 = 0xE5;
+
+const DOT: [u8; 11] = *b".          ";
+const DOT_DOT: [u8; 11] = *b"..         ";
+
+/// Partition types of FAT partitions in an MBR partition table.
+const FAT_PARTITION_TYPES: [u8; 6] = [0x01, 0x04, 0x06, 0x0B, 0x0C, 0x0E];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Layout of a mounted volume.
+#[derive(Clone, Copy, Debug)]
+struct Volume {
+    fat_type: FatType,
+    /// First sector of the first FAT.
+    fat_start: u64,
+    /// Sectors of each FAT.
+    fat_sectors: u32,
+    num_fats: u8,
+    /// First sector of the FAT12/16 root directory.
+    root_start: u64,
+    /// Sectors of the FAT12/16 root directory, 0 for FAT32.
+    root_sectors: u32,
+    /// First cluster of the FAT32 root directory, 0 for FAT12/16.
+    root_cluster: u32,
+    /// Sector of cluster 2.
+    data_start: u64,
+    sectors_per_cluster: u32,
+    cluster_count: u32,
+    /// FAT32 FSInfo sector.
+    fs_info: Option<u64>,
+}
+
+impl Volume {
+    /// Parse the BPB of a boot sector at `start`.
+    fn parse(buf: &[u8], start: u64) -> Option<Volume> {
+        let bytes_per_sector = read_u16(buf, 11);
+        let sectors_per_cluster = buf[13] as u32;
+        let reserved = read_u16(buf, 14) as u32;
+        let num_fats = buf[16];
+        let root_entries = read_u16(buf, 17) as u32;
+        let total = match read_u16(buf, 19) {
+            0 => read_u32(buf, 32),
+            total => total as u32,
+        };
+        let fat_sectors = match read_u16(buf, 22) {
+            0 => read_u32(buf, 36),
+            sectors => sectors as u32,
+        };
+
+        if !(buf[0] == 0xEB || buf[0] == 0xE9)
+            || bytes_per_sector as usize != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || num_fats == 0
+            || fat_sectors == 0
+        {
+            return None;
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u32).div_ceil(SECTOR_SIZE as u32);
+        let meta = reserved as u64 + num_fats as u64 * fat_sectors as u64 + root_sectors as u64;
+        if total as u64 <= meta {
+            return None;
+        }
+        let cluster_count = (total - meta as u32) / sectors_per_cluster;
+        let fat_type = if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        let fat_start = start + reserved as u64;
+        let root_start = fat_start + num_fats as u64 * fat_sectors as u64;
+        let (root_cluster, fs_info) = if fat_type == FatType::Fat32 {
+            let fs_info = read_u16(buf, 48) as u32;
+            (
+                read_u32(buf, 44),
+                (fs_info != 0 && fs_info < reserved).then_some(start + fs_info as u64),
+            )
+        } else {
+            (0, None)
+        };
+
+        let volume = Volume {
+            fat_type,
+            fat_start,
+            fat_sectors,
+            num_fats,
+            root_start,
+            root_sectors,
+            root_cluster,
+            data_start: root_start + root_sectors as u64,
+            sectors_per_cluster,
+            cluster_count,
+            fs_info,
+        };
+        let valid_root = if fat_type == FatType::Fat32 {
+            root_entries == 0 && volume.is_cluster(root_cluster)
+        } else {
+            root_entries != 0
+        };
+        valid_root.then_some(volume)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster <= self.cluster_count + 1
+    }
+
+    /// First sector of `cluster`.
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.sectors_per_cluster as u64
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    /// Cluster of the root directory, 0 for the FAT12/16 root directory.
+    fn root_dir(&self) -> u32 {
+        self.root_cluster
+    }
+
+    /// Sector and offset within it of the FAT entry of `cluster`.
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        };
+        (
+            self.fat_start + offset / SECTOR_SIZE as u64,
+            (offset % SECTOR_SIZE as u64) as usize,
+        )
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn in_first_fat(&self, sector: u64) -> bool {
+        sector >= self.fat_start && sector < self.fat_start + self.fat_sectors as u64
+    }
+}
+
+/// Location of a directory entry.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct EntryLocation {
+    sector: u64,
+    index: usize,
+}
+
+impl EntryLocation {
+    fn range(&self) -> core::ops::Range<usize> {
+        self.index * ENTRY_SIZE..(self.index + 1) * ENTRY_SIZE
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DirEntry {
+    name: [u8; 11],
+    attr: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl DirEntry {
+    fn parse(entry: &[u8]) -> DirEntry {
+        let mut name = [0; 11];
+        name.copy_from_slice(&entry[..11]);
+        DirEntry {
+            name,
+            attr: entry[11],
+            cluster: (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32,
+            size: read_u32(entry, 28),
+        }
+    }
+
+    fn write(&self, entry: &mut [u8]) {
+        entry.fill(0);
+        entry[..11].copy_from_slice(&self.name);
+        entry[11] = self.attr;
+        write_u16(entry, 20, (self.cluster >> 16) as u16);
+        write_u16(entry, 26, self.cluster as u16);
+        write_u32(entry, 28, self.size);
+    }
+}
+
+/// An open file of a `Fat` file system.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FatFile {
+    entry: EntryLocation,
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    /// A cluster of the file and its index in the cluster chain, to follow the
+    /// chain from. 0 if not known.
+    cluster: u32,
+    cluster_index: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Op {
+    Idle,
+    Open(OpenMode),
+    Read,
+    Write,
+    Delete,
+    List,
+}
+
+/// Steps of an operation. Every step accesses at most one sector, or keeps
+/// its progress in a sub-state, so it can be repeated when the sector has to
+/// be loaded first.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Phase {
+    /// Read the first sector of the device.
+    Mount,
+    /// Read the boot sector of the partition starting at the sector.
+    MountPartition(u64),
+    /// Mark the free cluster count of the FAT32 FSInfo sector as unknown, as
+    /// it is not maintained.
+    MountFsInfo,
+    /// Search the root directory for the namespace directory.
+    FindNamespace,
+    /// Add a cluster after the cluster of the root directory for the entry of
+    /// the namespace directory.
+    ExtendRoot(u32),
+    /// Allocate the cluster of the namespace directory.
+    AllocNamespace,
+    /// Write the `.` and `..` entries of the namespace directory at the cluster.
+    InitNamespace(u32),
+    /// Add the entry of the namespace directory at the cluster to the root
+    /// directory.
+    AddNamespace(u32),
+    /// Search the directory for the file.
+    FindFile,
+    /// Add a cluster after the cluster of the directory for a new entry.
+    ExtendDir(u32),
+    /// Write the entry of a new file.
+    AddFile,
+    /// Write the first cluster and size of the file to its entry.
+    UpdateEntry,
+    /// Mark the entry of the file as deleted.
+    RemoveEntry,
+    /// Free the clusters of a truncated or deleted file.
+    FreeClusters,
+    /// Follow the cluster chain of the file to its position.
+    Seek,
+    /// Copy data between the sector at the file position and the buffer.
+    Transfer,
+    /// Search the directory for the file to list.
+    ListFiles,
+    Done,
+}
+
+/// Why a step stopped.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stop {
+    /// Waiting for the block storage device.
+    Busy,
+    Error(ErrorCode),
+}
+
+impl From<ErrorCode> for Stop {
+    fn from(error: ErrorCode) -> Stop {
+        Stop::Error(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ScanTarget {
+    /// The entry with the name.
+    Name([u8; 11]),
+    /// The file with the index.
+    Index(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ScanResult {
+    Found(EntryLocation, DirEntry),
+    /// The end of the directory is reached. `free` is the first unused entry,
+    /// `last` the last cluster of the directory.
+    End {
+        free: Option<EntryLocation>,
+        last: u32,
+    },
+}
+
+/// Progress of searching a directory.
+#[derive(Clone, Copy, Default, Debug)]
+struct Scan {
+    /// Cluster being searched, 0 for the FAT12/16 root directory.
+    cluster: u32,
+    /// Sector within the cluster or root directory.
+    sector: u32,
+    /// Files counted for `ScanTarget::Index`.
+    files: usize,
+    /// First unused entry found.
+    free: Option<EntryLocation>,
+    /// The next cluster of the directory has to be looked up.
+    next_cluster: bool,
+}
+
+/// Progress of allocating a cluster.
+#[derive(Clone, Copy, Debug)]
+enum Alloc {
+    /// Check whether `cluster` is free, with `remaining` clusters left to
+    /// check.
+    Search { cluster: u32, remaining: u32 },
+    /// Mark the cluster as the end of a chain.
+    Mark(u32),
+    /// Link the cluster to the chain it extends.
+    Link(u32),
+    /// Clear the sector of the cluster.
+    Zero { cluster: u32, sector: u32 },
+}
+
+/// Progress of freeing a cluster chain.
+#[derive(Clone, Copy, Debug)]
+enum Free {
+    /// Look up the cluster after the cluster.
+    Next(u32),
+    /// Free the cluster, the chain continues with `next`.
+    Clear { cluster: u32, next: u32 },
+}
+
+pub struct Fat<'a, B: BlockStorage<'a>> {
+    block: &'a B,
+    client: OptionalCell<&'a dyn FileSystemClient<FatFile>>,
+    deferred_call: DeferredCall,
+
+    /// Cached sector.
+    sector: TakeCell<'static, [u8]>,
+    cached: OptionalCell<u64>,
+    loading: Cell<u64>,
+    dirty: Cell<bool>,
+    /// Number of FAT copies a dirty FAT sector has been written to.
+    flush_copy: Cell<u8>,
+
+    volume: OptionalCell<Volume>,
+
+    op: Cell<Op>,
+    phase: Cell<Phase>,
+    result: Cell<Result<(), ErrorCode>>,
+    namespace: Cell<u32>,
+    name: Cell<[u8; 11]>,
+    index: Cell<usize>,
+    /// Cluster of the directory of the file, 0 for the FAT12/16 root.
+    dir: Cell<u32>,
+    /// Unused directory entry for a new entry.
+    slot: OptionalCell<EntryLocation>,
+    file: Cell<FatFile>,
+    info: OptionalCell<FileInfo>,
+    data: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    done: Cell<usize>,
+
+    scan: Cell<Scan>,
+    alloc: OptionalCell<Alloc>,
+    free: OptionalCell<Free>,
+    /// Cluster to start searching for free clusters at.
+    next_free: Cell<u32>,
+    /// FAT12 entry split between two sectors whose first byte has been read.
+    fat12_low: OptionalCell<(u32, u8)>,
+    /// FAT12 entry split between two sectors whose first byte has been written.
+    fat12_set: OptionalCell<u32>,
+}
+
+impl<'a, B: BlockStorage<'a>> Fat<'a, B> {
+    pub fn new(block: &'a B, buffer: &'static mut [u8; SECTOR_SIZE]) -> Fat<'a, B> {
+        Fat {
+            block,
+            client: OptionalCell::empty(),
+            deferred_call: DeferredCall::new(),
+            sector: TakeCell::new(buffer),
+            cached: OptionalCell::empty(),
+            loading: Cell::new(0),
+            dirty: Cell::new(false),
+            flush_copy: Cell::new(0),
+            volume: OptionalCell::empty(),
+            op: Cell::new(Op::Idle),
+            phase: Cell::new(Phase::Done),
+            result: Cell::new(Ok(())),
+            namespace: Cell::new(0),
+            name: Cell::new([0; 11]),
+            index: Cell::new(0),
+            dir: Cell::new(0),
+            slot: OptionalCell::empty(),
+            file: Cell::new(FatFile {
+                entry: EntryLocation {
+                    sector: 0,
+                    index: 0,
+                },
+                first_cluster: 0,
+                size: 0,
+                position: 0,
+                cluster: 0,
+                cluster_index: 0,
+            }),
+            info: OptionalCell::empty(),
+            data: TakeCell::empty(),
+            length: Cell::new(0),
+            done: Cell::new(0),
+            scan: Cell::new(Scan::default()),
+            alloc: OptionalCell::empty(),
+            free: OptionalCell::empty(),
+            next_free: Cell::new(2),
+            fat12_low: OptionalCell::empty(),
+            fat12_set: OptionalCell::empty(),
+        }
+    }
+
+    /// Forget the mounted volume, for example because the SD card was
+    /// replaced. The next operation mounts the device again.
+    pub fn unmount(&self) -> Result<(), ErrorCode> {
+        if self.op.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.volume.clear();
+        self.cached.clear();
+        self.dirty.set(false);
+        self.flush_copy.set(0);
+        self.next_free.set(2);
+        Ok(())
+    }
+
+    fn start(&self, op: Op) {
+        self.op.set(op);
+        self.result.set(Ok(()));
+        self.alloc.clear();
+        self.free.clear();
+        self.fat12_low.clear();
+        self.fat12_set.clear();
+        if self.volume.is_some() {
+            self.begin();
+        } else {
+            self.phase.set(Phase::Mount);
+        }
+        self.deferred_call.set();
+    }
+
+    /// Set the first phase of the operation on the mounted volume.
+    fn begin(&self) {
+        match self.op.get() {
+            Op::Read | Op::Write => self.phase.set(Phase::Seek),
+            Op::Idle => self.phase.set(Phase::Done),
+            Op::Open(_) | Op::Delete | Op::List => {
+                let root = self.volume.map_or(0, |volume| volume.root_dir());
+                if self.namespace.get() == 0 {
+                    self.dir.set(root);
+                    self.enter_dir();
+                } else {
+                    self.start_scan(root);
+                    self.phase.set(Phase::FindNamespace);
+                }
+            }
+        }
+    }
+
+    /// Continue the operation in the directory `self.dir`.
+    fn enter_dir(&self) {
+        self.start_scan(self.dir.get());
+        if self.op.get() == Op::List {
+            self.phase.set(Phase::ListFiles);
+        } else {
+            self.phase.set(Phase::FindFile);
+        }
+    }
+
+    /// Whether the operation creates missing files.
+    fn creates(&self) -> bool {
+        match self.op.get() {
+            Op::Open(mode) => mode != OpenMode::Existing,
+            _ => false,
+        }
+    }
+
+    /// Run the operation until it has to wait for the device or is finished.
+    fn run(&self) {
+        loop {
+            let result = if self.phase.get() == Phase::Done {
+                self.flush()
+            } else {
+                self.step()
+            };
+            match result {
+                Ok(()) if self.phase.get() == Phase::Done && !self.dirty.get() => {
+                    return self.complete(Ok(()));
+                }
+                Ok(()) => {}
+                Err(Stop::Busy) => return,
+                Err(Stop::Error(e)) => return self.complete(Err(e)),
+            }
+        }
+    }
+
+    fn complete(&self, result: Result<(), ErrorCode>) {
+        let result = result.and(self.result.get());
+        let file = self.file.get();
+        let length = self.done.get();
+        match self.op.replace(Op::Idle) {
+            Op::Idle => {}
+            Op::Open(_) => {
+                self.client
+                    .map(|client| client.open_done(result.map(|()| file)));
+            }
+            Op::Read => {
+                self.data.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.read_done(file, buffer, length, result));
+                });
+            }
+            Op::Write => {
+                self.data.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_done(file, buffer, length, result));
+                });
+            }
+            Op::Delete => {
+                self.client.map(|client| client.delete_done(result));
+            }
+            Op::List => {
+                let info = result.and_then(|()| self.info.take().ok_or(ErrorCode::FAIL));
+                self.client.map(|client| client.list_done(info));
+            }
+        }
+    }
+
+    fn volume(&self) -> Result<Volume, Stop> {
+        self.volume.get().ok_or(Stop::Error(ErrorCode::FAIL))
+    }
+
+    fn step(&self) -> Result<(), Stop> {
+        match self.phase.get() {
+            Phase::Mount => {
+                if self.block.block_size() != SECTOR_SIZE {
+                    return Err(ErrorCode::NOSUPPORT.into());
+                }
+                if self.block.block_count() == 0 {
+                    return Err(ErrorCode::RESERVE.into());
+                }
+                let partition = self.sector(0, |buf| {
+                    if let Some(volume) = Volume::parse(buf, 0).filter(|_| has_signature(buf)) {
+                        self.volume.set(volume);
+                        return Ok(None);
+                    }
+                    // Use the first FAT partition of the MBR.
+                    (0..4)
+                        .map(|i| &buf[446 + i * 16..462 + i * 16])
+                        .find(|entry| FAT_PARTITION_TYPES.contains(&entry[4]))
+                        .filter(|_| has_signature(buf))
+                        .map(|entry| Some(read_u32(entry, 8) as u64))
+                        .ok_or(ErrorCode::FAIL)
+                })??;
+                match partition {
+                    Some(start) => self.phase.set(Phase::MountPartition(start)),
+                    None => self.mounted(),
+                }
+            }
+            Phase::MountPartition(start) => {
+                let volume = self.sector(start, |buf| {
+                    Volume::parse(buf, start)
+                        .filter(|_| has_signature(buf))
+                        .ok_or(ErrorCode::FAIL)
+                })??;
+                self.volume.set(volume);
+                self.mounted();
+            }
+            Phase::MountFsInfo => {
+                let volume = self.volume()?;
+                if let Some(sector) = volume.fs_info {
+                    let stale = self.sector(sector, |buf| {
+                        read_u32(buf, 0) == 0x41615252 && read_u32(buf, 488) != u32::MAX
+                    })?;
+                    if stale {
+                        self.sector_mut(sector, |buf| {
+                            write_u32(buf, 488, u32::MAX);
+                            write_u32(buf, 492, u32::MAX);
+                        })?;
+                    }
+                }
+                self.begin();
+            }
+            Phase::FindNamespace => {
+                let name = namespace_name(self.namespace.get());
+                match self.scan(ScanTarget::Name(name))? {
+                    ScanResult::Found(_, entry) => {
+                        if entry.attr & ATTR_DIRECTORY == 0 {
+                            return Err(ErrorCode::FAIL.into());
+                        }
+                        self.dir.set(entry.cluster);
+                        self.enter_dir();
+                    }
+                    ScanResult::End { free, last } => {
+                        if !self.creates() {
+                            return Err(ErrorCode::NOSUPPORT.into());
+                        }
+                        match free {
+                            Some(slot) => {
+                                self.slot.set(slot);
+                                self.phase.set(Phase::AllocNamespace);
+                            }
+                            None if last == 0 => return Err(ErrorCode::NOMEM.into()),
+                            None => self.phase.set(Phase::ExtendRoot(last)),
+                        }
+                    }
+                }
+            }
+            Phase::ExtendRoot(last) => {
+                let cluster = self.alloc(last, true)?;
+                self.slot.set(EntryLocation {
+                    sector: self.volume()?.cluster_sector(cluster),
+                    index: 0,
+                });
+                self.phase.set(Phase::AllocNamespace);
+            }
+            Phase::AllocNamespace => {
+                let cluster = self.alloc(0, true)?;
+                self.phase.set(Phase::InitNamespace(cluster));
+            }
+            Phase::InitNamespace(cluster) => {
+                let sector = self.volume()?.cluster_sector(cluster);
+                self.sector_mut(sector, |buf| {
+                    let dot = DirEntry {
+                        name: DOT,
+                        attr: ATTR_DIRECTORY,
+                        cluster,
+                        size: 0,
+                    };
+                    dot.write(&mut buf[..ENTRY_SIZE]);
+                    // The parent is the root directory, which is cluster 0
+                    // in `..` entries.
+                    let dot_dot = DirEntry {
+                        name: DOT_DOT,
+                        cluster: 0,
+                        ..dot
+                    };
+                    dot_dot.write(&mut buf[ENTRY_SIZE..2 * ENTRY_SIZE]);
+                })?;
+                self.phase.set(Phase::AddNamespace(cluster));
+            }
+            Phase::AddNamespace(cluster) => {
+                let slot = self.slot.get().ok_or(ErrorCode::FAIL)?;
+                self.sector_mut(slot.sector, |buf| {
+                    DirEntry {
+                        name: namespace_name(self.namespace.get()),
+                        attr: ATTR_DIRECTORY,
+                        cluster,
+                        size: 0,
+                    }
+                    .write(&mut buf[slot.range()]);
+                })?;
+                self.dir.set(cluster);
+                self.enter_dir();
+            }
+            Phase::FindFile => match self.scan(ScanTarget::Name(self.name.get()))? {
+                ScanResult::Found(location, entry) => {
+                    if entry.attr & ATTR_DIRECTORY != 0 {
+                        return Err(ErrorCode::INVAL.into());
+                    }
+                    let mut file = FatFile {
+                        entry: location,
+                        first_cluster: entry.cluster,
+                        size: entry.size,
+                        position: 0,
+                        cluster: 0,
+                        cluster_index: 0,
+                    };
+                    match self.op.get() {
+                        Op::Open(OpenMode::Truncate) if entry.cluster != 0 || entry.size != 0 => {
+                            // Clear the entry before freeing the clusters, so
+                            // they are never used by two files.
+                            self.free.set(Free::Next(entry.cluster));
+                            file.first_cluster = 0;
+                            file.size = 0;
+                            self.phase.set(Phase::UpdateEntry);
+                        }
+                        Op::Delete => {
+                            self.free.set(Free::Next(entry.cluster));
+                            self.phase.set(Phase::RemoveEntry);
+                        }
+                        _ => self.phase.set(Phase::Done),
+                    }
+                    self.file.set(file);
+                }
+                ScanResult::End { free, last } => {
+                    if !self.creates() {
+                        return Err(ErrorCode::NOSUPPORT.into());
+                    }
+                    match free {
+                        Some(slot) => {
+                            self.slot.set(slot);
+                            self.phase.set(Phase::AddFile);
+                        }
+                        None if last == 0 => return Err(ErrorCode::NOMEM.into()),
+                        None => self.phase.set(Phase::ExtendDir(last)),
+                    }
+                }
+            },
+            Phase::ExtendDir(last) => {
+                let cluster = self.alloc(last, true)?;
+                self.slot.set(EntryLocation {
+                    sector: self.volume()?.cluster_sector(cluster),
+                    index: 0,
+                });
+                self.phase.set(Phase::AddFile);
+            }
+            Phase::AddFile => {
+                let slot = self.slot.get().ok_or(ErrorCode::FAIL)?;
+                self.sector_mut(slot.sector, |buf| {
+                    DirEntry {
+                        name: self.name.get(),
+                        attr: ATTR_ARCHIVE,
+                        cluster: 0,
+                        size: 0,
+                    }
+                    .write(&mut buf[slot.range()]);
+                })?;
+                self.file.set(FatFile {
+                    entry: slot,
+                    first_cluster: 0,
+                    size: 0,
+                    position: 0,
+                    cluster: 0,
+                    cluster_index: 0,
+                });
+                self.phase.set(Phase::Done);
+            }
+            Phase::UpdateEntry => {
+                let file = self.file.get();
+                self.sector_mut(file.entry.sector, |buf| {
+                    let entry = &mut buf[file.entry.range()];
+                    write_u16(entry, 20, (file.first_cluster >> 16) as u16);
+                    write_u16(entry, 26, file.first_cluster as u16);
+                    write_u32(entry, 28, file.size);
+                    entry[11] |= ATTR_ARCHIVE;
+                })?;
+                if self.free.is_some() {
+                    self.phase.set(Phase::FreeClusters);
+                } else {
+                    self.phase.set(Phase::Done);
+                }
+            }
+            Phase::RemoveEntry => {
+                let entry = self.file.get().entry;
+                self.sector_mut(entry.sector, |buf| {
+                    buf[entry.index * ENTRY_SIZE] = DELETED;
+                })?;
+                self.phase.set(Phase::FreeClusters);
+            }
+            Phase::FreeClusters => {
+                self.free_chain()?;
+                self.phase.set(Phase::Done);
+            }
+            Phase::Seek => self.seek_cluster()?,
+            Phase::Transfer => self.transfer()?,
+            Phase::ListFiles => match self.scan(ScanTarget::Index(self.index.get()))? {
+                ScanResult::Found(_, entry) => {
+                    let (name, length) = display_name(&entry.name);
+                    self.info.set(FileInfo::new(&name[..length], entry.size));
+                    self.phase.set(Phase::Done);
+                }
+                ScanResult::End { .. } => return Err(ErrorCode::NOSUPPORT.into()),
+            },
+            Phase::Done => {}
+        }
+        Ok(())
+    }
+
+    fn mounted(&self) {
+        if self.volume.map_or(false, |volume| volume.fs_info.is_some()) {
+            self.phase.set(Phase::MountFsInfo);
+        } else {
+            self.begin();
+        }
+    }
+
+    /// Follow the cluster chain of the file to the cluster at its position,
+    /// allocating clusters when writing past the end of the chain.
+    fn seek_cluster(&self) -> Result<(), Stop> {
+        let volume = self.volume()?;
+        let mut file = self.file.get();
+        let writing = self.op.get() == Op::Write;
+        if self.done.get() == self.length.get() || (!writing && file.position >= file.size) {
+            self.phase.set(if writing {
+                Phase::UpdateEntry
+            } else {
+                Phase::Done
+            });
+            return Ok(());
+        }
+
+        let target = file.position / volume.cluster_bytes();
+        if file.first_cluster == 0 {
+            if !writing {
+                return Err(ErrorCode::FAIL.into());
+            }
+            match self.grow(0)? {
+                Some(cluster) => file.first_cluster = cluster,
+                None => return Ok(()),
+            }
+            file.cluster = 0;
+            self.file.set(file);
+        }
+        if file.cluster == 0 || file.cluster_index > target {
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+            self.file.set(file);
+        }
+        while file.cluster_index < target {
+            // While a cluster is being allocated the chain is known to end
+            // here, and reading the entry again could evict the sector the
+            // allocation waits for.
+            let next = match self.alloc.get() {
+                Some(_) => 0,
+                None => self.fat_get(file.cluster)?,
+            };
+            file.cluster = if volume.is_cluster(next) {
+                next
+            } else if writing {
+                match self.grow(file.cluster)? {
+                    Some(cluster) => cluster,
+                    None => return Ok(()),
+                }
+            } else {
+                return Err(ErrorCode::FAIL.into());
+            };
+            file.cluster_index += 1;
+            self.file.set(file);
+        }
+        self.phase.set(Phase::Transfer);
+        Ok(())
+    }
+
+    /// Allocate a cluster for writing after `previous`. If the device is full,
+    /// the write ends and `None` is returned.
+    fn grow(&self, previous: u32) -> Result<Option<u32>, Stop> {
+        match self.alloc(previous, false) {
+            Ok(cluster) => Ok(Some(cluster)),
+            Err(Stop::Error(ErrorCode::NOMEM)) => {
+                self.result.set(Err(ErrorCode::NOMEM));
+                self.phase.set(Phase::UpdateEntry);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Copy data of the sector at the file position.
+    fn transfer(&self) -> Result<(), Stop> {
+        let volume = self.volume()?;
+        let mut file = self.file.get();
+        let done = self.done.get();
+        let remaining = self.length.get() - done;
+        let sector = volume.cluster_sector(file.cluster)
+            + ((file.position % volume.cluster_bytes()) as usize / SECTOR_SIZE) as u64;
+        let offset = file.position as usize % SECTOR_SIZE;
+
+        let length = if self.op.get() == Op::Write {
+            let length = cmp::min(SECTOR_SIZE - offset, remaining);
+            // Sectors without data of the file do not have to be read.
+            let unused = file.position - offset as u32 >= file.size;
+            self.data
+                .map_or(Err(Stop::Error(ErrorCode::FAIL)), |data| {
+                    let copy = |buf: &mut [u8]| {
+                        buf[offset..offset + length].copy_from_slice(&data[done..done + length]);
+                    };
+                    if offset == 0 && (length == SECTOR_SIZE || unused) {
+                        self.sector_new(sector, copy)
+                    } else {
+                        self.sector_mut(sector, copy)
+                    }
+                })?;
+            file.size = cmp::max(file.size, file.position + length as u32);
+            length
+        } else {
+            let length = cmp::min(
+                cmp::min(SECTOR_SIZE - offset, remaining),
+                (file.size - file.position) as usize,
+            );
+            self.data
+                .map_or(Err(Stop::Error(ErrorCode::FAIL)), |data| {
+                    self.sector(sector, |buf| {
+                        data[done..done + length].copy_from_slice(&buf[offset..offset + length]);
+                    })
+                })?;
+            length
+        };
+
+        file.position += length as u32;
+        self.file.set(file);
+        self.done.set(done + length);
+        self.phase.set(Phase::Seek);
+        Ok(())
+    }
+
+    fn start_scan(&self, dir: u32) {
+        self.scan.set(Scan {
+            cluster: dir,
+            ..Scan::default()
+        });
+    }
+
+    /// Search the directory of the last `start_scan` for `target`.
+    fn scan(&self, target: ScanTarget) -> Result<ScanResult, Stop> {
+        let volume = self.volume()?;
+        loop {
+            let mut scan = self.scan.get();
+            if scan.next_cluster {
+                let next = self.fat_get(scan.cluster)?;
+                if !volume.is_cluster(next) {
+                    return Ok(ScanResult::End {
+                        free: scan.free,
+                        last: scan.cluster,
+                    });
+                }
+                self.scan.set(Scan {
+                    cluster: next,
+                    sector: 0,
+                    next_cluster: false,
+                    ..scan
+                });
+                continue;
+            }
+
+            let sector = if scan.cluster == 0 {
+                if scan.sector >= volume.root_sectors {
+                    return Ok(ScanResult::End {
+                        free: scan.free,
+                        last: 0,
+                    });
+                }
+                volume.root_start + scan.sector as u64
+            } else {
+                volume.cluster_sector(scan.cluster) + scan.sector as u64
+            };
+
+            let result = self.sector(sector, |buf| {
+                for index in 0..ENTRIES_PER_SECTOR {
+                    let location = EntryLocation { sector, index };
+                    let entry = &buf[location.range()];
+                    match entry[0] {
+                        // No entries follow.
+                        0 => {
+                            return Some(ScanResult::End {
+                                free: scan.free.or(Some(location)),
+                                last: scan.cluster,
+                            });
+                        }
+                        DELETED => {
+                            scan.free = scan.free.or(Some(location));
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    // Skip volume labels and long file name entries.
+                    if entry[11] & ATTR_VOLUME_ID != 0 {
+                        continue;
+                    }
+                    match target {
+                        ScanTarget::Name(name) => {
+                            if entry[..11] == name {
+                                return Some(ScanResult::Found(location, DirEntry::parse(entry)));
+                            }
+                        }
+                        ScanTarget::Index(index) => {
+                            if entry[11] & ATTR_DIRECTORY == 0 {
+                                if scan.files == index {
+                                    return Some(ScanResult::Found(
+                                        location,
+                                        DirEntry::parse(entry),
+                                    ));
+                                }
+                                scan.files += 1;
+                            }
+                        }
+                    }
+                }
+                None
+            })?;
+            if let Some(result) = result {
+                return Ok(result);
+            }
+
+            scan.sector += 1;
+            if scan.cluster != 0 && scan.sector == volume.sectors_per_cluster {
+                scan.next_cluster = true;
+            }
+            self.scan.set(scan);
+        }
+    }
+
+    /// Allocate a free cluster and append it to the chain ending with
+    /// `previous`, unless that is 0. `zero` clears the cluster.
+    fn alloc(&self, previous: u32, zero: bool) -> Result<u32, Stop> {
+        let volume = self.volume()?;
+        loop {
+            let state = match self.alloc.get() {
+                None => {
+                    let start = self.next_free.get();
+                    Alloc::Search {
+                        cluster: if volume.is_cluster(start) { start } else { 2 },
+                        remaining: volume.cluster_count,
+                    }
+                }
+                Some(Alloc::Search { cluster, remaining }) => {
+                    if remaining == 0 {
+                        self.alloc.clear();
+                        return Err(ErrorCode::NOMEM.into());
+                    }
+                    if self.fat_get(cluster)? == 0 {
+                        Alloc::Mark(cluster)
+                    } else {
+                        Alloc::Search {
+                            cluster: if volume.is_cluster(cluster + 1) {
+                                cluster + 1
+                            } else {
+                                2
+                            },
+                            remaining: remaining - 1,
+                        }
+                    }
+                }
+                Some(Alloc::Mark(cluster)) => {
+                    self.fat_set(cluster, volume.end_of_chain())?;
+                    Alloc::Link(cluster)
+                }
+                Some(Alloc::Link(cluster)) => {
+                    if previous != 0 {
+                        self.fat_set(previous, cluster)?;
+                    }
+                    Alloc::Zero {
+                        cluster,
+                        sector: if zero { 0 } else { volume.sectors_per_cluster },
+                    }
+                }
+                Some(Alloc::Zero { cluster, sector }) => {
+                    if sector == volume.sectors_per_cluster {
+                        self.alloc.clear();
+                        self.next_free.set(cluster + 1);
+                        return Ok(cluster);
+                    }
+                    self.sector_new(volume.cluster_sector(cluster) + sector as u64, |buf| {
+                        buf.fill(0)
+                    })?;
+                    Alloc::Zero {
+                        cluster,
+                        sector: sector + 1,
+                    }
+                }
+            };
+            self.alloc.set(state);
+        }
+    }
+
+    /// Free the cluster chain set up in `self.free`.
+    fn free_chain(&self) -> Result<(), Stop> {
+        let volume = self.volume()?;
+        loop {
+            match self.free.get() {
+                None => return Ok(()),
+                Some(Free::Next(cluster)) => {
+                    if !volume.is_cluster(cluster) {
+                        self.free.clear();
+                        return Ok(());
+                    }
+                    let next = self.fat_get(cluster)?;
+                    self.free.set(Free::Clear { cluster, next });
+                }
+                Some(Free::Clear { cluster, next }) => {
+                    self.fat_set(cluster, 0)?;
+                    if cluster < self.next_free.get() {
+                        self.next_free.set(cluster);
+                    }
+                    self.free.set(Free::Next(next));
+                }
+            }
+        }
+    }
+
+    /// Read the FAT entry of `cluster`.
+    fn fat_get(&self, cluster: u32) -> Result<u32, Stop> {
+        let volume = self.volume()?;
+        let (sector, offset) = volume.fat_position(cluster);
+        match volume.fat_type {
+            FatType::Fat12 => {
+                let word = if offset == SECTOR_SIZE - 1 {
+                    // The entry continues in the next sector.
+                    let low = match self.fat12_low.get() {
+                        Some((split, low)) if split == cluster => low,
+                        _ => {
+                            let low = self.sector(sector, |buf| buf[offset])?;
+                            self.fat12_low.set((cluster, low));
+                            low
+                        }
+                    };
+                    let high = self.sector(sector + 1, |buf| buf[0])?;
+                    self.fat12_low.clear();
+                    u16::from_le_bytes([low, high])
+                } else {
+                    self.sector(sector, |buf| read_u16(buf, offset))?
+                };
+                Ok(if cluster & 1 == 1 {
+                    word >> 4
+                } else {
+                    word & 0xFFF
+                } as u32)
+            }
+            FatType::Fat16 => self.sector(sector, |buf| read_u16(buf, offset) as u32),
+            FatType::Fat32 => self.sector(sector, |buf| read_u32(buf, offset) & 0x0FFF_FFFF),
+        }
+    }
+
+    /// Write the FAT entry of `cluster`.
+    fn fat_set(&self, cluster: u32, value: u32) -> Result<(), Stop> {
+        let volume = self.volume()?;
+        let (sector, offset) = volume.fat_position(cluster);
+        match volume.fat_type {
+            FatType::Fat12 => {
+                let (bits, mask): (u16, u16) = if cluster & 1 == 1 {
+                    ((value as u16) << 4, 0xFFF0)
+                } else {
+                    (value as u16 & 0xFFF, 0x0FFF)
+                };
+                if offset == SECTOR_SIZE - 1 {
+                    // The entry continues in the next sector.
+                    if !self.fat12_set.contains(&cluster) {
+                        self.sector_mut(sector, |buf| {
+                            buf[offset] = (buf[offset] & !(mask as u8)) | bits as u8;
+                        })?;
+                        self.fat12_set.set(cluster);
+                    }
+                    self.sector_mut(sector + 1, |buf| {
+                        buf[0] = (buf[0] & !((mask >> 8) as u8)) | (bits >> 8) as u8;
+                    })?;
+                    self.fat12_set.clear();
+                    Ok(())
+                } else {
+                    self.sector_mut(sector, |buf| {
+                        let word = read_u16(buf, offset);
+                        write_u16(buf, offset, (word & !mask) | bits);
+                    })
+                }
+            }
+            FatType::Fat16 => self.sector_mut(sector, |buf| write_u16(buf, offset, value as u16)),
+            FatType::Fat32 => self.sector_mut(sector, |buf| {
+                let reserved = read_u32(buf, offset) & 0xF000_0000;
+                write_u32(buf, offset, reserved | (value & 0x0FFF_FFFF));
+            }),
+        }
+    }
+
+    /// Run `f` on `sector`, loading it first if it is not cached.
+    fn sector<R>(&self, sector: u64, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, Stop> {
+        if !self.cached.contains(&sector) {
+            self.flush()?;
+            return Err(self.load(sector));
+        }
+        self.sector
+            .map(|buf| f(buf))
+            .ok_or(Stop::Error(ErrorCode::FAIL))
+    }
+
+    /// Modify `sector` with `f`, loading it first if it is not cached.
+    fn sector_mut<R>(&self, sector: u64, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, Stop> {
+        let result = self.sector(sector, f)?;
+        self.dirty.set(true);
+        Ok(result)
+    }
+
+    /// Modify `sector` with `f`, starting with zeros instead of loading it if
+    /// it is not cached.
+    fn sector_new<R>(&self, sector: u64, f: impl FnOnce(&mut [u8]) -> R) -> Result<R, Stop> {
+        if !self.cached.contains(&sector) {
+            self.flush()?;
+            self.sector.map(|buf| buf.fill(0));
+            self.cached.set(sector);
+        }
+        self.sector_mut(sector, f)
+    }
+
+    fn load(&self, sector: u64) -> Stop {
+        self.cached.clear();
+        match self.sector.take() {
+            None => Stop::Error(ErrorCode::FAIL),
+            Some(buffer) => match self.block.read_blocks(buffer, sector, 1) {
+                Ok(()) => {
+                    self.loading.set(sector);
+                    Stop::Busy
+                }
+                Err((e, buffer)) => {
+                    self.sector.replace(buffer);
+                    Stop::Error(e)
+                }
+            },
+        }
+    }
+
+    /// Write the cached sector to the device if it was modified. FAT sectors
+    /// are written to every copy of the FAT.
+    fn flush(&self) -> Result<(), Stop> {
+        if !self.dirty.get() {
+            return Ok(());
+        }
+        let sector = self.cached.get().ok_or(Stop::Error(ErrorCode::FAIL))?;
+        let volume = self.volume()?;
+        let copies = if volume.in_first_fat(sector) {
+            volume.num_fats
+        } else {
+            1
+        };
+        let copy = self.flush_copy.get();
+        if copy >= copies {
+            self.dirty.set(false);
+            self.flush_copy.set(0);
+            return Ok(());
+        }
+
+        let target = sector + copy as u64 * volume.fat_sectors as u64;
+        let buffer = self.sector.take().ok_or(Stop::Error(ErrorCode::FAIL))?;
+        match self.block.write_blocks(buffer, target, 1) {
+            Ok(()) => Err(Stop::Busy),
+            Err((e, buffer)) => {
+                self.sector.replace(buffer);
+                Err(Stop::Error(e))
+            }
+        }
+    }
+}
+
+impl<'a, B: BlockStorage<'a>> FileSystem<'a> for Fat<'a, B> {
+    type File = FatFile;
+
+    fn set_client(&self, client: &'a dyn FileSystemClient<FatFile>) {
+        self.client.set(client);
+    }
+
+    fn open(&self, namespace: u32, name: &[u8], mode: OpenMode) -> Result<(), ErrorCode> {
+        if self.op.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.name.set(short_name(name).ok_or(ErrorCode::INVAL)?);
+        self.namespace.set(namespace);
+        self.start(Op::Open(mode));
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        file: FatFile,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.op.get() != Op::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        self.file.set(file);
+        self.data.replace(buffer);
+        self.length.set(length);
+        self.done.set(0);
+        self.start(Op::Read);
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        file: FatFile,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.op.get() != Op::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        // Files are limited to 4 GiB.
+        if file.position as u64 + length as u64 > u32::MAX as u64 {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        self.file.set(file);
+        self.data.replace(buffer);
+        self.length.set(length);
+        self.done.set(0);
+        self.start(Op::Write);
+        Ok(())
+    }
+
+    fn delete(&self, namespace: u32, name: &[u8]) -> Result<(), ErrorCode> {
+        if self.op.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.name.set(short_name(name).ok_or(ErrorCode::INVAL)?);
+        self.namespace.set(namespace);
+        self.start(Op::Delete);
+        Ok(())
+    }
+
+    fn list(&self, namespace: u32, index: usize) -> Result<(), ErrorCode> {
+        if self.op.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        self.namespace.set(namespace);
+        self.index.set(index);
+        self.start(Op::List);
+        Ok(())
+    }
+
+    fn seek(&self, file: FatFile, position: u32) -> Result<FatFile, ErrorCode> {
+        if position > file.size {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok(FatFile { position, ..file })
+    }
+
+    fn position(&self, file: &FatFile) -> u32 {
+        file.position
+    }
+
+    fn size(&self, file: &FatFile) -> u32 {
+        file.size
+    }
+}
+
+impl<'a, B: BlockStorage<'a>> BlockStorageClient for Fat<'a, B> {
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.sector.replace(buffer);
+        match result {
+            Ok(()) => {
+                self.cached.set(self.loading.get());
+                self.run();
+            }
+            Err(e) => self.complete(Err(e)),
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.sector.replace(buffer);
+        match result {
+            Ok(()) => {
+                self.flush_copy.set(self.flush_copy.get() + 1);
+                self.run();
+            }
+            Err(e) => {
+                // The sector stays dirty and is written again later.
+                self.flush_copy.set(0);
+                self.complete(Err(e));
+            }
+        }
+    }
+}
+
+impl<'a, B: BlockStorage<'a>> DeferredCallClient for Fat<'a, B> {
+    fn handle_deferred_call(&self) {
+        self.run();
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn has_signature(buf: &[u8]) -> bool {
+    buf[510] == 0x55 && buf[511] == 0xAA
+}
+
+/// Convert `name` to the form stored in directory entries, or `None` if it is
+/// not a valid 8.3 name.
+fn short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, extension) = match name.iter().rposition(|&c| c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &[][..]),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    for (part, field) in [(base, 0..8), (extension, 8..11)] {
+        for (dst, &c) in short[field].iter_mut().zip(part) {
+            let c = c.to_ascii_uppercase();
+            if !(c.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&c)) {
+                return None;
+            }
+            *dst = c;
+        }
+    }
+    Some(short)
+}
+
+/// Convert a name from a directory entry to `NAME.EXT` form.
+fn display_name(short: &[u8; 11]) -> ([u8; 12], usize) {
+    let mut name = [0; 12];
+    let mut length = 0;
+    for &c in short[..8].iter().filter(|&&c| c != b' ') {
+        name[length] = c;
+        length += 1;
+    }
+    if short[8] != b' ' {
+        name[length] = b'.';
+        length += 1;
+        for &c in short[8..].iter().filter(|&&c| c != b' ') {
+            name[length] = c;
+            length += 1;
+        }
+    }
+    (name, length)
+}
+
+/// Name of the directory of `namespace`.
+fn namespace_name(namespace: u32) -> [u8; 11] {
+    let mut name = [b' '; 11];
+    for (i, c) in name[..8].iter_mut().enumerate() {
+        let digit = (namespace >> (28 - 4 * i)) & 0xF;
+        *c = b"0123456789ABCDEF"[digit as usize];
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{display_name, namespace_name, short_name, Fat, FatFile, SECTOR_SIZE};
+    use crate::ram_disk::RamDisk;
+    use core::cell::{Cell, RefCell};
+    use kernel::deferred_call::DeferredCallClient;
+    use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+    use kernel::hil::filesystem::{FileInfo, FileSystem, FileSystemClient, OpenMode};
+    use kernel::utilities::cells::{OptionalCell, TakeCell};
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+    use std::format;
+    use std::string::{String, ToString};
+    use std::vec;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(short_name(b"log.txt"), Some(*b"LOG     TXT"));
+        assert_eq!(short_name(b"DATA0001.CSV"), Some(*b"DATA0001CSV"));
+        assert_eq!(short_name(b"README"), Some(*b"README     "));
+        assert_eq!(short_name(b"toolongname.txt"), None);
+        assert_eq!(short_name(b"a.text"), None);
+        assert_eq!(short_name(b".txt"), None);
+        assert_eq!(short_name(b"a b.txt"), None);
+        assert_eq!(short_name(b"dir/a.txt"), None);
+
+        let (name, length) = display_name(b"LOG     TXT");
+        assert_eq!(&name[..length], b"LOG.TXT");
+        let (name, length) = display_name(b"README     ");
+        assert_eq!(&name[..length], b"README");
+
+        assert_eq!(&namespace_name(0x2A), b"0000002A   ");
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum FatType {
+        Fat12,
+        Fat16,
+        Fat32,
+    }
+
+    /// Format `disk` with a FAT file system of `sectors` sectors starting at
+    /// sector `start`, with an MBR partition table if `start` is not 0.
+    fn format(disk: &mut [u8], start: usize, sectors: u32, fat_type: FatType) {
+        let (reserved, root_entries, entry_bits) = match fat_type {
+            FatType::Fat12 => (1, 64, 12),
+            FatType::Fat16 => (1, 512, 16),
+            FatType::Fat32 => (32, 0, 32),
+        };
+        let fat_sectors = (sectors as usize * entry_bits / 8).div_ceil(SECTOR_SIZE) as u32;
+
+        if start != 0 {
+            let mbr = &mut disk[..SECTOR_SIZE];
+            mbr[446 + 4] = 0x0C;
+            mbr[446 + 8..446 + 12].copy_from_slice(&(start as u32).to_le_bytes());
+            mbr[446 + 12..446 + 16].copy_from_slice(&sectors.to_le_bytes());
+            mbr[510] = 0x55;
+            mbr[511] = 0xAA;
+        }
+
+        let base = start * SECTOR_SIZE;
+        let boot = &mut disk[base..base + SECTOR_SIZE];
+        boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        boot[13] = 1;
+        boot[14..16].copy_from_slice(&(reserved as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+        boot[21] = 0xF8;
+        if fat_type == FatType::Fat32 {
+            boot[32..36].copy_from_slice(&sectors.to_le_bytes());
+            boot[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+            boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+            boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+        } else {
+            boot[19..21].copy_from_slice(&(sectors as u16).to_le_bytes());
+            boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+        }
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+
+        if fat_type == FatType::Fat32 {
+            let fs_info = &mut disk[base + SECTOR_SIZE..base + 2 * SECTOR_SIZE];
+            fs_info[..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+            fs_info[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+            fs_info[488..492].copy_from_slice(&1000u32.to_le_bytes());
+            fs_info[510] = 0x55;
+            fs_info[511] = 0xAA;
+        }
+
+        let volume = Volume::read(disk, start);
+        assert_eq!(volume.fat_type, fat_type);
+        for copy in 0..2 {
+            volume.set_fat(disk, copy, 0, 0x0FFF_FFF8);
+            volume.set_fat(disk, copy, 1, 0x0FFF_FFFF);
+            if fat_type == FatType::Fat32 {
+                // The root directory.
+                volume.set_fat(disk, copy, 2, 0x0FFF_FFFF);
+            }
+        }
+    }
+
+    /// Reader of FAT file systems, independent of the capsule.
+    struct Volume {
+        fat_type: FatType,
+        fat_start: usize,
+        fat_sectors: usize,
+        root_start: usize,
+        root_sectors: usize,
+        root_cluster: u32,
+        data_start: usize,
+        cluster_count: u32,
+    }
+
+    fn u16_at(buf: &[u8], offset: usize) -> u32 {
+        u16::from_le_bytes([buf[offset], buf[offset + 1]]) as u32
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    impl Volume {
+        fn read(disk: &[u8], start: usize) -> Volume {
+            let boot = &disk[start * SECTOR_SIZE..];
+            let reserved = u16_at(boot, 14) as usize;
+            let root_entries = u16_at(boot, 17) as usize;
+            let total = match u16_at(boot, 19) {
+                0 => u32_at(boot, 32),
+                total => total,
+            } as usize;
+            let fat_sectors = match u16_at(boot, 22) {
+                0 => u32_at(boot, 36),
+                sectors => sectors,
+            } as usize;
+            let root_sectors = root_entries * 32 / SECTOR_SIZE;
+            let fat_start = start + reserved;
+            let root_start = fat_start + 2 * fat_sectors;
+            let data_start = root_start + root_sectors;
+            let cluster_count = (total - (data_start - start)) as u32;
+            let fat_type = if cluster_count < 4085 {
+                FatType::Fat12
+            } else if cluster_count < 65525 {
+                FatType::Fat16
+            } else {
+                FatType::Fat32
+            };
+            Volume {
+                fat_type,
+                fat_start,
+                fat_sectors,
+                root_start,
+                root_sectors,
+                root_cluster: if fat_type == FatType::Fat32 {
+                    u32_at(boot, 44)
+                } else {
+                    0
+                },
+                data_start,
+                cluster_count,
+            }
+        }
+
+        fn fat_offset(&self, copy: usize, cluster: u32) -> usize {
+            let offset = match self.fat_type {
+                FatType::Fat12 => cluster as usize * 3 / 2,
+                FatType::Fat16 => cluster as usize * 2,
+                FatType::Fat32 => cluster as usize * 4,
+            };
+            (self.fat_start + copy * self.fat_sectors) * SECTOR_SIZE + offset
+        }
+
+        fn fat(&self, disk: &[u8], copy: usize, cluster: u32) -> u32 {
+            let offset = self.fat_offset(copy, cluster);
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let word = u16_at(disk, offset);
+                    if cluster & 1 == 1 {
+                        word >> 4
+                    } else {
+                        word & 0xFFF
+                    }
+                }
+                FatType::Fat16 => u16_at(disk, offset),
+                FatType::Fat32 => u32_at(disk, offset) & 0x0FFF_FFFF,
+            }
+        }
+
+        fn set_fat(&self, disk: &mut [u8], copy: usize, cluster: u32, value: u32) {
+            let offset = self.fat_offset(copy, cluster);
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let word = u16_at(disk, offset) as u16;
+                    let value = value as u16 & 0xFFF;
+                    let word = if cluster & 1 == 1 {
+                        (word & 0x000F) | value << 4
+                    } else {
+                        (word & 0xF000) | value
+                    };
+                    disk[offset..offset + 2].copy_from_slice(&word.to_le_bytes());
+                }
+                FatType::Fat16 => {
+                    disk[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes())
+                }
+                FatType::Fat32 => disk[offset..offset + 4].copy_from_slice(&value.to_le_bytes()),
+            }
+        }
+
+        fn chain(&self, disk: &[u8], first: u32) -> Vec<u32> {
+            let mut chain = Vec::new();
+            let mut cluster = first;
+            while cluster >= 2 && cluster <= self.cluster_count + 1 {
+                assert!(!chain.contains(&cluster), "cluster chain loops");
+                chain.push(cluster);
+                cluster = self.fat(disk, 0, cluster);
+            }
+            chain
+        }
+
+        fn free_clusters(&self, disk: &[u8]) -> usize {
+            (2..self.cluster_count + 2)
+                .filter(|&cluster| self.fat(disk, 0, cluster) == 0)
+                .count()
+        }
+
+        fn cluster<'a>(&self, disk: &'a [u8], cluster: u32) -> &'a [u8] {
+            let start = (self.data_start + cluster as usize - 2) * SECTOR_SIZE;
+            &disk[start..start + SECTOR_SIZE]
+        }
+
+        /// Entries of the directory starting at `cluster`, 0 for the FAT12/16
+        /// root directory.
+        fn entries(&self, disk: &[u8], cluster: u32) -> Vec<[u8; 32]> {
+            let data: Vec<u8> = if cluster == 0 {
+                disk[self.root_start * SECTOR_SIZE
+                    ..(self.root_start + self.root_sectors) * SECTOR_SIZE]
+                    .to_vec()
+            } else {
+                self.chain(disk, cluster)
+                    .iter()
+                    .flat_map(|&cluster| self.cluster(disk, cluster).to_vec())
+                    .collect()
+            };
+            data.chunks(32)
+                .map(|entry| entry.try_into().unwrap())
+                .take_while(|entry: &[u8; 32]| entry[0] != 0)
+                .filter(|entry| entry[0] != 0xE5 && entry[11] & 0x08 == 0)
+                .collect()
+        }
+
+        fn find(&self, disk: &[u8], dir: u32, name: &[u8; 11]) -> Option<[u8; 32]> {
+            self.entries(disk, dir)
+                .into_iter()
+                .find(|entry| &entry[..11] == name)
+        }
+
+        /// Contents of the file `name` in the directory of `namespace`.
+        fn read_file(&self, disk: &[u8], namespace: &[u8; 11], name: &[u8; 11]) -> Option<Vec<u8>> {
+            let dir = self.find(disk, self.root_cluster, namespace)?;
+            assert_eq!(dir[11] & 0x10, 0x10);
+            let file = self.find(disk, first_cluster(&dir), name)?;
+            let size = u32_at(&file, 28) as usize;
+            let mut data: Vec<u8> = self
+                .chain(disk, first_cluster(&file))
+                .iter()
+                .flat_map(|&cluster| self.cluster(disk, cluster).to_vec())
+                .collect();
+            assert!(data.len() >= size);
+            data.truncate(size);
+            Some(data)
+        }
+
+        fn check_fat_copies(&self, disk: &[u8]) {
+            let fat = |copy: usize| {
+                let start = (self.fat_start + copy * self.fat_sectors) * SECTOR_SIZE;
+                &disk[start..start + self.fat_sectors * SECTOR_SIZE]
+            };
+            assert!(fat(0) == fat(1), "FAT copies differ");
+        }
+    }
+
+    fn first_cluster(entry: &[u8]) -> u32 {
+        u16_at(entry, 20) << 16 | u16_at(entry, 26)
+    }
+
+    /// Block storage over a `Vec` whose operations complete when the test calls
+    /// `complete()`.
+    struct TestDisk {
+        disk: RefCell<Vec<u8>>,
+        client: OptionalCell<&'static dyn BlockStorageClient>,
+        /// Pending operation, whether it is a write and whether it failed.
+        pending: Cell<Option<(bool, bool)>>,
+        buffer: TakeCell<'static, [u8]>,
+        /// Number of writes until writes fail, if set.
+        writes_left: Cell<Option<usize>>,
+    }
+
+    impl TestDisk {
+        fn new(disk: Vec<u8>) -> &'static TestDisk {
+            Box::leak(Box::new(TestDisk {
+                disk: RefCell::new(disk),
+                client: OptionalCell::empty(),
+                pending: Cell::new(None),
+                buffer: TakeCell::empty(),
+                writes_left: Cell::new(None),
+            }))
+        }
+
+        fn start(
+            &self,
+            write: bool,
+            buffer: &'static mut [u8],
+            block: u64,
+            count: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            let range = block as usize * SECTOR_SIZE..(block as usize + count) * SECTOR_SIZE;
+            if self.pending.get().is_some() {
+                return Err((ErrorCode::BUSY, buffer));
+            }
+            if range.end > self.disk.borrow().len() {
+                return Err((ErrorCode::INVAL, buffer));
+            }
+            let failed = write && self.writes_left.get() == Some(0);
+            if write && !failed {
+                self.writes_left
+                    .set(self.writes_left.get().map(|left| left - 1));
+                self.disk.borrow_mut()[range].copy_from_slice(&buffer[..count * SECTOR_SIZE]);
+            } else if !write {
+                buffer[..count * SECTOR_SIZE].copy_from_slice(&self.disk.borrow()[range]);
+            }
+            self.pending.set(Some((write, failed)));
+            self.buffer.replace(buffer);
+            Ok(())
+        }
+
+        /// Complete the pending operation, returns whether there was one.
+        fn complete(&self) -> bool {
+            let (write, failed) = match self.pending.take() {
+                Some(pending) => pending,
+                None => return false,
+            };
+            let buffer = self.buffer.take().unwrap();
+            let result = if failed { Err(ErrorCode::FAIL) } else { Ok(()) };
+            self.client.map(|client| {
+                if write {
+                    client.write_complete(buffer, result)
+                } else {
+                    client.read_complete(buffer, result)
+                }
+            });
+            true
+        }
+    }
+
+    impl BlockStorage<'static> for TestDisk {
+        fn set_client(&self, client: &'static dyn BlockStorageClient) {
+            self.client.set(client);
+        }
+
+        fn block_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn block_count(&self) -> u64 {
+            (self.disk.borrow().len() / SECTOR_SIZE) as u64
+        }
+
+        fn read_blocks(
+            &self,
+            buffer: &'static mut [u8],
+            block: u64,
+            count: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.start(false, buffer, block, count)
+        }
+
+        fn write_blocks(
+            &self,
+            buffer: &'static mut [u8],
+            block: u64,
+            count: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.start(true, buffer, block, count)
+        }
+    }
+
+    #[derive(Debug)]
+    enum Event {
+        Open(Result<FatFile, ErrorCode>),
+        Read(FatFile, Vec<u8>, Result<(), ErrorCode>),
+        Write(FatFile, usize, Result<(), ErrorCode>),
+        Delete(Result<(), ErrorCode>),
+        List(Result<FileInfo, ErrorCode>),
+    }
+
+    struct TestClient {
+        events: RefCell<Vec<Event>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl FileSystemClient<FatFile> for TestClient {
+        fn open_done(&self, result: Result<FatFile, ErrorCode>) {
+            self.events.borrow_mut().push(Event::Open(result));
+        }
+
+        fn read_done(
+            &self,
+            file: FatFile,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: Result<(), ErrorCode>,
+        ) {
+            let data = buffer[..length].to_vec();
+            self.buffer.replace(buffer);
+            self.events
+                .borrow_mut()
+                .push(Event::Read(file, data, result));
+        }
+
+        fn write_done(
+            &self,
+            file: FatFile,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.buffer.replace(buffer);
+            self.events
+                .borrow_mut()
+                .push(Event::Write(file, length, result));
+        }
+
+        fn delete_done(&self, result: Result<(), ErrorCode>) {
+            self.events.borrow_mut().push(Event::Delete(result));
+        }
+
+        fn list_done(&self, result: Result<FileInfo, ErrorCode>) {
+            self.events.borrow_mut().push(Event::List(result));
+        }
+    }
+
+    /// Block storage whose pending operation the test completes.
+    trait TestBlockStorage: BlockStorage<'static> {
+        /// Complete the pending operation, returns whether there may have been
+        /// one.
+        fn complete(&self) -> bool;
+    }
+
+    impl TestBlockStorage for TestDisk {
+        fn complete(&self) -> bool {
+            TestDisk::complete(self)
+        }
+    }
+
+    impl TestBlockStorage for RamDisk<'static> {
+        fn complete(&self) -> bool {
+            self.handle_deferred_call();
+            true
+        }
+    }
+
+    /// A file system on a test disk with synchronous helpers for its operations.
+    ///
+    /// Deferred calls are made directly instead of through the global deferred
+    /// call table, so that the tests can run in parallel.
+    struct Harness<B: TestBlockStorage + 'static> {
+        fs: &'static Fat<'static, B>,
+        client: &'static TestClient,
+        disk: &'static B,
+    }
+
+    impl<B: TestBlockStorage + 'static> Harness<B> {
+        fn new(disk: &'static B) -> Harness<B> {
+            let fs = Box::leak(Box::new(Fat::new(disk, Box::leak(Box::new([0; 512])))));
+            disk.set_client(fs);
+            let client = Box::leak(Box::new(TestClient {
+                events: RefCell::new(Vec::new()),
+                buffer: TakeCell::new(Box::leak(vec![0; 8192].into_boxed_slice())),
+            }));
+            fs.set_client(client);
+            Harness { fs, client, disk }
+        }
+
+        /// Run the operation that was just started until it completes.
+        fn wait(&self) -> Event {
+            // Operations start from a deferred call.
+            self.fs.handle_deferred_call();
+            for _ in 0..100_000 {
+                if let Some(event) = self.client.events.borrow_mut().pop() {
+                    return event;
+                }
+                if !self.disk.complete() {
+                    break;
+                }
+            }
+            panic!("operation did not complete");
+        }
+
+        fn open(&self, namespace: u32, name: &str, mode: OpenMode) -> Result<FatFile, ErrorCode> {
+            self.fs.open(namespace, name.as_bytes(), mode)?;
+            match self.wait() {
+                Event::Open(result) => result,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn write(&self, file: FatFile, data: &[u8]) -> (FatFile, usize, Result<(), ErrorCode>) {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            self.fs.write(file, buffer, data.len()).unwrap();
+            match self.wait() {
+                Event::Write(file, length, result) => (file, length, result),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn read(&self, file: FatFile, length: usize) -> (FatFile, Vec<u8>) {
+            let buffer = self.client.buffer.take().unwrap();
+            self.fs.read(file, buffer, length).unwrap();
+            match self.wait() {
+                Event::Read(file, data, result) => {
+                    assert_eq!(result, Ok(()));
+                    (file, data)
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn delete(&self, namespace: u32, name: &str) -> Result<(), ErrorCode> {
+            self.fs.delete(namespace, name.as_bytes())?;
+            match self.wait() {
+                Event::Delete(result) => result,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn list(&self, namespace: u32) -> Vec<(String, u32)> {
+            let mut files = Vec::new();
+            loop {
+                self.fs.list(namespace, files.len()).unwrap();
+                match self.wait() {
+                    Event::List(Ok(info)) => {
+                        files.push((String::from_utf8(info.name().to_vec()).unwrap(), info.size))
+                    }
+                    Event::List(Err(ErrorCode::NOSUPPORT)) => return files,
+                    event => panic!("unexpected {:?}", event),
+                }
+            }
+        }
+    }
+
+    fn pattern(length: usize, seed: u8) -> Vec<u8> {
+        (0..length)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    /// Create, write, read, list, truncate and delete files.
+    fn check_files(fat_type: FatType, sectors: u32, start: usize) {
+        let mut image = vec![0; (start + sectors as usize) * SECTOR_SIZE];
+        format(&mut image, start, sectors, fat_type);
+        let disk = TestDisk::new(image);
+        let test = Harness::new(disk);
+        let volume = Volume::read(&disk.disk.borrow(), start);
+        let free = volume.free_clusters(&disk.disk.borrow());
+
+        assert_eq!(
+            test.open(7, "log.txt", OpenMode::Existing),
+            Err(ErrorCode::NOSUPPORT)
+        );
+        assert_eq!(test.list(7), []);
+        assert_eq!(
+            test.open(7, "not valid.txt", OpenMode::Create),
+            Err(ErrorCode::INVAL)
+        );
+
+        // Write across clusters, then overwrite part of it.
+        let mut contents = pattern(3000, 1);
+        let file = test.open(7, "log.txt", OpenMode::Create).unwrap();
+        assert_eq!(test.fs.size(&file), 0);
+        let (file, length, result) = test.write(file, &contents);
+        assert_eq!((length, result), (3000, Ok(())));
+        assert_eq!(test.fs.position(&file), 3000);
+        let file = test.fs.seek(file, 100).unwrap();
+        let (file, _, _) = test.write(file, &[0xAA; 700]);
+        contents[100..800].fill(0xAA);
+        assert_eq!(test.fs.size(&file), 3000);
+        assert_eq!(test.fs.seek(file, 3001), Err(ErrorCode::INVAL));
+
+        let file = test.fs.seek(file, 0).unwrap();
+        let (file, data) = test.read(file, 4096);
+        assert_eq!(data, contents);
+        let (_, data) = test.read(file, 10);
+        assert_eq!(data, []);
+
+        // Appending after reopening.
+        let file = test.open(7, "LOG.TXT", OpenMode::Existing).unwrap();
+        assert_eq!(test.fs.size(&file), 3000);
+        let file = test.fs.seek(file, 3000).unwrap();
+        let (_, length, _) = test.write(file, &pattern(1000, 2));
+        assert_eq!(length, 1000);
+        contents.extend(pattern(1000, 2));
+
+        let other = test.open(7, "b.bin", OpenMode::Truncate).unwrap();
+        assert_eq!(test.write(other, b"0123456789").2, Ok(()));
+        let mut files = test.list(7);
+        files.sort();
+        assert_eq!(
+            files,
+            [("B.BIN".to_string(), 10), ("LOG.TXT".to_string(), 4000)]
+        );
+        assert_eq!(test.list(8), []);
+
+        // The files can be read without the capsule.
+        {
+            let image = disk.disk.borrow();
+            assert_eq!(
+                volume.read_file(&image, b"00000007   ", b"LOG     TXT"),
+                Some(contents.clone())
+            );
+            assert_eq!(
+                volume.read_file(&image, b"00000007   ", b"B       BIN"),
+                Some(b"0123456789".to_vec())
+            );
+            volume.check_fat_copies(&image);
+            // The namespace directory, 8 clusters of LOG.TXT and 1 of B.BIN.
+            assert_eq!(volume.free_clusters(&image), free - 10);
+        }
+
+        // Truncating and deleting free the clusters.
+        let file = test.open(7, "log.txt", OpenMode::Truncate).unwrap();
+        assert_eq!(test.fs.size(&file), 0);
+        assert_eq!(test.delete(7, "B.BIN"), Ok(()));
+        assert_eq!(test.delete(7, "B.BIN"), Err(ErrorCode::NOSUPPORT));
+        assert_eq!(test.list(7), [("LOG.TXT".to_string(), 0)]);
+        {
+            let image = disk.disk.borrow();
+            assert_eq!(
+                volume.read_file(&image, b"00000007   ", b"LOG     TXT"),
+                Some(Vec::new())
+            );
+            assert_eq!(volume.free_clusters(&image), free - 1);
+            volume.check_fat_copies(&image);
+        }
+
+        // More files than fit in the first cluster of a directory.
+        for i in 0..20 {
+            let file = test
+                .open(9, &format!("FILE{}.DAT", i), OpenMode::Create)
+                .unwrap();
+            assert_eq!(test.write(file, &pattern(i, i as u8)).2, Ok(()));
+        }
+        let files = test.list(9);
+        assert_eq!(files.len(), 20);
+        for i in 0..20 {
+            let name = format!("FILE{}.DAT", i);
+            assert!(files.contains(&(name.clone(), i as u32)));
+            let file = test.open(9, &name, OpenMode::Existing).unwrap();
+            assert_eq!(test.read(file, 100).1, pattern(i, i as u8));
+        }
+        volume.check_fat_copies(&disk.disk.borrow());
+    }
+
+    /// Fill the device, a FAT12 volume whose FAT entries also cross sectors.
+    #[test]
+    fn full() {
+        let sectors = 1200;
+        let mut image = vec![0; sectors * SECTOR_SIZE];
+        format(&mut image, 0, sectors as u32, FatType::Fat12);
+        let disk = TestDisk::new(image);
+        let test = Harness::new(disk);
+
+        let mut file = test.open(0, "FULL.BIN", OpenMode::Create).unwrap();
+        let mut contents = Vec::new();
+        loop {
+            let data = pattern(8192, contents.len() as u8);
+            let (next, length, result) = test.write(file, &data);
+            contents.extend_from_slice(&data[..length]);
+            file = next;
+            if result.is_err() {
+                assert_eq!(result, Err(ErrorCode::NOMEM));
+                break;
+            }
+        }
+        assert_eq!(test.fs.size(&file) as usize, contents.len());
+
+        let image = disk.disk.borrow();
+        let volume = Volume::read(&image, 0);
+        assert_eq!(volume.free_clusters(&image), 0);
+        let entry = volume.find(&image, 0, b"FULL    BIN").unwrap();
+        assert_eq!(u32_at(&entry, 28) as usize, contents.len());
+        let data: Vec<u8> = volume
+            .chain(&image, first_cluster(&entry))
+            .iter()
+            .flat_map(|&cluster| volume.cluster(&image, cluster).to_vec())
+            .collect();
+        assert_eq!(data, contents);
+        volume.check_fat_copies(&image);
+    }
+
+    /// Files written by other systems, with long file names, can be read.
+    #[test]
+    fn existing_files() {
+        let sectors = 5000;
+        let mut image = vec![0; sectors * SECTOR_SIZE];
+        format(&mut image, 0, sectors as u32, FatType::Fat16);
+        let volume = Volume::read(&image, 0);
+        let root = volume.root_start * SECTOR_SIZE;
+        // A long file name entry, followed by the short name entry.
+        image[root..root + 11].copy_from_slice(b"\x41h\0e\0l\0l\0o\0");
+        image[root + 11] = 0x0F;
+        image[root + 32..root + 43].copy_from_slice(b"HELLO~1 TXT");
+        image[root + 32 + 26] = 2;
+        image[root + 32 + 28] = 5;
+        volume.set_fat(&mut image, 0, 2, 0xFFFF);
+        volume.set_fat(&mut image, 1, 2, 0xFFFF);
+        let data = volume.data_start * SECTOR_SIZE;
+        image[data..data + 5].copy_from_slice(b"hello");
+
+        let disk = TestDisk::new(image);
+        let test = Harness::new(disk);
+        assert_eq!(test.list(0), [("HELLO~1.TXT".to_string(), 5)]);
+        let file = test.open(0, "hello~1.txt", OpenMode::Existing).unwrap();
+        assert_eq!(test.read(file, 100).1, b"hello");
+    }
+
+    /// Errors of the device fail the operation, later operations write the
+    /// changes again.
+    #[test]
+    fn write_error() {
+        let sectors = 1200;
+        let mut image = vec![0; sectors * SECTOR_SIZE];
+        format(&mut image, 0, sectors as u32, FatType::Fat12);
+        let disk = TestDisk::new(image);
+        let test = Harness::new(disk);
+
+        let file = test.open(3, "A.TXT", OpenMode::Create).unwrap();
+        disk.writes_left.set(Some(1));
+        let (_, _, result) = test.write(file, &pattern(600, 3));
+        assert_eq!(result, Err(ErrorCode::FAIL));
+        disk.writes_left.set(None);
+
+        let file = test.open(3, "A.TXT", OpenMode::Truncate).unwrap();
+        let (file, _, result) = test.write(file, &pattern(600, 4));
+        assert_eq!(result, Ok(()));
+        let file = test.fs.seek(file, 0).unwrap();
+        assert_eq!(test.read(file, 1000).1, pattern(600, 4));
+        let image = disk.disk.borrow();
+        let volume = Volume::read(&image, 0);
+        assert_eq!(
+            volume.read_file(&image, b"00000003   ", b"A       TXT"),
+            Some(pattern(600, 4))
+        );
+        volume.check_fat_copies(&image);
+    }
+
+    /// The file system works over a RAM disk.
+    #[test]
+    fn ram_disk() {
+        let sectors = 100;
+        let storage = Box::leak(vec![0; sectors * SECTOR_SIZE].into_boxed_slice());
+        format(storage, 0, sectors as u32, FatType::Fat12);
+        let ram_disk: &'static RamDisk = Box::leak(Box::new(RamDisk::new(storage, SECTOR_SIZE)));
+        let test = Harness::new(ram_disk);
+
+        let file = test.open(1, "ram.txt", OpenMode::Create).unwrap();
+        let (file, _, _) = test.write(file, &pattern(2000, 5));
+        let file = test.fs.seek(file, 1500).unwrap();
+        assert_eq!(test.read(file, 1000).1, pattern(2000, 5)[1500..]);
+        assert_eq!(test.list(1), [("RAM.TXT".to_string(), 2000)]);
+    }
+
+    #[test]
+    fn fat12_files_in_partition() {
+        check_files(FatType::Fat12, 2000, 63);
+    }
+
+    #[test]
+    fn fat16_files() {
+        check_files(FatType::Fat16, 5000, 0);
+    }
+
+    #[test]
+    fn fat32_files() {
+        check_files(FatType::Fat32, 70000, 0);
+    }
+}
@@ -0,0 +1,534 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! File System Userspace Driver.
+//!
+//! Provides userspace access to files of a file system, such as a FAT file
+//! system on an SD card. Each process only sees the files in its own
+//! namespace, which is the write ID of its `StoragePermissions`, so processes
+//! can not access each other's files.
+//!
+//! Processes open files by name and receive a handle, which is used to read,
+//! write, seek in and close the file. A process can have up to
+//! `MAX_OPEN_FILES` files open at once. Every process has its own position in
+//! each of its open files.
+//!
+//! ```rust,ignore
+//! +===============+
+//! ||  Userspace  ||
+//! +===============+
+//!
+//! -----Syscall Interface-----
+//!
+//! +-------------------------------+
+//! |  FileSystem Driver (this file) |
+//! +-------------------------------+
+//!
+//!    hil::filesystem
+//!
+//! +-------------------------------+
+//! |  FAT                          |
+//! +-------------------------------+
+//!
+//!    hil::block_storage
+//!
+//! +-------------------------------+
+//! |  SD card                      |
+//! +-------------------------------+
+//! ```
+
+use capsules_core::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::FileSystem as usize;
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::errorcode;
+use kernel::grant::Grant;
+use kernel::grant::{AllowRoCount, AllowRwCount, GrantKernelData, UpcallCount};
+use kernel::hil::filesystem::{FileInfo, FileSystem, FileSystemClient, OpenMode, MAX_NAME_LEN};
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+/// Number of files each process can have open at once.
+pub const MAX_OPEN_FILES: usize = 4;
+
+/// IDs for read-only allow buffers.
+mod ro_allow {
+    /// Name of the file to open or delete.
+    pub const NAME: usize = 0;
+    /// Data to write.
+    pub const DATA: usize = 1;
+    /// The number of RO allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 2;
+}
+
+/// IDs for read-write allow buffers.
+mod rw_allow {
+    /// Data read, or name of a listed file.
+    pub const DATA: usize = 0;
+    /// The number of RW allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for upcalls.
+mod upcalls {
+    /// Single upcall.
+    pub const DONE: usize = 0;
+    /// The number of upcalls the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum UserSpaceOp {
+    Open,
+    Read,
+    Write,
+    List,
+    Delete,
+}
+
+/// Contents of the grant for each app.
+pub struct App<F: Copy> {
+    op: OptionalCell<UserSpaceOp>,
+    /// Argument of the pending operation: the open mode, file handle or list
+    /// index.
+    arg: Cell<usize>,
+    files: [OptionalCell<F>; MAX_OPEN_FILES],
+}
+
+impl<F: Copy> Default for App<F> {
+    fn default() -> App<F> {
+        App {
+            op: OptionalCell::empty(),
+            arg: Cell::new(0),
+            files: core::array::from_fn(|_| OptionalCell::empty()),
+        }
+    }
+}
+
+impl<F: Copy> App<F> {
+    fn file(&self, handle: usize) -> Result<F, ErrorCode> {
+        self.files
+            .get(handle)
+            .and_then(|file| file.get())
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    /// Store the new state of the file with `handle`, unless it was closed.
+    fn update_file(&self, handle: usize, file: F) {
+        self.files.get(handle).map(|slot| {
+            if slot.is_some() {
+                slot.set(file);
+            }
+        });
+    }
+}
+
+/// Capsule that provides userspace access to a file system.
+pub struct FileSystemDriver<'a, FS: FileSystem<'a>> {
+    fs: &'a FS,
+    /// Grant storage for each app.
+    apps: Grant<
+        App<FS::File>,
+        UpcallCount<{ upcalls::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// App that is actively using the file system.
+    processid: OptionalCell<ProcessId>,
+    /// Buffer for data read or written.
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, FS: FileSystem<'a>> FileSystemDriver<'a, FS> {
+    pub fn new(
+        fs: &'a FS,
+        buffer: &'static mut [u8],
+        grant: Grant<
+            App<FS::File>,
+            UpcallCount<{ upcalls::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> FileSystemDriver<'a, FS> {
+        FileSystemDriver {
+            fs,
+            apps: grant,
+            processid: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        self.processid.map_or(Err(ErrorCode::RESERVE), |processid| {
+            self.apps
+                .enter(processid, |app, kernel_data| {
+                    let namespace = processid
+                        .get_storage_permissions()
+                        .and_then(|perms| perms.get_write_id())
+                        .ok_or(ErrorCode::INVAL)?;
+
+                    match app.op.get() {
+                        Some(UserSpaceOp::Open) | Some(UserSpaceOp::Delete) => {
+                            let mut name = [0; MAX_NAME_LEN];
+                            let length = kernel_data
+                                .get_readonly_processbuffer(ro_allow::NAME)
+                                .and_then(|buffer| {
+                                    buffer.enter(|appname| {
+                                        if appname.len() > MAX_NAME_LEN {
+                                            Err(ErrorCode::SIZE)
+                                        } else {
+                                            appname.copy_to_slice(&mut name[..appname.len()]);
+                                            Ok(appname.len())
+                                        }
+                                    })
+                                })
+                                .unwrap_or(Err(ErrorCode::RESERVE))?;
+
+                            if app.op.contains(&UserSpaceOp::Delete) {
+                                return self.fs.delete(namespace, &name[..length]);
+                            }
+                            let mode = match app.arg.get() {
+                                0 => OpenMode::Existing,
+                                1 => OpenMode::Create,
+                                2 => OpenMode::Truncate,
+                                _ => return Err(ErrorCode::INVAL),
+                            };
+                            self.fs.open(namespace, &name[..length], mode)
+                        }
+                        Some(UserSpaceOp::Read) => {
+                            let file = app.file(app.arg.get())?;
+                            let length = kernel_data
+                                .get_readwrite_processbuffer(rw_allow::DATA)
+                                .map_or(0, |buffer| buffer.len());
+                            self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
+                                let length = cmp::min(length, buf.len());
+                                self.fs.read(file, buf, length).map_err(|(e, buf)| {
+                                    self.buffer.replace(buf);
+                                    e
+                                })
+                            })
+                        }
+                        Some(UserSpaceOp::Write) => {
+                            let file = app.file(app.arg.get())?;
+                            let length = kernel_data
+                                .get_readonly_processbuffer(ro_allow::DATA)
+                                .and_then(|buffer| {
+                                    buffer.enter(|data| {
+                                        self.buffer.map_or(0, |buf| {
+                                            // Longer data is written by
+                                            // several commands.
+                                            let length = cmp::min(data.len(), buf.len());
+                                            data[..length].copy_to_slice(&mut buf[..length]);
+                                            length
+                                        })
+                                    })
+                                })
+                                .unwrap_or(0);
+                            self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
+                                self.fs.write(file, buf, length).map_err(|(e, buf)| {
+                                    self.buffer.replace(buf);
+                                    e
+                                })
+                            })
+                        }
+                        Some(UserSpaceOp::List) => self.fs.list(namespace, app.arg.get()),
+                        None => Ok(()),
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    fn check_queue(&self) {
+        // If an app is already running let it complete.
+        if self.processid.is_some() {
+            return;
+        }
+
+        for appiter in self.apps.iter() {
+            let processid = appiter.processid();
+            let has_pending_op = appiter.enter(|app, _| {
+                // If this app has a pending command let's use it.
+                app.op.is_some()
+            });
+            let started_command = if has_pending_op {
+                // Mark this driver as being in use.
+                self.processid.set(processid);
+                match self.run() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        // Let the app know its command failed.
+                        let _ = self.apps.enter(processid, |app, upcalls| {
+                            app.op.clear();
+                            upcalls
+                                .schedule_upcall(
+                                    upcalls::DONE,
+                                    (errorcode::into_statuscode(Err(e)), 0, 0),
+                                )
+                                .ok();
+                        });
+                        false
+                    }
+                }
+            } else {
+                false
+            };
+            if started_command {
+                break;
+            } else {
+                self.processid.clear();
+            }
+        }
+    }
+
+    /// Finish the operation of the active app with an upcall and start the
+    /// next queued operation.
+    fn complete(
+        &self,
+        op: UserSpaceOp,
+        upcall: impl FnOnce(&App<FS::File>, &GrantKernelData) -> (usize, usize, usize),
+    ) {
+        self.processid.map(|processid| {
+            let _ = self.apps.enter(processid, |app, kernel_data| {
+                if app.op.contains(&op) {
+                    app.op.clear();
+                    let args = upcall(app, kernel_data);
+                    kernel_data.schedule_upcall(upcalls::DONE, args).ok();
+                }
+            });
+        });
+
+        self.processid.clear();
+        self.check_queue();
+    }
+}
+
+impl<'a, FS: FileSystem<'a>> FileSystemClient<FS::File> for FileSystemDriver<'a, FS> {
+    fn open_done(&self, result: Result<FS::File, ErrorCode>) {
+        self.complete(UserSpaceOp::Open, |app, _| match result {
+            Ok(file) => match app.files.iter().position(|slot| slot.is_none()) {
+                Some(handle) => {
+                    app.files[handle].set(file);
+                    (
+                        errorcode::into_statuscode(Ok(())),
+                        handle,
+                        self.fs.size(&file) as usize,
+                    )
+                }
+                None => (errorcode::into_statuscode(Err(ErrorCode::NOMEM)), 0, 0),
+            },
+            Err(e) => (errorcode::into_statuscode(Err(e)), 0, 0),
+        });
+    }
+
+    fn read_done(
+        &self,
+        file: FS::File,
+        buffer: &'static mut [u8],
+        length: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        self.complete(UserSpaceOp::Read, |app, kernel_data| {
+            app.update_file(app.arg.get(), file);
+            let _ = kernel_data
+                .get_readwrite_processbuffer(rw_allow::DATA)
+                .and_then(|appbuffer| {
+                    appbuffer.mut_enter(|appslice| {
+                        let length = cmp::min(length, appslice.len());
+                        self.buffer.map(|buf| {
+                            appslice[..length].copy_from_slice(&buf[..length]);
+                        });
+                    })
+                });
+            (errorcode::into_statuscode(result), length, 0)
+        });
+    }
+
+    fn write_done(
+        &self,
+        file: FS::File,
+        buffer: &'static mut [u8],
+        length: usize,
+        result: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        self.complete(UserSpaceOp::Write, |app, _| {
+            app.update_file(app.arg.get(), file);
+            (errorcode::into_statuscode(result), length, 0)
+        });
+    }
+
+    fn delete_done(&self, result: Result<(), ErrorCode>) {
+        self.complete(UserSpaceOp::Delete, |_, _| {
+            (errorcode::into_statuscode(result), 0, 0)
+        });
+    }
+
+    fn list_done(&self, result: Result<FileInfo, ErrorCode>) {
+        self.complete(UserSpaceOp::List, |_, kernel_data| match result {
+            Ok(info) => {
+                let name = info.name();
+                let copied = kernel_data
+                    .get_readwrite_processbuffer(rw_allow::DATA)
+                    .and_then(|appbuffer| {
+                        appbuffer.mut_enter(|appslice| {
+                            if appslice.len() < name.len() {
+                                Err(ErrorCode::SIZE)
+                            } else {
+                                appslice[..name.len()].copy_from_slice(name);
+                                Ok(())
+                            }
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::RESERVE));
+                (
+                    errorcode::into_statuscode(copied),
+                    name.len(),
+                    info.size as usize,
+                )
+            }
+            Err(e) => (errorcode::into_statuscode(Err(e)), 0, 0),
+        });
+    }
+}
+
+impl<'a, FS: FileSystem<'a>> SyscallDriver for FileSystemDriver<'a, FS> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let op = match command_num {
+            // check if present
+            0 => return CommandReturn::success(),
+
+            // open the file named in RO allow 0, `data1` is the open mode
+            1 => {
+                let has_free_handle = self
+                    .apps
+                    .enter(processid, |app, _| app.files.iter().any(|f| f.is_none()))
+                    .unwrap_or(false);
+                if !has_free_handle {
+                    return CommandReturn::failure(ErrorCode::NOMEM);
+                }
+                UserSpaceOp::Open
+            }
+
+            // close the file with handle `data1`
+            2 => {
+                return self
+                    .apps
+                    .enter(processid, |app, _| {
+                        let uses_file = app
+                            .op
+                            .get()
+                            .is_some_and(|op| op == UserSpaceOp::Read || op == UserSpaceOp::Write);
+                        if uses_file && app.arg.get() == data1 {
+                            // The pending operation uses the file.
+                            return CommandReturn::failure(ErrorCode::BUSY);
+                        }
+                        match app.files.get(data1).filter(|file| file.is_some()) {
+                            Some(file) => {
+                                file.clear();
+                                CommandReturn::success()
+                            }
+                            None => CommandReturn::failure(ErrorCode::INVAL),
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into());
+            }
+
+            // read from, write to the file with handle `data1`
+            3 => UserSpaceOp::Read,
+            4 => UserSpaceOp::Write,
+
+            // set the position in the file with handle `data1` to `data2`
+            5 => {
+                return self
+                    .apps
+                    .enter(processid, |app, _| {
+                        let file = app
+                            .file(data1)
+                            .and_then(|file| self.fs.seek(file, data2 as u32));
+                        match file {
+                            Ok(file) => {
+                                app.files[data1].set(file);
+                                CommandReturn::success()
+                            }
+                            Err(e) => CommandReturn::failure(e),
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into());
+            }
+
+            // get the position in and size of the file with handle `data1`
+            6 => {
+                return self
+                    .apps
+                    .enter(processid, |app, _| match app.file(data1) {
+                        Ok(file) => CommandReturn::success_u32_u32(
+                            self.fs.position(&file),
+                            self.fs.size(&file),
+                        ),
+                        Err(e) => CommandReturn::failure(e),
+                    })
+                    .unwrap_or_else(|err| err.into());
+            }
+
+            // list the file with index `data1`
+            7 => UserSpaceOp::List,
+
+            // delete the file named in RO allow 0
+            8 => UserSpaceOp::Delete,
+
+            // default
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+
+        if self.processid.is_none() {
+            // Nothing is using the file system, so we can handle this request.
+            self.processid.set(processid);
+            let _ = self.apps.enter(processid, |app, _| {
+                app.op.set(op);
+                app.arg.set(data1);
+            });
+            let ret = self.run();
+
+            if let Err(e) = ret {
+                let _ = self.apps.enter(processid, |app, _| app.op.clear());
+                self.processid.clear();
+                self.check_queue();
+                CommandReturn::failure(e)
+            } else {
+                CommandReturn::success()
+            }
+        } else {
+            // There is an active app, so queue this request (if possible).
+            self.apps
+                .enter(processid, |app, _| {
+                    if app.op.is_some() {
+                        // No more room in the queue, nowhere to store this
+                        // request.
+                        CommandReturn::failure(ErrorCode::NOMEM)
+                    } else {
+                        app.op.set(op);
+                        app.arg.set(data1);
+                        CommandReturn::success()
+                    }
+                })
+                .unwrap_or_else(|err| err.into())
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
@@ -0,0 +1,310 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Block storage device on a region of flash.
+//!
+//! Provides `hil::block_storage::BlockStorage` over consecutive flash pages,
+//! for example to hold a FAT volume that is exported over USB mass storage.
+//! Blocks may be smaller than a flash page, in which case several blocks share
+//! a page and writing a block reads, modifies and writes back its page.
+//!
+//! Writes are not power-safe: a reset while a page is written can lose the
+//! other blocks of that page.
+//!
+//! ```plain
+//! hil::block_storage::BlockStorage
+//!                ┌─────────────┐
+//!                │             │
+//!                │ This module │
+//!                │             │
+//!                └─────────────┘
+//!               hil::flash::Flash
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::{hil, static_init};
+//!
+//! let page_buffer = static_init!(nrf52::nvmc::NrfPage, nrf52::nvmc::NrfPage::default());
+//! let flash_block_storage = static_init!(
+//!     capsules_extra::flash_block_storage::FlashBlockStorage<'static, nrf52::nvmc::Nvmc>,
+//!     capsules_extra::flash_block_storage::FlashBlockStorage::new(
+//!         &base_peripherals.nvmc,
+//!         page_buffer,
+//!         0xC0000 / 4096, // First page of the region
+//!         64,             // Pages
+//!         512,            // Block size
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&base_peripherals.nvmc, flash_block_storage);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::hil;
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Read,
+    Write,
+}
+
+pub struct FlashBlockStorage<'a, F: hil::flash::Flash + 'static> {
+    /// The flash the region is on.
+    flash: &'a F,
+    /// Buffer for one flash page.
+    page_buffer: TakeCell<'static, F::Page>,
+    /// First page of the region.
+    first_page: usize,
+    /// Size of a block in bytes, divides the page size.
+    block_size: usize,
+    /// Number of blocks in a flash page.
+    blocks_per_page: usize,
+    /// Number of blocks in the region.
+    block_count: u64,
+    client: OptionalCell<&'a dyn BlockStorageClient>,
+    /// The operation in progress.
+    operation: OptionalCell<Operation>,
+    /// The buffer of the operation in progress.
+    buffer: TakeCell<'static, [u8]>,
+    /// Next block to read or write.
+    block: Cell<u64>,
+    /// Number of blocks left to read or write.
+    remaining: Cell<usize>,
+    /// Position of the next block in `buffer`.
+    offset: Cell<usize>,
+}
+
+impl<'a, F: hil::flash::Flash> FlashBlockStorage<'a, F> {
+    /// Create a block device on `page_count` flash pages starting with page
+    /// `first_page`. `block_size` must divide the size of a flash page.
+    pub fn new(
+        flash: &'a F,
+        page_buffer: &'static mut F::Page,
+        first_page: usize,
+        page_count: usize,
+        block_size: usize,
+    ) -> FlashBlockStorage<'a, F> {
+        let page_size = page_buffer.as_mut().len();
+        let blocks_per_page = if block_size > 0 && page_size % block_size == 0 {
+            page_size / block_size
+        } else {
+            0
+        };
+        FlashBlockStorage {
+            flash,
+            page_buffer: TakeCell::new(page_buffer),
+            first_page,
+            block_size,
+            blocks_per_page,
+            block_count: (page_count * blocks_per_page) as u64,
+            client: OptionalCell::empty(),
+            operation: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            block: Cell::new(0),
+            remaining: Cell::new(0),
+            offset: Cell::new(0),
+        }
+    }
+
+    fn start(
+        &self,
+        operation: Operation,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.block_count == 0 {
+            return Err((ErrorCode::RESERVE, buffer));
+        }
+        if self.operation.is_some() || self.page_buffer.is_none() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        let end = match block.checked_add(count as u64) {
+            Some(end) => end,
+            None => return Err((ErrorCode::INVAL, buffer)),
+        };
+        if count == 0 || end > self.block_count || buffer.len() < count * self.block_size {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+
+        self.buffer.replace(buffer);
+        self.operation.set(operation);
+        self.block.set(block);
+        self.remaining.set(count);
+        self.offset.set(0);
+
+        self.next_page().map_err(|e| {
+            self.operation.clear();
+            (e, self.buffer.take().unwrap())
+        })
+    }
+
+    /// The flash page of the next block.
+    fn page(&self) -> usize {
+        self.first_page + (self.block.get() / self.blocks_per_page as u64) as usize
+    }
+
+    /// The position of the next block in its page, and the number of blocks
+    /// of the operation in that page.
+    fn blocks_in_page(&self) -> (usize, usize) {
+        let first_block = (self.block.get() % self.blocks_per_page as u64) as usize;
+        (
+            first_block,
+            cmp::min(self.remaining.get(), self.blocks_per_page - first_block),
+        )
+    }
+
+    /// Start the flash operation for the page of the next block.
+    fn next_page(&self) -> Result<(), ErrorCode> {
+        let page = self.page();
+        let (_, blocks) = self.blocks_in_page();
+
+        let page_buffer = self.page_buffer.take().ok_or(ErrorCode::FAIL)?;
+        let result = if self.operation.contains(&Operation::Write) && blocks == self.blocks_per_page
+        {
+            // The whole page is replaced, so there is no need to read it first.
+            self.copy_to_page(page_buffer, 0, blocks);
+            self.flash.write_page(page, page_buffer)
+        } else {
+            self.flash.read_page(page, page_buffer)
+        };
+        result.map_err(|(e, page_buffer)| {
+            self.page_buffer.replace(page_buffer);
+            e
+        })
+    }
+
+    /// Copy `blocks` blocks from the buffer to the page buffer, starting with
+    /// block `first_block` of the page.
+    fn copy_to_page(&self, page_buffer: &mut F::Page, first_block: usize, blocks: usize) {
+        let offset = self.offset.get();
+        let length = blocks * self.block_size;
+        let start = first_block * self.block_size;
+        self.buffer.map(|buffer| {
+            page_buffer.as_mut()[start..start + length]
+                .copy_from_slice(&buffer[offset..offset + length]);
+        });
+    }
+
+    /// Account for `blocks` blocks done, then continue with the next page or
+    /// finish the operation.
+    fn advance(&self, blocks: usize) {
+        self.block.set(self.block.get() + blocks as u64);
+        self.remaining.set(self.remaining.get() - blocks);
+        self.offset
+            .set(self.offset.get() + blocks * self.block_size);
+
+        if self.remaining.get() == 0 {
+            self.finish(Ok(()));
+        } else if let Err(e) = self.next_page() {
+            self.finish(Err(e));
+        }
+    }
+
+    fn finish(&self, result: Result<(), ErrorCode>) {
+        if let (Some(operation), Some(buffer)) = (self.operation.take(), self.buffer.take()) {
+            // Report flash errors as FAIL, as documented by the HIL.
+            let result = result.map_err(|_| ErrorCode::FAIL);
+            self.client.map(move |client| match operation {
+                Operation::Read => client.read_complete(buffer, result),
+                Operation::Write => client.write_complete(buffer, result),
+            });
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash> BlockStorage<'a> for FlashBlockStorage<'a, F> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start(Operation::Read, buffer, block, count)
+    }
+
+    fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start(Operation::Write, buffer, block, count)
+    }
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for FlashBlockStorage<'_, F> {
+    fn read_complete(
+        &self,
+        page_buffer: &'static mut F::Page,
+        result: Result<(), hil::flash::Error>,
+    ) {
+        if result.is_err() {
+            self.page_buffer.replace(page_buffer);
+            self.finish(Err(ErrorCode::FAIL));
+            return;
+        }
+
+        let (first_block, blocks) = self.blocks_in_page();
+        match self.operation.get() {
+            Some(Operation::Read) => {
+                let offset = self.offset.get();
+                let length = blocks * self.block_size;
+                let start = first_block * self.block_size;
+                self.buffer.map(|buffer| {
+                    buffer[offset..offset + length]
+                        .copy_from_slice(&page_buffer.as_mut()[start..start + length]);
+                });
+                self.page_buffer.replace(page_buffer);
+                self.advance(blocks);
+            }
+            Some(Operation::Write) => {
+                // Replace the blocks in the page and write it back.
+                self.copy_to_page(page_buffer, first_block, blocks);
+                if let Err((_, page_buffer)) = self.flash.write_page(self.page(), page_buffer) {
+                    self.page_buffer.replace(page_buffer);
+                    self.finish(Err(ErrorCode::FAIL));
+                }
+            }
+            None => {
+                self.page_buffer.replace(page_buffer);
+            }
+        }
+    }
+
+    fn write_complete(
+        &self,
+        page_buffer: &'static mut F::Page,
+        result: Result<(), hil::flash::Error>,
+    ) {
+        self.page_buffer.replace(page_buffer);
+        if result.is_err() {
+            self.finish(Err(ErrorCode::FAIL));
+            return;
+        }
+        let (_, blocks) = self.blocks_in_page();
+        self.advance(blocks);
+    }
+
+    fn erase_complete(&self, _result: Result<(), hil::flash::Error>) {}
+}
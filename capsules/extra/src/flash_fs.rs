@@ -0,0 +1,1542 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Power-safe, wear-leveling file system for flash.
+//!
+//! Provides `hil::filesystem::FileSystem` over a region of a
+//! `hil::flash::Flash` device, such as the internal flash of the chip or an
+//! SPI NOR flash like the MX25R6435F. Files of different namespaces are kept
+//! apart, so with the file system driver every app has its own files.
+//!
+//! The region is used as a circular log of pages. Every page holds a header
+//! followed by either a chunk of the data of a file, or nothing for records
+//! that create or delete a file. Pages are never changed in place: writing to
+//! a file writes a new copy of the chunk at the head of the log, and the old
+//! copy becomes stale. Before the head reaches the tail of the log, pages at
+//! the tail that are still in use are copied to the head and the others are
+//! dropped. So every page of the region is written once on each lap around
+//! the log, including pages holding files that never change, which spreads
+//! wear evenly.
+//!
+//! Each page is written by a single flash operation and has a sequence number
+//! and a checksum. If the power is lost, only the page being written can be
+//! incomplete, and it is ignored when the file system is mounted again. Every
+//! create, truncate, delete and write within one chunk is therefore either
+//! done completely or not at all. A write spanning several chunks can be
+//! partly done.
+//!
+//! No index is kept in RAM: operations read every page of the region to find
+//! the pages they need, which suits regions of up to a few hundred pages.
+//! Each page holds `page size - 64` bytes of data of a single file, and file
+//! names are up to 32 bytes long.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//!
+//! let pagebuffer = static_init!(
+//!     <FlashCtrl as kernel::hil::flash::Flash>::Page,
+//!     <FlashCtrl as kernel::hil::flash::Flash>::Page::default()
+//! );
+//! // Use 64 pages starting at page 192 of the flash.
+//! let flash_fs = static_init!(
+//!     capsules_extra::flash_fs::FlashFs<'static, FlashCtrl>,
+//!     capsules_extra::flash_fs::FlashFs::new(flash_ctrl, pagebuffer, 192, 64)
+//! );
+//! kernel::hil::flash::HasClient::set_client(flash_ctrl, flash_fs);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::hil::filesystem::{FileInfo, FileSystem, FileSystemClient, OpenMode, MAX_NAME_LEN};
+use kernel::hil::flash::{self, Flash};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::helpers::crc32_posix;
+use kernel::ErrorCode;
+
+/// "TFS1"
+const MAGIC: u32 = 0x3153_4654;
+
+/// Layout of the page header.
+const CHECKSUM: usize = 4;
+const SEQUENCE: usize = 8;
+const TAIL: usize = 12;
+const KIND: usize = 16;
+const NAME_LEN: usize = 17;
+const LENGTH: usize = 18;
+const GENERATION: usize = 20;
+const AUX: usize = 24;
+const NAMESPACE: usize = 28;
+const NAME: usize = 32;
+const HEADER_LEN: usize = NAME + MAX_NAME_LEN;
+
+/// Free pages needed before writing a page: one for the page, one to copy a
+/// page from the tail when making room for the next page, and one kept for
+/// deleting files.
+const RESERVE: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    /// A chunk of the data of a file.
+    Data = 1,
+    /// Creates a file, or empties it by replacing an earlier generation.
+    Create = 2,
+    /// Deletes a file.
+    Delete = 3,
+}
+
+/// Header of a page.
+///
+/// Every time a file is created or truncated it gets a new generation, the
+/// sequence number of the record that created it. Data pages belong to a
+/// generation, and records that truncate or delete a file name the
+/// generation they replace. A page is in use until it is replaced by a newer
+/// copy of the same chunk or its generation is replaced.
+#[derive(Clone, Copy, Debug)]
+struct Header {
+    /// Sequence number, increased for every page written.
+    sequence: u32,
+    /// Sequence number of the oldest page that was still in the log when
+    /// this page was written.
+    tail: u32,
+    kind: Kind,
+    name_len: usize,
+    /// Length of the data in the page.
+    length: usize,
+    generation: u32,
+    /// The chunk of data pages, the generation replaced by records or 0.
+    aux: u32,
+    namespace: u32,
+}
+
+impl Header {
+    fn parse(page: &[u8]) -> Option<Header> {
+        let kind = match page[KIND] {
+            1 => Kind::Data,
+            2 => Kind::Create,
+            3 => Kind::Delete,
+            _ => return None,
+        };
+        let header = Header {
+            sequence: read_u32(page, SEQUENCE),
+            tail: read_u32(page, TAIL),
+            kind,
+            name_len: page[NAME_LEN] as usize,
+            length: u16::from_le_bytes([page[LENGTH], page[LENGTH + 1]]) as usize,
+            generation: read_u32(page, GENERATION),
+            aux: read_u32(page, AUX),
+            namespace: read_u32(page, NAMESPACE),
+        };
+        let valid = read_u32(page, 0) == MAGIC
+            && header.name_len <= MAX_NAME_LEN
+            && HEADER_LEN + header.length <= page.len()
+            && read_u32(page, CHECKSUM) == crc32_posix(&page[SEQUENCE..HEADER_LEN + header.length]);
+        valid.then_some(header)
+    }
+
+    /// Write the header to `page`, which already holds the name and data.
+    fn write(&self, page: &mut [u8]) {
+        write_u32(page, 0, MAGIC);
+        write_u32(page, SEQUENCE, self.sequence);
+        write_u32(page, TAIL, self.tail);
+        page[KIND] = self.kind as u8;
+        page[NAME_LEN] = self.name_len as u8;
+        page[LENGTH..LENGTH + 2].copy_from_slice(&(self.length as u16).to_le_bytes());
+        write_u32(page, GENERATION, self.generation);
+        write_u32(page, AUX, self.aux);
+        write_u32(page, NAMESPACE, self.namespace);
+        let checksum = crc32_posix(&page[SEQUENCE..HEADER_LEN + self.length]);
+        write_u32(page, CHECKSUM, checksum);
+    }
+}
+
+/// An open file of a `FlashFs` file system.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FlashFile {
+    generation: u32,
+    size: u32,
+    position: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Op {
+    Idle,
+    Open(OpenMode),
+    Read,
+    Write,
+    Delete,
+    List,
+}
+
+/// Steps of an operation. Steps that read every page keep their progress in
+/// `Scan`, so they can be repeated after each page is loaded.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Phase {
+    /// Find the newest page, which knows the tail of the log.
+    Mount,
+    /// Count the pages in the log.
+    MountCount,
+    /// Find the newest record of the file.
+    FindFile,
+    /// Find the size of the file with the generation.
+    FindSize(u32),
+    /// Write a record of the kind, replacing the generation.
+    WriteRecord(Kind, u32),
+    /// Make room for writing a chunk.
+    Collect,
+    /// Find the newest copy of the chunk at the file position.
+    FindChunk,
+    /// Copy data between the buffer and the chunk.
+    Transfer,
+    /// Check the candidate in `self.candidate` and find the next one.
+    ListFiles,
+    /// Read the name of the file whose record is at the page.
+    ListName(usize, u32),
+    Done,
+}
+
+/// Why a step stopped.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Stop {
+    /// Waiting for the flash.
+    Busy,
+    Error(ErrorCode),
+}
+
+impl From<ErrorCode> for Stop {
+    fn from(error: ErrorCode) -> Stop {
+        Stop::Error(error)
+    }
+}
+
+/// What a scan of the pages looks for, besides the newest record of
+/// `self.name`.
+#[derive(Clone, Copy, Default, Debug)]
+struct Query {
+    /// Generation of the file.
+    generation: u32,
+    /// Chunk of the file.
+    chunk: u32,
+    /// Find the lowest generation created after this one.
+    after: u32,
+}
+
+/// Progress and findings of reading every page.
+#[derive(Clone, Copy, Default, Debug)]
+struct Scan {
+    /// Next page to read.
+    page: usize,
+    /// Page, sequence number and tail of the newest page.
+    newest: Option<(usize, u32, u32)>,
+    /// Number of pages in the log.
+    pages: usize,
+    /// Sequence number, kind and generation of the newest record of the file.
+    record: Option<(u32, Kind, u32)>,
+    /// Page and sequence number of the newest record creating the query
+    /// generation.
+    created: Option<(usize, u32)>,
+    /// The query generation is replaced.
+    replaced: bool,
+    /// End of the data of the query generation.
+    size: u32,
+    /// Page, sequence number and data length of the newest copy of the query
+    /// chunk.
+    chunk: Option<(usize, u32, usize)>,
+    /// Generation and page of the first file created after the query in the
+    /// namespace.
+    next: Option<(u32, usize)>,
+}
+
+/// Progress of making room at the head of the log.
+#[derive(Clone, Copy, Debug)]
+enum Collect {
+    /// Look at the page at the tail, `examined` pages have been looked at.
+    Tail { examined: usize },
+    /// Check whether the tail page is in use.
+    Check { examined: usize, header: Header },
+    /// Copy the tail page to the head.
+    Copy { examined: usize, header: Header },
+    /// Drop the tail page with the sequence number from the log.
+    Drop { examined: usize, sequence: u32 },
+}
+
+pub struct FlashFs<'a, F: Flash + 'static> {
+    flash: &'a F,
+    client: OptionalCell<&'a dyn FileSystemClient<FlashFile>>,
+
+    /// Page buffer, holding the page `cached` when it is set.
+    buffer: TakeCell<'static, F::Page>,
+    cached: OptionalCell<usize>,
+    loading: Cell<usize>,
+    page_size: usize,
+    /// First page of the region on the flash.
+    first_page: usize,
+    /// Number of pages of the region.
+    pages: usize,
+
+    mounted: Cell<bool>,
+    /// Page the next page of the log is written to.
+    head: Cell<usize>,
+    /// Number of pages in the log.
+    used: Cell<usize>,
+    /// Sequence number of the next page.
+    sequence: Cell<u32>,
+    /// Pages with lower sequence numbers are not in the log.
+    tail: Cell<u32>,
+
+    op: Cell<Op>,
+    phase: Cell<Phase>,
+    result: Cell<Result<(), ErrorCode>>,
+    namespace: Cell<u32>,
+    name: Cell<[u8; MAX_NAME_LEN]>,
+    name_len: Cell<usize>,
+    file: Cell<FlashFile>,
+    data: TakeCell<'static, [u8]>,
+    length: Cell<usize>,
+    done: Cell<usize>,
+    /// Files to skip when listing.
+    index: Cell<usize>,
+    /// File to list if it is not replaced, its generation and page.
+    candidate: OptionalCell<(u32, usize)>,
+    info: OptionalCell<FileInfo>,
+
+    query: Cell<Query>,
+    scan: Cell<Scan>,
+    collect: OptionalCell<Collect>,
+}
+
+impl<'a, F: Flash> FlashFs<'a, F> {
+    /// Create a file system on `pages` pages of `flash` starting with
+    /// `first_page`. Pages must be larger than 64 bytes, and more than three
+    /// pages are needed, as three pages are kept free.
+    pub fn new(
+        flash: &'a F,
+        buffer: &'static mut F::Page,
+        first_page: usize,
+        pages: usize,
+    ) -> FlashFs<'a, F> {
+        let page_size = buffer.as_mut().len();
+        FlashFs {
+            flash,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            cached: OptionalCell::empty(),
+            loading: Cell::new(0),
+            page_size,
+            first_page,
+            pages,
+            mounted: Cell::new(false),
+            head: Cell::new(0),
+            used: Cell::new(0),
+            sequence: Cell::new(1),
+            tail: Cell::new(0),
+            op: Cell::new(Op::Idle),
+            phase: Cell::new(Phase::Done),
+            result: Cell::new(Ok(())),
+            namespace: Cell::new(0),
+            name: Cell::new([0; MAX_NAME_LEN]),
+            name_len: Cell::new(0),
+            file: Cell::new(FlashFile {
+                generation: 0,
+                size: 0,
+                position: 0,
+            }),
+            data: TakeCell::empty(),
+            length: Cell::new(0),
+            done: Cell::new(0),
+            index: Cell::new(0),
+            candidate: OptionalCell::empty(),
+            info: OptionalCell::empty(),
+            query: Cell::new(Query::default()),
+            scan: Cell::new(Scan::default()),
+            collect: OptionalCell::empty(),
+        }
+    }
+
+    /// Bytes of data each page holds.
+    fn chunk_size(&self) -> usize {
+        self.page_size - HEADER_LEN
+    }
+
+    fn set_name(&self, name: &[u8]) -> Result<(), ErrorCode> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        let mut stored = [0; MAX_NAME_LEN];
+        stored[..name.len()].copy_from_slice(name);
+        self.name.set(stored);
+        self.name_len.set(name.len());
+        Ok(())
+    }
+
+    /// Start an operation. Every operation reads pages of the flash before
+    /// it completes, so the client is only called after this returns. Errors
+    /// before the first page is read are returned instead.
+    fn start(&self, op: Op) -> Result<(), ErrorCode> {
+        self.op.set(op);
+        self.result.set(Ok(()));
+        self.collect.clear();
+        self.candidate.clear();
+        self.cached.clear();
+        if self.mounted.get() {
+            self.begin();
+        } else {
+            self.tail.set(0);
+            self.start_scan(Query::default());
+            self.phase.set(Phase::Mount);
+        }
+        match self.steps() {
+            Ok(()) => self.complete(Ok(())),
+            Err(Stop::Busy) => {}
+            Err(Stop::Error(e)) => {
+                self.op.set(Op::Idle);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the first phase of the operation on the mounted file system.
+    fn begin(&self) {
+        match self.op.get() {
+            Op::Open(_) | Op::Delete => {
+                self.start_scan(Query::default());
+                self.phase.set(Phase::FindFile);
+            }
+            Op::Read => self.find_chunk(),
+            Op::Write => self.phase.set(Phase::Collect),
+            Op::List => {
+                self.start_scan(Query::default());
+                self.phase.set(Phase::ListFiles);
+            }
+            Op::Idle => self.phase.set(Phase::Done),
+        }
+    }
+
+    /// Run the operation until it has to wait for the flash or is finished.
+    fn run(&self) {
+        match self.steps() {
+            Ok(()) => self.complete(Ok(())),
+            Err(Stop::Busy) => {}
+            Err(Stop::Error(e)) => self.complete(Err(e)),
+        }
+    }
+
+    fn steps(&self) -> Result<(), Stop> {
+        while self.phase.get() != Phase::Done {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    fn complete(&self, result: Result<(), ErrorCode>) {
+        let result = result.and(self.result.get());
+        let file = self.file.get();
+        let length = self.done.get();
+        match self.op.replace(Op::Idle) {
+            Op::Idle => {}
+            Op::Open(_) => {
+                self.client
+                    .map(|client| client.open_done(result.map(|()| file)));
+            }
+            Op::Read => {
+                self.data.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.read_done(file, buffer, length, result));
+                });
+            }
+            Op::Write => {
+                self.data.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.write_done(file, buffer, length, result));
+                });
+            }
+            Op::Delete => {
+                self.client.map(|client| client.delete_done(result));
+            }
+            Op::List => {
+                let info = result.and_then(|()| self.info.take().ok_or(ErrorCode::FAIL));
+                self.client.map(|client| client.list_done(info));
+            }
+        }
+    }
+
+    fn step(&self) -> Result<(), Stop> {
+        match self.phase.get() {
+            Phase::Mount => {
+                let scan = self.scan()?;
+                match scan.newest {
+                    Some((page, sequence, tail)) => {
+                        self.head.set((page + 1) % self.pages);
+                        self.sequence.set(sequence.wrapping_add(1));
+                        self.tail.set(tail);
+                    }
+                    None => {
+                        self.head.set(0);
+                        self.sequence.set(1);
+                        self.tail.set(1);
+                    }
+                }
+                self.start_scan(Query::default());
+                self.phase.set(Phase::MountCount);
+            }
+            Phase::MountCount => {
+                let scan = self.scan()?;
+                self.used.set(scan.pages);
+                self.mounted.set(true);
+                self.begin();
+            }
+            Phase::FindFile => {
+                let scan = self.scan()?;
+                let existing = match scan.record {
+                    Some((_, Kind::Create, generation)) => Some(generation),
+                    _ => None,
+                };
+                match (self.op.get(), existing) {
+                    (Op::Delete, Some(generation)) => {
+                        self.phase.set(Phase::WriteRecord(Kind::Delete, generation))
+                    }
+                    (Op::Open(OpenMode::Truncate), Some(generation)) => {
+                        self.phase.set(Phase::WriteRecord(Kind::Create, generation))
+                    }
+                    (Op::Open(_), Some(generation)) => {
+                        self.start_scan(Query {
+                            generation,
+                            ..Query::default()
+                        });
+                        self.phase.set(Phase::FindSize(generation));
+                    }
+                    (Op::Open(OpenMode::Create), None) | (Op::Open(OpenMode::Truncate), None) => {
+                        self.phase.set(Phase::WriteRecord(Kind::Create, 0))
+                    }
+                    _ => return Err(ErrorCode::NOSUPPORT.into()),
+                }
+            }
+            Phase::FindSize(generation) => {
+                let scan = self.scan()?;
+                self.file.set(FlashFile {
+                    generation,
+                    size: scan.size,
+                    position: 0,
+                });
+                self.phase.set(Phase::Done);
+            }
+            Phase::WriteRecord(kind, replaces) => {
+                // Deleting needs less room, so that files can still be
+                // deleted when the device is full.
+                self.make_room(match kind {
+                    Kind::Delete => RESERVE - 1,
+                    _ => RESERVE,
+                })?;
+                let generation = match kind {
+                    Kind::Create => self.sequence.get(),
+                    _ => 0,
+                };
+                self.file.set(FlashFile {
+                    generation,
+                    size: 0,
+                    position: 0,
+                });
+                self.phase.set(Phase::Done);
+                let name = self.name.get();
+                return Err(self.append(
+                    Header {
+                        sequence: 0,
+                        tail: 0,
+                        kind,
+                        name_len: self.name_len.get(),
+                        length: 0,
+                        generation,
+                        aux: replaces,
+                        namespace: self.namespace.get(),
+                    },
+                    |page| page[NAME..HEADER_LEN].copy_from_slice(&name),
+                ));
+            }
+            Phase::Collect => match self.make_room(RESERVE) {
+                Ok(()) => self.find_chunk(),
+                Err(Stop::Error(ErrorCode::NOMEM)) => {
+                    // The device is full, the data written so far is kept.
+                    self.result.set(Err(ErrorCode::NOMEM));
+                    self.phase.set(Phase::Done);
+                }
+                Err(e) => return Err(e),
+            },
+            Phase::FindChunk => {
+                let scan = self.scan()?;
+                if scan.created.is_none() || scan.replaced {
+                    // The file was deleted or truncated since it was opened.
+                    return Err(ErrorCode::NOSUPPORT.into());
+                }
+                let file = self.file.get();
+                let reading = self.op.get() == Op::Read;
+                if self.done.get() == self.length.get() || (reading && file.position >= file.size) {
+                    self.phase.set(Phase::Done);
+                } else {
+                    self.phase.set(Phase::Transfer);
+                }
+            }
+            Phase::Transfer => return self.transfer(),
+            Phase::ListFiles => {
+                let scan = self.scan()?;
+                if let Some((_, page)) = self.candidate.take().filter(|_| !scan.replaced) {
+                    if self.index.get() == 0 {
+                        self.phase.set(Phase::ListName(page, scan.size));
+                        return Ok(());
+                    }
+                    self.index.set(self.index.get() - 1);
+                }
+                match scan.next {
+                    Some((generation, page)) => {
+                        self.candidate.set((generation, page));
+                        self.start_scan(Query {
+                            generation,
+                            after: generation,
+                            ..Query::default()
+                        });
+                    }
+                    None => return Err(ErrorCode::NOSUPPORT.into()),
+                }
+            }
+            Phase::ListName(page, size) => {
+                let info = self.page(page, |page| {
+                    let name_len = page[NAME_LEN] as usize;
+                    FileInfo::new(&page[NAME..NAME + name_len], size)
+                })?;
+                self.info.set(info);
+                self.phase.set(Phase::Done);
+            }
+            Phase::Done => {}
+        }
+        Ok(())
+    }
+
+    /// Find the chunk at the position of the file.
+    fn find_chunk(&self) {
+        let file = self.file.get();
+        self.start_scan(Query {
+            generation: file.generation,
+            chunk: file.position / self.chunk_size() as u32,
+            ..Query::default()
+        });
+        self.phase.set(Phase::FindChunk);
+    }
+
+    /// Copy data between the buffer and the chunk found by the last scan.
+    fn transfer(&self) -> Result<(), Stop> {
+        let scan = self.scan.get();
+        let mut file = self.file.get();
+        let done = self.done.get();
+        let offset = file.position as usize % self.chunk_size();
+        let length = cmp::min(self.chunk_size() - offset, self.length.get() - done);
+
+        let length = if self.op.get() == Op::Write {
+            let (existing, kept) = match scan.chunk {
+                // Data of the chunk that is not overwritten has to be kept.
+                Some((page, _, kept)) if offset > 0 || length < kept => (Some(page), kept),
+                _ => (None, 0),
+            };
+            if let Some(page) = existing {
+                self.page(page, |_| ())?;
+            }
+            let header = Header {
+                sequence: 0,
+                tail: 0,
+                kind: Kind::Data,
+                name_len: 0,
+                length: cmp::max(kept, offset + length),
+                generation: file.generation,
+                aux: self.query.get().chunk,
+                namespace: 0,
+            };
+            let copied = self
+                .data
+                .map_or(Err(Stop::Error(ErrorCode::FAIL)), |data| {
+                    Ok(self.append(header, |page| {
+                        page[NAME..HEADER_LEN].fill(0);
+                        if existing.is_none() {
+                            page[HEADER_LEN..HEADER_LEN + offset].fill(0);
+                        }
+                        page[HEADER_LEN + offset..HEADER_LEN + offset + length]
+                            .copy_from_slice(&data[done..done + length]);
+                    }))
+                })?;
+            if copied != Stop::Busy {
+                return Err(copied);
+            }
+            file.size = cmp::max(file.size, file.position + length as u32);
+            length
+        } else {
+            let (page, available) = match scan.chunk {
+                Some((page, _, available)) if available > offset => (page, available - offset),
+                _ => return Err(ErrorCode::FAIL.into()),
+            };
+            let length = cmp::min(
+                cmp::min(length, available),
+                (file.size - file.position) as usize,
+            );
+            self.data
+                .map_or(Err(Stop::Error(ErrorCode::FAIL)), |data| {
+                    self.page(page, |page| {
+                        data[done..done + length].copy_from_slice(
+                            &page[HEADER_LEN + offset..HEADER_LEN + offset + length],
+                        );
+                    })
+                })?;
+            length
+        };
+
+        file.position += length as u32;
+        self.file.set(file);
+        self.done.set(done + length);
+        let finished = self.done.get() == self.length.get() || file.position >= file.size;
+        match self.op.get() {
+            Op::Write if self.done.get() == self.length.get() => self.phase.set(Phase::Done),
+            Op::Write => self.phase.set(Phase::Collect),
+            _ if finished => self.phase.set(Phase::Done),
+            _ => self.find_chunk(),
+        }
+        if self.op.get() == Op::Write {
+            // Waiting for the page to be written.
+            Err(Stop::Busy)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Drop pages from the tail of the log until `free` pages are free.
+    /// Fails with `NOMEM` if every page is in use.
+    fn make_room(&self, free: usize) -> Result<(), Stop> {
+        loop {
+            let state = match self.collect.get() {
+                None => {
+                    if self.pages - self.used.get() >= free {
+                        return Ok(());
+                    }
+                    Collect::Tail { examined: 0 }
+                }
+                Some(Collect::Tail { examined }) => {
+                    if examined >= self.used.get() {
+                        self.collect.clear();
+                        return Err(ErrorCode::NOMEM.into());
+                    }
+                    let tail = self.tail_page();
+                    match self.page(tail, Header::parse)? {
+                        Some(header) if header.kind != Kind::Delete => {
+                            self.start_scan(Query {
+                                generation: header.generation,
+                                chunk: header.aux,
+                                after: u32::MAX,
+                            });
+                            Collect::Check { examined, header }
+                        }
+                        // Pages that delete a file are not needed once they
+                        // reach the tail, as the pages of the file are older.
+                        header => Collect::Drop {
+                            examined,
+                            sequence: header.map_or(self.tail.get(), |header| header.sequence),
+                        },
+                    }
+                }
+                Some(Collect::Check { examined, header }) => {
+                    let scan = self.scan()?;
+                    // A page is in use if it is the newest copy of its chunk
+                    // or record.
+                    let newest = match header.kind {
+                        Kind::Data => scan.chunk.map(|(page, _, _)| page),
+                        _ => scan.created.map(|(page, _)| page),
+                    };
+                    if scan.created.is_some() && !scan.replaced && newest == Some(self.tail_page())
+                    {
+                        if self.used.get() == self.pages {
+                            // The page can not be copied without overwriting
+                            // it.
+                            self.collect.clear();
+                            return Err(ErrorCode::NOMEM.into());
+                        }
+                        Collect::Copy { examined, header }
+                    } else {
+                        Collect::Drop {
+                            examined,
+                            sequence: header.sequence,
+                        }
+                    }
+                }
+                Some(Collect::Copy { examined, header }) => {
+                    self.page(self.tail_page(), |_| ())?;
+                    self.collect.set(Collect::Drop {
+                        examined,
+                        sequence: header.sequence,
+                    });
+                    return Err(self.append(header, |_| ()));
+                }
+                Some(Collect::Drop { examined, sequence }) => {
+                    self.used.set(self.used.get() - 1);
+                    self.tail
+                        .set(cmp::max(self.tail.get(), sequence.wrapping_add(1)));
+                    Collect::Tail {
+                        examined: examined + 1,
+                    }
+                }
+            };
+            if let Collect::Tail { .. } = state {
+                if self.pages - self.used.get() >= free {
+                    self.collect.clear();
+                    return Ok(());
+                }
+            }
+            self.collect.set(state);
+        }
+    }
+
+    /// Page at the tail of the log.
+    fn tail_page(&self) -> usize {
+        (self.head.get() + self.pages - self.used.get()) % self.pages
+    }
+
+    fn start_scan(&self, query: Query) {
+        self.query.set(query);
+        self.scan.set(Scan::default());
+    }
+
+    /// Read every page, and return what was found.
+    fn scan(&self) -> Result<Scan, Stop> {
+        loop {
+            let mut scan = self.scan.get();
+            if scan.page == self.pages {
+                return Ok(scan);
+            }
+            self.page(scan.page, |page| self.visit(page, &mut scan))?;
+            scan.page += 1;
+            self.scan.set(scan);
+        }
+    }
+
+    /// Add what the page `scan.page` holds to the scan.
+    fn visit(&self, page: &[u8], scan: &mut Scan) {
+        let header = match Header::parse(page) {
+            Some(header) if header.sequence >= self.tail.get() => header,
+            _ => return,
+        };
+        let query = self.query.get();
+        scan.pages += 1;
+        if scan
+            .newest
+            .is_none_or(|(_, sequence, _)| header.sequence > sequence)
+        {
+            scan.newest = Some((scan.page, header.sequence, header.tail));
+        }
+
+        if header.kind == Kind::Data {
+            if header.generation == query.generation {
+                let end = header.aux as u64 * self.chunk_size() as u64 + header.length as u64;
+                scan.size = cmp::max(scan.size, cmp::min(end, u32::MAX as u64) as u32);
+                if header.aux == query.chunk
+                    && scan
+                        .chunk
+                        .is_none_or(|(_, sequence, _)| header.sequence > sequence)
+                {
+                    scan.chunk = Some((scan.page, header.sequence, header.length));
+                }
+            }
+            return;
+        }
+
+        if header.kind == Kind::Create
+            && header.generation == query.generation
+            && scan
+                .created
+                .is_none_or(|(_, sequence)| header.sequence > sequence)
+        {
+            scan.created = Some((scan.page, header.sequence));
+        }
+        if header.aux != 0 && header.aux == query.generation {
+            scan.replaced = true;
+        }
+        if header.namespace != self.namespace.get() {
+            return;
+        }
+        let name = self.name.get();
+        if page[NAME..NAME + header.name_len] == name[..self.name_len.get()]
+            && scan
+                .record
+                .is_none_or(|(sequence, _, _)| header.sequence > sequence)
+        {
+            scan.record = Some((header.sequence, header.kind, header.generation));
+        }
+        if header.kind == Kind::Create
+            && header.generation > query.after
+            && scan
+                .next
+                .is_none_or(|(generation, _)| header.generation < generation)
+        {
+            scan.next = Some((header.generation, scan.page));
+        }
+    }
+
+    /// Run `f` on `page`, loading it first if it is not cached.
+    fn page<R>(&self, page: usize, f: impl FnOnce(&[u8]) -> R) -> Result<R, Stop> {
+        if !self.cached.contains(&page) {
+            return Err(self.load(page));
+        }
+        self.buffer
+            .map(|buffer| f(buffer.as_mut()))
+            .ok_or(Stop::Error(ErrorCode::FAIL))
+    }
+
+    fn load(&self, page: usize) -> Stop {
+        self.cached.clear();
+        match self.buffer.take() {
+            None => Stop::Error(ErrorCode::FAIL),
+            Some(buffer) => match self.flash.read_page(self.first_page + page, buffer) {
+                Ok(()) => {
+                    self.loading.set(page);
+                    Stop::Busy
+                }
+                Err((e, buffer)) => {
+                    self.buffer.replace(buffer);
+                    Stop::Error(e)
+                }
+            },
+        }
+    }
+
+    /// Write a page at the head of the log with `header`, after `fill` has
+    /// put the name or data in the buffer. The sequence number and tail of
+    /// the header are set here.
+    fn append(&self, mut header: Header, fill: impl FnOnce(&mut [u8])) -> Stop {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return Stop::Error(ErrorCode::FAIL),
+        };
+        header.sequence = self.sequence.get();
+        header.tail = self.tail.get();
+        let page = buffer.as_mut();
+        fill(page);
+        header.write(page);
+
+        let head = self.head.get();
+        self.cached.clear();
+        match self.flash.write_page(self.first_page + head, buffer) {
+            Ok(()) => {
+                self.loading.set(head);
+                self.head.set((head + 1) % self.pages);
+                self.used.set(self.used.get() + 1);
+                self.sequence.set(self.sequence.get().wrapping_add(1));
+                Stop::Busy
+            }
+            Err((e, buffer)) => {
+                self.buffer.replace(buffer);
+                Stop::Error(e)
+            }
+        }
+    }
+
+    fn check_ready(&self) -> Result<(), ErrorCode> {
+        if self.pages <= RESERVE || self.page_size <= HEADER_LEN {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        if self.op.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        Ok(())
+    }
+
+    /// Start reading or writing `file`.
+    fn start_transfer(
+        &self,
+        op: Op,
+        file: FlashFile,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if let Err(e) = self.check_ready() {
+            return Err((e, buffer));
+        }
+        if length > buffer.len() {
+            return Err((ErrorCode::INVAL, buffer));
+        }
+        // Files are limited to 4 GiB.
+        if op == Op::Write && file.position as u64 + length as u64 > u32::MAX as u64 {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        self.file.set(file);
+        self.data.replace(buffer);
+        self.length.set(length);
+        self.done.set(0);
+        self.start(op)
+            .map_err(|e| (e, self.data.take().unwrap_or_default()))
+    }
+
+    /// A flash operation failed. The log is read again by the next operation,
+    /// as the page written may or may not have been stored.
+    fn flash_error(&self) {
+        self.mounted.set(false);
+        self.complete(Err(ErrorCode::FAIL));
+    }
+}
+
+impl<'a, F: Flash> FileSystem<'a> for FlashFs<'a, F> {
+    type File = FlashFile;
+
+    fn set_client(&self, client: &'a dyn FileSystemClient<FlashFile>) {
+        self.client.set(client);
+    }
+
+    fn open(&self, namespace: u32, name: &[u8], mode: OpenMode) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        self.set_name(name)?;
+        self.namespace.set(namespace);
+        self.start(Op::Open(mode))
+    }
+
+    fn read(
+        &self,
+        file: FlashFile,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_transfer(Op::Read, file, buffer, length)
+    }
+
+    fn write(
+        &self,
+        file: FlashFile,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start_transfer(Op::Write, file, buffer, length)
+    }
+
+    fn delete(&self, namespace: u32, name: &[u8]) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        self.set_name(name)?;
+        self.namespace.set(namespace);
+        self.start(Op::Delete)
+    }
+
+    fn list(&self, namespace: u32, index: usize) -> Result<(), ErrorCode> {
+        self.check_ready()?;
+        self.name_len.set(0);
+        self.namespace.set(namespace);
+        self.index.set(index);
+        self.start(Op::List)
+    }
+
+    fn seek(&self, file: FlashFile, position: u32) -> Result<FlashFile, ErrorCode> {
+        if position > file.size {
+            return Err(ErrorCode::INVAL);
+        }
+        Ok(FlashFile { position, ..file })
+    }
+
+    fn position(&self, file: &FlashFile) -> u32 {
+        file.position
+    }
+
+    fn size(&self, file: &FlashFile) -> u32 {
+        file.size
+    }
+}
+
+impl<F: Flash> flash::Client<F> for FlashFs<'_, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, result: Result<(), flash::Error>) {
+        self.buffer.replace(buffer);
+        match result {
+            Ok(()) => {
+                self.cached.set(self.loading.get());
+                self.run();
+            }
+            Err(_) => self.flash_error(),
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, result: Result<(), flash::Error>) {
+        self.buffer.replace(buffer);
+        match result {
+            Ok(()) => {
+                self.cached.set(self.loading.get());
+                self.run();
+            }
+            Err(_) => self.flash_error(),
+        }
+    }
+
+    fn erase_complete(&self, _result: Result<(), flash::Error>) {}
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    use kernel::hil::filesystem::{FileInfo, FileSystem, FileSystemClient, OpenMode};
+    use kernel::hil::flash::HasClient;
+    use kernel::utilities::cells::TakeCell;
+    use kernel::ErrorCode;
+
+    use super::{FlashFile, FlashFs};
+    use crate::test::sim_flash::{SimFlash, SimPage};
+
+    /// Pages of 128 bytes hold 64 bytes of data.
+    const PAGE: usize = 128;
+    const CHUNK: usize = PAGE - super::HEADER_LEN;
+
+    type Flash<const PAGES: usize> = SimFlash<PAGE, PAGES>;
+    type TestFs<const PAGES: usize> = FlashFs<'static, Flash<PAGES>>;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Event {
+        Open(Result<FlashFile, ErrorCode>),
+        Read(FlashFile, usize, Result<(), ErrorCode>),
+        Write(FlashFile, usize, Result<(), ErrorCode>),
+        Delete(Result<(), ErrorCode>),
+        List(Result<FileInfo, ErrorCode>),
+    }
+
+    struct Client {
+        buffer: TakeCell<'static, [u8]>,
+        event: Cell<Option<Event>>,
+    }
+
+    impl FileSystemClient<FlashFile> for Client {
+        fn open_done(&self, result: Result<FlashFile, ErrorCode>) {
+            self.event.set(Some(Event::Open(result)));
+        }
+
+        fn read_done(
+            &self,
+            file: FlashFile,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.buffer.replace(buffer);
+            self.event.set(Some(Event::Read(file, length, result)));
+        }
+
+        fn write_done(
+            &self,
+            file: FlashFile,
+            buffer: &'static mut [u8],
+            length: usize,
+            result: Result<(), ErrorCode>,
+        ) {
+            self.buffer.replace(buffer);
+            self.event.set(Some(Event::Write(file, length, result)));
+        }
+
+        fn delete_done(&self, result: Result<(), ErrorCode>) {
+            self.event.set(Some(Event::Delete(result)));
+        }
+
+        fn list_done(&self, result: Result<FileInfo, ErrorCode>) {
+            self.event.set(Some(Event::List(result)));
+        }
+    }
+
+    /// A file system mounted on a flash, with synchronous operations. They
+    /// return `None` if the power was lost before they completed.
+    struct Test<const PAGES: usize> {
+        flash: &'static Flash<PAGES>,
+        fs: &'static TestFs<PAGES>,
+        client: &'static Client,
+    }
+
+    impl<const PAGES: usize> Test<PAGES> {
+        /// Mount the file system stored on the flash, like a board does after
+        /// a reboot.
+        fn mount(flash: &'static Flash<PAGES>) -> Self {
+            let pagebuffer = Box::leak(Box::new(SimPage::default()));
+            let fs = Box::leak(Box::new(FlashFs::new(flash, pagebuffer, 0, PAGES)));
+            let client = Box::leak(Box::new(Client {
+                buffer: TakeCell::new(Box::leak(Box::new([0; 4 * PAGE]))),
+                event: Cell::new(None),
+            }));
+            flash.set_client(fs);
+            fs.set_client(client);
+            Test { flash, fs, client }
+        }
+
+        /// Complete the flash operations of a started operation.
+        fn complete(&self, started: Result<(), ErrorCode>) -> Option<Event> {
+            if let Err(e) = started {
+                assert!(!self.flash.is_powered(), "failed to start: {:?}", e);
+                return None;
+            }
+            while self.flash.service() {}
+            let event = self.client.event.take();
+            assert_eq!(event.is_some(), self.flash.is_powered());
+            event
+        }
+
+        fn open(
+            &self,
+            namespace: u32,
+            name: &str,
+            mode: OpenMode,
+        ) -> Option<Result<FlashFile, ErrorCode>> {
+            match self.complete(self.fs.open(namespace, name.as_bytes(), mode))? {
+                Event::Open(result) => Some(result),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn write(
+            &self,
+            file: FlashFile,
+            data: &[u8],
+        ) -> Option<(FlashFile, usize, Result<(), ErrorCode>)> {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            let started = self
+                .fs
+                .write(file, buffer, data.len())
+                .map_err(|(e, buffer)| {
+                    self.client.buffer.replace(buffer);
+                    e
+                });
+            match self.complete(started)? {
+                Event::Write(file, length, result) => Some((file, length, result)),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn read(&self, file: FlashFile, length: usize) -> Option<(FlashFile, Vec<u8>)> {
+            let buffer = self.client.buffer.take().unwrap();
+            let started = self.fs.read(file, buffer, length).map_err(|(e, buffer)| {
+                self.client.buffer.replace(buffer);
+                e
+            });
+            match self.complete(started)? {
+                Event::Read(file, length, result) => {
+                    assert_eq!(result, Ok(()));
+                    let data = self.client.buffer.map(|buffer| buffer[..length].to_vec());
+                    Some((file, data.unwrap()))
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        fn delete(&self, namespace: u32, name: &str) -> Option<Result<(), ErrorCode>> {
+            match self.complete(self.fs.delete(namespace, name.as_bytes()))? {
+                Event::Delete(result) => Some(result),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        /// The names and sizes of the files in the namespace.
+        fn list(&self, namespace: u32) -> Vec<(Vec<u8>, u32)> {
+            let mut files = Vec::new();
+            loop {
+                match self.complete(self.fs.list(namespace, files.len())) {
+                    Some(Event::List(Ok(info))) => files.push((info.name().to_vec(), info.size)),
+                    Some(Event::List(Err(ErrorCode::NOSUPPORT))) => return files,
+                    event => panic!("unexpected {:?}", event),
+                }
+            }
+        }
+
+        /// Write `data` at `position` of the existing file.
+        fn write_at(&self, namespace: u32, name: &str, position: u32, data: &[u8]) -> Option<()> {
+            let file = self.open(namespace, name, OpenMode::Existing)?.unwrap();
+            let file = self.fs.seek(file, position).unwrap();
+            let (_, length, result) = self.write(file, data)?;
+            assert_eq!((length, result), (data.len(), Ok(())));
+            Some(())
+        }
+
+        /// The contents of every file in the namespaces.
+        fn contents(&self, namespaces: &[u32]) -> BTreeMap<(u32, Vec<u8>), Vec<u8>> {
+            let mut contents = BTreeMap::new();
+            for &namespace in namespaces {
+                for (name, size) in self.list(namespace) {
+                    let name_str = core::str::from_utf8(&name).unwrap();
+                    let file = self
+                        .open(namespace, name_str, OpenMode::Existing)
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(self.fs.size(&file), size);
+                    let (_, data) = self.read(file, size as usize).unwrap();
+                    contents.insert((namespace, name), data);
+                }
+            }
+            contents
+        }
+    }
+
+    fn pattern(seed: usize, length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 + seed * 13) as u8).collect()
+    }
+
+    #[test]
+    fn test_files() {
+        let flash = Box::leak(Box::new(Flash::<16>::new()));
+        let test = Test::mount(flash);
+
+        assert_eq!(
+            test.open(1, "log.txt", OpenMode::Existing),
+            Some(Err(ErrorCode::NOSUPPORT))
+        );
+        let file = test.open(1, "log.txt", OpenMode::Create).unwrap().unwrap();
+        assert_eq!(test.fs.size(&file), 0);
+
+        // Spans several chunks, starting and ending within one.
+        let mut expected = pattern(1, 200);
+        let (file, length, result) = test.write(file, &expected).unwrap();
+        assert_eq!((length, result), (200, Ok(())));
+        assert_eq!((test.fs.position(&file), test.fs.size(&file)), (200, 200));
+
+        let file = test.fs.seek(file, 50).unwrap();
+        let (file, data) = test.read(file, 100).unwrap();
+        assert_eq!(data, expected[50..150]);
+        assert_eq!(test.fs.position(&file), 150);
+        let (_, data) = test.read(file, 100).unwrap();
+        assert_eq!(data, expected[150..]);
+
+        // Overwrite across a chunk boundary and extend the file.
+        let file = test.fs.seek(file, 60).unwrap();
+        let (_, _, result) = test.write(file, &pattern(2, 10)).unwrap();
+        assert_eq!(result, Ok(()));
+        expected[60..70].copy_from_slice(&pattern(2, 10));
+        test.write_at(1, "log.txt", 200, &pattern(3, 30)).unwrap();
+        expected.extend_from_slice(&pattern(3, 30));
+        assert_eq!(test.fs.seek(file, 231), Err(ErrorCode::INVAL));
+
+        // Namespaces are separate.
+        assert_eq!(
+            test.open(2, "log.txt", OpenMode::Existing),
+            Some(Err(ErrorCode::NOSUPPORT))
+        );
+        let other = test.open(2, "log.txt", OpenMode::Create).unwrap().unwrap();
+        assert_eq!(test.write(other, b"other").unwrap().2, Ok(()));
+        let empty = test.open(1, "empty", OpenMode::Create).unwrap().unwrap();
+        assert_eq!(test.fs.size(&empty), 0);
+        assert_eq!(
+            test.fs.open(1, b"", OpenMode::Create),
+            Err(ErrorCode::INVAL)
+        );
+
+        let mut files = test.list(1);
+        files.sort();
+        assert_eq!(files, [(b"empty".to_vec(), 0), (b"log.txt".to_vec(), 230)]);
+        assert_eq!(test.list(2), [(b"log.txt".to_vec(), 5)]);
+        assert_eq!(test.list(3), []);
+
+        // The files are stored on the flash.
+        let test = Test::mount(flash);
+        let file = test.open(1, "log.txt", OpenMode::Create).unwrap().unwrap();
+        assert_eq!(test.fs.size(&file), 230);
+        assert_eq!(test.read(file, 4 * PAGE).unwrap().1, expected);
+
+        // Truncating and deleting.
+        let file = test
+            .open(1, "log.txt", OpenMode::Truncate)
+            .unwrap()
+            .unwrap();
+        assert_eq!(test.fs.size(&file), 0);
+        assert_eq!(test.read(file, 10).unwrap().1, []);
+        assert_eq!(test.delete(2, "log.txt"), Some(Ok(())));
+        assert_eq!(test.delete(2, "log.txt"), Some(Err(ErrorCode::NOSUPPORT)));
+        // Handles of deleted files can not be used.
+        assert_eq!(
+            test.write(other, b"x").unwrap().2,
+            Err(ErrorCode::NOSUPPORT)
+        );
+
+        let test = Test::mount(flash);
+        assert_eq!(test.list(1).len(), 2);
+        assert_eq!(test.list(2), []);
+        assert_eq!(test.contents(&[1])[&(1, b"log.txt".to_vec())], []);
+        assert_eq!(flash.violations(), 0);
+    }
+
+    #[test]
+    fn test_wear_leveling_and_full() {
+        let flash = Box::leak(Box::new(Flash::<12>::new()));
+        let test = Test::mount(flash);
+
+        // A file that never changes is moved around as the log wraps.
+        let fixed = test.open(1, "fixed", OpenMode::Create).unwrap().unwrap();
+        assert_eq!(
+            test.write(fixed, &pattern(0, CHUNK + 10)).unwrap().2,
+            Ok(())
+        );
+        let counter = test.open(1, "counter", OpenMode::Create).unwrap().unwrap();
+        for i in 0..100 {
+            let (_, _, result) = test.write(counter, &pattern(i, 8)).unwrap();
+            assert_eq!(result, Ok(()));
+        }
+        // Every page has been written many times.
+        assert!(test.fs.sequence.get() > 100);
+        let test = Test::mount(flash);
+        let contents = test.contents(&[1]);
+        assert_eq!(contents[&(1, b"fixed".to_vec())], pattern(0, CHUNK + 10));
+        assert_eq!(contents[&(1, b"counter".to_vec())], pattern(99, 8));
+
+        // Fill the flash.
+        let mut file = test.open(2, "big", OpenMode::Create).unwrap().unwrap();
+        let mut written = 0;
+        loop {
+            let (next, length, result) = test.write(file, &pattern(written, CHUNK)).unwrap();
+            written += length;
+            file = next;
+            if result.is_err() {
+                assert_eq!(result, Err(ErrorCode::NOMEM));
+                break;
+            }
+        }
+        assert!(written > 0);
+        assert_eq!(
+            test.open(2, "more", OpenMode::Create),
+            Some(Err(ErrorCode::NOMEM))
+        );
+        // Deleting a file makes room again.
+        assert_eq!(test.delete(1, "fixed"), Some(Ok(())));
+        assert!(test.open(2, "more", OpenMode::Create).unwrap().is_ok());
+        assert_eq!(
+            test.fs
+                .size(&test.open(2, "big", OpenMode::Existing).unwrap().unwrap()),
+            written as u32
+        );
+    }
+
+    /// Operations of the crash test, each of which changes at most one
+    /// chunk.
+    #[derive(Clone, Copy, Debug)]
+    enum Op {
+        Create(u32, &'static str),
+        Truncate(u32, &'static str),
+        Write(u32, &'static str, u32, usize),
+        Delete(u32, &'static str),
+    }
+
+    const SCENARIO: [Op; 16] = [
+        Op::Create(1, "a"),
+        Op::Write(1, "a", 0, 40),
+        Op::Write(1, "a", 40, 24),
+        Op::Write(1, "a", 64, 50),
+        Op::Create(1, "b"),
+        Op::Write(1, "b", 0, 64),
+        Op::Write(1, "a", 10, 20),
+        Op::Create(2, "a"),
+        Op::Write(2, "a", 0, 60),
+        Op::Truncate(1, "b"),
+        Op::Write(1, "b", 0, 30),
+        Op::Delete(1, "a"),
+        Op::Write(2, "a", 20, 30),
+        Op::Write(1, "b", 30, 34),
+        Op::Write(1, "b", 64, 20),
+        Op::Write(2, "a", 0, 5),
+    ];
+
+    type Contents = BTreeMap<(u32, Vec<u8>), Vec<u8>>;
+
+    fn apply(contents: &mut Contents, index: usize, op: Op) {
+        match op {
+            Op::Create(namespace, name) => {
+                contents.entry((namespace, name.into())).or_default();
+            }
+            Op::Truncate(namespace, name) => {
+                contents.insert((namespace, name.into()), Vec::new());
+            }
+            Op::Write(namespace, name, position, length) => {
+                let data = contents.get_mut(&(namespace, name.into())).unwrap();
+                let end = position as usize + length;
+                data.resize(data.len().max(end), 0);
+                data[position as usize..end].copy_from_slice(&pattern(index, length));
+            }
+            Op::Delete(namespace, name) => {
+                contents.remove(&(namespace, name.into()));
+            }
+        }
+    }
+
+    /// Run the scenario until the power is lost. Returns the contents
+    /// before and after the operation that was interrupted.
+    fn run<const PAGES: usize>(flash: &'static Flash<PAGES>) -> (Contents, Contents) {
+        let test = Test::mount(flash);
+        let mut contents = Contents::new();
+        for (index, op) in SCENARIO.iter().enumerate() {
+            let before = contents.clone();
+            apply(&mut contents, index, *op);
+            let done = match *op {
+                Op::Create(namespace, name) => test
+                    .open(namespace, name, OpenMode::Create)
+                    .map(|result| assert!(result.is_ok())),
+                Op::Truncate(namespace, name) => test
+                    .open(namespace, name, OpenMode::Truncate)
+                    .map(|result| assert!(result.is_ok())),
+                Op::Write(namespace, name, position, length) => {
+                    test.write_at(namespace, name, position, &pattern(index, length))
+                }
+                Op::Delete(namespace, name) => test
+                    .delete(namespace, name)
+                    .map(|result| assert_eq!(result, Ok(()))),
+            };
+            if done.is_none() {
+                return (before, contents);
+            }
+        }
+        assert!(flash.is_powered());
+        (contents.clone(), contents)
+    }
+
+    #[test]
+    fn test_recovers_after_every_crash_point() {
+        const PAGES: usize = 16;
+        let steps = {
+            let flash = Box::leak(Box::new(Flash::<PAGES>::new()));
+            run(flash);
+            flash.steps()
+        };
+
+        for crash_point in 0..steps {
+            let flash = Box::leak(Box::new(Flash::<PAGES>::new()));
+            flash.lose_power_after(crash_point);
+            let (before, after) = run(flash);
+            assert!(!flash.is_powered());
+            flash.power_cycle();
+
+            let test = Test::mount(flash);
+            let contents = test.contents(&[1, 2]);
+            assert!(
+                contents == before || contents == after,
+                "crash point {}: {:?}",
+                crash_point,
+                contents
+            );
+
+            // The file system can still be written.
+            let file = test.open(3, "new", OpenMode::Create).unwrap().unwrap();
+            let (_, _, result) = test.write(file, &pattern(100, 70)).unwrap();
+            assert_eq!(result, Ok(()));
+            let test = Test::mount(flash);
+            let mut expected = contents;
+            expected.insert((3, b"new".to_vec()), pattern(100, 70));
+            assert_eq!(test.contents(&[1, 2, 3]), expected);
+            assert_eq!(flash.violations(), 0);
+        }
+    }
+}
@@ -0,0 +1,1407 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Software CSMA-CA MAC layer with frame retransmission and indirect
+//! transmission for IEEE 802.15.4 radios.
+//!
+//! `AwakeMac` and `XMac` rely on the radio hardware for channel access and
+//! for retransmitting frames that were not acknowledged. This layer
+//! implements both in software on top of any `kernel::hil::radio::Radio`, so
+//! that the behavior is the same across radios:
+//!
+//! - Unslotted CSMA-CA (IEEE 802.15.4-2015 section 6.2.5.1). Before every
+//!   transmission attempt the layer waits a random number of unit backoff
+//!   periods in `[0, 2^BE - 1]`. If the radio reports that the channel is
+//!   busy (`ErrorCode::BUSY`), the backoff exponent is increased and the
+//!   attempt is repeated, up to `max_csma_backoffs` times.
+//! - Retransmission. If a frame requests an acknowledgement and the radio
+//!   does not report one, the layer listens for an ACK frame with a
+//!   matching sequence number for `ack_wait_us`, and otherwise retransmits
+//!   the frame up to `max_frame_retries` times before returning
+//!   `ErrorCode::NOACK`.
+//! - Indirect transmission (section 6.7.3). Frames addressed to a sleepy
+//!   child registered with `add_sleepy_child()` are copied into an internal
+//!   queue instead of being transmitted, and are sent when the child polls
+//!   with a Data Request command. The frame pending bit of a queued frame is
+//!   set if more frames are waiting for the same child. The client buffer
+//!   is held until the frame has been delivered, and `send_done` reports
+//!   the outcome: `Ok(())` once the child received the frame, or
+//!   `ErrorCode::NOACK` if it was not requested within
+//!   `transaction_persistence_ms`.
+//! - Data polling for sleepy end devices. With `rx_on_when_idle` disabled,
+//!   the radio is only powered while transmitting or while waiting for data
+//!   after a `poll()` of the parent. If a received frame has the frame
+//!   pending bit set, the parent is polled again.
+//!
+//! Note that radios that acknowledge frames in hardware decide the value of
+//! the frame pending bit in those acknowledgements themselves. Since this
+//! layer cannot observe such acknowledgements, a polling device always
+//! waits `poll_wait_ms` for data unless it received an acknowledgement with
+//! the frame pending bit cleared.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//!
+//! type CsmaDevice = capsules_extra::ieee802154::csma::CsmaMac<'static, RadioDevice, Alarm>;
+//!
+//! static mut INDIRECT_BUFS: [[u8; radio::MAX_BUF_SIZE]; csma::INDIRECT_QUEUE_LEN] =
+//!     [[0; radio::MAX_BUF_SIZE]; csma::INDIRECT_QUEUE_LEN];
+//! static mut POLL_BUF: [u8; radio::MAX_BUF_SIZE] = [0; radio::MAX_BUF_SIZE];
+//!
+//! let csma: &CsmaDevice = static_init!(
+//!     CsmaDevice,
+//!     csma::CsmaMac::new(radio, alarm, rng, &mut INDIRECT_BUFS, &mut POLL_BUF)
+//! );
+//! kernel::deferred_call::DeferredCallClient::register(csma);
+//! rng.set_client(csma);
+//! alarm.set_alarm_client(csma);
+//! radio.set_transmit_client(csma);
+//! radio.set_receive_client(csma);
+//! radio.set_power_client(csma);
+//!
+//! // The layer can now be used like any other `Mac` implementation, e.g. as
+//! // the backend of a `Framer`.
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{FrameType, FrameVersion, Header, MacAddress, PanID};
+use core::cell::Cell;
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::hil::radio::{self, MAX_FRAME_SIZE, PSDU_OFFSET};
+use kernel::hil::rng::{self, Rng};
+use kernel::hil::time::{self, Alarm, ConvertTicks, Ticks};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Number of frames that can be queued for indirect transmission.
+pub const INDIRECT_QUEUE_LEN: usize = 4;
+/// Number of sleepy children that frames can be queued for.
+pub const MAX_SLEEPY_CHILDREN: usize = 8;
+
+/// Duration of a unit backoff period (20 symbols at 62.5 ksymbol/s).
+const UNIT_BACKOFF_PERIOD_US: u32 = 320;
+/// MAC command identifier of a Data Request.
+const DATA_REQUEST_COMMAND: u8 = 0x04;
+/// Frame pending bit in the first octet of the frame control field.
+const FRAME_PENDING_BIT: u8 = 1 << 4;
+
+/// Parameters of the CSMA-CA and retransmission algorithms.
+///
+/// The defaults are those of IEEE 802.15.4-2015 for the O-QPSK PHY.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CsmaConfig {
+    /// Initial backoff exponent (macMinBe).
+    pub min_be: u8,
+    /// Maximum backoff exponent (macMaxBe).
+    pub max_be: u8,
+    /// Number of times the channel may be found busy before a transmission
+    /// fails with `ErrorCode::BUSY` (macMaxCsmaBackoffs).
+    pub max_csma_backoffs: u8,
+    /// Number of retransmissions of an unacknowledged frame
+    /// (macMaxFrameRetries).
+    pub max_frame_retries: u8,
+    /// Time to wait for an acknowledgement (macAckWaitDuration).
+    pub ack_wait_us: u32,
+    /// Whether the radio is kept on while there is nothing to transmit.
+    /// Sleepy end devices set this to `false` and receive data by polling.
+    pub rx_on_when_idle: bool,
+    /// Time to wait for data after a poll has been acknowledged
+    /// (macMaxFrameTotalWaitTime).
+    pub poll_wait_ms: u32,
+    /// Time a frame for a sleepy child is kept in the indirect queue
+    /// (macTransactionPersistenceTime).
+    pub transaction_persistence_ms: u32,
+}
+
+impl Default for CsmaConfig {
+    fn default() -> Self {
+        CsmaConfig {
+            min_be: 3,
+            max_be: 5,
+            max_csma_backoffs: 4,
+            max_frame_retries: 3,
+            ack_wait_us: 864,
+            rx_on_when_idle: true,
+            poll_wait_ms: 20,
+            transaction_persistence_ms: 7680,
+        }
+    }
+}
+
+/// Client for the result of `CsmaMac::poll()`.
+pub trait PollClient {
+    /// The Data Request was sent and the data the parent had pending, if
+    /// any, has been passed to the receive client. On `Err()`, the parent
+    /// could not be reached:
+    ///
+    /// - `ErrorCode::NOACK`: The Data Request was not acknowledged.
+    /// - `ErrorCode::BUSY`: The channel was never clear.
+    /// - `ErrorCode::FAIL`: Internal error occurred.
+    fn poll_done(&self, result: Result<(), ErrorCode>);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum CsmaState {
+    Idle,
+    // Waiting for the radio to power on before the first backoff.
+    Startup,
+    // Waiting for randomness or for the backoff period to end.
+    Backoff,
+    // Radio is transmitting.
+    Transmit,
+    // Transmission done, listening for an acknowledgement.
+    AckWait,
+}
+
+/// Origin of the frame currently owned by the CSMA engine.
+#[derive(Copy, Clone, PartialEq)]
+enum TxSource {
+    Client,
+    Indirect(usize),
+    Poll,
+}
+
+/// What to do with a received frame, decided before the buffer is handed on.
+enum RxAction {
+    Ack {
+        frame_pending: bool,
+    },
+    DataRequest(MacAddress),
+    Deliver {
+        src_addr: Option<MacAddress>,
+        frame_pending: bool,
+    },
+    Drop,
+}
+
+struct IndirectFrame<T: Ticks> {
+    buf: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+    // `Some` while the slot holds a frame.
+    dst: Cell<Option<MacAddress>>,
+    queued_at: Cell<T>,
+    requested: Cell<bool>,
+    // Client buffer of the frame, held until the frame is delivered or
+    // dropped.
+    client_buf: TakeCell<'static, [u8]>,
+    // (acked, result) to report to the client from a deferred call.
+    done: Cell<Option<(bool, Result<(), ErrorCode>)>>,
+}
+
+pub struct CsmaMac<'a, R: radio::Radio<'a>, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    rng: &'a dyn Rng<'a>,
+    tx_client: OptionalCell<&'a dyn radio::TxClient>,
+    rx_client: OptionalCell<&'a dyn radio::RxClient>,
+    poll_client: OptionalCell<&'a dyn PollClient>,
+    config: Cell<CsmaConfig>,
+    state: Cell<CsmaState>,
+
+    // Frame currently being sent by the CSMA engine, at `PSDU_OFFSET`.
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_source: Cell<TxSource>,
+    tx_seq: Cell<Option<u8>>,
+    tx_ack_requested: Cell<bool>,
+    backoffs: Cell<u8>,
+    backoff_exponent: Cell<u8>,
+    retries: Cell<u8>,
+    // (reference, dt) of the backoff or acknowledgement timeout.
+    engine_timer: OptionalCell<(A::Ticks, A::Ticks)>,
+
+    // Client frame waiting for the engine to become idle.
+    client_buf: TakeCell<'static, [u8]>,
+    client_len: Cell<usize>,
+
+    // Returns the client buffers of completed indirect frames.
+    deferred_call: DeferredCall,
+
+    children: [Cell<Option<MacAddress>>; MAX_SLEEPY_CHILDREN],
+    indirect: [IndirectFrame<A::Ticks>; INDIRECT_QUEUE_LEN],
+
+    poll_buf: TakeCell<'static, [u8]>,
+    poll_len: Cell<usize>,
+    poll_pending: Cell<bool>,
+    poll_seq: Cell<u8>,
+    // `Some` while a poll of this parent is in progress.
+    poll_parent: Cell<Option<MacAddress>>,
+    // (reference, dt) of the wait for data after an acknowledged poll.
+    poll_wait: OptionalCell<(A::Ticks, A::Ticks)>,
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> CsmaMac<'a, R, A> {
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        rng: &'a dyn Rng<'a>,
+        indirect_bufs: &'static mut [[u8; radio::MAX_BUF_SIZE]; INDIRECT_QUEUE_LEN],
+        poll_buf: &'static mut [u8],
+    ) -> CsmaMac<'a, R, A> {
+        let mut bufs = indirect_bufs.iter_mut();
+        CsmaMac {
+            radio,
+            alarm,
+            rng,
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            poll_client: OptionalCell::empty(),
+            config: Cell::new(CsmaConfig::default()),
+            state: Cell::new(CsmaState::Idle),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_source: Cell::new(TxSource::Client),
+            tx_seq: Cell::new(None),
+            tx_ack_requested: Cell::new(false),
+            backoffs: Cell::new(0),
+            backoff_exponent: Cell::new(0),
+            retries: Cell::new(0),
+            engine_timer: OptionalCell::empty(),
+            client_buf: TakeCell::empty(),
+            client_len: Cell::new(0),
+            deferred_call: DeferredCall::new(),
+            children: Default::default(),
+            indirect: core::array::from_fn(|_| IndirectFrame {
+                buf: bufs.next().map_or(TakeCell::empty(), |b| TakeCell::new(b)),
+                len: Cell::new(0),
+                dst: Cell::new(None),
+                queued_at: Cell::new(A::Ticks::from(0)),
+                requested: Cell::new(false),
+                client_buf: TakeCell::empty(),
+                done: Cell::new(None),
+            }),
+            poll_buf: TakeCell::new(poll_buf),
+            poll_len: Cell::new(0),
+            poll_pending: Cell::new(false),
+            poll_seq: Cell::new(0),
+            poll_parent: Cell::new(None),
+            poll_wait: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_poll_client(&self, client: &'a dyn PollClient) {
+        self.poll_client.set(client);
+    }
+
+    pub fn get_config(&self) -> CsmaConfig {
+        self.config.get()
+    }
+
+    /// Changes the CSMA-CA and retransmission parameters. Takes effect with
+    /// the next transmitted frame.
+    pub fn set_config(&self, config: CsmaConfig) -> Result<(), ErrorCode> {
+        if config.min_be > config.max_be || config.max_be > 8 {
+            return Err(ErrorCode::INVAL);
+        }
+        self.config.set(config);
+        if config.rx_on_when_idle {
+            if !self.radio.is_on() {
+                let _ = self.radio.start();
+            }
+        } else {
+            self.maybe_sleep();
+        }
+        Ok(())
+    }
+
+    /// Registers a sleepy child. Frames addressed to it are held until it
+    /// polls for them.
+    pub fn add_sleepy_child(&self, addr: MacAddress) -> Result<(), ErrorCode> {
+        if self.is_sleepy_child(addr) {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.children
+            .iter()
+            .find(|child| child.get().is_none())
+            .map_or(Err(ErrorCode::NOMEM), |child| {
+                child.set(Some(addr));
+                Ok(())
+            })
+    }
+
+    /// Removes a sleepy child and drops the frames queued for it, which
+    /// complete with `ErrorCode::CANCEL`.
+    pub fn remove_sleepy_child(&self, addr: MacAddress) -> Result<(), ErrorCode> {
+        let child = self
+            .children
+            .iter()
+            .find(|child| child.get() == Some(addr))
+            .ok_or(ErrorCode::INVAL)?;
+        child.set(None);
+        for (i, frame) in self.indirect.iter().enumerate() {
+            if frame.dst.get() == Some(addr) && !self.sending_indirect(i) {
+                self.indirect_done(i, false, Err(ErrorCode::CANCEL));
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks `parent` for frames it holds for this device by sending a Data
+    /// Request command. The `PollClient` is notified once the poll is
+    /// complete; received frames are passed to the receive client.
+    pub fn poll(&self, parent: MacAddress) -> Result<(), ErrorCode> {
+        if self.poll_parent.get().is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.poll_parent.set(Some(parent));
+        self.send_poll(parent).inspect_err(|_| {
+            self.poll_parent.set(None);
+        })
+    }
+
+    fn is_sleepy_child(&self, addr: MacAddress) -> bool {
+        self.children.iter().any(|child| child.get() == Some(addr))
+    }
+
+    fn sending_indirect(&self, index: usize) -> bool {
+        self.state.get() != CsmaState::Idle && self.tx_source.get() == TxSource::Indirect(index)
+    }
+
+    fn own_address(&self) -> MacAddress {
+        let addr = self.radio.get_address();
+        // 0xfffe and 0xffff mean that no short address is assigned.
+        if addr >= 0xfffe {
+            MacAddress::Long(self.radio.get_address_long())
+        } else {
+            MacAddress::Short(addr)
+        }
+    }
+
+    fn addressed_to_us(&self, dst_pan: Option<PanID>, dst_addr: Option<MacAddress>) -> bool {
+        // Frames without a destination PAN ID belong to our PAN.
+        if dst_pan.is_some_and(|pan| pan != self.radio.get_pan() && pan != 0xffff) {
+            return false;
+        }
+        match dst_addr {
+            Some(MacAddress::Short(addr)) => addr == self.radio.get_address() || addr == 0xffff,
+            Some(MacAddress::Long(addr)) => addr == self.radio.get_address_long(),
+            None => false,
+        }
+    }
+
+    fn send_poll(&self, parent: MacAddress) -> Result<(), ErrorCode> {
+        let buf = self.poll_buf.take().ok_or(ErrorCode::BUSY)?;
+        let pan = self.radio.get_pan();
+        let seq = self.poll_seq.get();
+        self.poll_seq.set(seq.wrapping_add(1));
+        let header = Header {
+            frame_type: FrameType::MACCommand,
+            frame_pending: false,
+            ack_requested: true,
+            version: FrameVersion::V2006,
+            seq: Some(seq),
+            dst_pan: Some(pan),
+            dst_addr: Some(parent),
+            src_pan: Some(pan),
+            src_addr: Some(self.own_address()),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        match header.encode(&mut buf[PSDU_OFFSET..], true).done() {
+            Some((off, _)) if PSDU_OFFSET + off + radio::MFR_SIZE < buf.len() => {
+                buf[PSDU_OFFSET + off] = DATA_REQUEST_COMMAND;
+                self.poll_len.set(off + 1);
+                if self.state.get() == CsmaState::Idle {
+                    self.start_tx(buf, off + 1, TxSource::Poll);
+                } else {
+                    self.poll_buf.replace(buf);
+                    self.poll_pending.set(true);
+                }
+                Ok(())
+            }
+            _ => {
+                self.poll_buf.replace(buf);
+                Err(ErrorCode::FAIL)
+            }
+        }
+    }
+
+    fn poll_finished(&self, result: Result<(), ErrorCode>) {
+        self.poll_parent.set(None);
+        self.poll_pending.set(false);
+        self.poll_wait.clear();
+        self.rearm();
+        self.maybe_sleep();
+        self.poll_client.map(|client| client.poll_done(result));
+    }
+
+    /// Queues a frame for a sleepy child. The client buffer is returned
+    /// once the frame has been delivered or dropped.
+    fn enqueue_indirect(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        dst: MacAddress,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let slot = match self
+            .indirect
+            .iter()
+            .find(|frame| frame.dst.get().is_none() && frame.client_buf.is_none())
+        {
+            Some(slot) => slot,
+            None => return Err((ErrorCode::NOMEM, buf)),
+        };
+        let copied = slot.buf.map_or(false, |slot_buf| {
+            let end = PSDU_OFFSET + frame_len;
+            if slot_buf.len() < end + radio::MFR_SIZE {
+                false
+            } else {
+                slot_buf[PSDU_OFFSET..end].copy_from_slice(&buf[PSDU_OFFSET..end]);
+                true
+            }
+        });
+        if !copied {
+            return Err((ErrorCode::SIZE, buf));
+        }
+        slot.len.set(frame_len);
+        slot.dst.set(Some(dst));
+        slot.queued_at.set(self.alarm.now());
+        slot.requested.set(false);
+        slot.client_buf.replace(buf);
+        self.rearm();
+        Ok(())
+    }
+
+    /// Removes frame `index` from the indirect queue and returns its client
+    /// buffer with the outcome from a deferred call.
+    fn indirect_done(&self, index: usize, acked: bool, result: Result<(), ErrorCode>) {
+        let frame = &self.indirect[index];
+        frame.dst.set(None);
+        frame.done.set(Some((acked, result)));
+        self.deferred_call.set();
+    }
+
+    fn data_request_received(&self, src_addr: MacAddress) {
+        let frame = self
+            .indirect
+            .iter()
+            .enumerate()
+            .find(|(i, frame)| frame.dst.get() == Some(src_addr) && !self.sending_indirect(*i));
+        if let Some((_, frame)) = frame {
+            frame.requested.set(true);
+            self.next();
+        }
+    }
+
+    /// Hands `buf` to the CSMA engine. The engine must be idle.
+    fn start_tx(&self, buf: &'static mut [u8], frame_len: usize, source: TxSource) {
+        let (seq, ack_requested) =
+            match Header::decode(&buf[PSDU_OFFSET..PSDU_OFFSET + frame_len], false).done() {
+                Some((_, (header, _))) => (header.seq, header.ack_requested),
+                None => (None, false),
+            };
+        let config = self.config.get();
+        self.tx_buf.replace(buf);
+        self.tx_len.set(frame_len);
+        self.tx_source.set(source);
+        self.tx_seq.set(seq);
+        self.tx_ack_requested.set(ack_requested && seq.is_some());
+        self.backoffs.set(0);
+        self.backoff_exponent.set(config.min_be);
+        self.retries.set(0);
+
+        if self.radio.is_on() {
+            self.begin_backoff();
+        } else {
+            self.state.set(CsmaState::Startup);
+            if let Err(ecode) = self.radio.start() {
+                self.tx_buf
+                    .take()
+                    .map(|buf| self.finish(buf, false, Err(ecode), None));
+            }
+        }
+    }
+
+    fn begin_backoff(&self) {
+        self.state.set(CsmaState::Backoff);
+        self.engine_timer.clear();
+        if self.rng.get().is_err() {
+            // Without randomness, fall back to not backing off at all.
+            self.set_engine_timer(A::Ticks::from(0));
+        }
+    }
+
+    fn set_engine_timer(&self, dt: A::Ticks) {
+        self.engine_timer.set((self.alarm.now(), dt));
+        self.rearm();
+    }
+
+    fn transmit_frame(&self) {
+        self.state.set(CsmaState::Transmit);
+        if let Some(buf) = self.tx_buf.take() {
+            if let Err((ecode, buf)) = self.radio.transmit(buf, self.tx_len.get()) {
+                if ecode == ErrorCode::BUSY {
+                    self.channel_busy(buf);
+                } else {
+                    self.finish(buf, false, Err(ecode), None);
+                }
+            }
+        }
+    }
+
+    fn channel_busy(&self, buf: &'static mut [u8]) {
+        let config = self.config.get();
+        self.backoffs.set(self.backoffs.get() + 1);
+        self.backoff_exponent.set(core::cmp::min(
+            self.backoff_exponent.get() + 1,
+            config.max_be,
+        ));
+        if self.backoffs.get() > config.max_csma_backoffs {
+            self.finish(buf, false, Err(ErrorCode::BUSY), None);
+        } else {
+            self.tx_buf.replace(buf);
+            self.begin_backoff();
+        }
+    }
+
+    fn ack_timeout(&self) {
+        let config = self.config.get();
+        self.retries.set(self.retries.get() + 1);
+        if self.retries.get() > config.max_frame_retries {
+            self.tx_buf
+                .take()
+                .map(|buf| self.finish(buf, false, Err(ErrorCode::NOACK), None));
+        } else {
+            self.backoffs.set(0);
+            self.backoff_exponent.set(config.min_be);
+            self.begin_backoff();
+        }
+    }
+
+    /// Completes the frame owned by the engine. `frame_pending` is the frame
+    /// pending bit of the acknowledgement, if it was observed.
+    fn finish(
+        &self,
+        buf: &'static mut [u8],
+        acked: bool,
+        result: Result<(), ErrorCode>,
+        frame_pending: Option<bool>,
+    ) {
+        self.state.set(CsmaState::Idle);
+        self.engine_timer.clear();
+        self.rearm();
+
+        match self.tx_source.get() {
+            TxSource::Client => {
+                self.tx_client.map(move |c| c.send_done(buf, acked, result));
+            }
+            TxSource::Indirect(index) => {
+                let frame = &self.indirect[index];
+                frame.buf.replace(buf);
+                frame.requested.set(false);
+                // A frame that could not be delivered stays queued until
+                // the child polls again or the frame expires.
+                if result.is_ok() {
+                    self.indirect_done(index, acked, result);
+                }
+            }
+            TxSource::Poll => {
+                self.poll_buf.replace(buf);
+                match result {
+                    Ok(()) if frame_pending != Some(false) => {
+                        let wait = self.alarm.ticks_from_ms(self.config.get().poll_wait_ms);
+                        self.poll_wait.set((self.alarm.now(), wait));
+                        self.rearm();
+                    }
+                    _ => self.poll_finished(result),
+                }
+            }
+        }
+
+        self.next();
+    }
+
+    /// Starts the next waiting frame if the engine is idle.
+    fn next(&self) {
+        if self.state.get() != CsmaState::Idle {
+            return;
+        }
+        if let Some(buf) = self.client_buf.take() {
+            self.start_tx(buf, self.client_len.get(), TxSource::Client);
+            return;
+        }
+        let requested = self
+            .indirect
+            .iter()
+            .position(|frame| frame.dst.get().is_some() && frame.requested.get());
+        if let Some(index) = requested {
+            let frame = &self.indirect[index];
+            let more = self.indirect.iter().enumerate().any(|(i, other)| {
+                i != index && other.dst.get().is_some() && other.dst.get() == frame.dst.get()
+            });
+            if let Some(buf) = frame.buf.take() {
+                if more {
+                    buf[PSDU_OFFSET] |= FRAME_PENDING_BIT;
+                } else {
+                    buf[PSDU_OFFSET] &= !FRAME_PENDING_BIT;
+                }
+                self.start_tx(buf, frame.len.get(), TxSource::Indirect(index));
+                return;
+            }
+        }
+        if self.poll_pending.get() {
+            if let Some(buf) = self.poll_buf.take() {
+                self.poll_pending.set(false);
+                self.start_tx(buf, self.poll_len.get(), TxSource::Poll);
+                return;
+            }
+        }
+        self.maybe_sleep();
+    }
+
+    /// Powers the radio down if this device does not listen while idle and
+    /// nothing is in progress.
+    fn maybe_sleep(&self) {
+        if !self.config.get().rx_on_when_idle
+            && self.state.get() == CsmaState::Idle
+            && self.poll_wait.is_none()
+            && self.radio.is_on()
+        {
+            let _ = self.radio.stop();
+        }
+    }
+
+    /// Returns the ticks left until `reference + dt`, or zero if that time
+    /// has passed.
+    fn remaining(now: A::Ticks, reference: A::Ticks, dt: A::Ticks) -> A::Ticks {
+        let end = reference.wrapping_add(dt);
+        if now.within_range(reference, end) {
+            end.wrapping_sub(now)
+        } else {
+            A::Ticks::from(0)
+        }
+    }
+
+    /// Sets the alarm for the earliest pending timeout.
+    fn rearm(&self) {
+        let now = self.alarm.now();
+        let persistence = self
+            .alarm
+            .ticks_from_ms(self.config.get().transaction_persistence_ms);
+        let expiries = self
+            .indirect
+            .iter()
+            .filter(|frame| frame.dst.get().is_some())
+            .map(|frame| Self::remaining(now, frame.queued_at.get(), persistence));
+        let earliest = self
+            .engine_timer
+            .get()
+            .into_iter()
+            .chain(self.poll_wait.get())
+            .map(|(reference, dt)| Self::remaining(now, reference, dt))
+            .chain(expiries)
+            .min();
+        match earliest {
+            Some(dt) => self.alarm.set_alarm(now, dt),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> rng::Client for CsmaMac<'a, R, A> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        _error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        match randomness.next() {
+            Some(random) => {
+                if self.state.get() == CsmaState::Backoff && self.engine_timer.is_none() {
+                    let periods = random % (1 << self.backoff_exponent.get());
+                    let dt = self.alarm.ticks_from_us(periods * UNIT_BACKOFF_PERIOD_US);
+                    self.set_engine_timer(dt);
+                }
+                rng::Continue::Done
+            }
+            None => rng::Continue::More,
+        }
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> time::AlarmClient for CsmaMac<'a, R, A> {
+    fn alarm(&self) {
+        let now = self.alarm.now();
+
+        let engine_expired = self
+            .engine_timer
+            .get()
+            .is_some_and(|(reference, dt)| Self::remaining(now, reference, dt) == 0.into());
+        if engine_expired {
+            self.engine_timer.clear();
+            match self.state.get() {
+                CsmaState::Backoff => self.transmit_frame(),
+                CsmaState::AckWait => self.ack_timeout(),
+                _ => {}
+            }
+        }
+
+        let poll_expired = self
+            .poll_wait
+            .get()
+            .is_some_and(|(reference, dt)| Self::remaining(now, reference, dt) == 0.into());
+        if poll_expired {
+            self.poll_finished(Ok(()));
+        }
+
+        let persistence = self
+            .alarm
+            .ticks_from_ms(self.config.get().transaction_persistence_ms);
+        for (i, frame) in self.indirect.iter().enumerate() {
+            if frame.dst.get().is_some()
+                && !self.sending_indirect(i)
+                && Self::remaining(now, frame.queued_at.get(), persistence) == 0.into()
+            {
+                self.indirect_done(i, false, Err(ErrorCode::NOACK));
+            }
+        }
+
+        self.rearm();
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> DeferredCallClient for CsmaMac<'a, R, A> {
+    fn handle_deferred_call(&self) {
+        for frame in self.indirect.iter() {
+            if let Some((acked, result)) = frame.done.take() {
+                frame.client_buf.take().map(|buf| {
+                    self.tx_client.map(move |c| c.send_done(buf, acked, result));
+                });
+            }
+        }
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> Mac<'a> for CsmaMac<'a, R, A> {
+    fn initialize(&self) -> Result<(), ErrorCode> {
+        Ok(())
+    }
+
+    // A sleepy device reports the radio as on, since it powers the radio up
+    // by itself when it has something to send.
+    fn is_on(&self) -> bool {
+        !self.config.get().rx_on_when_idle || self.radio.is_on()
+    }
+
+    fn start(&self) -> Result<(), ErrorCode> {
+        self.radio.start()
+    }
+
+    fn set_config_client(&self, client: &'a dyn radio::ConfigClient) {
+        self.radio.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'a dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        // Like `AwakeMac`, shift the frame by the `PSDU_OFFSET` required by
+        // the radio after checking the arguments.
+        if full_mac_frame.len() < frame_len + PSDU_OFFSET + radio::MFR_SIZE {
+            return Err((ErrorCode::NOMEM, full_mac_frame));
+        }
+        if frame_len > MAX_FRAME_SIZE {
+            return Err((ErrorCode::INVAL, full_mac_frame));
+        }
+        if self.client_buf.is_some()
+            || (self.state.get() != CsmaState::Idle && self.tx_source.get() == TxSource::Client)
+        {
+            return Err((ErrorCode::BUSY, full_mac_frame));
+        }
+
+        full_mac_frame.copy_within(0..frame_len, PSDU_OFFSET);
+
+        let dst_addr = Header::decode(&full_mac_frame[PSDU_OFFSET..PSDU_OFFSET + frame_len], false)
+            .done()
+            .and_then(|(_, (header, _))| header.dst_addr);
+        if let Some(dst) = dst_addr.filter(|dst| self.is_sleepy_child(*dst)) {
+            return self.enqueue_indirect(full_mac_frame, frame_len, dst);
+        }
+
+        if self.state.get() == CsmaState::Idle {
+            self.start_tx(full_mac_frame, frame_len, TxSource::Client);
+        } else {
+            self.client_buf.replace(full_mac_frame);
+            self.client_len.set(frame_len);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> radio::PowerClient for CsmaMac<'a, R, A> {
+    fn changed(&self, on: bool) {
+        if on && self.state.get() == CsmaState::Startup {
+            self.begin_backoff();
+        }
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> radio::TxClient for CsmaMac<'a, R, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: Result<(), ErrorCode>) {
+        if self.state.get() != CsmaState::Transmit {
+            return;
+        }
+        match result {
+            Err(ErrorCode::BUSY) => self.channel_busy(buf),
+            Err(ecode) => self.finish(buf, false, Err(ecode), None),
+            Ok(()) if !self.tx_ack_requested.get() => self.finish(buf, false, Ok(()), None),
+            Ok(()) if acked => self.finish(buf, true, Ok(()), None),
+            Ok(()) => {
+                // The radio did not see an acknowledgement; listen for one
+                // ourselves before retransmitting.
+                self.tx_buf.replace(buf);
+                self.state.set(CsmaState::AckWait);
+                let dt = self.alarm.ticks_from_us(self.config.get().ack_wait_us);
+                self.set_engine_timer(dt);
+            }
+        }
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, A: Alarm<'a>> radio::RxClient for CsmaMac<'a, R, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        lqi: u8,
+        crc_valid: bool,
+        result: Result<(), ErrorCode>,
+    ) {
+        let action = {
+            let end = core::cmp::min(PSDU_OFFSET + frame_len, buf.len());
+            let frame = buf.get(PSDU_OFFSET..end).unwrap_or(&[]);
+            match Header::decode(frame, false).done() {
+                Some((off, (header, _))) => match header.frame_type {
+                    FrameType::Acknowledgement => {
+                        if crc_valid
+                            && self.state.get() == CsmaState::AckWait
+                            && header.seq == self.tx_seq.get()
+                        {
+                            RxAction::Ack {
+                                frame_pending: header.frame_pending,
+                            }
+                        } else {
+                            RxAction::Drop
+                        }
+                    }
+                    FrameType::MACCommand
+                        if frame.get(off) == Some(&DATA_REQUEST_COMMAND)
+                            && self.addressed_to_us(header.dst_pan, header.dst_addr) =>
+                    {
+                        header
+                            .src_addr
+                            .map_or(RxAction::Drop, RxAction::DataRequest)
+                    }
+                    _ if self.addressed_to_us(header.dst_pan, header.dst_addr) => {
+                        RxAction::Deliver {
+                            src_addr: header.src_addr,
+                            frame_pending: header.frame_pending,
+                        }
+                    }
+                    _ => RxAction::Drop,
+                },
+                None => RxAction::Drop,
+            }
+        };
+
+        match action {
+            RxAction::Ack { frame_pending } => {
+                self.radio.set_receive_buffer(buf);
+                self.engine_timer.clear();
+                self.tx_buf
+                    .take()
+                    .map(|tx_buf| self.finish(tx_buf, true, Ok(()), Some(frame_pending)));
+            }
+            RxAction::DataRequest(src_addr) => {
+                self.radio.set_receive_buffer(buf);
+                self.data_request_received(src_addr);
+            }
+            RxAction::Deliver {
+                src_addr,
+                frame_pending,
+            } => {
+                self.rx_client.map(move |c| {
+                    c.receive(buf, frame_len, lqi, crc_valid, result);
+                });
+                // Data from the parent we are polling ends the poll, unless
+                // the parent indicates that it has more.
+                if let Some(parent) = self
+                    .poll_parent
+                    .get()
+                    .filter(|parent| self.poll_wait.is_some() && src_addr == Some(*parent))
+                {
+                    if frame_pending {
+                        self.poll_wait.clear();
+                        if let Err(ecode) = self.send_poll(parent) {
+                            self.poll_finished(Err(ecode));
+                        }
+                    } else {
+                        self.poll_finished(Ok(()));
+                    }
+                }
+            }
+            RxAction::Drop => {
+                self.radio.set_receive_buffer(buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use kernel::hil::time::{Freq1MHz, Ticks32, Time};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const OWN_ADDR: u16 = 0x0001;
+    const PAN: u16 = 0xabcd;
+
+    /// A radio that is always on and either reports a busy channel or keeps
+    /// the transmitted frame until the test completes it.
+    struct Radio {
+        busy: Cell<bool>,
+        attempts: Cell<usize>,
+        sent: TakeCell<'static, [u8]>,
+    }
+
+    impl<'a> radio::RadioConfig<'a> for Radio {
+        fn initialize(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn reset(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn start(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn stop(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn is_on(&self) -> bool {
+            true
+        }
+        fn busy(&self) -> bool {
+            false
+        }
+        fn set_power_client(&self, _client: &'a dyn radio::PowerClient) {}
+        fn config_commit(&self) {}
+        fn set_config_client(&self, _client: &'a dyn radio::ConfigClient) {}
+        fn get_address(&self) -> u16 {
+            OWN_ADDR
+        }
+        fn get_address_long(&self) -> [u8; 8] {
+            [0; 8]
+        }
+        fn get_pan(&self) -> u16 {
+            PAN
+        }
+        fn get_tx_power(&self) -> i8 {
+            0
+        }
+        fn get_channel(&self) -> u8 {
+            26
+        }
+        fn set_address(&self, _addr: u16) {}
+        fn set_address_long(&self, _addr: [u8; 8]) {}
+        fn set_pan(&self, _id: u16) {}
+        fn set_tx_power(&self, _power: i8) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn set_channel(&self, _chan: radio::RadioChannel) {}
+    }
+
+    impl<'a> radio::RadioData<'a> for Radio {
+        fn set_transmit_client(&self, _client: &'a dyn radio::TxClient) {}
+        fn set_receive_client(&self, _client: &'a dyn radio::RxClient) {}
+        fn set_receive_buffer(&self, _receive_buffer: &'static mut [u8]) {}
+        fn transmit(
+            &self,
+            buf: &'static mut [u8],
+            _frame_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.attempts.set(self.attempts.get() + 1);
+            if self.busy.get() {
+                return Err((ErrorCode::BUSY, buf));
+            }
+            self.sent.replace(buf);
+            Ok(())
+        }
+    }
+
+    /// An alarm whose time only moves when the test advances it.
+    struct Clock {
+        now: Cell<Ticks32>,
+    }
+
+    impl Time for Clock {
+        type Frequency = Freq1MHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            self.now.get()
+        }
+    }
+
+    impl<'a> Alarm<'a> for Clock {
+        fn set_alarm_client(&self, _client: &'a dyn time::AlarmClient) {}
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {}
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn is_armed(&self) -> bool {
+            false
+        }
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    /// An RNG without randomness, so that backoffs last zero periods.
+    struct NoRng;
+
+    impl<'a> Rng<'a> for NoRng {
+        fn get(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::FAIL)
+        }
+        fn cancel(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn set_client(&'a self, _client: &'a dyn rng::Client) {}
+    }
+
+    #[derive(Default)]
+    struct Client {
+        results: core::cell::RefCell<Vec<Result<(), ErrorCode>>>,
+    }
+
+    impl radio::TxClient for Client {
+        fn send_done(&self, _buf: &'static mut [u8], _acked: bool, result: Result<(), ErrorCode>) {
+            self.results.borrow_mut().push(result);
+        }
+    }
+
+    type Mac = CsmaMac<'static, Radio, Clock>;
+
+    fn setup() -> (
+        &'static Mac,
+        &'static Radio,
+        &'static Clock,
+        &'static Client,
+    ) {
+        let radio = Box::leak(Box::new(Radio {
+            busy: Cell::new(false),
+            attempts: Cell::new(0),
+            sent: TakeCell::empty(),
+        }));
+        let clock = Box::leak(Box::new(Clock {
+            now: Cell::new(Ticks32::from(1000)),
+        }));
+        let mac = Box::leak(Box::new(CsmaMac::new(
+            &*radio,
+            &*clock,
+            Box::leak(Box::new(NoRng)),
+            Box::leak(Box::new([[0; radio::MAX_BUF_SIZE]; INDIRECT_QUEUE_LEN])),
+            Box::leak(Box::new([0; radio::MAX_BUF_SIZE])),
+        )));
+        let client = Box::leak(Box::new(Client::default()));
+        crate::ieee802154::mac::Mac::set_transmit_client(&*mac, &*client);
+        (mac, radio, clock, client)
+    }
+
+    /// Returns a client buffer with a data frame to `dst` and its length.
+    fn data_frame(dst: u16, seq: u8, ack_requested: bool) -> (&'static mut [u8], usize) {
+        let buf = Box::leak(Box::new([0; radio::MAX_BUF_SIZE]));
+        let header = Header {
+            frame_type: FrameType::Data,
+            frame_pending: false,
+            ack_requested,
+            version: FrameVersion::V2006,
+            seq: Some(seq),
+            dst_pan: Some(PAN),
+            dst_addr: Some(MacAddress::Short(dst)),
+            src_pan: Some(PAN),
+            src_addr: Some(MacAddress::Short(OWN_ADDR)),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        let (len, _) = header.encode(&mut buf[..], true).done().unwrap();
+        buf[len] = 0x42;
+        (buf, len + 1)
+    }
+
+    fn send(mac: &Mac, (buf, len): (&'static mut [u8], usize)) {
+        assert!(crate::ieee802154::mac::Mac::transmit(mac, buf, len).is_ok());
+    }
+
+    /// Destination address of the frame the radio is transmitting.
+    fn sent_to(radio: &Radio) -> Option<MacAddress> {
+        radio.sent.map_or(None, |buf| {
+            Header::decode(&buf[PSDU_OFFSET..], false)
+                .done()
+                .and_then(|(_, (header, _))| header.dst_addr)
+        })
+    }
+
+    #[test]
+    fn busy_channel_grows_backoff_exponent_until_giving_up() {
+        let (mac, radio, _, client) = setup();
+        let config = mac.get_config();
+        radio.busy.set(true);
+        send(mac, data_frame(0x0002, 1, false));
+        assert_eq!(mac.backoff_exponent.get(), config.min_be);
+
+        let mut expected_be = config.min_be;
+        for backoff in 1..=config.max_csma_backoffs {
+            time::AlarmClient::alarm(mac);
+            expected_be = core::cmp::min(expected_be + 1, config.max_be);
+            assert_eq!(mac.backoffs.get(), backoff);
+            assert_eq!(mac.backoff_exponent.get(), expected_be);
+            assert!(client.results.borrow().is_empty());
+        }
+        assert_eq!(mac.backoff_exponent.get(), config.max_be);
+
+        // One more busy channel than allowed fails the transmission.
+        time::AlarmClient::alarm(mac);
+        assert_eq!(
+            radio.attempts.get(),
+            usize::from(config.max_csma_backoffs) + 1
+        );
+        assert_eq!(*client.results.borrow(), [Err(ErrorCode::BUSY)]);
+        assert!(mac.state.get() == CsmaState::Idle);
+    }
+
+    #[test]
+    fn unacknowledged_frame_is_retried_until_noack() {
+        let (mac, radio, clock, client) = setup();
+        let config = mac.get_config();
+        send(mac, data_frame(0x0002, 7, true));
+
+        for attempt in 0..=config.max_frame_retries {
+            assert_eq!(mac.retries.get(), attempt);
+            time::AlarmClient::alarm(mac);
+            assert_eq!(radio.attempts.get(), usize::from(attempt) + 1);
+            let buf = radio.sent.take().unwrap();
+            radio::TxClient::send_done(mac, buf, false, Ok(()));
+            assert!(mac.state.get() == CsmaState::AckWait);
+
+            // No acknowledgement within the wait duration.
+            clock.now.set(
+                clock
+                    .now
+                    .get()
+                    .wrapping_add(Ticks32::from(config.ack_wait_us)),
+            );
+            time::AlarmClient::alarm(mac);
+        }
+
+        assert_eq!(
+            radio.attempts.get(),
+            usize::from(config.max_frame_retries) + 1
+        );
+        assert_eq!(*client.results.borrow(), [Err(ErrorCode::NOACK)]);
+    }
+
+    #[test]
+    fn acknowledged_retry_resets_backoff() {
+        let (mac, radio, clock, client) = setup();
+        let config = mac.get_config();
+        send(mac, data_frame(0x0002, 7, true));
+
+        time::AlarmClient::alarm(mac);
+        radio::TxClient::send_done(mac, radio.sent.take().unwrap(), false, Ok(()));
+        clock.now.set(
+            clock
+                .now
+                .get()
+                .wrapping_add(Ticks32::from(config.ack_wait_us)),
+        );
+        time::AlarmClient::alarm(mac);
+        assert_eq!(mac.retries.get(), 1);
+        assert_eq!(mac.backoffs.get(), 0);
+        assert_eq!(mac.backoff_exponent.get(), config.min_be);
+
+        time::AlarmClient::alarm(mac);
+        radio::TxClient::send_done(mac, radio.sent.take().unwrap(), true, Ok(()));
+        assert_eq!(*client.results.borrow(), [Ok(())]);
+    }
+
+    #[test]
+    fn indirect_frames_wait_for_data_request_of_their_child() {
+        let (mac, radio, _, client) = setup();
+        let child = MacAddress::Short(0x0003);
+        assert_eq!(mac.add_sleepy_child(child), Ok(()));
+        assert_eq!(mac.add_sleepy_child(child), Err(ErrorCode::ALREADY));
+
+        // Both frames are queued, and their buffers are held until they
+        // are delivered.
+        send(mac, data_frame(0x0003, 1, false));
+        send(mac, data_frame(0x0003, 2, false));
+        mac.handle_deferred_call();
+        assert!(client.results.borrow().is_empty());
+        assert_eq!(
+            mac.indirect
+                .iter()
+                .filter(|f| f.dst.get().is_some())
+                .count(),
+            2
+        );
+
+        // A Data Request of another device does not release them.
+        mac.data_request_received(MacAddress::Short(0x0004));
+        time::AlarmClient::alarm(mac);
+        assert_eq!(radio.attempts.get(), 0);
+
+        // The first request of the child sends one frame, with the frame
+        // pending bit set since another one is waiting.
+        mac.data_request_received(child);
+        time::AlarmClient::alarm(mac);
+        assert_eq!(radio.attempts.get(), 1);
+        assert_eq!(sent_to(radio), Some(child));
+        let buf = radio.sent.take().unwrap();
+        assert_ne!(buf[PSDU_OFFSET] & FRAME_PENDING_BIT, 0);
+        radio::TxClient::send_done(mac, buf, false, Ok(()));
+        mac.handle_deferred_call();
+        assert_eq!(*client.results.borrow(), [Ok(())]);
+        assert_eq!(
+            mac.indirect
+                .iter()
+                .filter(|f| f.dst.get().is_some())
+                .count(),
+            1
+        );
+
+        // The second request sends the last frame without the bit.
+        mac.data_request_received(child);
+        time::AlarmClient::alarm(mac);
+        assert_eq!(radio.attempts.get(), 2);
+        let buf = radio.sent.take().unwrap();
+        assert_eq!(buf[PSDU_OFFSET] & FRAME_PENDING_BIT, 0);
+        radio::TxClient::send_done(mac, buf, false, Ok(()));
+        assert!(mac.indirect.iter().all(|f| f.dst.get().is_none()));
+        mac.handle_deferred_call();
+        assert_eq!(*client.results.borrow(), [Ok(()), Ok(())]);
+    }
+
+    #[test]
+    fn undelivered_indirect_frames_complete_on_expiry() {
+        let (mac, radio, clock, client) = setup();
+        let child = MacAddress::Short(0x0003);
+        assert_eq!(mac.add_sleepy_child(child), Ok(()));
+        send(mac, data_frame(0x0003, 1, false));
+
+        // A failed delivery keeps the frame queued for the next poll.
+        mac.data_request_received(child);
+        time::AlarmClient::alarm(mac);
+        radio::TxClient::send_done(mac, radio.sent.take().unwrap(), false, Err(ErrorCode::FAIL));
+        mac.handle_deferred_call();
+        assert!(client.results.borrow().is_empty());
+
+        let persistence = mac.get_config().transaction_persistence_ms * 1000;
+        clock
+            .now
+            .set(clock.now.get().wrapping_add(Ticks32::from(persistence)));
+        time::AlarmClient::alarm(mac);
+        mac.handle_deferred_call();
+        assert_eq!(*client.results.borrow(), [Err(ErrorCode::NOACK)]);
+        assert!(mac.indirect.iter().all(|f| f.dst.get().is_none()));
+
+        // Frames of a removed child are cancelled.
+        send(mac, data_frame(0x0003, 2, false));
+        assert_eq!(mac.remove_sleepy_child(child), Ok(()));
+        mac.handle_deferred_call();
+        assert_eq!(
+            *client.results.borrow(),
+            [Err(ErrorCode::NOACK), Err(ErrorCode::CANCEL)]
+        );
+    }
+
+    #[test]
+    fn frames_for_other_pans_are_not_for_us() {
+        let (mac, _, _, _) = setup();
+        let own = Some(MacAddress::Short(OWN_ADDR));
+        let broadcast = Some(MacAddress::Short(0xffff));
+        assert!(mac.addressed_to_us(Some(PAN), own));
+        assert!(mac.addressed_to_us(Some(0xffff), own));
+        assert!(mac.addressed_to_us(Some(PAN), broadcast));
+        assert!(mac.addressed_to_us(None, own));
+        assert!(!mac.addressed_to_us(Some(PAN + 1), own));
+        assert!(!mac.addressed_to_us(Some(PAN + 1), broadcast));
+        assert!(!mac.addressed_to_us(Some(PAN), Some(MacAddress::Short(0x0002))));
+    }
+
+    #[test]
+    fn indirect_queue_full() {
+        let (mac, _, _, _) = setup();
+        assert_eq!(mac.add_sleepy_child(MacAddress::Short(0x0003)), Ok(()));
+        for seq in 0..INDIRECT_QUEUE_LEN as u8 {
+            send(mac, data_frame(0x0003, seq, false));
+        }
+        let (buf, len) = data_frame(0x0003, 0xff, false);
+        assert!(matches!(
+            crate::ieee802154::mac::Mac::transmit(mac, buf, len),
+            Err((ErrorCode::NOMEM, _))
+        ));
+    }
+}
@@ -4,9 +4,11 @@
 
 //! Support for IEEE 802.15.4.
 
+pub mod csma;
 pub mod device;
 pub mod framer;
 pub mod mac;
+pub mod sniffer;
 pub mod virtual_mac;
 pub mod xmac;
 
@@ -0,0 +1,482 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! IEEE 802.15.4 sniffer that streams received frames out a UART.
+//!
+//! The sniffer is the receive client of a radio and forwards every frame the
+//! radio receives, together with its reception metadata, as a record on a
+//! UART. Frames are buffered in a ring buffer while the UART is busy; if the
+//! ring buffer is full or the UART fails to send them, frames are dropped and
+//! the number of dropped frames is reported in the next record. `tools/ieee802154_pcap.py` converts the
+//! stream into a PCAP file that Wireshark can open.
+//!
+//! The radio must not be used by anything else, and should be configured
+//! (channel, PAN, addresses) by the board before the sniffer is started.
+//!
+//! Limitations
+//! -----------
+//!
+//! The radio HIL has no promiscuous mode, so the sniffer captures what the
+//! radio passes to its receive client. Radios that filter frames by
+//! destination address in hardware, such as the RF233 in its `RX_AACK` mode,
+//! only pass frames addressed to this node or broadcast. The nRF52 radio does
+//! not filter by address and passes every frame on the channel.
+//!
+//! The radio HIL does not report the RSSI of received frames either, so
+//! records only carry the LQI.
+//!
+//! Record Format
+//! -------------
+//!
+//! All multi-byte fields are little endian.
+//!
+//! ```text
+//!  0      1      2         3       4     6           10          14    15        17
+//! +------+------+---------+-------+-----+-----------+-----------+-----+---------+-------+
+//! | 0xC5 | 0x15 | version | flags | len | timestamp | frequency | lqi | dropped | width |
+//! +------+------+---------+-------+-----+-----------+-----------+-----+---------+-------+
+//! | frame (len bytes) ...
+//! +----------------------
+//! ```
+//!
+//! - `version`: `RECORD_VERSION` (2).
+//! - `flags`: bit 0 is set if the frame check sequence was valid.
+//! - `len` (u16): length of the frame, excluding the frame check sequence.
+//! - `timestamp` (u32): value of the sniffer's clock, in ticks, when the
+//!   frame was passed to the sniffer.
+//! - `frequency` (u32): frequency of the clock in Hz.
+//! - `lqi` (u8): link quality indicator reported by the radio.
+//! - `dropped` (u16): number of frames dropped before this one because the
+//!   ring buffer was full or the UART failed to send them (saturating).
+//! - `width` (u8): number of bits of `timestamp` before it wraps: the width
+//!   of the clock, at most 32.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//!
+//! let sniffer = static_init!(
+//!     capsules_extra::ieee802154::sniffer::Sniffer<'static, RadioDevice, Alarm>,
+//!     capsules_extra::ieee802154::sniffer::Sniffer::new(
+//!         radio, uart_device, alarm, &mut SNIFFER_RING, &mut SNIFFER_TX_BUF
+//!     )
+//! );
+//! radio.set_receive_client(sniffer);
+//! radio.set_receive_buffer(&mut RADIO_RX_BUF);
+//! uart_device.set_transmit_client(sniffer);
+//! sniffer.start();
+//! ```
+
+use core::cell::Cell;
+use kernel::hil::radio;
+use kernel::hil::time::{Frequency, Ticks, Time};
+use kernel::hil::uart;
+use kernel::utilities::cells::TakeCell;
+use kernel::ErrorCode;
+
+/// First two bytes of every record.
+pub const RECORD_SYNC: [u8; 2] = [0xc5, 0x15];
+/// Version of the record format.
+pub const RECORD_VERSION: u8 = 2;
+/// Length of the record header preceding the frame.
+pub const RECORD_HEADER_LEN: usize = 18;
+
+const FLAG_CRC_VALID: u8 = 1 << 0;
+
+pub struct Sniffer<'a, R: radio::Radio<'a>, T: Time> {
+    radio: &'a R,
+    uart: &'a dyn uart::Transmit<'a>,
+    time: &'a T,
+    // Records waiting to be sent, stored as a ring of bytes.
+    ring: TakeCell<'static, [u8]>,
+    ring_head: Cell<usize>,
+    ring_len: Cell<usize>,
+    // Bytes from the ring head to the start of the next record, nonzero
+    // while a record has only been sent in part.
+    next_record: Cell<usize>,
+    tx_buf: TakeCell<'static, [u8]>,
+    // Number of frames lost if the data the UART is sending is lost: the
+    // records that start in it and the dropped frames they report.
+    tx_frames: Cell<u16>,
+    dropped: Cell<u16>,
+}
+
+impl<'a, R: radio::Radio<'a>, T: Time> Sniffer<'a, R, T> {
+    pub fn new(
+        radio: &'a R,
+        uart: &'a dyn uart::Transmit<'a>,
+        time: &'a T,
+        ring: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+    ) -> Sniffer<'a, R, T> {
+        Sniffer {
+            radio,
+            uart,
+            time,
+            ring: TakeCell::new(ring),
+            ring_head: Cell::new(0),
+            ring_len: Cell::new(0),
+            next_record: Cell::new(0),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_frames: Cell::new(0),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// Turns on the radio if it is not on already.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        if self.radio.is_on() {
+            Ok(())
+        } else {
+            self.radio.start()
+        }
+    }
+
+    /// Appends a record for `frame` to the ring buffer. Returns false if it
+    /// does not fit.
+    fn enqueue_record(&self, frame: &[u8], lqi: u8, crc_valid: bool) -> bool {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0..2].copy_from_slice(&RECORD_SYNC);
+        header[2] = RECORD_VERSION;
+        header[3] = if crc_valid { FLAG_CRC_VALID } else { 0 };
+        header[4..6].copy_from_slice(&(frame.len() as u16).to_le_bytes());
+        header[6..10].copy_from_slice(&self.time.now().into_u32().to_le_bytes());
+        header[10..14].copy_from_slice(&T::Frequency::frequency().to_le_bytes());
+        header[14] = lqi;
+        header[15..17].copy_from_slice(&self.dropped.get().to_le_bytes());
+        // The timestamp is truncated to 32 bits, so it wraps after at most
+        // 32 bits even if the clock is wider.
+        header[17] = core::cmp::min(T::Ticks::width(), 32) as u8;
+
+        self.ring.map_or(false, |ring| {
+            let record_len = RECORD_HEADER_LEN + frame.len();
+            if ring.len() - self.ring_len.get() < record_len {
+                return false;
+            }
+            let mut tail = (self.ring_head.get() + self.ring_len.get()) % ring.len();
+            for byte in header.iter().chain(frame.iter()) {
+                ring[tail] = *byte;
+                tail = (tail + 1) % ring.len();
+            }
+            self.ring_len.set(self.ring_len.get() + record_len);
+            true
+        })
+    }
+
+    /// Sends as much of the ring buffer as fits in the transmit buffer, if
+    /// the UART is idle.
+    fn send(&self) {
+        if self.ring_len.get() == 0 {
+            return;
+        }
+        let Some(tx_buf) = self.tx_buf.take() else {
+            return;
+        };
+        let len = self.ring.map_or(0, |ring| {
+            let len = core::cmp::min(self.ring_len.get(), tx_buf.len());
+            let mut head = self.ring_head.get();
+
+            // Count the frames of the records that start in the data sent,
+            // and the dropped frames they report, so they can be counted as
+            // dropped if sending fails.
+            let mut next_record = self.next_record.get();
+            let mut frames: u16 = 0;
+            while next_record < len {
+                let at = |i: usize| ring[(head + next_record + i) % ring.len()];
+                let dropped = u16::from_le_bytes([at(15), at(16)]);
+                frames = frames.saturating_add(1).saturating_add(dropped);
+                next_record += RECORD_HEADER_LEN + u16::from_le_bytes([at(4), at(5)]) as usize;
+            }
+            self.next_record.set(next_record - len);
+            self.tx_frames.set(frames);
+
+            for byte in tx_buf[..len].iter_mut() {
+                *byte = ring[head];
+                head = (head + 1) % ring.len();
+            }
+            self.ring_head.set(head);
+            self.ring_len.set(self.ring_len.get() - len);
+            len
+        });
+        if let Err((_, tx_buf)) = self.uart.transmit_buffer(tx_buf, len) {
+            // The data is lost; keep the buffer for the next record.
+            self.tx_buf.replace(tx_buf);
+            self.transmit_failed();
+        }
+    }
+
+    /// Counts the frames that were being sent as dropped, and skips the
+    /// rest of a record that was only sent in part, so that the stream
+    /// continues with a whole record.
+    fn transmit_failed(&self) {
+        self.dropped
+            .set(self.dropped.get().saturating_add(self.tx_frames.take()));
+        let rest = self.next_record.take();
+        self.ring.map(|ring| {
+            self.ring_head
+                .set((self.ring_head.get() + rest) % ring.len());
+            self.ring_len.set(self.ring_len.get() - rest);
+        });
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, T: Time> radio::RxClient for Sniffer<'a, R, T> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        lqi: u8,
+        crc_valid: bool,
+        _result: Result<(), ErrorCode>,
+    ) {
+        let end = core::cmp::min(radio::PSDU_OFFSET + frame_len, buf.len());
+        let frame = buf.get(radio::PSDU_OFFSET..end).unwrap_or(&[]);
+        if self.enqueue_record(frame, lqi, crc_valid) {
+            self.dropped.set(0);
+        } else {
+            self.dropped.set(self.dropped.get().saturating_add(1));
+        }
+        self.radio.set_receive_buffer(buf);
+        self.send();
+    }
+}
+
+impl<'a, R: radio::Radio<'a>, T: Time> uart::TransmitClient for Sniffer<'a, R, T> {
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        self.tx_buf.replace(tx_buffer);
+        if rval.is_err() {
+            self.transmit_failed();
+        }
+        self.send();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use kernel::hil::time::{Freq1MHz, Ticks32};
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// A radio that is always on and keeps the receive buffer.
+    struct Radio {
+        rx_buf: TakeCell<'static, [u8]>,
+    }
+
+    impl<'a> radio::RadioConfig<'a> for Radio {
+        fn initialize(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn reset(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn start(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn stop(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn is_on(&self) -> bool {
+            true
+        }
+        fn busy(&self) -> bool {
+            false
+        }
+        fn set_power_client(&self, _client: &'a dyn radio::PowerClient) {}
+        fn config_commit(&self) {}
+        fn set_config_client(&self, _client: &'a dyn radio::ConfigClient) {}
+        fn get_address(&self) -> u16 {
+            0
+        }
+        fn get_address_long(&self) -> [u8; 8] {
+            [0; 8]
+        }
+        fn get_pan(&self) -> u16 {
+            0
+        }
+        fn get_tx_power(&self) -> i8 {
+            0
+        }
+        fn get_channel(&self) -> u8 {
+            26
+        }
+        fn set_address(&self, _addr: u16) {}
+        fn set_address_long(&self, _addr: [u8; 8]) {}
+        fn set_pan(&self, _id: u16) {}
+        fn set_tx_power(&self, _power: i8) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn set_channel(&self, _chan: radio::RadioChannel) {}
+    }
+
+    impl<'a> radio::RadioData<'a> for Radio {
+        fn set_transmit_client(&self, _client: &'a dyn radio::TxClient) {}
+        fn set_receive_client(&self, _client: &'a dyn radio::RxClient) {}
+        fn set_receive_buffer(&self, receive_buffer: &'static mut [u8]) {
+            self.rx_buf.replace(receive_buffer);
+        }
+        fn transmit(
+            &self,
+            buf: &'static mut [u8],
+            _frame_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            Err((ErrorCode::NOSUPPORT, buf))
+        }
+    }
+
+    /// A UART that either refuses to send, or keeps the buffer until the
+    /// test completes the transmission.
+    struct Uart {
+        fail: Cell<bool>,
+        tx: TakeCell<'static, [u8]>,
+        tx_len: Cell<usize>,
+    }
+
+    impl<'a> uart::Transmit<'a> for Uart {
+        fn set_transmit_client(&self, _client: &'a dyn uart::TransmitClient) {}
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            tx_len: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            if self.fail.get() {
+                return Err((ErrorCode::FAIL, tx_buffer));
+            }
+            self.tx.replace(tx_buffer);
+            self.tx_len.set(tx_len);
+            Ok(())
+        }
+        fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+        fn transmit_abort(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
+
+    struct Clock;
+
+    impl Time for Clock {
+        type Frequency = Freq1MHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    struct Harness {
+        radio: &'static Radio,
+        uart: &'static Uart,
+        sniffer: &'static Sniffer<'static, Radio, Clock>,
+    }
+
+    impl Harness {
+        fn new(tx_len: usize) -> Self {
+            let radio = Box::leak(Box::new(Radio {
+                rx_buf: TakeCell::new(Box::leak(Box::new([0; 128]))),
+            }));
+            let uart = Box::leak(Box::new(Uart {
+                fail: Cell::new(false),
+                tx: TakeCell::empty(),
+                tx_len: Cell::new(0),
+            }));
+            let sniffer = Box::leak(Box::new(Sniffer::new(
+                radio,
+                uart,
+                Box::leak(Box::new(Clock)),
+                Box::leak(vec![0; 256].into_boxed_slice()),
+                Box::leak(vec![0; tx_len].into_boxed_slice()),
+            )));
+            Harness {
+                radio,
+                uart,
+                sniffer,
+            }
+        }
+
+        /// The radio receives a frame of `len` bytes.
+        fn receive(&self, len: usize) {
+            let buf = self.radio.rx_buf.take().unwrap();
+            buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + len].fill(0xab);
+            radio::RxClient::receive(self.sniffer, buf, len, 0, true, Ok(()));
+        }
+
+        /// Complete the transmission of the UART, and return the data sent.
+        fn transmitted(&self, result: Result<(), ErrorCode>) -> Vec<u8> {
+            let buf = self.uart.tx.take().unwrap();
+            let len = self.uart.tx_len.get();
+            let data = buf[..len].to_vec();
+            uart::TransmitClient::transmitted_buffer(self.sniffer, buf, len, result);
+            data
+        }
+    }
+
+    fn dropped(record: &[u8]) -> u16 {
+        assert_eq!(&record[0..3], &[0xc5, 0x15, RECORD_VERSION]);
+        u16::from_le_bytes([record[15], record[16]])
+    }
+
+    #[test]
+    fn refused_transmission_counts_dropped() {
+        let h = Harness::new(64);
+        h.uart.fail.set(true);
+        h.receive(10);
+        h.receive(10);
+        h.uart.fail.set(false);
+
+        h.receive(10);
+        let data = h.transmitted(Ok(()));
+        assert_eq!(data.len(), RECORD_HEADER_LEN + 10);
+        assert_eq!(dropped(&data), 2);
+
+        h.receive(10);
+        assert_eq!(dropped(&h.transmitted(Ok(()))), 0);
+    }
+
+    #[test]
+    fn failed_transmission_skips_partial_record() {
+        // Records are sent in two parts.
+        let h = Harness::new(RECORD_HEADER_LEN + 5);
+        h.receive(10);
+        let first = h.transmitted(Err(ErrorCode::FAIL));
+        assert_eq!(dropped(&first), 0);
+
+        // The rest of the failed record is not sent, the next record starts
+        // at the beginning of the next transmission.
+        h.receive(4);
+        let data = h.transmitted(Ok(()));
+        assert_eq!(data.len(), RECORD_HEADER_LEN + 4);
+        assert_eq!(dropped(&data), 1);
+    }
+
+    #[test]
+    fn failed_transmission_of_several_records() {
+        let h = Harness::new(3 * RECORD_HEADER_LEN);
+        // The first record is sent right away, the next three wait for it.
+        h.receive(1);
+        h.receive(1);
+        h.receive(1);
+        h.receive(1);
+        h.transmitted(Ok(()));
+
+        // Two whole records and part of the third are lost.
+        let data = h.transmitted(Err(ErrorCode::FAIL));
+        assert_eq!(data.len(), 3 * RECORD_HEADER_LEN);
+        h.receive(2);
+        let data = h.transmitted(Ok(()));
+        assert_eq!(data.len(), RECORD_HEADER_LEN + 2);
+        assert_eq!(dropped(&data), 3);
+    }
+}
@@ -0,0 +1,615 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! ISO-TP (ISO 15765-2) transport over CAN.
+//!
+//! ISO-TP carries messages of up to 4095 bytes over classic CAN frames. A
+//! message of up to 7 bytes is sent in a single frame. Longer messages are
+//! split into a first frame and consecutive frames, and the receiver paces
+//! the consecutive frames with flow control frames.
+//!
+//! ```text
+//!          +------------------------+
+//!          |  IsoTpClient, e.g. UDS |
+//!          +------------------------+
+//!                      |
+//!          +------------------------+
+//!          |  IsoTp (this file)     |
+//!          +------------------------+
+//!                |            |
+//!      can::Transmit/Receive  Alarm
+//! ```
+//!
+//! The transport uses normal addressing: it sends frames with one CAN
+//! identifier and receives the frames with another, which makes it one end
+//! of a point to point link, for example the physical addresses of an ECU.
+//! All frames are padded to 8 bytes.
+//!
+//! Sending and receiving are independent, so a message can be received while
+//! another one is sent. The alarm times out a peer that stops sending flow
+//! control or consecutive frames (N_Bs and N_Cr) and spaces consecutive
+//! frames by the separation time the receiver asks for.
+//!
+//! The transport receives into a buffer passed with `receive()` and hands it
+//! to the client with the message. Until the client passes a buffer again,
+//! single frames are dropped and first frames are answered with an overflow.
+//! Receptions that fail, because the peer timed out or a frame was lost, are
+//! dropped without telling the client.
+//!
+//! The CAN peripheral is configured and enabled by someone else, the board
+//! or an application using the CAN driver. The transport starts receiving
+//! when `receive()` is called with the peripheral enabled, or when the
+//! peripheral is enabled later.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let isotp = static_init!(
+//!     IsoTp<'static, VirtualCan<'static, Can>, VirtualMuxAlarm<'static, Rtc>>,
+//!     IsoTp::new(
+//!         can_device,
+//!         alarm,
+//!         tx_frame,
+//!         rx_frame,
+//!         can::Id::Standard(0x7E8),
+//!         can::Id::Standard(0x7E0),
+//!     )
+//! );
+//! kernel::hil::can::Controller::set_client(can_device, Some(isotp));
+//! kernel::hil::can::Transmit::set_client(can_device, Some(isotp));
+//! kernel::hil::can::Receive::set_client(can_device, Some(isotp));
+//! alarm.set_alarm_client(isotp);
+//! isotp.set_client(client);
+//! isotp.receive(rx_buffer);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::hil::can;
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks, Ticks};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Longest message the transport can send or receive.
+pub const MAX_MESSAGE_LEN: usize = 0xFFF;
+
+const FRAME_LEN: usize = can::STANDARD_CAN_PACKET_SIZE;
+const PADDING: u8 = 0xCC;
+
+// Protocol control information, the high nibble of the first byte.
+const SINGLE_FRAME: u8 = 0x00;
+const FIRST_FRAME: u8 = 0x10;
+const CONSECUTIVE_FRAME: u8 = 0x20;
+const FLOW_CONTROL: u8 = 0x30;
+
+// Flow status of a flow control frame.
+const CONTINUE_TO_SEND: u8 = 0x0;
+const WAIT: u8 = 0x1;
+const OVERFLOW: u8 = 0x2;
+
+/// Time a peer has to send the next flow control frame (N_Bs) or
+/// consecutive frame (N_Cr).
+const TIMEOUT_MS: u32 = 1000;
+
+/// Number of flow control frames asking to wait the sender accepts in a row
+/// (N_WFTmax).
+const MAX_WAIT_FRAMES: u8 = 10;
+
+/// Separation time between consecutive frames asked from a sender.
+const SEPARATION_TIME: u8 = 0;
+
+pub trait IsoTpClient {
+    /// A message was received into the buffer passed to `IsoTp::receive`.
+    fn message_received(&self, buffer: &'static mut [u8], len: usize);
+
+    /// The message passed to `IsoTp::send` was sent, or sending it failed.
+    ///
+    /// Sending fails with `NOACK` if the receiver did not send flow control
+    /// in time, with `SIZE` if the message is too long for the receiver and
+    /// with `FAIL` if the receiver or the bus aborted the transfer.
+    fn message_sent(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum TxState {
+    Idle,
+    /// A frame of the message is being sent.
+    Sending,
+    /// The next frame waits for the CAN buffer.
+    Ready,
+    WaitFlowControl,
+    /// The next consecutive frame waits for the separation time.
+    WaitSeparation,
+}
+
+pub struct IsoTp<'a, C, A>
+where
+    C: can::Transmit<FRAME_LEN> + can::Receive<FRAME_LEN>,
+    A: Alarm<'a>,
+{
+    can: &'a C,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn IsoTpClient>,
+    tx_id: can::Id,
+    rx_id: can::Id,
+
+    tx_frame: TakeCell<'static, [u8; FRAME_LEN]>,
+    rx_frame: TakeCell<'static, [u8; FRAME_LEN]>,
+    /// Flow status of a flow control frame waiting for `tx_frame`.
+    flow_control: Cell<Option<u8>>,
+    sending_flow_control: Cell<bool>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_state: Cell<TxState>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    tx_sequence: Cell<u8>,
+    /// Consecutive frames left before the next flow control, `None` if the
+    /// receiver does not limit them.
+    tx_block_remaining: Cell<Option<u8>>,
+    tx_separation_us: Cell<u32>,
+    tx_wait_frames: Cell<u8>,
+    tx_timer: Cell<Option<(A::Ticks, A::Ticks)>>,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    receiving_message: Cell<bool>,
+    rx_len: Cell<usize>,
+    rx_offset: Cell<usize>,
+    rx_sequence: Cell<u8>,
+    rx_timer: Cell<Option<(A::Ticks, A::Ticks)>>,
+}
+
+impl<'a, C, A> IsoTp<'a, C, A>
+where
+    C: can::Transmit<FRAME_LEN> + can::Receive<FRAME_LEN>,
+    A: Alarm<'a>,
+{
+    /// `tx_id` is the identifier of the frames sent and `rx_id` the
+    /// identifier of the frames received.
+    pub fn new(
+        can: &'a C,
+        alarm: &'a A,
+        tx_frame: &'static mut [u8; FRAME_LEN],
+        rx_frame: &'static mut [u8; FRAME_LEN],
+        tx_id: can::Id,
+        rx_id: can::Id,
+    ) -> Self {
+        Self {
+            can,
+            alarm,
+            client: OptionalCell::empty(),
+            tx_id,
+            rx_id,
+            tx_frame: TakeCell::new(tx_frame),
+            rx_frame: TakeCell::new(rx_frame),
+            flow_control: Cell::new(None),
+            sending_flow_control: Cell::new(false),
+            tx_buffer: TakeCell::empty(),
+            tx_state: Cell::new(TxState::Idle),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_sequence: Cell::new(0),
+            tx_block_remaining: Cell::new(None),
+            tx_separation_us: Cell::new(0),
+            tx_wait_frames: Cell::new(0),
+            tx_timer: Cell::new(None),
+            rx_buffer: TakeCell::empty(),
+            receiving_message: Cell::new(false),
+            rx_len: Cell::new(0),
+            rx_offset: Cell::new(0),
+            rx_sequence: Cell::new(0),
+            rx_timer: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn IsoTpClient) {
+        self.client.set(client);
+    }
+
+    /// Send the first `len` bytes of `buffer` as one message.
+    pub fn send(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_state.get() != TxState::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len == 0 || len > MAX_MESSAGE_LEN || len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+        self.tx_buffer.replace(buffer);
+        self.tx_len.set(len);
+        self.tx_offset.set(0);
+        self.tx_sequence.set(1);
+        self.tx_wait_frames.set(0);
+        self.tx_state.set(TxState::Ready);
+        if self.tx_frame.is_none() {
+            // The first frame follows the flow control frame being sent.
+            return Ok(());
+        }
+        match self.send_data_frame() {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                self.tx_state.set(TxState::Idle);
+                Err((err, self.tx_buffer.take().unwrap_or(&mut [])))
+            }
+        }
+    }
+
+    /// Pass the buffer the next message is received into.
+    pub fn receive(&self, buffer: &'static mut [u8]) {
+        self.rx_buffer.replace(buffer);
+        self.start_receiving();
+    }
+
+    fn start_receiving(&self) {
+        self.rx_frame.take().map(|frame| {
+            if let Err((_, frame)) = self.can.start_receive_process(frame) {
+                self.rx_frame.replace(frame);
+            }
+        });
+    }
+
+    fn send_frame(&self, fill: impl FnOnce(&mut [u8; FRAME_LEN])) -> Result<(), ErrorCode> {
+        let frame = self.tx_frame.take().ok_or(ErrorCode::BUSY)?;
+        frame.fill(PADDING);
+        fill(frame);
+        self.can
+            .send(self.tx_id, frame, FRAME_LEN)
+            .map_err(|(err, frame)| {
+                self.tx_frame.replace(frame);
+                err
+            })
+    }
+
+    /// Send the next frame of the message.
+    fn send_data_frame(&self) -> Result<(), ErrorCode> {
+        let len = self.tx_len.get();
+        let offset = self.tx_offset.get();
+        self.tx_buffer.map_or(Err(ErrorCode::FAIL), |buffer| {
+            let chunk = if offset == 0 && len < FRAME_LEN {
+                self.send_frame(|frame| {
+                    frame[0] = SINGLE_FRAME | len as u8;
+                    frame[1..=len].copy_from_slice(&buffer[..len]);
+                })?;
+                len
+            } else if offset == 0 {
+                self.send_frame(|frame| {
+                    frame[0] = FIRST_FRAME | (len >> 8) as u8;
+                    frame[1] = len as u8;
+                    frame[2..].copy_from_slice(&buffer[..FRAME_LEN - 2]);
+                })?;
+                self.tx_block_remaining.set(Some(0));
+                FRAME_LEN - 2
+            } else {
+                let chunk = (len - offset).min(FRAME_LEN - 1);
+                let sequence = self.tx_sequence.get();
+                self.send_frame(|frame| {
+                    frame[0] = CONSECUTIVE_FRAME | sequence;
+                    frame[1..=chunk].copy_from_slice(&buffer[offset..offset + chunk]);
+                })?;
+                self.tx_sequence.set((sequence + 1) & 0x0F);
+                self.tx_block_remaining
+                    .set(self.tx_block_remaining.get().map(|n| n.saturating_sub(1)));
+                chunk
+            };
+            self.tx_offset.set(offset + chunk);
+            self.tx_state.set(TxState::Sending);
+            Ok(())
+        })
+    }
+
+    fn data_frame_sent(&self, status: Result<(), can::Error>) {
+        if status.is_err() {
+            self.finish_sending(Err(ErrorCode::FAIL));
+        } else if self.tx_offset.get() == self.tx_len.get() {
+            self.finish_sending(Ok(()));
+        } else if self.tx_block_remaining.get() == Some(0) {
+            self.tx_state.set(TxState::WaitFlowControl);
+            self.start_timer(&self.tx_timer, self.alarm.ticks_from_ms(TIMEOUT_MS));
+        } else if self.tx_separation_us.get() == 0 {
+            self.tx_state.set(TxState::Ready);
+        } else {
+            self.tx_state.set(TxState::WaitSeparation);
+            self.start_timer(
+                &self.tx_timer,
+                self.alarm.ticks_from_us(self.tx_separation_us.get()),
+            );
+        }
+    }
+
+    fn finish_sending(&self, result: Result<(), ErrorCode>) {
+        self.tx_state.set(TxState::Idle);
+        self.stop_timer(&self.tx_timer);
+        self.tx_buffer.take().map(|buffer| {
+            self.client
+                .map(|client| client.message_sent(buffer, result));
+        });
+    }
+
+    /// Send a pending flow control frame, or else the next frame of the
+    /// message, if the CAN buffer is free.
+    fn send_pending_frame(&self) {
+        if self.tx_frame.is_none() {
+            return;
+        }
+        if let Some(status) = self.flow_control.take() {
+            let sent = self.send_frame(|frame| {
+                frame[0] = FLOW_CONTROL | status;
+                frame[1] = 0;
+                frame[2] = SEPARATION_TIME;
+            });
+            if sent.is_ok() {
+                self.sending_flow_control.set(true);
+                return;
+            }
+        }
+        if self.tx_state.get() == TxState::Ready {
+            if let Err(err) = self.send_data_frame() {
+                self.finish_sending(Err(err));
+            }
+        }
+    }
+
+    fn send_flow_control(&self, status: u8) {
+        self.flow_control.set(Some(status));
+        self.send_pending_frame();
+    }
+
+    fn flow_control_received(&self, frame: &[u8]) {
+        // The flow control can arrive before the frame asking for it was
+        // reported as sent.
+        let expected = match self.tx_state.get() {
+            TxState::WaitFlowControl => true,
+            TxState::Sending => self.tx_block_remaining.get() == Some(0),
+            TxState::Idle | TxState::Ready | TxState::WaitSeparation => false,
+        };
+        if !expected || frame.len() < 3 {
+            return;
+        }
+        match frame[0] & 0x0F {
+            CONTINUE_TO_SEND => {
+                self.stop_timer(&self.tx_timer);
+                self.tx_wait_frames.set(0);
+                self.tx_block_remaining
+                    .set(if frame[1] == 0 { None } else { Some(frame[1]) });
+                self.tx_separation_us.set(match frame[2] {
+                    ms @ 0..=0x7F => u32::from(ms) * 1000,
+                    us @ 0xF1..=0xF9 => u32::from(us - 0xF0) * 100,
+                    // Reserved values ask for the longest separation time.
+                    _ => 0x7F * 1000,
+                });
+                if self.tx_state.get() == TxState::WaitFlowControl {
+                    self.tx_state.set(TxState::Ready);
+                    self.send_pending_frame();
+                }
+            }
+            WAIT if self.tx_wait_frames.get() < MAX_WAIT_FRAMES => {
+                self.tx_wait_frames.set(self.tx_wait_frames.get() + 1);
+                self.start_timer(&self.tx_timer, self.alarm.ticks_from_ms(TIMEOUT_MS));
+            }
+            OVERFLOW => self.finish_sending(Err(ErrorCode::SIZE)),
+            _ => self.finish_sending(Err(ErrorCode::FAIL)),
+        }
+    }
+
+    fn single_frame_received(&self, frame: &[u8]) {
+        let len = usize::from(frame[0] & 0x0F);
+        if len == 0 || len >= frame.len() {
+            return;
+        }
+        // A new message aborts the one being received.
+        self.abort_receiving();
+        self.rx_buffer.take().map(|buffer| {
+            if len > buffer.len() {
+                self.rx_buffer.replace(buffer);
+                return;
+            }
+            buffer[..len].copy_from_slice(&frame[1..=len]);
+            self.client
+                .map(|client| client.message_received(buffer, len));
+        });
+    }
+
+    fn first_frame_received(&self, frame: &[u8]) {
+        let len = usize::from(frame[0] & 0x0F) << 8 | usize::from(frame[1]);
+        // Shorter messages are sent in single frames, longer ones need the
+        // escape sequence of CAN FD.
+        if len < FRAME_LEN || frame.len() < FRAME_LEN {
+            return;
+        }
+        self.abort_receiving();
+        if self.rx_buffer.map_or(true, |buffer| len > buffer.len()) {
+            self.send_flow_control(OVERFLOW);
+            return;
+        }
+        self.rx_buffer.map(|buffer| {
+            buffer[..FRAME_LEN - 2].copy_from_slice(&frame[2..FRAME_LEN]);
+        });
+        self.receiving_message.set(true);
+        self.rx_len.set(len);
+        self.rx_offset.set(FRAME_LEN - 2);
+        self.rx_sequence.set(1);
+        self.start_timer(&self.rx_timer, self.alarm.ticks_from_ms(TIMEOUT_MS));
+        self.send_flow_control(CONTINUE_TO_SEND);
+    }
+
+    fn consecutive_frame_received(&self, frame: &[u8]) {
+        if !self.receiving_message.get() {
+            return;
+        }
+        if frame[0] & 0x0F != self.rx_sequence.get() {
+            self.abort_receiving();
+            return;
+        }
+        let offset = self.rx_offset.get();
+        let len = self.rx_len.get();
+        let chunk = (len - offset).min(frame.len() - 1);
+        self.rx_buffer.map(|buffer| {
+            buffer[offset..offset + chunk].copy_from_slice(&frame[1..=chunk]);
+        });
+        self.rx_offset.set(offset + chunk);
+        self.rx_sequence.set((self.rx_sequence.get() + 1) & 0x0F);
+
+        if offset + chunk < len {
+            self.start_timer(&self.rx_timer, self.alarm.ticks_from_ms(TIMEOUT_MS));
+            return;
+        }
+        self.abort_receiving();
+        self.rx_buffer.take().map(|buffer| {
+            self.client
+                .map(|client| client.message_received(buffer, len));
+        });
+    }
+
+    fn abort_receiving(&self) {
+        self.receiving_message.set(false);
+        self.stop_timer(&self.rx_timer);
+    }
+
+    fn start_timer(&self, timer: &Cell<Option<(A::Ticks, A::Ticks)>>, dt: A::Ticks) {
+        timer.set(Some((self.alarm.now(), dt)));
+        self.arm_alarm();
+    }
+
+    fn stop_timer(&self, timer: &Cell<Option<(A::Ticks, A::Ticks)>>) {
+        timer.set(None);
+        self.arm_alarm();
+    }
+
+    /// Set the alarm for the timer that expires first.
+    fn arm_alarm(&self) {
+        let now = self.alarm.now();
+        let remaining = |(reference, dt): (A::Ticks, A::Ticks)| {
+            dt.wrapping_sub(now.wrapping_sub(reference).min(dt))
+        };
+        let first = match (self.tx_timer.get(), self.rx_timer.get()) {
+            (Some(tx), Some(rx)) if remaining(rx) < remaining(tx) => Some(rx),
+            (Some(tx), _) => Some(tx),
+            (None, rx) => rx,
+        };
+        match first {
+            Some((reference, dt)) => self.alarm.set_alarm(reference, dt),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+    }
+}
+
+fn expired<T: Ticks>(timer: Option<(T, T)>, now: T) -> bool {
+    timer.is_some_and(|(reference, dt)| now.wrapping_sub(reference) >= dt)
+}
+
+impl<'a, C, A> AlarmClient for IsoTp<'a, C, A>
+where
+    C: can::Transmit<FRAME_LEN> + can::Receive<FRAME_LEN>,
+    A: Alarm<'a>,
+{
+    fn alarm(&self) {
+        let now = self.alarm.now();
+        if expired(self.rx_timer.get(), now) {
+            self.abort_receiving();
+        }
+        if expired(self.tx_timer.get(), now) {
+            self.tx_timer.set(None);
+            match self.tx_state.get() {
+                TxState::WaitFlowControl => self.finish_sending(Err(ErrorCode::NOACK)),
+                TxState::WaitSeparation => {
+                    self.tx_state.set(TxState::Ready);
+                    self.send_pending_frame();
+                }
+                TxState::Idle | TxState::Sending | TxState::Ready => {}
+            }
+        }
+        self.arm_alarm();
+    }
+}
+
+impl<'a, C, A> can::ControllerClient for IsoTp<'a, C, A>
+where
+    C: can::Transmit<FRAME_LEN> + can::Receive<FRAME_LEN>,
+    A: Alarm<'a>,
+{
+    fn state_changed(&self, _state: can::State) {}
+
+    fn enabled(&self, status: Result<(), ErrorCode>) {
+        if status.is_ok() {
+            self.start_receiving();
+        }
+    }
+
+    fn disabled(&self, _status: Result<(), ErrorCode>) {}
+}
+
+impl<'a, C, A> can::TransmitClient<FRAME_LEN> for IsoTp<'a, C, A>
+where
+    C: can::Transmit<FRAME_LEN> + can::Receive<FRAME_LEN>,
+    A: Alarm<'a>,
+{
+    fn transmit_complete(
+        &self,
+        status: Result<(), can::Error>,
+        buffer: &'static mut [u8; FRAME_LEN],
+    ) {
+        self.tx_frame.replace(buffer);
+        if !self.sending_flow_control.replace(false) && self.tx_state.get() == TxState::Sending {
+            self.data_frame_sent(status);
+        }
+        self.send_pending_frame();
+    }
+}
+
+impl<'a, C, A> can::ReceiveClient<FRAME_LEN> for IsoTp<'a, C, A>
+where
+    C: can::Transmit<FRAME_LEN> + can::Receive<FRAME_LEN>,
+    A: Alarm<'a>,
+{
+    fn message_received(
+        &self,
+        id: can::Id,
+        buffer: &mut [u8; FRAME_LEN],
+        len: usize,
+        status: Result<(), can::Error>,
+    ) {
+        let addressed = match (id, self.rx_id) {
+            (can::Id::Standard(id), can::Id::Standard(rx_id)) => id == rx_id,
+            (can::Id::Extended(id), can::Id::Extended(rx_id)) => id == rx_id,
+            _ => false,
+        };
+        if status.is_err() || !addressed || len == 0 {
+            return;
+        }
+        let frame = &buffer[..len.min(FRAME_LEN)];
+        match frame[0] & 0xF0 {
+            SINGLE_FRAME => self.single_frame_received(frame),
+            FIRST_FRAME if frame.len() >= 2 => self.first_frame_received(frame),
+            CONSECUTIVE_FRAME => self.consecutive_frame_received(frame),
+            FLOW_CONTROL => self.flow_control_received(frame),
+            _ => {}
+        }
+    }
+
+    fn stopped(&self, buffer: &'static mut [u8; FRAME_LEN]) {
+        self.rx_frame.replace(buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kernel::hil::time::Ticks32;
+
+    #[test]
+    fn timers_expire_across_wrap() {
+        let timer = Some((Ticks32::from(0xFFFF_FFF0), Ticks32::from(0x20)));
+        assert!(!expired(timer, Ticks32::from(0xFFFF_FFFF)));
+        assert!(!expired(timer, Ticks32::from(0x0000_000F)));
+        assert!(expired(timer, Ticks32::from(0x0000_0010)));
+        assert!(!expired(None, Ticks32::from(0)));
+    }
+}
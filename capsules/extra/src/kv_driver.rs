@@ -8,6 +8,10 @@
 //! `StoragePermissions` so processes must have the required permissions in
 //! their TBF headers to use this interface.
 //!
+//! Processes can also enumerate the keys they are allowed to read and query
+//! the storage usage of the store. Keys are returned as stored, so for a
+//! hashed store such as TicKV the hashed key is returned.
+//!
 //! ```rust,ignore
 //! +===============+
 //! ||  Userspace  ||
@@ -44,6 +48,7 @@ use capsules_core::driver;
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = driver::NUM::Kv as usize;
 
+use core::cell::Cell;
 use core::cmp;
 use kernel::errorcode;
 use kernel::grant::Grant;
@@ -67,10 +72,12 @@ mod ro_allow {
 
 /// IDs for read-write allow buffers.
 mod rw_allow {
-    /// Output value for get.
+    /// Output value for get and next key, output statistics for stats.
     pub const VALUE: usize = 0;
+    /// Output key for next key.
+    pub const KEY: usize = 1;
     /// The number of RW allow buffers the kernel stores for this grant.
-    pub const COUNT: u8 = 1;
+    pub const COUNT: u8 = 2;
 }
 
 /// IDs for upcalls.
@@ -89,12 +96,16 @@ enum UserSpaceOp {
     Add,
     Update,
     GarbageCollect,
+    NextKey,
+    Stats,
 }
 
 /// Contents of the grant for each app.
 #[derive(Default)]
 pub struct App {
     op: OptionalCell<UserSpaceOp>,
+    /// Where the next key enumeration continues from.
+    cursor: Cell<usize>,
 }
 
 /// Capsule that provides userspace access to a key-value store.
@@ -141,8 +152,13 @@ impl<'a, V: kv::KVPermissions<'a>> KVStoreDriver<'a, V> {
         self.processid.map_or(Err(ErrorCode::RESERVE), |processid| {
             self.apps
                 .enter(processid, |app, kernel_data| {
-                    let key_len = if app.op.is_some() {
-                        // For all operations we need to copy in the key.
+                    let key_len = if app.op.get().is_some_and(|op| {
+                        op != UserSpaceOp::GarbageCollect
+                            && op != UserSpaceOp::NextKey
+                            && op != UserSpaceOp::Stats
+                    }) {
+                        // For all operations on a key we need to copy in the
+                        // key.
                         kernel_data
                             .get_readonly_processbuffer(ro_allow::KEY)
                             .and_then(|buffer| {
@@ -271,6 +287,43 @@ impl<'a, V: kv::KVPermissions<'a>> KVStoreDriver<'a, V> {
                             self.kv.garbage_collect()?;
                             return Ok(());
                         }
+                        Some(UserSpaceOp::NextKey) => {
+                            if let Some(Some(e)) = self.key_buffer.take().map(|key_buf| {
+                                self.value_buffer.take().map(|val_buf| {
+                                    let perms = match processid.get_storage_permissions() {
+                                        Some(perms) => perms,
+                                        None => {
+                                            self.key_buffer.replace(key_buf);
+                                            self.value_buffer.replace(val_buf);
+                                            return Err(ErrorCode::INVAL);
+                                        }
+                                    };
+
+                                    let key = SubSliceMut::new(key_buf);
+                                    let value = SubSliceMut::new(val_buf);
+
+                                    if let Err((key_ret, val_ret, e)) =
+                                        self.kv.next_key(app.cursor.get(), key, value, perms)
+                                    {
+                                        self.key_buffer.replace(key_ret.take());
+                                        self.value_buffer.replace(val_ret.take());
+                                        return Err(e);
+                                    }
+                                    Ok(())
+                                })
+                            }) {
+                                return e;
+                            }
+                        }
+                        Some(UserSpaceOp::Stats) => {
+                            // Only processes with storage permissions can see
+                            // the storage usage.
+                            processid
+                                .get_storage_permissions()
+                                .ok_or(ErrorCode::INVAL)?;
+                            self.kv.get_stats()?;
+                            return Ok(());
+                        }
 
                         _ => {}
                     }
@@ -489,13 +542,139 @@ impl<'a, V: kv::KVPermissions<'a>> kv::KVClient for KVStoreDriver<'a, V> {
         self.processid.clear();
         self.check_queue();
     }
+
+    fn next_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        cursor: usize,
+        mut key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.processid.map(|id| {
+            self.apps.enter(id, |app, upcalls| {
+                if app.op.contains(&UserSpaceOp::NextKey) {
+                    app.op.clear();
+
+                    match result {
+                        Ok(()) | Err(ErrorCode::SIZE) => {
+                            // A key was found, even if all of the value did
+                            // not fit. Continue after it next time.
+                            app.cursor.set(cursor);
+
+                            let key_len = key.len();
+                            let key_ret = upcalls
+                                .get_readwrite_processbuffer(rw_allow::KEY)
+                                .and_then(|buffer| {
+                                    buffer.mut_enter(|appslice| {
+                                        if appslice.len() < key_len {
+                                            Err(ErrorCode::SIZE)
+                                        } else {
+                                            appslice[..key_len].copy_from_slice(key.as_slice());
+                                            Ok(())
+                                        }
+                                    })
+                                })
+                                .unwrap_or(Err(ErrorCode::RESERVE));
+
+                            let value_len = value.len();
+                            let value_ret = upcalls
+                                .get_readwrite_processbuffer(rw_allow::VALUE)
+                                .and_then(|buffer| {
+                                    buffer.mut_enter(|appslice| {
+                                        let copy_len = cmp::min(value_len, appslice.len());
+                                        appslice[..copy_len].copy_from_slice(&value[..copy_len]);
+                                        if copy_len < value_len {
+                                            Err(ErrorCode::SIZE)
+                                        } else {
+                                            Ok(())
+                                        }
+                                    })
+                                })
+                                .unwrap_or(Err(ErrorCode::RESERVE));
+
+                            // Return the length of the value and the key. As
+                            // with get, userspace should only read the portion
+                            // of the value that fits if `SIZE` is returned.
+                            let ret = result.and(key_ret).and(value_ret);
+                            upcalls
+                                .schedule_upcall(
+                                    upcalls::VALUE,
+                                    (errorcode::into_statuscode(ret), value_len, key_len),
+                                )
+                                .ok();
+                        }
+                        Err(e) => {
+                            upcalls
+                                .schedule_upcall(
+                                    upcalls::VALUE,
+                                    (errorcode::into_statuscode(Err(e)), 0, 0),
+                                )
+                                .ok();
+                        }
+                    }
+                }
+            })
+        });
+
+        self.key_buffer.replace(key.take());
+        self.value_buffer.replace(value.take());
+
+        // We have completed the operation so see if there is a queued operation
+        // to run next.
+        self.processid.clear();
+        self.check_queue();
+    }
+
+    fn stats_complete(&self, result: Result<kv::KVStats, ErrorCode>) {
+        self.processid.map(move |id| {
+            self.apps.enter(id, move |app, upcalls| {
+                if app.op.contains(&UserSpaceOp::Stats) {
+                    app.op.clear();
+
+                    // The statistics are returned as four little endian u32
+                    // values: keys, used, reclaimable and free.
+                    let ret = result.and_then(|stats| {
+                        upcalls
+                            .get_readwrite_processbuffer(rw_allow::VALUE)
+                            .and_then(|buffer| {
+                                buffer.mut_enter(|appslice| {
+                                    if appslice.len() < 16 {
+                                        return Err(ErrorCode::SIZE);
+                                    }
+                                    for (i, field) in
+                                        [stats.keys, stats.used, stats.reclaimable, stats.free]
+                                            .iter()
+                                            .enumerate()
+                                    {
+                                        appslice[i * 4..(i + 1) * 4]
+                                            .copy_from_slice(&(*field as u32).to_le_bytes());
+                                    }
+                                    Ok(())
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    });
+
+                    let len = if ret.is_ok() { 16 } else { 0 };
+                    upcalls
+                        .schedule_upcall(upcalls::VALUE, (errorcode::into_statuscode(ret), len, 0))
+                        .ok();
+                }
+            })
+        });
+
+        // We have completed the operation so see if there is a queued operation
+        // to run next.
+        self.processid.clear();
+        self.check_queue();
+    }
 }
 
 impl<'a, V: kv::KVPermissions<'a>> SyscallDriver for KVStoreDriver<'a, V> {
     fn command(
         &self,
         command_num: usize,
-        _data1: usize,
+        data1: usize,
         _data2: usize,
         processid: ProcessId,
     ) -> CommandReturn {
@@ -503,8 +682,11 @@ impl<'a, V: kv::KVPermissions<'a>> SyscallDriver for KVStoreDriver<'a, V> {
             // check if present
             0 => CommandReturn::success(),
 
-            // get, set, delete, add, update, garbage collect
-            1 | 2 | 3 | 4 | 5 | 6 => {
+            // get, set, delete, add, update, garbage collect, next key, stats
+            //
+            // For next key, `data1` of 0 restarts the enumeration from the
+            // first key, any other value continues after the last key found.
+            1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 => {
                 if self.processid.is_none() {
                     // Nothing is using the KV store, so we can handle this
                     // request.
@@ -516,6 +698,13 @@ impl<'a, V: kv::KVPermissions<'a>> SyscallDriver for KVStoreDriver<'a, V> {
                         4 => app.op.set(UserSpaceOp::Add),
                         5 => app.op.set(UserSpaceOp::Update),
                         6 => app.op.set(UserSpaceOp::GarbageCollect),
+                        7 => {
+                            if data1 == 0 {
+                                app.cursor.set(0);
+                            }
+                            app.op.set(UserSpaceOp::NextKey)
+                        }
+                        8 => app.op.set(UserSpaceOp::Stats),
                         _ => {}
                     });
                     let ret = self.run();
@@ -546,6 +735,13 @@ impl<'a, V: kv::KVPermissions<'a>> SyscallDriver for KVStoreDriver<'a, V> {
                                     4 => app.op.set(UserSpaceOp::Add),
                                     5 => app.op.set(UserSpaceOp::Update),
                                     6 => app.op.set(UserSpaceOp::GarbageCollect),
+                                    7 => {
+                                        if data1 == 0 {
+                                            app.cursor.set(0);
+                                        }
+                                        app.op.set(UserSpaceOp::NextKey)
+                                    }
+                                    8 => app.op.set(UserSpaceOp::Stats),
                                     _ => {}
                                 }
                                 CommandReturn::success()
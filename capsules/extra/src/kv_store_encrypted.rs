@@ -0,0 +1,1181 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Key-Value store layer that encrypts and authenticates values.
+//!
+//! This capsule implements `hil::kv::KV` on top of another `hil::kv::KV` and
+//! encrypts every value with AES-128-CCM before it is stored, so values cannot
+//! be read from a dump of the flash. Every stored value gets a new random
+//! nonce, and the key is used as associated data, so a value that was modified
+//! or copied to another key is detected on `get()`, which then fails with
+//! `FAIL`.
+//!
+//! The AES key is the device key, provided by the board through the
+//! `DeviceKey` trait. Keys are stored in plaintext.
+//!
+//! Keys cannot be enumerated through this layer, as enumeration only returns
+//! the stored (hashed) keys and a value can only be authenticated with the
+//! original key.
+//!
+//! ```text
+//! +-----------------------+
+//! |  Capsule using K-V    |
+//! +-----------------------+
+//!
+//!    hil::kv::KV
+//!
+//! +-----------------------+
+//! | K-V store (this file) |
+//! +-----------------------+
+//!
+//!    hil::kv::KV
+//!
+//! +-----------------------+
+//! |  K-V library          |
+//! +-----------------------+
+//!
+//!    hil::flash
+//! ```
+//!
+//! Usually this sits between `KVStorePermissions` and `TicKVKVStore`, so the
+//! permission header of every value is encrypted as well.
+//!
+//! Stored values have the following format:
+//!
+//! ```text
+//! +---------+--------+-------+------------+-----+
+//! | version | length | nonce | ciphertext | MIC |
+//! +---------+--------+-------+------------+-----+
+//! ```
+//!
+//! `version` is one byte, `length` is the length of the ciphertext as a
+//! little endian u32, `nonce` is `CCM_NONCE_LENGTH` bytes and the MIC is
+//! `MIC_LENGTH` bytes.
+//!
+//! The buffer passed to `new()` holds the key and value while they are
+//! encrypted or decrypted. It must be at least `max(key length,
+//! HEADER_LENGTH) + value length + MIC_LENGTH` bytes long; larger keys and
+//! values are rejected with `SIZE`.
+//!
+//! The AES-CCM implementation must be able to process the whole buffer at
+//! once. With `VirtualAES128CCM`, its crypt buffer must hold `BUF_LEN +
+//! MIC_LENGTH` bytes. The crypt buffers of `AES128CCMComponent` are sized for
+//! radio frames (112 bytes), so with those values longer than about 100
+//! bytes fail.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::hil::kv;
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, AES128_KEY_SIZE, CCM_NONCE_LENGTH};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::SubSliceMut;
+use kernel::ErrorCode;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operation {
+    Get,
+    Set,
+    Add,
+    Update,
+    Delete,
+    GarbageCollect,
+    GetStats,
+}
+
+/// Current version of the encrypted value format.
+const HEADER_VERSION: u8 = 0;
+/// Length of the header stored in front of the ciphertext.
+pub const HEADER_LENGTH: usize = 1 + 4 + CCM_NONCE_LENGTH;
+/// Length of the message integrity code stored after the ciphertext.
+pub const MIC_LENGTH: usize = 16;
+/// Buffer length that fits the keys and values of the userspace KV driver,
+/// including the permission header.
+pub const BUF_LEN: usize = 608;
+
+/// Source of the device key that values are encrypted with.
+pub trait DeviceKey {
+    /// Returns the AES-128 key, or `None` if it is not available.
+    fn device_key(&self) -> Option<[u8; AES128_KEY_SIZE]>;
+}
+
+impl DeviceKey for [u8; AES128_KEY_SIZE] {
+    fn device_key(&self) -> Option<[u8; AES128_KEY_SIZE]> {
+        Some(*self)
+    }
+}
+
+/// `KVStoreEncrypted` implements `KV` on top of `KV`, encrypting values with
+/// AES-CCM.
+pub struct KVStoreEncrypted<'a, K: kv::KV<'a>, A: AES128CCM<'a>> {
+    kv: &'a K,
+    aes: &'a A,
+    rng: &'a dyn rng::Rng<'a>,
+    device_key: &'a dyn DeviceKey,
+    crypt_buf: TakeCell<'static, [u8]>,
+
+    client: OptionalCell<&'a dyn kv::KVClient>,
+    operation: OptionalCell<Operation>,
+
+    key: MapCell<SubSliceMut<'static, u8>>,
+    value: MapCell<SubSliceMut<'static, u8>>,
+    nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
+    // Number of random bytes in `nonce` so far.
+    nonce_len: Cell<usize>,
+    key_len: Cell<usize>,
+    value_len: Cell<usize>,
+}
+
+impl<'a, K: kv::KV<'a>, A: AES128CCM<'a>> KVStoreEncrypted<'a, K, A> {
+    pub fn new(
+        kv: &'a K,
+        aes: &'a A,
+        rng: &'a dyn rng::Rng<'a>,
+        device_key: &'a dyn DeviceKey,
+        crypt_buf: &'static mut [u8],
+    ) -> KVStoreEncrypted<'a, K, A> {
+        Self {
+            kv,
+            aes,
+            rng,
+            device_key,
+            crypt_buf: TakeCell::new(crypt_buf),
+            client: OptionalCell::empty(),
+            operation: OptionalCell::empty(),
+            key: MapCell::empty(),
+            value: MapCell::empty(),
+            nonce: Cell::new([0; CCM_NONCE_LENGTH]),
+            nonce_len: Cell::new(0),
+            key_len: Cell::new(0),
+            value_len: Cell::new(0),
+        }
+    }
+
+    fn insert(
+        &self,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+        operation: Operation,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        if self.operation.is_some() {
+            return Err((key, value, ErrorCode::BUSY));
+        }
+
+        let needed = cmp::max(key.len(), HEADER_LENGTH) + value.len() + MIC_LENGTH;
+        if self.crypt_buf.map_or(0, |buf| buf.len()) < needed {
+            return Err((key, value, ErrorCode::SIZE));
+        }
+
+        // Get a fresh nonce first, the value is encrypted once it arrives.
+        self.operation.set(operation);
+        self.nonce_len.set(0);
+        self.key_len.set(key.len());
+        self.value_len.set(value.len());
+        self.key.replace(key);
+        self.value.replace(value);
+
+        match self.rng.get() {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                self.operation.clear();
+                match (self.key.take(), self.value.take()) {
+                    (Some(key), Some(value)) => Err((key, value, ErrorCode::FAIL)),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Sets the device key and the nonce of the current operation on the AES
+    /// engine.
+    fn configure_aes(&self) -> Result<(), ErrorCode> {
+        let device_key = self.device_key.device_key().ok_or(ErrorCode::FAIL)?;
+        self.aes.set_key(&device_key)?;
+        self.aes.set_nonce(&self.nonce.get())
+    }
+
+    /// Copies the key and value into the crypt buffer and starts encrypting
+    /// them.
+    fn encrypt(&self) -> Result<(), ErrorCode> {
+        self.configure_aes()?;
+        let buf = self.crypt_buf.take().ok_or(ErrorCode::FAIL)?;
+        let key_len = self.key_len.get();
+        let value_len = self.value_len.get();
+        self.key.map(|key| {
+            buf[..key_len].copy_from_slice(key.as_slice());
+        });
+        self.value.map(|value| {
+            buf[key_len..key_len + value_len].copy_from_slice(value.as_slice());
+        });
+        self.aes
+            .crypt(buf, 0, key_len, value_len, MIC_LENGTH, true, true)
+            .map_err(|(e, buf)| {
+                self.crypt_buf.replace(buf);
+                e
+            })
+    }
+
+    /// Checks the header of a stored value and starts decrypting it, using
+    /// `key` as the associated data.
+    fn decrypt(
+        &self,
+        key: &[u8],
+        buf: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if buf.len() < HEADER_LENGTH || buf[0] != HEADER_VERSION {
+            return Err((ErrorCode::FAIL, buf));
+        }
+        let value_len = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+        // The length comes from storage, so it must not wrap around.
+        let fits = cmp::max(key.len(), HEADER_LENGTH)
+            .checked_add(value_len)
+            .and_then(|len| len.checked_add(MIC_LENGTH))
+            .is_some_and(|len| len <= buf.len());
+        if !fits {
+            return Err((ErrorCode::FAIL, buf));
+        }
+        let mut nonce = [0; CCM_NONCE_LENGTH];
+        nonce.copy_from_slice(&buf[5..HEADER_LENGTH]);
+        self.nonce.set(nonce);
+        self.key_len.set(key.len());
+        self.value_len.set(value_len);
+
+        if let Err(e) = self.configure_aes() {
+            return Err((e, buf));
+        }
+
+        // Put the key in front of the ciphertext as associated data.
+        buf.copy_within(
+            HEADER_LENGTH..HEADER_LENGTH + value_len + MIC_LENGTH,
+            key.len(),
+        );
+        buf[..key.len()].copy_from_slice(key);
+        self.aes
+            .crypt(buf, 0, key.len(), value_len, MIC_LENGTH, true, false)
+    }
+
+    /// Stores the encrypted value in `buf` in the underlying KV store.
+    fn store(&self, buf: &'static mut [u8]) -> Result<(), ErrorCode> {
+        let key_len = self.key_len.get();
+        let value_len = self.value_len.get();
+
+        // Replace the associated data with the header.
+        buf.copy_within(key_len..key_len + value_len + MIC_LENGTH, HEADER_LENGTH);
+        buf[0] = HEADER_VERSION;
+        buf[1..5].copy_from_slice(&(value_len as u32).to_le_bytes());
+        buf[5..HEADER_LENGTH].copy_from_slice(&self.nonce.get());
+
+        let mut stored = SubSliceMut::new(buf);
+        stored.slice(..HEADER_LENGTH + value_len + MIC_LENGTH);
+
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => {
+                self.crypt_buf.replace(stored.take());
+                return Err(ErrorCode::FAIL);
+            }
+        };
+        let res = match self.operation.get() {
+            Some(Operation::Set) => self.kv.set(key, stored),
+            Some(Operation::Add) => self.kv.add(key, stored),
+            Some(Operation::Update) => self.kv.update(key, stored),
+            _ => Err((key, stored, ErrorCode::FAIL)),
+        };
+        res.map_err(|(key, stored, e)| {
+            self.key.replace(key);
+            self.crypt_buf.replace(stored.take());
+            e
+        })
+    }
+
+    /// Finishes a set, add or update operation and calls the client.
+    fn insert_complete(&self, result: Result<(), ErrorCode>) {
+        let operation = self.operation.take();
+        if let (Some(key), Some(value)) = (self.key.take(), self.value.take()) {
+            self.client.map(move |cb| match operation {
+                Some(Operation::Set) => cb.set_complete(result, key, value),
+                Some(Operation::Add) => cb.add_complete(result, key, value),
+                Some(Operation::Update) => cb.update_complete(result, key, value),
+                _ => {}
+            });
+        }
+    }
+
+    /// Finishes a get operation and calls the client.
+    fn get_complete_with(&self, result: Result<(), ErrorCode>, key: SubSliceMut<'static, u8>) {
+        self.operation.clear();
+        if let Some(mut value) = self.value.take() {
+            if result.is_err() {
+                value.as_slice().iter_mut().for_each(|m| *m = 0);
+            }
+            self.client.map(move |cb| {
+                cb.get_complete(result, key, value);
+            });
+        }
+    }
+}
+
+impl<'a, K: kv::KV<'a>, A: AES128CCM<'a>> kv::KV<'a> for KVStoreEncrypted<'a, K, A> {
+    fn set_client(&self, client: &'a dyn kv::KVClient) {
+        self.client.set(client);
+    }
+
+    fn get(
+        &self,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        if self.operation.is_some() {
+            return Err((key, value, ErrorCode::BUSY));
+        }
+        let Some(buf) = self.crypt_buf.take() else {
+            return Err((key, value, ErrorCode::FAIL));
+        };
+
+        self.operation.set(Operation::Get);
+        self.value.replace(value);
+
+        // Read the encrypted value into our own buffer, it is only copied to
+        // the caller's buffer once it has been authenticated.
+        match self.kv.get(key, SubSliceMut::new(buf)) {
+            Ok(()) => Ok(()),
+            Err((key, buf, e)) => {
+                self.crypt_buf.replace(buf.take());
+                self.operation.clear();
+                match self.value.take() {
+                    Some(value) => Err((key, value, e)),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn set(
+        &self,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        self.insert(key, value, Operation::Set)
+    }
+
+    fn add(
+        &self,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        self.insert(key, value, Operation::Add)
+    }
+
+    fn update(
+        &self,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        self.insert(key, value, Operation::Update)
+    }
+
+    fn delete(
+        &self,
+        key: SubSliceMut<'static, u8>,
+    ) -> Result<(), (SubSliceMut<'static, u8>, ErrorCode)> {
+        if self.operation.is_some() {
+            return Err((key, ErrorCode::BUSY));
+        }
+
+        self.operation.set(Operation::Delete);
+
+        self.kv.delete(key).inspect_err(|_| {
+            self.operation.clear();
+        })
+    }
+
+    fn garbage_collect(&self) -> Result<(), ErrorCode> {
+        if self.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.operation.set(Operation::GarbageCollect);
+
+        self.kv.garbage_collect().inspect_err(|_| {
+            self.operation.clear();
+        })
+    }
+
+    fn next_key(
+        &self,
+        _cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        Err((key, value, ErrorCode::NOSUPPORT))
+    }
+
+    fn get_stats(&self) -> Result<(), ErrorCode> {
+        if self.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.operation.set(Operation::GetStats);
+
+        self.kv.get_stats().inspect_err(|_| {
+            self.operation.clear();
+        })
+    }
+}
+
+impl<'a, K: kv::KV<'a>, A: AES128CCM<'a>> rng::Client for KVStoreEncrypted<'a, K, A> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: Result<(), ErrorCode>,
+    ) -> rng::Continue {
+        match self.operation.get() {
+            Some(Operation::Set) | Some(Operation::Add) | Some(Operation::Update) => {}
+            _ => return rng::Continue::Done,
+        }
+        if error.is_err() {
+            self.insert_complete(Err(ErrorCode::FAIL));
+            return rng::Continue::Done;
+        }
+
+        let mut nonce = self.nonce.get();
+        let mut filled = self.nonce_len.get();
+        while filled < CCM_NONCE_LENGTH {
+            let Some(random) = randomness.next() else {
+                break;
+            };
+            for byte in random.to_le_bytes() {
+                if filled < CCM_NONCE_LENGTH {
+                    nonce[filled] = byte;
+                    filled += 1;
+                }
+            }
+        }
+        self.nonce.set(nonce);
+        self.nonce_len.set(filled);
+        if filled < CCM_NONCE_LENGTH {
+            return rng::Continue::More;
+        }
+
+        if let Err(e) = self.encrypt() {
+            self.insert_complete(Err(e));
+        }
+        rng::Continue::Done
+    }
+}
+
+impl<'a, K: kv::KV<'a>, A: AES128CCM<'a>> CCMClient for KVStoreEncrypted<'a, K, A> {
+    fn crypt_done(&self, buf: &'static mut [u8], res: Result<(), ErrorCode>, tag_is_valid: bool) {
+        match self.operation.get() {
+            Some(Operation::Get) => {
+                let key_len = self.key_len.get();
+                let value_len = self.value_len.get();
+                let mut result = Err(ErrorCode::FAIL);
+                if res.is_ok() && tag_is_valid {
+                    self.value.map(|value| {
+                        let len = cmp::min(value.len(), value_len);
+                        value.as_slice()[..len].copy_from_slice(&buf[key_len..key_len + len]);
+                        result = if len < value_len {
+                            Err(ErrorCode::SIZE)
+                        } else {
+                            Ok(())
+                        };
+                    });
+                }
+                // Do not leave the plaintext in the buffer.
+                buf.iter_mut().for_each(|b| *b = 0);
+                self.crypt_buf.replace(buf);
+                if let Some(key) = self.key.take() {
+                    self.get_complete_with(result, key);
+                }
+            }
+            Some(Operation::Set) | Some(Operation::Add) | Some(Operation::Update) => {
+                if res.is_err() {
+                    self.crypt_buf.replace(buf);
+                    self.insert_complete(Err(ErrorCode::FAIL));
+                } else if let Err(e) = self.store(buf) {
+                    self.insert_complete(Err(e));
+                }
+            }
+            _ => {
+                self.crypt_buf.replace(buf);
+            }
+        }
+    }
+}
+
+impl<'a, K: kv::KV<'a>, A: AES128CCM<'a>> kv::KVClient for KVStoreEncrypted<'a, K, A> {
+    fn get_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        mut key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        let buf = value.take();
+        match result {
+            Ok(()) => match self.decrypt(key.as_slice(), buf) {
+                Ok(()) => {
+                    self.key.replace(key);
+                }
+                Err((e, buf)) => {
+                    self.crypt_buf.replace(buf);
+                    self.get_complete_with(Err(e), key);
+                }
+            },
+            Err(e) => {
+                self.crypt_buf.replace(buf);
+                // A value that does not fit our buffer cannot be
+                // authenticated.
+                let e = if e == ErrorCode::SIZE {
+                    ErrorCode::FAIL
+                } else {
+                    e
+                };
+                self.get_complete_with(Err(e), key);
+            }
+        }
+    }
+
+    fn set_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.crypt_buf.replace(value.take());
+        self.key.replace(key);
+        self.insert_complete(result);
+    }
+
+    fn add_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.crypt_buf.replace(value.take());
+        self.key.replace(key);
+        self.insert_complete(result);
+    }
+
+    fn update_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.crypt_buf.replace(value.take());
+        self.key.replace(key);
+        self.insert_complete(result);
+    }
+
+    fn delete_complete(&self, result: Result<(), ErrorCode>, key: SubSliceMut<'static, u8>) {
+        self.operation.clear();
+        self.client.map(move |cb| {
+            cb.delete_complete(result, key);
+        });
+    }
+
+    fn garbage_collection_complete(&self, result: Result<(), ErrorCode>) {
+        self.operation.clear();
+        self.client.map(move |cb| {
+            cb.garbage_collection_complete(result);
+        });
+    }
+
+    fn next_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _cursor: usize,
+        _key: SubSliceMut<'static, u8>,
+        _value: SubSliceMut<'static, u8>,
+    ) {
+    }
+
+    fn stats_complete(&self, result: Result<kv::KVStats, ErrorCode>) {
+        self.operation.clear();
+        self.client.map(move |cb| {
+            cb.stats_complete(result);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    use kernel::hil::kv::{KVClient, KV};
+    use kernel::hil::rng::{self, Rng};
+    use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, AES128_KEY_SIZE};
+    use kernel::utilities::cells::{OptionalCell, TakeCell};
+    use kernel::utilities::leasable_buffer::SubSliceMut;
+    use kernel::ErrorCode;
+
+    use super::{DeviceKey, KVStoreEncrypted, CCM_NONCE_LENGTH, HEADER_LENGTH, MIC_LENGTH};
+
+    type Store = KVStoreEncrypted<'static, MockKv, MockCcm>;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn leak_buf(len: usize) -> &'static mut [u8] {
+        Box::leak(std::vec![0; len].into_boxed_slice())
+    }
+
+    fn buf_from(data: &[u8]) -> SubSliceMut<'static, u8> {
+        let buf = leak_buf(data.len());
+        buf.copy_from_slice(data);
+        SubSliceMut::new(buf)
+    }
+
+    /// KV store that keeps values in memory and completes operations when
+    /// `run()` is called.
+    #[derive(Default)]
+    struct MockKv {
+        values: RefCell<BTreeMap<Vec<u8>, Vec<u8>>>,
+        pending: RefCell<Option<(bool, SubSliceMut<'static, u8>, SubSliceMut<'static, u8>)>>,
+        client: OptionalCell<&'static dyn KVClient>,
+    }
+
+    impl MockKv {
+        fn run(&self) -> bool {
+            let Some((is_get, key, mut value)) = self.pending.take() else {
+                return false;
+            };
+            let client = self.client.get().unwrap();
+            if is_get {
+                let stored = self.values.borrow().get(&key[..]).cloned();
+                match stored {
+                    Some(stored) if stored.len() <= value.len() => {
+                        value.as_slice()[..stored.len()].copy_from_slice(&stored);
+                        client.get_complete(Ok(()), key, value);
+                    }
+                    Some(_) => client.get_complete(Err(ErrorCode::SIZE), key, value),
+                    None => client.get_complete(Err(ErrorCode::NOSUPPORT), key, value),
+                }
+            } else {
+                self.values
+                    .borrow_mut()
+                    .insert(key[..].to_vec(), value[..].to_vec());
+                client.set_complete(Ok(()), key, value);
+            }
+            true
+        }
+    }
+
+    impl KV<'static> for MockKv {
+        fn set_client(&self, client: &'static dyn KVClient) {
+            self.client.set(client);
+        }
+
+        fn get(
+            &self,
+            key: SubSliceMut<'static, u8>,
+            value: SubSliceMut<'static, u8>,
+        ) -> Result<
+            (),
+            (
+                SubSliceMut<'static, u8>,
+                SubSliceMut<'static, u8>,
+                ErrorCode,
+            ),
+        > {
+            self.pending.replace(Some((true, key, value)));
+            Ok(())
+        }
+
+        fn set(
+            &self,
+            key: SubSliceMut<'static, u8>,
+            value: SubSliceMut<'static, u8>,
+        ) -> Result<
+            (),
+            (
+                SubSliceMut<'static, u8>,
+                SubSliceMut<'static, u8>,
+                ErrorCode,
+            ),
+        > {
+            self.pending.replace(Some((false, key, value)));
+            Ok(())
+        }
+
+        fn add(
+            &self,
+            key: SubSliceMut<'static, u8>,
+            value: SubSliceMut<'static, u8>,
+        ) -> Result<
+            (),
+            (
+                SubSliceMut<'static, u8>,
+                SubSliceMut<'static, u8>,
+                ErrorCode,
+            ),
+        > {
+            Err((key, value, ErrorCode::NOSUPPORT))
+        }
+
+        fn update(
+            &self,
+            key: SubSliceMut<'static, u8>,
+            value: SubSliceMut<'static, u8>,
+        ) -> Result<
+            (),
+            (
+                SubSliceMut<'static, u8>,
+                SubSliceMut<'static, u8>,
+                ErrorCode,
+            ),
+        > {
+            Err((key, value, ErrorCode::NOSUPPORT))
+        }
+
+        fn delete(
+            &self,
+            key: SubSliceMut<'static, u8>,
+        ) -> Result<(), (SubSliceMut<'static, u8>, ErrorCode)> {
+            Err((key, ErrorCode::NOSUPPORT))
+        }
+
+        fn garbage_collect(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn next_key(
+            &self,
+            _cursor: usize,
+            key: SubSliceMut<'static, u8>,
+            value: SubSliceMut<'static, u8>,
+        ) -> Result<
+            (),
+            (
+                SubSliceMut<'static, u8>,
+                SubSliceMut<'static, u8>,
+                ErrorCode,
+            ),
+        > {
+            Err((key, value, ErrorCode::NOSUPPORT))
+        }
+
+        fn get_stats(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
+
+    /// Random number generator that returns a counter.
+    #[derive(Default)]
+    struct MockRng {
+        requested: Cell<bool>,
+        counter: Cell<u32>,
+        client: OptionalCell<&'static dyn rng::Client>,
+    }
+
+    impl MockRng {
+        fn run(&self) -> bool {
+            if !self.requested.take() {
+                return false;
+            }
+            let start = self.counter.get();
+            self.counter.set(start + 16);
+            let mut randomness = start..start + 16;
+            self.client
+                .map(|client| client.randomness_available(&mut randomness, Ok(())));
+            true
+        }
+    }
+
+    impl Rng<'static> for MockRng {
+        fn get(&self) -> Result<(), ErrorCode> {
+            self.requested.set(true);
+            Ok(())
+        }
+
+        fn cancel(&self) -> Result<(), ErrorCode> {
+            self.requested.set(false);
+            Ok(())
+        }
+
+        fn set_client(&'static self, client: &'static dyn rng::Client) {
+            self.client.set(client);
+        }
+    }
+
+    /// Stand-in for AES-CCM: a keyed stream cipher with a keyed checksum as
+    /// the MIC. Not secure, but any change to the key, nonce, associated data,
+    /// ciphertext or MIC is detected.
+    struct MockCcm {
+        key: Cell<[u8; AES128_KEY_SIZE]>,
+        nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
+        pending: TakeCell<'static, [u8]>,
+        // (a_off, m_off, m_len, mic_len, encrypting)
+        args: Cell<(usize, usize, usize, usize, bool)>,
+        client: OptionalCell<&'static dyn CCMClient>,
+    }
+
+    impl MockCcm {
+        fn new() -> MockCcm {
+            MockCcm {
+                key: Cell::new([0; AES128_KEY_SIZE]),
+                nonce: Cell::new([0; CCM_NONCE_LENGTH]),
+                pending: TakeCell::empty(),
+                args: Cell::new((0, 0, 0, 0, false)),
+                client: OptionalCell::empty(),
+            }
+        }
+
+        fn hash(&self, seed: u64, data: &[u8]) -> u64 {
+            let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+            let key = self.key.get();
+            let nonce = self.nonce.get();
+            for byte in key.iter().chain(nonce.iter()).chain(data.iter()) {
+                hash = (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+            }
+            hash
+        }
+
+        fn keystream(&self, i: usize) -> u8 {
+            self.hash(1, &(i as u64).to_le_bytes()) as u8
+        }
+
+        fn mic(&self, aad: &[u8], plaintext: &[u8]) -> [u8; MIC_LENGTH] {
+            let mut data = aad.to_vec();
+            data.push(0xff);
+            data.extend_from_slice(plaintext);
+            let mut mic = [0; MIC_LENGTH];
+            mic[..8].copy_from_slice(&self.hash(2, &data).to_le_bytes());
+            mic[8..].copy_from_slice(&self.hash(3, &data).to_le_bytes());
+            mic
+        }
+
+        fn run(&self) -> bool {
+            let Some(buf) = self.pending.take() else {
+                return false;
+            };
+            let (a_off, m_off, m_len, mic_len, encrypting) = self.args.get();
+            let keystream: Vec<u8> = (0..m_len).map(|i| self.keystream(i)).collect();
+            let message = &mut buf[m_off..m_off + m_len];
+            if !encrypting {
+                message
+                    .iter_mut()
+                    .zip(&keystream)
+                    .for_each(|(b, k)| *b ^= k);
+            }
+            let mic = self.mic(&buf[a_off..m_off], &buf[m_off..m_off + m_len]);
+            let message = &mut buf[m_off..m_off + m_len];
+            if encrypting {
+                message
+                    .iter_mut()
+                    .zip(&keystream)
+                    .for_each(|(b, k)| *b ^= k);
+            }
+            let stored_mic = &mut buf[m_off + m_len..m_off + m_len + mic_len];
+            let tag_is_valid = encrypting || stored_mic == &mic[..mic_len];
+            if encrypting {
+                stored_mic.copy_from_slice(&mic[..mic_len]);
+            }
+            self.client
+                .map(|client| client.crypt_done(buf, Ok(()), tag_is_valid));
+            true
+        }
+    }
+
+    impl AES128CCM<'static> for MockCcm {
+        fn set_client(&'static self, client: &'static dyn CCMClient) {
+            self.client.set(client);
+        }
+
+        fn set_key(&self, key: &[u8]) -> Result<(), ErrorCode> {
+            let mut k = [0; AES128_KEY_SIZE];
+            k.copy_from_slice(key);
+            self.key.set(k);
+            Ok(())
+        }
+
+        fn set_nonce(&self, nonce: &[u8]) -> Result<(), ErrorCode> {
+            let mut n = [0; CCM_NONCE_LENGTH];
+            n.copy_from_slice(nonce);
+            self.nonce.set(n);
+            Ok(())
+        }
+
+        fn crypt(
+            &self,
+            buf: &'static mut [u8],
+            a_off: usize,
+            m_off: usize,
+            m_len: usize,
+            mic_len: usize,
+            _confidential: bool,
+            encrypting: bool,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.args.set((a_off, m_off, m_len, mic_len, encrypting));
+            self.pending.replace(buf);
+            Ok(())
+        }
+    }
+
+    struct TestKey(Cell<[u8; AES128_KEY_SIZE]>);
+
+    impl DeviceKey for TestKey {
+        fn device_key(&self) -> Option<[u8; AES128_KEY_SIZE]> {
+            Some(self.0.get())
+        }
+    }
+
+    #[derive(Default)]
+    struct Client {
+        result: Cell<Option<Result<(), ErrorCode>>>,
+        value: RefCell<Vec<u8>>,
+    }
+
+    impl KVClient for Client {
+        fn get_complete(
+            &self,
+            result: Result<(), ErrorCode>,
+            _key: SubSliceMut<'static, u8>,
+            value: SubSliceMut<'static, u8>,
+        ) {
+            self.result.set(Some(result));
+            self.value.replace(value[..].to_vec());
+        }
+
+        fn set_complete(
+            &self,
+            result: Result<(), ErrorCode>,
+            _key: SubSliceMut<'static, u8>,
+            _value: SubSliceMut<'static, u8>,
+        ) {
+            self.result.set(Some(result));
+        }
+
+        fn add_complete(
+            &self,
+            _result: Result<(), ErrorCode>,
+            _key: SubSliceMut<'static, u8>,
+            _value: SubSliceMut<'static, u8>,
+        ) {
+        }
+
+        fn update_complete(
+            &self,
+            _result: Result<(), ErrorCode>,
+            _key: SubSliceMut<'static, u8>,
+            _value: SubSliceMut<'static, u8>,
+        ) {
+        }
+
+        fn delete_complete(&self, _result: Result<(), ErrorCode>, _key: SubSliceMut<'static, u8>) {}
+
+        fn garbage_collection_complete(&self, _result: Result<(), ErrorCode>) {}
+
+        fn next_key_complete(
+            &self,
+            _result: Result<(), ErrorCode>,
+            _cursor: usize,
+            _key: SubSliceMut<'static, u8>,
+            _value: SubSliceMut<'static, u8>,
+        ) {
+        }
+
+        fn stats_complete(&self, _result: Result<kernel::hil::kv::KVStats, ErrorCode>) {}
+    }
+
+    struct Test {
+        kv: &'static MockKv,
+        rng: &'static MockRng,
+        aes: &'static MockCcm,
+        device_key: &'static TestKey,
+        store: &'static Store,
+        client: &'static Client,
+    }
+
+    impl Test {
+        fn new() -> Test {
+            let kv = leak(MockKv::default());
+            let rng = leak(MockRng::default());
+            let aes = leak(MockCcm::new());
+            let device_key = leak(TestKey(Cell::new([0x42; AES128_KEY_SIZE])));
+            let store = leak(KVStoreEncrypted::new(
+                kv,
+                aes,
+                rng,
+                device_key,
+                leak_buf(64),
+            ));
+            let client = leak(Client::default());
+            kv.set_client(store);
+            rng.set_client(store);
+            aes.set_client(store);
+            store.set_client(client);
+            Test {
+                kv,
+                rng,
+                aes,
+                device_key,
+                store,
+                client,
+            }
+        }
+
+        fn run(&self) -> Option<Result<(), ErrorCode>> {
+            self.client.result.set(None);
+            while self.rng.run() || self.aes.run() || self.kv.run() {}
+            self.client.result.get()
+        }
+
+        fn set(&self, key: &[u8], value: &[u8]) -> Option<Result<(), ErrorCode>> {
+            assert!(self.store.set(buf_from(key), buf_from(value)).is_ok());
+            self.run()
+        }
+
+        fn get(&self, key: &[u8], len: usize) -> Option<Result<(), ErrorCode>> {
+            assert!(self
+                .store
+                .get(buf_from(key), SubSliceMut::new(leak_buf(len)))
+                .is_ok());
+            self.run()
+        }
+
+        fn modify_stored(&self, key: &[u8], f: impl FnOnce(&mut Vec<u8>)) {
+            f(self.kv.values.borrow_mut().get_mut(key).unwrap());
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"secret value"), Some(Ok(())));
+
+        let stored = test.kv.values.borrow().get(&b"key"[..]).cloned().unwrap();
+        assert_eq!(stored.len(), HEADER_LENGTH + 12 + MIC_LENGTH);
+        assert_eq!(&stored[1..5], &12u32.to_le_bytes());
+        assert!(!stored.windows(6).any(|w| w == b"secret"));
+
+        assert_eq!(test.get(b"key", 12), Some(Ok(())));
+        assert_eq!(&test.client.value.borrow()[..], b"secret value");
+
+        // A value that does not fit is not returned at all.
+        assert_eq!(test.get(b"key", 6), Some(Err(ErrorCode::SIZE)));
+        assert_eq!(&test.client.value.borrow()[..], &[0; 6]);
+    }
+
+    #[test]
+    fn nonce_changes_with_every_set() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        let first = test.kv.values.borrow().get(&b"key"[..]).cloned().unwrap();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        let second = test.kv.values.borrow().get(&b"key"[..]).cloned().unwrap();
+        assert_ne!(first[5..HEADER_LENGTH], second[5..HEADER_LENGTH]);
+        assert_ne!(first[HEADER_LENGTH..], second[HEADER_LENGTH..]);
+    }
+
+    #[test]
+    fn modified_ciphertext_is_rejected() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        test.modify_stored(b"key", |stored| stored[HEADER_LENGTH] ^= 1);
+        assert_eq!(test.get(b"key", 5), Some(Err(ErrorCode::FAIL)));
+        assert_eq!(&test.client.value.borrow()[..], &[0; 5]);
+    }
+
+    #[test]
+    fn modified_mic_is_rejected() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        test.modify_stored(b"key", |stored| *stored.last_mut().unwrap() ^= 0x80);
+        assert_eq!(test.get(b"key", 5), Some(Err(ErrorCode::FAIL)));
+    }
+
+    #[test]
+    fn value_moved_to_another_key_is_rejected() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        let stored = test.kv.values.borrow().get(&b"key"[..]).cloned().unwrap();
+        test.kv
+            .values
+            .borrow_mut()
+            .insert(b"other".to_vec(), stored);
+        assert_eq!(test.get(b"other", 5), Some(Err(ErrorCode::FAIL)));
+    }
+
+    #[test]
+    fn wrong_device_key_is_rejected() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        test.device_key.0.set([0x43; AES128_KEY_SIZE]);
+        assert_eq!(test.get(b"key", 5), Some(Err(ErrorCode::FAIL)));
+        test.device_key.0.set([0x42; AES128_KEY_SIZE]);
+        assert_eq!(test.get(b"key", 5), Some(Ok(())));
+    }
+
+    #[test]
+    fn oversized_value_length_is_rejected() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        for value_len in [6u32, 64, u32::MAX - MIC_LENGTH as u32, u32::MAX] {
+            test.modify_stored(b"key", |stored| {
+                stored[1..5].copy_from_slice(&value_len.to_le_bytes())
+            });
+            assert_eq!(test.get(b"key", 5), Some(Err(ErrorCode::FAIL)));
+        }
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let test = Test::new();
+        assert_eq!(test.set(b"key", b"value"), Some(Ok(())));
+        test.modify_stored(b"key", |stored| stored[0] = 1);
+        assert_eq!(test.get(b"key", 5), Some(Err(ErrorCode::FAIL)));
+    }
+}
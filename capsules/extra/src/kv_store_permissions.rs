@@ -41,6 +41,8 @@ enum Operation {
     Update,
     Delete,
     GarbageCollect,
+    NextKey,
+    GetStats,
 }
 
 /// Current version of the Tock K-V header.
@@ -299,6 +301,48 @@ impl<'a, K: kv::KV<'a>> kv::KVPermissions<'a> for KVStorePermissions<'a, K> {
         self.kv.garbage_collect()
     }
 
+    fn next_key(
+        &self,
+        cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+        permissions: StoragePermissions,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        if self.operation.is_some() {
+            return Err((key, value, ErrorCode::BUSY));
+        }
+
+        self.operation.set(Operation::NextKey);
+        self.valid_ids.set(permissions);
+
+        match self.kv.next_key(cursor, key, value) {
+            Ok(()) => Ok(()),
+            Err((key, val, e)) => {
+                self.operation.clear();
+                Err((key, val, e))
+            }
+        }
+    }
+
+    fn get_stats(&self) -> Result<(), ErrorCode> {
+        if self.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.operation.set(Operation::GetStats);
+
+        self.kv.get_stats().inspect_err(|_e| {
+            self.operation.clear();
+        })
+    }
+
     fn header_size(&self) -> usize {
         HEADER_LENGTH
     }
@@ -521,4 +565,59 @@ impl<'a, K: kv::KV<'a>> kv::KVClient for KVStorePermissions<'a, K> {
             cb.garbage_collection_complete(result);
         });
     }
+
+    fn next_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        cursor: usize,
+        mut key: SubSliceMut<'static, u8>,
+        mut value: SubSliceMut<'static, u8>,
+    ) {
+        let mut read_allowed = false;
+
+        if (result.is_ok() || result.err() == Some(ErrorCode::SIZE)) && value.len() >= HEADER_LENGTH
+        {
+            let header = KeyHeader::new_from_buf(value.as_slice());
+
+            if header.version == HEADER_VERSION {
+                self.valid_ids.map(|perms| {
+                    read_allowed = perms.check_read_permission(header.write_id);
+                });
+            }
+        }
+
+        if read_allowed {
+            // Remove the header from the accessible portion of the buffer.
+            value.slice(HEADER_LENGTH..);
+            self.operation.clear();
+            self.client.map(move |cb| {
+                cb.next_key_complete(result, cursor, key, value);
+            });
+        } else if result.is_ok() || result.err() == Some(ErrorCode::SIZE) {
+            // The caller can't read this object, zero the buffer and skip
+            // over it.
+            value.reset();
+            value.as_slice().iter_mut().for_each(|m| *m = 0);
+            key.reset();
+
+            if let Err((key, value, e)) = self.kv.next_key(cursor, key, value) {
+                self.operation.clear();
+                self.client.map(move |cb| {
+                    cb.next_key_complete(Err(e), cursor, key, value);
+                });
+            }
+        } else {
+            self.operation.clear();
+            self.client.map(move |cb| {
+                cb.next_key_complete(result, cursor, key, value);
+            });
+        }
+    }
+
+    fn stats_complete(&self, result: Result<kv::KVStats, ErrorCode>) {
+        self.operation.clear();
+        self.client.map(move |cb| {
+            cb.stats_complete(result);
+        });
+    }
 }
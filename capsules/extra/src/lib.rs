@@ -20,7 +20,9 @@ pub mod apds9960;
 pub mod app_flash_driver;
 pub mod at24c_eeprom;
 pub mod atecc508a;
+pub mod ble;
 pub mod ble_advertising_driver;
+pub mod ble_gatt_driver;
 pub mod bme280;
 pub mod bmm150;
 pub mod bmp280;
@@ -31,6 +33,8 @@ pub mod can;
 pub mod ccs811;
 pub mod chirp_i2c_moisture;
 pub mod crc;
+pub mod ctap_attestation;
+pub mod ctap_driver;
 pub mod cycle_count;
 pub mod dac;
 pub mod date_time;
@@ -38,6 +42,10 @@ pub mod debug_process_restart;
 pub mod dfrobot_rainfall_sensor;
 pub mod distance;
 pub mod eui64;
+pub mod fat;
+pub mod filesystem_driver;
+pub mod flash_block_storage;
+pub mod flash_fs;
 pub mod fm25cl;
 pub mod ft6x06;
 pub mod fxos8700cq;
@@ -51,11 +59,14 @@ pub mod hts221;
 pub mod humidity;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod isotp;
 pub mod kv_driver;
+pub mod kv_store_encrypted;
 pub mod kv_store_permissions;
 pub mod l3gd20;
 pub mod led_matrix;
 pub mod log;
+pub mod log_driver;
 pub mod lpm013m126;
 pub mod lps22hb;
 pub mod lps25hb;
@@ -80,6 +91,7 @@ pub mod proximity;
 pub mod public_key_crypto;
 pub mod pwm;
 pub mod rainfall;
+pub mod ram_disk;
 pub mod read_only_state;
 pub mod rf233;
 pub mod rf233_const;
@@ -105,9 +117,13 @@ pub mod temperature_rp2040;
 pub mod temperature_stm;
 pub mod text_screen;
 pub mod tickv;
+pub mod tickv_app_driver;
 pub mod tickv_kv_store;
 pub mod touch;
 pub mod tsl2561;
+pub mod uds_driver;
 pub mod usb;
 pub mod usb_hid_driver;
+pub mod virtual_can;
 pub mod virtual_kv;
+pub mod virtual_log;
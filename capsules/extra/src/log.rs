@@ -26,6 +26,12 @@
 //! of the entry within the page to find the position of the entry within the log (which is the
 //! ID). Entries also have a header of their own, which contains the length of the entry.
 //!
+//! The page header also contains a CRC32 of the rest of the page. Pages are always written to
+//! flash whole, so a page whose write or erase was interrupted by a power loss fails the check and
+//! is left out when the log is reconstructed. This only loses entries that were in the page being
+//! written. Note that a page that is synced before it is full is written again once more entries
+//! are added to it, so a power loss during that write also loses the entries synced to it before.
+//!
 //! Logs support the following basic operations:
 //!     * Read:     Read back previously written entries in whole. Entries are read in their
 //!                 entirety (no partial reads) from oldest to newest.
@@ -70,13 +76,18 @@ use kernel::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::hil::flash::{self, Flash};
 use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::helpers::crc32_posix;
 use kernel::ErrorCode;
 
 /// Globally declare entry ID type.
 type EntryID = usize;
 
+/// Size of the page ID at the start of a page header.
+const PAGE_ID_SIZE: usize = size_of::<EntryID>();
+/// Size of the checksum of the page contents following the page ID.
+const PAGE_CHECKSUM_SIZE: usize = size_of::<u32>();
 /// Maximum page header size.
-pub const PAGE_HEADER_SIZE: usize = size_of::<EntryID>();
+pub const PAGE_HEADER_SIZE: usize = PAGE_ID_SIZE + PAGE_CHECKSUM_SIZE;
 /// Maximum entry header size.
 pub const ENTRY_HEADER_SIZE: usize = size_of::<usize>();
 
@@ -221,14 +232,13 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         let mut newest_page_id: EntryID = 0;
         for header_pos in (0..self.volume.len()).step_by(self.page_size) {
             let page_id = {
-                const ID_SIZE: usize = size_of::<EntryID>();
-                let id_bytes = &self.volume[header_pos..header_pos + ID_SIZE];
-                let id_bytes = <[u8; ID_SIZE]>::try_from(id_bytes).unwrap();
+                let id_bytes = &self.volume[header_pos..header_pos + PAGE_ID_SIZE];
+                let id_bytes = <[u8; PAGE_ID_SIZE]>::try_from(id_bytes).unwrap();
                 usize::from_ne_bytes(id_bytes)
             };
 
-            // Validate page ID read from header.
-            if page_id % self.volume.len() == header_pos {
+            // Validate page ID read from header and the page contents.
+            if page_id % self.volume.len() == header_pos && self.page_checksum_valid(header_pos) {
                 if page_id < oldest_page_id {
                     oldest_page_id = page_id;
                 }
@@ -300,6 +310,17 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         }
     }
 
+    /// Checks the checksum in the header of the page starting at the given position in the volume.
+    fn page_checksum_valid(&self, header_pos: usize) -> bool {
+        self.volume
+            .get(header_pos..header_pos + self.page_size)
+            .is_some_and(|page| {
+                let checksum = &page[PAGE_ID_SIZE..PAGE_HEADER_SIZE];
+                let checksum = <[u8; PAGE_CHECKSUM_SIZE]>::try_from(checksum).unwrap();
+                u32::from_ne_bytes(checksum) == crc32_posix(&page[PAGE_HEADER_SIZE..])
+            })
+    }
+
     /// Returns the ID of the next entry to read or an error if no entry could be retrieved.
     /// Result<(), ErrorCode>s used:
     ///     * FAIL: reached end of log, nothing to read.
@@ -439,15 +460,22 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             pad_ptr += 1;
         }
 
+        // Checksum page contents, so that a page that is only partially written is detected.
+        let checksum = crc32_posix(&pagebuffer.as_mut()[PAGE_HEADER_SIZE..self.page_size]);
+        pagebuffer.as_mut()[PAGE_ID_SIZE..PAGE_HEADER_SIZE]
+            .copy_from_slice(&checksum.to_ne_bytes());
+
         // Get flash page to write to and log page being overwritten. Subtract page_size since
         // padding pointer points to start of the page following the one we want to flush after the
-        // padding operation.
+        // padding operation. No page is overwritten until the log wraps around.
         let page_number = self.page_number(pad_ptr - self.page_size);
-        let overwritten_page = (pad_ptr - self.volume.len() - self.page_size) / self.page_size;
+        let overwritten_page = pad_ptr
+            .checked_sub(self.volume.len() + self.page_size)
+            .map(|pos| pos / self.page_size);
 
         // Advance read and oldest entry IDs, if within flash page being overwritten.
         let read_entry_id = self.read_entry_id.get();
-        if read_entry_id / self.page_size == overwritten_page {
+        if Some(read_entry_id / self.page_size) == overwritten_page {
             // Move read entry ID to start of next page.
             self.read_entry_id.set(
                 read_entry_id + self.page_size + PAGE_HEADER_SIZE - read_entry_id % self.page_size,
@@ -455,7 +483,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         }
 
         let oldest_entry_id = self.oldest_entry_id.get();
-        if oldest_entry_id / self.page_size == overwritten_page {
+        if Some(oldest_entry_id / self.page_size) == overwritten_page {
             self.oldest_entry_id.set(oldest_entry_id + self.page_size);
         }
 
@@ -894,3 +922,307 @@ impl<F: Flash + 'static> DeferredCallClient for Log<'_, F> {
         self.deferred_call.register(self);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use kernel::hil::flash::HasClient;
+    use kernel::hil::log::{LogRead, LogWrite, LogWriteClient};
+    use kernel::utilities::cells::TakeCell;
+    use kernel::ErrorCode;
+
+    use super::Log;
+    use crate::test::sim_flash::{SimFlash, SimPage};
+
+    const PAGE: usize = 64;
+
+    type Flash<const PAGES: usize> = SimFlash<PAGE, PAGES>;
+    type TestLog<const PAGES: usize> = Log<'static, Flash<PAGES>>;
+
+    /// A storage volume. The log finds the flash page of an entry from its
+    /// address, so the volume has to be aligned to a page.
+    #[repr(align(64))]
+    struct Volume<const PAGES: usize>([[u8; PAGE]; PAGES]);
+
+    #[derive(Clone, Copy)]
+    enum Op {
+        /// Append an entry with a value of this length.
+        Append(usize),
+        Sync,
+        Erase,
+    }
+
+    /// Fills many more pages than the circular log has before erasing the
+    /// log, so it wraps around, with syncs of partially filled pages in
+    /// between.
+    const SCENARIO: [Op; 24] = [
+        Op::Append(20),
+        Op::Append(10),
+        Op::Sync,
+        Op::Append(30),
+        Op::Append(14),
+        Op::Append(24),
+        Op::Sync,
+        Op::Append(36),
+        Op::Append(20),
+        Op::Append(6),
+        Op::Append(16),
+        Op::Sync,
+        Op::Append(28),
+        Op::Append(12),
+        Op::Append(40),
+        Op::Append(18),
+        Op::Append(22),
+        Op::Sync,
+        Op::Append(8),
+        Op::Append(26),
+        Op::Erase,
+        Op::Append(12),
+        Op::Append(40),
+        Op::Sync,
+    ];
+
+    /// The value of entry `index`, which starts with the index so that it
+    /// can be identified when it is read back.
+    fn value(index: usize, length: usize) -> Vec<u8> {
+        (0..length)
+            .map(|i| match i {
+                0 => index as u8,
+                _ => (i as u8).wrapping_mul(31).wrapping_add(index as u8),
+            })
+            .collect()
+    }
+
+    struct Client {
+        buffer: TakeCell<'static, [u8]>,
+        done: Cell<Option<Result<(), ErrorCode>>>,
+    }
+
+    impl LogWriteClient for Client {
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            _length: usize,
+            _records_lost: bool,
+            error: Result<(), ErrorCode>,
+        ) {
+            self.buffer.replace(buffer);
+            self.done.set(Some(error));
+        }
+
+        fn sync_done(&self, error: Result<(), ErrorCode>) {
+            self.done.set(Some(error));
+        }
+
+        fn erase_done(&self, error: Result<(), ErrorCode>) {
+            self.done.set(Some(error));
+        }
+    }
+
+    /// Open the log stored on the flash, like a board does after a reboot.
+    ///
+    /// The log reads entries straight from the memory mapped volume, so the
+    /// contents of the flash are copied to a new volume first. Entries
+    /// appended after that can only be read back by opening the log again.
+    fn open<const PAGES: usize>(
+        flash: &'static Flash<PAGES>,
+        circular: bool,
+    ) -> (&'static TestLog<PAGES>, &'static Client) {
+        let volume: &'static Volume<PAGES> = Box::leak(Box::new(Volume(flash.pages())));
+        flash.set_first_page(volume.0.as_ptr() as usize / PAGE);
+
+        let pagebuffer = Box::leak(Box::new(SimPage::default()));
+        let log = Box::leak(Box::new(Log::new(
+            volume.0.as_flattened(),
+            flash,
+            pagebuffer,
+            circular,
+        )));
+        let client = Box::leak(Box::new(Client {
+            buffer: TakeCell::new(Box::leak(Box::new([0; PAGE]))),
+            done: Cell::new(None),
+        }));
+        flash.set_client(log);
+        log.set_append_client(client);
+        (log, client)
+    }
+
+    /// Start an operation and complete the flash operations it needs.
+    /// Returns `None` if the power was lost before it completed.
+    fn complete<const PAGES: usize>(
+        flash: &Flash<PAGES>,
+        client: &Client,
+        started: Result<(), ErrorCode>,
+    ) -> Option<Result<(), ErrorCode>> {
+        if let Err(e) = started {
+            return Some(Err(e));
+        }
+        while flash.service() {}
+
+        match client.done.take() {
+            Some(result) => Some(result),
+            // A sync with nothing to write completes straight away
+            None if flash.is_powered() => Some(Ok(())),
+            None => None,
+        }
+    }
+
+    fn append<const PAGES: usize>(
+        flash: &Flash<PAGES>,
+        log: &TestLog<PAGES>,
+        client: &Client,
+        value: &[u8],
+    ) -> Option<Result<(), ErrorCode>> {
+        let buffer = client.buffer.take().unwrap();
+        buffer[..value.len()].copy_from_slice(value);
+        let started = log.append(buffer, value.len()).map_err(|(e, buffer)| {
+            client.buffer.replace(buffer);
+            e
+        });
+        complete(flash, client, started)
+    }
+
+    /// An entry that was appended, and its entry ID.
+    struct Entry {
+        index: usize,
+        length: usize,
+        id: usize,
+    }
+
+    /// What the log must contain after the power was lost during the
+    /// scenario.
+    struct Expected {
+        /// The entries appended since the log was last erased.
+        entries: Vec<Entry>,
+        /// The IDs of the entries that must be kept. Entries in the page
+        /// being written and the page it overwrites can be lost.
+        kept: core::ops::Range<usize>,
+    }
+
+    /// Run the scenario until the power is lost.
+    fn run<const PAGES: usize>(flash: &'static Flash<PAGES>, circular: bool) -> Expected {
+        let (log, client) = open(flash, circular);
+        let mut expected = Expected {
+            entries: Vec::new(),
+            kept: 0..0,
+        };
+
+        for (index, op) in SCENARIO.iter().enumerate() {
+            let start = log.log_start() / PAGE * PAGE + PAGE;
+            let end = (log.log_end() - 1) / PAGE * PAGE;
+            expected.kept = match op {
+                Op::Erase => 0..0,
+                _ => start..end.max(start),
+            };
+
+            let result = match *op {
+                Op::Append(length) => append(flash, log, client, &value(index, length)),
+                Op::Sync => complete(flash, client, log.sync()),
+                Op::Erase => complete(flash, client, log.erase()),
+            };
+            match result {
+                Some(Ok(())) => {}
+                Some(Err(e)) => panic!("operation {} failed: {:?}", index, e),
+                None => return expected,
+            }
+
+            match *op {
+                Op::Append(length) => expected.entries.push(Entry {
+                    index,
+                    length,
+                    id: log.log_end() - length - super::ENTRY_HEADER_SIZE,
+                }),
+                Op::Sync => {}
+                Op::Erase => expected.entries.clear(),
+            }
+        }
+
+        assert!(flash.is_powered());
+        expected
+    }
+
+    fn read_all<const PAGES: usize>(log: &TestLog<PAGES>) -> Vec<Vec<u8>> {
+        let mut entries = Vec::new();
+        let mut buffer = [0; PAGE];
+        loop {
+            match log.read_entry(&mut buffer, PAGE) {
+                Ok(length) => entries.push(buffer[..length].to_vec()),
+                Err(Err(ErrorCode::FAIL)) => return entries,
+                Err(e) => panic!("reading the log failed: {:?}", e),
+            }
+        }
+    }
+
+    /// Check that the log reconstructed after the power was lost contains
+    /// a run of the appended entries, including all of the entries it must
+    /// have kept, and that it can still be appended to.
+    fn check_recovery<const PAGES: usize>(
+        flash: &'static Flash<PAGES>,
+        circular: bool,
+        expected: &Expected,
+    ) {
+        let (log, client) = open(flash, circular);
+        let read = read_all(log);
+
+        let first = read.first().map_or(0, |value| {
+            expected
+                .entries
+                .iter()
+                .position(|entry| entry.index == value[0] as usize)
+                .expect("read an entry that wasn't appended")
+        });
+        for (value, entry) in read.iter().zip(&expected.entries[first..]) {
+            assert_eq!(*value, self::value(entry.index, entry.length));
+        }
+        assert!(first + read.len() <= expected.entries.len());
+        for (i, entry) in expected.entries.iter().enumerate() {
+            if expected.kept.contains(&entry.id) {
+                assert!((first..first + read.len()).contains(&i), "lost entry {}", i);
+            }
+        }
+
+        let new = value(100, 24);
+        assert_eq!(append(flash, log, client, &new), Some(Ok(())));
+        assert_eq!(complete(flash, client, log.sync()), Some(Ok(())));
+
+        let (log, _client) = open(flash, circular);
+        let read_again = read_all(log);
+        let (last, rest) = read_again.split_last().unwrap();
+        assert_eq!(*last, new);
+        assert!(read.ends_with(rest));
+    }
+
+    fn check_every_crash_point<const PAGES: usize>(circular: bool) {
+        let steps = {
+            let flash = Box::leak(Box::new(Flash::<PAGES>::new()));
+            run(flash, circular);
+            flash.steps()
+        };
+
+        for crash_point in 0..steps {
+            let flash = Box::leak(Box::new(Flash::<PAGES>::new()));
+            flash.lose_power_after(crash_point);
+            let expected = run(flash, circular);
+            assert!(!flash.is_powered());
+
+            flash.power_cycle();
+            check_recovery(flash, circular, &expected);
+            assert_eq!(flash.violations(), 0);
+        }
+    }
+
+    #[test]
+    fn test_circular_log_recovers_after_every_crash_point() {
+        check_every_crash_point::<4>(true);
+    }
+
+    #[test]
+    fn test_linear_log_recovers_after_every_crash_point() {
+        check_every_crash_point::<16>(false);
+    }
+}
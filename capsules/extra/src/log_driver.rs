@@ -0,0 +1,479 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Log Userspace Driver.
+//!
+//! Provides userspace access to a log shared through a `MuxLog`. Each process appends to its own
+//! logical log, identified by the write ID of its `StoragePermissions`, and can iterate over the
+//! entries of all logs its storage permissions allow it to read, including logs written by the
+//! kernel. Every process has its own read position and filter, which can restrict reading to a
+//! single log or to entries with a timestamp within a range.
+//!
+//! Reads copy the ID of the log the entry belongs to (a little endian u32) and its timestamp in
+//! milliseconds (a little endian u64, `u64::MAX` if the entry has no timestamp) in front of the
+//! entry data.
+//!
+//! ```rust,ignore
+//! +===============+
+//! ||  Userspace  ||
+//! +===============+
+//!
+//! -----Syscall Interface-----
+//!
+//! +--------------------------+
+//! |  Log Driver (this file)  |
+//! +--------------------------+
+//!
+//!    VirtualLog
+//!
+//! +--------------------------+
+//! |  MuxLog                  |
+//! +--------------------------+
+//!
+//!    hil::log
+//!
+//! +--------------------------+
+//! |  Log                     |
+//! +--------------------------+
+//!
+//!    hil::flash
+//! ```
+
+use capsules_core::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Log as usize;
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::errorcode;
+use kernel::grant::Grant;
+use kernel::grant::{AllowRoCount, AllowRwCount, GrantKernelData, UpcallCount};
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::{ErrorCode, ProcessId};
+
+use crate::virtual_log::{LogFilter, VirtualLog, HEADER_SIZE};
+
+/// IDs for read-only allow buffers.
+mod ro_allow {
+    /// Entry to append.
+    pub const ENTRY: usize = 0;
+    /// Time range of the filter.
+    pub const FILTER: usize = 1;
+    /// The number of RO allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 2;
+}
+
+/// IDs for read-write allow buffers.
+mod rw_allow {
+    /// Entry read.
+    pub const ENTRY: usize = 0;
+    /// The number of RW allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for upcalls.
+mod upcalls {
+    /// Single upcall.
+    pub const DONE: usize = 0;
+    /// The number of upcalls the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum UserSpaceOp {
+    Append,
+    Read,
+    SeekTimestamp,
+    Sync,
+}
+
+/// Contents of the grant for each app.
+#[derive(Default)]
+pub struct App {
+    op: OptionalCell<UserSpaceOp>,
+    /// Where the next read continues, `None` for the oldest entry.
+    read_entry_id: OptionalCell<usize>,
+    /// Entries that reads return.
+    filter: OptionalCell<LogFilter>,
+    /// Time to seek to.
+    seek_time: Cell<u64>,
+}
+
+/// Capsule that provides userspace access to a shared log.
+pub struct LogDriver<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> {
+    /// Virtual log used for all apps.
+    log: &'a VirtualLog<'a, L>,
+    /// Grant storage for each app.
+    apps: Grant<
+        App,
+        UpcallCount<{ upcalls::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// App that is actively using the log.
+    processid: OptionalCell<ProcessId>,
+    /// Entry buffer.
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogDriver<'a, L> {
+    pub fn new(
+        log: &'a VirtualLog<'a, L>,
+        buffer: &'static mut [u8],
+        grant: Grant<
+            App,
+            UpcallCount<{ upcalls::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> LogDriver<'a, L> {
+        LogDriver {
+            log,
+            apps: grant,
+            processid: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        self.processid.map_or(Err(ErrorCode::RESERVE), |processid| {
+            self.apps
+                .enter(processid, |app, kernel_data| {
+                    let perms = processid
+                        .get_storage_permissions()
+                        .ok_or(ErrorCode::INVAL)?;
+
+                    match app.op.get() {
+                        Some(UserSpaceOp::Append) => {
+                            let write_id = perms.get_write_id().ok_or(ErrorCode::INVAL)?;
+                            let length = kernel_data
+                                .get_readonly_processbuffer(ro_allow::ENTRY)
+                                .and_then(|buffer| {
+                                    buffer.enter(|entry| {
+                                        self.buffer.map_or(Err(ErrorCode::NOMEM), |buf| {
+                                            if entry.len() > buf.len() {
+                                                Err(ErrorCode::SIZE)
+                                            } else {
+                                                entry.copy_to_slice(&mut buf[..entry.len()]);
+                                                Ok(entry.len())
+                                            }
+                                        })
+                                    })
+                                })
+                                .unwrap_or(Err(ErrorCode::RESERVE))?;
+
+                            self.log.set_log_id(write_id);
+                            self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
+                                self.log.append(buf, length).map_err(|(e, buf)| {
+                                    self.buffer.replace(buf);
+                                    e
+                                })
+                            })
+                        }
+                        Some(UserSpaceOp::Read) | Some(UserSpaceOp::SeekTimestamp) => {
+                            // The virtual log is shared by all apps, so set up
+                            // this app's view of it.
+                            self.log.set_filter(app.filter.get().unwrap_or_default());
+                            self.log.set_read_permissions(Some(perms));
+                            self.log.set_read_position(
+                                app.read_entry_id.unwrap_or(self.log.log_start()),
+                            );
+
+                            if app.op.contains(&UserSpaceOp::SeekTimestamp) {
+                                return self.log.seek_timestamp(app.seek_time.get());
+                            }
+                            self.buffer.take().map_or(Err(ErrorCode::NOMEM), |buf| {
+                                let length = buf.len();
+                                self.log.read(buf, length).map_err(|(e, buf)| {
+                                    self.buffer.replace(buf);
+                                    e
+                                })
+                            })
+                        }
+                        Some(UserSpaceOp::Sync) => self.log.sync(),
+                        None => Ok(()),
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    fn check_queue(&self) {
+        // If an app is already running let it complete.
+        if self.processid.is_some() {
+            return;
+        }
+
+        for appiter in self.apps.iter() {
+            let processid = appiter.processid();
+            let has_pending_op = appiter.enter(|app, _| {
+                // If this app has a pending command let's use it.
+                app.op.is_some()
+            });
+            let started_command = if has_pending_op {
+                // Mark this driver as being in use.
+                self.processid.set(processid);
+                match self.run() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        // Let the app know its command failed.
+                        let _ = self.apps.enter(processid, |app, upcalls| {
+                            app.op.clear();
+                            upcalls
+                                .schedule_upcall(
+                                    upcalls::DONE,
+                                    (errorcode::into_statuscode(Err(e)), 0, 0),
+                                )
+                                .ok();
+                        });
+                        false
+                    }
+                }
+            } else {
+                false
+            };
+            if started_command {
+                break;
+            } else {
+                self.processid.clear();
+            }
+        }
+    }
+
+    /// Finish the operation of the active app with an upcall and start the
+    /// next queued operation.
+    fn complete(
+        &self,
+        op: UserSpaceOp,
+        upcall: impl FnOnce(&App, &GrantKernelData) -> (usize, usize, usize),
+    ) {
+        self.processid.map(|processid| {
+            let _ = self.apps.enter(processid, |app, kernel_data| {
+                if app.op.contains(&op) {
+                    app.op.clear();
+                    let args = upcall(app, kernel_data);
+                    kernel_data.schedule_upcall(upcalls::DONE, args).ok();
+                }
+            });
+        });
+
+        self.processid.clear();
+        self.check_queue();
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogReadClient for LogDriver<'a, L> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        self.complete(UserSpaceOp::Read, |app, kernel_data| {
+            // Entries that were skipped or failed to fit are not read again.
+            app.read_entry_id.set(self.log.next_read_entry_id());
+
+            let entry = self.log.last_read().filter(|_| error.is_ok());
+            let ret = error.and_then(|()| {
+                kernel_data
+                    .get_readwrite_processbuffer(rw_allow::ENTRY)
+                    .and_then(|appbuffer| {
+                        appbuffer.mut_enter(|appslice| {
+                            let entry = entry.ok_or(ErrorCode::FAIL)?;
+                            let mut header = [0; HEADER_SIZE];
+                            header[..4].copy_from_slice(&entry.log_id.to_le_bytes());
+                            header[4..].copy_from_slice(
+                                &entry.timestamp.unwrap_or(u64::MAX).to_le_bytes(),
+                            );
+
+                            // Copy as much as fits. Userspace should check for
+                            // an error and only use what fit in the buffer.
+                            let header_len = cmp::min(HEADER_SIZE, appslice.len());
+                            appslice[..header_len].copy_from_slice(&header[..header_len]);
+                            let copy_len = cmp::min(length, appslice.len() - header_len);
+                            self.buffer.map(|buf| {
+                                appslice[header_len..header_len + copy_len]
+                                    .copy_from_slice(&buf[..copy_len]);
+                            });
+                            if copy_len < length {
+                                Err(ErrorCode::SIZE)
+                            } else {
+                                Ok(())
+                            }
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::RESERVE))
+            });
+
+            (
+                errorcode::into_statuscode(ret),
+                length,
+                entry.map_or(0, |entry| entry.entry_id),
+            )
+        });
+    }
+
+    fn seek_done(&self, error: Result<(), ErrorCode>) {
+        self.complete(UserSpaceOp::SeekTimestamp, |app, _| {
+            let entry_id = self.log.next_read_entry_id();
+            if error.is_ok() {
+                app.read_entry_id.set(entry_id);
+            }
+            (errorcode::into_statuscode(error), entry_id, 0)
+        });
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogWriteClient for LogDriver<'a, L> {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        self.complete(UserSpaceOp::Append, |_, _| {
+            (
+                errorcode::into_statuscode(error),
+                length,
+                records_lost as usize,
+            )
+        });
+    }
+
+    fn sync_done(&self, error: Result<(), ErrorCode>) {
+        self.complete(UserSpaceOp::Sync, |_, _| {
+            (errorcode::into_statuscode(error), 0, 0)
+        });
+    }
+
+    fn erase_done(&self, _error: Result<(), ErrorCode>) {}
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> SyscallDriver for LogDriver<'a, L> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let op = match command_num {
+            // check if present
+            0 => return CommandReturn::success(),
+
+            // append, read
+            1 => UserSpaceOp::Append,
+            2 => UserSpaceOp::Read,
+
+            // set filter
+            //
+            // `data1` is the ID of the log to read, 0 reads all logs the app
+            // has permission to read. If RO allow 1 holds two little endian
+            // u64 values, only entries with a timestamp at or after the first
+            // and before the second are read.
+            3 => {
+                return self
+                    .apps
+                    .enter(processid, |app, kernel_data| {
+                        let time = kernel_data
+                            .get_readonly_processbuffer(ro_allow::FILTER)
+                            .and_then(|buffer| {
+                                buffer.enter(|range| {
+                                    if range.len() < 16 {
+                                        return None;
+                                    }
+                                    let mut bytes = [0; 16];
+                                    range[..16].copy_to_slice(&mut bytes);
+                                    let start = u64::from_le_bytes(bytes[..8].try_into().ok()?);
+                                    let end = u64::from_le_bytes(bytes[8..].try_into().ok()?);
+                                    Some((start, end))
+                                })
+                            })
+                            .ok()
+                            .flatten();
+                        app.filter.set(LogFilter {
+                            log_id: (data1 != 0).then_some(data1 as u32),
+                            time,
+                        });
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| err.into());
+            }
+
+            // rewind to the oldest entry
+            4 => {
+                return self
+                    .apps
+                    .enter(processid, |app, _| {
+                        app.read_entry_id.clear();
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| err.into());
+            }
+
+            // seek to the entry ID in `data1`, as returned by read and seek
+            5 => {
+                if data1 < self.log.log_start() || data1 > self.log.log_end() {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                return self
+                    .apps
+                    .enter(processid, |app, _| {
+                        app.read_entry_id.set(data1);
+                        CommandReturn::success()
+                    })
+                    .unwrap_or_else(|err| err.into());
+            }
+
+            // seek to the first entry at or after the time in milliseconds
+            // `data1 | data2 << 32`
+            6 => UserSpaceOp::SeekTimestamp,
+
+            // sync
+            7 => UserSpaceOp::Sync,
+
+            // default
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+
+        if self.processid.is_none() {
+            // Nothing is using the log, so we can handle this request.
+            self.processid.set(processid);
+            let _ = self.apps.enter(processid, |app, _| {
+                app.op.set(op);
+                app.seek_time.set(data1 as u64 | (data2 as u64) << 32);
+            });
+            let ret = self.run();
+
+            if let Err(e) = ret {
+                let _ = self.apps.enter(processid, |app, _| app.op.clear());
+                self.processid.clear();
+                self.check_queue();
+                CommandReturn::failure(e)
+            } else {
+                CommandReturn::success()
+            }
+        } else {
+            // There is an active app, so queue this request (if possible).
+            self.apps
+                .enter(processid, |app, _| {
+                    if app.op.is_some() {
+                        // No more room in the queue, nowhere to store this
+                        // request.
+                        CommandReturn::failure(ErrorCode::NOMEM)
+                    } else {
+                        app.op.set(op);
+                        app.seek_time.set(data1 as u64 | (data2 as u64) << 32);
+                        CommandReturn::success()
+                    }
+                })
+                .unwrap_or_else(|err| err.into())
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
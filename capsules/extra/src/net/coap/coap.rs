@@ -0,0 +1,619 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! CoAP message encoding and decoding (RFC 7252), including the Block1 and
+//! Block2 options used for block-wise transfers (RFC 7959).
+//!
+//! A CoAP message consists of a fixed four byte header, a token of up to
+//! eight bytes, a sequence of delta-encoded options and an optional payload
+//! which is preceded by the `0xFF` payload marker:
+//!
+//! ```text
+//!  0                   1                   2                   3
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |Ver| T |  TKL  |      Code     |          Message ID           |
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Token (if any, TKL bytes) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |   Options (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! |1 1 1 1 1 1 1 1|    Payload (if any) ...
+//! +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! The `CoapHeader` struct covers everything up to and including the token.
+//! Options are written in ascending order with an `OptionWriter` and read
+//! back with `parse_options`.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+use kernel::ErrorCode;
+
+/// The default UDP port for CoAP.
+pub const COAP_PORT: u16 = 5683;
+
+/// The only protocol version defined by RFC 7252.
+pub const VERSION: u8 = 1;
+
+/// The maximum length of a token in bytes.
+pub const MAX_TOKEN_LEN: usize = 8;
+
+/// Marks the end of the options and the start of the payload.
+pub const PAYLOAD_MARKER: u8 = 0xff;
+
+/// The size of the fixed part of the header.
+pub const FIXED_HEADER_LEN: usize = 4;
+
+/// Initial retransmission timeout (RFC 7252 section 4.8).
+///
+/// The timeout is randomized between `ACK_TIMEOUT_MS` and
+/// `ACK_TIMEOUT_MS * 1.5` and doubled for every retransmission.
+pub const ACK_TIMEOUT_MS: u32 = 2000;
+
+/// Number of retransmissions after which a confirmable message fails.
+pub const MAX_RETRANSMIT: u8 = 4;
+
+/// Time, in seconds, during which a message ID from a given endpoint is
+/// remembered for duplicate detection (`EXCHANGE_LIFETIME`).
+pub const EXCHANGE_LIFETIME_S: u32 = 247;
+
+/// Time, in seconds, a client waits for a response after its request was
+/// sent or acknowledged (`MAX_TRANSMIT_WAIT`).
+pub const MAX_TRANSMIT_WAIT_S: u32 = 93;
+
+/// Message types (the `T` field of the header).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> MessageType {
+        match bits & 0b11 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+/// Message codes, in the `class.detail` format where the upper three bits
+/// are the class and the lower five bits the detail.
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+
+    // Request methods (class 0).
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+
+    // Success (class 2).
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+    pub const CONTINUE: u8 = 0x5f;
+
+    // Client errors (class 4).
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const REQUEST_ENTITY_INCOMPLETE: u8 = 0x88;
+    pub const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8d;
+
+    // Server errors (class 5).
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+    /// Returns the class (upper three bits) of `code`.
+    pub fn class(code: u8) -> u8 {
+        code >> 5
+    }
+
+    /// Returns whether `code` is a request method.
+    pub fn is_request(code: u8) -> bool {
+        class(code) == 0 && code != EMPTY
+    }
+
+    /// Returns whether `code` is a response code.
+    pub fn is_response(code: u8) -> bool {
+        (2..=5).contains(&class(code))
+    }
+}
+
+/// Option numbers used by the kernel CoAP implementation.
+pub mod option {
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+}
+
+/// A CoAP token, used to match responses to requests.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Token {
+    len: u8,
+    bytes: [u8; MAX_TOKEN_LEN],
+}
+
+impl Token {
+    pub fn new(token: &[u8]) -> Option<Token> {
+        if token.len() > MAX_TOKEN_LEN {
+            return None;
+        }
+        let mut bytes = [0; MAX_TOKEN_LEN];
+        bytes[..token.len()].copy_from_slice(token);
+        Some(Token {
+            len: token.len() as u8,
+            bytes,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// The fixed header and token of a CoAP message.
+#[derive(Copy, Clone, Debug)]
+pub struct CoapHeader {
+    pub msg_type: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Token,
+}
+
+impl CoapHeader {
+    pub fn new(msg_type: MessageType, code: u8, message_id: u16, token: Token) -> CoapHeader {
+        CoapHeader {
+            msg_type,
+            code,
+            message_id,
+            token,
+        }
+    }
+
+    pub fn get_hdr_size(&self) -> usize {
+        FIXED_HEADER_LEN + self.token.len as usize
+    }
+
+    /// This function serializes the `CoapHeader` into the provided buffer.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - A mutable buffer to serialize the `CoapHeader` into
+    /// `offset` - The current offset into the provided buffer
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size() + offset);
+
+        let first = (VERSION << 6) | ((self.msg_type as u8) << 4) | self.token.len;
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u8, first);
+        off = enc_consume!(buf, off; encode_u8, self.code);
+        off = enc_consume!(buf, off; encode_u16, self.message_id);
+        off = enc_consume!(buf, off; encode_bytes, self.token.as_slice());
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes the `CoapHeader` from the provided buffer.
+    /// Messages with an unknown version or a reserved token length are
+    /// rejected.
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the offset of the first option (or the payload
+    /// marker) and the `CoapHeader` wrapped in an SResult.
+    pub fn decode(buf: &[u8]) -> SResult<CoapHeader> {
+        stream_len_cond!(buf, FIXED_HEADER_LEN);
+        let off = 0;
+        let (off, first) = dec_try!(buf, off; decode_u8);
+        let (off, code) = dec_try!(buf, off; decode_u8);
+        let (off, message_id) = dec_try!(buf, off; decode_u16);
+
+        stream_cond!(first >> 6 == VERSION);
+        let token_len = (first & 0x0f) as usize;
+        stream_cond!(token_len <= MAX_TOKEN_LEN);
+        stream_len_cond!(buf, off + token_len);
+
+        let mut token = [0; MAX_TOKEN_LEN];
+        let off = dec_consume!(buf, off; decode_bytes, &mut token[..token_len]);
+
+        stream_done!(
+            off,
+            CoapHeader {
+                msg_type: MessageType::from_bits(first >> 4),
+                code,
+                message_id,
+                token: Token::new(&token[..token_len]).unwrap_or_default(),
+            }
+        );
+    }
+}
+
+/// The value of a Block1 or Block2 option (RFC 7959 section 2.2).
+///
+/// The block size is `2^(szx + 4)` bytes, so `szx` ranges from 0 (16 bytes)
+/// to 6 (1024 bytes).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockOption {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl BlockOption {
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn new(num: u32, more: bool, szx: u8) -> BlockOption {
+        BlockOption { num, more, szx }
+    }
+
+    /// Returns the size of a block in bytes.
+    pub fn size(&self) -> usize {
+        szx_to_size(self.szx)
+    }
+
+    /// Returns the offset of this block within the whole body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Decodes an option value. Returns `None` if the value is too long or
+    /// uses the reserved size exponent 7.
+    pub fn decode(value: &[u8]) -> Option<BlockOption> {
+        if value.len() > 3 {
+            return None;
+        }
+        let raw = decode_uint(value);
+        let szx = (raw & 0x7) as u8;
+        if szx > Self::MAX_SZX {
+            return None;
+        }
+        Some(BlockOption {
+            num: raw >> 4,
+            more: raw & 0x8 != 0,
+            szx,
+        })
+    }
+
+    /// Returns the option value as an unsigned integer.
+    pub fn as_uint(&self) -> u32 {
+        (self.num << 4) | (u32::from(self.more) << 3) | u32::from(self.szx)
+    }
+}
+
+/// Converts a block size exponent to a size in bytes.
+pub fn szx_to_size(szx: u8) -> usize {
+    1 << (szx as usize + 4)
+}
+
+/// Returns the largest block size exponent whose block fits in `len` bytes,
+/// or `None` if not even a 16 byte block fits.
+pub fn size_to_szx(len: usize) -> Option<u8> {
+    (0..=BlockOption::MAX_SZX)
+        .rev()
+        .find(|&szx| szx_to_size(szx) <= len)
+}
+
+/// Decodes a big-endian unsigned integer option value of up to four bytes.
+pub fn decode_uint(value: &[u8]) -> u32 {
+    value
+        .iter()
+        .take(4)
+        .fold(0, |acc, &byte| (acc << 8) | byte as u32)
+}
+
+/// Walks the options of a message starting at `offset`, calling `f` with
+/// the number and value of each option in order.
+///
+/// Returns the payload (empty if there is none), or `Err(())` if the options
+/// are malformed or a payload marker is followed by an empty payload.
+pub fn parse_options<'b, F: FnMut(u16, &'b [u8])>(
+    buf: &'b [u8],
+    offset: usize,
+    mut f: F,
+) -> Result<&'b [u8], ()> {
+    let mut off = offset;
+    let mut number: u16 = 0;
+    while off < buf.len() {
+        let byte = buf[off];
+        off += 1;
+        if byte == PAYLOAD_MARKER {
+            if off == buf.len() {
+                return Err(());
+            }
+            return Ok(&buf[off..]);
+        }
+        let (delta, next) = decode_option_nibble(buf, off, byte >> 4)?;
+        let (len, next) = decode_option_nibble(buf, next, byte & 0x0f)?;
+        off = next;
+        if off + len as usize > buf.len() {
+            return Err(());
+        }
+        number = number.checked_add(delta).ok_or(())?;
+        f(number, &buf[off..off + len as usize]);
+        off += len as usize;
+    }
+    Ok(&buf[buf.len()..])
+}
+
+/// Decodes an option delta or length nibble, reading the extended bytes
+/// following the option header if needed.
+fn decode_option_nibble(buf: &[u8], offset: usize, nibble: u8) -> Result<(u16, usize), ()> {
+    match nibble {
+        0..=12 => Ok((nibble as u16, offset)),
+        13 => buf
+            .get(offset)
+            .map(|&ext| (ext as u16 + 13, offset + 1))
+            .ok_or(()),
+        14 => match buf.get(offset..offset + 2) {
+            Some(ext) => (decode_uint(ext) as u16)
+                .checked_add(269)
+                .map(|value| (value, offset + 2))
+                .ok_or(()),
+            None => Err(()),
+        },
+        _ => Err(()),
+    }
+}
+
+/// Serializes options in ascending order, followed by an optional payload.
+pub struct OptionWriter<'b> {
+    buf: &'b mut [u8],
+    offset: usize,
+    last_number: u16,
+}
+
+impl<'b> OptionWriter<'b> {
+    /// Creates a writer that starts writing at `offset`, usually the value
+    /// returned by `CoapHeader::encode`.
+    pub fn new(buf: &'b mut [u8], offset: usize) -> OptionWriter<'b> {
+        OptionWriter {
+            buf,
+            offset,
+            last_number: 0,
+        }
+    }
+
+    /// Appends an option. Options must be appended in ascending order of
+    /// their option number; repeated options are allowed.
+    ///
+    /// Returns `INVAL` if `number` is lower than the previous option and
+    /// `SIZE` if the option does not fit in the buffer.
+    pub fn push(&mut self, number: u16, value: &[u8]) -> Result<(), ErrorCode> {
+        if number < self.last_number || value.len() > u16::MAX as usize {
+            return Err(ErrorCode::INVAL);
+        }
+        let delta = number - self.last_number;
+        let (delta_nibble, delta_ext, delta_ext_len) = encode_option_nibble(delta);
+        let (len_nibble, len_ext, len_ext_len) = encode_option_nibble(value.len() as u16);
+
+        let needed = 1 + delta_ext_len + len_ext_len + value.len();
+        if self.offset + needed > self.buf.len() {
+            return Err(ErrorCode::SIZE);
+        }
+
+        let mut off = self.offset;
+        self.buf[off] = (delta_nibble << 4) | len_nibble;
+        off += 1;
+        self.buf[off..off + delta_ext_len].copy_from_slice(&delta_ext[..delta_ext_len]);
+        off += delta_ext_len;
+        self.buf[off..off + len_ext_len].copy_from_slice(&len_ext[..len_ext_len]);
+        off += len_ext_len;
+        self.buf[off..off + value.len()].copy_from_slice(value);
+
+        self.offset = off + value.len();
+        self.last_number = number;
+        Ok(())
+    }
+
+    /// Appends an option with an unsigned integer value, encoded in the
+    /// minimal number of bytes.
+    pub fn push_uint(&mut self, number: u16, value: u32) -> Result<(), ErrorCode> {
+        let bytes = value.to_be_bytes();
+        let skip = (value.leading_zeros() / 8) as usize;
+        self.push(number, &bytes[skip..])
+    }
+
+    /// Appends a Block1 or Block2 option.
+    pub fn push_block(&mut self, number: u16, block: BlockOption) -> Result<(), ErrorCode> {
+        self.push_uint(number, block.as_uint())
+    }
+
+    /// Returns the number of bytes that can still be written.
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    /// Returns a mutable slice of length `len` for the payload, writing the
+    /// payload marker in front of it if `len` is not zero. Returns `SIZE` if
+    /// the payload does not fit.
+    pub fn payload(&mut self, len: usize) -> Result<&mut [u8], ErrorCode> {
+        if len == 0 {
+            return Ok(&mut self.buf[self.offset..self.offset]);
+        }
+        if self.offset + 1 + len > self.buf.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        self.buf[self.offset] = PAYLOAD_MARKER;
+        let start = self.offset + 1;
+        self.offset = start + len;
+        Ok(&mut self.buf[start..start + len])
+    }
+
+    /// Returns the total length of the message written so far.
+    pub fn finish(self) -> usize {
+        self.offset
+    }
+}
+
+/// Splits an option delta or length into its nibble and extended bytes.
+fn encode_option_nibble(value: u16) -> (u8, [u8; 2], usize) {
+    match value {
+        0..=12 => (value as u8, [0, 0], 0),
+        13..=268 => (13, [(value - 13) as u8, 0], 1),
+        _ => (14, (value - 269).to_be_bytes(), 2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn header() -> CoapHeader {
+        CoapHeader::new(
+            MessageType::Confirmable,
+            0x01,
+            0x1234,
+            Token::new(&[0xde, 0xad, 0xbe, 0xef]).unwrap(),
+        )
+    }
+
+    /// Decodes `buf` and returns its options and payload.
+    fn options_of(buf: &[u8]) -> Result<(Vec<(u16, Vec<u8>)>, Vec<u8>), ()> {
+        let (off, _) = CoapHeader::decode(buf).done().ok_or(())?;
+        let mut options = Vec::new();
+        let payload = parse_options(buf, off, |number, value| {
+            options.push((number, value.to_vec()))
+        })?;
+        Ok((options, payload.to_vec()))
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut buf = [0; 16];
+        let end = header().encode(&mut buf, 0).done().unwrap().0;
+        assert_eq!(end, header().get_hdr_size());
+        assert_eq!(
+            &buf[..end],
+            &[0x44, 0x01, 0x12, 0x34, 0xde, 0xad, 0xbe, 0xef]
+        );
+
+        let (off, decoded) = CoapHeader::decode(&buf[..end]).done().unwrap();
+        assert_eq!(off, end);
+        assert_eq!(decoded.msg_type, MessageType::Confirmable);
+        assert_eq!(decoded.code, 0x01);
+        assert_eq!(decoded.message_id, 0x1234);
+        assert_eq!(decoded.token, header().token);
+    }
+
+    #[test]
+    fn truncated_token_is_rejected() {
+        let mut buf = [0; 16];
+        let end = header().encode(&mut buf, 0).done().unwrap().0;
+        for len in FIXED_HEADER_LEN..end {
+            assert!(CoapHeader::decode(&buf[..len]).done().is_none());
+        }
+        // Token lengths 9 to 15 are reserved.
+        buf[0] = (VERSION << 6) | 9;
+        assert!(CoapHeader::decode(&buf).done().is_none());
+    }
+
+    #[test]
+    fn option_extensions_round_trip() {
+        let long_value = [0xa5; 300];
+        // Deltas and lengths on both sides of the one byte (13) and two
+        // byte (14) extension boundaries.
+        let options: [(u16, &[u8]); 7] = [
+            (12, &[1]),
+            (25, &long_value[..12]),
+            (38, &long_value[..13]),
+            (268, &long_value[..268]),
+            (537, &long_value[..269]),
+            (537, &[]),
+            (1000, &long_value[..300]),
+        ];
+
+        let mut buf = [0; 1400];
+        let off = header().encode(&mut buf, 0).done().unwrap().0;
+        let mut writer = OptionWriter::new(&mut buf, off);
+        for (number, value) in options {
+            writer.push(number, value).unwrap();
+        }
+        writer.payload(3).unwrap().copy_from_slice(b"abc");
+        let len = writer.finish();
+
+        // The option with delta 13 uses a one byte extension.
+        let option = off + 2;
+        assert_eq!(buf[option] >> 4, 13);
+        assert_eq!(buf[option] & 0x0f, 12);
+
+        let (decoded, payload) = options_of(&buf[..len]).unwrap();
+        assert_eq!(decoded.len(), options.len());
+        for ((number, value), (decoded_number, decoded_value)) in options.iter().zip(&decoded) {
+            assert_eq!(number, decoded_number);
+            assert_eq!(*value, &decoded_value[..]);
+        }
+        assert_eq!(payload, b"abc");
+    }
+
+    #[test]
+    fn extended_nibbles_use_extension_bytes() {
+        assert_eq!(encode_option_nibble(12), (12, [0, 0], 0));
+        assert_eq!(encode_option_nibble(13), (13, [0, 0], 1));
+        assert_eq!(encode_option_nibble(268), (13, [255, 0], 1));
+        assert_eq!(encode_option_nibble(269), (14, [0, 0], 2));
+        assert_eq!(encode_option_nibble(0xffff), (14, [0xfe, 0xf2], 2));
+
+        assert_eq!(decode_option_nibble(&[255], 0, 13), Ok((268, 1)));
+        assert_eq!(decode_option_nibble(&[0xfe, 0xf2], 0, 14), Ok((0xffff, 2)));
+        // Missing extension bytes, overflow and the reserved nibble 15.
+        assert_eq!(decode_option_nibble(&[], 0, 13), Err(()));
+        assert_eq!(decode_option_nibble(&[0], 0, 14), Err(()));
+        assert_eq!(decode_option_nibble(&[0xff, 0xff], 0, 14), Err(()));
+        assert_eq!(decode_option_nibble(&[0], 0, 15), Err(()));
+    }
+
+    #[test]
+    fn payload_marker() {
+        let mut buf = [0; 32];
+        let off = header().encode(&mut buf, 0).done().unwrap().0;
+
+        // An empty payload is written without a marker.
+        let mut writer = OptionWriter::new(&mut buf, off);
+        writer.push_uint(option::CONTENT_FORMAT, 0).unwrap();
+        assert!(writer.payload(0).unwrap().is_empty());
+        let len = writer.finish();
+        assert!(!buf[..len].contains(&PAYLOAD_MARKER));
+        let (options, payload) = options_of(&buf[..len]).unwrap();
+        assert_eq!(options, [(option::CONTENT_FORMAT, Vec::new())]);
+        assert!(payload.is_empty());
+
+        // A marker followed by an empty payload is a format error.
+        buf[len] = PAYLOAD_MARKER;
+        assert_eq!(options_of(&buf[..len + 1]), Err(()));
+
+        buf[len + 1] = b'x';
+        assert_eq!(options_of(&buf[..len + 2]).unwrap().1, b"x");
+    }
+
+    #[test]
+    fn truncated_option_is_rejected() {
+        let mut buf = [0; 32];
+        let off = header().encode(&mut buf, 0).done().unwrap().0;
+        let mut writer = OptionWriter::new(&mut buf, off);
+        writer.push(option::URI_PATH, b"sensors").unwrap();
+        let len = writer.finish();
+        assert!(options_of(&buf[..len]).is_ok());
+        assert_eq!(options_of(&buf[..len - 1]), Err(()));
+    }
+}
@@ -0,0 +1,1708 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! CoAP server and client for userspace applications.
+//!
+//! This capsule owns a single UDP port (normally 5683) and shares it between
+//! all applications. Apps act as CoAP servers by registering resources
+//! (URI paths) and answering the requests the kernel dispatches to them, and
+//! as CoAP clients by asking the kernel to send a request and waiting for the
+//! matching response.
+//!
+//! The kernel takes care of the message layer of RFC 7252 so that apps only
+//! deal with requests and responses:
+//!
+//! - Confirmable (CON) messages sent by apps are retransmitted with
+//!   exponential backoff until they are acknowledged or `MAX_RETRANSMIT`
+//!   retransmissions have been sent.
+//! - Received confirmable requests are acknowledged by the kernel, and the
+//!   app's answer is sent as a separate response. Errors the kernel can
+//!   determine on its own (unknown resource, busy app, entity too large)
+//!   are piggybacked on the acknowledgement.
+//! - Duplicate messages, identified by message ID and source endpoint, are
+//!   recognized for `EXCHANGE_LIFETIME` and never delivered to an app twice.
+//! - Block-wise transfers (RFC 7959) are handled transparently. Request
+//!   payloads larger than the block size are sent with Block1, and large
+//!   responses are served with Block2 from the buffer the app responded
+//!   with. In the other direction, Block1 requests are reassembled into the
+//!   app's request buffer and Block2 responses are fetched block by block
+//!   into the app's response buffer before the app is notified.
+//!
+//! Each app can have at most one outstanding request or response in flight
+//! at a time, and at most one received request waiting for its response.
+//! Payload buffers are read from the app every time a message is
+//! (re)transmitted, so they must not be changed until the corresponding
+//! upcall has been delivered.
+//!
+//! Endpoints are exchanged with apps as 18 bytes: the 16 byte IPv6 address
+//! followed by the port in host byte order, the same layout used by the UDP
+//! driver.
+
+use crate::net::coap::coap::{
+    code, option, parse_options, size_to_szx, szx_to_size, BlockOption, CoapHeader, MessageType,
+    OptionWriter, Token, ACK_TIMEOUT_MS, EXCHANGE_LIFETIME_S, MAX_RETRANSMIT, MAX_TRANSMIT_WAIT_S,
+};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_recv::UDPRecvClient;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use crate::net::util::host_slice_to_u16;
+
+use core::cmp;
+
+use kernel::errorcode::into_statuscode;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
+use kernel::hil::time::{self, ConvertTicks, Ticks};
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{MapCell, OptionalCell};
+use kernel::utilities::leasable_buffer::SubSliceMut;
+use kernel::{ErrorCode, ProcessId};
+
+use capsules_core::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Coap as usize;
+
+/// Maximum number of resources a single app can register.
+pub const MAX_RESOURCES: usize = 4;
+
+/// Maximum length of a resource path, e.g. `sensors/temp`.
+pub const MAX_PATH_LEN: usize = 32;
+
+/// Number of (endpoint, message ID) pairs remembered for deduplication.
+const DEDUP_CACHE_LEN: usize = 8;
+
+/// Number of kernel-generated messages (acknowledgements, resets and
+/// piggybacked responses) that can wait for the UDP sender.
+const REPLY_QUEUE_LEN: usize = 4;
+
+/// Worst-case size of everything but the payload in a message built by this
+/// capsule: header with an 8 byte token, Uri-Path options for a
+/// `MAX_PATH_LEN` path, Content-Format, Block2, Block1 and the payload
+/// marker. The block size is the largest power of two that fits in the send
+/// buffer after this overhead.
+const MESSAGE_OVERHEAD: usize = 64;
+
+/// Length of an endpoint as exchanged with userspace.
+const ENDPOINT_LEN: usize = 18;
+
+/// Bit set in a Content-Format command argument to include the option.
+const CONTENT_FORMAT_PRESENT: usize = 1 << 16;
+
+/// IDs for subscribed upcalls.
+mod upcall {
+    /// A request for one of the app's resources was received. The upcall
+    /// receives the resource index, the method code and the payload length.
+    pub const REQUEST_RECEIVED: usize = 0;
+    /// A response from the app was sent (and acknowledged, if the request
+    /// was confirmable). The upcall receives a status code.
+    pub const RESPONSE_SENT: usize = 1;
+    /// A response to the app's request was received, or the request failed.
+    /// The upcall receives a status code, the response code and the payload
+    /// length.
+    pub const RESPONSE_RECEIVED: usize = 2;
+    /// A reply the kernel generated on behalf of the app (an acknowledgement,
+    /// a piggybacked error or a block-wise continuation) was dropped because
+    /// the reply queue was full. The upcall receives the code of the dropped
+    /// reply and the number of replies dropped for the app so far.
+    pub const REPLY_DROPPED: usize = 3;
+    /// Number of upcalls.
+    pub const COUNT: u8 = 4;
+}
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// Payload of requests sent by the app as a client.
+    pub const REQUEST: usize = 0;
+    /// Payload of responses sent by the app as a server.
+    pub const RESPONSE: usize = 1;
+    /// Resource path, used to register resources and to send requests.
+    pub const PATH: usize = 2;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 3;
+}
+
+/// Ids for read-write allow buffers
+mod rw_allow {
+    /// Receives the payload of requests for the app's resources.
+    pub const REQUEST: usize = 0;
+    /// Receives the payload of responses to the app's requests.
+    pub const RESPONSE: usize = 1;
+    /// Endpoint the app's requests are sent to.
+    pub const DESTINATION: usize = 2;
+    /// Filled with the endpoint of the last request delivered to the app.
+    pub const SOURCE: usize = 3;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 4;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Peer {
+    addr: IPAddr,
+    port: u16,
+}
+
+impl Peer {
+    fn encode(&self) -> [u8; ENDPOINT_LEN] {
+        let mut buf = [0; ENDPOINT_LEN];
+        buf[..16].copy_from_slice(&self.addr.0);
+        buf[16..].copy_from_slice(&self.port.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Peer> {
+        if buf.len() != ENDPOINT_LEN {
+            return None;
+        }
+        let mut addr = IPAddr::new();
+        addr.0.copy_from_slice(&buf[..16]);
+        Some(Peer {
+            addr,
+            port: host_slice_to_u16(&buf[16..]),
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Resource {
+    path: [u8; MAX_PATH_LEN],
+    len: usize,
+}
+
+impl Resource {
+    fn path(&self) -> &[u8] {
+        &self.path[..self.len]
+    }
+}
+
+/// A request delivered to the app that has not been answered yet.
+#[derive(Copy, Clone)]
+struct PendingRequest {
+    peer: Peer,
+    token: Token,
+    confirmable: bool,
+    resource: usize,
+    szx: u8,
+}
+
+/// A Block1 request body being reassembled into the app's request buffer.
+#[derive(Copy, Clone)]
+struct Block1Rx {
+    peer: Peer,
+    resource: usize,
+    next_num: u32,
+}
+
+/// The last response sent by the app, from which further Block2 blocks are
+/// served.
+#[derive(Copy, Clone)]
+struct Served {
+    peer: Peer,
+    resource: usize,
+    code: u8,
+    content_format: Option<u16>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Role {
+    Request,
+    Response,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum TxState {
+    /// Waiting for the UDP sender.
+    Queued,
+    /// Handed to the UDP sender, waiting for `send_done`.
+    Sending,
+    /// Confirmable message sent, waiting for an ACK or RST.
+    AwaitingAck,
+    /// Request sent or acknowledged, waiting for the response.
+    AwaitingResponse,
+}
+
+#[derive(Copy, Clone)]
+struct Expiration<T: Ticks> {
+    reference: T,
+    dt: T,
+}
+
+/// A request or response sent by the app.
+#[derive(Copy, Clone)]
+struct Outgoing<T: Ticks> {
+    role: Role,
+    peer: Peer,
+    token: Token,
+    message_id: u16,
+    confirmable: bool,
+    code: u8,
+    content_format: Option<u16>,
+    /// Number of the next Block1 block of the request payload to send.
+    block1: u32,
+    /// Number of the Block2 block to request, once the first block of the
+    /// response has been received.
+    block2: Option<BlockOption>,
+    szx: u8,
+    /// Bytes of the response copied into the app's buffer so far.
+    rx_len: usize,
+    transmissions: u8,
+    state: TxState,
+    timeout: Option<Expiration<T>>,
+}
+
+impl<T: Ticks> Outgoing<T> {
+    /// Whether an ACK or RST from `peer` for `message_id` completes this
+    /// message.
+    fn acked_by(&self, peer: Peer, message_id: u16) -> bool {
+        self.peer == peer
+            && self.message_id == message_id
+            && matches!(self.state, TxState::Sending | TxState::AwaitingAck)
+    }
+
+    /// Whether a separate response from `peer` with `token` answers this
+    /// request.
+    fn answered_by(&self, peer: Peer, token: Token) -> bool {
+        self.role == Role::Request
+            && self.peer == peer
+            && self.token == token
+            && self.state != TxState::Queued
+    }
+
+    /// Returns the block option and the range of a `len` byte payload to
+    /// send in the next transmission. Payloads that fit in a single block
+    /// are sent whole and without a block option.
+    fn payload_block(&self, len: usize) -> (Option<BlockOption>, usize, usize) {
+        let size = szx_to_size(self.szx);
+        if len <= size {
+            return (None, 0, len);
+        }
+        let block = BlockOption::new(
+            self.block1,
+            (self.block1 as usize + 1) * size < len,
+            self.szx,
+        );
+        let start = cmp::min(block.offset(), len);
+        (Some(block), start, cmp::min(start + size, len))
+    }
+
+    /// Queues the block following the one the server acknowledged with
+    /// 2.31 Continue, switching to the server's block size if it is smaller.
+    fn continue_block1(&mut self, block1: BlockOption, message_id: u16) {
+        let acked = (self.block1 as usize + 1) * szx_to_size(self.szx);
+        self.szx = cmp::min(self.szx, block1.szx);
+        self.block1 = (acked / szx_to_size(self.szx)) as u32;
+        self.requeue(message_id);
+    }
+
+    /// Queues a request for the Block2 block following `block2`.
+    fn fetch_block2(&mut self, block2: BlockOption, message_id: u16) {
+        self.block2 = Some(BlockOption::new(block2.num + 1, false, block2.szx));
+        self.requeue(message_id);
+    }
+
+    fn requeue(&mut self, message_id: u16) {
+        self.message_id = message_id;
+        self.transmissions = 0;
+        self.state = TxState::Queued;
+        self.timeout = None;
+    }
+}
+
+pub struct App<T: Ticks> {
+    resources: [Option<Resource>; MAX_RESOURCES],
+    request: Option<PendingRequest>,
+    block1: Option<Block1Rx>,
+    served: Option<Served>,
+    outgoing: Option<Outgoing<T>>,
+    /// Number of kernel replies for this app dropped because the reply
+    /// queue was full.
+    dropped_replies: usize,
+}
+
+impl<T: Ticks> App<T> {
+    /// Returns the offset in the request buffer at which the payload of a
+    /// request for `resource` from `peer` is stored, or `None` if the
+    /// request repeats a Block1 block that was already stored. Blocks out of
+    /// sequence are answered with 4.08 Request Entity Incomplete.
+    fn block1_offset(
+        &self,
+        peer: Peer,
+        resource: usize,
+        block1: Option<BlockOption>,
+    ) -> Result<Option<usize>, u8> {
+        let block1 = match block1 {
+            Some(block1) => block1,
+            None => return Ok(Some(0)),
+        };
+        let expected = self
+            .block1
+            .filter(|b| b.peer == peer && b.resource == resource)
+            .map_or(0, |b| b.next_num);
+        if block1.num + 1 == expected && block1.more {
+            Ok(None)
+        } else if block1.num != expected {
+            Err(code::REQUEST_ENTITY_INCOMPLETE)
+        } else {
+            Ok(Some(block1.offset()))
+        }
+    }
+}
+
+impl<T: Ticks> Default for App<T> {
+    fn default() -> App<T> {
+        App {
+            resources: [None; MAX_RESOURCES],
+            request: None,
+            block1: None,
+            served: None,
+            outgoing: None,
+            dropped_replies: 0,
+        }
+    }
+}
+
+/// A message generated by the kernel in response to a received message.
+#[derive(Copy, Clone)]
+struct Reply {
+    peer: Peer,
+    msg_type: MessageType,
+    message_id: u16,
+    token: Token,
+    code: u8,
+    content_format: Option<u16>,
+    block1: Option<BlockOption>,
+    /// Block of an app's response payload to include.
+    block2: Option<(ProcessId, BlockOption)>,
+    /// App the reply is sent on behalf of, which is notified if the reply
+    /// has to be dropped.
+    owner: Option<ProcessId>,
+}
+
+#[derive(Copy, Clone)]
+struct DedupEntry<T: Ticks> {
+    peer: Peer,
+    message_id: u16,
+    received: T,
+    delivered: bool,
+}
+
+#[derive(Copy, Clone)]
+enum InFlight {
+    Reply,
+    App(ProcessId),
+}
+
+/// Options of a received message that the kernel interprets.
+struct ParsedOptions<'b> {
+    path: [u8; MAX_PATH_LEN],
+    path_len: usize,
+    path_valid: bool,
+    block1: Option<BlockOption>,
+    block2: Option<BlockOption>,
+    unknown_critical: bool,
+    payload: &'b [u8],
+}
+
+impl<'b> ParsedOptions<'b> {
+    fn parse(buf: &'b [u8], offset: usize) -> Option<ParsedOptions<'b>> {
+        let mut path = [0; MAX_PATH_LEN];
+        let mut path_len = 0;
+        let mut path_valid = true;
+        let mut block1 = None;
+        let mut block2 = None;
+        let mut unknown_critical = false;
+        let mut block_valid = true;
+
+        let payload = parse_options(buf, offset, |number, value| match number {
+            option::URI_PATH => {
+                let sep = usize::from(path_len != 0);
+                if path_len + sep + value.len() > MAX_PATH_LEN {
+                    path_valid = false;
+                } else {
+                    if sep != 0 {
+                        path[path_len] = b'/';
+                    }
+                    path[path_len + sep..path_len + sep + value.len()].copy_from_slice(value);
+                    path_len += sep + value.len();
+                }
+            }
+            option::BLOCK1 => {
+                block1 = BlockOption::decode(value);
+                block_valid &= block1.is_some();
+            }
+            option::BLOCK2 => {
+                block2 = BlockOption::decode(value);
+                block_valid &= block2.is_some();
+            }
+            // Odd option numbers are critical and must not be ignored.
+            n if n & 1 == 1 => unknown_critical = true,
+            _ => {}
+        })
+        .ok()?;
+
+        if !block_valid {
+            return None;
+        }
+        Some(ParsedOptions {
+            path,
+            path_len,
+            path_valid,
+            block1,
+            block2,
+            unknown_critical,
+            payload,
+        })
+    }
+
+    fn path(&self) -> &[u8] {
+        &self.path[..self.path_len]
+    }
+}
+
+pub struct CoapDriver<'a, A: time::Alarm<'a>> {
+    /// UDP sender, bound to the CoAP port.
+    sender: &'a dyn UDPSender<'a>,
+
+    /// Alarm for retransmission and response timeouts.
+    alarm: &'a A,
+
+    /// Grant of apps that use this driver.
+    apps: Grant<
+        App<A::Ticks>,
+        UpcallCount<{ upcall::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+
+    /// Network capability used for sending.
+    net_cap: &'static NetworkCapability,
+
+    /// Buffer in which outgoing messages are built.
+    send_buffer: MapCell<SubSliceMut<'static, u8>>,
+
+    /// The message currently held by the UDP sender, if any.
+    in_flight: OptionalCell<InFlight>,
+
+    /// Kernel-generated messages waiting for the UDP sender.
+    replies: [OptionalCell<Reply>; REPLY_QUEUE_LEN],
+
+    /// Recently received messages, for duplicate detection.
+    dedup: [OptionalCell<DedupEntry<A::Ticks>>; DEDUP_CACHE_LEN],
+
+    /// Block size exponent used for block-wise transfers.
+    szx: u8,
+
+    next_message_id: OptionalCell<u16>,
+    next_token: OptionalCell<u32>,
+}
+
+impl<'a, A: time::Alarm<'a>> CoapDriver<'a, A> {
+    pub fn new(
+        sender: &'a dyn UDPSender<'a>,
+        alarm: &'a A,
+        grant: Grant<
+            App<A::Ticks>,
+            UpcallCount<{ upcall::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+        net_cap: &'static NetworkCapability,
+        send_buffer: SubSliceMut<'static, u8>,
+    ) -> CoapDriver<'a, A> {
+        let szx = size_to_szx(send_buffer.len().saturating_sub(MESSAGE_OVERHEAD)).unwrap_or(0);
+        CoapDriver {
+            sender,
+            alarm,
+            apps: grant,
+            net_cap,
+            send_buffer: MapCell::new(send_buffer),
+            in_flight: OptionalCell::empty(),
+            replies: core::array::from_fn(|_| OptionalCell::empty()),
+            dedup: core::array::from_fn(|_| OptionalCell::empty()),
+            szx,
+            next_message_id: OptionalCell::new(1),
+            next_token: OptionalCell::new(1),
+        }
+    }
+
+    /// Returns the block size used for block-wise transfers.
+    pub fn block_size(&self) -> usize {
+        szx_to_size(self.szx)
+    }
+
+    fn new_message_id(&self) -> u16 {
+        let id = self.next_message_id.unwrap_or(1);
+        self.next_message_id.set(id.wrapping_add(1));
+        id
+    }
+
+    fn new_token(&self) -> Token {
+        let token = self.next_token.unwrap_or(1);
+        self.next_token.set(token.wrapping_add(1));
+        Token::new(&token.to_be_bytes()).unwrap_or_default()
+    }
+
+    /// Retransmission timeout after the `transmissions`-th transmission of
+    /// message `message_id`. The message ID provides the randomization
+    /// between `ACK_TIMEOUT` and `1.5 * ACK_TIMEOUT`.
+    fn retransmission_timeout_ms(message_id: u16, transmissions: u8) -> u32 {
+        let initial = ACK_TIMEOUT_MS + u32::from(message_id) % (ACK_TIMEOUT_MS / 2 + 1);
+        initial << transmissions.saturating_sub(1)
+    }
+
+    fn expiration_ms(&self, ms: u32) -> Expiration<A::Ticks> {
+        Expiration {
+            reference: self.alarm.now(),
+            dt: self.alarm.ticks_from_ms(ms),
+        }
+    }
+
+    // Deduplication
+
+    /// Looks up a received message. Returns `Some(delivered)` if the message
+    /// was already received within `EXCHANGE_LIFETIME`.
+    fn dedup_lookup(&self, peer: Peer, message_id: u16) -> Option<bool> {
+        let now = self.alarm.now();
+        let lifetime = self.alarm.ticks_from_seconds(EXCHANGE_LIFETIME_S);
+        self.dedup.iter().find_map(|entry| {
+            entry.get().and_then(|e| {
+                let fresh = now.within_range(e.received, e.received.wrapping_add(lifetime));
+                (fresh && e.peer == peer && e.message_id == message_id).then_some(e.delivered)
+            })
+        })
+    }
+
+    /// Records a received message, replacing the oldest entry if the cache
+    /// is full.
+    fn dedup_record(&self, peer: Peer, message_id: u16, delivered: bool) {
+        let now = self.alarm.now();
+        let entry = DedupEntry {
+            peer,
+            message_id,
+            received: now,
+            delivered,
+        };
+        if let Some(slot) = self.dedup.iter().find(|slot| {
+            slot.get()
+                .is_some_and(|e| e.peer == peer && e.message_id == message_id)
+        }) {
+            slot.set(entry);
+            return;
+        }
+        let oldest = self
+            .dedup
+            .iter()
+            .max_by_key(|slot| {
+                slot.get()
+                    .map_or(u32::MAX, |e| now.wrapping_sub(e.received).into_u32())
+            })
+            .unwrap_or(&self.dedup[0]);
+        oldest.set(entry);
+    }
+
+    // Transmission
+
+    fn queue_reply(&self, reply: Reply) {
+        match self.replies.iter().find(|slot| slot.is_none()) {
+            Some(slot) => slot.set(reply),
+            None => self.reply_dropped(&reply),
+        }
+        self.send_next();
+    }
+
+    /// Notifies the owner of a reply that did not fit in the queue. The
+    /// peer retransmits confirmable messages, but non-confirmable exchanges
+    /// are lost, so the app is told either way.
+    fn reply_dropped(&self, reply: &Reply) {
+        if let Some(processid) = reply.owner {
+            let _ = self.apps.enter(processid, |app, kernel_data| {
+                app.dropped_replies += 1;
+                kernel_data
+                    .schedule_upcall(
+                        upcall::REPLY_DROPPED,
+                        (reply.code as usize, app.dropped_replies, 0),
+                    )
+                    .ok();
+            });
+        }
+    }
+
+    /// Queues a reply without options or payload, e.g. an empty ACK or a
+    /// piggybacked error, on behalf of `owner`.
+    fn reply(&self, peer: Peer, hdr: &CoapHeader, code: u8, owner: Option<ProcessId>) {
+        let msg_type = match (hdr.msg_type, code) {
+            (MessageType::Confirmable, _) => MessageType::Acknowledgement,
+            (_, code::EMPTY) => MessageType::Reset,
+            _ => MessageType::NonConfirmable,
+        };
+        let (message_id, token) = if msg_type == MessageType::NonConfirmable {
+            (self.new_message_id(), hdr.token)
+        } else if code == code::EMPTY {
+            (hdr.message_id, Token::default())
+        } else {
+            (hdr.message_id, hdr.token)
+        };
+        self.queue_reply(Reply {
+            peer,
+            msg_type,
+            message_id,
+            token,
+            code,
+            content_format: None,
+            block1: None,
+            block2: None,
+            owner,
+        });
+    }
+
+    fn reset(&self, peer: Peer, message_id: u16) {
+        self.queue_reply(Reply {
+            peer,
+            msg_type: MessageType::Reset,
+            message_id,
+            token: Token::default(),
+            code: code::EMPTY,
+            content_format: None,
+            block1: None,
+            block2: None,
+            owner: None,
+        });
+    }
+
+    /// Starts the next transmission if the UDP sender is idle. Kernel
+    /// replies take precedence over app messages.
+    fn send_next(&self) {
+        if self.in_flight.is_some() {
+            return;
+        }
+        for slot in self.replies.iter() {
+            if let Some(reply) = slot.take() {
+                if self.transmit_reply(reply).is_ok() {
+                    return;
+                }
+            }
+        }
+        let next = self.apps.iter().find_map(|app| {
+            let processid = app.processid();
+            app.enter(|app, _| {
+                app.outgoing
+                    .is_some_and(|out| out.state == TxState::Queued)
+                    .then_some(processid)
+            })
+        });
+        if let Some(processid) = next {
+            self.transmit_outgoing(processid);
+        }
+    }
+
+    fn transmit(&self, peer: Peer, mut buf: SubSliceMut<'static, u8>, len: usize) -> bool {
+        buf.slice(0..len);
+        match self.sender.send_to(peer.addr, peer.port, buf, self.net_cap) {
+            Ok(()) => true,
+            Err(mut buf) => {
+                buf.reset();
+                self.send_buffer.replace(buf);
+                false
+            }
+        }
+    }
+
+    fn transmit_reply(&self, reply: Reply) -> Result<(), ErrorCode> {
+        let mut buf = self.send_buffer.take().ok_or(ErrorCode::BUSY)?;
+        match self.encode_reply(&reply, buf.as_slice()) {
+            Ok(len) => {
+                if self.transmit(reply.peer, buf, len) {
+                    self.in_flight.set(InFlight::Reply);
+                    Ok(())
+                } else {
+                    Err(ErrorCode::FAIL)
+                }
+            }
+            Err(e) => {
+                self.send_buffer.replace(buf);
+                Err(e)
+            }
+        }
+    }
+
+    fn encode_reply(&self, reply: &Reply, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+        let hdr = CoapHeader::new(reply.msg_type, reply.code, reply.message_id, reply.token);
+        let (off, _) = hdr.encode(buf, 0).done().ok_or(ErrorCode::SIZE)?;
+        let mut writer = OptionWriter::new(buf, off);
+        if let Some(content_format) = reply.content_format {
+            writer.push_uint(option::CONTENT_FORMAT, content_format.into())?;
+        }
+        match reply.block2 {
+            Some((processid, block)) => {
+                self.apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readonly_processbuffer(ro_allow::RESPONSE)
+                            .and_then(|response| {
+                                response.enter(|response| {
+                                    let start = cmp::min(block.offset(), response.len());
+                                    let end = cmp::min(start + block.size(), response.len());
+                                    let block = BlockOption::new(
+                                        block.num,
+                                        end < response.len(),
+                                        block.szx,
+                                    );
+                                    writer.push_block(option::BLOCK2, block)?;
+                                    response[start..end]
+                                        .copy_to_slice_or_err(writer.payload(end - start)?)
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    })
+                    .unwrap_or_else(|err| Err(err.into()))?;
+            }
+            None => {
+                if let Some(block1) = reply.block1 {
+                    writer.push_block(option::BLOCK1, block1)?;
+                }
+            }
+        }
+        Ok(writer.finish())
+    }
+
+    /// Encodes and sends the app's outgoing message. Failures are reported
+    /// to the app through the usual upcall.
+    fn transmit_outgoing(&self, processid: ProcessId) {
+        let mut buf = match self.send_buffer.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        let encoded = self.apps.enter(processid, |app, kernel_data| {
+            let out = app.outgoing.as_mut()?;
+            let result = Self::encode_outgoing(out, kernel_data, buf.as_slice());
+            match result {
+                Ok(len) => {
+                    out.transmissions += 1;
+                    out.state = TxState::Sending;
+                    out.timeout = None;
+                    Some(Ok((out.peer, len)))
+                }
+                Err(e) => Some(Err(e)),
+            }
+        });
+        match encoded {
+            Ok(Some(Ok((peer, len)))) => {
+                if self.transmit(peer, buf, len) {
+                    self.in_flight.set(InFlight::App(processid));
+                } else {
+                    self.finish_outgoing(processid, Err(ErrorCode::FAIL), 0);
+                }
+            }
+            Ok(Some(Err(e))) => {
+                self.send_buffer.replace(buf);
+                self.finish_outgoing(processid, Err(e), 0);
+            }
+            _ => {
+                self.send_buffer.replace(buf);
+            }
+        }
+        if self.in_flight.is_none() {
+            self.send_next();
+        }
+    }
+
+    fn encode_outgoing(
+        out: &Outgoing<A::Ticks>,
+        kernel_data: &GrantKernelData,
+        buf: &mut [u8],
+    ) -> Result<usize, ErrorCode> {
+        let msg_type = if out.confirmable {
+            MessageType::Confirmable
+        } else {
+            MessageType::NonConfirmable
+        };
+        let hdr = CoapHeader::new(msg_type, out.code, out.message_id, out.token);
+        let (off, _) = hdr.encode(buf, 0).done().ok_or(ErrorCode::SIZE)?;
+        let mut writer = OptionWriter::new(buf, off);
+
+        if out.role == Role::Request {
+            kernel_data
+                .get_readonly_processbuffer(ro_allow::PATH)
+                .and_then(|path| {
+                    path.enter(|path| {
+                        let mut tmp = [0; MAX_PATH_LEN];
+                        let len = cmp::min(path.len(), MAX_PATH_LEN);
+                        path[..len].copy_to_slice(&mut tmp[..len]);
+                        tmp[..len]
+                            .split(|&c| c == b'/')
+                            .filter(|segment| !segment.is_empty())
+                            .try_for_each(|segment| writer.push(option::URI_PATH, segment))
+                    })
+                })
+                .unwrap_or(Err(ErrorCode::RESERVE))?;
+        }
+        if let Some(content_format) = out.content_format {
+            writer.push_uint(option::CONTENT_FORMAT, content_format.into())?;
+        }
+
+        // Requests for subsequent Block2 blocks of a response do not carry
+        // the request payload again.
+        if let Some(block2) = out.block2 {
+            writer.push_block(option::BLOCK2, block2)?;
+            return Ok(writer.finish());
+        }
+
+        let (allow, option) = match out.role {
+            Role::Request => (ro_allow::REQUEST, option::BLOCK1),
+            Role::Response => (ro_allow::RESPONSE, option::BLOCK2),
+        };
+        kernel_data
+            .get_readonly_processbuffer(allow)
+            .and_then(|payload| {
+                payload.enter(|payload| {
+                    let (block, start, end) = out.payload_block(payload.len());
+                    if let Some(block) = block {
+                        writer.push_block(option, block)?;
+                    }
+                    payload[start..end].copy_to_slice_or_err(writer.payload(end - start)?)
+                })
+            })
+            .unwrap_or(Ok(()))?;
+        Ok(writer.finish())
+    }
+
+    /// Completes the app's outgoing exchange and notifies the app.
+    fn finish_outgoing(&self, processid: ProcessId, result: Result<(), ErrorCode>, code: u8) {
+        let _ = self.apps.enter(processid, |app, kernel_data| {
+            if let Some(out) = app.outgoing.take() {
+                Self::notify(&out, kernel_data, result, code);
+            }
+        });
+    }
+
+    fn notify(
+        out: &Outgoing<A::Ticks>,
+        kernel_data: &GrantKernelData,
+        result: Result<(), ErrorCode>,
+        code: u8,
+    ) {
+        let _ = match out.role {
+            Role::Request => kernel_data.schedule_upcall(
+                upcall::RESPONSE_RECEIVED,
+                (into_statuscode(result), code as usize, out.rx_len),
+            ),
+            Role::Response => {
+                kernel_data.schedule_upcall(upcall::RESPONSE_SENT, (into_statuscode(result), 0, 0))
+            }
+        };
+    }
+
+    // Timeouts
+
+    /// Handles expired timeouts and re-arms the alarm for the earliest
+    /// remaining one.
+    fn update_timeouts(&self) {
+        let now = self.alarm.now();
+        let mut earliest: Option<A::Ticks> = None;
+        let mut retransmit = false;
+        for app in self.apps.iter() {
+            app.enter(|app, kernel_data| {
+                let out = match app.outgoing.as_mut() {
+                    Some(out) => out,
+                    None => return,
+                };
+                let timeout = match out.timeout {
+                    Some(timeout) => timeout,
+                    None => return,
+                };
+                let end = timeout.reference.wrapping_add(timeout.dt);
+                if now.within_range(timeout.reference, end) {
+                    let remaining = end.wrapping_sub(now);
+                    earliest = Some(earliest.map_or(remaining, |e| cmp::min(e, remaining)));
+                } else if out.state == TxState::AwaitingAck && out.transmissions <= MAX_RETRANSMIT {
+                    out.state = TxState::Queued;
+                    out.timeout = None;
+                    retransmit = true;
+                } else {
+                    Self::notify(out, kernel_data, Err(ErrorCode::NOACK), 0);
+                    app.outgoing = None;
+                }
+            });
+        }
+        match earliest {
+            Some(dt) => self.alarm.set_alarm(now, dt),
+            None => {
+                let _ = self.alarm.disarm();
+            }
+        }
+        if retransmit {
+            self.send_next();
+        }
+    }
+
+    // Reception
+
+    /// Finds the app and resource index registered for `path`.
+    fn find_resource(&self, path: &[u8]) -> Option<(ProcessId, usize)> {
+        self.apps.iter().find_map(|app| {
+            let processid = app.processid();
+            app.enter(|app, _| {
+                app.resources
+                    .iter()
+                    .position(|r| r.is_some_and(|r| r.path() == path))
+                    .map(|idx| (processid, idx))
+            })
+        })
+    }
+
+    fn receive_request(&self, peer: Peer, hdr: &CoapHeader, opts: &ParsedOptions) {
+        let confirmable = hdr.msg_type == MessageType::Confirmable;
+        match self.dedup_lookup(peer, hdr.message_id) {
+            // The request was already handed to an app. Acknowledge it again
+            // but do not deliver it twice.
+            Some(true) => {
+                if confirmable {
+                    self.reply(peer, hdr, code::EMPTY, None);
+                }
+                return;
+            }
+            // Requests that were answered by the kernel are handled again,
+            // which regenerates the same answer.
+            Some(false) | None => self.dedup_record(peer, hdr.message_id, false),
+        }
+
+        if opts.unknown_critical {
+            self.reply(peer, hdr, code::BAD_OPTION, None);
+            return;
+        }
+        let (processid, resource) = match opts
+            .path_valid
+            .then(|| self.find_resource(opts.path()))
+            .flatten()
+        {
+            Some(found) => found,
+            None => {
+                self.reply(peer, hdr, code::NOT_FOUND, None);
+                return;
+            }
+        };
+
+        // Follow-up requests for further blocks of a response are served
+        // from the app's response buffer without involving the app.
+        if let Some(block2) = opts.block2.filter(|block2| block2.num > 0) {
+            self.serve_block2(peer, hdr, processid, resource, block2);
+            return;
+        }
+
+        let result = self.apps.enter(processid, |app, kernel_data| {
+            if app.request.is_some() {
+                return Err(code::SERVICE_UNAVAILABLE);
+            }
+
+            let offset = match app.block1_offset(peer, resource, opts.block1)? {
+                Some(offset) => offset,
+                // Retransmission of a block that was already stored.
+                None => return Ok(opts.block1),
+            };
+
+            let len = offset + opts.payload.len();
+            kernel_data
+                .get_readwrite_processbuffer(rw_allow::REQUEST)
+                .and_then(|buffer| {
+                    buffer.mut_enter(|buffer| {
+                        buffer
+                            .get(offset..len)
+                            .map(|dest| dest.copy_from_slice(opts.payload))
+                    })
+                })
+                .ok()
+                .flatten()
+                .ok_or(code::REQUEST_ENTITY_TOO_LARGE)?;
+
+            if let Some(block1) = opts.block1.filter(|block1| block1.more) {
+                app.block1 = Some(Block1Rx {
+                    peer,
+                    resource,
+                    next_num: block1.num + 1,
+                });
+                return Ok(Some(block1));
+            }
+            app.block1 = None;
+
+            let _ = kernel_data
+                .get_readwrite_processbuffer(rw_allow::SOURCE)
+                .and_then(|source| {
+                    source.mut_enter(|source| source.copy_from_slice_or_err(&peer.encode()))
+                });
+            let szx = opts
+                .block2
+                .map_or(self.szx, |block2| cmp::min(block2.szx, self.szx));
+            app.request = Some(PendingRequest {
+                peer,
+                token: hdr.token,
+                confirmable,
+                resource,
+                szx,
+            });
+            kernel_data
+                .schedule_upcall(upcall::REQUEST_RECEIVED, (resource, hdr.code as usize, len))
+                .ok();
+            Ok(None)
+        });
+
+        match result {
+            // Delivered to the app, which answers with a separate response.
+            Ok(Ok(None)) => {
+                self.dedup_record(peer, hdr.message_id, true);
+                if confirmable {
+                    self.reply(peer, hdr, code::EMPTY, Some(processid));
+                }
+            }
+            // Intermediate Block1 block, ask for the next one.
+            Ok(Ok(Some(block1))) => {
+                self.queue_reply(Reply {
+                    block1: Some(block1),
+                    ..self.response_to(peer, hdr, code::CONTINUE, processid)
+                });
+            }
+            Ok(Err(code)) => self.reply(peer, hdr, code, Some(processid)),
+            Err(_) => self.reply(peer, hdr, code::INTERNAL_SERVER_ERROR, None),
+        }
+    }
+
+    /// Builds a piggybacked (for CON) or non-confirmable response to `hdr`
+    /// on behalf of `owner`.
+    fn response_to(&self, peer: Peer, hdr: &CoapHeader, code: u8, owner: ProcessId) -> Reply {
+        let (msg_type, message_id) = if hdr.msg_type == MessageType::Confirmable {
+            (MessageType::Acknowledgement, hdr.message_id)
+        } else {
+            (MessageType::NonConfirmable, self.new_message_id())
+        };
+        Reply {
+            peer,
+            msg_type,
+            message_id,
+            token: hdr.token,
+            code,
+            content_format: None,
+            block1: None,
+            block2: None,
+            owner: Some(owner),
+        }
+    }
+
+    fn serve_block2(
+        &self,
+        peer: Peer,
+        hdr: &CoapHeader,
+        processid: ProcessId,
+        resource: usize,
+        block2: BlockOption,
+    ) {
+        let served = self
+            .apps
+            .enter(processid, |app, _| {
+                app.served
+                    .filter(|served| served.peer == peer && served.resource == resource)
+            })
+            .ok()
+            .flatten();
+        match served {
+            Some(served) => {
+                let block = BlockOption::new(block2.num, false, cmp::min(block2.szx, self.szx));
+                self.queue_reply(Reply {
+                    content_format: served.content_format,
+                    block2: Some((processid, block)),
+                    ..self.response_to(peer, hdr, served.code, processid)
+                });
+            }
+            None => self.reply(peer, hdr, code::REQUEST_ENTITY_INCOMPLETE, Some(processid)),
+        }
+    }
+
+    /// Handles ACK and RST messages, which complete confirmable messages
+    /// sent by apps.
+    fn receive_ack(&self, peer: Peer, hdr: &CoapHeader, opts: &ParsedOptions) {
+        let matched = self.apps.iter().find_map(|app| {
+            let processid = app.processid();
+            app.enter(|app, _| {
+                app.outgoing
+                    .filter(|out| out.acked_by(peer, hdr.message_id))
+                    .map(|out| (processid, out.role))
+            })
+        });
+        let (processid, role) = match matched {
+            Some(matched) => matched,
+            None => return,
+        };
+
+        if hdr.msg_type == MessageType::Reset {
+            self.finish_outgoing(processid, Err(ErrorCode::FAIL), 0);
+        } else if role == Role::Response {
+            self.finish_outgoing(processid, Ok(()), 0);
+        } else if hdr.code == code::EMPTY {
+            // Separate response will follow.
+            let timeout = self.expiration_ms(MAX_TRANSMIT_WAIT_S * 1000);
+            let _ = self.apps.enter(processid, |app, _| {
+                if let Some(out) = app.outgoing.as_mut() {
+                    out.state = TxState::AwaitingResponse;
+                    out.timeout = Some(timeout);
+                }
+            });
+        } else {
+            self.receive_response(processid, hdr, opts);
+        }
+        self.update_timeouts();
+    }
+
+    /// Handles a response to the app's request, which can be piggybacked on
+    /// an ACK or be a separate CON or NON message.
+    fn receive_response(&self, processid: ProcessId, hdr: &CoapHeader, opts: &ParsedOptions) {
+        let next_message_id = self.new_message_id();
+        let result = self.apps.enter(processid, |app, kernel_data| {
+            let out = match app.outgoing.as_mut() {
+                Some(out) => out,
+                None => return None,
+            };
+
+            // The server asks for the next block of the request payload,
+            // possibly with a smaller block size.
+            if let Some(block1) = opts.block1.filter(|_| hdr.code == code::CONTINUE) {
+                out.continue_block1(block1, next_message_id);
+                return Some(false);
+            }
+
+            let offset = opts.block2.map_or(0, |block2| block2.offset());
+            let len = offset + opts.payload.len();
+            let copied = kernel_data
+                .get_readwrite_processbuffer(rw_allow::RESPONSE)
+                .and_then(|buffer| {
+                    buffer.mut_enter(|buffer| {
+                        buffer
+                            .get(offset..len)
+                            .map(|dest| dest.copy_from_slice(opts.payload))
+                    })
+                })
+                .ok()
+                .flatten()
+                .is_some();
+            if !copied {
+                Self::notify(out, kernel_data, Err(ErrorCode::SIZE), hdr.code);
+                app.outgoing = None;
+                return Some(true);
+            }
+            out.rx_len = len;
+
+            match opts.block2 {
+                Some(block2) if block2.more => {
+                    out.fetch_block2(block2, next_message_id);
+                    Some(false)
+                }
+                _ => {
+                    Self::notify(out, kernel_data, Ok(()), hdr.code);
+                    app.outgoing = None;
+                    Some(true)
+                }
+            }
+        });
+        if let Ok(Some(false)) = result {
+            self.send_next();
+        }
+    }
+
+    fn receive_separate_response(&self, peer: Peer, hdr: &CoapHeader, opts: &ParsedOptions) {
+        let matched = self.apps.iter().find_map(|app| {
+            let processid = app.processid();
+            app.enter(|app, _| {
+                app.outgoing
+                    .filter(|out| out.answered_by(peer, hdr.token))
+                    .map(|_| processid)
+            })
+        });
+        let duplicate = self.dedup_lookup(peer, hdr.message_id).is_some();
+        if hdr.msg_type == MessageType::Confirmable {
+            if matched.is_some() || duplicate {
+                self.reply(peer, hdr, code::EMPTY, matched);
+            } else {
+                self.reset(peer, hdr.message_id);
+                return;
+            }
+        }
+        if duplicate {
+            return;
+        }
+        self.dedup_record(peer, hdr.message_id, true);
+        if let Some(processid) = matched {
+            self.receive_response(processid, hdr, opts);
+            self.update_timeouts();
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> SyscallDriver for CoapDriver<'a, A> {
+    /// CoAP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver existence check.
+    /// - `1`: Register the resource whose path is in the `PATH` read-only
+    ///        buffer (e.g. `sensors/temp`, without a leading slash). Returns
+    ///        the resource index, which is passed to the request upcall.
+    ///        Returns BUSY if another app registered the same path, and NOMEM
+    ///        if the app already registered `MAX_RESOURCES` resources.
+    /// - `2`: Unregister the resource with index `arg1`.
+    /// - `3`: Respond to the pending request with code `arg1` (e.g. `0x45`
+    ///        for 2.05 Content) and the payload in the `RESPONSE` read-only
+    ///        buffer. If bit 16 of `arg2` is set, a Content-Format option
+    ///        with the value in its lower 16 bits is included. Returns
+    ///        RESERVE if there is no pending request and BUSY if another
+    ///        message from this app is in flight.
+    /// - `4`: Send a request with method `arg1` (1 GET, 2 POST, 3 PUT,
+    ///        4 DELETE) to the endpoint in the `DESTINATION` buffer, for the
+    ///        path in the `PATH` buffer and with the payload in the `REQUEST`
+    ///        buffer. Bit 8 of `arg1` makes the request confirmable. `arg2`
+    ///        selects the Content-Format as for command 3. Returns BUSY if
+    ///        another message from this app is in flight.
+    /// - `5`: Cancel the app's outstanding request or response.
+    /// - `6`: Returns the block size used for block-wise transfers.
+    fn command(
+        &self,
+        command_num: usize,
+        arg1: usize,
+        arg2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let content_format = (arg2 & CONTENT_FORMAT_PRESENT != 0).then_some((arg2 & 0xffff) as u16);
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => {
+                let mut path = [0; MAX_PATH_LEN];
+                let len = self
+                    .apps
+                    .enter(processid, |_, kernel_data| {
+                        kernel_data
+                            .get_readonly_processbuffer(ro_allow::PATH)
+                            .and_then(|buf| {
+                                buf.enter(|buf| {
+                                    let len = buf.len();
+                                    if len == 0 || len > MAX_PATH_LEN {
+                                        return Err(ErrorCode::SIZE);
+                                    }
+                                    buf.copy_to_slice(&mut path[..len]);
+                                    Ok(len)
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                let len = match len {
+                    Ok(len) => len,
+                    Err(e) => return CommandReturn::failure(e),
+                };
+                if path[0] == b'/' || path[len - 1] == b'/' {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                if self.find_resource(&path[..len]).is_some() {
+                    return CommandReturn::failure(ErrorCode::BUSY);
+                }
+                self.apps
+                    .enter(processid, |app, _| {
+                        match app.resources.iter().position(|r| r.is_none()) {
+                            Some(idx) => {
+                                app.resources[idx] = Some(Resource { path, len });
+                                CommandReturn::success_u32(idx as u32)
+                            }
+                            None => CommandReturn::failure(ErrorCode::NOMEM),
+                        }
+                    })
+                    .unwrap_or_else(|err| CommandReturn::failure(err.into()))
+            }
+
+            2 => self
+                .apps
+                .enter(processid, |app, _| {
+                    match app.resources.get_mut(arg1).and_then(|r| r.take()) {
+                        Some(_) => {
+                            if app.request.is_some_and(|r| r.resource == arg1) {
+                                app.request = None;
+                            }
+                            if app.served.is_some_and(|s| s.resource == arg1) {
+                                app.served = None;
+                            }
+                            CommandReturn::success()
+                        }
+                        None => CommandReturn::failure(ErrorCode::INVAL),
+                    }
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+
+            3 => {
+                let message_id = self.new_message_id();
+                let res = self
+                    .apps
+                    .enter(processid, |app, _| {
+                        if app.outgoing.is_some() {
+                            return Err(ErrorCode::BUSY);
+                        }
+                        if !code::is_response(arg1 as u8) || arg1 > u8::MAX as usize {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        let request = app.request.take().ok_or(ErrorCode::RESERVE)?;
+                        app.served = Some(Served {
+                            peer: request.peer,
+                            resource: request.resource,
+                            code: arg1 as u8,
+                            content_format,
+                        });
+                        app.outgoing = Some(Outgoing {
+                            role: Role::Response,
+                            peer: request.peer,
+                            token: request.token,
+                            message_id,
+                            confirmable: request.confirmable,
+                            code: arg1 as u8,
+                            content_format,
+                            block1: 0,
+                            block2: None,
+                            szx: request.szx,
+                            rx_len: 0,
+                            transmissions: 0,
+                            state: TxState::Queued,
+                            timeout: None,
+                        });
+                        Ok(())
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                match res {
+                    Ok(()) => {
+                        self.send_next();
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            4 => {
+                let method = (arg1 & 0xff) as u8;
+                if !code::is_request(method) || method > code::DELETE {
+                    return CommandReturn::failure(ErrorCode::INVAL);
+                }
+                let message_id = self.new_message_id();
+                let token = self.new_token();
+                let res = self
+                    .apps
+                    .enter(processid, |app, kernel_data| {
+                        if app.outgoing.is_some() {
+                            return Err(ErrorCode::BUSY);
+                        }
+                        let peer = kernel_data
+                            .get_readwrite_processbuffer(rw_allow::DESTINATION)
+                            .and_then(|dest| {
+                                dest.enter(|dest| {
+                                    let mut tmp = [0; ENDPOINT_LEN];
+                                    dest.copy_to_slice_or_err(&mut tmp)
+                                        .ok()
+                                        .and_then(|()| Peer::decode(&tmp))
+                                })
+                            })
+                            .ok()
+                            .flatten()
+                            .ok_or(ErrorCode::INVAL)?;
+                        app.outgoing = Some(Outgoing {
+                            role: Role::Request,
+                            peer,
+                            token,
+                            message_id,
+                            confirmable: arg1 & (1 << 8) != 0,
+                            code: method,
+                            content_format,
+                            block1: 0,
+                            block2: None,
+                            szx: self.szx,
+                            rx_len: 0,
+                            transmissions: 0,
+                            state: TxState::Queued,
+                            timeout: None,
+                        });
+                        Ok(())
+                    })
+                    .unwrap_or_else(|err| Err(err.into()));
+                match res {
+                    Ok(()) => {
+                        self.send_next();
+                        CommandReturn::success()
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            5 => {
+                // A message currently held by the UDP sender completes
+                // normally in `send_done`, which finds nothing to update.
+                let res = self
+                    .apps
+                    .enter(processid, |app, _| app.outgoing.take().is_some())
+                    .unwrap_or(false);
+                self.update_timeouts();
+                if res {
+                    CommandReturn::success()
+                } else {
+                    CommandReturn::failure(ErrorCode::ALREADY)
+                }
+            }
+
+            6 => CommandReturn::success_u32(self.block_size() as u32),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> UDPSendClient for CoapDriver<'a, A> {
+    fn send_done(&self, result: Result<(), ErrorCode>, mut dgram: SubSliceMut<'static, u8>) {
+        dgram.reset();
+        self.send_buffer.replace(dgram);
+
+        if let Some(InFlight::App(processid)) = self.in_flight.take() {
+            let ack_timeout = self.apps.enter(processid, |app, kernel_data| {
+                let out = match app.outgoing.as_mut() {
+                    Some(out) if out.state == TxState::Sending => out,
+                    _ => return None,
+                };
+                if result.is_err() {
+                    Self::notify(out, kernel_data, result, 0);
+                    app.outgoing = None;
+                    None
+                } else if out.confirmable {
+                    out.state = TxState::AwaitingAck;
+                    Some(Self::retransmission_timeout_ms(
+                        out.message_id,
+                        out.transmissions,
+                    ))
+                } else if out.role == Role::Response {
+                    Self::notify(out, kernel_data, Ok(()), 0);
+                    app.outgoing = None;
+                    None
+                } else {
+                    out.state = TxState::AwaitingResponse;
+                    Some(MAX_TRANSMIT_WAIT_S * 1000)
+                }
+            });
+            if let Ok(Some(ms)) = ack_timeout {
+                let timeout = self.expiration_ms(ms);
+                let _ = self.apps.enter(processid, |app, _| {
+                    if let Some(out) = app.outgoing.as_mut() {
+                        out.timeout = Some(timeout);
+                    }
+                });
+                self.update_timeouts();
+            }
+        }
+        self.send_next();
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> UDPRecvClient for CoapDriver<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let peer = Peer {
+            addr: src_addr,
+            port: src_port,
+        };
+        let (offset, hdr) = match CoapHeader::decode(payload).done() {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let opts = match ParsedOptions::parse(payload, offset) {
+            Some(opts) => opts,
+            None => {
+                // Malformed confirmable messages are rejected, all others
+                // are silently ignored.
+                if hdr.msg_type == MessageType::Confirmable {
+                    self.reset(peer, hdr.message_id);
+                }
+                return;
+            }
+        };
+
+        match hdr.msg_type {
+            MessageType::Acknowledgement | MessageType::Reset => {
+                self.receive_ack(peer, &hdr, &opts)
+            }
+            MessageType::Confirmable | MessageType::NonConfirmable => {
+                if code::is_request(hdr.code) {
+                    self.receive_request(peer, &hdr, &opts);
+                } else if code::is_response(hdr.code) {
+                    self.receive_separate_response(peer, &hdr, &opts);
+                } else if hdr.msg_type == MessageType::Confirmable {
+                    // Empty CON messages ("CoAP ping") and unknown codes are
+                    // answered with RST.
+                    self.reset(peer, hdr.message_id);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: time::Alarm<'a>> time::AlarmClient for CoapDriver<'a, A> {
+    fn alarm(&self) {
+        self.update_timeouts();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::time::Ticks32;
+
+    const SZX_64: u8 = 2;
+
+    fn peer(last: u8) -> Peer {
+        let mut addr = IPAddr::new();
+        addr.0[15] = last;
+        Peer { addr, port: 5683 }
+    }
+
+    fn token(value: u8) -> Token {
+        Token::new(&[value]).unwrap()
+    }
+
+    fn request(state: TxState) -> Outgoing<Ticks32> {
+        Outgoing {
+            role: Role::Request,
+            peer: peer(1),
+            token: token(7),
+            message_id: 100,
+            confirmable: true,
+            code: code::POST,
+            content_format: None,
+            block1: 0,
+            block2: None,
+            szx: SZX_64,
+            rx_len: 0,
+            transmissions: 1,
+            state,
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn ack_matching() {
+        let out = request(TxState::AwaitingAck);
+        assert!(out.acked_by(peer(1), 100));
+        assert!(!out.acked_by(peer(2), 100));
+        assert!(!out.acked_by(peer(1), 101));
+        // The ACK can overtake the UDP sender's `send_done`.
+        assert!(request(TxState::Sending).acked_by(peer(1), 100));
+        // Nothing to acknowledge once the request was acknowledged or
+        // before it was sent.
+        assert!(!request(TxState::AwaitingResponse).acked_by(peer(1), 100));
+        assert!(!request(TxState::Queued).acked_by(peer(1), 100));
+    }
+
+    #[test]
+    fn separate_response_matching() {
+        let out = request(TxState::AwaitingResponse);
+        assert!(out.answered_by(peer(1), token(7)));
+        assert!(!out.answered_by(peer(1), token(8)));
+        assert!(!out.answered_by(peer(2), token(7)));
+        // A response can arrive before the empty ACK.
+        assert!(request(TxState::AwaitingAck).answered_by(peer(1), token(7)));
+        assert!(!request(TxState::Queued).answered_by(peer(1), token(7)));
+        // Responses sent by the app are never answered.
+        let response = Outgoing {
+            role: Role::Response,
+            ..request(TxState::AwaitingAck)
+        };
+        assert!(!response.answered_by(peer(1), token(7)));
+    }
+
+    #[test]
+    fn block1_transmission() {
+        let mut out = request(TxState::AwaitingAck);
+        assert_eq!(out.payload_block(64), (None, 0, 64));
+
+        let (block, start, end) = out.payload_block(200);
+        assert_eq!(block, Some(BlockOption::new(0, true, SZX_64)));
+        assert_eq!((start, end), (0, 64));
+
+        out.continue_block1(BlockOption::new(0, true, SZX_64), 101);
+        assert_eq!(out.message_id, 101);
+        assert_eq!(out.transmissions, 0);
+        assert!(out.state == TxState::Queued);
+        let (block, start, end) = out.payload_block(200);
+        assert_eq!(block, Some(BlockOption::new(1, true, SZX_64)));
+        assert_eq!((start, end), (64, 128));
+
+        // The server asks for 32 byte blocks, so the transfer continues at
+        // the same offset with twice the block number.
+        out.continue_block1(BlockOption::new(1, true, SZX_64 - 1), 102);
+        let (block, start, end) = out.payload_block(200);
+        assert_eq!(block, Some(BlockOption::new(4, true, SZX_64 - 1)));
+        assert_eq!((start, end), (128, 160));
+
+        out.continue_block1(BlockOption::new(4, true, SZX_64 - 1), 103);
+        out.continue_block1(BlockOption::new(5, true, SZX_64 - 1), 104);
+        let (block, start, end) = out.payload_block(200);
+        assert_eq!(block, Some(BlockOption::new(6, false, SZX_64 - 1)));
+        assert_eq!((start, end), (192, 200));
+    }
+
+    #[test]
+    fn block2_fetch() {
+        let mut out = request(TxState::AwaitingResponse);
+        out.fetch_block2(BlockOption::new(0, true, SZX_64), 101);
+        assert_eq!(out.block2, Some(BlockOption::new(1, false, SZX_64)));
+        assert_eq!(out.message_id, 101);
+        assert_eq!(out.transmissions, 0);
+        assert!(out.state == TxState::Queued);
+        // Only the message ID changes, the response is matched by token.
+        assert!(out.token == token(7));
+
+        out.fetch_block2(BlockOption::new(1, true, SZX_64), 102);
+        assert_eq!(out.block2, Some(BlockOption::new(2, false, SZX_64)));
+    }
+
+    #[test]
+    fn block1_reassembly() {
+        let mut app = App::<Ticks32>::default();
+        let block = |num, more| Some(BlockOption::new(num, more, SZX_64));
+
+        assert_eq!(app.block1_offset(peer(1), 0, None), Ok(Some(0)));
+        assert_eq!(app.block1_offset(peer(1), 0, block(0, true)), Ok(Some(0)));
+        // A transfer has to start with the first block.
+        assert_eq!(
+            app.block1_offset(peer(1), 0, block(1, true)),
+            Err(code::REQUEST_ENTITY_INCOMPLETE)
+        );
+
+        app.block1 = Some(Block1Rx {
+            peer: peer(1),
+            resource: 0,
+            next_num: 1,
+        });
+        assert_eq!(app.block1_offset(peer(1), 0, block(1, false)), Ok(Some(64)));
+        // Retransmission of the stored block, which is acknowledged again.
+        assert_eq!(app.block1_offset(peer(1), 0, block(0, true)), Ok(None));
+        assert_eq!(
+            app.block1_offset(peer(1), 0, block(2, true)),
+            Err(code::REQUEST_ENTITY_INCOMPLETE)
+        );
+        // The transfer belongs to one peer and resource.
+        assert_eq!(
+            app.block1_offset(peer(2), 0, block(1, true)),
+            Err(code::REQUEST_ENTITY_INCOMPLETE)
+        );
+        assert_eq!(
+            app.block1_offset(peer(1), 1, block(1, true)),
+            Err(code::REQUEST_ENTITY_INCOMPLETE)
+        );
+        assert_eq!(app.block1_offset(peer(2), 0, block(0, true)), Ok(Some(0)));
+    }
+}
@@ -0,0 +1,540 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! IPv6 over Ethernet.
+//!
+//! `IP6EthernetInterface` implements the `IP6Sender` and `IP6Receiver`
+//! traits on an Ethernet adapter, for example the USB CDC-ECM function, so
+//! the UDP stack can be used on boards without an 802.15.4 radio.
+//!
+//! The interface has a link-local address derived from its MAC address, and
+//! accepts packets to that address, to the addresses in its interface list,
+//! to the all-nodes multicast address and to the solicited-node multicast
+//! addresses of its unicast addresses. It handles enough of ICMPv6 itself
+//! to be reachable from a host:
+//!
+//! - Neighbor solicitations for its addresses are answered with neighbor
+//!   advertisements.
+//! - Echo requests are answered with echo replies, so the board can be
+//!   pinged.
+//!
+//! All other packets, in particular UDP, are passed to the receive client
+//! after their checksum has been verified.
+//!
+//! The MAC addresses of neighbors are learned from the packets they send,
+//! and from their neighbor solicitations and advertisements, in a small
+//! cache. The interface does not send neighbor solicitations itself. A
+//! packet to a unicast address that is not in the cache is sent to the
+//! Ethernet broadcast address, which is good enough for a point-to-point
+//! link such as USB.
+//!
+//! Replies are sent from a separate buffer, so they never delay a packet of
+//! the send client for longer than one frame. A reply is dropped if the
+//! previous reply is still being sent.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let ip6 = static_init!(
+//!     IP6EthernetInterface<'static, CdcEcm<'static, nrf52::usbd::Usbd>>,
+//!     IP6EthernetInterface::new(
+//!         ecm,
+//!         [0x02, 0x00, 0x00, 0x00, 0x00, 0x02],
+//!         local_ip_ifaces,
+//!         ip6_packet,
+//!         tx_buffer,
+//!         reply_buffer,
+//!         ip_vis,
+//!     )
+//! );
+//! ecm.set_client(ip6);
+//! ecm.enable_receive();
+//! ```
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::{compute_ipv6_ph_sum, ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvClient};
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::ipv6::{IP6Header, IP6Packet, TransportHeader};
+use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+
+use core::cell::Cell;
+
+use kernel::hil::ethernet::{
+    EthernetAdapterDatapath, EthernetAdapterDatapathClient, HEADER_LEN, MAC_ADDRESS_LEN,
+};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::SubSliceMut;
+use kernel::ErrorCode;
+
+/// EtherType of IPv6.
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// Length of the IPv6 header.
+const IP6_HEADER_LEN: usize = 40;
+
+/// Offset of the IPv6 payload in a frame.
+const PAYLOAD_OFFSET: usize = HEADER_LEN + IP6_HEADER_LEN;
+
+/// Number of neighbors whose MAC addresses are remembered.
+pub const NEIGHBOR_CACHE_LEN: usize = 4;
+
+const BROADCAST_MAC: [u8; MAC_ADDRESS_LEN] = [0xff; MAC_ADDRESS_LEN];
+
+// Transmission identifiers, to tell frames of the send client and replies
+// apart when the adapter returns them.
+const TX_CLIENT: usize = 0;
+const TX_REPLY: usize = 1;
+
+// ICMPv6 message types.
+const ICMP_ECHO_REQUEST: u8 = 128;
+const ICMP_ECHO_REPLY: u8 = 129;
+const ICMP_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMP_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+// Neighbor discovery options.
+const ND_OPTION_TARGET_LINK_ADDRESS: u8 = 2;
+
+// Neighbor advertisement flags.
+const NA_FLAG_SOLICITED: u8 = 0x40;
+const NA_FLAG_OVERRIDE: u8 = 0x20;
+
+/// Length of a neighbor advertisement with a target link-layer address
+/// option.
+const NA_LEN: usize = 32;
+
+/// The all-nodes link-local multicast address, ff02::1.
+const ALL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+#[derive(Copy, Clone)]
+struct Neighbor {
+    addr: IPAddr,
+    mac: [u8; MAC_ADDRESS_LEN],
+}
+
+/// Compute the checksum of an upper layer packet with the IPv6 pseudo
+/// header. Over a packet that includes a correct checksum, the result is 0.
+fn transport_checksum(header: &IP6Header, payload: &[u8]) -> u16 {
+    let mut sum = compute_ipv6_ph_sum(header);
+    for chunk in payload.chunks(2) {
+        sum += (chunk[0] as u32) << 8 | chunk.get(1).map_or(0, |lsb| *lsb as u32);
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the solicited-node multicast address of `addr`.
+fn solicited_node(addr: &IPAddr) -> IPAddr {
+    IPAddr([
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, addr.0[13], addr.0[14], addr.0[15],
+    ])
+}
+
+pub struct IP6EthernetInterface<'a, E: EthernetAdapterDatapath<'a>> {
+    ethernet: &'a E,
+    mac: [u8; MAC_ADDRESS_LEN],
+    /// Link-local address derived from the MAC address.
+    link_local: IPAddr,
+    /// Further unicast addresses of the interface.
+    interface_list: &'a [IPAddr],
+    /// Source address of packets from the send client.
+    src_addr: Cell<IPAddr>,
+
+    ip6_packet: TakeCell<'static, IP6Packet<'static>>,
+    tx_buf: TakeCell<'static, [u8]>,
+    /// Length of a frame of the send client that waits for a reply to be
+    /// sent.
+    tx_pending: OptionalCell<u16>,
+    reply_buf: TakeCell<'static, [u8]>,
+
+    neighbors: [Cell<Option<Neighbor>>; NEIGHBOR_CACHE_LEN],
+    /// Slot of the neighbor cache that is replaced next.
+    next_neighbor: Cell<usize>,
+
+    send_client: OptionalCell<&'a dyn IP6SendClient>,
+    recv_client: OptionalCell<&'a dyn IP6RecvClient>,
+    ip_vis: &'static IpVisibilityCapability,
+}
+
+impl<'a, E: EthernetAdapterDatapath<'a>> IP6EthernetInterface<'a, E> {
+    pub fn new(
+        ethernet: &'a E,
+        mac: [u8; MAC_ADDRESS_LEN],
+        interface_list: &'a [IPAddr],
+        ip6_packet: &'static mut IP6Packet<'static>,
+        tx_buf: &'static mut [u8],
+        reply_buf: &'static mut [u8],
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> Self {
+        // Modified EUI-64 interface identifier, RFC 4291 appendix A.
+        let link_local = IPAddr([
+            0xfe,
+            0x80,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            mac[0] ^ 0x02,
+            mac[1],
+            mac[2],
+            0xff,
+            0xfe,
+            mac[3],
+            mac[4],
+            mac[5],
+        ]);
+        Self {
+            ethernet,
+            mac,
+            link_local,
+            interface_list,
+            src_addr: Cell::new(link_local),
+            ip6_packet: TakeCell::new(ip6_packet),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_pending: OptionalCell::empty(),
+            reply_buf: TakeCell::new(reply_buf),
+            neighbors: [const { Cell::new(None) }; NEIGHBOR_CACHE_LEN],
+            next_neighbor: Cell::new(0),
+            send_client: OptionalCell::empty(),
+            recv_client: OptionalCell::empty(),
+            ip_vis,
+        }
+    }
+
+    /// The link-local address of the interface.
+    pub fn link_local_addr(&self) -> IPAddr {
+        self.link_local
+    }
+
+    /// True if `addr` is one of the unicast addresses of the interface.
+    fn is_own_addr(&self, addr: &IPAddr) -> bool {
+        *addr == self.link_local || self.interface_list.iter().any(|own| own == addr)
+    }
+
+    /// True if packets to `addr` are for this interface.
+    fn accepts(&self, addr: &IPAddr) -> bool {
+        self.is_own_addr(addr)
+            || *addr == ALL_NODES
+            || *addr == solicited_node(&self.link_local)
+            || self
+                .interface_list
+                .iter()
+                .any(|own| *addr == solicited_node(own))
+    }
+
+    fn lookup_neighbor(&self, addr: &IPAddr) -> Option<[u8; MAC_ADDRESS_LEN]> {
+        self.neighbors
+            .iter()
+            .filter_map(|neighbor| neighbor.get())
+            .find(|neighbor| neighbor.addr == *addr)
+            .map(|neighbor| neighbor.mac)
+    }
+
+    fn learn_neighbor(&self, addr: IPAddr, mac: [u8; MAC_ADDRESS_LEN]) {
+        if addr.is_unspecified() || addr.is_multicast() || mac[0] & 0x01 != 0 {
+            return;
+        }
+        let neighbor = Some(Neighbor { addr, mac });
+        match self
+            .neighbors
+            .iter()
+            .find(|slot| slot.get().is_some_and(|n| n.addr == addr))
+        {
+            Some(slot) => slot.set(neighbor),
+            None => {
+                let index = self.next_neighbor.get();
+                self.neighbors[index].set(neighbor);
+                self.next_neighbor.set((index + 1) % NEIGHBOR_CACHE_LEN);
+            }
+        }
+    }
+
+    /// The MAC address packets to `dst` are sent to.
+    fn resolve(&self, dst: &IPAddr) -> [u8; MAC_ADDRESS_LEN] {
+        if dst.is_multicast() {
+            // RFC 2464 section 7.
+            [0x33, 0x33, dst.0[12], dst.0[13], dst.0[14], dst.0[15]]
+        } else {
+            self.lookup_neighbor(dst).unwrap_or(BROADCAST_MAC)
+        }
+    }
+
+    fn write_ethernet_header(&self, frame: &mut [u8], dst_mac: &[u8; MAC_ADDRESS_LEN]) {
+        frame[0..6].copy_from_slice(dst_mac);
+        frame[6..12].copy_from_slice(&self.mac);
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+    }
+
+    /// Send the frame of the send client in `tx_buf`. If a reply is being
+    /// sent, the frame is sent after it.
+    fn transmit_client_frame(&self, len: u16) -> Result<(), ErrorCode> {
+        let frame = self.tx_buf.take().ok_or(ErrorCode::BUSY)?;
+        match self.ethernet.transmit_frame(frame, len, TX_CLIENT) {
+            Ok(()) => Ok(()),
+            Err((ErrorCode::BUSY, frame)) if self.reply_buf.is_none() => {
+                self.tx_buf.replace(frame);
+                self.tx_pending.set(len);
+                Ok(())
+            }
+            Err((err, frame)) => {
+                self.tx_buf.replace(frame);
+                Err(err)
+            }
+        }
+    }
+
+    /// Send an ICMPv6 message to `dst` at `dst_mac`. `write_body` writes the
+    /// message without the checksum into the buffer it is passed, and returns
+    /// its length.
+    fn send_icmp(
+        &self,
+        src: IPAddr,
+        dst: IPAddr,
+        dst_mac: &[u8; MAC_ADDRESS_LEN],
+        write_body: impl FnOnce(&mut [u8]) -> Option<usize>,
+    ) {
+        let frame = match self.reply_buf.take() {
+            Some(frame) => frame,
+            // The previous reply is still being sent.
+            None => return,
+        };
+        let len = match frame
+            .get_mut(PAYLOAD_OFFSET..)
+            .and_then(|body| write_body(body))
+        {
+            Some(len) => len,
+            None => {
+                self.reply_buf.replace(frame);
+                return;
+            }
+        };
+
+        let mut header = IP6Header::new();
+        header.set_payload_len(len as u16);
+        header.set_next_header(ip6_nh::ICMP);
+        header.set_hop_limit(255);
+        header.src_addr = src;
+        header.dst_addr = dst;
+
+        self.write_ethernet_header(frame, dst_mac);
+        let _ = header.encode(&mut frame[HEADER_LEN..]);
+        let body = &mut frame[PAYLOAD_OFFSET..PAYLOAD_OFFSET + len];
+        body[2..4].copy_from_slice(&[0, 0]);
+        let checksum = transport_checksum(&header, body);
+        body[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        if let Err((_, frame)) =
+            self.ethernet
+                .transmit_frame(frame, (PAYLOAD_OFFSET + len) as u16, TX_REPLY)
+        {
+            self.reply_buf.replace(frame);
+        }
+    }
+
+    /// Handle an ICMPv6 message for the interface. Returns false if the
+    /// message is not handled here and should go to the receive client.
+    fn receive_icmp(
+        &self,
+        header: &IP6Header,
+        src_mac: &[u8; MAC_ADDRESS_LEN],
+        icmp: &[u8],
+    ) -> bool {
+        match icmp[0] {
+            ICMP_NEIGHBOR_SOLICITATION if icmp.len() >= 24 && header.hop_limit == 255 => {
+                let mut target = IPAddr::new();
+                target.0.copy_from_slice(&icmp[8..24]);
+                if !self.is_own_addr(&target) {
+                    return true;
+                }
+                let solicited = !header.src_addr.is_unspecified();
+                let (dst, dst_mac) = if solicited {
+                    (header.src_addr, *src_mac)
+                } else {
+                    (ALL_NODES, self.resolve(&ALL_NODES))
+                };
+                let mac = self.mac;
+                self.send_icmp(target, dst, &dst_mac, |body| {
+                    let body = body.get_mut(..NA_LEN)?;
+                    body[0] = ICMP_NEIGHBOR_ADVERTISEMENT;
+                    body[1] = 0;
+                    body[4] = NA_FLAG_OVERRIDE | if solicited { NA_FLAG_SOLICITED } else { 0 };
+                    body[5..8].copy_from_slice(&[0, 0, 0]);
+                    body[8..24].copy_from_slice(&target.0);
+                    body[24] = ND_OPTION_TARGET_LINK_ADDRESS;
+                    body[25] = 1; // In units of 8 bytes
+                    body[26..32].copy_from_slice(&mac);
+                    Some(NA_LEN)
+                });
+                true
+            }
+            ICMP_NEIGHBOR_ADVERTISEMENT if icmp.len() >= 24 => {
+                let mut target = IPAddr::new();
+                target.0.copy_from_slice(&icmp[8..24]);
+                let mut options = &icmp[24..];
+                while options.len() >= 8 && options[1] > 0 {
+                    let option_len = options[1] as usize * 8;
+                    if options[0] == ND_OPTION_TARGET_LINK_ADDRESS {
+                        let mut mac = [0; MAC_ADDRESS_LEN];
+                        mac.copy_from_slice(&options[2..8]);
+                        self.learn_neighbor(target, mac);
+                    }
+                    options = options.get(option_len..).unwrap_or(&[]);
+                }
+                true
+            }
+            ICMP_ECHO_REQUEST => {
+                let src = if self.is_own_addr(&header.dst_addr) {
+                    header.dst_addr
+                } else {
+                    self.link_local
+                };
+                self.send_icmp(src, header.src_addr, src_mac, |body| {
+                    let body = body.get_mut(..icmp.len())?;
+                    body.copy_from_slice(icmp);
+                    body[0] = ICMP_ECHO_REPLY;
+                    Some(icmp.len())
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'a, E: EthernetAdapterDatapath<'a>> IP6Sender<'a> for IP6EthernetInterface<'a, E> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.send_client.set(client);
+    }
+
+    fn set_addr(&self, src_addr: IPAddr) {
+        self.src_addr.set(src_addr);
+    }
+
+    /// Ethernet neighbors are found by their IP address, so the gateway is
+    /// not used.
+    fn set_gateway(&self, _gateway: MacAddress) {}
+
+    fn set_header(&mut self, ip6_header: IP6Header) {
+        self.ip6_packet
+            .map(|ip6_packet| ip6_packet.header = ip6_header);
+    }
+
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &SubSliceMut<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), ErrorCode> {
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return Err(ErrorCode::FAIL);
+        }
+        if self.tx_pending.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        let dst_mac = self.resolve(&dst);
+        let len = self.tx_buf.map_or(Err(ErrorCode::BUSY), |frame| {
+            self.ip6_packet.map_or(Err(ErrorCode::NOMEM), |ip6_packet| {
+                ip6_packet.header = IP6Header::default();
+                ip6_packet.header.src_addr = if dst.is_unicast_link_local() {
+                    self.link_local
+                } else {
+                    self.src_addr.get()
+                };
+                ip6_packet.header.dst_addr = dst;
+                ip6_packet.set_payload(transport_header, payload);
+                ip6_packet.set_transport_checksum();
+
+                if frame.len() < PAYLOAD_OFFSET + ip6_packet.header.get_payload_len() as usize {
+                    return Err(ErrorCode::SIZE);
+                }
+                self.write_ethernet_header(frame, &dst_mac);
+                ip6_packet
+                    .encode(&mut frame[HEADER_LEN..])
+                    .done()
+                    .map(|(len, _)| (HEADER_LEN + len) as u16)
+                    .ok_or(ErrorCode::SIZE)
+            })
+        })?;
+        self.transmit_client_frame(len)
+    }
+}
+
+impl<'a, E: EthernetAdapterDatapath<'a>> IP6Receiver<'a> for IP6EthernetInterface<'a, E> {
+    fn set_client(&self, client: &'a dyn IP6RecvClient) {
+        self.recv_client.set(client);
+    }
+}
+
+impl<'a, E: EthernetAdapterDatapath<'a>> EthernetAdapterDatapathClient
+    for IP6EthernetInterface<'a, E>
+{
+    fn tx_done(
+        &self,
+        err: Result<(), ErrorCode>,
+        frame_buffer: &'static mut [u8],
+        _len: u16,
+        transmission_identifier: usize,
+        _timestamp: Option<u64>,
+    ) {
+        match transmission_identifier {
+            TX_CLIENT => {
+                self.tx_buf.replace(frame_buffer);
+                self.send_client.map(|client| client.send_done(err));
+            }
+            _ => {
+                self.reply_buf.replace(frame_buffer);
+                if let Some(len) = self.tx_pending.take() {
+                    if let Err(err) = self.transmit_client_frame(len) {
+                        self.send_client.map(|client| client.send_done(Err(err)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn rx_frame(&self, frame: &[u8], _timestamp: Option<u64>) {
+        if frame.len() < PAYLOAD_OFFSET || frame[12..14] != ETHERTYPE_IPV6.to_be_bytes() {
+            return;
+        }
+        let mut src_mac = [0; MAC_ADDRESS_LEN];
+        src_mac.copy_from_slice(&frame[6..12]);
+
+        let header = match IP6Header::decode(&frame[HEADER_LEN..]).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        if header.get_version() != 6 || !self.accepts(&header.dst_addr) {
+            return;
+        }
+        let payload = match frame.get(PAYLOAD_OFFSET..PAYLOAD_OFFSET + header.payload_len as usize)
+        {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        match header.next_header {
+            ip6_nh::UDP | ip6_nh::ICMP => {
+                if payload.len() < 8 || transport_checksum(&header, payload) != 0 {
+                    return; // Dropped.
+                }
+            }
+            // Checksums of other protocols are checked by their clients.
+            _ => {}
+        }
+
+        self.learn_neighbor(header.src_addr, src_mac);
+        if header.next_header == ip6_nh::ICMP && self.receive_icmp(&header, &src_mac, payload) {
+            return;
+        }
+        self.recv_client
+            .map(|client| client.receive(header, payload));
+    }
+}
@@ -26,8 +26,9 @@ use crate::net::ieee802154::MacAddress;
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::ipv6::{IP6Header, IP6Packet, TransportHeader};
 use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+use crate::net::sixlowpan::sixlowpan_mesh::{RouteTable, DEFAULT_HOPS_LEFT};
 use crate::net::sixlowpan::sixlowpan_state::TxState;
-use crate::net::thread::thread_utils::{mac_from_ipv6, MULTICAST_IPV6};
+use crate::net::thread::thread_utils::mac_from_ipv6;
 
 use core::cell::Cell;
 
@@ -114,6 +115,8 @@ pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
     src_mac_addr: MacAddress,
     client: OptionalCell<&'a dyn IP6SendClient>,
     ip_vis: &'static IpVisibilityCapability,
+    // Mesh-under routes to destinations that are not neighbors
+    routes: OptionalCell<&'a RouteTable>,
 }
 
 impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
@@ -151,8 +154,8 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
         // but may conflict with some other or future protocol
         // that sits above and uses IPV6
         let dst_mac_addr;
-        if dst == MULTICAST_IPV6 {
-            // use short multicast ipv6 for dst mac address
+        if dst.is_multicast() {
+            // use short broadcast mac address for all multicast groups
             dst_mac_addr = MacAddress::Short(0xFFFF)
         } else if dst.0[0..8] == [0xfe, 0x80, 0, 0, 0, 0, 0, 0] {
             // ipv6 address is of form fe80::MAC; use mac_from_ipv6
@@ -167,6 +170,21 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
             .sixlowpan
             .init(self.src_mac_addr, dst_mac_addr, self.radio.get_pan(), None);
 
+        // Destinations reached through another node are sent with a mesh
+        // header
+        if dst_mac_addr != MacAddress::Short(0xFFFF) {
+            self.routes.map(|routes| {
+                if let Some(next_hop) = routes
+                    .lookup(dst_mac_addr)
+                    .filter(|next_hop| *next_hop != dst_mac_addr)
+                {
+                    let _ = self
+                        .sixlowpan
+                        .set_mesh_next_hop(next_hop, DEFAULT_HOPS_LEFT);
+                }
+            });
+        }
+
         self.init_packet(dst, transport_header, payload);
         let ret = self.send_next_fragment();
         ret
@@ -196,9 +214,16 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             src_mac_addr,
             client: OptionalCell::empty(),
             ip_vis,
+            routes: OptionalCell::empty(),
         }
     }
 
+    /// Sets the route table used to send packets to destinations that are
+    /// not neighbors using mesh-under forwarding.
+    pub fn set_route_table(&self, routes: &'a RouteTable) {
+        self.routes.set(routes);
+    }
+
     fn init_packet(
         &self,
         dst_addr: IPAddr,
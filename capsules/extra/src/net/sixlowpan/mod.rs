@@ -3,4 +3,5 @@
 // Copyright Tock Contributors 2022.
 
 pub mod sixlowpan_compression;
+pub mod sixlowpan_mesh;
 pub mod sixlowpan_state;
@@ -0,0 +1,568 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! 6LoWPAN mesh-under forwarding.
+//!
+//! RFC 4944 section 5.2 defines a mesh addressing header that carries the
+//! link-layer addresses of the originator and the final destination of a
+//! frame, so that frames can be relayed over several 802.15.4 hops without
+//! involving the IPv6 layer. This module provides:
+//!
+//! - [MeshHeader](struct.MeshHeader.html), which encodes and decodes the
+//!   mesh addressing header.
+//! - [RouteTable](struct.RouteTable.html), a small table mapping final
+//!   destinations to next hops, with an optional default route.
+//! - [MeshForwarder](struct.MeshForwarder.html), which sits between a
+//!   `MacDevice` and the `Sixlowpan` receive path. Frames carrying a mesh
+//!   header for another node are relayed to the next hop from the route
+//!   table; frames for this node have the mesh header removed and are passed
+//!   up with the originator and final addresses as source and destination,
+//!   which is what 6LoWPAN decompression and reassembly must use.
+//!
+//! Frames originated by this node get a mesh header when the IPv6 sender is
+//! given a route table (see `IP6SendStruct::set_route_table`) and the route to
+//! the destination goes through another node.
+//!
+//! Usage
+//! -----
+//!
+//! The forwarder needs its own `MacUser`, so that the `send_done` callbacks
+//! of relayed frames go to the forwarder and those of the 6LoWPAN sender to
+//! the sender. Received frames reach 6LoWPAN through the forwarder only, so
+//! the `MacUser` of the sender gets no receive client.
+//!
+//! ```rust,ignore
+//! let forward_mac_user = static_init!(
+//!     MacUser<'static, AwakeMac<'static, RF233Device>>,
+//!     MacUser::new(mux_mac)
+//! );
+//! mux_mac.add_user(forward_mac_user);
+//! let routes = static_init!(RouteTable, RouteTable::new());
+//! let forwarder = static_init!(
+//!     MeshForwarder<'static>,
+//!     MeshForwarder::new(forward_mac_user, routes, forward_bufs)
+//! );
+//! forward_mac_user.set_receive_client(forwarder);
+//! forward_mac_user.set_transmit_client(forwarder);
+//! forwarder.set_receive_client(sixlowpan);
+//! ip6_sender.set_route_table(routes);
+//! ```
+//!
+//! Broadcast frames with a mesh header are delivered locally but not
+//! relayed, since that requires the broadcast header (RFC 4944 section 11.1)
+//! for duplicate suppression. Relayed frames are sent without link-layer
+//! security.
+
+use crate::ieee802154::device::{MacDevice, RxClient, TxClient};
+use crate::ieee802154::framer::Frame;
+use crate::net::ieee802154::{Header, MacAddress};
+
+use core::cell::Cell;
+
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Number of routes a `RouteTable` can hold.
+pub const MAX_ROUTES: usize = 16;
+/// Number of frames a `MeshForwarder` can hold while waiting to relay them.
+pub const FORWARD_QUEUE_LEN: usize = 4;
+/// Hops left value used for frames originated by this node.
+pub const DEFAULT_HOPS_LEFT: u8 = 8;
+
+pub mod lowpan_mesh {
+    pub const MESH_HDR: u8 = 0b10000000;
+    pub const MESH_MASK: u8 = 0b11000000;
+    pub const V_SHORT: u8 = 0b00100000;
+    pub const F_SHORT: u8 = 0b00010000;
+    pub const HOPS_MASK: u8 = 0b00001111;
+    /// Hops left value indicating that an extra byte holds the hop count.
+    pub const DEEP_HOPS: u8 = 0xf;
+}
+
+/// Returns true if the 6LoWPAN payload starts with a mesh addressing header.
+pub fn is_mesh(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .is_some_and(|b| b & lowpan_mesh::MESH_MASK == lowpan_mesh::MESH_HDR)
+}
+
+/// The RFC 4944 mesh addressing header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MeshHeader {
+    pub hops_left: u8,
+    pub originator: MacAddress,
+    pub final_dst: MacAddress,
+}
+
+impl MeshHeader {
+    pub fn get_hdr_size(&self) -> usize {
+        let deep_hops = usize::from(self.hops_left >= lowpan_mesh::DEEP_HOPS);
+        1 + deep_hops + addr_size(&self.originator) + addr_size(&self.final_dst)
+    }
+
+    /// Writes the header to the start of `buf` and returns its length, or
+    /// `None` if `buf` is too short.
+    pub fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let size = self.get_hdr_size();
+        if buf.len() < size {
+            return None;
+        }
+        let mut dispatch = lowpan_mesh::MESH_HDR;
+        if let MacAddress::Short(_) = self.originator {
+            dispatch |= lowpan_mesh::V_SHORT;
+        }
+        if let MacAddress::Short(_) = self.final_dst {
+            dispatch |= lowpan_mesh::F_SHORT;
+        }
+        let mut off = 1;
+        if self.hops_left >= lowpan_mesh::DEEP_HOPS {
+            dispatch |= lowpan_mesh::DEEP_HOPS;
+            buf[off] = self.hops_left;
+            off += 1;
+        } else {
+            dispatch |= self.hops_left;
+        }
+        buf[0] = dispatch;
+        off += encode_addr(&self.originator, &mut buf[off..]);
+        off += encode_addr(&self.final_dst, &mut buf[off..]);
+        Some(off)
+    }
+
+    /// Parses a mesh header at the start of `buf`, returning the header and
+    /// its length.
+    pub fn decode(buf: &[u8]) -> Option<(usize, MeshHeader)> {
+        let dispatch = *buf.first()?;
+        if dispatch & lowpan_mesh::MESH_MASK != lowpan_mesh::MESH_HDR {
+            return None;
+        }
+        let mut off = 1;
+        let hops_left = if dispatch & lowpan_mesh::HOPS_MASK == lowpan_mesh::DEEP_HOPS {
+            off += 1;
+            *buf.get(1)?
+        } else {
+            dispatch & lowpan_mesh::HOPS_MASK
+        };
+        let (len, originator) = decode_addr(buf.get(off..)?, dispatch & lowpan_mesh::V_SHORT != 0)?;
+        off += len;
+        let (len, final_dst) = decode_addr(buf.get(off..)?, dispatch & lowpan_mesh::F_SHORT != 0)?;
+        off += len;
+        Some((
+            off,
+            MeshHeader {
+                hops_left,
+                originator,
+                final_dst,
+            },
+        ))
+    }
+}
+
+fn addr_size(addr: &MacAddress) -> usize {
+    match addr {
+        MacAddress::Short(_) => 2,
+        MacAddress::Long(_) => 8,
+    }
+}
+
+// Addresses in the mesh header are in network byte order.
+fn encode_addr(addr: &MacAddress, buf: &mut [u8]) -> usize {
+    match addr {
+        MacAddress::Short(short) => {
+            buf[0..2].copy_from_slice(&short.to_be_bytes());
+            2
+        }
+        MacAddress::Long(long) => {
+            buf[0..8].copy_from_slice(long);
+            8
+        }
+    }
+}
+
+fn decode_addr(buf: &[u8], short: bool) -> Option<(usize, MacAddress)> {
+    if short {
+        let bytes = buf.get(0..2)?;
+        Some((
+            2,
+            MacAddress::Short(u16::from_be_bytes([bytes[0], bytes[1]])),
+        ))
+    } else {
+        let mut long = [0; 8];
+        long.copy_from_slice(buf.get(0..8)?);
+        Some((8, MacAddress::Long(long)))
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct Route {
+    dst: MacAddress,
+    next_hop: MacAddress,
+}
+
+/// Maps final destinations to the neighbor frames for them are sent to.
+///
+/// Neighbors that are reached directly do not need a route. Destinations
+/// without a route use the default route, if one is set.
+pub struct RouteTable {
+    routes: [Cell<Option<Route>>; MAX_ROUTES],
+    default_route: Cell<Option<MacAddress>>,
+}
+
+impl RouteTable {
+    pub fn new() -> RouteTable {
+        RouteTable {
+            routes: Default::default(),
+            default_route: Cell::new(None),
+        }
+    }
+
+    /// Adds a route to `dst` through `next_hop`, replacing any existing
+    /// route to `dst`. Returns `NOMEM` if the table is full.
+    pub fn add_route(&self, dst: MacAddress, next_hop: MacAddress) -> Result<(), ErrorCode> {
+        let slot = self
+            .routes
+            .iter()
+            .find(|route| route.get().is_some_and(|route| route.dst == dst))
+            .or_else(|| self.routes.iter().find(|route| route.get().is_none()))
+            .ok_or(ErrorCode::NOMEM)?;
+        slot.set(Some(Route { dst, next_hop }));
+        Ok(())
+    }
+
+    pub fn remove_route(&self, dst: MacAddress) -> Result<(), ErrorCode> {
+        let slot = self
+            .routes
+            .iter()
+            .find(|route| route.get().is_some_and(|route| route.dst == dst))
+            .ok_or(ErrorCode::INVAL)?;
+        slot.set(None);
+        Ok(())
+    }
+
+    pub fn set_default_route(&self, next_hop: Option<MacAddress>) {
+        self.default_route.set(next_hop);
+    }
+
+    /// Returns the next hop towards `dst`.
+    pub fn lookup(&self, dst: MacAddress) -> Option<MacAddress> {
+        self.routes
+            .iter()
+            .find_map(|route| route.get().filter(|route| route.dst == dst))
+            .map(|route| route.next_hop)
+            .or(self.default_route.get())
+    }
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Relays mesh frames for other nodes and strips the mesh header from frames
+/// addressed to this node.
+pub struct MeshForwarder<'a> {
+    radio: &'a dyn MacDevice<'a>,
+    routes: &'a RouteTable,
+    rx_client: OptionalCell<&'a dyn RxClient>,
+    // Free buffers for relayed frames.
+    bufs: [TakeCell<'static, [u8]>; FORWARD_QUEUE_LEN],
+    // Frames waiting for the MAC device.
+    queue: [MapCell<Frame>; FORWARD_QUEUE_LEN],
+    sending: Cell<bool>,
+}
+
+impl<'a> MeshForwarder<'a> {
+    /// Creates a new `MeshForwarder`
+    ///
+    /// # Arguments
+    ///
+    /// `radio` - The MAC device relayed frames are sent on
+    /// `routes` - The route table used to find the next hop
+    /// `bufs` - Buffers for relayed frames, each at least as large as an
+    /// 802.15.4 frame
+    pub fn new(
+        radio: &'a dyn MacDevice<'a>,
+        routes: &'a RouteTable,
+        bufs: [&'static mut [u8]; FORWARD_QUEUE_LEN],
+    ) -> MeshForwarder<'a> {
+        MeshForwarder {
+            radio,
+            routes,
+            rx_client: OptionalCell::empty(),
+            bufs: bufs.map(TakeCell::new),
+            queue: core::array::from_fn(|_| MapCell::empty()),
+            sending: Cell::new(false),
+        }
+    }
+
+    pub fn set_receive_client(&self, client: &'a dyn RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn is_own_address(&self, addr: MacAddress) -> bool {
+        match addr {
+            MacAddress::Short(short) => short == self.radio.get_address(),
+            MacAddress::Long(long) => long == self.radio.get_address_long(),
+        }
+    }
+
+    fn own_address(&self) -> MacAddress {
+        let short = self.radio.get_address();
+        // 0xfffe and 0xffff mean that no short address is assigned.
+        if short >= 0xfffe {
+            MacAddress::Long(self.radio.get_address_long())
+        } else {
+            MacAddress::Short(short)
+        }
+    }
+
+    /// Queues `payload`, which follows the mesh header, to be relayed towards
+    /// `mesh.final_dst`.
+    fn forward(&self, mesh: MeshHeader, payload: &[u8]) -> Result<(), ErrorCode> {
+        // Without a route, the final destination is assumed to be a neighbor.
+        let next_hop = self.routes.lookup(mesh.final_dst).unwrap_or(mesh.final_dst);
+        let buf = self
+            .bufs
+            .iter()
+            .find_map(|buf| buf.take())
+            .ok_or(ErrorCode::NOMEM)?;
+        let pan = self.radio.get_pan();
+        let mut frame = self
+            .radio
+            .prepare_data_frame(buf, pan, next_hop, pan, self.own_address(), None)
+            .map_err(|buf| {
+                self.return_buf(buf);
+                ErrorCode::FAIL
+            })?;
+
+        let mut hdr = [0; 18];
+        let hdr_len = mesh.encode(&mut hdr).unwrap_or(0);
+        // The frame check sequence is not included in the data capacity.
+        if hdr_len + payload.len() + 2 > frame.remaining_data_capacity() {
+            self.return_buf(frame.into_buf());
+            return Err(ErrorCode::SIZE);
+        }
+        let _ = frame.append_payload(&hdr[..hdr_len]);
+        let _ = frame.append_payload(payload);
+
+        match self.queue.iter().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                slot.put(frame);
+                self.send_next();
+                Ok(())
+            }
+            None => {
+                self.return_buf(frame.into_buf());
+                Err(ErrorCode::NOMEM)
+            }
+        }
+    }
+
+    fn return_buf(&self, buf: &'static mut [u8]) {
+        if let Some(slot) = self.bufs.iter().find(|slot| slot.is_none()) {
+            slot.replace(buf);
+        }
+    }
+
+    fn send_next(&self) {
+        if self.sending.get() {
+            return;
+        }
+        if let Some(frame) = self.queue.iter().find_map(|slot| slot.take()) {
+            self.sending.set(true);
+            if let Err((_, buf)) = self.radio.transmit(frame) {
+                self.sending.set(false);
+                self.return_buf(buf);
+            }
+        }
+    }
+}
+
+impl RxClient for MeshForwarder<'_> {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        lqi: u8,
+        data_offset: usize,
+        data_len: usize,
+    ) {
+        let payload = &buf[data_offset..data_offset + data_len];
+        let (hdr_len, mesh) = match MeshHeader::decode(payload) {
+            Some(decoded) => decoded,
+            None => {
+                // Not a mesh frame (or a malformed one, which the 6LoWPAN
+                // layer will reject).
+                self.rx_client
+                    .map(|client| client.receive(buf, header, lqi, data_offset, data_len));
+                return;
+            }
+        };
+
+        let broadcast = mesh.final_dst == MacAddress::Short(0xffff);
+        if broadcast || self.is_own_address(mesh.final_dst) {
+            let mut header = header;
+            header.src_addr = Some(mesh.originator);
+            header.dst_addr = Some(mesh.final_dst);
+            self.rx_client.map(|client| {
+                client.receive(buf, header, lqi, data_offset + hdr_len, data_len - hdr_len)
+            });
+        } else if mesh.hops_left > 1 && !self.is_own_address(mesh.originator) {
+            let mesh = MeshHeader {
+                hops_left: mesh.hops_left - 1,
+                ..mesh
+            };
+            // Frames that cannot be relayed are dropped, as 6LoWPAN has no
+            // way to report the error back to the originator.
+            let _ = self.forward(mesh, &payload[hdr_len..]);
+        }
+    }
+}
+
+impl TxClient for MeshForwarder<'_> {
+    fn send_done(&self, spi_buf: &'static mut [u8], _acked: bool, _result: Result<(), ErrorCode>) {
+        self.sending.set(false);
+        self.return_buf(spi_buf);
+        self.send_next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_A: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const LONG_B: [u8; 8] = [9, 10, 11, 12, 13, 14, 15, 16];
+
+    fn round_trip(mesh: MeshHeader) -> [u8; 18] {
+        let mut buf = [0; 18];
+        let len = mesh.encode(&mut buf).unwrap();
+        assert_eq!(len, mesh.get_hdr_size());
+        assert_eq!(MeshHeader::decode(&buf[..len]), Some((len, mesh)));
+        assert_eq!(MeshHeader::decode(&buf[..len - 1]), None);
+        buf
+    }
+
+    #[test]
+    fn mesh_header_short_addresses() {
+        let buf = round_trip(MeshHeader {
+            hops_left: 3,
+            originator: MacAddress::Short(0x1234),
+            final_dst: MacAddress::Short(0xabcd),
+        });
+        assert_eq!(buf[..5], [0b1011_0011, 0x12, 0x34, 0xab, 0xcd]);
+    }
+
+    #[test]
+    fn mesh_header_long_addresses() {
+        let buf = round_trip(MeshHeader {
+            hops_left: 1,
+            originator: MacAddress::Long(LONG_A),
+            final_dst: MacAddress::Long(LONG_B),
+        });
+        assert_eq!(buf[0], 0b1000_0001);
+        assert_eq!(buf[1..9], LONG_A);
+        assert_eq!(buf[9..17], LONG_B);
+
+        let buf = round_trip(MeshHeader {
+            hops_left: 2,
+            originator: MacAddress::Long(LONG_A),
+            final_dst: MacAddress::Short(0xffff),
+        });
+        assert_eq!(buf[0], 0b1001_0010);
+    }
+
+    #[test]
+    fn mesh_header_hops_left_boundary() {
+        // 14 hops fit in the dispatch byte, 15 and more need another byte.
+        let mut mesh = MeshHeader {
+            hops_left: lowpan_mesh::DEEP_HOPS - 1,
+            originator: MacAddress::Short(1),
+            final_dst: MacAddress::Short(2),
+        };
+        let buf = round_trip(mesh);
+        assert_eq!(mesh.get_hdr_size(), 5);
+        assert_eq!(buf[0] & lowpan_mesh::HOPS_MASK, 14);
+
+        mesh.hops_left = lowpan_mesh::DEEP_HOPS;
+        let buf = round_trip(mesh);
+        assert_eq!(mesh.get_hdr_size(), 6);
+        assert_eq!(buf[..2], [0b1011_1111, 15]);
+
+        mesh.hops_left = 0xff;
+        let buf = round_trip(mesh);
+        assert_eq!(buf[..2], [0b1011_1111, 0xff]);
+    }
+
+    #[test]
+    fn mesh_header_rejects_other_dispatches() {
+        assert_eq!(MeshHeader::decode(&[]), None);
+        // IPHC and fragment dispatches
+        assert_eq!(MeshHeader::decode(&[0x60, 0, 0, 0, 0]), None);
+        assert_eq!(MeshHeader::decode(&[0xc0, 0, 0, 0, 0]), None);
+        assert!(MeshHeader {
+            hops_left: 1,
+            originator: MacAddress::Short(1),
+            final_dst: MacAddress::Short(2),
+        }
+        .encode(&mut [0; 4])
+        .is_none());
+    }
+
+    #[test]
+    fn route_table_lookup() {
+        let routes = RouteTable::new();
+        let dst = MacAddress::Short(7);
+        assert_eq!(routes.lookup(dst), None);
+
+        routes.add_route(dst, MacAddress::Short(2)).unwrap();
+        routes
+            .add_route(MacAddress::Long(LONG_A), MacAddress::Short(3))
+            .unwrap();
+        assert_eq!(routes.lookup(dst), Some(MacAddress::Short(2)));
+        assert_eq!(
+            routes.lookup(MacAddress::Long(LONG_A)),
+            Some(MacAddress::Short(3))
+        );
+        assert_eq!(routes.lookup(MacAddress::Long(LONG_B)), None);
+
+        routes.set_default_route(Some(MacAddress::Short(9)));
+        assert_eq!(routes.lookup(dst), Some(MacAddress::Short(2)));
+        assert_eq!(
+            routes.lookup(MacAddress::Long(LONG_B)),
+            Some(MacAddress::Short(9))
+        );
+
+        routes.remove_route(dst).unwrap();
+        assert_eq!(routes.lookup(dst), Some(MacAddress::Short(9)));
+        assert_eq!(routes.remove_route(dst), Err(ErrorCode::INVAL));
+    }
+
+    #[test]
+    fn route_table_replaces_routes() {
+        let routes = RouteTable::new();
+        for i in 0..MAX_ROUTES as u16 {
+            routes
+                .add_route(MacAddress::Short(i), MacAddress::Short(100))
+                .unwrap();
+        }
+        assert_eq!(
+            routes.add_route(MacAddress::Short(MAX_ROUTES as u16), MacAddress::Short(100)),
+            Err(ErrorCode::NOMEM)
+        );
+
+        // Replacing a route does not need a free slot.
+        routes
+            .add_route(MacAddress::Short(0), MacAddress::Short(101))
+            .unwrap();
+        assert_eq!(
+            routes.lookup(MacAddress::Short(0)),
+            Some(MacAddress::Short(101))
+        );
+        assert_eq!(
+            routes.lookup(MacAddress::Short(1)),
+            Some(MacAddress::Short(100))
+        );
+    }
+}
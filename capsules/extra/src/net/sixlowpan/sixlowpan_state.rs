@@ -237,6 +237,7 @@ use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
 use crate::net::ipv6::IP6Packet;
 use crate::net::sixlowpan::sixlowpan_compression;
 use crate::net::sixlowpan::sixlowpan_compression::{is_lowpan, ContextStore};
+use crate::net::sixlowpan::sixlowpan_mesh::MeshHeader;
 use crate::net::util::{network_slice_to_u16, u16_to_network_slice};
 
 use core::cell::Cell;
@@ -245,11 +246,11 @@ use core::cmp::min;
 use kernel::collections::list::{List, ListLink, ListNode};
 use kernel::hil::radio;
 use kernel::hil::time;
-use kernel::hil::time::{Frequency, Ticks};
+use kernel::hil::time::{ConvertTicks, Ticks};
 use kernel::utilities::cells::{MapCell, TakeCell};
 use kernel::ErrorCode;
 
-// Reassembly timeout in seconds
+// Default reassembly timeout in seconds (the maximum allowed by RFC 4944)
 const FRAG_TIMEOUT: u32 = 60;
 
 /// Client trait for receiving 6lowpan frames.
@@ -330,6 +331,9 @@ pub struct TxState<'a> {
     dgram_tag: Cell<u16>, // Used to identify particular fragment streams
     dgram_size: Cell<u16>,
     dgram_offset: Cell<usize>,
+    // Neighbor to send frames to and hops left value when the destination
+    // is reached through a mesh
+    mesh_next_hop: Cell<Option<(MacAddress, u8)>>,
 
     busy: Cell<bool>,
     // We need a reference to sixlowpan to compute and increment
@@ -357,6 +361,7 @@ impl<'a> TxState<'a> {
             dgram_tag: Cell::new(0),
             dgram_size: Cell::new(0),
             dgram_offset: Cell::new(0),
+            mesh_next_hop: Cell::new(None),
 
             busy: Cell::new(false),
             sixlowpan,
@@ -395,6 +400,23 @@ impl<'a> TxState<'a> {
             self.busy.set(false);
             self.src_pan.set(radio_pan);
             self.dst_pan.set(radio_pan);
+            self.mesh_next_hop.set(None);
+            Ok(())
+        }
+    }
+
+    /// Sends the packet initialized by `init` through the neighbor
+    /// `next_hop` with a mesh addressing header (RFC 4944 section 5.2).
+    ///
+    /// The MAC addresses passed to `init` become the originator and final
+    /// destination addresses of the mesh header, and are still used for
+    /// header compression. Returns `Err(ErrorCode::BUSY)` while a packet
+    /// is being sent.
+    pub fn set_mesh_next_hop(&self, next_hop: MacAddress, hops_left: u8) -> Result<(), ErrorCode> {
+        if self.busy.get() {
+            Err(ErrorCode::BUSY)
+        } else {
+            self.mesh_next_hop.set(Some((next_hop, hops_left)));
             Ok(())
         }
     }
@@ -425,17 +447,33 @@ impl<'a> TxState<'a> {
         radio: &dyn MacDevice,
     ) -> Result<(bool, Frame), (Result<(), ErrorCode>, &'static mut [u8])> {
         // This consumes frag_buf
-        let frame = radio
+        let mesh_next_hop = self.mesh_next_hop.get();
+        let mut frame = radio
             .prepare_data_frame(
                 frag_buf,
                 self.dst_pan.get(),
-                self.dst_mac_addr.get(),
+                mesh_next_hop.map_or(self.dst_mac_addr.get(), |(next_hop, _)| next_hop),
                 self.src_pan.get(),
                 self.src_mac_addr.get(),
                 self.security.get(),
             )
             .map_err(|frame| (Err(ErrorCode::FAIL), frame))?;
 
+        // The mesh header precedes all other 6LoWPAN headers in every
+        // fragment
+        if let Some((_, hops_left)) = mesh_next_hop {
+            let mesh = MeshHeader {
+                hops_left,
+                originator: self.src_mac_addr.get(),
+                final_dst: self.dst_mac_addr.get(),
+            };
+            let mut mesh_hdr = [0_u8; 18];
+            let mesh_len = mesh.encode(&mut mesh_hdr).unwrap_or(0);
+            if frame.append_payload(&mesh_hdr[0..mesh_len]).is_err() {
+                return Err((Err(ErrorCode::SIZE), frame.into_buf()));
+            }
+        }
+
         // If this is the first fragment
         if !self.busy.get() {
             let frame = self.start_transmit(ip6_packet, frame, self.sixlowpan.get_ctx_store())?;
@@ -632,7 +670,8 @@ impl<'a> TxState<'a> {
 /// A list of `RxState`s is maintained by [Sixlowpan](struct.Sixlowpan.html) to
 /// keep track of ongoing packet reassemblies. The number of `RxState`s is the
 /// number of packets that can be reassembled at the same time. Generally,
-/// two `RxState`s are sufficient for normal-case operation.
+/// two `RxState`s are sufficient for normal-case operation, but nodes that
+/// relay traffic for many senders (such as border routers) need more.
 pub struct RxState<'a> {
     packet: TakeCell<'static, [u8]>,
     bitmap: MapCell<Bitmap>,
@@ -676,27 +715,16 @@ impl<'a> RxState<'a> {
         }
     }
 
-    fn is_my_fragment(
-        &self,
-        src_mac_addr: MacAddress,
-        dst_mac_addr: MacAddress,
-        dgram_size: u16,
-        dgram_tag: u16,
-    ) -> bool {
+    // Reassemblies are identified by the originator of the datagram and the
+    // datagram tag it chose. With mesh-under forwarding the originator is
+    // the address from the mesh header rather than the link-layer source.
+    fn is_my_fragment(&self, src_mac_addr: MacAddress, dgram_tag: u16) -> bool {
         self.busy.get()
             && (self.dgram_tag.get() == dgram_tag)
-            && (self.dgram_size.get() == dgram_size)
             && (self.src_mac_addr.get() == src_mac_addr)
-            && (self.dst_mac_addr.get() == dst_mac_addr)
     }
 
-    // Checks if a given RxState is free or expired (and thus, can be freed).
-    // This function implements the reassembly timeout for 6LoWPAN lazily.
-    fn is_busy(&self, frequency: u32, current_time: u32) -> bool {
-        let expired = current_time >= (self.start_time.get() + FRAG_TIMEOUT * frequency);
-        if expired {
-            self.end_receive(None, Err(ErrorCode::FAIL));
-        }
+    fn is_busy(&self) -> bool {
         self.busy.get()
     }
 
@@ -802,6 +830,8 @@ pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
     clock: &'a A,
     tx_dgram_tag: Cell<u16>,
     rx_client: Cell<Option<&'a dyn SixlowpanRxClient>>,
+    // Reassembly timeout in seconds
+    reassembly_timeout: Cell<u32>,
 
     // Receive state
     rx_states: List<'a, RxState<'a>>,
@@ -887,11 +917,41 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
             clock,
             tx_dgram_tag: Cell::new(0),
             rx_client: Cell::new(None),
+            reassembly_timeout: Cell::new(FRAG_TIMEOUT),
 
             rx_states: List::new(),
         }
     }
 
+    /// Sets the time after which incomplete reassemblies are discarded.
+    /// RFC 4944 limits this to at most 60 seconds, which is the default.
+    pub fn set_reassembly_timeout(&self, seconds: u32) {
+        self.reassembly_timeout.set(min(seconds, FRAG_TIMEOUT));
+    }
+
+    fn reassembly_age(&self, state: &RxState<'a>) -> A::Ticks {
+        self.clock
+            .now()
+            .wrapping_sub(A::Ticks::from(state.start_time.get()))
+    }
+
+    // Discards reassemblies that have timed out.
+    fn expire_rx_states(&self) {
+        let timeout = self.clock.ticks_from_seconds(self.reassembly_timeout.get());
+        for state in self.rx_states.iter() {
+            if state.is_busy() && self.reassembly_age(state) >= timeout {
+                state.end_receive(None, Err(ErrorCode::FAIL));
+            }
+        }
+    }
+
+    // Finds a free RxState for a new datagram. Reassemblies in progress are
+    // only abandoned once they time out (see `expire_rx_states`), so that a
+    // stream of first fragments cannot starve them.
+    fn allocate_rx_state(&self) -> Option<&RxState<'a>> {
+        self.rx_states.iter().find(|state| !state.is_busy())
+    }
+
     fn receive_frame(
         &self,
         packet: &[u8],
@@ -899,6 +959,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
     ) -> (Option<&RxState<'a>>, Result<(), ErrorCode>) {
+        self.expire_rx_states();
         if is_fragment(packet) {
             let (is_frag1, dgram_size, dgram_tag, dgram_offset) = get_frag_hdr(&packet[0..5]);
             let offset_to_payload = if is_frag1 {
@@ -927,10 +988,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
     ) -> (Option<&RxState<'a>>, Result<(), ErrorCode>) {
-        let rx_state = self
-            .rx_states
-            .iter()
-            .find(|state| !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency()));
+        let rx_state = self.rx_states.iter().find(|state| !state.is_busy());
         rx_state.map_or((None, Err(ErrorCode::NOMEM)), |state| {
             state.start_receive(
                 src_mac_addr,
@@ -993,13 +1051,20 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         let mut rx_state = self
             .rx_states
             .iter()
-            .find(|state| state.is_my_fragment(src_mac_addr, dst_mac_addr, dgram_size, dgram_tag));
+            .find(|state| state.is_my_fragment(src_mac_addr, dgram_tag));
+
+        // A different size for a known tag means the sender reused the tag
+        // for a new datagram, so the old reassembly is abandoned
+        if let Some(state) = rx_state {
+            if state.dgram_size.get() != dgram_size {
+                state.end_receive(None, Err(ErrorCode::FAIL));
+                rx_state = None;
+            }
+        }
 
         // Else find a free state
         if rx_state.is_none() {
-            rx_state = self.rx_states.iter().find(|state| {
-                !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency())
-            });
+            rx_state = self.allocate_rx_state();
             // Initialize new state
             rx_state.map(|state| {
                 state.start_receive(
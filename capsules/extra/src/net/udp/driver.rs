@@ -9,6 +9,13 @@
 //! and bind to UDP ports for receiving packets.
 //! Also exposes a list of interface addresses to the application (currently
 //! hard-coded).
+//!
+//! Apps can join IPv6 multicast groups (e.g. ff02::1 or ff03::fc). A datagram
+//! sent to a multicast group is delivered to every app that joined the group
+//! and is bound to the destination port. For several apps to receive the same
+//! discovery traffic, each of them binds the port as shared (see command 3).
+//! Which ports an app may bind can be restricted by the board, see
+//! `UdpPortManager::set_app_port_ranges()`.
 
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::network_capabilities::NetworkCapability;
@@ -36,6 +43,12 @@ use kernel::{ErrorCode, ProcessId};
 use capsules_core::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Udp as usize;
 
+/// Maximum number of multicast groups each app can join.
+pub const MAX_MULTICAST_GROUPS: usize = 4;
+
+/// Flag passed to the bind command to allow other apps to bind the same port.
+const BIND_SHARED: usize = 1 << 0;
+
 /// IDs for subscribed upcalls.
 mod upcall {
     /// Callback for when packet is received. If no port has been bound, return
@@ -66,7 +79,8 @@ mod rw_allow {
     /// Read buffer. Will contain the received payload.
     pub const READ: usize = 0;
     /// Config buffer. Used to contain miscellaneous data associated with some
-    /// commands, namely source/destination addresses and ports.
+    /// commands, namely source/destination addresses and ports, and the group
+    /// address for joining or leaving a multicast group.
     pub const CFG: usize = 1;
     /// Rx config buffer. Used to contain source/destination addresses and ports
     /// for receives (separate from `2` because receives may be waiting for an
@@ -115,6 +129,27 @@ impl UDPEndpoint {
 pub struct App {
     pending_tx: Option<[UDPEndpoint; 2]>,
     bound_port: Option<UDPEndpoint>,
+    /// Whether other apps may bind `bound_port` as well.
+    shared_port: bool,
+    multicast_groups: [Option<IPAddr>; MAX_MULTICAST_GROUPS],
+}
+
+impl App {
+    /// Checks whether a datagram sent to `dst_addr` and `dst_port` is for
+    /// this app. Multicast datagrams go to every member of the group bound
+    /// to the port, unicast datagrams only to the first app bound to the
+    /// port, so `delivered` tells whether another app already received it.
+    fn accepts(&self, dst_addr: IPAddr, dst_port: u16, delivered: bool) -> bool {
+        self.bound_port.is_some_and(|bound| {
+            if bound.port != dst_port {
+                false
+            } else if dst_addr.is_multicast() {
+                self.multicast_groups.contains(&Some(dst_addr))
+            } else {
+                bound.addr == dst_addr && !delivered
+            }
+        })
+    }
 }
 
 #[allow(dead_code)]
@@ -307,6 +342,49 @@ impl<'a> UDPDriver<'a> {
         })
     }
 
+    /// Returns true if `port` is only bound by apps that allow sharing it.
+    fn port_shareable(&self, port: u16) -> bool {
+        if self.port_table.is_bound_by_capsule(port) {
+            return false;
+        }
+        let mut shareable = true;
+        for app in self.apps.iter() {
+            app.enter(|other_app, _| {
+                if let Some(bound) = other_app.bound_port {
+                    if bound.port == port && !other_app.shared_port {
+                        shareable = false;
+                    }
+                }
+            });
+        }
+        shareable
+    }
+
+    /// Reads the multicast group address from the start of the config buffer.
+    fn read_group_addr(&self, processid: ProcessId) -> Result<IPAddr, ErrorCode> {
+        self.apps
+            .enter(processid, |_, kernel_data| {
+                kernel_data
+                    .get_readwrite_processbuffer(rw_allow::CFG)
+                    .and_then(|cfg| {
+                        cfg.enter(|cfg| {
+                            if cfg.len() < size_of::<IPAddr>() {
+                                return Err(ErrorCode::INVAL);
+                            }
+                            let mut addr = IPAddr::new();
+                            cfg[..size_of::<IPAddr>()].copy_to_slice(&mut addr.0);
+                            if addr.is_multicast() {
+                                Ok(addr)
+                            } else {
+                                Err(ErrorCode::INVAL)
+                            }
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::INVAL))
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
+
     #[inline]
     fn parse_ip_port_pair(&self, buf: &[u8]) -> Option<UDPEndpoint> {
         if buf.len() != size_of::<UDPEndpoint>() {
@@ -363,8 +441,13 @@ impl SyscallDriver for UDPDriver<'_> {
     ///        an app with a lower app id can send constantly and starve an app with a
     ///        later ID.
     /// - `3`: Bind to the address in rx_cfg. Returns Ok(()) if that addr/port combo is free,
-    ///        returns INVAL if the address requested is not a local interface, or if the port
-    ///        requested is 0. Returns BUSY if that port is already bound to by another app.
+    ///        returns INVAL if the address requested is not a local interface, if the port
+    ///        requested is 0, or if the board does not allow this app to bind the port.
+    ///        Returns BUSY if that port is already bound to by another app.
+    ///        If bit 0 of `arg1` is set, the port is bound as shared: other apps that
+    ///        also bind it as shared may bind the same port, which lets several apps
+    ///        receive datagrams sent to a multicast group. Unicast datagrams to a shared
+    ///        port are delivered to only one of the apps.
     ///        This command should be called after allow() is called on the rx_cfg buffer, and
     ///        before subscribe() is used to set up the recv callback. Additionally, apps can only
     ///        send on ports after they have bound to said port. If this command is called
@@ -373,9 +456,15 @@ impl SyscallDriver for UDPDriver<'_> {
     ///        the current implementation of this only allows for each app to bind to a single
     ///        port at a time, as such an implementation conserves memory (and is similar
     ///        to the approach applied by TinyOS and Riot).
-    ///        /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
+    /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
     ///        This represents the size of the payload buffer in the kernel. Apps can use this
     ///        syscall to ensure they do not attempt to send too-large messages.
+    /// - `5`: Join the multicast group whose address is in the first 16 bytes of the
+    ///        config buffer. Returns INVAL if the address is not a multicast address,
+    ///        ALREADY if the app already joined the group, and NOMEM if the app joined
+    ///        `MAX_MULTICAST_GROUPS` groups already.
+    /// - `6`: Leave the multicast group whose address is in the first 16 bytes of the
+    ///        config buffer. Returns INVAL if the app is not a member of the group.
 
     fn command(
         &self,
@@ -500,6 +589,7 @@ impl SyscallDriver for UDPDriver<'_> {
                             // If zero address, close any already bound socket
                             if requested_addr.is_zero() {
                                 app.bound_port = None;
+                                app.shared_port = false;
                                 return Ok(None);
                             }
                             // Check that requested addr is a local interface
@@ -519,16 +609,25 @@ impl SyscallDriver for UDPDriver<'_> {
                 match err {
                     Ok(requested_addr_opt) => {
                         requested_addr_opt.map_or(CommandReturn::success(), |requested_addr| {
+                            let port = requested_addr.port;
+                            if !self
+                                .port_table
+                                .app_port_allowed(processid.short_app_id(), port)
+                            {
+                                return CommandReturn::failure(ErrorCode::INVAL);
+                            }
+                            let shared = arg1 & BIND_SHARED != 0;
                             // Check bound ports in the kernel.
-                            match self.port_table.is_bound(requested_addr.port) {
+                            match self.port_table.is_bound(port) {
                                 Ok(bound) => {
-                                    if bound {
+                                    if bound && !(shared && self.port_shareable(port)) {
                                         CommandReturn::failure(ErrorCode::BUSY)
                                     } else {
                                         self.apps
                                             .enter(processid, |app, _| {
                                                 // The requested addr is free and valid
                                                 app.bound_port = Some(requested_addr);
+                                                app.shared_port = shared;
                                                 CommandReturn::success()
                                             })
                                             .unwrap_or_else(|err| {
@@ -544,6 +643,41 @@ impl SyscallDriver for UDPDriver<'_> {
                 }
             }
             4 => CommandReturn::success_u32(self.max_tx_pyld_len as u32),
+            5 => {
+                let res = self.read_group_addr(processid).and_then(|group| {
+                    self.apps
+                        .enter(processid, |app, _| {
+                            if app.multicast_groups.contains(&Some(group)) {
+                                return Err(ErrorCode::ALREADY);
+                            }
+                            app.multicast_groups
+                                .iter_mut()
+                                .find(|slot| slot.is_none())
+                                .map_or(Err(ErrorCode::NOMEM), |slot| {
+                                    *slot = Some(group);
+                                    Ok(())
+                                })
+                        })
+                        .unwrap_or_else(|err| Err(err.into()))
+                });
+                CommandReturn::from(res)
+            }
+            6 => {
+                let res = self.read_group_addr(processid).and_then(|group| {
+                    self.apps
+                        .enter(processid, |app, _| {
+                            app.multicast_groups
+                                .iter_mut()
+                                .find(|slot| **slot == Some(group))
+                                .map_or(Err(ErrorCode::INVAL), |slot| {
+                                    *slot = None;
+                                    Ok(())
+                                })
+                        })
+                        .unwrap_or_else(|err| Err(err.into()))
+                });
+                CommandReturn::from(res)
+            }
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
@@ -582,54 +716,48 @@ impl UDPRecvClient for UDPDriver<'_> {
         dst_port: u16,
         payload: &[u8],
     ) {
+        let mut delivered = false;
         self.apps.each(|_, app, kernel_data| {
-            if app.bound_port.is_some() {
-                let mut for_me = false;
-                app.bound_port.as_ref().map(|requested_addr| {
-                    if requested_addr.addr == dst_addr && requested_addr.port == dst_port {
-                        for_me = true;
-                    }
-                });
-                if for_me {
-                    let len = payload.len();
-                    let res = kernel_data
-                        .get_readwrite_processbuffer(rw_allow::READ)
-                        .and_then(|read| {
-                            read.mut_enter(|rbuf| {
-                                if rbuf.len() >= len {
-                                    rbuf[..len].copy_from_slice(&payload[..len]);
-                                    Ok(())
-                                } else {
-                                    Err(ErrorCode::SIZE) //packet does not fit
+            if app.accepts(dst_addr, dst_port, delivered) {
+                delivered = true;
+                let len = payload.len();
+                let res = kernel_data
+                    .get_readwrite_processbuffer(rw_allow::READ)
+                    .and_then(|read| {
+                        read.mut_enter(|rbuf| {
+                            if rbuf.len() >= len {
+                                rbuf[..len].copy_from_slice(&payload[..len]);
+                                Ok(())
+                            } else {
+                                Err(ErrorCode::SIZE) //packet does not fit
+                            }
+                        })
+                    })
+                    .unwrap_or(Ok(()));
+                if res.is_ok() {
+                    // Write address of sender into rx_cfg so it can be read by client
+                    let sender_addr = UDPEndpoint {
+                        addr: src_addr,
+                        port: src_port,
+                    };
+                    kernel_data
+                        .schedule_upcall(upcall::PACKET_RECEIVED, (len, 0, 0))
+                        .ok();
+                    const CFG_LEN: usize = 2 * size_of::<UDPEndpoint>();
+                    let _ = kernel_data
+                        .get_readwrite_processbuffer(rw_allow::RX_CFG)
+                        .and_then(|rx_cfg| {
+                            rx_cfg.mut_enter(|cfg| {
+                                if cfg.len() != CFG_LEN {
+                                    return Err(ErrorCode::INVAL);
                                 }
+                                let mut tmp_cfg_buffer: [u8; CFG_LEN] = [0; CFG_LEN];
+                                sender_addr.encode(&mut tmp_cfg_buffer, 0);
+                                cfg.copy_from_slice(&tmp_cfg_buffer);
+                                Ok(())
                             })
                         })
-                        .unwrap_or(Ok(()));
-                    if res.is_ok() {
-                        // Write address of sender into rx_cfg so it can be read by client
-                        let sender_addr = UDPEndpoint {
-                            addr: src_addr,
-                            port: src_port,
-                        };
-                        kernel_data
-                            .schedule_upcall(upcall::PACKET_RECEIVED, (len, 0, 0))
-                            .ok();
-                        const CFG_LEN: usize = 2 * size_of::<UDPEndpoint>();
-                        let _ = kernel_data
-                            .get_readwrite_processbuffer(rw_allow::RX_CFG)
-                            .and_then(|rx_cfg| {
-                                rx_cfg.mut_enter(|cfg| {
-                                    if cfg.len() != CFG_LEN {
-                                        return Err(ErrorCode::INVAL);
-                                    }
-                                    let mut tmp_cfg_buffer: [u8; CFG_LEN] = [0; CFG_LEN];
-                                    sender_addr.encode(&mut tmp_cfg_buffer, 0);
-                                    cfg.copy_from_slice(&tmp_cfg_buffer);
-                                    Ok(())
-                                })
-                            })
-                            .unwrap_or(Err(ErrorCode::INVAL));
-                    }
+                        .unwrap_or(Err(ErrorCode::INVAL));
                 }
             }
         });
@@ -654,3 +782,78 @@ impl PortQuery for UDPDriver<'_> {
         port_bound
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PORT: u16 = 5683;
+
+    fn addr(first: u8, last: u8) -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[0] = first;
+        addr.0[1] = 0x02;
+        addr.0[15] = last;
+        addr
+    }
+
+    /// An app bound to `local`:`PORT` as shared and a member of `groups`.
+    fn app(local: IPAddr, groups: &[IPAddr]) -> App {
+        let mut app = App {
+            bound_port: Some(UDPEndpoint {
+                addr: local,
+                port: PORT,
+            }),
+            shared_port: true,
+            ..App::default()
+        };
+        for (slot, group) in app.multicast_groups.iter_mut().zip(groups) {
+            *slot = Some(*group);
+        }
+        app
+    }
+
+    /// Returns which of `apps` a datagram to `dst_addr`:`dst_port` is
+    /// delivered to, in the order the driver visits them.
+    fn deliver(apps: &[App], dst_addr: IPAddr, dst_port: u16) -> [bool; 3] {
+        let mut delivered = false;
+        let mut to = [false; 3];
+        for (app, to) in apps.iter().zip(to.iter_mut()) {
+            *to = app.accepts(dst_addr, dst_port, delivered);
+            delivered |= *to;
+        }
+        to
+    }
+
+    #[test]
+    fn multicast_is_delivered_to_every_member_sharing_the_port() {
+        let local = addr(0xfe, 1);
+        let all_nodes = addr(0xff, 1);
+        let other_group = addr(0xff, 0xfc);
+        let apps = [
+            app(local, &[all_nodes]),
+            app(local, &[other_group, all_nodes]),
+            app(local, &[other_group]),
+        ];
+
+        assert_eq!(deliver(&apps, all_nodes, PORT), [true, true, false]);
+        assert_eq!(deliver(&apps, other_group, PORT), [false, true, true]);
+        // Members of the group that are bound to another port do not
+        // receive it.
+        assert_eq!(deliver(&apps, all_nodes, PORT + 1), [false; 3]);
+        // Nor do apps that are not bound at all.
+        let unbound = App {
+            bound_port: None,
+            ..app(local, &[all_nodes])
+        };
+        assert!(!unbound.accepts(all_nodes, PORT, false));
+    }
+
+    #[test]
+    fn unicast_to_a_shared_port_is_delivered_once() {
+        let local = addr(0xfe, 1);
+        let apps = [app(addr(0xfe, 2), &[]), app(local, &[]), app(local, &[])];
+        assert_eq!(deliver(&apps, local, PORT), [false, true, false]);
+        assert_eq!(deliver(&apps, local, PORT + 1), [false; 3]);
+    }
+}
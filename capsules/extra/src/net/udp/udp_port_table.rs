@@ -35,12 +35,19 @@
 //! such that removing an app automatically unbinds it. This file is able to query the
 //! userspace UDP driver to check which ports are bound, and vice-versa, such that
 //! exclusive access to ports between userspace apps and capsules is still enforced.
+//!
+//! Boards can additionally restrict which ports each app may bind by passing a
+//! list of `AppPortRange`s to `set_app_port_ranges()`. Apps are identified by
+//! their `ShortId`. Ports covered by a range are reserved for the apps the
+//! range belongs to, and an app that has ranges may only bind ports inside
+//! them. Apps without a range may bind any port not reserved for another app.
 
-use crate::net::network_capabilities::{NetworkCapability, UdpVisibilityCapability};
+use crate::net::network_capabilities::{NetworkCapability, PortRange, UdpVisibilityCapability};
 
 use core::fmt;
 
 use kernel::capabilities::{CreatePortTableCapability, UdpDriverCapability};
+use kernel::process::ShortId;
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::ErrorCode;
 
@@ -62,6 +69,37 @@ pub enum SocketBindingEntry {
     Unbound,
 }
 
+/// Ports that the app with the given `ShortId` may bind from userspace.
+pub struct AppPortRange {
+    app_id: ShortId,
+    ports: PortRange,
+}
+
+impl AppPortRange {
+    pub const fn new(app_id: ShortId, ports: PortRange) -> AppPortRange {
+        AppPortRange { app_id, ports }
+    }
+}
+
+/// Checks whether `ranges` allow the app with `app_id` to bind `port`: an
+/// app with ranges may only bind ports inside them, and an app without
+/// ranges may bind any port not covered by the ranges of another app.
+fn port_allowed(ranges: &[AppPortRange], app_id: ShortId, port: u16) -> bool {
+    let mut has_range = false;
+    let mut reserved = false;
+    for range in ranges.iter() {
+        if range.app_id == app_id {
+            if range.ports.is_port_valid(port) {
+                return true;
+            }
+            has_range = true;
+        } else if range.ports.is_port_valid(port) {
+            reserved = true;
+        }
+    }
+    !has_range && !reserved
+}
+
 /// The PortQuery trait enables the UdpPortManager to query the userspace bound
 /// ports in the UDP driver. The UDP driver struct implements this trait.
 pub trait PortQuery {
@@ -83,6 +121,7 @@ pub struct UdpSocket {
 pub struct UdpPortManager {
     port_array: TakeCell<'static, [Option<SocketBindingEntry>]>,
     user_ports: OptionalCell<&'static dyn PortQuery>,
+    app_port_ranges: OptionalCell<&'static [AppPortRange]>,
     udp_vis: &'static UdpVisibilityCapability,
 }
 
@@ -155,6 +194,7 @@ impl UdpPortManager {
         UdpPortManager {
             port_array: TakeCell::new(used_kernel_ports),
             user_ports: OptionalCell::empty(),
+            app_port_ranges: OptionalCell::empty(),
             udp_vis,
         }
     }
@@ -169,6 +209,24 @@ impl UdpPortManager {
         self.user_ports.replace(user_ports_ref);
     }
 
+    /// Restricts the ports apps may bind to the given ranges. Without ranges,
+    /// apps may bind any port that is not already bound.
+    pub fn set_app_port_ranges(
+        &self,
+        ranges: &'static [AppPortRange],
+        _cap: &dyn CreatePortTableCapability,
+    ) {
+        self.app_port_ranges.set(ranges);
+    }
+
+    /// Checks whether the app with `app_id` may bind `port` according to the
+    /// ranges set with `set_app_port_ranges()`. This does not check whether
+    /// the port is already bound.
+    pub fn app_port_allowed(&self, app_id: ShortId, port: u16) -> bool {
+        self.app_port_ranges
+            .map_or(true, |ranges| port_allowed(ranges, app_id, port))
+    }
+
     /// Called by capsules that would like to eventually be able to bind to a
     /// UDP port. This call will succeed unless MAX_NUM_BOUND_PORTS capsules
     /// have already bound to a port.
@@ -218,8 +276,12 @@ impl UdpPortManager {
         if user_bound {
             return Ok(true);
         };
-        let ret = self
-            .port_array
+        Ok(self.is_bound_by_capsule(port))
+    }
+
+    /// Check if a given port is bound by a capsule, ignoring app bindings.
+    pub fn is_bound_by_capsule(&self, port: u16) -> bool {
+        self.port_array
             .map(|table| {
                 let mut port_exists = false;
                 for i in 0..MAX_NUM_BOUND_PORTS {
@@ -235,8 +297,7 @@ impl UdpPortManager {
                 }
                 port_exists
             })
-            .unwrap();
-        Ok(ret)
+            .unwrap()
     }
 
     /// Called by capsules that have already reserved a socket to attempt to bind to
@@ -294,3 +355,42 @@ impl UdpPortManager {
         Ok(UdpSocket::new(idx, self))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU32;
+
+    fn app_id(id: u32) -> ShortId {
+        ShortId::Fixed(NonZeroU32::new(id).unwrap())
+    }
+
+    #[test]
+    fn binds_outside_an_apps_ranges_are_rejected() {
+        let ranges = [
+            AppPortRange::new(app_id(1), PortRange::Range(1000, 1999)),
+            AppPortRange::new(app_id(1), PortRange::Port(5683)),
+            AppPortRange::new(app_id(2), PortRange::Range(2000, 2999)),
+        ];
+
+        assert!(port_allowed(&ranges, app_id(1), 1000));
+        assert!(port_allowed(&ranges, app_id(1), 1999));
+        assert!(port_allowed(&ranges, app_id(1), 5683));
+        assert!(!port_allowed(&ranges, app_id(1), 2000));
+        assert!(!port_allowed(&ranges, app_id(1), 3000));
+
+        assert!(port_allowed(&ranges, app_id(2), 2500));
+        assert!(!port_allowed(&ranges, app_id(2), 1500));
+        assert!(!port_allowed(&ranges, app_id(2), 5683));
+    }
+
+    #[test]
+    fn apps_without_ranges_cannot_bind_reserved_ports() {
+        let ranges = [AppPortRange::new(app_id(1), PortRange::Range(1000, 1999))];
+        assert!(!port_allowed(&ranges, app_id(3), 1500));
+        assert!(!port_allowed(&ranges, ShortId::LocallyUnique, 1500));
+        assert!(port_allowed(&ranges, app_id(3), 3000));
+        assert!(port_allowed(&ranges, ShortId::LocallyUnique, 3000));
+        assert!(port_allowed(&[], app_id(1), 1500));
+    }
+}
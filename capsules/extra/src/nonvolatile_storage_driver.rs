@@ -4,12 +4,35 @@
 
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! The memory provided to userspace is partitioned into isolated regions, one
+//! per application. Each application only sees its own region, which starts at
+//! address 0 from the application's point of view. Regions are keyed by the
+//! write ID in the application's `StoragePermissions`, so an application gets
+//! the same region back after it is updated or the board reboots, and an
+//! application without a write ID cannot use this driver. Applications request
+//! the size of their region with a nonvolatile storage TBF header; applications
+//! without one get `DEFAULT_APP_REGION_SIZE` bytes.
 //!
-//! However, the kernel accessible memory does not have to be the same range
+//! Regions are allocated when an application first accesses the storage and
+//! are recorded in a region table at the start of the userspace memory, so
+//! allocations persist across reboots. Regions are never freed or resized.
+//!
+//! Region Table Format
+//! -------------------
+//!
+//! The table holds `MAX_APP_REGIONS` entries of 16 bytes. All fields are
+//! little endian u32s:
+//!
+//! ```text
+//! +-------+----------+--------+--------+
+//! | magic | write_id | offset | length |
+//! +-------+----------+--------+--------+
+//! ```
+//!
+//! An entry is in use if `magic` is `REGION_MAGIC`. `offset` is relative to the
+//! start of the userspace memory, and the first region starts after the table.
+//!
+//! The kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
 //! if desired, or can be a completely separate range.
 //!
@@ -61,11 +84,11 @@
 use core::cell::Cell;
 use core::cmp;
 
-use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, GrantKernelData, UpcallCount};
 use kernel::hil;
 use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
 use kernel::syscall::{CommandReturn, SyscallDriver};
-use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
 
 /// Syscall driver number.
@@ -100,6 +123,18 @@ mod rw_allow {
 
 pub const BUF_LEN: usize = 512;
 
+/// Maximum number of applications that can be allocated a region.
+pub const MAX_APP_REGIONS: usize = 8;
+/// Size of the region allocated to applications that do not request a size in
+/// their TBF header.
+pub const DEFAULT_APP_REGION_SIZE: usize = 2048;
+/// Marks an entry of the region table that is in use.
+pub const REGION_MAGIC: u32 = 0x5256_4e54;
+
+const REGION_ENTRY_LEN: usize = 16;
+/// Length of the region table at the start of the userspace memory.
+pub const REGION_TABLE_LEN: usize = MAX_APP_REGIONS * REGION_ENTRY_LEN;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -110,8 +145,110 @@ pub enum NonvolatileCommand {
 
 #[derive(Clone, Copy)]
 pub enum NonvolatileUser {
-    App { processid: ProcessId },
+    App {
+        processid: ProcessId,
+    },
     Kernel,
+    /// The driver itself, reading or updating the region table.
+    RegionTable,
+}
+
+/// Part of the userspace memory allocated to one application.
+#[derive(Clone, Copy)]
+struct AppRegion {
+    write_id: u32,
+    /// Offset from the start of the userspace memory.
+    offset: usize,
+    length: usize,
+}
+
+impl AppRegion {
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&REGION_MAGIC.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.write_id.to_le_bytes());
+        buf[8..12].copy_from_slice(&(self.offset as u32).to_le_bytes());
+        buf[12..16].copy_from_slice(&(self.length as u32).to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<AppRegion> {
+        let field = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        if field(0) != REGION_MAGIC {
+            return None;
+        }
+        Some(AppRegion {
+            write_id: field(4),
+            offset: field(8) as usize,
+            length: field(12) as usize,
+        })
+    }
+
+    /// Offset of the first byte after the region, or `None` if it overflows.
+    fn end(&self) -> Option<usize> {
+        self.offset.checked_add(self.length)
+    }
+}
+
+/// Places a new region of `length` bytes after the last region in `regions`.
+/// Returns the free table index to record it at and the region.
+fn next_region(
+    regions: &[Option<AppRegion>; MAX_APP_REGIONS],
+    write_id: u32,
+    length: usize,
+    userspace_length: usize,
+) -> Result<(usize, AppRegion), ErrorCode> {
+    let index = regions
+        .iter()
+        .position(|region| region.is_none())
+        .ok_or(ErrorCode::NOMEM)?;
+    let offset = regions
+        .iter()
+        .flatten()
+        .filter_map(AppRegion::end)
+        .max()
+        .unwrap_or(REGION_TABLE_LEN);
+    // The length comes from the TBF header of the app, so do not let it wrap
+    // around the end of the address space.
+    let end = offset.checked_add(length).ok_or(ErrorCode::INVAL)?;
+    if end > userspace_length {
+        return Err(ErrorCode::NOMEM);
+    }
+    Ok((
+        index,
+        AppRegion {
+            write_id,
+            offset,
+            length,
+        },
+    ))
+}
+
+/// Parses the region table in `buf`. Entries that do not fit in
+/// `userspace_length` bytes, overlap an earlier entry or reuse the write ID of
+/// an earlier entry are dropped.
+fn parse_region_table(buf: &[u8], userspace_length: usize) -> [Option<AppRegion>; MAX_APP_REGIONS] {
+    let mut regions: [Option<AppRegion>; MAX_APP_REGIONS] = [None; MAX_APP_REGIONS];
+    let entries = buf[..cmp::min(buf.len(), REGION_TABLE_LEN)]
+        .chunks_exact(REGION_ENTRY_LEN)
+        .map(AppRegion::decode);
+    for (i, entry) in entries.enumerate() {
+        let Some(entry) = entry else {
+            continue;
+        };
+        let Some(end) = entry.end() else {
+            continue;
+        };
+        if entry.offset < REGION_TABLE_LEN || end > userspace_length {
+            continue;
+        }
+        let conflicts = regions[..i].iter().flatten().any(|other| {
+            other.write_id == entry.write_id
+                || (entry.offset < other.offset + other.length && other.offset < end)
+        });
+        if !conflicts {
+            regions[i] = Some(entry);
+        }
+    }
+    regions
 }
 
 pub struct App {
@@ -152,6 +289,11 @@ pub struct NonvolatileStorage<'a> {
     userspace_start_address: usize,
     // How many bytes allocated to userspace.
     userspace_length: usize,
+    // Copy of the region table, empty until it has been read from storage.
+    regions: MapCell<[Option<AppRegion>; MAX_APP_REGIONS]>,
+    // Region being written to the region table and its index in the table.
+    // It is added to `regions` once the write completes.
+    pending_region: OptionalCell<(usize, AppRegion)>,
     // The first byte that is accessible from the kernel.
     kernel_start_address: usize,
     // How many bytes allocated to kernel.
@@ -194,6 +336,8 @@ impl<'a> NonvolatileStorage<'a> {
             current_user: OptionalCell::empty(),
             userspace_start_address,
             userspace_length,
+            regions: MapCell::empty(),
+            pending_region: OptionalCell::empty(),
             kernel_start_address,
             kernel_length,
             kernel_client: OptionalCell::empty(),
@@ -218,11 +362,17 @@ impl<'a> NonvolatileStorage<'a> {
         // Do bounds check.
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
+                // Userspace sees its region starting at address 0. If the
+                // region is not allocated yet, check against the size it
+                // will be allocated with.
+                let region_length =
+                    processid.map_or(0, |processid| self.app_region_length(processid));
+                if region_length == 0 {
+                    return Err(ErrorCode::NOSUPPORT);
+                }
+                if offset >= region_length
+                    || length > region_length
+                    || offset + length > region_length
                 {
                     return Err(ErrorCode::INVAL);
                 }
@@ -259,60 +409,34 @@ impl<'a> NonvolatileStorage<'a> {
                             };
 
                             // Check that it exists.
-                            if allow_buf_len == 0 || self.buffer.is_none() {
+                            if allow_buf_len == 0 {
                                 return Err(ErrorCode::RESERVE);
                             }
 
+                            if app.pending_command {
+                                // No more room in the queue, nowhere to store this
+                                // request.
+                                return Err(ErrorCode::NOMEM);
+                            }
+
                             // Shorten the length if the application gave us nowhere to
                             // put it.
-                            let active_len = cmp::min(length, allow_buf_len);
-
-                            // First need to determine if we can execute this or must
-                            // queue it.
+                            app.pending_command = true;
+                            app.command = command;
+                            app.offset = offset;
+                            app.length = cmp::min(length, allow_buf_len);
+
+                            // If no one is using the underlying storage, start
+                            // the command now. Otherwise it runs when the
+                            // pending command completes.
                             if self.current_user.is_none() {
-                                // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user.set(NonvolatileUser::App { processid });
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    let _ = kernel_data
-                                        .get_readonly_processbuffer(ro_allow::WRITE)
-                                        .and_then(|write| {
-                                            write.enter(|app_buffer| {
-                                                self.buffer.map(|kernel_buffer| {
-                                                    // Check that the internal buffer and the buffer that was
-                                                    // allowed are long enough.
-                                                    let write_len =
-                                                        cmp::min(active_len, kernel_buffer.len());
-
-                                                    let d = &app_buffer[0..write_len];
-                                                    for (i, c) in kernel_buffer[0..write_len]
-                                                        .iter_mut()
-                                                        .enumerate()
-                                                    {
-                                                        *c = d[i].get();
-                                                    }
-                                                });
-                                            })
-                                        });
+                                let res = self.start_app_command(processid, app, kernel_data);
+                                if res.is_err() {
+                                    app.pending_command = false;
                                 }
-
-                                self.userspace_call_driver(command, offset, active_len)
+                                res
                             } else {
-                                // Some app is using the storage, we must wait.
-                                if app.pending_command {
-                                    // No more room in the queue, nowhere to store this
-                                    // request.
-                                    Err(ErrorCode::NOMEM)
-                                } else {
-                                    // We can store this, so lets do it.
-                                    app.pending_command = true;
-                                    app.command = command;
-                                    app.offset = offset;
-                                    app.length = active_len;
-                                    Ok(())
-                                }
+                                Ok(())
                             }
                         })
                         .unwrap_or_else(|err| Err(err.into()))
@@ -355,6 +479,139 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
+    /// The write ID that identifies the region of `processid`.
+    fn app_write_id(&self, processid: ProcessId) -> Option<u32> {
+        processid
+            .get_storage_permissions()
+            .and_then(|permissions| permissions.get_write_id())
+            .filter(|write_id| *write_id != 0)
+    }
+
+    fn find_region(&self, write_id: u32) -> Option<AppRegion> {
+        self.regions
+            .map(|regions| {
+                regions
+                    .iter()
+                    .flatten()
+                    .find(|region| region.write_id == write_id)
+                    .copied()
+            })
+            .flatten()
+    }
+
+    /// Size of the region `processid` requested.
+    fn requested_region_size(&self, processid: ProcessId) -> usize {
+        processid
+            .get_nonvolatile_storage_size()
+            .map_or(DEFAULT_APP_REGION_SIZE, |size| size as usize)
+    }
+
+    /// Length of the region of `processid`, or the length it will be allocated
+    /// with. Returns 0 if the process cannot have a region.
+    fn app_region_length(&self, processid: ProcessId) -> usize {
+        self.app_write_id(processid).map_or(0, |write_id| {
+            self.find_region(write_id).map_or_else(
+                || self.requested_region_size(processid),
+                |region| region.length,
+            )
+        })
+    }
+
+    /// Starts the pending command of an app, after first reading the region
+    /// table or allocating the app's region if needed. In that case the
+    /// command stays pending and is started by `check_queue()` once the region
+    /// table operation completes.
+    fn start_app_command(
+        &self,
+        processid: ProcessId,
+        app: &mut App,
+        kernel_data: &GrantKernelData,
+    ) -> Result<(), ErrorCode> {
+        let write_id = self.app_write_id(processid).ok_or(ErrorCode::NOSUPPORT)?;
+        if self.regions.is_none() {
+            return self.read_region_table();
+        }
+        let region = match self.find_region(write_id) {
+            Some(region) => region,
+            None => return self.allocate_region(write_id, self.requested_region_size(processid)),
+        };
+        if app.offset + app.length > region.length {
+            return Err(ErrorCode::INVAL);
+        }
+        app.pending_command = false;
+
+        // Need to copy bytes if this is a write!
+        if app.command == NonvolatileCommand::UserspaceWrite {
+            let _ = kernel_data
+                .get_readonly_processbuffer(ro_allow::WRITE)
+                .and_then(|write| {
+                    write.enter(|app_buffer| {
+                        self.buffer.map(|kernel_buffer| {
+                            // Check that the internal buffer and the buffer that was
+                            // allowed are long enough.
+                            let write_len = cmp::min(app.length, kernel_buffer.len());
+
+                            let d = &app_buffer[0..write_len];
+                            for (i, c) in kernel_buffer[0..write_len].iter_mut().enumerate() {
+                                *c = d[i].get();
+                            }
+                        });
+                    })
+                });
+        }
+
+        self.current_user.set(NonvolatileUser::App { processid });
+        let res = self.userspace_call_driver(app.command, region.offset + app.offset, app.length);
+        if res.is_err() {
+            self.current_user.clear();
+        }
+        res
+    }
+
+    fn read_region_table(&self) -> Result<(), ErrorCode> {
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                self.current_user.set(NonvolatileUser::RegionTable);
+                let res = self
+                    .driver
+                    .read(buffer, self.userspace_start_address, REGION_TABLE_LEN);
+                if res.is_err() {
+                    self.current_user.clear();
+                }
+                res
+            })
+    }
+
+    /// Allocates a region after the last allocated one and records it in the
+    /// region table.
+    fn allocate_region(&self, write_id: u32, length: usize) -> Result<(), ErrorCode> {
+        let (index, region) = self
+            .regions
+            .map(|regions| next_region(regions, write_id, length, self.userspace_length))
+            .unwrap_or(Err(ErrorCode::FAIL))?;
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                region.encode(&mut buffer[..REGION_ENTRY_LEN]);
+                self.current_user.set(NonvolatileUser::RegionTable);
+                match self.driver.write(
+                    buffer,
+                    self.userspace_start_address + index * REGION_ENTRY_LEN,
+                    REGION_ENTRY_LEN,
+                ) {
+                    Ok(()) => {
+                        self.pending_region.set((index, region));
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.current_user.clear();
+                        Err(e)
+                    }
+                }
+            })
+    }
+
     fn userspace_call_driver(
         &self,
         command: NonvolatileCommand,
@@ -362,7 +619,7 @@ impl<'a> NonvolatileStorage<'a> {
         length: usize,
     ) -> Result<(), ErrorCode> {
         // Calculate where we want to actually read from in the physical
-        // storage.
+        // storage. `offset` is relative to the start of the userspace memory.
         let physical_address = offset + self.userspace_start_address;
 
         self.buffer
@@ -410,19 +667,28 @@ impl<'a> NonvolatileStorage<'a> {
             // If the kernel is not requesting anything, check all of the apps.
             for cntr in self.apps.iter() {
                 let processid = cntr.processid();
-                let started_command = cntr.enter(|app, _| {
-                    if app.pending_command {
-                        app.pending_command = false;
-                        self.current_user.set(NonvolatileUser::App { processid });
-                        if let Ok(()) =
-                            self.userspace_call_driver(app.command, app.offset, app.length)
-                        {
-                            true
-                        } else {
+                let started_command = cntr.enter(|app, kernel_data| {
+                    if !app.pending_command {
+                        return false;
+                    }
+                    match self.start_app_command(processid, app, kernel_data) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            // The command can no longer fail synchronously,
+                            // so report the error with its done upcall.
+                            app.pending_command = false;
+                            let upcall_num = match app.command {
+                                NonvolatileCommand::UserspaceRead => upcall::READ_DONE,
+                                _ => upcall::WRITE_DONE,
+                            };
+                            kernel_data
+                                .schedule_upcall(
+                                    upcall_num,
+                                    (0, kernel::errorcode::into_statuscode(Err(e)), 0),
+                                )
+                                .ok();
                             false
                         }
-                    } else {
-                        false
                     }
                 });
                 if started_command {
@@ -444,6 +710,12 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.read_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable => {
+                    let length = cmp::min(length, buffer.len());
+                    self.regions
+                        .replace(parse_region_table(&buffer[..length], self.userspace_length));
+                    self.buffer.replace(buffer);
+                }
                 NonvolatileUser::App { processid } => {
                     let _ = self.apps.enter(processid, move |_, kernel_data| {
                         // Need to copy in the contents of the buffer
@@ -484,6 +756,15 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.write_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable => {
+                    // Only use the new region once it is recorded in storage.
+                    self.pending_region.take().map(|(index, region)| {
+                        if length == REGION_ENTRY_LEN {
+                            self.regions.map(|regions| regions[index] = Some(region));
+                        }
+                    });
+                    self.buffer.replace(buffer);
+                }
                 NonvolatileUser::App { processid } => {
                     let _ = self.apps.enter(processid, move |_app, kernel_data| {
                         // Replace the buffer we used to do this write.
@@ -538,9 +819,15 @@ impl SyscallDriver for NonvolatileStorage<'_> {
     /// ### `command_num`
     ///
     /// - `0`: Return Ok(()) if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the size of this app's region. Returns NOSUPPORT if the
+    ///        app has no storage write ID.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
+    ///
+    /// The read and write done upcalls pass the number of bytes read or
+    /// written and a status code. A command that has to wait for another one
+    /// and then fails to start gets its done upcall with length 0 and the
+    /// error, instead of failing the command.
     fn command(
         &self,
         command_num: usize,
@@ -552,9 +839,12 @@ impl SyscallDriver for NonvolatileStorage<'_> {
             0 => CommandReturn::success(),
 
             1 => {
-                // How many bytes are accessible from userspace
+                // How many bytes are accessible from this app
                 // TODO: Would break on 64-bit platforms
-                CommandReturn::success_u32(self.userspace_length as u32)
+                match self.app_region_length(processid) {
+                    0 => CommandReturn::failure(ErrorCode::NOSUPPORT),
+                    length => CommandReturn::success_u32(length as u32),
+                }
             }
 
             2 => {
@@ -595,3 +885,94 @@ impl SyscallDriver for NonvolatileStorage<'_> {
         self.apps.enter(processid, |_, _| {})
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERSPACE_LENGTH: usize = 0x1000;
+
+    fn table(entries: &[(u32, u32, u32)]) -> [u8; REGION_TABLE_LEN] {
+        let mut buf = [0xff; REGION_TABLE_LEN];
+        for (entry, (write_id, offset, length)) in
+            buf.chunks_exact_mut(REGION_ENTRY_LEN).zip(entries)
+        {
+            AppRegion {
+                write_id: *write_id,
+                offset: *offset as usize,
+                length: *length as usize,
+            }
+            .encode(entry);
+        }
+        buf
+    }
+
+    fn write_ids(regions: &[Option<AppRegion>; MAX_APP_REGIONS]) -> [Option<u32>; MAX_APP_REGIONS] {
+        regions.map(|region| region.map(|region| region.write_id))
+    }
+
+    #[test]
+    fn region_table_keeps_valid_entries() {
+        let buf = table(&[(1, 0x80, 0x100), (2, 0x180, 0x200)]);
+        let regions = parse_region_table(&buf, USERSPACE_LENGTH);
+        assert_eq!(
+            write_ids(&regions),
+            [Some(1), Some(2), None, None, None, None, None, None]
+        );
+        let second = regions[1].unwrap();
+        assert_eq!((second.offset, second.length), (0x180, 0x200));
+    }
+
+    #[test]
+    fn region_table_drops_overflowing_entries() {
+        // The end of the second entry wraps around to 0x80 on 32-bit targets.
+        let buf = table(&[(1, 0x80, 0x100), (2, 0x100, 0xffff_ff80), (3, 0xf00, 0x200)]);
+        let regions = parse_region_table(&buf, USERSPACE_LENGTH);
+        assert_eq!(
+            write_ids(&regions),
+            [Some(1), None, None, None, None, None, None, None]
+        );
+    }
+
+    #[test]
+    fn region_table_drops_overlapping_entries() {
+        let buf = table(&[
+            (1, 0x80, 0x100),
+            (2, 0x100, 0x100),
+            (3, 0x40, 0x100),
+            (1, 0x400, 0x100),
+            (4, 0x180, 0x80),
+        ]);
+        let regions = parse_region_table(&buf, USERSPACE_LENGTH);
+        assert_eq!(
+            write_ids(&regions),
+            [Some(1), None, None, None, Some(4), None, None, None]
+        );
+    }
+
+    #[test]
+    fn next_region_rejects_overflowing_length() {
+        let regions = parse_region_table(&table(&[(1, 0x80, 0x100)]), USERSPACE_LENGTH);
+        assert_eq!(
+            next_region(&regions, 2, usize::MAX - 0x100, USERSPACE_LENGTH).err(),
+            Some(ErrorCode::INVAL)
+        );
+        assert_eq!(
+            next_region(&regions, 2, USERSPACE_LENGTH, USERSPACE_LENGTH).err(),
+            Some(ErrorCode::NOMEM)
+        );
+        let (index, region) = next_region(&regions, 2, 0x100, USERSPACE_LENGTH).unwrap();
+        assert_eq!((index, region.offset, region.length), (1, 0x180, 0x100));
+    }
+
+    #[test]
+    fn next_region_needs_a_free_entry() {
+        let entries: [(u32, u32, u32); MAX_APP_REGIONS] =
+            core::array::from_fn(|i| (i as u32 + 1, 0x80 + 0x10 * i as u32, 0x10));
+        let regions = parse_region_table(&table(&entries), USERSPACE_LENGTH);
+        assert_eq!(
+            next_region(&regions, 20, 0x10, USERSPACE_LENGTH).err(),
+            Some(ErrorCode::NOMEM)
+        );
+    }
+}
@@ -0,0 +1,150 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Block storage device backed by RAM.
+//!
+//! Provides `hil::block_storage::BlockStorage` over a buffer in memory, for
+//! example to hold a temporary file system or to test users of block storage
+//! without hardware. The contents are lost on reset.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//!
+//! let ram_disk = static_init!(
+//!     capsules_extra::ram_disk::RamDisk<'static>,
+//!     capsules_extra::ram_disk::RamDisk::new(
+//!         static_init!([u8; 64 * 512], [0; 64 * 512]),
+//!         512,
+//!     )
+//! );
+//! kernel::deferred_call::DeferredCallClient::register(ram_disk);
+//! ```
+
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Read,
+    Write,
+}
+
+pub struct RamDisk<'a> {
+    storage: TakeCell<'static, [u8]>,
+    block_size: usize,
+    block_count: u64,
+    client: OptionalCell<&'a dyn BlockStorageClient>,
+    operation: OptionalCell<Operation>,
+    buffer: TakeCell<'static, [u8]>,
+    deferred_call: DeferredCall,
+}
+
+impl<'a> RamDisk<'a> {
+    /// Create a RAM disk with blocks of `block_size` bytes. Trailing bytes of
+    /// `storage` that do not fill a block are not used.
+    pub fn new(storage: &'static mut [u8], block_size: usize) -> RamDisk<'a> {
+        let block_count = (storage.len() / block_size) as u64;
+        RamDisk {
+            storage: TakeCell::new(storage),
+            block_size,
+            block_count,
+            client: OptionalCell::empty(),
+            operation: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            deferred_call: DeferredCall::new(),
+        }
+    }
+
+    /// Check a request and return the range of the storage it covers.
+    fn range(
+        &self,
+        buffer: &[u8],
+        block: u64,
+        count: usize,
+    ) -> Result<core::ops::Range<usize>, ErrorCode> {
+        if self.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        let end = block.checked_add(count as u64).ok_or(ErrorCode::INVAL)?;
+        if end > self.block_count || buffer.len() < count * self.block_size {
+            return Err(ErrorCode::INVAL);
+        }
+        let start = block as usize * self.block_size;
+        Ok(start..start + count * self.block_size)
+    }
+
+    fn start(
+        &self,
+        operation: Operation,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let range = match self.range(buffer, block, count) {
+            Ok(range) => range,
+            Err(e) => return Err((e, buffer)),
+        };
+        let length = range.len();
+        self.storage.map(|storage| match operation {
+            Operation::Read => buffer[..length].copy_from_slice(&storage[range]),
+            Operation::Write => storage[range].copy_from_slice(&buffer[..length]),
+        });
+        self.buffer.replace(buffer);
+        self.operation.set(operation);
+        self.deferred_call.set();
+        Ok(())
+    }
+}
+
+impl<'a> BlockStorage<'a> for RamDisk<'a> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start(Operation::Read, buffer, block, count)
+    }
+
+    fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.start(Operation::Write, buffer, block, count)
+    }
+}
+
+impl DeferredCallClient for RamDisk<'_> {
+    fn handle_deferred_call(&self) {
+        if let (Some(operation), Some(buffer)) = (self.operation.take(), self.buffer.take()) {
+            self.client.map(move |client| match operation {
+                Operation::Read => client.read_complete(buffer, Ok(())),
+                Operation::Write => client.write_complete(buffer, Ok(())),
+            });
+        }
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
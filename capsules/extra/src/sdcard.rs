@@ -4,7 +4,9 @@
 
 //! Provides driver for accessing an SD Card and a userspace Driver.
 //!
-//! This allows initialization and block reads or writes on top of SPI.
+//! This allows initialization and block reads or writes on top of SPI. Once
+//! initialized, the card can also be used through the
+//! `hil::block_storage::BlockStorage` interface, for example by a file system.
 //!
 //! Usage
 //! -----
@@ -132,6 +134,18 @@ pub struct SDCard<'a, A: hil::time::Alarm<'a>> {
     client: OptionalCell<&'a dyn SDCardClient>,
     client_buffer: TakeCell<'static, [u8]>,
     client_offset: Cell<usize>,
+
+    block_client: OptionalCell<&'a dyn hil::block_storage::BlockStorageClient>,
+    block_op: OptionalCell<BlockOp>,
+    block_count: Cell<u64>,
+}
+
+/// Block storage operation in progress, whose result goes to the block
+/// storage client instead of the `SDCardClient`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockOp {
+    Read,
+    Write,
 }
 
 /// SD card command codes
@@ -293,6 +307,9 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             client: OptionalCell::empty(),
             client_buffer: TakeCell::empty(),
             client_offset: Cell::new(0),
+            block_client: OptionalCell::empty(),
+            block_op: OptionalCell::empty(),
+            block_count: Cell::new(0),
         }
     }
 
@@ -521,9 +538,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
-                    });
+                    self.report_error(SdCardError::InitializationFailure);
                 }
             }
 
@@ -591,9 +606,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
-                    });
+                    self.report_error(SdCardError::InitializationFailure);
                 }
             }
 
@@ -620,9 +633,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
-                    });
+                    self.report_error(SdCardError::InitializationFailure);
                 }
             }
 
@@ -687,9 +698,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
-                    });
+                    self.report_error(SdCardError::InitializationFailure);
                 }
             }
 
@@ -725,9 +734,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
-                    });
+                    self.report_error(SdCardError::InitializationFailure);
                 }
             }
 
@@ -748,9 +755,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
-                    });
+                    self.report_error(SdCardError::InitializationFailure);
                 }
             }
 
@@ -798,6 +803,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     // initialization complete
                     self.state.set(SpiState::Idle);
                     self.is_initialized.set(true);
+                    self.block_count.set(total_size / 512);
 
                     // perform callback
                     self.client.map(move |client| {
@@ -810,9 +816,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::InitializationFailure as u32);
-                    });
+                    self.report_error(SdCardError::InitializationFailure);
                 }
             }
 
@@ -837,9 +841,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    self.report_error(SdCardError::ReadFailure);
                 }
             }
 
@@ -867,9 +869,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    self.report_error(SdCardError::ReadFailure);
                 }
             }
 
@@ -893,9 +893,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                         // callback
                         let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
-                        self.client.map(move |client| {
-                            client.read_done(buffer, read_len);
-                        });
+                        self.report_read_done(buffer, read_len);
                     });
                 });
             }
@@ -925,9 +923,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    self.report_error(SdCardError::ReadFailure);
                 }
             }
 
@@ -976,9 +972,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                     // read finished, perform callback
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.read_done(buffer, self.client_offset.get());
-                        });
+                        self.report_read_done(buffer, self.client_offset.get());
                     });
                 } else {
                     // error, send callback and quit
@@ -987,9 +981,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::ReadFailure as u32);
-                    });
+                    self.report_error(SdCardError::ReadFailure);
                 }
             }
 
@@ -1040,9 +1032,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                         self.state.set(SpiState::Idle);
                         self.alarm_state.set(AlarmState::Idle);
                         self.alarm_count.set(0);
-                        self.client.map(move |client| {
-                            client.error(SdCardError::WriteFailure as u32);
-                        });
+                        self.report_error(SdCardError::WriteFailure);
                     }
                 } else {
                     // error, send callback and quit
@@ -1051,9 +1041,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::WriteFailure as u32);
-                    });
+                    self.report_error(SdCardError::WriteFailure);
                 }
             }
 
@@ -1075,9 +1063,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(SdCardError::WriteFailure as u32);
-                    });
+                    self.report_error(SdCardError::WriteFailure);
                 }
             }
 
@@ -1092,9 +1078,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_count.set(0);
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.write_done(buffer);
-                        });
+                        self.report_write_done(buffer);
                     });
                 } else {
                     // replace buffers
@@ -1127,9 +1111,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
             self.alarm_count.set(0);
-            self.client.map(move |client| {
-                client.error(SdCardError::TimeoutFailure as u32);
-            });
+            self.report_error(SdCardError::TimeoutFailure);
         } else {
             self.alarm_count.set(repeats + 1);
         }
@@ -1256,6 +1238,49 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         self.client.set(client);
     }
 
+    /// Send the result of a failed operation to the client that started it.
+    fn report_error(&self, error: SdCardError) {
+        match self.block_op.take() {
+            Some(op) => {
+                self.client_buffer.take().map(|buffer| {
+                    self.block_client.map(move |client| match op {
+                        BlockOp::Read => client.read_complete(buffer, Err(ErrorCode::FAIL)),
+                        BlockOp::Write => client.write_complete(buffer, Err(ErrorCode::FAIL)),
+                    });
+                });
+            }
+            None => {
+                self.client.map(move |client| {
+                    client.error(error as u32);
+                });
+            }
+        }
+    }
+
+    fn report_read_done(&self, buffer: &'static mut [u8], length: usize) {
+        if self.block_op.take().is_some() {
+            self.block_client.map(move |client| {
+                client.read_complete(buffer, Ok(()));
+            });
+        } else {
+            self.client.map(move |client| {
+                client.read_done(buffer, length);
+            });
+        }
+    }
+
+    fn report_write_done(&self, buffer: &'static mut [u8]) {
+        if self.block_op.take().is_some() {
+            self.block_client.map(move |client| {
+                client.write_complete(buffer, Ok(()));
+            });
+        } else {
+            self.client.map(move |client| {
+                client.write_done(buffer);
+            });
+        }
+    }
+
     pub fn is_installed(&self) -> bool {
         // if there is no detect pin, assume an sd card is installed
         self.detect_pin.get().map_or(true, |pin| {
@@ -1307,56 +1332,54 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         sector: u32,
         count: u32,
     ) -> Result<(), ErrorCode> {
+        self.start_read(buffer, sector, count).map_err(|(e, _)| e)
+    }
+
+    fn start_read(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer
-                    .take()
-                    .map_or(Err(ErrorCode::NOMEM), |txbuffer| {
-                        self.rxbuffer
-                            .take()
-                            .map_or(Err(ErrorCode::NOMEM), move |rxbuffer| {
-                                // save the user buffer for later
-                                self.client_buffer.replace(buffer);
-                                self.client_offset.set(0);
-
-                                // convert block address to byte address for non-block
-                                //  access cards
-                                let mut address = sector;
-                                if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                    address *= 512;
-                                }
+        if !self.is_installed() {
+            // sd card not installed
+            return Err((ErrorCode::UNINSTALLED, buffer));
+        }
+        if !self.is_initialized() {
+            // sd card not initialized
+            return Err((ErrorCode::RESERVE, buffer));
+        }
 
-                                self.state.set(SpiState::StartReadBlocks { count });
-                                if count == 1 {
-                                    self.send_command(
-                                        SDCmd::CMD17_ReadSingle,
-                                        address,
-                                        txbuffer,
-                                        rxbuffer,
-                                        10,
-                                    );
-                                } else {
-                                    self.send_command(
-                                        SDCmd::CMD18_ReadMultiple,
-                                        address,
-                                        txbuffer,
-                                        rxbuffer,
-                                        10,
-                                    );
-                                }
+        match (self.txbuffer.take(), self.rxbuffer.take()) {
+            (Some(txbuffer), Some(rxbuffer)) => {
+                // save the user buffer for later
+                self.client_buffer.replace(buffer);
+                self.client_offset.set(0);
+
+                // convert block address to byte address for non-block
+                //  access cards
+                let mut address = sector;
+                if self.card_type.get() != SDCardType::SDv2BlockAddressable {
+                    address *= 512;
+                }
 
-                                // command started successfully
-                                Ok(())
-                            })
-                    })
-            } else {
-                // sd card not initialized
-                Err(ErrorCode::RESERVE)
+                self.state.set(SpiState::StartReadBlocks { count });
+                if count == 1 {
+                    self.send_command(SDCmd::CMD17_ReadSingle, address, txbuffer, rxbuffer, 10);
+                } else {
+                    self.send_command(SDCmd::CMD18_ReadMultiple, address, txbuffer, rxbuffer, 10);
+                }
+
+                // command started successfully
+                Ok(())
+            }
+            (txbuffer, rxbuffer) => {
+                // an operation is in progress
+                txbuffer.map(|txbuffer| self.txbuffer.replace(txbuffer));
+                rxbuffer.map(|rxbuffer| self.rxbuffer.replace(rxbuffer));
+                Err((ErrorCode::NOMEM, buffer))
             }
-        } else {
-            // sd card not installed
-            Err(ErrorCode::UNINSTALLED)
         }
     }
 
@@ -1366,53 +1389,141 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         sector: u32,
         count: u32,
     ) -> Result<(), ErrorCode> {
+        self.start_write(buffer, sector, count).map_err(|(e, _)| e)
+    }
+
+    fn start_write(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
         // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer
-                    .take()
-                    .map_or(Err(ErrorCode::NOMEM), |txbuffer| {
-                        self.rxbuffer
-                            .take()
-                            .map_or(Err(ErrorCode::NOMEM), move |rxbuffer| {
-                                // save the user buffer for later
-                                self.client_buffer.replace(buffer);
-                                self.client_offset.set(0);
-
-                                // convert block address to byte address for non-block
-                                //  access cards
-                                let mut address = sector;
-                                if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                    address *= 512;
-                                }
+        if !self.is_installed() {
+            // sd card not installed
+            return Err((ErrorCode::UNINSTALLED, buffer));
+        }
+        if !self.is_initialized() {
+            // sd card not initialized
+            return Err((ErrorCode::RESERVE, buffer));
+        }
+        if count != 1 {
+            // can't write multiple blocks yet
+            return Err((ErrorCode::NOSUPPORT, buffer));
+        }
 
-                                self.state.set(SpiState::StartWriteBlocks { count });
-                                if count == 1 {
-                                    self.send_command(
-                                        SDCmd::CMD24_WriteSingle,
-                                        address,
-                                        txbuffer,
-                                        rxbuffer,
-                                        10,
-                                    );
-
-                                    // command started successfully
-                                    Ok(())
-                                } else {
-                                    // can't write multiple blocks yet
-                                    Err(ErrorCode::NOSUPPORT)
-                                }
-                            })
-                    })
-            } else {
-                // sd card not initialized
-                Err(ErrorCode::RESERVE)
+        match (self.txbuffer.take(), self.rxbuffer.take()) {
+            (Some(txbuffer), Some(rxbuffer)) => {
+                // save the user buffer for later
+                self.client_buffer.replace(buffer);
+                self.client_offset.set(0);
+
+                // convert block address to byte address for non-block
+                //  access cards
+                let mut address = sector;
+                if self.card_type.get() != SDCardType::SDv2BlockAddressable {
+                    address *= 512;
+                }
+
+                self.state.set(SpiState::StartWriteBlocks { count });
+                self.send_command(SDCmd::CMD24_WriteSingle, address, txbuffer, rxbuffer, 10);
+
+                // command started successfully
+                Ok(())
             }
+            (txbuffer, rxbuffer) => {
+                // an operation is in progress
+                txbuffer.map(|txbuffer| self.txbuffer.replace(txbuffer));
+                rxbuffer.map(|rxbuffer| self.rxbuffer.replace(rxbuffer));
+                Err((ErrorCode::NOMEM, buffer))
+            }
+        }
+    }
+
+    /// Check a block storage request and convert the block number to the
+    /// sector number used by `read_blocks` and `write_blocks`.
+    fn check_block_request(
+        &self,
+        buffer: &[u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(u32, u32), ErrorCode> {
+        if self.block_op.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if !self.is_installed() || !self.is_initialized() {
+            return Err(ErrorCode::RESERVE);
+        }
+        let end = block.checked_add(count as u64).ok_or(ErrorCode::INVAL)?;
+        if count == 0 || end > self.block_count.get() || buffer.len() < count * 512 {
+            return Err(ErrorCode::INVAL);
+        }
+        let sector = u32::try_from(block).map_err(|_| ErrorCode::INVAL)?;
+        Ok((sector, count as u32))
+    }
+}
+
+impl<'a, A: hil::time::Alarm<'a>> hil::block_storage::BlockStorage<'a> for SDCard<'a, A> {
+    fn set_client(&self, client: &'a dyn hil::block_storage::BlockStorageClient) {
+        self.block_client.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn block_count(&self) -> u64 {
+        if self.is_installed() && self.is_initialized() {
+            self.block_count.get()
         } else {
-            // sd card not installed
-            Err(ErrorCode::UNINSTALLED)
+            0
         }
     }
+
+    fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let (sector, count) = match self.check_block_request(buffer, block, count) {
+            Ok(request) => request,
+            Err(e) => return Err((e, buffer)),
+        };
+        self.block_op.set(BlockOp::Read);
+        self.start_read(buffer, sector, count)
+            .map_err(|(e, buffer)| {
+                self.block_op.clear();
+                (map_busy(e), buffer)
+            })
+    }
+
+    fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let (sector, count) = match self.check_block_request(buffer, block, count) {
+            Ok(request) => request,
+            Err(e) => return Err((e, buffer)),
+        };
+        self.block_op.set(BlockOp::Write);
+        self.start_write(buffer, sector, count)
+            .map_err(|(e, buffer)| {
+                self.block_op.clear();
+                (map_busy(e), buffer)
+            })
+    }
+}
+
+/// The SPI buffers are missing while another operation is in progress, which
+/// the block storage interface reports as `BUSY`.
+fn map_busy(error: ErrorCode) -> ErrorCode {
+    match error {
+        ErrorCode::NOMEM => ErrorCode::BUSY,
+        e => e,
+    }
 }
 
 /// Handle callbacks from the SPI peripheral
@@ -1446,9 +1557,7 @@ impl<'a, A: hil::time::Alarm<'a>> hil::gpio::Client for SDCard<'a, A> {
             //  send an error callback
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
-            self.client.map(move |client| {
-                client.error(SdCardError::CardStateChanged as u32);
-            });
+            self.report_error(SdCardError::CardStateChanged);
         }
 
         // either the card is new or gone, in either case it isn't initialized
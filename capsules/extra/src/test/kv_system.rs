@@ -34,6 +34,7 @@ use crate::tickv::{KVSystem, KVSystemClient, KeyType};
 use core::cell::Cell;
 use core::marker::PhantomData;
 use kernel::debug;
+use kernel::hil::kv::KVStats;
 use kernel::utilities::cells::{MapCell, TakeCell};
 use kernel::utilities::leasable_buffer::SubSliceMut;
 use kernel::ErrorCode;
@@ -168,4 +169,18 @@ impl<S: KVSystem<'static, K = T>, T: KeyType + core::fmt::Debug> KVSystemClient<
             }
         }
     }
+
+    fn get_next_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _cursor: usize,
+        _key: &'static mut T,
+        _value: SubSliceMut<'static, u8>,
+    ) {
+        unreachable!()
+    }
+
+    fn get_stats_complete(&self, _result: Result<KVStats, ErrorCode>) {
+        unreachable!()
+    }
 }
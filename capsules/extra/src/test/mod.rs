@@ -9,5 +9,7 @@ pub mod crc;
 pub mod hmac_sha256;
 pub mod kv_system;
 pub mod sha256;
+#[cfg(test)]
+pub mod sim_flash;
 pub mod siphash24;
 pub mod udp;
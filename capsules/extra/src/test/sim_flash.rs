@@ -0,0 +1,565 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2023.
+
+//! A simulated NOR flash for testing storage layers against power loss.
+//!
+//! `SimFlash` keeps its contents in RAM and implements both
+//! `kernel::hil::flash::Flash` and `tickv::FlashController`, so the same
+//! simulated chip can be used below the log, TicKV and anything else that
+//! stores data in flash.
+//!
+//! It behaves like NOR flash: erasing sets every byte of a page to 0xFF and
+//! programming can only clear bits, so programming a byte stores the old
+//! value ANDed with the new one. Programming that would have to set a bit
+//! is counted by `violations()`. `hil::flash::Flash::write_page()` erases
+//! the page before programming it, like the chip drivers do.
+//!
+//! Every byte that is erased or programmed costs one step. After
+//! `lose_power_after(steps)` the power is lost once that many steps have
+//! been made: the operation in progress stops part way through, leaving
+//! the bytes before the crash point changed and the ones after it as they
+//! were, and every later operation fails. `power_cycle()` turns the power
+//! back on with the contents left at the time of the crash.
+//!
+//! Tests find every crash point of a deterministic scenario by running it
+//! once to count the steps it makes, and then replaying it once for each
+//! step with the power lost at that step:
+//!
+//! ```rust,ignore
+//! let steps = {
+//!     let flash = SimFlash::<256, 8>::new();
+//!     scenario(&flash);
+//!     flash.steps()
+//! };
+//! for crash_point in 0..steps {
+//!     let flash = SimFlash::<256, 8>::new();
+//!     flash.lose_power_after(crash_point);
+//!     scenario(&flash);
+//!     flash.power_cycle();
+//!     check_recovery(&flash);
+//! }
+//! ```
+//!
+//! Operations through `hil::flash::Flash` complete when `service()` is
+//! called, which stands in for the flash controller interrupt. Operations
+//! through `tickv::FlashController` complete synchronously.
+
+use core::cell::Cell;
+
+use kernel::hil::flash::{self, Flash};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// A page of a `SimFlash`.
+pub struct SimPage<const PAGE: usize>(pub [u8; PAGE]);
+
+impl<const PAGE: usize> Default for SimPage<PAGE> {
+    fn default() -> Self {
+        Self([0; PAGE])
+    }
+}
+
+impl<const PAGE: usize> AsMut<[u8]> for SimPage<PAGE> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// An operation started through `hil::flash::Flash`.
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+/// A simulated flash of `PAGES` pages of `PAGE` bytes.
+pub struct SimFlash<const PAGE: usize, const PAGES: usize> {
+    pages: MapCell<[[u8; PAGE]; PAGES]>,
+    /// The page number of the first page, for `hil::flash::Flash`.
+    first_page: Cell<usize>,
+    /// The number of bytes erased or programmed so far.
+    steps: Cell<usize>,
+    /// The number of steps left before the power is lost.
+    steps_left: Cell<Option<usize>>,
+    powered: Cell<bool>,
+    /// The number of bytes that were programmed with bits that were not
+    /// set in the flash.
+    violations: Cell<usize>,
+    operation: OptionalCell<Operation>,
+    buffer: TakeCell<'static, SimPage<PAGE>>,
+    client: OptionalCell<&'static dyn flash::Client<Self>>,
+}
+
+impl<const PAGE: usize, const PAGES: usize> SimFlash<PAGE, PAGES> {
+    /// Create an erased flash.
+    pub fn new() -> Self {
+        Self::from_pages([[0xFF; PAGE]; PAGES])
+    }
+
+    /// Create a flash with the given contents.
+    pub fn from_pages(pages: [[u8; PAGE]; PAGES]) -> Self {
+        Self {
+            pages: MapCell::new(pages),
+            first_page: Cell::new(0),
+            steps: Cell::new(0),
+            steps_left: Cell::new(None),
+            powered: Cell::new(true),
+            violations: Cell::new(0),
+            operation: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the page number `hil::flash::Flash` uses for the first page.
+    ///
+    /// Users of memory mapped flash, like the log, pass page numbers
+    /// derived from the address of the flash in memory.
+    pub fn set_first_page(&self, first_page: usize) {
+        self.first_page.set(first_page);
+    }
+
+    /// Lose the power once `steps` more bytes have been erased or
+    /// programmed.
+    pub fn lose_power_after(&self, steps: usize) {
+        self.steps_left.set(Some(steps));
+    }
+
+    /// Restore the power. An operation that was in progress when the power
+    /// was lost never completes.
+    pub fn power_cycle(&self) {
+        self.steps_left.set(None);
+        self.powered.set(true);
+        self.operation.clear();
+    }
+
+    /// Returns false once the power has been lost, until `power_cycle()`.
+    pub fn is_powered(&self) -> bool {
+        self.powered.get()
+    }
+
+    /// The number of bytes erased or programmed since the flash was
+    /// created.
+    pub fn steps(&self) -> usize {
+        self.steps.get()
+    }
+
+    /// The number of bytes programmed without erasing bits that had to be
+    /// set.
+    pub fn violations(&self) -> usize {
+        self.violations.get()
+    }
+
+    /// A copy of the contents of the flash.
+    pub fn pages(&self) -> [[u8; PAGE]; PAGES] {
+        self.pages.map(|pages| *pages).unwrap()
+    }
+
+    /// Make one step, returns false if the power is lost.
+    fn step(&self) -> bool {
+        if !self.powered.get() {
+            return false;
+        }
+        match self.steps_left.get() {
+            Some(0) => {
+                self.powered.set(false);
+                return false;
+            }
+            Some(steps) => self.steps_left.set(Some(steps - 1)),
+            None => {}
+        }
+        self.steps.set(self.steps.get() + 1);
+        true
+    }
+
+    /// Program `data` at `address`, one byte per step. Returns false if the
+    /// power was lost before all of it was programmed.
+    fn program(&self, address: usize, data: &[u8]) -> bool {
+        self.pages
+            .map(|pages| {
+                for (i, byte) in data.iter().enumerate() {
+                    if !self.step() {
+                        return false;
+                    }
+                    let old = &mut pages[(address + i) / PAGE][(address + i) % PAGE];
+                    if *byte & !*old != 0 {
+                        self.violations.set(self.violations.get() + 1);
+                    }
+                    *old &= *byte;
+                }
+                true
+            })
+            .unwrap()
+    }
+
+    /// Erase `page`, one byte per step. Returns false if the power was lost
+    /// before all of it was erased.
+    fn erase(&self, page: usize) -> bool {
+        self.pages
+            .map(|pages| {
+                for byte in pages[page].iter_mut() {
+                    if !self.step() {
+                        return false;
+                    }
+                    *byte = 0xFF;
+                }
+                true
+            })
+            .unwrap()
+    }
+
+    /// Map a `hil::flash::Flash` page number to a page of the flash.
+    fn page_index(&self, page_number: usize) -> Result<usize, ErrorCode> {
+        page_number
+            .checked_sub(self.first_page.get())
+            .filter(|page| *page < PAGES)
+            .ok_or(ErrorCode::INVAL)
+    }
+
+    /// Check that a new `hil::flash::Flash` operation can be started.
+    fn start(&self, page_number: usize) -> Result<usize, ErrorCode> {
+        if !self.powered.get() {
+            Err(ErrorCode::OFF)
+        } else if self.operation.is_some() {
+            Err(ErrorCode::BUSY)
+        } else {
+            self.page_index(page_number)
+        }
+    }
+
+    /// Complete the `hil::flash::Flash` operation in progress and call the
+    /// client. Returns false if there was no operation, or the power was
+    /// lost before it completed.
+    pub fn service(&self) -> bool {
+        let operation = match self.operation.take() {
+            Some(operation) => operation,
+            None => return false,
+        };
+        match operation {
+            Operation::Read(page) => {
+                let buffer = self.buffer.take().unwrap();
+                buffer.0 = self.pages.map(|pages| pages[page]).unwrap();
+                self.client
+                    .map(|client| client.read_complete(buffer, Ok(())));
+            }
+            Operation::Write(page) => {
+                let buffer = self.buffer.take().unwrap();
+                if !self.erase(page) || !self.program(page * PAGE, &buffer.0) {
+                    self.buffer.replace(buffer);
+                    return false;
+                }
+                self.client
+                    .map(|client| client.write_complete(buffer, Ok(())));
+            }
+            Operation::Erase(page) => {
+                if !self.erase(page) {
+                    return false;
+                }
+                self.client.map(|client| client.erase_complete(Ok(())));
+            }
+        }
+        true
+    }
+}
+
+impl<const PAGE: usize, const PAGES: usize> Default for SimFlash<PAGE, PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: flash::Client<Self>, const PAGE: usize, const PAGES: usize> flash::HasClient<'static, C>
+    for SimFlash<PAGE, PAGES>
+{
+    fn set_client(&self, client: &'static C) {
+        self.client.set(client);
+    }
+}
+
+impl<const PAGE: usize, const PAGES: usize> Flash for SimFlash<PAGE, PAGES> {
+    type Page = SimPage<PAGE>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(page_number) {
+            Ok(page) => {
+                self.buffer.replace(buf);
+                self.operation.set(Operation::Read(page));
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ErrorCode, &'static mut Self::Page)> {
+        match self.start(page_number) {
+            Ok(page) => {
+                self.buffer.replace(buf);
+                self.operation.set(Operation::Write(page));
+                Ok(())
+            }
+            Err(e) => Err((e, buf)),
+        }
+    }
+
+    fn erase_page(&self, page_number: usize) -> Result<(), ErrorCode> {
+        let page = self.start(page_number)?;
+        self.operation.set(Operation::Erase(page));
+        Ok(())
+    }
+}
+
+impl<const PAGE: usize, const PAGES: usize> tickv::FlashController<PAGE> for SimFlash<PAGE, PAGES> {
+    fn read_region(
+        &self,
+        region_number: usize,
+        buf: &mut [u8; PAGE],
+    ) -> Result<(), tickv::ErrorCode> {
+        if !self.powered.get() || region_number >= PAGES {
+            return Err(tickv::ErrorCode::ReadFail);
+        }
+        *buf = self.pages.map(|pages| pages[region_number]).unwrap();
+        Ok(())
+    }
+
+    fn write(&self, address: usize, buf: &[u8]) -> Result<(), tickv::ErrorCode> {
+        if address + buf.len() > PAGE * PAGES || !self.program(address, buf) {
+            return Err(tickv::ErrorCode::WriteFail);
+        }
+        Ok(())
+    }
+
+    fn erase_region(&self, region_number: usize) -> Result<(), tickv::ErrorCode> {
+        if region_number >= PAGES || !self.erase(region_number) {
+            return Err(tickv::ErrorCode::EraseFail);
+        }
+        Ok(())
+    }
+}
+
+impl<const PAGE: usize, const PAGES: usize> tickv::FlashController<PAGE>
+    for &SimFlash<PAGE, PAGES>
+{
+    fn read_region(
+        &self,
+        region_number: usize,
+        buf: &mut [u8; PAGE],
+    ) -> Result<(), tickv::ErrorCode> {
+        (*self).read_region(region_number, buf)
+    }
+
+    fn write(&self, address: usize, buf: &[u8]) -> Result<(), tickv::ErrorCode> {
+        (*self).write(address, buf)
+    }
+
+    fn erase_region(&self, region_number: usize) -> Result<(), tickv::ErrorCode> {
+        (*self).erase_region(region_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SimFlash;
+    use tickv::{ErrorCode, TicKV};
+
+    const REGION: usize = 256;
+    const REGIONS: usize = 8;
+
+    type Flash = SimFlash<REGION, REGIONS>;
+
+    const MAIN_KEY: u64 = 0x5a17_e2c4_9b3d_0f86;
+    const ONE: u64 = 0x0123_4567_89ab_cd03;
+    const TWO: u64 = 0x8c9f_1e2d_3b4a_5905;
+    const THREE: u64 = 0xfedc_ba98_7654_3213;
+    const FOUR: u64 = 0x2468_ace0_1357_9b07;
+
+    #[derive(Clone, Copy)]
+    enum Op {
+        /// Append a key with the value `value(seed, len)`.
+        Append(u64, u8, usize),
+        Invalidate(u64),
+        Zeroise(u64),
+        GarbageCollect,
+    }
+
+    /// Exercises small and chained values, removing keys and garbage
+    /// collection.
+    const SCENARIO: [Op; 10] = [
+        Op::Append(ONE, 1, 20),
+        Op::Append(TWO, 2, 400),
+        Op::Append(THREE, 3, 100),
+        Op::Invalidate(ONE),
+        Op::Append(ONE, 4, 30),
+        Op::Zeroise(THREE),
+        Op::GarbageCollect,
+        Op::Invalidate(TWO),
+        Op::Append(TWO, 5, 300),
+        Op::GarbageCollect,
+    ];
+
+    const KEYS: [u64; 3] = [ONE, TWO, THREE];
+
+    fn value(seed: u8, len: usize) -> [u8; 600] {
+        let mut value = [0; 600];
+        for (i, b) in value[..len].iter_mut().enumerate() {
+            *b = (i as u8).wrapping_mul(31).wrapping_add(seed);
+        }
+        value
+    }
+
+    /// The value of `key` after the first `ops` operations of the scenario.
+    fn expected(key: u64, ops: usize) -> Option<(u8, usize)> {
+        SCENARIO[..ops].iter().fold(None, |state, op| match *op {
+            Op::Append(k, seed, len) if k == key => Some((seed, len)),
+            Op::Invalidate(k) | Op::Zeroise(k) if k == key => None,
+            _ => state,
+        })
+    }
+
+    /// Run the scenario on a new store, returns the number of operations
+    /// that completed before the power was lost, or `None` if it was lost
+    /// while initialising the store.
+    fn run(flash: &Flash) -> Option<usize> {
+        let mut read_buf = [0; REGION];
+        let tickv = TicKV::<&Flash, REGION>::new(flash, &mut read_buf, REGION * REGIONS);
+        if tickv.initialise(MAIN_KEY).is_err() {
+            assert!(!flash.is_powered());
+            return None;
+        }
+
+        for (i, op) in SCENARIO.iter().enumerate() {
+            let ret = match *op {
+                Op::Append(key, seed, len) => tickv.append_key(key, &value(seed, len)[..len]),
+                Op::Invalidate(key) => tickv.invalidate_key(key),
+                Op::Zeroise(key) => tickv.zeroise_key(key),
+                Op::GarbageCollect => tickv
+                    .garbage_collect()
+                    .map(|_| tickv::success_codes::SuccessCode::Complete),
+            };
+            if ret.is_err() {
+                assert!(!flash.is_powered(), "operation {} failed: {:?}", i, ret);
+                return Some(i);
+            }
+        }
+        Some(SCENARIO.len())
+    }
+
+    /// Check that `key` has the value from one of `states`.
+    fn check_key(tickv: &TicKV<&Flash, REGION>, key: u64, states: &[Option<(u8, usize)>]) {
+        let mut buf = [0; 600];
+        let found = match tickv.get_key(key, &mut buf) {
+            Ok((_, len)) => Some(len),
+            Err(ErrorCode::KeyNotFound) => None,
+            Err(e) => panic!("reading key {:#x} failed: {:?}", key, e),
+        };
+        assert!(
+            states.iter().any(|state| match (state, found) {
+                (Some((seed, len)), Some(found)) =>
+                    *len == found && buf[..found] == value(*seed, *len)[..found],
+                (None, None) => true,
+                _ => false,
+            }),
+            "key {:#x} has an unexpected value, length {:?}",
+            key,
+            found
+        );
+    }
+
+    /// Check that the store can be opened after the power was lost, that
+    /// every operation that completed is kept and that the interrupted
+    /// operation either completed or left no trace. Then check that the
+    /// store can still be used.
+    fn check_recovery(flash: &Flash, completed: Option<usize>) {
+        let mut read_buf = [0; REGION];
+        let tickv = TicKV::<&Flash, REGION>::new(flash, &mut read_buf, REGION * REGIONS);
+        tickv.initialise(MAIN_KEY).unwrap();
+
+        let ops = completed.unwrap_or(0);
+        for key in KEYS {
+            let states = [
+                expected(key, ops),
+                expected(key, (ops + 1).min(SCENARIO.len())),
+            ];
+            check_key(&tickv, key, &states);
+        }
+
+        // Interrupted appends can leave fragments of chained values behind,
+        // writing the keys again must replace them.
+        for key in KEYS {
+            let _ = tickv.invalidate_key(key);
+        }
+        tickv.garbage_collect().unwrap();
+        for key in KEYS {
+            tickv.append_key(key, &value(9, 250)[..250]).unwrap();
+        }
+        tickv.append_key(FOUR, &value(10, 40)[..40]).unwrap();
+        tickv.garbage_collect().unwrap();
+        for key in KEYS {
+            check_key(&tickv, key, &[Some((9, 250))]);
+        }
+        check_key(&tickv, FOUR, &[Some((10, 40))]);
+    }
+
+    #[test]
+    fn test_nor_semantics() {
+        let flash = Flash::new();
+        tickv::FlashController::write(&flash, 10, &[0xF0, 0x0F]).unwrap();
+        tickv::FlashController::write(&flash, 10, &[0x3C, 0x0F]).unwrap();
+        assert_eq!(flash.pages()[0][10..12], [0x30, 0x0F]);
+        assert_eq!(flash.violations(), 1);
+
+        tickv::FlashController::erase_region(&flash, 0).unwrap();
+        assert_eq!(flash.pages()[0][10..12], [0xFF, 0xFF]);
+        assert_eq!(flash.steps(), 4 + REGION);
+    }
+
+    #[test]
+    fn test_lose_power() {
+        let flash = Flash::new();
+        flash.lose_power_after(3);
+        assert_eq!(
+            tickv::FlashController::write(&flash, 0, &[0; 5]),
+            Err(ErrorCode::WriteFail)
+        );
+        assert!(!flash.is_powered());
+        assert_eq!(flash.pages()[0][..6], [0, 0, 0, 0xFF, 0xFF, 0xFF]);
+
+        // Nothing changes until the power is restored
+        assert_eq!(
+            tickv::FlashController::erase_region(&flash, 0),
+            Err(ErrorCode::EraseFail)
+        );
+        flash.power_cycle();
+        tickv::FlashController::erase_region(&flash, 0).unwrap();
+        assert_eq!(flash.pages()[0][..6], [0xFF; 6]);
+    }
+
+    #[test]
+    fn test_tickv_recovers_after_every_crash_point() {
+        let steps = {
+            let flash = Flash::new();
+            assert_eq!(run(&flash), Some(SCENARIO.len()));
+            flash.steps()
+        };
+
+        for crash_point in 0..steps {
+            let flash = Flash::new();
+            flash.lose_power_after(crash_point);
+            let completed = run(&flash);
+            assert!(!flash.is_powered());
+
+            flash.power_cycle();
+            check_recovery(&flash, completed);
+            assert_eq!(flash.violations(), 0, "crash point {}", crash_point);
+        }
+    }
+}
@@ -36,6 +36,7 @@
 use core::cell::Cell;
 use kernel::hil::flash::{self, Flash};
 use kernel::hil::hasher::{self, Hasher};
+use kernel::hil::kv::KVStats;
 use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::utilities::leasable_buffer::{SubSlice, SubSliceMut};
 use kernel::ErrorCode;
@@ -95,6 +96,25 @@ pub trait KVSystemClient<K: KeyType> {
     ///
     /// - `result`: Nothing on success, 'ErrorCode' on error
     fn garbage_collect_complete(&self, result: Result<(), ErrorCode>);
+
+    /// This callback is called when the get_next_key operation completes.
+    ///
+    /// - `result`: Nothing on success, 'ErrorCode' on error
+    /// - `cursor`: The cursor to continue the enumeration from
+    /// - `key`: The key buffer, holding the found key
+    /// - `value`: The value buffer
+    fn get_next_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        cursor: usize,
+        key: &'static mut K,
+        value: SubSliceMut<'static, u8>,
+    );
+
+    /// This callback is called when the get_stats operation completes.
+    ///
+    /// - `result`: The storage usage on success, 'ErrorCode' on error
+    fn get_stats_complete(&self, result: Result<KVStats, ErrorCode>);
 }
 
 pub trait KVSystem<'a> {
@@ -193,6 +213,39 @@ pub trait KVSystem<'a> {
     /// - `INVAL`: An invalid parameter was passed.
     /// - `NODEVICE`: No KV store was setup.
     fn garbage_collect(&self) -> Result<(), ErrorCode>;
+
+    /// Finds the next valid key in the KV Store.
+    ///
+    /// Keys are enumerated by starting with a `cursor` of 0 and passing the
+    /// cursor returned in each callback until `NOSUPPORT` is returned.
+    ///
+    /// - `cursor`: Where to continue the enumeration.
+    /// - `key`: A buffer to store the found hashed key to.
+    /// - `value`: A buffer to store the value of the found key to.
+    ///
+    /// On success nothing will be returned.
+    /// On error the key, value and a `Result<(), ErrorCode>` will be returned.
+    ///
+    /// The possible `Result<(), ErrorCode>`s are:
+    /// - `BUSY`: An operation is already in progress
+    /// - `SIZE`: The value buffer has been sliced.
+    /// - `NODEVICE`: No KV store was setup
+    fn get_next_key(
+        &self,
+        cursor: usize,
+        key: &'static mut Self::K,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<(), (&'static mut Self::K, SubSliceMut<'static, u8>, ErrorCode)>;
+
+    /// Collects the storage usage of the KV Store.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `Result<(), ErrorCode>` will be returned.
+    ///
+    /// The possible `ErrorCode`s are:
+    /// - `BUSY`: An operation is already in progress.
+    /// - `NODEVICE`: No KV store was setup.
+    fn get_stats(&self) -> Result<(), ErrorCode>;
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -203,6 +256,21 @@ enum Operation {
     AppendKey,
     InvalidateKey,
     GarbageCollect,
+    GetNextKey,
+    GetStats,
+}
+
+/// Convert the statistics of a TicKV store to the statistics reported to
+/// users of the store.
+pub(crate) fn kv_stats(stats: tickv::StorageStats) -> KVStats {
+    KVStats {
+        // Don't count the main key, which isn't visible to users of the
+        // store.
+        keys: stats.keys.saturating_sub(1),
+        used: stats.used,
+        reclaimable: stats.reclaimable,
+        free: stats.free,
+    }
 }
 
 /// Wrapper object that provides the flash interface TicKV expects using the
@@ -276,6 +344,11 @@ impl<F: Flash, const PAGE_SIZE: usize> tickv::flash_controller::FlashController<
     }
 
     fn erase_region(&self, region_number: usize) -> Result<(), tickv::error_codes::ErrorCode> {
+        // Writes modify the last page that was read, so make that match the
+        // erased page for writes that follow the erase, like the region
+        // header.
+        self.flash_read_buffer.map(|buf| buf.as_mut().fill(0xFF));
+
         let _ = self.flash.erase_page(self.region_offset + region_number);
 
         Err(tickv::error_codes::ErrorCode::EraseNotReady(region_number))
@@ -284,6 +357,10 @@ impl<F: Flash, const PAGE_SIZE: usize> tickv::flash_controller::FlashController<
 
 pub type TicKVKeyType = [u8; 8];
 
+/// The hash of `tickv::MAIN_KEY`, which TicKV stores to mark the flash as
+/// initialised.
+pub(crate) const MAIN_KEY_HASH: u64 = 0x7bc9f7ff4f76f244;
+
 /// `TicKVSystem` implements `KVSystem` using the TicKV library.
 pub struct TicKVSystem<'a, F: Flash + 'static, H: Hasher<'a, 8>, const PAGE_SIZE: usize> {
     /// Underlying asynchronous TicKV implementation.
@@ -302,6 +379,9 @@ pub struct TicKVSystem<'a, F: Flash + 'static, H: Hasher<'a, 8>, const PAGE_SIZE
     /// Holder for a buffer containing a value being read from or written to the
     /// key-value store.
     value_buffer: MapCell<SubSliceMut<'static, u8>>,
+    /// The cursor of a key enumeration that is waiting for initialization to
+    /// complete.
+    cursor: Cell<usize>,
     /// Callback client when the `KVSystem` operation completes.
     client: OptionalCell<&'a dyn KVSystemClient<TicKVKeyType>>,
 }
@@ -329,12 +409,13 @@ impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> TicKVSystem<'a, F,
             unhashed_key_buffer: MapCell::empty(),
             key_buffer: TakeCell::empty(),
             value_buffer: MapCell::empty(),
+            cursor: Cell::new(0),
             client: OptionalCell::empty(),
         }
     }
 
     pub fn initialise(&self) {
-        let _ret = self.tickv.initialise(0x7bc9f7ff4f76f244);
+        let _ret = self.tickv.initialise(MAIN_KEY_HASH);
         self.operation.set(Operation::Init);
     }
 
@@ -386,40 +467,36 @@ impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> TicKVSystem<'a, F,
                 }
                 _ => {}
             },
+            Operation::GetNextKey => {
+                match self.get_next_key(
+                    self.cursor.get(),
+                    self.key_buffer.take().unwrap(),
+                    self.value_buffer.take().unwrap(),
+                ) {
+                    Err((key, value, error)) => {
+                        let cursor = self.cursor.get();
+                        self.client.map(move |cb| {
+                            cb.get_next_key_complete(Err(error), cursor, key, value);
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Operation::GetStats => match self.get_stats() {
+                Err(error) => {
+                    self.client.map(move |cb| {
+                        cb.get_stats_complete(Err(error));
+                    });
+                }
+                _ => {}
+            },
         }
         self.next_operation.set(Operation::None);
     }
-}
 
-impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> hasher::Client<8>
-    for TicKVSystem<'a, F, H, PAGE_SIZE>
-{
-    fn add_mut_data_done(&self, _result: Result<(), ErrorCode>, data: SubSliceMut<'static, u8>) {
-        self.unhashed_key_buffer.replace(data);
-        self.hasher.run(self.key_buffer.take().unwrap()).unwrap();
-    }
-
-    fn add_data_done(&self, _result: Result<(), ErrorCode>, _data: SubSlice<'static, u8>) {}
-
-    fn hash_done(&self, _result: Result<(), ErrorCode>, digest: &'static mut [u8; 8]) {
-        self.client.map(move |cb| {
-            cb.generate_key_complete(Ok(()), self.unhashed_key_buffer.take().unwrap(), digest);
-        });
-
-        self.hasher.clear_data();
-    }
-}
-
-impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> flash::Client<F>
-    for TicKVSystem<'a, F, H, PAGE_SIZE>
-{
-    fn read_complete(&self, pagebuffer: &'static mut F::Page, _result: Result<(), flash::Error>) {
-        self.tickv.set_read_buffer(pagebuffer.as_mut());
-        self.tickv
-            .tickv
-            .controller
-            .flash_read_buffer
-            .replace(pagebuffer);
+    /// Continues the TicKV operation after a flash operation has completed
+    /// and handles the result.
+    fn continue_operation(&self) {
         let (ret, tickv_buf, tickv_buf_len) = self.tickv.continue_operation();
 
         // If we got the buffer back from TicKV then store it.
@@ -528,8 +605,15 @@ impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> flash::Client<F>
                 }
             }
             Operation::InvalidateKey => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
+                Ok(tickv::success_codes::SuccessCode::Complete) => {
+                    // The remaining fragments of a chained value had already
+                    // been removed, there is nothing left to write.
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.invalidate_key_complete(Ok(()), self.key_buffer.take().unwrap());
+                    });
+                }
+                Ok(tickv::success_codes::SuccessCode::Written) => {
                     // Need to wait for flash write to complete.
                     self.operation.set(Operation::None);
                 }
@@ -564,10 +648,125 @@ impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> flash::Client<F>
                 }
                 _ => {}
             },
+            Operation::GetNextKey => self.next_key_step(ret),
+            Operation::GetStats => match ret {
+                Ok(tickv::success_codes::SuccessCode::Complete) => {
+                    self.operation.set(Operation::None);
+                    let stats = self.tickv.stats().map(kv_stats);
+                    self.client.map(|cb| {
+                        cb.get_stats_complete(stats.ok_or(ErrorCode::FAIL));
+                    });
+                }
+                Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => {}
+                Err(_) => {
+                    self.operation.set(Operation::None);
+                    self.client.map(|cb| {
+                        cb.get_stats_complete(Err(ErrorCode::FAIL));
+                    });
+                }
+                Ok(_) => {}
+            },
             _ => unreachable!(),
         }
     }
 
+    /// Handles the result of a step of a key enumeration.
+    fn next_key_step(
+        &self,
+        ret: Result<tickv::success_codes::SuccessCode, tickv::error_codes::ErrorCode>,
+    ) {
+        match ret {
+            Ok(tickv::success_codes::SuccessCode::Complete) => {
+                let Some(entry) = self.tickv.key_entry() else {
+                    return;
+                };
+
+                if entry.hashed_key == MAIN_KEY_HASH {
+                    // The main key is internal to TicKV, skip over it.
+                    self.cursor.set(entry.next);
+                    let value = self.value_buffer.take().unwrap();
+                    if let Err((buf, _e)) = self.tickv.get_next_key(entry.next, value.take()) {
+                        self.operation.set(Operation::None);
+                        self.client.map(|cb| {
+                            cb.get_next_key_complete(
+                                Err(ErrorCode::FAIL),
+                                entry.next,
+                                self.key_buffer.take().unwrap(),
+                                SubSliceMut::new(buf),
+                            );
+                        });
+                    }
+                    return;
+                }
+
+                self.operation.set(Operation::None);
+                let key = self.key_buffer.take().unwrap();
+                *key = entry.hashed_key.to_be_bytes();
+                let value = self.value_buffer.take().unwrap();
+                let result = if value.len() < entry.value_length {
+                    Err(ErrorCode::SIZE)
+                } else {
+                    Ok(())
+                };
+                self.client.map(move |cb| {
+                    cb.get_next_key_complete(result, entry.next, key, value);
+                });
+            }
+            Err(tickv::error_codes::ErrorCode::ReadNotReady(_)) => {
+                // Need to do another flash read.
+            }
+            Err(e) => {
+                let tock_hil_error = match e {
+                    tickv::error_codes::ErrorCode::KeyNotFound => ErrorCode::NOSUPPORT,
+                    _ => ErrorCode::FAIL,
+                };
+                self.operation.set(Operation::None);
+                self.client.map(|cb| {
+                    cb.get_next_key_complete(
+                        Err(tock_hil_error),
+                        self.cursor.get(),
+                        self.key_buffer.take().unwrap(),
+                        self.value_buffer.take().unwrap(),
+                    );
+                });
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> hasher::Client<8>
+    for TicKVSystem<'a, F, H, PAGE_SIZE>
+{
+    fn add_mut_data_done(&self, _result: Result<(), ErrorCode>, data: SubSliceMut<'static, u8>) {
+        self.unhashed_key_buffer.replace(data);
+        self.hasher.run(self.key_buffer.take().unwrap()).unwrap();
+    }
+
+    fn add_data_done(&self, _result: Result<(), ErrorCode>, _data: SubSlice<'static, u8>) {}
+
+    fn hash_done(&self, _result: Result<(), ErrorCode>, digest: &'static mut [u8; 8]) {
+        self.client.map(move |cb| {
+            cb.generate_key_complete(Ok(()), self.unhashed_key_buffer.take().unwrap(), digest);
+        });
+
+        self.hasher.clear_data();
+    }
+}
+
+impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> flash::Client<F>
+    for TicKVSystem<'a, F, H, PAGE_SIZE>
+{
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, _result: Result<(), flash::Error>) {
+        self.tickv.set_read_buffer(pagebuffer.as_mut());
+        self.tickv
+            .tickv
+            .controller
+            .flash_read_buffer
+            .replace(pagebuffer);
+        self.continue_operation();
+    }
+
     fn write_complete(&self, pagebuffer: &'static mut F::Page, _result: Result<(), flash::Error>) {
         self.tickv
             .tickv
@@ -575,6 +774,13 @@ impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> flash::Client<F>
             .flash_read_buffer
             .replace(pagebuffer);
 
+        if self.tickv.is_busy() {
+            // TicKV writes more than one object for this operation, such as
+            // the fragments of a chained value, so it isn't done yet.
+            self.continue_operation();
+            return;
+        }
+
         match self.operation.get() {
             Operation::Init => {
                 self.complete_init();
@@ -600,39 +806,7 @@ impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> flash::Client<F>
     }
 
     fn erase_complete(&self, _result: Result<(), flash::Error>) {
-        let (ret, tickv_buf, tickv_buf_len) = self.tickv.continue_operation();
-
-        // If we got the buffer back from TicKV then store it.
-        tickv_buf.map(|buf| {
-            let mut val_buf = SubSliceMut::new(buf);
-            if tickv_buf_len > 0 {
-                // Length of zero means nothing was inserted into the buffer so
-                // no need to slice it.
-                val_buf.slice(0..tickv_buf_len);
-            }
-            self.value_buffer.replace(val_buf);
-        });
-
-        match self.operation.get() {
-            Operation::Init => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
-                    self.complete_init();
-                }
-                _ => {}
-            },
-            Operation::GarbageCollect => match ret {
-                Ok(tickv::success_codes::SuccessCode::Complete)
-                | Ok(tickv::success_codes::SuccessCode::Written) => {
-                    self.operation.set(Operation::None);
-                    self.client.map(|cb| {
-                        cb.garbage_collect_complete(Ok(()));
-                    });
-                }
-                _ => {}
-            },
-            _ => unreachable!(),
-        }
+        self.continue_operation();
     }
 }
 
@@ -787,4 +961,67 @@ impl<'a, F: Flash, H: Hasher<'a, 8>, const PAGE_SIZE: usize> KVSystem<'a>
             }
         }
     }
+
+    fn get_next_key(
+        &self,
+        cursor: usize,
+        key: &'static mut Self::K,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<(), (&'static mut Self::K, SubSliceMut<'static, u8>, ErrorCode)> {
+        if value.is_sliced() {
+            return Err((key, value, ErrorCode::SIZE));
+        }
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::GetNextKey);
+                self.cursor.set(cursor);
+
+                match self.tickv.get_next_key(cursor, value.take()) {
+                    Ok(_ret) => {
+                        self.key_buffer.replace(key);
+                        Ok(())
+                    }
+                    Err((buf, _e)) => {
+                        self.operation.set(Operation::None);
+                        Err((key, SubSliceMut::new(buf), ErrorCode::FAIL))
+                    }
+                }
+            }
+            Operation::Init => {
+                // The init process is still occurring.
+                // We can save this request and start it after init
+                self.next_operation.set(Operation::GetNextKey);
+                self.cursor.set(cursor);
+                self.key_buffer.replace(key);
+                self.value_buffer.replace(value);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err((key, value, ErrorCode::BUSY))
+            }
+        }
+    }
+
+    fn get_stats(&self) -> Result<(), ErrorCode> {
+        match self.operation.get() {
+            Operation::None => {
+                self.operation.set(Operation::GetStats);
+                self.tickv.get_stats().and(Ok(())).map_err(|_e| {
+                    self.operation.set(Operation::None);
+                    ErrorCode::FAIL
+                })
+            }
+            Operation::Init => {
+                // The init process is still occurring.
+                // We can save this request and start it after init.
+                self.next_operation.set(Operation::GetStats);
+                Ok(())
+            }
+            _ => {
+                // An operation is already in process.
+                Err(ErrorCode::BUSY)
+            }
+        }
+    }
 }
@@ -0,0 +1,746 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! TicKV App Namespace Userspace Driver.
+//!
+//! Provides userspace access to TicKV stores that each belong to a single
+//! app. The board sets up a TicKV store on a separate flash region for every
+//! namespace and assigns each namespace to the write ID of an app's
+//! `StoragePermissions`. As the namespaces do not share flash, one app
+//! filling its store or causing garbage collection does not affect the
+//! stores of other apps.
+//!
+//! Unlike the KV driver, values are stored as is, without a permissions
+//! header, and keys are the raw 8 byte TicKV keys. Apps that want to use
+//! longer keys hash them first.
+//!
+//! Each namespace has a quota of keys and bytes. Appending a value fails with
+//! `NOMEM` if the store would then hold more keys than allowed, or use more
+//! space than allowed. The space counts the flash used by the stored
+//! objects, including the TicKV object headers, as reported by the storage
+//! statistics.
+//!
+//! ```rust,ignore
+//! +===============+
+//! ||  Userspace  ||
+//! +===============+
+//!
+//! -----Syscall Interface-----
+//!
+//! +---------------------------------+
+//! |  TicKV App Driver (this file)   |
+//! +---------------------------------+
+//!
+//!    capsules::tickv::KVSystem
+//!
+//! +-----------+   +-----------+
+//! |  TicKV    |   |  TicKV    |   ...
+//! +-----------+   +-----------+
+//!
+//!    hil::flash (virtual_flash)
+//! ```
+
+use capsules_core::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::TicKvApp as usize;
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::errorcode;
+use kernel::grant::Grant;
+use kernel::grant::{AllowRoCount, AllowRwCount, GrantKernelData, UpcallCount};
+use kernel::hil::kv::KVStats;
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::leasable_buffer::SubSliceMut;
+use kernel::{ErrorCode, ProcessId};
+
+use crate::tickv::{KVSystem, KVSystemClient, TicKVKeyType, MAIN_KEY_HASH};
+
+/// IDs for read-only allow buffers.
+mod ro_allow {
+    /// Key.
+    pub const KEY: usize = 0;
+    /// Value to append.
+    pub const VALUE: usize = 1;
+    /// The number of RO allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 2;
+}
+
+/// IDs for read-write allow buffers.
+mod rw_allow {
+    /// Output value for get and next key.
+    pub const VALUE: usize = 0;
+    /// Output key for next key.
+    pub const KEY: usize = 1;
+    /// The number of RW allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 2;
+}
+
+/// IDs for upcalls.
+mod upcalls {
+    /// Single upcall.
+    pub const DONE: usize = 0;
+    /// The number of upcalls the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum UserSpaceOp {
+    Get,
+    Append,
+    Invalidate,
+    GarbageCollect,
+    NextKey,
+    Usage,
+}
+
+/// A TicKV store that belongs to the app with the write ID `write_id`.
+pub struct Namespace<'a, K: KVSystem<'a, K = TicKVKeyType>> {
+    kv: &'a K,
+    write_id: u32,
+    max_keys: usize,
+    max_bytes: usize,
+}
+
+impl<'a, K: KVSystem<'a, K = TicKVKeyType>> Namespace<'a, K> {
+    /// Assign `kv` to the app with `write_id`, which can store up to
+    /// `max_keys` keys using up to `max_bytes` bytes of flash.
+    pub const fn new(kv: &'a K, write_id: u32, max_keys: usize, max_bytes: usize) -> Self {
+        Namespace {
+            kv,
+            write_id,
+            max_keys,
+            max_bytes,
+        }
+    }
+
+    /// Returns true if a value of `value_len` bytes can be appended to the
+    /// store while it uses `stats`, without exceeding the quota.
+    fn admits(&self, stats: &KVStats, value_len: usize) -> bool {
+        stats.keys < self.max_keys && stats.used + value_len <= self.max_bytes
+    }
+}
+
+/// Contents of the grant for each app.
+#[derive(Default)]
+pub struct App {
+    op: OptionalCell<UserSpaceOp>,
+    /// Where the next key enumeration continues from.
+    cursor: Cell<usize>,
+}
+
+/// Capsule that provides userspace access to per-app TicKV stores.
+pub struct TicKVAppDriver<'a, K: KVSystem<'a, K = TicKVKeyType>> {
+    namespaces: &'a [Namespace<'a, K>],
+    /// Grant storage for each app.
+    apps: Grant<
+        App,
+        UpcallCount<{ upcalls::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    /// App that is actively using a store.
+    processid: OptionalCell<ProcessId>,
+    /// Namespace of the active app.
+    namespace: OptionalCell<&'a Namespace<'a, K>>,
+    /// Length of the value to append once the quota has been checked.
+    value_len: Cell<usize>,
+    key_buffer: TakeCell<'static, TicKVKeyType>,
+    value_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, K: KVSystem<'a, K = TicKVKeyType>> TicKVAppDriver<'a, K> {
+    pub fn new(
+        namespaces: &'a [Namespace<'a, K>],
+        key_buffer: &'static mut TicKVKeyType,
+        value_buffer: &'static mut [u8],
+        grant: Grant<
+            App,
+            UpcallCount<{ upcalls::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+    ) -> TicKVAppDriver<'a, K> {
+        TicKVAppDriver {
+            namespaces,
+            apps: grant,
+            processid: OptionalCell::empty(),
+            namespace: OptionalCell::empty(),
+            value_len: Cell::new(0),
+            key_buffer: TakeCell::new(key_buffer),
+            value_buffer: TakeCell::new(value_buffer),
+        }
+    }
+
+    /// Set this driver as the client of the stores of all namespaces.
+    pub fn set_clients(&'a self) {
+        for namespace in self.namespaces {
+            namespace.kv.set_client(self);
+        }
+    }
+
+    fn run(&self) -> Result<(), ErrorCode> {
+        self.processid.map_or(Err(ErrorCode::RESERVE), |processid| {
+            self.apps
+                .enter(processid, |app, kernel_data| {
+                    let write_id = processid
+                        .get_storage_permissions()
+                        .and_then(|perms| perms.get_write_id())
+                        .ok_or(ErrorCode::INVAL)?;
+                    let namespace = self
+                        .namespaces
+                        .iter()
+                        .find(|namespace| namespace.write_id == write_id)
+                        .ok_or(ErrorCode::NODEVICE)?;
+                    self.namespace.set(namespace);
+
+                    let op = app.op.get().ok_or(ErrorCode::FAIL)?;
+                    if let UserSpaceOp::Get | UserSpaceOp::Append | UserSpaceOp::Invalidate = op {
+                        let key = kernel_data
+                            .get_readonly_processbuffer(ro_allow::KEY)
+                            .and_then(|buffer| {
+                                buffer.enter(|key| {
+                                    let mut raw: TicKVKeyType = [0; 8];
+                                    if key.len() != raw.len() {
+                                        return Err(ErrorCode::SIZE);
+                                    }
+                                    key.copy_to_slice(&mut raw);
+                                    Ok(raw)
+                                })
+                            })
+                            .unwrap_or(Err(ErrorCode::RESERVE))?;
+                        // The main key marks the store as initialised, apps
+                        // can not change it.
+                        if u64::from_be_bytes(key) == MAIN_KEY_HASH {
+                            return Err(ErrorCode::INVAL);
+                        }
+                        self.key_buffer.map(|buf| *buf = key);
+                    }
+
+                    match op {
+                        UserSpaceOp::Get => {
+                            let (key, value) = self.buffers()?;
+                            namespace
+                                .kv
+                                .get_value(key, SubSliceMut::new(value))
+                                .map_err(|(key, value, e)| self.restore(key, value, e))
+                        }
+                        UserSpaceOp::Append => {
+                            let value_len = kernel_data
+                                .get_readonly_processbuffer(ro_allow::VALUE)
+                                .and_then(|buffer| {
+                                    buffer.enter(|value| {
+                                        self.value_buffer.map_or(Err(ErrorCode::NOMEM), |buf| {
+                                            if value.len() > buf.len() {
+                                                Err(ErrorCode::SIZE)
+                                            } else {
+                                                value.copy_to_slice(&mut buf[..value.len()]);
+                                                Ok(value.len())
+                                            }
+                                        })
+                                    })
+                                })
+                                .unwrap_or(Err(ErrorCode::RESERVE))?;
+                            self.value_len.set(value_len);
+                            // Check the quota before appending.
+                            namespace.kv.get_stats()
+                        }
+                        UserSpaceOp::Invalidate => {
+                            let key = self.key_buffer.take().ok_or(ErrorCode::NOMEM)?;
+                            namespace.kv.invalidate_key(key).map_err(|(key, e)| {
+                                self.key_buffer.replace(key);
+                                e
+                            })
+                        }
+                        UserSpaceOp::GarbageCollect => namespace.kv.garbage_collect(),
+                        UserSpaceOp::NextKey => {
+                            let (key, value) = self.buffers()?;
+                            namespace
+                                .kv
+                                .get_next_key(app.cursor.get(), key, SubSliceMut::new(value))
+                                .map_err(|(key, value, e)| self.restore(key, value, e))
+                        }
+                        UserSpaceOp::Usage => namespace.kv.get_stats(),
+                    }
+                })
+                .unwrap_or_else(|err| Err(err.into()))
+        })
+    }
+
+    fn buffers(&self) -> Result<(&'static mut TicKVKeyType, &'static mut [u8]), ErrorCode> {
+        match (self.key_buffer.take(), self.value_buffer.take()) {
+            (Some(key), Some(value)) => Ok((key, value)),
+            (key, value) => {
+                key.map(|key| self.key_buffer.replace(key));
+                value.map(|value| self.value_buffer.replace(value));
+                Err(ErrorCode::NOMEM)
+            }
+        }
+    }
+
+    fn restore(
+        &self,
+        key: &'static mut TicKVKeyType,
+        value: SubSliceMut<'static, u8>,
+        error: ErrorCode,
+    ) -> ErrorCode {
+        self.key_buffer.replace(key);
+        self.value_buffer.replace(value.take());
+        error
+    }
+
+    /// Append the value once the statistics show it fits in the quota.
+    fn append(&self, stats: Result<KVStats, ErrorCode>) -> Result<(), ErrorCode> {
+        let stats = stats?;
+        let namespace = self.namespace.get().ok_or(ErrorCode::FAIL)?;
+        let value_len = self.value_len.get();
+        if !namespace.admits(&stats, value_len) {
+            return Err(ErrorCode::NOMEM);
+        }
+        let (key, value) = self.buffers()?;
+        let mut value = SubSliceMut::new(value);
+        value.slice(..value_len);
+        namespace
+            .kv
+            .append_key(key, value)
+            .map_err(|(key, value, e)| self.restore(key, value, e))
+    }
+
+    fn check_queue(&self) {
+        // If an app is already running let it complete.
+        if self.processid.is_some() {
+            return;
+        }
+
+        for appiter in self.apps.iter() {
+            let processid = appiter.processid();
+            let has_pending_op = appiter.enter(|app, _| {
+                // If this app has a pending command let's use it.
+                app.op.is_some()
+            });
+            let started_command = if has_pending_op {
+                // Mark this driver as being in use.
+                self.processid.set(processid);
+                match self.run() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        // Let the app know its command failed.
+                        let _ = self.apps.enter(processid, |app, upcalls| {
+                            app.op.clear();
+                            upcalls
+                                .schedule_upcall(
+                                    upcalls::DONE,
+                                    (errorcode::into_statuscode(Err(e)), 0, 0),
+                                )
+                                .ok();
+                        });
+                        false
+                    }
+                }
+            } else {
+                false
+            };
+            if started_command {
+                break;
+            } else {
+                self.processid.clear();
+            }
+        }
+    }
+
+    /// Finish the operation of the active app with an upcall and start the
+    /// next queued operation.
+    fn complete(
+        &self,
+        op: UserSpaceOp,
+        upcall: impl FnOnce(&App, &GrantKernelData) -> (usize, usize, usize),
+    ) {
+        self.processid.map(|processid| {
+            let _ = self.apps.enter(processid, |app, kernel_data| {
+                if app.op.contains(&op) {
+                    app.op.clear();
+                    let args = upcall(app, kernel_data);
+                    kernel_data.schedule_upcall(upcalls::DONE, args).ok();
+                }
+            });
+        });
+
+        self.processid.clear();
+        self.namespace.clear();
+        self.check_queue();
+    }
+}
+
+/// Copy `data` to the RW allow buffer `allow_num`. Returns `SIZE` if only
+/// part of it fits.
+fn copy_out(kernel_data: &GrantKernelData, allow_num: usize, data: &[u8]) -> Result<(), ErrorCode> {
+    kernel_data
+        .get_readwrite_processbuffer(allow_num)
+        .and_then(|buffer| {
+            buffer.mut_enter(|appslice| {
+                let copy_len = cmp::min(data.len(), appslice.len());
+                appslice[..copy_len].copy_from_slice(&data[..copy_len]);
+                if copy_len < data.len() {
+                    Err(ErrorCode::SIZE)
+                } else {
+                    Ok(())
+                }
+            })
+        })
+        .unwrap_or(Err(ErrorCode::RESERVE))
+}
+
+impl<'a, K: KVSystem<'a, K = TicKVKeyType>> KVSystemClient<TicKVKeyType> for TicKVAppDriver<'a, K> {
+    fn generate_key_complete(
+        &self,
+        _result: Result<(), ErrorCode>,
+        _unhashed_key: SubSliceMut<'static, u8>,
+        key_buf: &'static mut TicKVKeyType,
+    ) {
+        // Keys are not hashed by this driver.
+        self.key_buffer.replace(key_buf);
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut TicKVKeyType,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.key_buffer.replace(key);
+        self.value_buffer.replace(value.take());
+        self.complete(UserSpaceOp::Append, |_, _| {
+            (errorcode::into_statuscode(result), 0, 0)
+        });
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut TicKVKeyType,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.key_buffer.replace(key);
+        self.complete(UserSpaceOp::Get, |_, kernel_data| match result {
+            // With `SIZE` the value did not fit in the buffer of the driver,
+            // the part that fit is still returned.
+            Ok(()) | Err(ErrorCode::SIZE) => {
+                let ret = result.and(copy_out(
+                    kernel_data,
+                    rw_allow::VALUE,
+                    &value[..value.len()],
+                ));
+                (errorcode::into_statuscode(ret), value.len(), 0)
+            }
+            Err(e) => (errorcode::into_statuscode(Err(e)), 0, 0),
+        });
+        self.value_buffer.replace(value.take());
+    }
+
+    fn invalidate_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut TicKVKeyType,
+    ) {
+        self.key_buffer.replace(key);
+        self.complete(UserSpaceOp::Invalidate, |_, _| {
+            (errorcode::into_statuscode(result), 0, 0)
+        });
+    }
+
+    fn garbage_collect_complete(&self, result: Result<(), ErrorCode>) {
+        self.complete(UserSpaceOp::GarbageCollect, |_, _| {
+            (errorcode::into_statuscode(result), 0, 0)
+        });
+    }
+
+    fn get_next_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        cursor: usize,
+        key: &'static mut TicKVKeyType,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.complete(UserSpaceOp::NextKey, |app, kernel_data| match result {
+            Ok(()) | Err(ErrorCode::SIZE) => {
+                // A key was found, even if all of the value did not fit.
+                // Continue after it next time.
+                app.cursor.set(cursor);
+                let ret = result
+                    .and(copy_out(kernel_data, rw_allow::KEY, key))
+                    .and(copy_out(
+                        kernel_data,
+                        rw_allow::VALUE,
+                        &value[..value.len()],
+                    ));
+                (errorcode::into_statuscode(ret), value.len(), 0)
+            }
+            Err(e) => (errorcode::into_statuscode(Err(e)), 0, 0),
+        });
+        self.key_buffer.replace(key);
+        self.value_buffer.replace(value.take());
+    }
+
+    fn get_stats_complete(&self, result: Result<KVStats, ErrorCode>) {
+        let appending = self.processid.map_or(false, |processid| {
+            self.apps
+                .enter(processid, |app, _| app.op.contains(&UserSpaceOp::Append))
+                .unwrap_or(false)
+        });
+        if appending {
+            if let Err(e) = self.append(result) {
+                self.complete(UserSpaceOp::Append, |_, _| {
+                    (errorcode::into_statuscode(Err(e)), 0, 0)
+                });
+            }
+            return;
+        }
+
+        self.complete(UserSpaceOp::Usage, |_, _| match result {
+            Ok(stats) => (errorcode::into_statuscode(Ok(())), stats.keys, stats.used),
+            Err(e) => (errorcode::into_statuscode(Err(e)), 0, 0),
+        });
+    }
+}
+
+impl<'a, K: KVSystem<'a, K = TicKVKeyType>> SyscallDriver for TicKVAppDriver<'a, K> {
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        let op = match command_num {
+            // check if present
+            0 => return CommandReturn::success(),
+
+            // get, append, invalidate
+            1 => UserSpaceOp::Get,
+            2 => UserSpaceOp::Append,
+            3 => UserSpaceOp::Invalidate,
+
+            // garbage collect
+            4 => UserSpaceOp::GarbageCollect,
+
+            // next key, `data1` of 0 restarts the enumeration from the first
+            // key, any other value continues after the last key found.
+            5 => {
+                if data1 == 0 {
+                    let _ = self.apps.enter(processid, |app, _| app.cursor.set(0));
+                }
+                UserSpaceOp::NextKey
+            }
+
+            // number of keys and bytes used
+            6 => UserSpaceOp::Usage,
+
+            // quota of keys and bytes
+            7 => {
+                let write_id = processid
+                    .get_storage_permissions()
+                    .and_then(|perms| perms.get_write_id());
+                return match self
+                    .namespaces
+                    .iter()
+                    .find(|namespace| Some(namespace.write_id) == write_id)
+                {
+                    Some(namespace) => CommandReturn::success_u32_u32(
+                        namespace.max_keys as u32,
+                        namespace.max_bytes as u32,
+                    ),
+                    None if write_id.is_none() => CommandReturn::failure(ErrorCode::INVAL),
+                    None => CommandReturn::failure(ErrorCode::NODEVICE),
+                };
+            }
+
+            // default
+            _ => return CommandReturn::failure(ErrorCode::NOSUPPORT),
+        };
+
+        if self.processid.is_none() {
+            // Nothing is using the stores, so we can handle this request.
+            self.processid.set(processid);
+            let _ = self.apps.enter(processid, |app, _| app.op.set(op));
+            let ret = self.run();
+
+            if let Err(e) = ret {
+                let _ = self.apps.enter(processid, |app, _| app.op.clear());
+                self.processid.clear();
+                self.namespace.clear();
+                self.check_queue();
+                CommandReturn::failure(e)
+            } else {
+                CommandReturn::success()
+            }
+        } else {
+            // There is an active app, so queue this request (if possible).
+            self.apps
+                .enter(processid, |app, _| {
+                    if app.op.is_some() {
+                        // No more room in the queue, nowhere to store this
+                        // request.
+                        CommandReturn::failure(ErrorCode::NOMEM)
+                    } else {
+                        app.op.set(op);
+                        CommandReturn::success()
+                    }
+                })
+                .unwrap_or_else(|err| err.into())
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::sim_flash::SimFlash;
+    use crate::tickv::kv_stats;
+    use tickv::TicKV;
+
+    const REGION: usize = 256;
+    const REGIONS: usize = 8;
+
+    type Flash = SimFlash<REGION, REGIONS>;
+
+    const ONE: u64 = 0x0123_4567_89ab_cd03;
+    const TWO: u64 = 0x8c9f_1e2d_3b4a_5905;
+    const THREE: u64 = 0xfedc_ba98_7654_3213;
+
+    /// A store that is never used, for namespaces that only check quotas.
+    struct NoStore;
+
+    impl<'a> KVSystem<'a> for NoStore {
+        type K = TicKVKeyType;
+
+        fn set_client(&self, _client: &'a dyn KVSystemClient<Self::K>) {}
+        fn generate_key(
+            &self,
+            unhashed_key: SubSliceMut<'static, u8>,
+            key: &'static mut Self::K,
+        ) -> Result<(), (SubSliceMut<'static, u8>, &'static mut Self::K, ErrorCode)> {
+            Err((unhashed_key, key, ErrorCode::NOSUPPORT))
+        }
+        fn append_key(
+            &self,
+            key: &'static mut Self::K,
+            value: SubSliceMut<'static, u8>,
+        ) -> Result<(), (&'static mut Self::K, SubSliceMut<'static, u8>, ErrorCode)> {
+            Err((key, value, ErrorCode::NOSUPPORT))
+        }
+        fn get_value(
+            &self,
+            key: &'static mut Self::K,
+            ret_buf: SubSliceMut<'static, u8>,
+        ) -> Result<(), (&'static mut Self::K, SubSliceMut<'static, u8>, ErrorCode)> {
+            Err((key, ret_buf, ErrorCode::NOSUPPORT))
+        }
+        fn invalidate_key(
+            &self,
+            key: &'static mut Self::K,
+        ) -> Result<(), (&'static mut Self::K, ErrorCode)> {
+            Err((key, ErrorCode::NOSUPPORT))
+        }
+        fn garbage_collect(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+        fn get_next_key(
+            &self,
+            _cursor: usize,
+            key: &'static mut Self::K,
+            ret_buf: SubSliceMut<'static, u8>,
+        ) -> Result<(), (&'static mut Self::K, SubSliceMut<'static, u8>, ErrorCode)> {
+            Err((key, ret_buf, ErrorCode::NOSUPPORT))
+        }
+        fn get_stats(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
+
+    /// A namespace with the quota, its store is only used for the type.
+    fn namespace(max_keys: usize, max_bytes: usize) -> Namespace<'static, NoStore> {
+        Namespace::new(&NoStore, 1, max_keys, max_bytes)
+    }
+
+    /// The usage of `tickv` as the driver sees it.
+    fn stats(tickv: &TicKV<&Flash, REGION>) -> KVStats {
+        kv_stats(tickv.get_stats().unwrap().1)
+    }
+
+    #[test]
+    fn key_quota() {
+        let flash = Flash::new();
+        let mut read_buf = [0; REGION];
+        let tickv = TicKV::<&Flash, REGION>::new(&flash, &mut read_buf, REGION * REGIONS);
+        tickv.initialise(MAIN_KEY_HASH).unwrap();
+        let namespace = namespace(2, REGION * REGIONS);
+
+        assert!(namespace.admits(&stats(&tickv), 10));
+        tickv.append_key(ONE, &[1; 10]).unwrap();
+        assert!(namespace.admits(&stats(&tickv), 10));
+        tickv.append_key(TWO, &[2; 10]).unwrap();
+        assert_eq!(stats(&tickv).keys, 2);
+        assert!(!namespace.admits(&stats(&tickv), 10));
+
+        // Deleting a key makes room for another one.
+        tickv.invalidate_key(ONE).unwrap();
+        assert!(namespace.admits(&stats(&tickv), 10));
+        tickv.append_key(THREE, &[3; 10]).unwrap();
+        assert!(!namespace.admits(&stats(&tickv), 10));
+    }
+
+    #[test]
+    fn byte_quota() {
+        let flash = Flash::new();
+        let mut read_buf = [0; REGION];
+        let tickv = TicKV::<&Flash, REGION>::new(&flash, &mut read_buf, REGION * REGIONS);
+        tickv.initialise(MAIN_KEY_HASH).unwrap();
+        tickv.append_key(ONE, &[1; 100]).unwrap();
+
+        let used = stats(&tickv).used;
+        let namespace = namespace(10, used + 50);
+        assert!(namespace.admits(&stats(&tickv), 50));
+        assert!(!namespace.admits(&stats(&tickv), 51));
+
+        // The quota counts the flash used, including the object header.
+        tickv.append_key(TWO, &[2; 30]).unwrap();
+        assert!(stats(&tickv).used > used + 30);
+        assert!(!namespace.admits(&stats(&tickv), 20));
+    }
+
+    #[test]
+    fn update_and_delete_credit_bytes() {
+        let flash = Flash::new();
+        let mut read_buf = [0; REGION];
+        let tickv = TicKV::<&Flash, REGION>::new(&flash, &mut read_buf, REGION * REGIONS);
+        tickv.initialise(MAIN_KEY_HASH).unwrap();
+        tickv.append_key(ONE, &[1; 200]).unwrap();
+        tickv.append_key(TWO, &[2; 200]).unwrap();
+
+        let full = stats(&tickv);
+        let namespace = namespace(10, full.used);
+        assert!(!namespace.admits(&full, 1));
+
+        // Updating a value replaces the old object, the store uses as much
+        // as before, even though the old object is not erased yet.
+        tickv.invalidate_key(TWO).unwrap();
+        tickv.append_key(TWO, &[3; 200]).unwrap();
+        let updated = stats(&tickv);
+        assert_eq!(updated.used, full.used);
+        assert!(updated.reclaimable > 0);
+        assert!(!namespace.admits(&updated, 1));
+
+        // Deleting a value credits its bytes back before garbage collection.
+        tickv.invalidate_key(ONE).unwrap();
+        let deleted = stats(&tickv);
+        assert!(namespace.admits(&deleted, 200));
+        assert_eq!(deleted.keys, 1);
+    }
+}
@@ -41,6 +41,8 @@ enum Operation {
     Update,
     Delete,
     GarbageCollect,
+    NextKey,
+    GetStats,
 }
 
 /// `TicKVKVStore` implements the KV interface using the TicKV KVSystem
@@ -225,6 +227,64 @@ impl<'a, K: KVSystem<'a, K = T>, T: KeyType> kv::KV<'a> for TicKVKVStore<'a, K,
             Ok(())
         }
     }
+
+    fn next_key(
+        &self,
+        cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        if self.operation.is_some() {
+            return Err((key, value, ErrorCode::BUSY));
+        }
+
+        match self.hashed_key.take() {
+            Some(hashed_key) => {
+                // The found key is returned in the key buffer.
+                if key.len() < hashed_key.as_ref().len() {
+                    self.hashed_key.replace(hashed_key);
+                    return Err((key, value, ErrorCode::SIZE));
+                }
+
+                self.operation.set(Operation::NextKey);
+
+                match self.kv.get_next_key(cursor, hashed_key, value) {
+                    Ok(()) => {
+                        self.unhashed_key.replace(key);
+                        Ok(())
+                    }
+                    Err((hashed_key, value, e)) => {
+                        self.hashed_key.replace(hashed_key);
+                        self.operation.clear();
+                        Err((key, value, e))
+                    }
+                }
+            }
+            None => Err((key, value, ErrorCode::FAIL)),
+        }
+    }
+
+    fn get_stats(&self) -> Result<(), ErrorCode> {
+        if self.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.operation.set(Operation::GetStats);
+
+        if let Err(e) = self.kv.get_stats() {
+            self.operation.clear();
+            Err(e)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl<'a, K: KVSystem<'a, K = T>, T: KeyType> KVSystemClient<T> for TicKVKVStore<'a, K, T> {
@@ -276,7 +336,7 @@ impl<'a, K: KVSystem<'a, K = T>, T: KeyType> KVSystemClient<T> for TicKVKVStore<
                             cb.delete_complete(Err(ErrorCode::FAIL), unhashed_key);
                         });
                     }
-                    Operation::GarbageCollect => {}
+                    Operation::GarbageCollect | Operation::NextKey | Operation::GetStats => {}
                 }
             } else {
                 match op {
@@ -367,7 +427,7 @@ impl<'a, K: KVSystem<'a, K = T>, T: KeyType> KVSystemClient<T> for TicKVKVStore<
                             }
                         };
                     }
-                    Operation::GarbageCollect => {}
+                    Operation::GarbageCollect | Operation::NextKey | Operation::GetStats => {}
                 }
             }
         });
@@ -459,7 +519,7 @@ impl<'a, K: KVSystem<'a, K = T>, T: KeyType> KVSystemClient<T> for TicKVKVStore<
                     });
                 });
             }
-            Operation::GarbageCollect => {}
+            Operation::GarbageCollect | Operation::NextKey | Operation::GetStats => {}
         });
     }
 
@@ -583,7 +643,7 @@ impl<'a, K: KVSystem<'a, K = T>, T: KeyType> KVSystemClient<T> for TicKVKVStore<
                     });
                 });
             }
-            Operation::GarbageCollect => {}
+            Operation::GarbageCollect | Operation::NextKey | Operation::GetStats => {}
         });
     }
 
@@ -593,4 +653,47 @@ impl<'a, K: KVSystem<'a, K = T>, T: KeyType> KVSystemClient<T> for TicKVKVStore<
             cb.garbage_collection_complete(result);
         });
     }
+
+    fn get_next_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        cursor: usize,
+        hashed_key: &'static mut T,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.operation.clear();
+
+        self.unhashed_key.take().map(|mut key| {
+            if result.is_ok() || result == Err(ErrorCode::SIZE) {
+                // Return the hashed key, as that is all that is stored.
+                let key_len = hashed_key.as_ref().len();
+                key.as_slice()[..key_len].copy_from_slice(hashed_key.as_ref());
+                key.slice(..key_len);
+            } else {
+                key.slice(..0);
+            }
+
+            self.client.map(move |cb| {
+                cb.next_key_complete(
+                    result.map_err(|e| match e {
+                        ErrorCode::SIZE => ErrorCode::SIZE,
+                        ErrorCode::NOSUPPORT => ErrorCode::NOSUPPORT,
+                        _ => ErrorCode::FAIL,
+                    }),
+                    cursor,
+                    key,
+                    value,
+                );
+            });
+        });
+
+        self.hashed_key.replace(hashed_key);
+    }
+
+    fn get_stats_complete(&self, result: Result<kv::KVStats, ErrorCode>) {
+        self.operation.clear();
+        self.client.map(move |cb| {
+            cb.stats_complete(result);
+        });
+    }
 }
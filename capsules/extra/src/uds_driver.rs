@@ -0,0 +1,676 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! UDS (ISO 14229) diagnostic server userspace driver.
+//!
+//! Answers the diagnostic requests a tester sends over ISO-TP. The server
+//! handles session control and tester present itself and dispatches read
+//! data by identifier, write data by identifier and routine control to the
+//! apps that registered the data or routine identifier. Other services are
+//! answered with "service not supported".
+//!
+//! ```rust,ignore
+//! +===============+
+//! ||  Userspace  ||
+//! +===============+
+//!
+//! -----Syscall Interface-----
+//!
+//! +---------------------------------+
+//! |  UDS Driver (this file)         |
+//! +---------------------------------+
+//!
+//!      isotp::IsoTpClient
+//!
+//! +---------------------------------+
+//! |  isotp::IsoTp                   |
+//! +---------------------------------+
+//!
+//!    hil::can::Transmit, hil::can::Receive
+//! ```
+//!
+//! Sessions
+//! --------
+//!
+//! The server starts in the default session (1) and switches to the
+//! programming (2) or extended (3) session on request. Without requests for
+//! 5 seconds (S3), it returns to the default session. Apps are told about
+//! session changes and can read the current session, to reject requests
+//! that their session does not allow.
+//!
+//! Requests
+//! --------
+//!
+//! The server handles one request at a time. When a request for a
+//! registered identifier arrives, its data (the written value or the
+//! routine control option record) is copied to the app's request buffer and
+//! the app gets an upcall. The app answers with the respond command, whose
+//! data is taken from its response buffer, or with the reject command and a
+//! negative response code. The server adds the service, sub-function and
+//! identifier to the response. If the app takes longer than 50 ms (P2), the
+//! server tells the tester that the response is pending, and repeats that
+//! until the app answers or exits. Read data by identifier takes a single
+//! data identifier.
+//!
+//! The request upcall passes `(service | sub_function << 8, identifier,
+//! data length)`. The sub-function is the routine control type for routine
+//! control and 0 otherwise. The session upcall passes the new session.
+
+use capsules_core::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Uds as usize;
+
+use core::cell::Cell;
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::can;
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks};
+use kernel::processbuffer::{ReadableProcessBuffer, WriteableProcessBuffer};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::TakeCell;
+use kernel::{ErrorCode, ProcessId};
+
+use crate::isotp::{IsoTp, IsoTpClient};
+
+/// IDs for read-only allow buffers.
+mod ro_allow {
+    /// Data of the positive response.
+    pub const RESPONSE: usize = 0;
+    /// The number of RO allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for read-write allow buffers.
+mod rw_allow {
+    /// Data of the request.
+    pub const REQUEST: usize = 0;
+    /// The number of RW allow buffers the kernel stores for this grant.
+    pub const COUNT: u8 = 1;
+}
+
+/// IDs for upcalls.
+mod upcalls {
+    /// A request for an identifier of the app arrived.
+    pub const REQUEST: usize = 0;
+    /// The session changed.
+    pub const SESSION: usize = 1;
+    /// The number of upcalls the kernel stores for this grant.
+    pub const COUNT: u8 = 2;
+}
+
+/// Longest request or response the server handles.
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+/// Number of data identifiers, and of routine identifiers, an app can
+/// register.
+pub const MAX_IDENTIFIERS: usize = 8;
+
+pub const DEFAULT_SESSION: u8 = 0x01;
+pub const PROGRAMMING_SESSION: u8 = 0x02;
+pub const EXTENDED_SESSION: u8 = 0x03;
+
+// Service identifiers.
+const DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+const READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+const WRITE_DATA_BY_IDENTIFIER: u8 = 0x2E;
+const ROUTINE_CONTROL: u8 = 0x31;
+const TESTER_PRESENT: u8 = 0x3E;
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const POSITIVE_RESPONSE: u8 = 0x40;
+const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+// Routine control types.
+const START_ROUTINE: u8 = 0x01;
+const REQUEST_ROUTINE_RESULTS: u8 = 0x03;
+
+// Negative response codes.
+const GENERAL_REJECT: u8 = 0x10;
+const SERVICE_NOT_SUPPORTED: u8 = 0x11;
+const SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+const INCORRECT_MESSAGE_LENGTH: u8 = 0x13;
+const BUSY_REPEAT_REQUEST: u8 = 0x21;
+const CONDITIONS_NOT_CORRECT: u8 = 0x22;
+const REQUEST_OUT_OF_RANGE: u8 = 0x31;
+const RESPONSE_PENDING: u8 = 0x78;
+
+/// Time the server takes at most to start a response (P2).
+const P2_MS: u16 = 50;
+/// Time the server takes at most to respond after saying that the response
+/// is pending (P2*).
+const P2_EXTENDED_MS: u16 = 5000;
+/// Time after which a pending response is announced, or announced again.
+const RESPONSE_PENDING_MS: u32 = 40;
+const RESPONSE_PENDING_REPEAT_MS: u32 = 4000;
+/// Time without requests after which the server returns to the default
+/// session (S3).
+const S3_MS: u32 = 5000;
+
+#[derive(Default)]
+pub struct App {
+    data_identifiers: [Option<u16>; MAX_IDENTIFIERS],
+    routine_identifiers: [Option<u16>; MAX_IDENTIFIERS],
+}
+
+impl App {
+    fn identifiers(&mut self, service: u8) -> &mut [Option<u16>; MAX_IDENTIFIERS] {
+        if service == ROUTINE_CONTROL {
+            &mut self.routine_identifiers
+        } else {
+            &mut self.data_identifiers
+        }
+    }
+
+    fn handles(&mut self, service: u8, identifier: u16) -> bool {
+        self.identifiers(service).contains(&Some(identifier))
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Answer {
+    /// Positive response with this many bytes of the app's response buffer.
+    Positive(usize),
+    /// Negative response code.
+    Negative(u8),
+}
+
+/// A request an app handles.
+#[derive(Copy, Clone)]
+struct Request {
+    service: u8,
+    sub_function: u8,
+    identifier: u16,
+    suppress_positive_response: bool,
+    handler: ProcessId,
+    /// Whether the tester was told that the response is pending.
+    response_pending: bool,
+    /// Answer of the app waiting for the previous response to be sent.
+    answer: Option<Answer>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Timer {
+    Idle,
+    ResponsePending,
+    SessionTimeout,
+}
+
+pub struct Uds<'a, C, A>
+where
+    C: can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+        + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>,
+    A: Alarm<'a>,
+{
+    isotp: &'a IsoTp<'a, C, A>,
+    alarm: &'a A,
+    apps: Grant<
+        App,
+        UpcallCount<{ upcalls::COUNT }>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
+    tx_buffer: TakeCell<'static, [u8]>,
+    session: Cell<u8>,
+    request: Cell<Option<Request>>,
+    timer: Cell<Timer>,
+}
+
+impl<'a, C, A> Uds<'a, C, A>
+where
+    C: can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+        + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>,
+    A: Alarm<'a>,
+{
+    pub fn new(
+        isotp: &'a IsoTp<'a, C, A>,
+        alarm: &'a A,
+        grant: Grant<
+            App,
+            UpcallCount<{ upcalls::COUNT }>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
+        tx_buffer: &'static mut [u8; MAX_MESSAGE_LEN],
+    ) -> Uds<'a, C, A> {
+        Uds {
+            isotp,
+            alarm,
+            apps: grant,
+            tx_buffer: TakeCell::new(tx_buffer),
+            session: Cell::new(DEFAULT_SESSION),
+            request: Cell::new(None),
+            timer: Cell::new(Timer::Idle),
+        }
+    }
+
+    fn set_timer(&self, timer: Timer, ms: u32) {
+        self.timer.set(timer);
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(ms));
+    }
+
+    /// Start S3 after a request was handled.
+    fn restart_session_timer(&self) {
+        if self.session.get() == DEFAULT_SESSION {
+            self.timer.set(Timer::Idle);
+            let _ = self.alarm.disarm();
+        } else {
+            self.set_timer(Timer::SessionTimeout, S3_MS);
+        }
+    }
+
+    fn set_session(&self, session: u8) {
+        if self.session.replace(session) == session {
+            return;
+        }
+        self.apps.each(|_, _, kernel_data| {
+            kernel_data
+                .schedule_upcall(upcalls::SESSION, (usize::from(session), 0, 0))
+                .ok();
+        });
+    }
+
+    /// Send the response `fill` writes to the buffer, unless the previous
+    /// response is still being sent.
+    fn send_response(&self, fill: impl FnOnce(&mut [u8]) -> usize) {
+        self.tx_buffer.take().map(|buffer| {
+            let len = fill(buffer);
+            if let Err((_, buffer)) = self.isotp.send(buffer, len) {
+                self.tx_buffer.replace(buffer);
+            }
+        });
+    }
+
+    fn send_negative_response(&self, service: u8, code: u8) {
+        self.send_response(|response| {
+            response[..3].copy_from_slice(&[NEGATIVE_RESPONSE, service, code]);
+            3
+        });
+    }
+
+    fn request_received(&self, request: &[u8]) {
+        let Some(&service) = request.first() else {
+            return;
+        };
+        if self.request.get().is_some() {
+            self.send_negative_response(service, BUSY_REPEAT_REQUEST);
+            return;
+        }
+        let result = match service {
+            DIAGNOSTIC_SESSION_CONTROL => self.session_control(request),
+            TESTER_PRESENT => self.tester_present(request),
+            READ_DATA_BY_IDENTIFIER if request.len() == 3 => {
+                self.dispatch(service, 0, &request[1..3], &[], false)
+            }
+            WRITE_DATA_BY_IDENTIFIER if request.len() > 3 => {
+                self.dispatch(service, 0, &request[1..3], &request[3..], false)
+            }
+            ROUTINE_CONTROL if request.len() >= 4 => {
+                let routine_control_type = request[1] & !SUPPRESS_POSITIVE_RESPONSE;
+                if (START_ROUTINE..=REQUEST_ROUTINE_RESULTS).contains(&routine_control_type) {
+                    self.dispatch(
+                        service,
+                        routine_control_type,
+                        &request[2..4],
+                        &request[4..],
+                        request[1] & SUPPRESS_POSITIVE_RESPONSE != 0,
+                    )
+                } else {
+                    Err(SUB_FUNCTION_NOT_SUPPORTED)
+                }
+            }
+            READ_DATA_BY_IDENTIFIER | WRITE_DATA_BY_IDENTIFIER | ROUTINE_CONTROL => {
+                Err(INCORRECT_MESSAGE_LENGTH)
+            }
+            _ => Err(SERVICE_NOT_SUPPORTED),
+        };
+        if let Err(code) = result {
+            self.send_negative_response(service, code);
+        }
+        if self.request.get().is_none() {
+            self.restart_session_timer();
+        }
+    }
+
+    fn session_control(&self, request: &[u8]) -> Result<(), u8> {
+        if request.len() != 2 {
+            return Err(INCORRECT_MESSAGE_LENGTH);
+        }
+        let session = request[1] & !SUPPRESS_POSITIVE_RESPONSE;
+        if !(DEFAULT_SESSION..=EXTENDED_SESSION).contains(&session) {
+            return Err(SUB_FUNCTION_NOT_SUPPORTED);
+        }
+        self.set_session(session);
+        if request[1] & SUPPRESS_POSITIVE_RESPONSE == 0 {
+            self.send_response(|response| {
+                response[0] = DIAGNOSTIC_SESSION_CONTROL + POSITIVE_RESPONSE;
+                response[1] = session;
+                response[2..4].copy_from_slice(&P2_MS.to_be_bytes());
+                response[4..6].copy_from_slice(&(P2_EXTENDED_MS / 10).to_be_bytes());
+                6
+            });
+        }
+        Ok(())
+    }
+
+    fn tester_present(&self, request: &[u8]) -> Result<(), u8> {
+        if request.len() != 2 {
+            return Err(INCORRECT_MESSAGE_LENGTH);
+        }
+        if request[1] & !SUPPRESS_POSITIVE_RESPONSE != 0 {
+            return Err(SUB_FUNCTION_NOT_SUPPORTED);
+        }
+        if request[1] & SUPPRESS_POSITIVE_RESPONSE == 0 {
+            self.send_response(|response| {
+                response[..2].copy_from_slice(&[TESTER_PRESENT + POSITIVE_RESPONSE, 0]);
+                2
+            });
+        }
+        Ok(())
+    }
+
+    /// Pass a request to the app that registered `identifier`.
+    fn dispatch(
+        &self,
+        service: u8,
+        sub_function: u8,
+        identifier: &[u8],
+        data: &[u8],
+        suppress_positive_response: bool,
+    ) -> Result<(), u8> {
+        let identifier = u16::from_be_bytes([identifier[0], identifier[1]]);
+        let handler = self
+            .apps
+            .iter()
+            .find_map(|app| {
+                let processid = app.processid();
+                app.enter(|app, _| app.handles(service, identifier))
+                    .then_some(processid)
+            })
+            .ok_or(REQUEST_OUT_OF_RANGE)?;
+
+        let delivered = self
+            .apps
+            .enter(handler, |_, kernel_data| {
+                let copied = data.is_empty()
+                    || kernel_data
+                        .get_readwrite_processbuffer(rw_allow::REQUEST)
+                        .and_then(|request| {
+                            request.mut_enter(|request| {
+                                request.get(..data.len()).is_some_and(|request| {
+                                    request.copy_from_slice(data);
+                                    true
+                                })
+                            })
+                        })
+                        .unwrap_or(false);
+                copied
+                    && kernel_data
+                        .schedule_upcall(
+                            upcalls::REQUEST,
+                            (
+                                usize::from(service) | usize::from(sub_function) << 8,
+                                usize::from(identifier),
+                                data.len(),
+                            ),
+                        )
+                        .is_ok()
+            })
+            .unwrap_or(false);
+        if !delivered {
+            return Err(CONDITIONS_NOT_CORRECT);
+        }
+
+        self.request.set(Some(Request {
+            service,
+            sub_function,
+            identifier,
+            suppress_positive_response,
+            handler,
+            response_pending: false,
+            answer: None,
+        }));
+        self.set_timer(Timer::ResponsePending, RESPONSE_PENDING_MS);
+        Ok(())
+    }
+
+    fn answer(&self, processid: ProcessId, answer: Answer) -> Result<(), ErrorCode> {
+        let mut request = self
+            .request
+            .get()
+            .filter(|request| request.handler == processid && request.answer.is_none())
+            .ok_or(ErrorCode::INVAL)?;
+        if let Answer::Positive(len) = answer {
+            let fits = self
+                .apps
+                .enter(processid, |_, kernel_data| {
+                    kernel_data
+                        .get_readonly_processbuffer(ro_allow::RESPONSE)
+                        .is_ok_and(|response| len <= response.len())
+                })
+                .unwrap_or(false);
+            // The response adds up to 4 bytes to the data.
+            if !fits || len + 4 > MAX_MESSAGE_LEN {
+                return Err(ErrorCode::SIZE);
+            }
+        }
+        request.answer = Some(answer);
+        self.request.set(Some(request));
+        self.send_answer();
+        Ok(())
+    }
+
+    /// Send the answer of the app, if the app answered and no response is
+    /// being sent.
+    fn send_answer(&self) {
+        let Some(request) = self.request.get() else {
+            return;
+        };
+        let Some(answer) = request.answer else {
+            return;
+        };
+        if self.tx_buffer.is_none() {
+            return;
+        }
+        self.request.set(None);
+        match answer {
+            Answer::Positive(_)
+                if request.suppress_positive_response && !request.response_pending => {}
+            Answer::Positive(len) => self.send_response(|response| {
+                response[0] = request.service + POSITIVE_RESPONSE;
+                let identifier = request.identifier.to_be_bytes();
+                let header_len = if request.service == ROUTINE_CONTROL {
+                    response[1] = request.sub_function;
+                    response[2..4].copy_from_slice(&identifier);
+                    4
+                } else {
+                    response[1..3].copy_from_slice(&identifier);
+                    3
+                };
+                let copied = len == 0
+                    || self.apps.enter(request.handler, |_, kernel_data| {
+                        kernel_data
+                            .get_readonly_processbuffer(ro_allow::RESPONSE)
+                            .and_then(|data| {
+                                data.enter(|data| {
+                                    data.get(..len).is_some_and(|data| {
+                                        data.copy_to_slice(
+                                            &mut response[header_len..header_len + len],
+                                        );
+                                        true
+                                    })
+                                })
+                            })
+                            .unwrap_or(false)
+                    }) == Ok(true);
+                if copied {
+                    header_len + len
+                } else {
+                    response[..3].copy_from_slice(&[
+                        NEGATIVE_RESPONSE,
+                        request.service,
+                        GENERAL_REJECT,
+                    ]);
+                    3
+                }
+            }),
+            Answer::Negative(code) => self.send_negative_response(request.service, code),
+        }
+        self.restart_session_timer();
+    }
+
+    fn register(&self, processid: ProcessId, service: u8, identifier: usize) -> CommandReturn {
+        let Ok(identifier) = u16::try_from(identifier) else {
+            return CommandReturn::failure(ErrorCode::INVAL);
+        };
+        let taken = self
+            .apps
+            .iter()
+            .any(|app| app.enter(|app, _| app.handles(service, identifier)));
+        if taken {
+            return CommandReturn::failure(ErrorCode::ALREADY);
+        }
+        self.apps
+            .enter(processid, |app, _| {
+                match app
+                    .identifiers(service)
+                    .iter_mut()
+                    .find(|slot| slot.is_none())
+                {
+                    Some(slot) => {
+                        *slot = Some(identifier);
+                        CommandReturn::success()
+                    }
+                    None => CommandReturn::failure(ErrorCode::NOMEM),
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a, C, A> IsoTpClient for Uds<'a, C, A>
+where
+    C: can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+        + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>,
+    A: Alarm<'a>,
+{
+    fn message_received(&self, buffer: &'static mut [u8], len: usize) {
+        self.request_received(&buffer[..len]);
+        self.isotp.receive(buffer);
+    }
+
+    fn message_sent(&self, buffer: &'static mut [u8], _result: Result<(), ErrorCode>) {
+        self.tx_buffer.replace(buffer);
+        self.send_answer();
+    }
+}
+
+impl<'a, C, A> AlarmClient for Uds<'a, C, A>
+where
+    C: can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+        + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>,
+    A: Alarm<'a>,
+{
+    fn alarm(&self) {
+        match self.timer.replace(Timer::Idle) {
+            Timer::Idle => {}
+            Timer::ResponsePending => {
+                let Some(mut request) = self.request.get() else {
+                    return;
+                };
+                if self.apps.enter(request.handler, |_, _| {}).is_ok() {
+                    request.response_pending = true;
+                    self.request.set(Some(request));
+                    self.send_negative_response(request.service, RESPONSE_PENDING);
+                    self.set_timer(Timer::ResponsePending, RESPONSE_PENDING_REPEAT_MS);
+                } else {
+                    // The app exited without answering.
+                    self.request.set(None);
+                    self.send_negative_response(request.service, GENERAL_REJECT);
+                    self.restart_session_timer();
+                }
+            }
+            Timer::SessionTimeout => self.set_session(DEFAULT_SESSION),
+        }
+    }
+}
+
+impl<'a, C, A> SyscallDriver for Uds<'a, C, A>
+where
+    C: can::Transmit<{ can::STANDARD_CAN_PACKET_SIZE }>
+        + can::Receive<{ can::STANDARD_CAN_PACKET_SIZE }>,
+    A: Alarm<'a>,
+{
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return Ok(()) if this driver is included on the platform.
+    /// - `1`: Register the data identifier `data1`, so that the app handles
+    ///   reading and writing it.
+    /// - `2`: Register the routine identifier `data1`, so that the app
+    ///   handles controlling the routine.
+    /// - `3`: Remove all identifiers the app registered.
+    /// - `4`: Answer the current request with a positive response, whose
+    ///   data are the first `data1` bytes of the response buffer.
+    /// - `5`: Answer the current request with the negative response code
+    ///   `data1`.
+    /// - `6`: Return the current session.
+    fn command(
+        &self,
+        command_num: usize,
+        data1: usize,
+        _data2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+
+            1 => self.register(processid, READ_DATA_BY_IDENTIFIER, data1),
+
+            2 => self.register(processid, ROUTINE_CONTROL, data1),
+
+            3 => self
+                .apps
+                .enter(processid, |app, _| {
+                    **app = App::default();
+                    CommandReturn::success()
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            4 => self.answer(processid, Answer::Positive(data1)).into(),
+
+            5 => match u8::try_from(data1) {
+                Ok(code) if code != 0 && code != RESPONSE_PENDING => {
+                    self.answer(processid, Answer::Negative(code)).into()
+                }
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+
+            6 => CommandReturn::success_u32(u32::from(self.session.get())),
+
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifiers_are_registered_per_service() {
+        let mut app = App::default();
+        app.identifiers(READ_DATA_BY_IDENTIFIER)[0] = Some(0xF190);
+        app.identifiers(ROUTINE_CONTROL)[1] = Some(0xFF00);
+
+        assert!(app.handles(READ_DATA_BY_IDENTIFIER, 0xF190));
+        assert!(app.handles(WRITE_DATA_BY_IDENTIFIER, 0xF190));
+        assert!(!app.handles(ROUTINE_CONTROL, 0xF190));
+        assert!(app.handles(ROUTINE_CONTROL, 0xFF00));
+        assert!(!app.handles(READ_DATA_BY_IDENTIFIER, 0xFF00));
+    }
+}
@@ -5,16 +5,24 @@
 //! Communications Class Device for USB
 //!
 //! This capsule allows Tock to support a serial port over USB.
+//!
+//! The CDC-ACM device can either be the only client of the USB controller
+//! (see `CdcAcm::new()`), or one function of a composite device (see
+//! `CdcAcm::new_function()` and the `composite` module).
 
 use core::cell::Cell;
 use core::cmp;
 
+use super::composite::UsbFunction;
 use super::descriptors;
 use super::descriptors::Buffer64;
 use super::descriptors::CdcInterfaceDescriptor;
+use super::descriptors::Descriptor;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceAssociationDescriptor;
 use super::descriptors::InterfaceDescriptor;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
@@ -34,6 +42,8 @@ const ENDPOINT_IN_NUM: usize = 2;
 /// Identifying number for the endpoint when transferring data from the host to
 /// us.
 const ENDPOINT_OUT_NUM: usize = 3;
+/// Identifying number for the endpoint for notifications to the host.
+const ENDPOINT_NOTIFY_NUM: usize = 4;
 
 static LANGUAGES: &[u16; 1] = &[
     0x0409, // English (United States)
@@ -51,8 +61,6 @@ pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
 /// if a debug output is not connected.
 pub const CDC_BUFFER_TIMEOUT_MS: u32 = 10000;
 
-const N_ENDPOINTS: usize = 3;
-
 /// States of the CDC driver.
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum State {
@@ -89,12 +97,15 @@ enum CtrlState {
     SetLineCoding,
     /// Host has send a SET_CONTROL_LINE_STATE configuration request.
     SetControlLineState,
+    /// Host has sent a GET_LINE_CODING request.
+    GetLineCoding,
 }
 
 #[derive(PartialEq)]
 enum CDCCntrlMessage {
     NotSupported,
     SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
     SetControlLineState = 0x22,
     SendBreak = 0x23,
 }
@@ -103,6 +114,7 @@ impl From<u8> for CDCCntrlMessage {
     fn from(num: u8) -> Self {
         match num {
             0x20 => CDCCntrlMessage::SetLineCoding,
+            0x21 => CDCCntrlMessage::GetLineCoding,
             0x22 => CDCCntrlMessage::SetControlLineState,
             0x23 => CDCCntrlMessage::SendBreak,
             _ => CDCCntrlMessage::NotSupported,
@@ -116,8 +128,19 @@ pub struct CdcAcm<'a, U: 'a, A: 'a + Alarm<'a>> {
     /// Helper USB client library for handling many USB operations.
     client_ctrl: ClientCtrl<'a, 'static, U>,
 
-    /// 64 byte buffers for each endpoint.
-    buffers: [Buffer64; N_ENDPOINTS],
+    /// 64 byte buffer for the IN endpoint.
+    in_buffer: Buffer64,
+    /// 64 byte buffer for the OUT endpoint.
+    out_buffer: Buffer64,
+
+    /// Number of the communication interface, the data interface follows it.
+    interface: u8,
+    /// Endpoint for transferring data from us to the host.
+    endpoint_in: usize,
+    /// Endpoint for transferring data from the host to us.
+    endpoint_out: usize,
+    /// Endpoint for notifications to the host.
+    endpoint_notify: usize,
 
     /// Current state of the CDC driver. This helps us track if a CDC client is
     /// connected and listening or not.
@@ -191,74 +214,66 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         timeout_alarm: &'a A,
         host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
     ) -> Self {
-        let interfaces: &mut [InterfaceDescriptor] = &mut [
-            InterfaceDescriptor {
-                interface_number: 0,
-                interface_class: 0x02,    // CDC communication
-                interface_subclass: 0x02, // abstract control model (ACM)
-                interface_protocol: 0x01, // V.25ter (AT commands)
-                ..InterfaceDescriptor::default()
-            },
-            InterfaceDescriptor {
-                interface_number: 1,
-                interface_class: 0x0a,    // CDC data
-                interface_subclass: 0x00, // none
-                interface_protocol: 0x00, // none
-                ..InterfaceDescriptor::default()
-            },
-        ];
-
-        let cdc_descriptors: &mut [CdcInterfaceDescriptor] = &mut [
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
-                field1: 0x10, // CDC
-                field2: 0x11, // CDC
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
-                field1: 0x00, // Capabilities
-                field2: 0x01, // Data interface 1
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
-                field1: 0x06, // Capabilities
-                field2: 0x00, // unused
-            },
-            CdcInterfaceDescriptor {
-                subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
-                field1: 0x00, // Interface 0
-                field2: 0x01, // Interface 1
-            },
-        ];
-
-        let endpoints: &[&[EndpointDescriptor]] = &[
-            &[EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(4, TransferDirection::DeviceToHost),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 8,
-                interval: 16,
-            }],
-            &[
-                EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        2,
-                        TransferDirection::DeviceToHost,
-                    ),
-                    transfer_type: TransferType::Bulk,
-                    max_packet_size: 64,
-                    interval: 0,
-                },
-                EndpointDescriptor {
-                    endpoint_address: EndpointAddress::new_const(
-                        3,
-                        TransferDirection::HostToDevice,
-                    ),
-                    transfer_type: TransferType::Bulk,
-                    max_packet_size: 64,
-                    interval: 0,
-                },
-            ],
-        ];
+        Self::new_internal(
+            controller,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+            0,
+            ENDPOINT_IN_NUM,
+            ENDPOINT_OUT_NUM,
+            ENDPOINT_NOTIFY_NUM,
+            timeout_alarm,
+            host_initiated_function,
+        )
+    }
+
+    /// Create a CDC-ACM function for a composite device.
+    ///
+    /// The function uses the interfaces `interface` and `interface + 1`, and
+    /// the given endpoints. Requests on the default control endpoint are
+    /// handled by the composite device.
+    pub fn new_function(
+        controller: &'a U,
+        interface: u8,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        endpoint_notify: usize,
+        timeout_alarm: &'a A,
+        host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
+    ) -> Self {
+        Self::new_internal(
+            controller,
+            MAX_CTRL_PACKET_SIZE_NRF52840,
+            0,
+            0,
+            &["", "", ""],
+            interface,
+            endpoint_in,
+            endpoint_out,
+            endpoint_notify,
+            timeout_alarm,
+            host_initiated_function,
+        )
+    }
+
+    fn new_internal(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        interface: u8,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        endpoint_notify: usize,
+        timeout_alarm: &'a A,
+        host_initiated_function: Option<&'a (dyn Fn() + 'a)>,
+    ) -> Self {
+        let (mut interfaces, cdc_descriptors, notify_endpoints, data_endpoints) =
+            cdc_acm_descriptors(interface, endpoint_in, endpoint_out, endpoint_notify);
+        let endpoints: &[&[EndpointDescriptor]] = &[&notify_endpoints, &data_endpoints];
 
         let (device_descriptor_buffer, other_descriptor_buffer) =
             descriptors::create_descriptor_buffers(
@@ -273,10 +288,10 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                     ..descriptors::DeviceDescriptor::default()
                 },
                 descriptors::ConfigurationDescriptor::default(),
-                interfaces,
+                &mut interfaces,
                 endpoints,
                 None, // No HID descriptor
-                Some(cdc_descriptors),
+                Some(&cdc_descriptors),
             );
 
         Self {
@@ -289,11 +304,12 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
                 LANGUAGES,
                 strings,
             ),
-            buffers: [
-                Buffer64::default(),
-                Buffer64::default(),
-                Buffer64::default(),
-            ],
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            interface,
+            endpoint_in,
+            endpoint_out,
+            endpoint_notify,
             state: Cell::new(State::Disabled),
             ctrl_state: Cell::new(CtrlState::Idle),
             tx_buffer: TakeCell::empty(),
@@ -318,11 +334,6 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
         self.client_ctrl.controller()
     }
 
-    #[inline]
-    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
-        &self.buffers[i - 1].buf
-    }
-
     /// This is a helper function used to indicate successful uart transmission to
     /// a higher layer client despite not actually being connected to a host. Allows
     /// blocking debug interfaces to function in the same way they do when an actual UART
@@ -363,25 +374,18 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> CdcAcm<'a, U, A> {
             _ => {}
         }
     }
-}
 
-impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
-    for CdcAcm<'a, U, A>
-{
-    fn enable(&'a self) {
-        // Set up the default control endpoint
-        self.client_ctrl.enable();
-
-        // Setup buffers for IN and OUT data transfer.
+    /// Set up the IN and OUT endpoints for data transfer.
+    fn enable_endpoints(&'a self) {
         self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+            .endpoint_set_in_buffer(self.endpoint_in, &self.in_buffer.buf);
         self.controller()
-            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+            .endpoint_in_enable(TransferType::Bulk, self.endpoint_in);
 
         self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
+            .endpoint_set_out_buffer(self.endpoint_out, &self.out_buffer.buf);
         self.controller()
-            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+            .endpoint_out_enable(TransferType::Bulk, self.endpoint_out);
 
         self.state.set(State::Enabled);
 
@@ -391,6 +395,201 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
         );
     }
 
+    /// Handle a CDC request from the host. Returns false if the request is
+    /// not a CDC request we support.
+    ///
+    /// CDC uses special values here, and we can use these to know when a CDC
+    /// client is connected or not.
+    fn handle_cdc_request(&self, setup_data: &SetupData) -> bool {
+        match CDCCntrlMessage::from(setup_data.request_code) {
+            CDCCntrlMessage::SetLineCoding => {
+                self.ctrl_state.set(CtrlState::SetLineCoding);
+                true
+            }
+            CDCCntrlMessage::SetControlLineState => {
+                // Bit 0 and 1 of the value (setup_data.value) can be set
+                // D0: Indicates to DCE if DTE is present or not.
+                //     - 0 -> Not present
+                //     - 1 -> Present
+                // D1: Carrier control for half duplex modems.
+                //     - 0 -> Deactivate carrier
+                //     - 1 -> Activate carrier
+                //
+                // Currently we don't care about the value, just that this
+                // event has occurred. If it has happened, update the flag
+                // in `State::Connecting`.
+                self.set_connecting_state(false, true);
+
+                self.ctrl_state.set(CtrlState::SetControlLineState);
+                true
+            }
+            CDCCntrlMessage::SendBreak => {
+                // On Mac, we seem to get the SEND_BREAK to signal that a
+                // client disconnects.
+                self.state.set(State::Enumerated);
+                true
+            }
+            CDCCntrlMessage::GetLineCoding | CDCCntrlMessage::NotSupported => false,
+        }
+    }
+
+    /// Handle the data of a SET_LINE_CODING request.
+    fn set_line_coding(&self, packet: &[VolatileCell<u8>]) {
+        if self.ctrl_state.get() != CtrlState::SetLineCoding {
+            return;
+        }
+
+        // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
+        // We can parse the data we got.
+        descriptors::CdcAcmSetLineCodingData::get(packet).map(|line_coding| {
+            // If the device is configuring the baud rate to what we
+            // expect, we continue with the connecting process.
+            if line_coding.baud_rate == 115200 {
+                self.set_connecting_state(true, false);
+            }
+
+            // Check if the baud rate we got matches the special flag
+            // value (1200 baud). If so, we run an optional function
+            // provided when the CDC stack was configured.
+            if line_coding.baud_rate == 1200 {
+                self.host_initiated_function.map(|f| {
+                    f();
+                });
+            }
+        });
+    }
+
+    /// A CDC request has completed.
+    fn cdc_request_complete(&self) {
+        self.ctrl_state.set(CtrlState::Idle);
+
+        // Here we check to see if we just got connected to a CDC client. If so,
+        // we do a delay before transmitting if needed.
+        match self.state.get() {
+            State::Connecting {
+                line_coding,
+                line_state,
+            } => {
+                if line_coding && line_state {
+                    self.state.set(State::ConnectingDelay);
+
+                    // Wait a 100 ms before sending data.
+                    self.timeout_alarm.set_alarm(
+                        self.timeout_alarm.now(),
+                        self.timeout_alarm.ticks_from_ms(100),
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Build the interface, CDC functional and endpoint descriptors of a CDC-ACM
+/// device using interfaces `interface` and `interface + 1`.
+fn cdc_acm_descriptors(
+    interface: u8,
+    endpoint_in: usize,
+    endpoint_out: usize,
+    endpoint_notify: usize,
+) -> (
+    [InterfaceDescriptor; 2],
+    [CdcInterfaceDescriptor; 4],
+    [EndpointDescriptor; 1],
+    [EndpointDescriptor; 2],
+) {
+    let interfaces = [
+        InterfaceDescriptor {
+            interface_number: interface,
+            interface_class: 0x02,    // CDC communication
+            interface_subclass: 0x02, // abstract control model (ACM)
+            interface_protocol: 0x01, // V.25ter (AT commands)
+            num_endpoints: 1,
+            ..InterfaceDescriptor::default()
+        },
+        InterfaceDescriptor {
+            interface_number: interface + 1,
+            interface_class: 0x0a,    // CDC data
+            interface_subclass: 0x00, // none
+            interface_protocol: 0x00, // none
+            num_endpoints: 2,
+            ..InterfaceDescriptor::default()
+        },
+    ];
+
+    let cdc_descriptors = [
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::Header,
+            field1: 0x10, // CDC
+            field2: 0x11, // CDC
+        },
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::CallManagement,
+            field1: 0x00,          // Capabilities
+            field2: interface + 1, // Data interface
+        },
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::AbstractControlManagement,
+            field1: 0x06, // Capabilities
+            field2: 0x00, // unused
+        },
+        CdcInterfaceDescriptor {
+            subtype: descriptors::CdcInterfaceDescriptorSubType::Union,
+            field1: interface,     // Communication interface
+            field2: interface + 1, // Data interface
+        },
+    ];
+
+    let notify_endpoints = [EndpointDescriptor {
+        endpoint_address: EndpointAddress::new_const(
+            endpoint_notify,
+            TransferDirection::DeviceToHost,
+        ),
+        transfer_type: TransferType::Interrupt,
+        max_packet_size: 8,
+        interval: 16,
+    }];
+
+    let data_endpoints = [
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                endpoint_in,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: 64,
+            interval: 0,
+        },
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                endpoint_out,
+                TransferDirection::HostToDevice,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: 64,
+            interval: 0,
+        },
+    ];
+
+    (
+        interfaces,
+        cdc_descriptors,
+        notify_endpoints,
+        data_endpoints,
+    )
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
+    for CdcAcm<'a, U, A>
+{
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.enable_endpoints();
+    }
+
     fn attach(&'a self) {
         self.client_ctrl.attach();
         self.state.set(State::Attached);
@@ -402,40 +601,9 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     }
 
     /// Handle a Control Setup transaction.
-    ///
-    /// CDC uses special values here, and we can use these to know when a CDC
-    /// client is connected or not.
     fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
-        descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
-            let b_request = setup_data.request_code;
-
-            match CDCCntrlMessage::from(b_request) {
-                CDCCntrlMessage::SetLineCoding => {
-                    self.ctrl_state.set(CtrlState::SetLineCoding);
-                }
-                CDCCntrlMessage::SetControlLineState => {
-                    // Bit 0 and 1 of the value (setup_data.value) can be set
-                    // D0: Indicates to DCE if DTE is present or not.
-                    //     - 0 -> Not present
-                    //     - 1 -> Present
-                    // D1: Carrier control for half duplex modems.
-                    //     - 0 -> Deactivate carrier
-                    //     - 1 -> Activate carrier
-                    //
-                    // Currently we don't care about the value, just that this
-                    // event has occurred. If it has happened, update the flag
-                    // in `State::Connecting`.
-                    self.set_connecting_state(false, true);
-
-                    self.ctrl_state.set(CtrlState::SetControlLineState);
-                }
-                CDCCntrlMessage::SendBreak => {
-                    // On Mac, we seem to get the SEND_BREAK to signal that a
-                    // client disconnects.
-                    self.state.set(State::Enumerated)
-                }
-                _ => {}
-            }
+        SetupData::get(&self.client_ctrl.ctrl_buffer.buf).map(|setup_data| {
+            self.handle_cdc_request(&setup_data);
         });
 
         self.client_ctrl.ctrl_setup(endpoint)
@@ -448,32 +616,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
     /// Handle a Control Out transaction
     fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
-        // Check what state our Ctrl endpoint is in.
-        match self.ctrl_state.get() {
-            CtrlState::SetLineCoding => {
-                // We got a Ctrl SET_LINE_CODING setup, now we are getting the data.
-                // We can parse the data we got.
-                descriptors::CdcAcmSetLineCodingData::get(&self.client_ctrl.ctrl_buffer.buf).map(
-                    |line_coding| {
-                        // If the device is configuring the baud rate to what we
-                        // expect, we continue with the connecting process.
-                        if line_coding.baud_rate == 115200 {
-                            self.set_connecting_state(true, false);
-                        }
-
-                        // Check if the baud rate we got matches the special flag
-                        // value (1200 baud). If so, we run an optional function
-                        // provided when the CDC stack was configured.
-                        if line_coding.baud_rate == 1200 {
-                            self.host_initiated_function.map(|f| {
-                                f();
-                            });
-                        }
-                    },
-                );
-            }
-            _ => {}
-        }
+        self.set_line_coding(&self.client_ctrl.ctrl_buffer.buf);
 
         self.client_ctrl.ctrl_out(endpoint, packet_bytes)
     }
@@ -484,27 +627,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
-        self.ctrl_state.set(CtrlState::Idle);
-
-        // Here we check to see if we just got connected to a CDC client. If so,
-        // we do a delay before transmitting if needed.
-        match self.state.get() {
-            State::Connecting {
-                line_coding,
-                line_state,
-            } => {
-                if line_coding && line_state {
-                    self.state.set(State::ConnectingDelay);
-
-                    // Wait a 100 ms before sending data.
-                    self.timeout_alarm.set_alarm(
-                        self.timeout_alarm.now(),
-                        self.timeout_alarm.ticks_from_ms(100),
-                    );
-                }
-            }
-            _ => {}
-        }
+        self.cdc_request_complete();
 
         self.client_ctrl.ctrl_status_complete(endpoint)
     }
@@ -517,7 +640,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     /// `hil::usb::InResult::Delay` from this function. That means we can use
     /// this as a callback to mean that the transmission finished by waiting
     /// until this function is called when we don't have anything left to send.
-    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
         match transfer_type {
             TransferType::Bulk => {
                 self.tx_buffer
@@ -531,7 +654,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
 
                             // Get packet that we have shared with the underlying
                             // USB stack to copy the tx into.
-                            let packet = self.buffer(endpoint);
+                            let packet = &self.in_buffer.buf;
 
                             // Calculate how much more we can send.
                             let to_send = cmp::min(packet.len(), remaining);
@@ -575,7 +698,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     fn packet_out(
         &'a self,
         transfer_type: TransferType,
-        endpoint: usize,
+        _endpoint: usize,
         packet_bytes: u32,
     ) -> hil::usb::OutResult {
         match transfer_type {
@@ -590,7 +713,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
                     let copy_length = cmp::min(packet_bytes as usize, available_bytes);
 
                     // Do the copy into the RX buffer.
-                    let packet = self.buffer(endpoint);
+                    let packet = &self.out_buffer.buf;
                     for i in 0..copy_length {
                         rx_buf[rx_offset + i] = packet[i].get();
                     }
@@ -636,7 +759,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
             if remaining > 0 {
                 // We do, so ask to send again.
                 self.tx_buffer.replace(tx_buf);
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller().endpoint_resume_in(self.endpoint_in);
             } else {
                 // We don't have anything to send, so that means we are
                 // ok to signal the callback.
@@ -650,6 +773,112 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> hil::usb::Client<'a>
     }
 }
 
+impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> UsbFunction<'a> for CdcAcm<'a, U, A> {
+    fn interfaces(&self) -> (u8, u8) {
+        (self.interface, 2)
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint_in
+            || endpoint == self.endpoint_out
+            || endpoint == self.endpoint_notify
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+        let (interfaces, cdc_descriptors, notify_endpoints, data_endpoints) = cdc_acm_descriptors(
+            self.interface,
+            self.endpoint_in,
+            self.endpoint_out,
+            self.endpoint_notify,
+        );
+
+        f(&InterfaceAssociationDescriptor {
+            first_interface: self.interface,
+            interface_count: 2,
+            function_class: 0x02,    // CDC communication
+            function_subclass: 0x02, // abstract control model (ACM)
+            function_protocol: 0x01, // V.25ter (AT commands)
+            string_index: 0,
+        });
+        f(&interfaces[0]);
+        for descriptor in cdc_descriptors.iter() {
+            f(descriptor);
+        }
+        for descriptor in notify_endpoints.iter() {
+            f(descriptor);
+        }
+        f(&interfaces[1]);
+        for descriptor in data_endpoints.iter() {
+            f(descriptor);
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn bus_reset(&'a self) {
+        // We take a bus reset to mean the enumeration has finished.
+        self.state.set(State::Enumerated);
+    }
+
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        if self.handle_cdc_request(setup) {
+            hil::usb::CtrlSetupResult::Ok
+        } else if CDCCntrlMessage::from(setup.request_code) == CDCCntrlMessage::GetLineCoding {
+            self.ctrl_state.set(CtrlState::GetLineCoding);
+            hil::usb::CtrlSetupResult::Ok
+        } else {
+            hil::usb::CtrlSetupResult::ErrNonstandardRequest
+        }
+    }
+
+    fn ctrl_in(&'a self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::GetLineCoding => {
+                // We are not a real UART, so always report 115200 baud, 1
+                // stop bit, no parity and 8 data bits.
+                let line_coding = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+                for (cell, byte) in packet.iter().zip(line_coding.iter()) {
+                    cell.set(*byte);
+                }
+                hil::usb::CtrlInResult::Packet(line_coding.len(), true)
+            }
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    fn ctrl_out(
+        &'a self,
+        packet: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        self.set_line_coding(packet);
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        self.cdc_request_complete();
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
 impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Configure for CdcAcm<'a, U, A> {
     fn configure(&self, _parameters: uart::Parameters) -> Result<(), ErrorCode> {
         // Since this is not a real UART, we don't need to consider these
@@ -688,7 +917,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> uart::Transmit<'a>
             if self.state.get() == State::Connected {
                 // Then signal to the lower layer that we are ready to do a TX
                 // by putting data in the IN endpoint.
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller().endpoint_resume_in(self.endpoint_in);
                 Ok(())
             } else if self.boot_period.get() {
                 // indicate success because we will try to send it once a host connects
@@ -766,7 +995,7 @@ impl<'a, U: hil::usb::UsbController<'a>, A: 'a + Alarm<'a>> AlarmClient for CdcA
         if self.state.get() == State::ConnectingDelay {
             self.state.set(State::Connected);
             if self.tx_buffer.is_some() {
-                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                self.controller().endpoint_resume_in(self.endpoint_in);
             }
         } else {
             // no client has connected, but we do not want to block indefinitely, so go ahead
@@ -0,0 +1,619 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! CDC Ethernet Control Model (CDC-ECM) function for USB
+//!
+//! Presents an Ethernet link to the host over USB, so boards without a radio
+//! can still talk IP to a host, for example with the IPv6 stack in
+//! `net::ipv6::ipv6_ethernet`. Linux and macOS support CDC-ECM without
+//! drivers.
+//!
+//! The function has a communication interface with an interrupt endpoint for
+//! notifications, and a data interface with two alternate settings. The host
+//! selects alternate setting 1, which has the bulk endpoints, to bring the
+//! link up. Each Ethernet frame is sent as a sequence of full size packets
+//! ended by a short packet, or by a zero length packet if the frame length is
+//! a multiple of the packet size.
+//!
+//! The MAC address in the descriptors is the address of the host side of the
+//! link. The device side uses its own MAC address in the frames it sends.
+//!
+//! CDC-ECM is only available as a function of a composite device (see the
+//! `composite` module). The function exports the MAC address string with
+//! `mac_string_index`, which must not be one of the indexes 1 to 3 of the
+//! composite device.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let rx_buffer = static_init!([u8; 1514], [0; 1514]);
+//! let ecm = static_init!(
+//!     capsules_extra::usb::cdc_ecm::CdcEcm<'static, nrf52::usbd::Usbd>,
+//!     capsules_extra::usb::cdc_ecm::CdcEcm::new_function(
+//!         usb,
+//!         0,
+//!         1,
+//!         2,
+//!         3,
+//!         [0x02, 0x00, 0x00, 0x00, 0x00, 0x01],
+//!         4,
+//!         rx_buffer,
+//!     )
+//! );
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::UsbFunction;
+use super::descriptors::Buffer64;
+use super::descriptors::CdcEthernetNetworkingDescriptor;
+use super::descriptors::CdcInterfaceDescriptor;
+use super::descriptors::CdcInterfaceDescriptorSubType;
+use super::descriptors::Descriptor;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceAssociationDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+
+use kernel::hil;
+use kernel::hil::ethernet::{EthernetAdapterDatapath, EthernetAdapterDatapathClient};
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Max packet size of the bulk endpoints.
+const MAX_PACKET_SIZE: usize = 64;
+
+/// Max packet size of the notification endpoint, large enough for the
+/// connection speed change notification.
+const NOTIFICATION_PACKET_SIZE: usize = 16;
+
+/// Length of the receive buffer, the largest frame we accept.
+pub const RX_BUFFER_LEN: usize = hil::ethernet::MAX_FRAME_LEN;
+
+/// Bit rate we report to the host, the signalling rate of full speed USB.
+const BIT_RATE: u32 = 12_000_000;
+
+// CDC-ECM class specific requests. We do not filter frames, so the filter
+// requests are accepted and ignored.
+const SET_ETHERNET_MULTICAST_FILTERS: u8 = 0x40;
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+
+// CDC notifications.
+const NETWORK_CONNECTION: u8 = 0x00;
+const CONNECTION_SPEED_CHANGE: u8 = 0x2a;
+
+/// Notifications sent to the host when the link comes up.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Notification {
+    /// Nothing to send.
+    None,
+    /// Send NETWORK_CONNECTION next.
+    Connection,
+    /// Send CONNECTION_SPEED_CHANGE next.
+    Speed,
+}
+
+/// The frame being sent to the host.
+#[derive(Copy, Clone)]
+struct Transmission {
+    /// Length of the frame.
+    len: usize,
+    /// Number of bytes of the frame written to the IN endpoint so far.
+    offset: usize,
+    /// True if a zero length packet must follow the frame.
+    zero_length_packet: bool,
+    /// Identifier passed back to the client in `tx_done()`.
+    identifier: usize,
+}
+
+pub struct CdcEcm<'a, U: 'a> {
+    controller: &'a U,
+
+    /// Number of the communication interface, the data interface follows it.
+    interface: u8,
+    /// Interrupt endpoint for notifications.
+    endpoint_notify: usize,
+    /// Bulk endpoint for frames to the host.
+    endpoint_in: usize,
+    /// Bulk endpoint for frames from the host.
+    endpoint_out: usize,
+
+    notify_buffer: Buffer64,
+    in_buffer: Buffer64,
+    out_buffer: Buffer64,
+
+    /// MAC address of the host side of the link, as a string descriptor.
+    mac_string: [u8; 12],
+    mac_string_index: u8,
+
+    /// Alternate setting of the data interface. The link is up in setting 1.
+    alternate_setting: Cell<u8>,
+    notification: Cell<Notification>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx: Cell<Option<Transmission>>,
+
+    /// Buffer the packets of a frame from the host are collected in.
+    rx_buffer: TakeCell<'static, [u8]>,
+    /// Number of bytes of the current frame received so far.
+    rx_len: Cell<usize>,
+    /// The current frame does not fit into the receive buffer and is dropped.
+    rx_overflow: Cell<bool>,
+    receive_enabled: Cell<bool>,
+
+    client: OptionalCell<&'a dyn EthernetAdapterDatapathClient>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CdcEcm<'a, U> {
+    /// Create the CDC-ECM function of a composite device, with the
+    /// communication interface `interface` and the data interface after it.
+    /// `host_mac` is the MAC address the host uses for its end of the link.
+    pub fn new_function(
+        controller: &'a U,
+        interface: u8,
+        endpoint_notify: usize,
+        endpoint_in: usize,
+        endpoint_out: usize,
+        host_mac: [u8; 6],
+        mac_string_index: u8,
+        rx_buffer: &'static mut [u8],
+    ) -> Self {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        let mut mac_string = [0; 12];
+        for (i, byte) in host_mac.iter().enumerate() {
+            mac_string[2 * i] = HEX[(byte >> 4) as usize];
+            mac_string[2 * i + 1] = HEX[(byte & 0xf) as usize];
+        }
+
+        Self {
+            controller,
+            interface,
+            endpoint_notify,
+            endpoint_in,
+            endpoint_out,
+            notify_buffer: Buffer64::default(),
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            mac_string,
+            mac_string_index,
+            alternate_setting: Cell::new(0),
+            notification: Cell::new(Notification::None),
+            tx_buffer: TakeCell::empty(),
+            tx: Cell::new(None),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_len: Cell::new(0),
+            rx_overflow: Cell::new(false),
+            receive_enabled: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// True if the host selected the alternate setting with the bulk
+    /// endpoints, so frames can be exchanged.
+    pub fn link_up(&self) -> bool {
+        self.alternate_setting.get() == 1
+    }
+
+    /// Take the link down, and fail the frame being sent.
+    fn link_down(&self) {
+        self.alternate_setting.set(0);
+        self.notification.set(Notification::None);
+        self.rx_len.set(0);
+        self.rx_overflow.set(false);
+        if let Some(tx) = self.tx.take() {
+            self.tx_done(Err(ErrorCode::OFF), tx);
+        }
+    }
+
+    fn tx_done(&self, result: Result<(), ErrorCode>, tx: Transmission) {
+        self.tx_buffer.take().map(|buffer| {
+            self.client.map(move |client| {
+                client.tx_done(result, buffer, tx.len as u16, tx.identifier, None)
+            });
+        });
+    }
+
+    /// Write the next notification into the notification packet, and return
+    /// its length.
+    fn write_notification(&self) -> Option<usize> {
+        let packet = &self.notify_buffer.buf;
+        let (notification, value, length) = match self.notification.get() {
+            Notification::None => return None,
+            Notification::Connection => (NETWORK_CONNECTION, 1, 0),
+            Notification::Speed => (CONNECTION_SPEED_CHANGE, 0, 8),
+        };
+        packet[0].set(0xa1); // Class request to an interface, device to host
+        packet[1].set(notification);
+        packet[2].set(value);
+        packet[3].set(0);
+        packet[4].set(self.interface);
+        packet[5].set(0);
+        packet[6].set(length);
+        packet[7].set(0);
+        if length > 0 {
+            // Downstream and upstream bit rate.
+            for (i, byte) in BIT_RATE
+                .to_le_bytes()
+                .iter()
+                .chain(BIT_RATE.to_le_bytes().iter())
+                .enumerate()
+            {
+                packet[8 + i].set(*byte);
+            }
+        }
+        Some(8 + length as usize)
+    }
+
+    /// Write the next packet of the frame being sent into the IN packet, and
+    /// return its length.
+    fn write_frame_packet(&self) -> Option<usize> {
+        let mut tx = self.tx.get()?;
+        let len = if tx.offset < tx.len {
+            self.tx_buffer.map_or(0, |buffer| {
+                let len = cmp::min(MAX_PACKET_SIZE, tx.len - tx.offset);
+                for (cell, byte) in self
+                    .in_buffer
+                    .buf
+                    .iter()
+                    .zip(buffer[tx.offset..tx.offset + len].iter())
+                {
+                    cell.set(*byte);
+                }
+                len
+            })
+        } else if tx.zero_length_packet {
+            tx.zero_length_packet = false;
+            0
+        } else {
+            return None;
+        };
+        tx.offset += len;
+        self.tx.set(Some(tx));
+        Some(len)
+    }
+}
+
+/// Build the descriptors of a CDC-ECM function.
+fn ecm_descriptors(
+    interface: u8,
+    endpoint_notify: usize,
+    endpoint_in: usize,
+    endpoint_out: usize,
+    mac_string_index: u8,
+) -> (
+    [InterfaceDescriptor; 3],
+    [CdcInterfaceDescriptor; 2],
+    CdcEthernetNetworkingDescriptor,
+    EndpointDescriptor,
+    [EndpointDescriptor; 2],
+) {
+    let interfaces = [
+        InterfaceDescriptor {
+            interface_number: interface,
+            interface_class: 0x02,    // CDC communication
+            interface_subclass: 0x06, // Ethernet networking control model
+            interface_protocol: 0x00,
+            num_endpoints: 1,
+            ..InterfaceDescriptor::default()
+        },
+        InterfaceDescriptor {
+            interface_number: interface + 1,
+            alternate_setting: 0,
+            interface_class: 0x0a, // CDC data
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            num_endpoints: 0,
+            ..InterfaceDescriptor::default()
+        },
+        InterfaceDescriptor {
+            interface_number: interface + 1,
+            alternate_setting: 1,
+            interface_class: 0x0a, // CDC data
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            num_endpoints: 2,
+            ..InterfaceDescriptor::default()
+        },
+    ];
+
+    let cdc_descriptors = [
+        CdcInterfaceDescriptor {
+            subtype: CdcInterfaceDescriptorSubType::Header,
+            field1: 0x10, // CDC
+            field2: 0x11, // CDC
+        },
+        CdcInterfaceDescriptor {
+            subtype: CdcInterfaceDescriptorSubType::Union,
+            field1: interface,     // Communication interface
+            field2: interface + 1, // Data interface
+        },
+    ];
+
+    let ethernet_descriptor = CdcEthernetNetworkingDescriptor {
+        mac_address_string: mac_string_index,
+        max_segment_size: RX_BUFFER_LEN as u16,
+    };
+
+    let notify_endpoint = EndpointDescriptor {
+        endpoint_address: EndpointAddress::new_const(
+            endpoint_notify,
+            TransferDirection::DeviceToHost,
+        ),
+        transfer_type: TransferType::Interrupt,
+        max_packet_size: NOTIFICATION_PACKET_SIZE as u16,
+        interval: 32,
+    };
+
+    let data_endpoints = [
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                endpoint_in,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: MAX_PACKET_SIZE as u16,
+            interval: 0,
+        },
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                endpoint_out,
+                TransferDirection::HostToDevice,
+            ),
+            transfer_type: TransferType::Bulk,
+            max_packet_size: MAX_PACKET_SIZE as u16,
+            interval: 0,
+        },
+    ];
+
+    (
+        interfaces,
+        cdc_descriptors,
+        ethernet_descriptor,
+        notify_endpoint,
+        data_endpoints,
+    )
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for CdcEcm<'a, U> {
+    fn interfaces(&self) -> (u8, u8) {
+        (self.interface, 2)
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint_notify
+            || endpoint == self.endpoint_in
+            || endpoint == self.endpoint_out
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+        let (interfaces, cdc_descriptors, ethernet_descriptor, notify_endpoint, data_endpoints) =
+            ecm_descriptors(
+                self.interface,
+                self.endpoint_notify,
+                self.endpoint_in,
+                self.endpoint_out,
+                self.mac_string_index,
+            );
+        f(&InterfaceAssociationDescriptor {
+            first_interface: self.interface,
+            interface_count: 2,
+            function_class: 0x02,    // CDC communication
+            function_subclass: 0x06, // Ethernet networking control model
+            function_protocol: 0x00,
+            string_index: 0,
+        });
+        f(&interfaces[0]);
+        for descriptor in cdc_descriptors.iter() {
+            f(descriptor);
+        }
+        f(&ethernet_descriptor);
+        f(&notify_endpoint);
+        f(&interfaces[1]);
+        f(&interfaces[2]);
+        for descriptor in data_endpoints.iter() {
+            f(descriptor);
+        }
+    }
+
+    fn alternate_setting(&self, interface: u8) -> u8 {
+        if interface == self.interface + 1 {
+            self.alternate_setting.get()
+        } else {
+            0
+        }
+    }
+
+    fn set_alternate_setting(&'a self, interface: u8, alternate_setting: u8) -> bool {
+        if interface != self.interface + 1 {
+            return alternate_setting == 0;
+        }
+        match alternate_setting {
+            0 => {
+                self.link_down();
+                true
+            }
+            1 => {
+                if !self.link_up() {
+                    self.alternate_setting.set(1);
+                    self.notification.set(Notification::Connection);
+                    self.controller.endpoint_resume_in(self.endpoint_notify);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn string(&self, index: u8) -> Option<&str> {
+        if index == self.mac_string_index {
+            core::str::from_utf8(&self.mac_string).ok()
+        } else {
+            None
+        }
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_notify, &self.notify_buffer.buf);
+        self.controller
+            .endpoint_in_enable(TransferType::Interrupt, self.endpoint_notify);
+
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint_in, &self.in_buffer.buf);
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint_out, &self.out_buffer.buf);
+        if self.endpoint_in == self.endpoint_out {
+            self.controller
+                .endpoint_in_out_enable(TransferType::Bulk, self.endpoint_in);
+        } else {
+            self.controller
+                .endpoint_in_enable(TransferType::Bulk, self.endpoint_in);
+            self.controller
+                .endpoint_out_enable(TransferType::Bulk, self.endpoint_out);
+        }
+    }
+
+    fn bus_reset(&'a self) {
+        self.link_down();
+    }
+
+    fn configured(&'a self) {
+        // Selecting a configuration resets the alternate settings.
+        self.link_down();
+    }
+
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        match setup.request_type.request_type() {
+            RequestType::Class
+                if (SET_ETHERNET_MULTICAST_FILTERS..=SET_ETHERNET_PACKET_FILTER)
+                    .contains(&setup.request_code) =>
+            {
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        if !matches!(transfer_type, TransferType::Interrupt | TransferType::Bulk) {
+            return hil::usb::InResult::Error;
+        }
+        let len = if endpoint == self.endpoint_notify {
+            self.write_notification()
+        } else if endpoint == self.endpoint_in {
+            self.write_frame_packet()
+        } else {
+            return hil::usb::InResult::Error;
+        };
+        len.map_or(hil::usb::InResult::Delay, hil::usb::InResult::Packet)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        if !matches!(transfer_type, TransferType::Bulk) || endpoint != self.endpoint_out {
+            return hil::usb::OutResult::Error;
+        }
+        let packet_bytes = packet_bytes as usize;
+        self.rx_buffer.map(|buffer| {
+            let offset = self.rx_len.get();
+            if offset + packet_bytes > buffer.len() {
+                self.rx_overflow.set(true);
+            } else {
+                for (byte, cell) in buffer[offset..offset + packet_bytes]
+                    .iter_mut()
+                    .zip(self.out_buffer.buf.iter())
+                {
+                    *byte = cell.get();
+                }
+                self.rx_len.set(offset + packet_bytes);
+            }
+
+            // A short packet ends the frame.
+            if packet_bytes < MAX_PACKET_SIZE {
+                let len = self.rx_len.get();
+                if !self.rx_overflow.get() && len > 0 && self.receive_enabled.get() {
+                    self.client
+                        .map(|client| client.rx_frame(&buffer[..len], None));
+                }
+                self.rx_len.set(0);
+                self.rx_overflow.set(false);
+            }
+        });
+        hil::usb::OutResult::Ok
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        if endpoint == self.endpoint_notify {
+            match self.notification.get() {
+                Notification::Connection => {
+                    self.notification.set(Notification::Speed);
+                    self.controller.endpoint_resume_in(self.endpoint_notify);
+                }
+                Notification::Speed | Notification::None => {
+                    self.notification.set(Notification::None);
+                }
+            }
+        } else if endpoint == self.endpoint_in {
+            if let Some(tx) = self.tx.get() {
+                if tx.offset >= tx.len && !tx.zero_length_packet {
+                    self.tx.set(None);
+                    self.tx_done(Ok(()), tx);
+                } else {
+                    self.controller.endpoint_resume_in(self.endpoint_in);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> EthernetAdapterDatapath<'a> for CdcEcm<'a, U> {
+    fn set_client(&self, client: &'a dyn EthernetAdapterDatapathClient) {
+        self.client.set(client);
+    }
+
+    fn enable_receive(&self) {
+        self.receive_enabled.set(true);
+    }
+
+    fn disable_receive(&self) {
+        self.receive_enabled.set(false);
+    }
+
+    fn transmit_frame(
+        &self,
+        frame_buffer: &'static mut [u8],
+        len: u16,
+        transmission_identifier: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let len = len as usize;
+        if !self.link_up() {
+            return Err((ErrorCode::OFF, frame_buffer));
+        }
+        if len > RX_BUFFER_LEN || len > frame_buffer.len() {
+            return Err((ErrorCode::SIZE, frame_buffer));
+        }
+        if self.tx.get().is_some() {
+            return Err((ErrorCode::BUSY, frame_buffer));
+        }
+        self.tx_buffer.replace(frame_buffer);
+        self.tx.set(Some(Transmission {
+            len,
+            offset: 0,
+            zero_length_packet: len % MAX_PACKET_SIZE == 0,
+            identifier: transmission_identifier,
+        }));
+        self.controller.endpoint_resume_in(self.endpoint_in);
+        Ok(())
+    }
+}
@@ -0,0 +1,1079 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! USB composite device
+//!
+//! Combines several USB functions, for example a CDC-ACM console, a CTAP
+//! security key and a keyboard, into one device with a single configuration.
+//!
+//! The composite device is the client of the USB controller. It handles the
+//! standard requests on the default control endpoint, and builds the
+//! configuration descriptor from the descriptors of all functions. Class and
+//! vendor specific requests, and the transfers on all other endpoints, are
+//! passed to the function that owns the interface or endpoint.
+//!
+//! ```text
+//!                    UsbController
+//!                          |
+//!                   CompositeDevice
+//!                   /      |      \
+//!              CdcAcm   CtapHid   KeyboardHid
+//! ```
+//!
+//! Each function is created with the interface and endpoint numbers it uses.
+//! The interfaces of the functions must be numbered consecutively from 0, and
+//! no two functions may use the same endpoint.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let cdc = CdcAcm::new_function(usb, 0, 2, 3, 4, alarm, None);
+//! let ctap = CtapHid::new_function(usb, 2, 1);
+//! let keyboard = KeyboardHid::new_function(usb, 3, 5);
+//!
+//! let functions = static_init!(
+//!     [&'static dyn UsbFunction<'static>; 3],
+//!     [cdc, ctap, keyboard]
+//! );
+//! let composite = static_init!(
+//!     CompositeDevice<'static, nrf52::usbd::Usbd>,
+//!     CompositeDevice::new(usb, 64, 0x1915, 0x521f, STRINGS, functions)
+//! );
+//! usb.set_client(composite);
+//! composite.enable();
+//! composite.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+
+use super::descriptors::Buffer64;
+use super::descriptors::ConfigurationDescriptor;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::DeviceDescriptor;
+use super::descriptors::LanguagesDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::StandardRequest;
+use super::descriptors::StringDescriptor;
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::VolatileCell;
+
+/// Size of the buffer for the configuration descriptor and other responses
+/// to control requests.
+const DESCRIPTOR_BUFLEN: usize = 256;
+
+static LANGUAGES: &[u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// A function of a composite USB device.
+///
+/// The composite device calls these methods for the requests and transfers
+/// that concern the interfaces and endpoints of the function.
+pub trait UsbFunction<'a> {
+    /// The interfaces of this function, as the number of its first interface
+    /// and the number of interfaces.
+    fn interfaces(&self) -> (u8, u8);
+
+    /// Returns true if `endpoint` belongs to this function.
+    fn has_endpoint(&self, endpoint: usize) -> bool;
+
+    /// Calls `f` with each descriptor of the function, in the order they must
+    /// appear in the configuration descriptor.
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor));
+
+    /// Returns the class specific descriptor of `descriptor_type` the host
+    /// requested from `interface`, for example the HID report descriptor.
+    fn interface_descriptor(
+        &self,
+        _interface: u8,
+        _descriptor_type: DescriptorType,
+    ) -> Option<&dyn Descriptor> {
+        None
+    }
+
+    /// Returns the alternate setting that is selected for `interface`.
+    fn alternate_setting(&self, _interface: u8) -> u8 {
+        0
+    }
+
+    /// The host selected `alternate_setting` for `interface`. Returns false
+    /// if the interface has no such alternate setting.
+    fn set_alternate_setting(&'a self, _interface: u8, alternate_setting: u8) -> bool {
+        alternate_setting == 0
+    }
+
+    /// Returns the string with `index`, for strings the function refers to
+    /// from its descriptors. The composite device uses indexes 1 to 3 for its
+    /// own strings.
+    fn string(&self, _index: u8) -> Option<&str> {
+        None
+    }
+
+    /// Set up the endpoints of the function. The default control endpoint is
+    /// set up by the composite device.
+    fn enable(&'a self);
+
+    /// The bus was reset.
+    fn bus_reset(&'a self) {}
+
+    /// The host selected the configuration, the function can now be used.
+    fn configured(&'a self) {}
+
+    /// Handle a class or vendor specific request to an interface or endpoint
+    /// of this function. If this returns `Ok`, the data stage of the request
+    /// is passed to `ctrl_in()` or `ctrl_out()`.
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult;
+
+    /// Write the next packet of the data stage of a request into `packet`.
+    fn ctrl_in(&'a self, _packet: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        hil::usb::CtrlInResult::Error
+    }
+
+    /// Handle a packet received in the data stage of a request.
+    fn ctrl_out(
+        &'a self,
+        _packet: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    /// A request accepted by `ctrl_setup()` has completed.
+    fn ctrl_status_complete(&'a self) {}
+
+    /// Handle a Bulk/Interrupt IN transaction on an endpoint of the function.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult;
+
+    /// Handle a Bulk/Interrupt OUT transaction on an endpoint of the function.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult;
+
+    /// A packet was transmitted on an endpoint of the function.
+    fn packet_transmitted(&'a self, endpoint: usize);
+}
+
+/// States of the default control endpoint.
+#[derive(Copy, Clone)]
+enum State {
+    Init,
+
+    /// We are doing a Control In transfer of some data in
+    /// self.descriptor_storage, with the given extent remaining to send.
+    CtrlIn(usize, usize),
+
+    SetAddress,
+
+    SetConfiguration,
+
+    /// The function with this index handles the current request.
+    Function(usize),
+}
+
+/// A USB device made of several functions.
+pub struct CompositeDevice<'a, U: 'a> {
+    /// The USB hardware controller.
+    controller: &'a U,
+
+    /// The functions of the device.
+    functions: &'a [&'a dyn UsbFunction<'a>],
+
+    /// Descriptor of the device.
+    device_descriptor: DeviceDescriptor,
+
+    /// State of the default control endpoint.
+    state: Cell<State>,
+
+    /// The configuration selected by the host, 0 if not configured.
+    configuration: Cell<u8>,
+
+    /// A 64-byte buffer for the control endpoint to be passed to the USB
+    /// driver.
+    ctrl_buffer: Buffer64,
+
+    /// Storage for composing responses to control requests.
+    descriptor_storage: [Cell<u8>; DESCRIPTOR_BUFLEN],
+
+    /// USB strings for the manufacturer, product and serial number.
+    strings: &'a [&'a str],
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> CompositeDevice<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'a [&'a str; 3],
+        functions: &'a [&'a dyn UsbFunction<'a>],
+    ) -> Self {
+        Self {
+            controller,
+            functions,
+            device_descriptor: DeviceDescriptor {
+                vendor_id,
+                product_id,
+                manufacturer_string: 1,
+                product_string: 2,
+                serial_number_string: 3,
+                // Miscellaneous device class with interface association
+                // descriptors.
+                class: 0xef,
+                subclass: 0x02,
+                protocol: 0x01,
+                max_packet_size_ep0: max_ctrl_packet_size,
+                ..DeviceDescriptor::default()
+            },
+            state: Cell::new(State::Init),
+            configuration: Cell::new(0),
+            ctrl_buffer: Buffer64::default(),
+            descriptor_storage: [(); DESCRIPTOR_BUFLEN].map(|()| Cell::default()),
+            strings,
+        }
+    }
+
+    fn function_for_interface(&self, interface: u8) -> Option<usize> {
+        self.functions.iter().position(|function| {
+            let (first, count) = function.interfaces();
+            interface >= first && interface - first < count
+        })
+    }
+
+    fn function_for_endpoint(&self, endpoint: usize) -> Option<&'a dyn UsbFunction<'a>> {
+        self.functions
+            .iter()
+            .find(|function| function.has_endpoint(endpoint))
+            .copied()
+    }
+
+    /// Write the configuration descriptor followed by the descriptors of all
+    /// functions into the descriptor storage. Returns the length, or `None`
+    /// if the descriptors do not fit.
+    fn write_configuration(&self) -> Option<usize> {
+        let mut num_interfaces = 0;
+        let mut related_descriptor_length = 0;
+        for function in self.functions {
+            num_interfaces += function.interfaces().1;
+            function.descriptors(&mut |descriptor| {
+                related_descriptor_length += descriptor.size();
+            });
+        }
+
+        let configuration = ConfigurationDescriptor {
+            num_interfaces,
+            related_descriptor_length,
+            ..ConfigurationDescriptor::default()
+        };
+        if configuration.size() + related_descriptor_length > self.descriptor_storage.len() {
+            return None;
+        }
+
+        let buf = &self.descriptor_storage;
+        let mut len = configuration.write_to(buf);
+        for function in self.functions {
+            function.descriptors(&mut |descriptor| {
+                len += descriptor.write_to(&buf[len..]);
+            });
+        }
+        Some(len)
+    }
+
+    /// Send the first `len` bytes of the descriptor storage in the data stage,
+    /// at most as many as the host requested.
+    fn send(&self, len: usize, requested_length: u16) -> hil::usb::CtrlSetupResult {
+        let end = min(len, requested_length as usize);
+        self.state.set(State::CtrlIn(0, end));
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    /// Send a short response, for example a status.
+    fn send_bytes(&self, bytes: &[u8], requested_length: u16) -> hil::usb::CtrlSetupResult {
+        for (cell, byte) in self.descriptor_storage.iter().zip(bytes) {
+            cell.set(*byte);
+        }
+        self.send(bytes.len(), requested_length)
+    }
+
+    fn send_descriptor(
+        &self,
+        descriptor: &dyn Descriptor,
+        requested_length: u16,
+    ) -> hil::usb::CtrlSetupResult {
+        let len = descriptor.write_to(&self.descriptor_storage);
+        if len == 0 {
+            return hil::usb::CtrlSetupResult::ErrGeneric;
+        }
+        self.send(len, requested_length)
+    }
+
+    fn handle_standard_device_request(
+        &self,
+        request: StandardRequest,
+        requested_length: u16,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            StandardRequest::GetDescriptor {
+                descriptor_type,
+                descriptor_index,
+                lang_id,
+                requested_length,
+            } => match descriptor_type {
+                DescriptorType::Device => match descriptor_index {
+                    0 => self.send_descriptor(&self.device_descriptor, requested_length),
+                    _ => hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex,
+                },
+                DescriptorType::Configuration => match descriptor_index {
+                    0 => self
+                        .write_configuration()
+                        .map_or(hil::usb::CtrlSetupResult::ErrGeneric, |len| {
+                            self.send(len, requested_length)
+                        }),
+                    _ => hil::usb::CtrlSetupResult::ErrInvalidConfigurationIndex,
+                },
+                DescriptorType::String => match descriptor_index {
+                    0 => self.send_descriptor(
+                        &LanguagesDescriptor { langs: LANGUAGES },
+                        requested_length,
+                    ),
+                    i if i > 0 && (i as usize) <= self.strings.len() && lang_id == LANGUAGES[0] => {
+                        self.send_descriptor(
+                            &StringDescriptor {
+                                string: self.strings[i as usize - 1],
+                            },
+                            requested_length,
+                        )
+                    }
+                    i if lang_id == LANGUAGES[0] => self
+                        .functions
+                        .iter()
+                        .find_map(|function| function.string(i))
+                        .map_or(hil::usb::CtrlSetupResult::ErrInvalidStringIndex, |string| {
+                            self.send_descriptor(&StringDescriptor { string }, requested_length)
+                        }),
+                    _ => hil::usb::CtrlSetupResult::ErrInvalidStringIndex,
+                },
+                DescriptorType::DeviceQualifier => {
+                    // We are full-speed only, so we must respond with a
+                    // request error.
+                    hil::usb::CtrlSetupResult::ErrNoDeviceQualifier
+                }
+                _ => hil::usb::CtrlSetupResult::ErrUnrecognizedDescriptorType,
+            },
+            StandardRequest::SetAddress { device_address } => {
+                // Load the address we've been assigned, it is enabled when
+                // this request gets to the Status stage.
+                self.controller.set_address(device_address);
+                self.state.set(State::SetAddress);
+                hil::usb::CtrlSetupResult::OkSetAddress
+            }
+            StandardRequest::SetConfiguration {
+                configuration_value,
+            } => match configuration_value {
+                0 | 1 => {
+                    self.configuration.set(configuration_value);
+                    self.state.set(State::SetConfiguration);
+                    hil::usb::CtrlSetupResult::Ok
+                }
+                _ => hil::usb::CtrlSetupResult::ErrInvalidConfigurationIndex,
+            },
+            StandardRequest::GetConfiguration => {
+                self.send_bytes(&[self.configuration.get()], requested_length)
+            }
+            StandardRequest::GetStatus { .. } => {
+                // Self powered, no remote wakeup.
+                self.send_bytes(&[1, 0], requested_length)
+            }
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    fn handle_standard_interface_request(
+        &self,
+        request: StandardRequest,
+        interface: u8,
+        value: u16,
+        requested_length: u16,
+    ) -> hil::usb::CtrlSetupResult {
+        let function = match self.function_for_interface(interface) {
+            Some(index) => self.functions[index],
+            None => return hil::usb::CtrlSetupResult::ErrInvalidInterfaceIndex,
+        };
+        match request {
+            StandardRequest::GetDescriptor {
+                descriptor_type,
+                requested_length,
+                ..
+            } => function
+                .interface_descriptor(interface, descriptor_type)
+                .map_or(
+                    hil::usb::CtrlSetupResult::ErrUnrecognizedDescriptorType,
+                    |descriptor| self.send_descriptor(descriptor, requested_length),
+                ),
+            StandardRequest::GetInterface { .. } => {
+                self.send_bytes(&[function.alternate_setting(interface)], requested_length)
+            }
+            StandardRequest::SetInterface => {
+                if function.set_alternate_setting(interface, value as u8) {
+                    hil::usb::CtrlSetupResult::Ok
+                } else {
+                    hil::usb::CtrlSetupResult::ErrGeneric
+                }
+            }
+            StandardRequest::GetStatus { .. } => self.send_bytes(&[0, 0], requested_length),
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    fn handle_standard_endpoint_request(
+        &self,
+        request: StandardRequest,
+        requested_length: u16,
+    ) -> hil::usb::CtrlSetupResult {
+        match request {
+            // Endpoints are never halted, so there is nothing to do.
+            StandardRequest::ClearFeature { .. } | StandardRequest::SetFeature { .. } => {
+                hil::usb::CtrlSetupResult::Ok
+            }
+            StandardRequest::GetStatus { .. } => self.send_bytes(&[0, 0], requested_length),
+            _ => hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    /// Pass a class or vendor specific request to the function that owns the
+    /// interface or endpoint it is addressed to.
+    fn handle_function_request(&self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        match setup.request_type.request_type() {
+            RequestType::Class | RequestType::Vendor => {}
+            RequestType::Standard | RequestType::Reserved => {
+                return hil::usb::CtrlSetupResult::ErrUnrecognizedRequestType
+            }
+        }
+        let index = match setup.request_type.recipient() {
+            Recipient::Interface => self.function_for_interface(setup.index as u8),
+            Recipient::Endpoint => {
+                let endpoint = (setup.index & 0xf) as usize;
+                self.functions
+                    .iter()
+                    .position(|function| function.has_endpoint(endpoint))
+            }
+            _ => return hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        };
+        match index {
+            Some(index) => {
+                let result = self.functions[index].ctrl_setup(setup);
+                if let hil::usb::CtrlSetupResult::Ok = result {
+                    self.state.set(State::Function(index));
+                }
+                result
+            }
+            None => hil::usb::CtrlSetupResult::ErrInvalidInterfaceIndex,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CompositeDevice<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.controller
+            .endpoint_set_ctrl_buffer(&self.ctrl_buffer.buf);
+        self.controller
+            .enable_as_device(hil::usb::DeviceSpeed::Full); // must be Full for Bulk transfers
+        self.controller
+            .endpoint_out_enable(TransferType::Control, 0);
+
+        for function in self.functions {
+            function.enable();
+        }
+    }
+
+    fn attach(&'a self) {
+        self.controller.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.state.set(State::Init);
+        self.configuration.set(0);
+        for function in self.functions {
+            function.bus_reset();
+        }
+    }
+
+    /// Handle a Control Setup transaction
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        if endpoint != 0 {
+            // For now we only support the default Control endpoint
+            return hil::usb::CtrlSetupResult::ErrInvalidDeviceIndex;
+        }
+        let setup = match SetupData::get(&self.ctrl_buffer.buf) {
+            Some(setup) => setup,
+            None => return hil::usb::CtrlSetupResult::ErrNoParse,
+        };
+        match setup.get_standard_request() {
+            Some(request) => match setup.request_type.recipient() {
+                Recipient::Device => self.handle_standard_device_request(request, setup.length),
+                Recipient::Interface => self.handle_standard_interface_request(
+                    request,
+                    setup.index as u8,
+                    setup.value,
+                    setup.length,
+                ),
+                Recipient::Endpoint => self.handle_standard_endpoint_request(request, setup.length),
+                _ => hil::usb::CtrlSetupResult::ErrGeneric,
+            },
+            None => self.handle_function_request(&setup),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, _endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.state.get() {
+            State::CtrlIn(start, end) => {
+                let len = end.saturating_sub(start);
+                if len > 0 {
+                    let packet_bytes = min(self.ctrl_buffer.buf.len(), len);
+                    let packet = &self.descriptor_storage[start..start + packet_bytes];
+                    let buf = &self.ctrl_buffer.buf;
+
+                    // Copy a packet into the endpoint buffer
+                    for (i, b) in packet.iter().enumerate() {
+                        buf[i].set(b.get());
+                    }
+
+                    let start = start + packet_bytes;
+                    let transfer_complete = start >= end;
+                    self.state.set(State::CtrlIn(start, end));
+
+                    hil::usb::CtrlInResult::Packet(packet_bytes, transfer_complete)
+                } else {
+                    hil::usb::CtrlInResult::Packet(0, true)
+                }
+            }
+            State::Function(index) => self.functions[index].ctrl_in(&self.ctrl_buffer.buf),
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, _endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.state.get() {
+            State::Function(index) => {
+                self.functions[index].ctrl_out(&self.ctrl_buffer.buf, packet_bytes)
+            }
+            // None of the standard requests we support has a data stage
+            // from the host.
+            _ => hil::usb::CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status(&'a self, _endpoint: usize) {
+        // Entered Status stage
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, _endpoint: usize) {
+        match self.state.replace(State::Init) {
+            State::SetAddress => {
+                self.controller.enable_address();
+            }
+            State::SetConfiguration => {
+                if self.configuration.get() != 0 {
+                    for function in self.functions {
+                        function.configured();
+                    }
+                }
+            }
+            State::Function(index) => {
+                self.functions[index].ctrl_status_complete();
+            }
+            State::Init | State::CtrlIn(..) => {}
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        self.function_for_endpoint(endpoint)
+            .map_or(hil::usb::InResult::Error, |function| {
+                function.packet_in(transfer_type, endpoint)
+            })
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        self.function_for_endpoint(endpoint)
+            .map_or(hil::usb::OutResult::Error, |function| {
+                function.packet_out(transfer_type, endpoint, packet_bytes)
+            })
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        if let Some(function) = self.function_for_endpoint(endpoint) {
+            function.packet_transmitted(endpoint);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::usb::cdc::CdcAcm;
+    use crate::usb::descriptors::TransferDirection;
+    use crate::usb::descriptors::{EndpointAddress, EndpointDescriptor, InterfaceDescriptor};
+    use hil::time::{Alarm, AlarmClient, Freq1MHz, Ticks32, Time};
+    use hil::uart::{Transmit, TransmitClient};
+    use hil::usb::{Client, CtrlInResult, CtrlSetupResult, DeviceSpeed, InResult, OutResult};
+    use kernel::utilities::cells::OptionalCell;
+    use kernel::ErrorCode;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// A USB controller that keeps the control endpoint buffer and counts
+    /// resumed IN endpoints.
+    #[derive(Default)]
+    struct Usbd {
+        ctrl_buffer: OptionalCell<&'static [VolatileCell<u8>]>,
+        resumed_in: Cell<usize>,
+    }
+
+    impl Usbd {
+        fn ctrl_buffer(&self) -> &'static [VolatileCell<u8>] {
+            self.ctrl_buffer.get().unwrap()
+        }
+    }
+
+    impl hil::usb::UsbController<'static> for Usbd {
+        fn set_client(&self, _client: &'static dyn hil::usb::Client<'static>) {}
+        fn endpoint_set_ctrl_buffer(&self, buf: &'static [VolatileCell<u8>]) {
+            self.ctrl_buffer.set(buf);
+        }
+        fn endpoint_set_in_buffer(&self, _endpoint: usize, _buf: &'static [VolatileCell<u8>]) {}
+        fn endpoint_set_out_buffer(&self, _endpoint: usize, _buf: &'static [VolatileCell<u8>]) {}
+        fn enable_as_device(&self, _speed: DeviceSpeed) {}
+        fn attach(&self) {}
+        fn detach(&self) {}
+        fn set_address(&self, _addr: u16) {}
+        fn enable_address(&self) {}
+        fn endpoint_in_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_in_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_resume_in(&self, _endpoint: usize) {
+            self.resumed_in.set(self.resumed_in.get() + 1);
+        }
+        fn endpoint_resume_out(&self, _endpoint: usize) {}
+    }
+
+    /// An alarm that only fires when the test calls the client.
+    struct Clock;
+
+    impl Time for Clock {
+        type Frequency = Freq1MHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    impl<'a> Alarm<'a> for Clock {
+        fn set_alarm_client(&self, _client: &'a dyn AlarmClient) {}
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {}
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn is_armed(&self) -> bool {
+            false
+        }
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    /// A vendor function with one interface and one IN endpoint, which
+    /// counts the requests and transfers routed to it.
+    struct TestFunction {
+        interface: u8,
+        endpoint: usize,
+        setups: Cell<usize>,
+        completed: Cell<usize>,
+        packets_in: Cell<usize>,
+    }
+
+    /// The only request `TestFunction` accepts.
+    const TEST_REQUEST: u8 = 0x01;
+
+    impl TestFunction {
+        fn new(interface: u8, endpoint: usize) -> Self {
+            Self {
+                interface,
+                endpoint,
+                setups: Cell::new(0),
+                completed: Cell::new(0),
+                packets_in: Cell::new(0),
+            }
+        }
+    }
+
+    impl<'a> UsbFunction<'a> for TestFunction {
+        fn interfaces(&self) -> (u8, u8) {
+            (self.interface, 1)
+        }
+        fn has_endpoint(&self, endpoint: usize) -> bool {
+            endpoint == self.endpoint
+        }
+        fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+            f(&InterfaceDescriptor {
+                interface_number: self.interface,
+                interface_class: 0xff,
+                num_endpoints: 1,
+                ..InterfaceDescriptor::default()
+            });
+            f(&EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    self.endpoint,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 64,
+                interval: 1,
+            });
+        }
+        fn enable(&'a self) {}
+        fn ctrl_setup(&'a self, setup: &SetupData) -> CtrlSetupResult {
+            self.setups.set(self.setups.get() + 1);
+            if setup.request_code == TEST_REQUEST {
+                CtrlSetupResult::Ok
+            } else {
+                CtrlSetupResult::ErrNonstandardRequest
+            }
+        }
+        fn ctrl_in(&'a self, packet: &[VolatileCell<u8>]) -> CtrlInResult {
+            packet[0].set(0xaa);
+            CtrlInResult::Packet(1, true)
+        }
+        fn ctrl_status_complete(&'a self) {
+            self.completed.set(self.completed.get() + 1);
+        }
+        fn packet_in(&'a self, _transfer_type: TransferType, _endpoint: usize) -> InResult {
+            self.packets_in.set(self.packets_in.get() + 1);
+            InResult::Delay
+        }
+        fn packet_out(
+            &'a self,
+            _transfer_type: TransferType,
+            _endpoint: usize,
+            _packet_bytes: u32,
+        ) -> OutResult {
+            OutResult::Error
+        }
+        fn packet_transmitted(&'a self, _endpoint: usize) {}
+    }
+
+    type Cdc = CdcAcm<'static, Usbd, Clock>;
+
+    struct Harness {
+        usbd: &'static Usbd,
+        function: &'static TestFunction,
+        device: &'static CompositeDevice<'static, Usbd>,
+    }
+
+    impl Harness {
+        /// A device with a CDC-ACM function on interfaces 0 and 1 and
+        /// endpoints 2, 3 and 4, and a test function on interface 2 and
+        /// endpoint 1.
+        fn new() -> Self {
+            let usbd = Box::leak(Box::<Usbd>::default());
+            let cdc = Box::leak(Box::new(CdcAcm::new_function(
+                usbd,
+                0,
+                2,
+                3,
+                4,
+                Box::leak(Box::new(Clock)),
+                None,
+            )));
+            let function = Box::leak(Box::new(TestFunction::new(2, 1)));
+            let functions: &'static [&'static dyn UsbFunction<'static>] =
+                Box::leak(Box::new([cdc as &dyn UsbFunction, function]));
+            let device = Box::leak(Box::new(CompositeDevice::new(
+                usbd,
+                64,
+                0x1915,
+                0x521f,
+                &["Tock", "Composite", "1"],
+                functions,
+            )));
+            device.enable();
+            Harness {
+                usbd,
+                function,
+                device,
+            }
+        }
+
+        fn setup(&self, packet: [u8; 8]) -> CtrlSetupResult {
+            control_setup(self.device, self.usbd.ctrl_buffer(), packet)
+        }
+    }
+
+    fn control_setup(
+        client: &'static dyn Client<'static>,
+        ctrl_buffer: &[VolatileCell<u8>],
+        packet: [u8; 8],
+    ) -> CtrlSetupResult {
+        for (cell, byte) in ctrl_buffer.iter().zip(packet) {
+            cell.set(byte);
+        }
+        client.ctrl_setup(0)
+    }
+
+    /// Perform a control read and return the data.
+    fn control_read(
+        client: &'static dyn Client<'static>,
+        ctrl_buffer: &[VolatileCell<u8>],
+        packet: [u8; 8],
+    ) -> Vec<u8> {
+        assert!(matches!(
+            control_setup(client, ctrl_buffer, packet),
+            CtrlSetupResult::Ok
+        ));
+        let mut data = Vec::new();
+        loop {
+            match client.ctrl_in(0) {
+                CtrlInResult::Packet(len, last) => {
+                    data.extend(ctrl_buffer[..len].iter().map(|cell| cell.get()));
+                    if last {
+                        break;
+                    }
+                }
+                _ => panic!("control read failed"),
+            }
+        }
+        client.ctrl_status_complete(0);
+        data
+    }
+
+    const GET_CONFIGURATION_DESCRIPTOR: [u8; 8] = [0x80, 6, 0, 2, 0, 0, 0xff, 0];
+
+    /// Split a configuration descriptor into its descriptors.
+    fn split(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut descriptors = Vec::new();
+        while !data.is_empty() {
+            let (descriptor, rest) = data.split_at(data[0] as usize);
+            descriptors.push(descriptor);
+            data = rest;
+        }
+        descriptors
+    }
+
+    #[test]
+    fn configuration_descriptor() {
+        let h = Harness::new();
+        let data = control_read(h.device, h.usbd.ctrl_buffer(), GET_CONFIGURATION_DESCRIPTOR);
+        assert_eq!(u16::from_le_bytes([data[2], data[3]]) as usize, data.len());
+        // Three interfaces in one configuration.
+        assert_eq!(data[4], 3);
+
+        let descriptors = split(&data);
+        let types: Vec<u8> = descriptors.iter().map(|d| d[1]).collect();
+        assert_eq!(
+            types,
+            [2, 0x0b, 4, 0x24, 0x24, 0x24, 0x24, 5, 4, 5, 5, 4, 5]
+        );
+
+        // The CDC-ACM function is grouped by an interface association.
+        assert_eq!(descriptors[1], [8, 0x0b, 0, 2, 0x02, 0x02, 0x01, 0]);
+
+        // Interfaces are numbered in order, with their endpoints.
+        let interfaces: Vec<(u8, u8)> = descriptors
+            .iter()
+            .filter(|d| d[1] == 4)
+            .map(|d| (d[2], d[4]))
+            .collect();
+        assert_eq!(interfaces, [(0, 1), (1, 2), (2, 1)]);
+        let endpoints: Vec<u8> = descriptors
+            .iter()
+            .filter(|d| d[1] == 5)
+            .map(|d| d[2])
+            .collect();
+        assert_eq!(endpoints, [0x84, 0x82, 0x03, 0x81]);
+    }
+
+    #[test]
+    fn control_request_routing() {
+        let h = Harness::new();
+
+        // A class request to the interface of the test function.
+        let data = control_read(
+            h.device,
+            h.usbd.ctrl_buffer(),
+            [0xa1, TEST_REQUEST, 0, 0, 2, 0, 1, 0],
+        );
+        assert_eq!(data, [0xaa]);
+        assert_eq!(h.function.setups.get(), 1);
+        assert_eq!(h.function.completed.get(), 1);
+
+        // A request the function rejects is not completed.
+        assert!(matches!(
+            h.setup([0xa1, 0x02, 0, 0, 2, 0, 1, 0]),
+            CtrlSetupResult::ErrNonstandardRequest
+        ));
+        assert_eq!(h.function.setups.get(), 2);
+
+        // GET_LINE_CODING to the CDC-ACM communication interface.
+        let data = control_read(
+            h.device,
+            h.usbd.ctrl_buffer(),
+            [0xa1, 0x21, 0, 0, 0, 0, 7, 0],
+        );
+        assert_eq!(data, [0x00, 0xc2, 0x01, 0x00, 0, 0, 8]);
+        assert_eq!(h.function.setups.get(), 2);
+
+        // An endpoint request to the endpoint of the test function.
+        assert!(matches!(
+            h.setup([0x22, TEST_REQUEST, 0, 0, 0x81, 0, 0, 0]),
+            CtrlSetupResult::Ok
+        ));
+        assert_eq!(h.function.setups.get(), 3);
+        h.device.ctrl_status_complete(0);
+        assert_eq!(h.function.completed.get(), 2);
+
+        // No function owns interface 3.
+        assert!(matches!(
+            h.setup([0xa1, TEST_REQUEST, 0, 0, 3, 0, 1, 0]),
+            CtrlSetupResult::ErrInvalidInterfaceIndex
+        ));
+    }
+
+    #[test]
+    fn endpoint_routing() {
+        let h = Harness::new();
+
+        assert!(matches!(
+            h.device.packet_in(TransferType::Interrupt, 1),
+            InResult::Delay
+        ));
+        assert_eq!(h.function.packets_in.get(), 1);
+
+        // The CDC-ACM function has nothing to send yet.
+        assert!(matches!(
+            h.device.packet_in(TransferType::Bulk, 2),
+            InResult::Delay
+        ));
+        assert!(matches!(
+            h.device.packet_out(TransferType::Bulk, 3, 0),
+            OutResult::Ok
+        ));
+        assert_eq!(h.function.packets_in.get(), 1);
+
+        // No function owns endpoint 5.
+        assert!(matches!(
+            h.device.packet_in(TransferType::Bulk, 5),
+            InResult::Error
+        ));
+        assert!(matches!(
+            h.device.packet_out(TransferType::Bulk, 5, 0),
+            OutResult::Error
+        ));
+    }
+
+    struct TestTransmitClient {
+        transmitted: Cell<Option<usize>>,
+    }
+
+    impl TransmitClient for TestTransmitClient {
+        fn transmitted_buffer(
+            &self,
+            _tx_buffer: &'static mut [u8],
+            tx_len: usize,
+            _rval: Result<(), ErrorCode>,
+        ) {
+            self.transmitted.set(Some(tx_len));
+        }
+    }
+
+    /// The standalone CDC-ACM device, without a composite device, reports
+    /// the same configuration as before composite devices were added and
+    /// sends data once a host connects.
+    #[test]
+    fn standalone_cdc_acm() {
+        let usbd = Box::leak(Box::<Usbd>::default());
+        let cdc: &'static Cdc = Box::leak(Box::new(CdcAcm::new(
+            usbd,
+            64,
+            0x1915,
+            0x503a,
+            &["Tock", "Serial", "1"],
+            Box::leak(Box::new(Clock)),
+            None,
+        )));
+        let client = Box::leak(Box::new(TestTransmitClient {
+            transmitted: Cell::new(None),
+        }));
+        cdc.set_transmit_client(client);
+        Client::enable(cdc);
+        Client::attach(cdc);
+
+        let ctrl_buffer = usbd.ctrl_buffer();
+        let data = control_read(cdc, ctrl_buffer, GET_CONFIGURATION_DESCRIPTOR);
+        #[rustfmt::skip]
+        let expected: [u8; 67] = [
+            // Configuration
+            9, 2, 67, 0, 2, 1, 0, 0xc0, 0,
+            // Communication interface with its CDC functional descriptors
+            9, 4, 0, 0, 1, 0x02, 0x02, 0x01, 0,
+            5, 0x24, 0x00, 0x10, 0x11,
+            5, 0x24, 0x01, 0x00, 0x01,
+            4, 0x24, 0x02, 0x06,
+            5, 0x24, 0x06, 0x00, 0x01,
+            7, 5, 0x84, 0x03, 8, 0, 16,
+            // Data interface
+            9, 4, 1, 0, 2, 0x0a, 0x00, 0x00, 0,
+            7, 5, 0x82, 0x02, 64, 0, 0,
+            7, 5, 0x03, 0x02, 64, 0, 0,
+        ];
+        assert_eq!(data, expected);
+
+        // A host connects: the bus reset ends the enumeration, then the
+        // host sets the line coding and the control line state.
+        Client::bus_reset(cdc);
+        control_setup(cdc, ctrl_buffer, [0x21, 0x20, 0, 0, 0, 0, 7, 0]);
+        for (cell, byte) in ctrl_buffer.iter().zip([0x00, 0xc2, 0x01, 0x00, 0, 0, 8]) {
+            cell.set(byte);
+        }
+        Client::ctrl_out(cdc, 0, 7);
+        Client::ctrl_status_complete(cdc, 0);
+        control_setup(cdc, ctrl_buffer, [0x21, 0x22, 3, 0, 0, 0, 0, 0]);
+        Client::ctrl_status_complete(cdc, 0);
+        // The delay after connecting.
+        AlarmClient::alarm(cdc);
+
+        let resumed = usbd.resumed_in.get();
+        let buffer = Box::leak(Box::new([b'x'; 3]));
+        cdc.transmit_buffer(buffer, 3).unwrap();
+        assert_eq!(usbd.resumed_in.get(), resumed + 1);
+        assert!(matches!(
+            Client::packet_in(cdc, TransferType::Bulk, 2),
+            InResult::Packet(3)
+        ));
+        Client::packet_transmitted(cdc, 2);
+        assert_eq!(client.transmitted.get(), Some(3));
+    }
+}
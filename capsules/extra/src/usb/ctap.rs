@@ -5,11 +5,17 @@
 //! Client to Authenticator Protocol CTAPv2 over USB HID
 //!
 //! Based on the spec avaliable at: <https://fidoalliance.org/specs/fido-v2.0-id-20180227/fido-client-to-authenticator-protocol-v2.0-id-20180227.html>
+//!
+//! The CTAP HID device can either be the only client of the USB controller
+//! (see `CtapHid::new()`), or one function of a composite device (see
+//! `CtapHid::new_function()` and the `composite` module).
 
 use core::cmp;
 
+use super::composite::UsbFunction;
 use super::descriptors;
 use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
 use super::descriptors::DescriptorType;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
@@ -18,6 +24,8 @@ use super::descriptors::HIDDescriptor;
 use super::descriptors::HIDSubordinateDescriptor;
 use super::descriptors::InterfaceDescriptor;
 use super::descriptors::ReportDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
@@ -30,6 +38,9 @@ use kernel::ErrorCode;
 /// Use 1 Interrupt transfer IN/OUT endpoint
 const ENDPOINT_NUM: usize = 1;
 
+/// HID class specific SET_IDLE request.
+const HID_SET_IDLE: u8 = 0x0a;
+
 const OUT_BUFFER: usize = 0;
 const IN_BUFFER: usize = 1;
 
@@ -88,6 +99,11 @@ pub struct CtapHid<'a, U: 'a> {
     /// 64 byte buffers for each endpoint.
     buffers: [Buffer64; N_ENDPOINTS],
 
+    /// Number of the HID interface.
+    interface: u8,
+    /// Interrupt IN/OUT endpoint for the CTAP HID reports.
+    endpoint: usize,
+
     client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; 64]>>,
 
     /// A buffer to hold the data we want to send
@@ -105,34 +121,26 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
         product_id: u16,
         strings: &'static [&'static str; 3],
     ) -> Self {
-        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
-            interface_number: 0,
-            interface_class: 0x03,    // HID
-            interface_subclass: 0x00, // No subcall
-            interface_protocol: 0x00, // No protocol
-            ..InterfaceDescriptor::default()
-        }];
+        Self::new_internal(controller, vendor_id, product_id, strings, 0, ENDPOINT_NUM)
+    }
 
-        let endpoints: &[&[EndpointDescriptor]] = &[&[
-            EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(
-                    ENDPOINT_NUM,
-                    TransferDirection::DeviceToHost,
-                ),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 64,
-                interval: 5,
-            },
-            EndpointDescriptor {
-                endpoint_address: EndpointAddress::new_const(
-                    ENDPOINT_NUM,
-                    TransferDirection::HostToDevice,
-                ),
-                transfer_type: TransferType::Interrupt,
-                max_packet_size: 64,
-                interval: 5,
-            },
-        ]];
+    /// Create a CTAP HID function for a composite device, using the interface
+    /// `interface` and the interrupt IN and OUT endpoints `endpoint`.
+    pub fn new_function(controller: &'a U, interface: u8, endpoint: usize) -> Self {
+        Self::new_internal(controller, 0, 0, &["", "", ""], interface, endpoint)
+    }
+
+    fn new_internal(
+        controller: &'a U,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        interface: u8,
+        endpoint: usize,
+    ) -> Self {
+        let (interface_descriptor, endpoint_descriptors) = ctap_descriptors(interface, endpoint);
+        let interfaces: &mut [InterfaceDescriptor] = &mut [interface_descriptor];
+        let endpoints: &[&[EndpointDescriptor]] = &[&endpoint_descriptors];
 
         let (device_descriptor_buffer, other_descriptor_buffer) =
             descriptors::create_descriptor_buffers(
@@ -164,6 +172,8 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
                 strings,
             ),
             buffers: [Buffer64::default(), Buffer64::default()],
+            interface,
+            endpoint,
             client: OptionalCell::empty(),
             send_buffer: TakeCell::empty(),
             recv_buffer: TakeCell::empty(),
@@ -178,6 +188,53 @@ impl<'a, U: hil::usb::UsbController<'a>> CtapHid<'a, U> {
     pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; 64]>) {
         self.client.set(client);
     }
+
+    /// Set up the interrupt IN and OUT endpoints.
+    fn enable_endpoints(&'a self) {
+        self.controller()
+            .endpoint_set_out_buffer(self.endpoint, &self.buffers[OUT_BUFFER].buf);
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint, &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_out_enable(TransferType::Interrupt, self.endpoint);
+    }
+}
+
+/// Build the interface and endpoint descriptors of the CTAP HID device.
+fn ctap_descriptors(
+    interface: u8,
+    endpoint: usize,
+) -> (InterfaceDescriptor, [EndpointDescriptor; 2]) {
+    (
+        InterfaceDescriptor {
+            interface_number: interface,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x00, // No subcall
+            interface_protocol: 0x00, // No protocol
+            num_endpoints: 2,
+            ..InterfaceDescriptor::default()
+        },
+        [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    endpoint,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 64,
+                interval: 5,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    endpoint,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 64,
+                interval: 5,
+            },
+        ],
+    )
 }
 
 impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for CtapHid<'a, U> {
@@ -188,7 +245,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
         let len = send.len();
 
         self.send_buffer.replace(send);
-        self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        self.controller().endpoint_resume_in(self.endpoint);
 
         Ok(len)
     }
@@ -205,7 +262,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
         recv: &'static mut [u8; 64],
     ) -> Result<(), (ErrorCode, &'static mut [u8; 64])> {
         self.recv_buffer.replace(recv);
-        self.controller().endpoint_resume_out(ENDPOINT_NUM);
+        self.controller().endpoint_resume_out(self.endpoint);
         Ok(())
     }
 
@@ -223,12 +280,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         self.client_ctrl.enable();
 
         // Setup buffers for IN and OUT data transfer.
-        self.controller()
-            .endpoint_set_out_buffer(ENDPOINT_NUM, &self.buffers[OUT_BUFFER].buf);
-        self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
-        self.controller()
-            .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
@@ -259,7 +311,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&'a self, endpoint: usize) {
         if self.send_buffer.is_some() {
-            self.controller().endpoint_resume_in(ENDPOINT_NUM);
+            self.controller().endpoint_resume_in(self.endpoint);
         }
 
         self.client_ctrl.ctrl_status_complete(endpoint)
@@ -350,3 +402,71 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CtapHid<'a, U>
         });
     }
 }
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for CtapHid<'a, U> {
+    fn interfaces(&self) -> (u8, u8) {
+        (self.interface, 1)
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+        let (interface_descriptor, endpoint_descriptors) =
+            ctap_descriptors(self.interface, self.endpoint);
+        f(&interface_descriptor);
+        f(&HID_DESCRIPTOR);
+        for descriptor in endpoint_descriptors.iter() {
+            f(descriptor);
+        }
+    }
+
+    fn interface_descriptor(
+        &self,
+        _interface: u8,
+        descriptor_type: DescriptorType,
+    ) -> Option<&dyn Descriptor> {
+        match descriptor_type {
+            DescriptorType::HID => Some(&HID_DESCRIPTOR),
+            DescriptorType::Report => Some(&REPORT),
+            _ => None,
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn configured(&'a self) {
+        // Send a packet queued before the host configured the device.
+        if self.send_buffer.is_some() {
+            self.controller().endpoint_resume_in(self.endpoint);
+        }
+    }
+
+    /// Accept the HID request to set the idle rate, and ignore its value.
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        match (setup.request_type.request_type(), setup.request_code) {
+            (RequestType::Class, HID_SET_IDLE) => hil::usb::CtrlSetupResult::Ok,
+            _ => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
@@ -0,0 +1,874 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! CTAPHID transport for CTAP2 authenticators
+//!
+//! Implements the CTAPHID protocol of the FIDO Client to Authenticator
+//! Protocol on top of the 64 byte HID reports of the CTAP HID function (see
+//! the `ctap` module), so that the client receives whole CTAP2 CBOR requests
+//! and sends whole responses:
+//!
+//! - `CTAPHID_INIT` allocates channels, and resynchronizes a channel when it
+//!   is sent on a channel that is already allocated.
+//! - Requests are reassembled from an initialization packet and continuation
+//!   packets. A request that is not complete within 500 ms is aborted with
+//!   `ERR_MSG_TIMEOUT`.
+//! - `CTAPHID_PING` is answered by the transport.
+//! - `CTAPHID_CBOR` requests are passed to the client. While the client
+//!   processes a request, `CTAPHID_KEEPALIVE` is sent every 100 ms, with the
+//!   status set by `set_user_presence_needed()`.
+//! - `CTAPHID_CANCEL` for the request being processed is passed to the
+//!   client, which should answer the request with
+//!   `CTAP2_ERR_KEEPALIVE_CANCEL`.
+//! - Requests on other channels while a transaction is in progress are
+//!   answered with `ERR_CHANNEL_BUSY`.
+//!
+//! The transport handles one transaction at a time. CTAP1/U2F messages
+//! (`CTAPHID_MSG`), `CTAPHID_WINK` and `CTAPHID_LOCK` are not supported,
+//! and the `INIT` response reports this to the host.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let transport = static_init!(
+//!     CtapHidTransport<'static, CtapHid<'static, Usbd>, VirtualMuxAlarm<'static, Rtc>>,
+//!     CtapHidTransport::new(ctap, alarm, send_buffer, recv_buffer, message_buffer)
+//! );
+//! ctap.set_client(transport);
+//! transport.set_client(ctap_driver);
+//! alarm.set_alarm_client(transport);
+//! transport.enable();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::hil::time::{self, ConvertTicks};
+use kernel::hil::usb_hid;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Length of a CTAPHID packet.
+const PACKET_LEN: usize = 64;
+/// Payload bytes in an initialization packet.
+const INIT_DATA_LEN: usize = PACKET_LEN - 7;
+/// Payload bytes in a continuation packet.
+const CONT_DATA_LEN: usize = PACKET_LEN - 5;
+
+/// Largest message the protocol can carry, with 128 continuation packets.
+pub const MAX_MESSAGE_LEN: usize = INIT_DATA_LEN + 128 * CONT_DATA_LEN;
+
+/// The broadcast channel, used to allocate a channel with `CTAPHID_INIT`.
+const BROADCAST_CID: u32 = 0xffff_ffff;
+
+/// Time to wait for the next continuation packet of a request.
+const MSG_TIMEOUT_MS: u32 = 500;
+/// Interval of keepalive messages while a request is processed.
+const KEEPALIVE_INTERVAL_MS: u32 = 100;
+
+// CTAPHID commands, with the initialization packet bit set.
+const CTAPHID_PING: u8 = 0x81;
+const CTAPHID_INIT: u8 = 0x86;
+const CTAPHID_CBOR: u8 = 0x90;
+const CTAPHID_CANCEL: u8 = 0x91;
+const CTAPHID_KEEPALIVE: u8 = 0xbb;
+const CTAPHID_ERROR: u8 = 0xbf;
+
+// CTAPHID_ERROR codes.
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_MSG_TIMEOUT: u8 = 0x05;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0b;
+
+// CTAPHID_KEEPALIVE status codes.
+const STATUS_PROCESSING: u8 = 1;
+const STATUS_UPNEEDED: u8 = 2;
+
+/// Length of the `CTAPHID_INIT` nonce.
+const INIT_NONCE_LEN: usize = 8;
+/// Length of the `CTAPHID_INIT` response.
+const INIT_RESPONSE_LEN: usize = 17;
+/// CTAPHID protocol version.
+const PROTOCOL_VERSION: u8 = 2;
+/// Device version reported in the `CTAPHID_INIT` response.
+const DEVICE_VERSION: [u8; 3] = [1, 0, 0];
+// Capability flags.
+const CAPABILITY_CBOR: u8 = 0x04;
+const CAPABILITY_NMSG: u8 = 0x08;
+
+/// Client of the CTAPHID transport, usually the CTAP2 authenticator.
+pub trait CtapHidClient {
+    /// A CTAP2 CBOR request was received. The client answers it with
+    /// `send_response()`.
+    fn request_received(&self, request: &[u8]);
+
+    /// The host cancelled the request that is being processed.
+    fn request_cancelled(&self);
+
+    /// A response passed to `send_response()` was sent, or failed.
+    fn response_sent(&self, result: Result<(), ErrorCode>, response: &'static mut [u8]);
+}
+
+/// A CTAP2 transport that passes whole requests to its client.
+pub trait CtapTransport<'a> {
+    fn set_client(&self, client: &'a dyn CtapHidClient);
+
+    /// Send the response to the request passed to the client. `len` bytes of
+    /// `response` are sent, and the buffer is returned in `response_sent()`.
+    ///
+    /// Returns `INVAL` if there is no request to answer, because the host
+    /// aborted it, and `SIZE` if the response is too long.
+    fn send_response(
+        &self,
+        response: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Report to the host whether the client waits for the user to confirm
+    /// their presence.
+    fn set_user_presence_needed(&self, needed: bool);
+}
+
+/// The state of the current transaction.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    /// No transaction.
+    Idle,
+    /// Receiving the continuation packets of a request.
+    Receiving {
+        cid: u32,
+        cmd: u8,
+        len: usize,
+        received: usize,
+        seq: u8,
+    },
+    /// The client is processing a CBOR request.
+    Processing { cid: u32 },
+    /// Sending a response. `offset` is the number of bytes of the message
+    /// already written into packets.
+    Sending {
+        cid: u32,
+        cmd: u8,
+        len: usize,
+        offset: usize,
+        seq: u8,
+    },
+}
+
+pub struct CtapHidTransport<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: time::Alarm<'a>> {
+    hid: &'a H,
+    alarm: &'a A,
+
+    send_buffer: TakeCell<'static, [u8; 64]>,
+    /// The receive buffer, while it is not passed to the HID function.
+    recv_buffer: TakeCell<'static, [u8; 64]>,
+    /// Buffer requests are reassembled in, and ping responses are sent from.
+    message: TakeCell<'static, [u8]>,
+    /// The response of the client being sent.
+    response: TakeCell<'static, [u8]>,
+
+    state: Cell<State>,
+    /// A single packet reply, for example an error, that waits for the send
+    /// buffer.
+    reply: Cell<Option<[u8; PACKET_LEN]>>,
+    /// The packet in the send buffer is the last one of the client response.
+    response_done: Cell<bool>,
+    /// The channel allocated next.
+    next_cid: Cell<u32>,
+    user_presence_needed: Cell<bool>,
+
+    client: OptionalCell<&'a dyn CtapHidClient>,
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: time::Alarm<'a>> CtapHidTransport<'a, H, A> {
+    pub fn new(
+        hid: &'a H,
+        alarm: &'a A,
+        send_buffer: &'static mut [u8; 64],
+        recv_buffer: &'static mut [u8; 64],
+        message: &'static mut [u8],
+    ) -> Self {
+        Self {
+            hid,
+            alarm,
+            send_buffer: TakeCell::new(send_buffer),
+            recv_buffer: TakeCell::new(recv_buffer),
+            message: TakeCell::new(message),
+            response: TakeCell::empty(),
+            state: Cell::new(State::Idle),
+            reply: Cell::new(None),
+            response_done: Cell::new(false),
+            next_cid: Cell::new(1),
+            user_presence_needed: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Start receiving packets from the host.
+    pub fn enable(&self) -> Result<(), ErrorCode> {
+        let buffer = self.recv_buffer.take().ok_or(ErrorCode::ALREADY)?;
+        self.hid.receive_buffer(buffer).map_err(|(err, buffer)| {
+            self.recv_buffer.replace(buffer);
+            err
+        })
+    }
+
+    fn start_timer(&self, ms: u32) {
+        self.alarm
+            .set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(ms));
+    }
+
+    /// Queue a single packet message to `cid`.
+    fn queue_reply(&self, cid: u32, cmd: u8, data: &[u8]) {
+        if self.reply.get().is_some() {
+            // The previous reply was not sent yet, the host retries.
+            return;
+        }
+        let mut packet = [0; PACKET_LEN];
+        packet[0..4].copy_from_slice(&cid.to_be_bytes());
+        packet[4] = cmd;
+        packet[5..7].copy_from_slice(&(data.len() as u16).to_be_bytes());
+        packet[7..7 + data.len()].copy_from_slice(data);
+        self.reply.set(Some(packet));
+        self.transmit();
+    }
+
+    fn send_error(&self, cid: u32, error: u8) {
+        self.queue_reply(cid, CTAPHID_ERROR, &[error]);
+    }
+
+    /// Send the next packet, if the send buffer is free.
+    fn transmit(&self) {
+        let buffer = match self.send_buffer.take() {
+            Some(buffer) => buffer,
+            // A packet is being sent, we continue when it is done.
+            None => return,
+        };
+
+        if let Some(packet) = self.reply.take() {
+            buffer.copy_from_slice(&packet);
+        } else if !self.write_message_packet(buffer) {
+            self.send_buffer.replace(buffer);
+            return;
+        }
+
+        if let Err((_, buffer)) = self.hid.send_buffer(buffer) {
+            self.send_buffer.replace(buffer);
+            if self.response_done.take() {
+                self.response.take().map(|response| {
+                    self.client
+                        .map(|client| client.response_sent(Err(ErrorCode::FAIL), response));
+                });
+            }
+        }
+    }
+
+    /// Write the next packet of the message being sent into `packet`.
+    /// Returns false if there is nothing to send.
+    fn write_message_packet(&self, packet: &mut [u8; 64]) -> bool {
+        let (cid, cmd, len, offset, seq) = match self.state.get() {
+            State::Sending {
+                cid,
+                cmd,
+                len,
+                offset,
+                seq,
+            } => (cid, cmd, len, offset, seq),
+            _ => return false,
+        };
+        // Ping responses echo the request from the message buffer.
+        let source = if cmd == CTAPHID_CBOR {
+            &self.response
+        } else {
+            &self.message
+        };
+
+        packet.fill(0);
+        packet[0..4].copy_from_slice(&cid.to_be_bytes());
+        let (header_len, data_len) = if offset == 0 {
+            packet[4] = cmd;
+            packet[5..7].copy_from_slice(&(len as u16).to_be_bytes());
+            (7, INIT_DATA_LEN)
+        } else {
+            packet[4] = seq;
+            (5, CONT_DATA_LEN)
+        };
+        let chunk = cmp::min(data_len, len - offset);
+        source.map(|message| {
+            packet[header_len..header_len + chunk]
+                .copy_from_slice(&message[offset..offset + chunk]);
+        });
+
+        if offset + chunk >= len {
+            self.state.set(State::Idle);
+            self.response_done.set(cmd == CTAPHID_CBOR);
+        } else {
+            self.state.set(State::Sending {
+                cid,
+                cmd,
+                len,
+                offset: offset + chunk,
+                seq: if offset == 0 { 0 } else { seq + 1 },
+            });
+        }
+        true
+    }
+
+    /// Returns true if `cid` is a channel allocated with `CTAPHID_INIT`.
+    fn is_allocated(&self, cid: u32) -> bool {
+        cid != 0 && cid < self.next_cid.get()
+    }
+
+    fn allocate_channel(&self) -> u32 {
+        let cid = self.next_cid.get();
+        // Once all channels are used, the last one is reused.
+        if cid < BROADCAST_CID - 1 {
+            self.next_cid.set(cid + 1);
+        }
+        cid
+    }
+
+    /// Abort the transaction on `cid`, for example because the host
+    /// resynchronized the channel.
+    fn abort(&self, cid: u32) {
+        match self.state.get() {
+            State::Receiving { cid: current, .. } if current == cid => {
+                self.state.set(State::Idle);
+            }
+            State::Processing { cid: current } if current == cid => {
+                // The client still answers the request, the response is
+                // discarded.
+                self.state.set(State::Idle);
+                self.client.map(|client| client.request_cancelled());
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_init(&self, cid: u32, len: usize, data: &[u8]) {
+        if len != INIT_NONCE_LEN {
+            self.send_error(cid, ERR_INVALID_LEN);
+            return;
+        }
+        let new_cid = if cid == BROADCAST_CID {
+            self.allocate_channel()
+        } else if self.is_allocated(cid) {
+            self.abort(cid);
+            cid
+        } else {
+            self.send_error(cid, ERR_INVALID_CHANNEL);
+            return;
+        };
+
+        let mut response = [0; INIT_RESPONSE_LEN];
+        response[0..8].copy_from_slice(&data[0..INIT_NONCE_LEN]);
+        response[8..12].copy_from_slice(&new_cid.to_be_bytes());
+        response[12] = PROTOCOL_VERSION;
+        response[13..16].copy_from_slice(&DEVICE_VERSION);
+        response[16] = CAPABILITY_CBOR | CAPABILITY_NMSG;
+        self.queue_reply(cid, CTAPHID_INIT, &response);
+    }
+
+    /// Handle an initialization packet.
+    fn handle_init_packet(&self, cid: u32, packet: &[u8; 64]) {
+        let cmd = packet[4];
+        let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        let data = &packet[7..];
+
+        if cmd == CTAPHID_INIT {
+            self.handle_init(cid, len, data);
+            return;
+        }
+        if !self.is_allocated(cid) {
+            self.send_error(cid, ERR_INVALID_CHANNEL);
+            return;
+        }
+
+        match self.state.get() {
+            State::Idle => {}
+            State::Processing { cid: current } if current == cid && cmd == CTAPHID_CANCEL => {
+                self.client.map(|client| client.request_cancelled());
+                return;
+            }
+            State::Receiving { cid: current, .. } if current == cid => {
+                // A new request before the previous one is complete.
+                self.state.set(State::Idle);
+                self.send_error(cid, ERR_INVALID_SEQ);
+                return;
+            }
+            _ => {
+                if cmd != CTAPHID_CANCEL {
+                    self.send_error(cid, ERR_CHANNEL_BUSY);
+                }
+                return;
+            }
+        }
+
+        match cmd {
+            // Nothing to cancel.
+            CTAPHID_CANCEL => return,
+            CTAPHID_PING | CTAPHID_CBOR => {}
+            _ => {
+                self.send_error(cid, ERR_INVALID_CMD);
+                return;
+            }
+        }
+        // Longer messages need more than 128 continuation packets, and the
+        // sequence number would run into the initialization packet bit.
+        if len > MAX_MESSAGE_LEN || len > self.message.map_or(0, |message| message.len()) {
+            self.send_error(cid, ERR_INVALID_LEN);
+            return;
+        }
+
+        let chunk = cmp::min(len, INIT_DATA_LEN);
+        self.message.map(|message| {
+            message[..chunk].copy_from_slice(&data[..chunk]);
+        });
+        if chunk == len {
+            self.dispatch(cid, cmd, len);
+        } else {
+            self.state.set(State::Receiving {
+                cid,
+                cmd,
+                len,
+                received: chunk,
+                seq: 0,
+            });
+            self.start_timer(MSG_TIMEOUT_MS);
+        }
+    }
+
+    /// Handle a continuation packet.
+    fn handle_continuation_packet(&self, cid: u32, packet: &[u8; 64]) {
+        let (cmd, len, received, seq) = match self.state.get() {
+            State::Receiving {
+                cid: current,
+                cmd,
+                len,
+                received,
+                seq,
+            } if current == cid => (cmd, len, received, seq),
+            // Spurious continuation packets are ignored.
+            _ => return,
+        };
+        if packet[4] != seq {
+            self.state.set(State::Idle);
+            self.send_error(cid, ERR_INVALID_SEQ);
+            return;
+        }
+
+        let chunk = cmp::min(len - received, CONT_DATA_LEN);
+        self.message.map(|message| {
+            message[received..received + chunk].copy_from_slice(&packet[5..5 + chunk]);
+        });
+        if received + chunk == len {
+            self.dispatch(cid, cmd, len);
+        } else {
+            self.state.set(State::Receiving {
+                cid,
+                cmd,
+                len,
+                received: received + chunk,
+                seq: seq + 1,
+            });
+            self.start_timer(MSG_TIMEOUT_MS);
+        }
+    }
+
+    /// Handle a complete request.
+    fn dispatch(&self, cid: u32, cmd: u8, len: usize) {
+        match cmd {
+            CTAPHID_CBOR if len > 0 && self.client.is_some() => {
+                self.state.set(State::Processing { cid });
+                self.user_presence_needed.set(false);
+                self.start_timer(KEEPALIVE_INTERVAL_MS);
+                self.message.map(|message| {
+                    self.client
+                        .map(|client| client.request_received(&message[..len]));
+                });
+            }
+            CTAPHID_CBOR => {
+                self.state.set(State::Idle);
+                self.send_error(
+                    cid,
+                    if len == 0 {
+                        ERR_INVALID_LEN
+                    } else {
+                        ERR_INVALID_CMD
+                    },
+                );
+            }
+            _ => {
+                // Answer the ping with the request.
+                self.state.set(State::Sending {
+                    cid,
+                    cmd,
+                    len,
+                    offset: 0,
+                    seq: 0,
+                });
+                self.transmit();
+            }
+        }
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: time::Alarm<'a>> CtapTransport<'a>
+    for CtapHidTransport<'a, H, A>
+{
+    fn set_client(&self, client: &'a dyn CtapHidClient) {
+        self.client.set(client);
+    }
+
+    fn send_response(
+        &self,
+        response: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        let cid = match self.state.get() {
+            State::Processing { cid } => cid,
+            _ => return Err((ErrorCode::INVAL, response)),
+        };
+        if len > response.len() || len > MAX_MESSAGE_LEN {
+            return Err((ErrorCode::SIZE, response));
+        }
+        self.response.replace(response);
+        self.state.set(State::Sending {
+            cid,
+            cmd: CTAPHID_CBOR,
+            len,
+            offset: 0,
+            seq: 0,
+        });
+        self.transmit();
+        Ok(())
+    }
+
+    fn set_user_presence_needed(&self, needed: bool) {
+        self.user_presence_needed.set(needed);
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: time::Alarm<'a>> usb_hid::Client<'a, [u8; 64]>
+    for CtapHidTransport<'a, H, A>
+{
+    fn packet_received(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        let packet = *buffer;
+        // Receive the next packet while we handle this one.
+        if let Err((_, buffer)) = self.hid.receive_buffer(buffer) {
+            self.recv_buffer.replace(buffer);
+        }
+        if result.is_err() {
+            return;
+        }
+
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        if packet[4] & 0x80 != 0 {
+            self.handle_init_packet(cid, &packet);
+        } else {
+            self.handle_continuation_packet(cid, &packet);
+        }
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        result: Result<(), ErrorCode>,
+        buffer: &'static mut [u8; 64],
+        _endpoint: usize,
+    ) {
+        self.send_buffer.replace(buffer);
+        if self.response_done.take() {
+            self.response.take().map(|response| {
+                self.client
+                    .map(|client| client.response_sent(result, response));
+            });
+        }
+        self.transmit();
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: time::Alarm<'a>> time::AlarmClient
+    for CtapHidTransport<'a, H, A>
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Receiving { cid, .. } => {
+                self.state.set(State::Idle);
+                self.send_error(cid, ERR_MSG_TIMEOUT);
+            }
+            State::Processing { cid } => {
+                let status = if self.user_presence_needed.get() {
+                    STATUS_UPNEEDED
+                } else {
+                    STATUS_PROCESSING
+                };
+                self.queue_reply(cid, CTAPHID_KEEPALIVE, &[status]);
+                self.start_timer(KEEPALIVE_INTERVAL_MS);
+            }
+            State::Idle | State::Sending { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use kernel::hil::time::{Alarm, Freq1MHz, Ticks32, Time};
+    use kernel::hil::usb_hid::{Client, UsbHid};
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// A HID function that keeps the buffers until the test completes them.
+    struct Hid {
+        tx: TakeCell<'static, [u8; 64]>,
+        rx: TakeCell<'static, [u8; 64]>,
+    }
+
+    impl<'a> UsbHid<'a, [u8; 64]> for Hid {
+        fn send_buffer(
+            &'a self,
+            send: &'static mut [u8; 64],
+        ) -> Result<usize, (ErrorCode, &'static mut [u8; 64])> {
+            if self.tx.is_some() {
+                return Err((ErrorCode::BUSY, send));
+            }
+            self.tx.replace(send);
+            Ok(PACKET_LEN)
+        }
+        fn send_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+            self.tx.take().ok_or(ErrorCode::INVAL)
+        }
+        fn receive_buffer(
+            &'a self,
+            recv: &'static mut [u8; 64],
+        ) -> Result<(), (ErrorCode, &'static mut [u8; 64])> {
+            self.rx.replace(recv);
+            Ok(())
+        }
+        fn receive_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+            self.rx.take().ok_or(ErrorCode::INVAL)
+        }
+    }
+
+    /// An alarm that never fires on its own.
+    struct Clock;
+
+    impl Time for Clock {
+        type Frequency = Freq1MHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    impl<'a> Alarm<'a> for Clock {
+        fn set_alarm_client(&self, _client: &'a dyn time::AlarmClient) {}
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {}
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+        fn is_armed(&self) -> bool {
+            false
+        }
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    #[derive(Default)]
+    struct TestClient {
+        requests: RefCell<Vec<Vec<u8>>>,
+        cancelled: Cell<usize>,
+    }
+
+    impl CtapHidClient for TestClient {
+        fn request_received(&self, request: &[u8]) {
+            self.requests.borrow_mut().push(request.to_vec());
+        }
+        fn request_cancelled(&self) {
+            self.cancelled.set(self.cancelled.get() + 1);
+        }
+        fn response_sent(&self, _result: Result<(), ErrorCode>, _response: &'static mut [u8]) {}
+    }
+
+    type Transport = CtapHidTransport<'static, Hid, Clock>;
+
+    struct Harness {
+        hid: &'static Hid,
+        transport: &'static Transport,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn new(message_len: usize) -> Self {
+            let hid = Box::leak(Box::new(Hid {
+                tx: TakeCell::empty(),
+                rx: TakeCell::empty(),
+            }));
+            let transport = Box::leak(Box::new(CtapHidTransport::new(
+                hid,
+                Box::leak(Box::new(Clock)),
+                Box::leak(Box::new([0; 64])),
+                Box::leak(Box::new([0; 64])),
+                Box::leak(vec![0; message_len].into_boxed_slice()),
+            )));
+            let client = Box::leak(Box::<TestClient>::default());
+            transport.set_client(client);
+            transport.enable().unwrap();
+            Harness {
+                hid,
+                transport,
+                client,
+            }
+        }
+
+        /// Deliver a packet from the host.
+        fn receive(&self, cid: u32, header: &[u8], data: &[u8]) {
+            let buffer = self.hid.rx.take().unwrap();
+            buffer.fill(0);
+            buffer[0..4].copy_from_slice(&cid.to_be_bytes());
+            buffer[4..4 + header.len()].copy_from_slice(header);
+            let start = 4 + header.len();
+            buffer[start..start + data.len()].copy_from_slice(data);
+            self.transport.packet_received(Ok(()), buffer, 0);
+        }
+
+        /// Deliver an initialization packet with the first bytes of `data`.
+        fn receive_init(&self, cid: u32, cmd: u8, len: usize, data: &[u8]) {
+            let len = (len as u16).to_be_bytes();
+            self.receive(cid, &[cmd, len[0], len[1]], data);
+        }
+
+        /// Complete all packets the transport sends, and return them.
+        fn sent(&self) -> Vec<[u8; 64]> {
+            let mut packets = Vec::new();
+            while let Some(buffer) = self.hid.tx.take() {
+                packets.push(*buffer);
+                self.transport.packet_transmitted(Ok(()), buffer, 0);
+            }
+            packets
+        }
+
+        /// Allocate a channel with `CTAPHID_INIT`.
+        fn allocate(&self) -> u32 {
+            self.receive_init(BROADCAST_CID, CTAPHID_INIT, INIT_NONCE_LEN, &[7; 8]);
+            let packets = self.sent();
+            assert_eq!(packets.len(), 1);
+            let response = &packets[0][7..];
+            assert_eq!(&response[0..8], &[7; 8]);
+            u32::from_be_bytes([response[8], response[9], response[10], response[11]])
+        }
+    }
+
+    fn assert_error(packets: &[[u8; 64]], cid: u32, error: u8) {
+        assert_eq!(packets.len(), 1);
+        assert_eq!(&packets[0][0..4], &cid.to_be_bytes());
+        assert_eq!(&packets[0][4..8], &[CTAPHID_ERROR, 0, 1, error]);
+    }
+
+    #[test]
+    fn fragmented_cbor() {
+        let h = Harness::new(1024);
+        let cid = h.allocate();
+        let request: Vec<u8> = (0..150).map(|i| i as u8).collect();
+
+        h.receive_init(cid, CTAPHID_CBOR, request.len(), &request[..INIT_DATA_LEN]);
+        h.receive(
+            cid,
+            &[0],
+            &request[INIT_DATA_LEN..INIT_DATA_LEN + CONT_DATA_LEN],
+        );
+        assert!(h.client.requests.borrow().is_empty());
+        h.receive(cid, &[1], &request[INIT_DATA_LEN + CONT_DATA_LEN..]);
+        assert_eq!(*h.client.requests.borrow(), vec![request.clone()]);
+
+        // The response is split the same way.
+        let response = Box::leak(request.clone().into_boxed_slice());
+        h.transport.send_response(response, request.len()).unwrap();
+        let packets = h.sent();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(&packets[0][4..7], &[CTAPHID_CBOR, 0, 150]);
+        assert_eq!(&packets[0][7..], &request[..INIT_DATA_LEN]);
+        assert_eq!(packets[1][4], 0);
+        assert_eq!(packets[2][4], 1);
+        let end = INIT_DATA_LEN + CONT_DATA_LEN;
+        assert_eq!(&packets[1][5..], &request[INIT_DATA_LEN..end]);
+        assert_eq!(&packets[2][5..5 + request.len() - end], &request[end..]);
+    }
+
+    #[test]
+    fn wrong_sequence() {
+        let h = Harness::new(1024);
+        let cid = h.allocate();
+
+        h.receive_init(cid, CTAPHID_CBOR, 100, &[1; INIT_DATA_LEN]);
+        h.receive(cid, &[1], &[1; CONT_DATA_LEN]);
+        assert_error(&h.sent(), cid, ERR_INVALID_SEQ);
+        assert!(h.client.requests.borrow().is_empty());
+
+        // The aborted request does not block the next one.
+        h.receive_init(cid, CTAPHID_CBOR, 1, &[1]);
+        assert_eq!(h.client.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn busy_channel() {
+        let h = Harness::new(1024);
+        let first = h.allocate();
+        let second = h.allocate();
+
+        h.receive_init(first, CTAPHID_CBOR, 1, &[1]);
+        assert_eq!(h.client.requests.borrow().len(), 1);
+        h.receive_init(second, CTAPHID_CBOR, 1, &[2]);
+        assert_error(&h.sent(), second, ERR_CHANNEL_BUSY);
+        assert_eq!(h.client.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn cancel() {
+        let h = Harness::new(1024);
+        let cid = h.allocate();
+
+        // Cancel without a request is ignored.
+        h.receive_init(cid, CTAPHID_CANCEL, 0, &[]);
+        assert_eq!(h.client.cancelled.get(), 0);
+        assert!(h.sent().is_empty());
+
+        h.receive_init(cid, CTAPHID_CBOR, 1, &[1]);
+        h.receive_init(cid, CTAPHID_CANCEL, 0, &[]);
+        assert_eq!(h.client.cancelled.get(), 1);
+        assert!(h.sent().is_empty());
+    }
+
+    #[test]
+    fn message_too_long() {
+        // Even with a larger buffer, the sequence number limits the length.
+        let h = Harness::new(MAX_MESSAGE_LEN + 64);
+        let cid = h.allocate();
+
+        h.receive_init(cid, CTAPHID_CBOR, MAX_MESSAGE_LEN + 1, &[1; INIT_DATA_LEN]);
+        assert_error(&h.sent(), cid, ERR_INVALID_LEN);
+
+        let h = Harness::new(256);
+        let cid = h.allocate();
+        h.receive_init(cid, CTAPHID_CBOR, 257, &[1; INIT_DATA_LEN]);
+        assert_error(&h.sent(), cid, ERR_INVALID_LEN);
+    }
+}
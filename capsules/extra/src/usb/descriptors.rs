@@ -160,6 +160,7 @@ pub enum DescriptorType {
     DeviceQualifier,
     OtherSpeedConfiguration,
     InterfacePower,
+    InterfaceAssociation = 0x0b,
     HID = 0x21,
     Report = 0x22,
     CdcInterface = 0x24,
@@ -660,6 +661,63 @@ impl Descriptor for InterfaceDescriptor {
     }
 }
 
+/// Groups the interfaces of a function of a composite device, for example
+/// the communication and data interfaces of CDC-ACM.
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+}
+
+impl Descriptor for InterfaceAssociationDescriptor {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(8); // Size of descriptor
+        buf[1].set(DescriptorType::InterfaceAssociation as u8);
+        buf[2].set(self.first_interface);
+        buf[3].set(self.interface_count);
+        buf[4].set(self.function_class);
+        buf[5].set(self.function_subclass);
+        buf[6].set(self.function_protocol);
+        buf[7].set(self.string_index);
+        8
+    }
+}
+
+/// DFU functional descriptor, which follows the interface descriptor of a
+/// Device Firmware Upgrade interface.
+pub struct DfuFunctionalDescriptor {
+    /// bitCanDnload (0x01), bitCanUpload (0x02), bitManifestationTolerant
+    /// (0x04) and bitWillDetach (0x08).
+    pub attributes: u8,
+    /// Time in ms the device waits for a bus reset after DFU_DETACH.
+    pub detach_timeout: u16,
+    /// Maximum number of bytes per DFU_DNLOAD or DFU_UPLOAD request.
+    pub transfer_size: u16,
+}
+
+impl Descriptor for DfuFunctionalDescriptor {
+    fn size(&self) -> usize {
+        9
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(9); // Size of descriptor
+        buf[1].set(0x21); // DFU functional descriptor
+        buf[2].set(self.attributes);
+        put_u16(&buf[3..5], self.detach_timeout);
+        put_u16(&buf[5..7], self.transfer_size);
+        put_u16(&buf[7..9], 0x0110); // DFU version 1.1
+        9
+    }
+}
+
 pub struct EndpointAddress(u8);
 
 impl EndpointAddress {
@@ -865,6 +923,37 @@ impl Descriptor for CdcInterfaceDescriptor {
     }
 }
 
+/// CDC Ethernet Networking functional descriptor, used by CDC-ECM.
+pub struct CdcEthernetNetworkingDescriptor {
+    /// Index of the string descriptor with the MAC address of the host side
+    /// of the link, as 12 hex digits.
+    pub mac_address_string: u8,
+    /// Maximum segment size, the largest Ethernet frame the device supports.
+    pub max_segment_size: u16,
+}
+
+impl Descriptor for CdcEthernetNetworkingDescriptor {
+    fn size(&self) -> usize {
+        13
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(13); // Size of descriptor
+        buf[1].set(DescriptorType::CdcInterface as u8);
+        buf[2].set(CdcInterfaceDescriptorSubType::EthernetNetworking as u8);
+        buf[3].set(self.mac_address_string);
+        // No Ethernet statistics.
+        for b in buf[4..8].iter() {
+            b.set(0);
+        }
+        put_u16(&buf[8..10], self.max_segment_size);
+        // No multicast filters, no power filters.
+        put_u16(&buf[10..12], 0);
+        buf[12].set(0);
+        13
+    }
+}
+
 /// The data structure sent in a CDC-ACM Set Line Coding message.
 #[derive(Debug, Copy, Clone)]
 pub struct CdcAcmSetLineCodingData {
@@ -0,0 +1,659 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Device Firmware Upgrade (DFU 1.1) function for USB
+//!
+//! Lets the host download a new image with standard tools such as
+//! `dfu-util`, over the same USB connection as the other functions of a
+//! composite device, for example the CDC-ACM console.
+//!
+//! The function starts in runtime mode. `DFU_DETACH` followed by a bus reset
+//! switches it to DFU mode, in which the host downloads the image. Each block
+//! of the download is one flash page, and is written with `hil::flash` into a
+//! region of flash: either the flash for applications, or a staging region
+//! that the image is copied from after it was verified.
+//!
+//! Images are TBF objects. When the download is complete, the credentials in
+//! the TBF footers are checked with an `AppCredentialsPolicy`, the same
+//! checkers the kernel uses to decide whether to run a process. An image that
+//! is not accepted is invalidated by erasing its first page, and the host is
+//! told the download failed with `errVERIFY`. An accepted image is copied to
+//! its destination, if there is one, and activated: on the next bus reset,
+//! which `dfu-util --reset` triggers, the `DfuClient` is told the update is
+//! complete so the board can reset into the new image.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let dfu = static_init!(
+//!     capsules_extra::usb::dfu::Dfu<'static, nrf52::nvmc::Nvmc>,
+//!     capsules_extra::usb::dfu::Dfu::new(
+//!         &base_peripherals.nvmc,
+//!         page_buffer,
+//!         checker,
+//!         staging_region, // Memory mapped staging region in flash
+//!         staging_region.as_ptr() as usize / 4096,
+//!         Some(app_page), // Copy verified images to the application flash
+//!         2,              // Interface
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&base_peripherals.nvmc, dfu);
+//! checker.set_client(dfu);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::UsbFunction;
+use super::descriptors::Descriptor;
+use super::descriptors::DfuFunctionalDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::process_checker::{AppCredentialsPolicy, AppCredentialsPolicyClient, CheckResult};
+use kernel::utilities::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::ErrorCode;
+use tock_tbf::types::TbfFooterV2Credentials;
+
+/// Class specific requests.
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+/// bitCanDnload and bitManifestationTolerant: we can download, and remain
+/// responsive after the image was manifested.
+const DFU_ATTRIBUTES: u8 = 0x01 | 0x04;
+/// Time in ms we wait for the bus reset after DFU_DETACH.
+const DETACH_TIMEOUT_MS: u16 = 1000;
+/// Time in ms the host should wait before asking for the status while we are
+/// busy.
+const POLL_TIMEOUT_MS: u32 = 20;
+
+/// States of the DFU function, with their numbers in the DFU specification.
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Runtime mode.
+    AppIdle = 0,
+    /// Runtime mode, waiting for the bus reset to switch to DFU mode.
+    AppDetach = 1,
+    /// DFU mode, waiting for a download.
+    DfuIdle = 2,
+    /// Writing a block to flash.
+    DnBusy = 4,
+    /// Waiting for the next block of the download.
+    DnloadIdle = 5,
+    /// Verifying and activating the image.
+    Manifest = 7,
+    /// A request or the download failed, the status tells why.
+    DfuError = 10,
+}
+
+/// Status codes of the DFU specification.
+#[derive(Copy, Clone, PartialEq)]
+enum Status {
+    Ok = 0x00,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrErase = 0x04,
+    ErrVerify = 0x07,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrStalledPacket = 0x0f,
+}
+
+/// The request whose data stage or status stage is in progress.
+#[derive(Copy, Clone, PartialEq)]
+enum Request {
+    None,
+    Detach,
+    /// Download of `length` bytes.
+    Dnload {
+        length: usize,
+    },
+    GetStatus,
+    GetState,
+}
+
+/// Receives notification when an update is complete.
+pub trait DfuClient {
+    /// A verified image was activated, and the host reset the bus after the
+    /// download. The board should reset to run the new image.
+    fn update_complete(&self);
+}
+
+pub struct Dfu<'a, F: hil::flash::Flash + 'static> {
+    /// The flash the image is written to.
+    flash: &'a F,
+    /// Buffer for one block of the download, which is one flash page.
+    page_buffer: TakeCell<'static, F::Page>,
+    /// Size of a flash page, and of a block of the download.
+    page_size: usize,
+    /// Checks the credentials of downloaded images.
+    policy: &'static dyn AppCredentialsPolicy<'static>,
+    client: OptionalCell<&'a dyn DfuClient>,
+
+    /// Memory mapped flash region the image is downloaded to.
+    region: &'static [u8],
+    /// First flash page of `region`.
+    first_page: usize,
+    /// First flash page verified images are copied to, if the region is only
+    /// used for staging.
+    destination: Option<usize>,
+
+    /// Number of the DFU interface.
+    interface: u8,
+    /// True in DFU mode, false in runtime mode.
+    dfu_mode: Cell<bool>,
+    state: Cell<State>,
+    status: Cell<Status>,
+    request: Cell<Request>,
+
+    /// Number of bytes received of the current block.
+    received: Cell<usize>,
+    /// Number of the next block of the download.
+    block: Cell<usize>,
+    /// Number of bytes downloaded.
+    image_length: Cell<usize>,
+
+    /// End of the integrity region of the downloaded TBF.
+    binary_end: Cell<usize>,
+    /// Offset of the next footer to check.
+    footer_offset: Cell<usize>,
+    /// Total size of the downloaded TBF.
+    total_size: Cell<usize>,
+    /// Next page to copy to the destination.
+    copy_page: Cell<usize>,
+
+    /// A verified image was activated, and takes effect on the next reset.
+    activated: Cell<bool>,
+}
+
+impl<'a, F: hil::flash::Flash> Dfu<'a, F> {
+    /// Create a DFU function that downloads images into `region`, which
+    /// starts at flash page `first_page`. If `destination` is set, verified
+    /// images are copied to the flash starting at that page.
+    pub fn new(
+        flash: &'a F,
+        page_buffer: &'static mut F::Page,
+        policy: &'static dyn AppCredentialsPolicy<'static>,
+        region: &'static [u8],
+        first_page: usize,
+        destination: Option<usize>,
+        interface: u8,
+    ) -> Self {
+        let page_size = page_buffer.as_mut().len();
+        Self {
+            flash,
+            page_buffer: TakeCell::new(page_buffer),
+            page_size,
+            policy,
+            client: OptionalCell::empty(),
+            region,
+            first_page,
+            destination,
+            interface,
+            dfu_mode: Cell::new(false),
+            state: Cell::new(State::AppIdle),
+            status: Cell::new(Status::Ok),
+            request: Cell::new(Request::None),
+            received: Cell::new(0),
+            block: Cell::new(0),
+            image_length: Cell::new(0),
+            binary_end: Cell::new(0),
+            footer_offset: Cell::new(0),
+            total_size: Cell::new(0),
+            copy_page: Cell::new(0),
+            activated: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn DfuClient) {
+        self.client.set(client);
+    }
+
+    /// Enter the error state with `status`.
+    fn fail(&self, status: Status) {
+        self.status.set(status);
+        self.state.set(State::DfuError);
+    }
+
+    /// Reject a request, and stall it unless it already set a more specific
+    /// status.
+    fn stall(&self) -> hil::usb::CtrlSetupResult {
+        if self.state.get() != State::DfuError {
+            self.fail(Status::ErrStalledPacket);
+        }
+        hil::usb::CtrlSetupResult::ErrGeneric
+    }
+
+    /// Handle DFU_DNLOAD of `length` bytes of block `block`.
+    fn dnload(&self, block: u16, length: usize) -> hil::usb::CtrlSetupResult {
+        match self.state.get() {
+            State::DfuIdle if length > 0 => {
+                self.block.set(0);
+                self.image_length.set(0);
+                self.activated.set(false);
+            }
+            State::DnloadIdle => {}
+            _ => return self.stall(),
+        }
+        if length == 0 {
+            // The download is complete, verify the image once the request
+            // is done.
+            self.request.set(Request::Dnload { length });
+            return hil::usb::CtrlSetupResult::Ok;
+        }
+
+        let region_pages = self.region.len() / self.page_size;
+        if length > self.page_size {
+            return self.stall();
+        }
+        if block as usize != self.block.get() % 0x10000
+            || self.block.get() >= region_pages
+            || self.image_length.get() % self.page_size != 0
+        {
+            // Blocks must be consecutive, and only the last one may be
+            // shorter than a page.
+            self.fail(Status::ErrAddress);
+            return self.stall();
+        }
+        self.received.set(0);
+        self.request.set(Request::Dnload { length });
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    /// Write the block received to flash.
+    fn write_block(&self, length: usize) {
+        let page = self.first_page + self.block.get();
+        let result = match self.page_buffer.take() {
+            Some(page_buffer) => {
+                // Pad a short last block with erased flash.
+                page_buffer.as_mut()[length..].fill(0xff);
+                self.flash
+                    .write_page(page, page_buffer)
+                    .map_err(|(_, page_buffer)| {
+                        self.page_buffer.replace(page_buffer);
+                    })
+            }
+            None => Err(()),
+        };
+        match result {
+            Ok(()) => self.state.set(State::DnBusy),
+            Err(()) => self.fail(Status::ErrWrite),
+        }
+    }
+
+    /// Start verifying the downloaded image.
+    fn manifest(&self) {
+        if self.image_length.get() == 0 {
+            self.fail(Status::ErrNotDone);
+            return;
+        }
+        self.state.set(State::Manifest);
+        match self.parse_image() {
+            Ok(()) => self.check_next_footer(),
+            Err(status) => self.reject(status),
+        }
+    }
+
+    /// Parse the TBF header of the downloaded image, and find its integrity
+    /// region and footers.
+    fn parse_image(&self) -> Result<(), Status> {
+        let region = self.region;
+        let image = &region[..self.image_length.get()];
+        let lengths = image
+            .get(0..8)
+            .and_then(|lengths| lengths.try_into().ok())
+            .ok_or(Status::ErrFile)?;
+        let (version, header_length, total_size) =
+            tock_tbf::parse::parse_tbf_header_lengths(lengths).map_err(|_| Status::ErrFile)?;
+        let header = image
+            .get(..header_length as usize)
+            .ok_or(Status::ErrFile)
+            .and_then(|header| {
+                tock_tbf::parse::parse_tbf_header(header, version).map_err(|_| Status::ErrFile)
+            })?;
+
+        let total_size = total_size as usize;
+        let binary_end = header.get_binary_end() as usize;
+        if !header.is_app() || total_size > image.len() || binary_end > total_size {
+            return Err(Status::ErrFile);
+        }
+        self.binary_end.set(binary_end);
+        self.footer_offset.set(binary_end);
+        self.total_size.set(total_size);
+        Ok(())
+    }
+
+    /// Check the credentials in the next footer of the image. Without more
+    /// footers, the image is accepted unless the policy requires credentials.
+    fn check_next_footer(&self) {
+        let region = self.region;
+        let integrity_region = &region[..self.binary_end.get()];
+        loop {
+            let footers = &region[self.footer_offset.get()..self.total_size.get()];
+            let (footer, length) = match tock_tbf::parse::parse_tbf_footer(footers) {
+                Ok((footer, length)) if length as usize + 4 <= footers.len() => (footer, length),
+                _ => {
+                    // No more footers.
+                    if self.policy.require_credentials() {
+                        self.reject(Status::ErrVerify);
+                    } else {
+                        self.activate();
+                    }
+                    return;
+                }
+            };
+            self.footer_offset
+                .set(self.footer_offset.get() + length as usize + 4);
+            match self.policy.check_credentials(footer, integrity_region) {
+                Ok(()) => return,
+                // The checker does not handle this kind of credentials.
+                Err((ErrorCode::NOSUPPORT, _, _)) | Err((ErrorCode::ALREADY, _, _)) => {}
+                Err(_) => {
+                    self.reject(Status::ErrVerify);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The image was not accepted. Erase its first page so that it is never
+    /// loaded, then report `status` to the host.
+    fn reject(&self, status: Status) {
+        self.status.set(status);
+        if self.flash.erase_page(self.first_page).is_err() {
+            self.fail(Status::ErrErase);
+        }
+    }
+
+    /// The image was accepted, copy it to its destination.
+    fn activate(&self) {
+        match self.destination {
+            Some(_) => {
+                self.copy_page.set(0);
+                self.copy_next_page();
+            }
+            None => self.manifested(),
+        }
+    }
+
+    /// Copy the next page of the image to the destination.
+    fn copy_next_page(&self) {
+        let destination = match self.destination {
+            Some(destination) => destination,
+            None => return,
+        };
+        let index = self.copy_page.get();
+        let start = index * self.page_size;
+        if start >= self.total_size.get() {
+            self.manifested();
+            return;
+        }
+
+        let end = cmp::min(start + self.page_size, self.region.len());
+        let result = match self.page_buffer.take() {
+            Some(page_buffer) => {
+                let page = page_buffer.as_mut();
+                page[..end - start].copy_from_slice(&self.region[start..end]);
+                page[end - start..].fill(0xff);
+                self.flash
+                    .write_page(destination + index, page_buffer)
+                    .map_err(|(_, page_buffer)| {
+                        self.page_buffer.replace(page_buffer);
+                    })
+            }
+            None => Err(()),
+        };
+        if result.is_err() {
+            self.fail(Status::ErrWrite);
+        }
+    }
+
+    /// The image is verified and in place.
+    fn manifested(&self) {
+        self.activated.set(true);
+        self.status.set(Status::Ok);
+        self.state.set(State::DfuIdle);
+    }
+
+    /// Write the response to DFU_GETSTATUS or DFU_GETSTATE into `packet`.
+    fn status_response(&self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        match self.request.get() {
+            Request::GetStatus => {
+                let poll_timeout = match self.state.get() {
+                    State::DnBusy | State::Manifest => POLL_TIMEOUT_MS,
+                    _ => 0,
+                }
+                .to_le_bytes();
+                packet[0].set(self.status.get() as u8);
+                packet[1].set(poll_timeout[0]);
+                packet[2].set(poll_timeout[1]);
+                packet[3].set(poll_timeout[2]);
+                packet[4].set(self.state.get() as u8);
+                packet[5].set(0); // No status description string
+                hil::usb::CtrlInResult::Packet(6, true)
+            }
+            Request::GetState => {
+                packet[0].set(self.state.get() as u8);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    fn interface_descriptor(&self) -> InterfaceDescriptor {
+        InterfaceDescriptor {
+            interface_number: self.interface,
+            interface_class: 0xfe,    // Application specific
+            interface_subclass: 0x01, // Device Firmware Upgrade
+            // Runtime protocol, or DFU mode protocol.
+            interface_protocol: if self.dfu_mode.get() { 0x02 } else { 0x01 },
+            num_endpoints: 0,
+            ..InterfaceDescriptor::default()
+        }
+    }
+}
+
+impl<'a, F: hil::flash::Flash> UsbFunction<'a> for Dfu<'a, F> {
+    fn interfaces(&self) -> (u8, u8) {
+        (self.interface, 1)
+    }
+
+    fn has_endpoint(&self, _endpoint: usize) -> bool {
+        // DFU only uses the default control endpoint.
+        false
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+        f(&self.interface_descriptor());
+        f(&DfuFunctionalDescriptor {
+            attributes: DFU_ATTRIBUTES,
+            detach_timeout: DETACH_TIMEOUT_MS,
+            transfer_size: self.page_size as u16,
+        });
+    }
+
+    fn enable(&'a self) {}
+
+    fn bus_reset(&'a self) {
+        match self.state.get() {
+            State::AppDetach => {
+                self.dfu_mode.set(true);
+                self.state.set(State::DfuIdle);
+            }
+            State::DfuIdle if self.activated.get() => {
+                self.activated.set(false);
+                self.dfu_mode.set(false);
+                self.state.set(State::AppIdle);
+                self.client.map(|client| client.update_complete());
+            }
+            _ => {}
+        }
+        self.request.set(Request::None);
+    }
+
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        match setup.request_type.request_type() {
+            RequestType::Class => {}
+            _ => return hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+
+        let request = match (self.dfu_mode.get(), setup.request_code) {
+            (_, DFU_GETSTATUS) => Request::GetStatus,
+            (_, DFU_GETSTATE) => Request::GetState,
+            (false, DFU_DETACH) if self.state.get() == State::AppIdle => {
+                self.state.set(State::AppDetach);
+                Request::Detach
+            }
+            (true, DFU_DNLOAD) => return self.dnload(setup.value, setup.length as usize),
+            (true, DFU_CLRSTATUS) if self.state.get() == State::DfuError => {
+                self.status.set(Status::Ok);
+                self.state.set(State::DfuIdle);
+                Request::None
+            }
+            (true, DFU_ABORT) => match self.state.get() {
+                State::DfuIdle | State::DnloadIdle => {
+                    self.state.set(State::DfuIdle);
+                    Request::None
+                }
+                _ => return self.stall(),
+            },
+            // DFU_UPLOAD is not supported.
+            _ => return self.stall(),
+        };
+        self.request.set(request);
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    fn ctrl_in(&'a self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        self.status_response(packet)
+    }
+
+    fn ctrl_out(
+        &'a self,
+        packet: &[VolatileCell<u8>],
+        packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        let length = match self.request.get() {
+            Request::Dnload { length } => length,
+            _ => return hil::usb::CtrlOutResult::Halted,
+        };
+        let received = self.received.get();
+        let count = cmp::min(packet_bytes as usize, length - received);
+        self.page_buffer
+            .map(|page_buffer| {
+                let page = &mut page_buffer.as_mut()[received..received + count];
+                for (byte, cell) in page.iter_mut().zip(packet) {
+                    *byte = cell.get();
+                }
+                self.received.set(received + count);
+                hil::usb::CtrlOutResult::Ok
+            })
+            .unwrap_or(hil::usb::CtrlOutResult::Halted)
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        match self.request.replace(Request::None) {
+            Request::Dnload { length: 0 } => self.manifest(),
+            Request::Dnload { length } => {
+                if self.received.get() == length {
+                    self.write_block(length);
+                } else {
+                    self.fail(Status::ErrNotDone);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn packet_in(&'a self, _transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        hil::usb::InResult::Error
+    }
+
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {}
+}
+
+impl<F: hil::flash::Flash> hil::flash::Client<F> for Dfu<'_, F> {
+    fn read_complete(
+        &self,
+        page_buffer: &'static mut F::Page,
+        _result: Result<(), hil::flash::Error>,
+    ) {
+        self.page_buffer.replace(page_buffer);
+    }
+
+    fn write_complete(
+        &self,
+        page_buffer: &'static mut F::Page,
+        result: Result<(), hil::flash::Error>,
+    ) {
+        self.page_buffer.replace(page_buffer);
+        match self.state.get() {
+            State::DnBusy => {
+                if result.is_err() {
+                    self.fail(Status::ErrWrite);
+                    return;
+                }
+                self.image_length
+                    .set(self.block.get() * self.page_size + self.received.get());
+                self.block.set(self.block.get() + 1);
+                self.state.set(State::DnloadIdle);
+            }
+            State::Manifest => {
+                if result.is_err() {
+                    self.fail(Status::ErrWrite);
+                    return;
+                }
+                self.copy_page.set(self.copy_page.get() + 1);
+                self.copy_next_page();
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, result: Result<(), hil::flash::Error>) {
+        if self.state.get() == State::Manifest {
+            match result {
+                Ok(()) => self.fail(self.status.get()),
+                Err(_) => self.fail(Status::ErrErase),
+            }
+        }
+    }
+}
+
+impl<F: hil::flash::Flash> AppCredentialsPolicyClient<'static> for Dfu<'_, F> {
+    fn check_done(
+        &self,
+        result: Result<CheckResult, ErrorCode>,
+        _credentials: TbfFooterV2Credentials,
+        _integrity_region: &'static [u8],
+    ) {
+        if self.state.get() != State::Manifest {
+            return;
+        }
+        match result {
+            Ok(CheckResult::Accept(_)) => self.activate(),
+            Ok(CheckResult::Reject) => self.reject(Status::ErrVerify),
+            // The checker ignored these credentials, try the next ones.
+            Ok(CheckResult::Pass) | Err(_) => self.check_next_footer(),
+        }
+    }
+}
@@ -0,0 +1,587 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! USB HID device with a report descriptor set at runtime.
+//!
+//! Unlike the keyboard and CTAP devices, which have a fixed report
+//! descriptor, this device describes whatever reports its user registers
+//! with `HidReports::set_report_descriptor()`, for example a mouse, a
+//! gamepad or vendor defined reports. The descriptor is checked with
+//! `hid_report::parse()`, and input reports are sent with the length it
+//! describes.
+//!
+//! The device is one function of a composite device (see the `composite`
+//! module), with an interrupt IN and an interrupt OUT endpoint. If the host
+//! has already configured the device when a new descriptor is registered,
+//! the device detaches from the bus and attaches again, so that the host
+//! enumerates it again and reads the new descriptor.
+//!
+//! Besides the interrupt endpoints, the host can send output and feature
+//! reports with the HID `SET_REPORT` request, which are passed to the
+//! `HidReportsClient`, and read the feature report set with
+//! `HidReports::set_feature_report()` with the `GET_REPORT` request.
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::UsbFunction;
+use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use super::hid_report::{self, ReportLengths, ReportType, MAX_REPORT_LEN};
+
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::ErrorCode;
+
+/// HID class specific requests.
+const HID_GET_REPORT: u8 = 0x01;
+const HID_SET_REPORT: u8 = 0x09;
+const HID_SET_IDLE: u8 = 0x0a;
+const HID_SET_PROTOCOL: u8 = 0x0b;
+
+const OUT_BUFFER: usize = 0;
+const IN_BUFFER: usize = 1;
+
+/// Client for the reports the host sends with control transfers.
+pub trait HidReportsClient {
+    /// The host sent an output or feature report with the `SET_REPORT`
+    /// request. `report` starts with the report ID if the descriptor uses
+    /// report IDs.
+    fn report_set(&self, report_type: ReportType, report: &[u8]);
+}
+
+/// A HID device whose reports are described at runtime.
+pub trait HidReports<'a> {
+    fn set_reports_client(&self, client: &'a dyn HidReportsClient);
+
+    /// Check `descriptor` and use it as the report descriptor of the device.
+    ///
+    /// Returns the errors of `hid_report::parse()`, and `SIZE` if the
+    /// descriptor does not fit in the buffer of the device.
+    fn set_report_descriptor(&self, descriptor: &[u8]) -> Result<ReportLengths, ErrorCode>;
+
+    /// Set the feature report the device returns to `GET_REPORT` requests.
+    /// `report` starts with the report ID if the descriptor uses report IDs.
+    ///
+    /// Returns `SIZE` if `report` is longer than the longest feature report
+    /// of the descriptor.
+    fn set_feature_report(&self, report: &[u8]) -> Result<(), ErrorCode>;
+}
+
+/// The HID descriptor, which refers to the report descriptor.
+struct HidDescriptor {
+    report_descriptor_len: Cell<u16>,
+}
+
+impl Descriptor for HidDescriptor {
+    fn size(&self) -> usize {
+        9
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        let [hid_low, hid_high] = 0x0111u16.to_le_bytes();
+        let [len_low, len_high] = self.report_descriptor_len.get().to_le_bytes();
+        let bytes = [
+            9,
+            DescriptorType::HID as u8,
+            hid_low,
+            hid_high,
+            HIDCountryCode::NotSupported as u8,
+            1,
+            DescriptorType::Report as u8,
+            len_low,
+            len_high,
+        ];
+        for (cell, byte) in buf.iter().zip(bytes.iter()) {
+            cell.set(*byte);
+        }
+        bytes.len()
+    }
+}
+
+/// The report descriptor registered at runtime.
+struct RegisteredReportDescriptor {
+    buffer: TakeCell<'static, [u8]>,
+    len: Cell<usize>,
+}
+
+impl Descriptor for RegisteredReportDescriptor {
+    fn size(&self) -> usize {
+        self.len.get()
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        let len = self.len.get();
+        self.buffer.map(|descriptor| {
+            for (cell, byte) in buf.iter().zip(descriptor[..len].iter()) {
+                cell.set(*byte);
+            }
+        });
+        len
+    }
+}
+
+/// State of the data stage of a class request.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum CtrlState {
+    Idle,
+    /// Sending the feature report, up to `len` bytes.
+    GetReport {
+        len: usize,
+    },
+    /// Receiving a report of `len` bytes, of which `received` arrived.
+    SetReport {
+        report_type: ReportType,
+        len: usize,
+        received: usize,
+    },
+}
+
+pub struct GenericHid<'a, U: 'a> {
+    controller: &'a U,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; 2],
+
+    /// Number of the HID interface.
+    interface: u8,
+    /// Interrupt IN/OUT endpoint for the reports.
+    endpoint: usize,
+
+    hid_descriptor: HidDescriptor,
+    report_descriptor: RegisteredReportDescriptor,
+    lengths: Cell<ReportLengths>,
+
+    /// Whether the host has configured the device.
+    configured: Cell<bool>,
+
+    client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; 64]>>,
+    reports_client: OptionalCell<&'a dyn HidReportsClient>,
+
+    /// A buffer to hold the data we want to send
+    send_buffer: TakeCell<'static, [u8; 64]>,
+
+    /// A holder for the buffer to receive bytes into. We use this as a flag as
+    /// well, if we have a buffer then we are actively doing a receive.
+    recv_buffer: TakeCell<'static, [u8; 64]>,
+
+    ctrl_state: Cell<CtrlState>,
+    /// The feature report for `GET_REPORT` requests.
+    feature_report: TakeCell<'static, [u8; MAX_REPORT_LEN]>,
+    feature_report_len: Cell<usize>,
+    /// The report of the current `SET_REPORT` request.
+    ctrl_report: TakeCell<'static, [u8; MAX_REPORT_LEN]>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> GenericHid<'a, U> {
+    /// Create a HID function for a composite device, using the interface
+    /// `interface` and the interrupt IN and OUT endpoints `endpoint`. The
+    /// report descriptor is stored in `report_descriptor_buffer`, which
+    /// limits its length.
+    pub fn new_function(
+        controller: &'a U,
+        interface: u8,
+        endpoint: usize,
+        report_descriptor_buffer: &'static mut [u8],
+        feature_report: &'static mut [u8; MAX_REPORT_LEN],
+        ctrl_report: &'static mut [u8; MAX_REPORT_LEN],
+    ) -> Self {
+        GenericHid {
+            controller,
+            buffers: [Buffer64::default(), Buffer64::default()],
+            interface,
+            endpoint,
+            hid_descriptor: HidDescriptor {
+                report_descriptor_len: Cell::new(0),
+            },
+            report_descriptor: RegisteredReportDescriptor {
+                buffer: TakeCell::new(report_descriptor_buffer),
+                len: Cell::new(0),
+            },
+            lengths: Cell::new(ReportLengths::default()),
+            configured: Cell::new(false),
+            client: OptionalCell::empty(),
+            reports_client: OptionalCell::empty(),
+            send_buffer: TakeCell::empty(),
+            recv_buffer: TakeCell::empty(),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            feature_report: TakeCell::new(feature_report),
+            feature_report_len: Cell::new(0),
+            ctrl_report: TakeCell::new(ctrl_report),
+        }
+    }
+
+    pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; 64]>) {
+        self.client.set(client);
+    }
+
+    /// The lengths of the reports of the registered descriptor.
+    pub fn report_lengths(&self) -> ReportLengths {
+        self.lengths.get()
+    }
+
+    fn stall(&self) -> hil::usb::CtrlSetupResult {
+        self.ctrl_state.set(CtrlState::Idle);
+        hil::usb::CtrlSetupResult::ErrNonstandardRequest
+    }
+
+    /// Build the interface and endpoint descriptors of the device.
+    fn hid_descriptors(&self) -> (InterfaceDescriptor, [EndpointDescriptor; 2]) {
+        (
+            InterfaceDescriptor {
+                interface_number: self.interface,
+                interface_class: 0x03,    // HID
+                interface_subclass: 0x00, // No subclass
+                interface_protocol: 0x00, // No protocol
+                num_endpoints: 2,
+                ..InterfaceDescriptor::default()
+            },
+            [
+                EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new_const(
+                        self.endpoint,
+                        TransferDirection::DeviceToHost,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: 64,
+                    interval: 5,
+                },
+                EndpointDescriptor {
+                    endpoint_address: EndpointAddress::new_const(
+                        self.endpoint,
+                        TransferDirection::HostToDevice,
+                    ),
+                    transfer_type: TransferType::Interrupt,
+                    max_packet_size: 64,
+                    interval: 5,
+                },
+            ],
+        )
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> HidReports<'a> for GenericHid<'a, U> {
+    fn set_reports_client(&self, client: &'a dyn HidReportsClient) {
+        self.reports_client.set(client);
+    }
+
+    fn set_report_descriptor(&self, descriptor: &[u8]) -> Result<ReportLengths, ErrorCode> {
+        let lengths = hid_report::parse(descriptor)?;
+        self.report_descriptor
+            .buffer
+            .map_or(Err(ErrorCode::BUSY), |buffer| {
+                if descriptor.len() > buffer.len() {
+                    return Err(ErrorCode::SIZE);
+                }
+                buffer[..descriptor.len()].copy_from_slice(descriptor);
+                Ok(())
+            })?;
+        self.report_descriptor.len.set(descriptor.len());
+        self.hid_descriptor
+            .report_descriptor_len
+            .set(descriptor.len() as u16);
+        self.lengths.set(lengths);
+        self.feature_report_len.set(0);
+
+        // Make the host read the new descriptor.
+        if self.configured.get() {
+            self.configured.set(false);
+            self.controller.detach();
+            self.controller.attach();
+        }
+        Ok(lengths)
+    }
+
+    fn set_feature_report(&self, report: &[u8]) -> Result<(), ErrorCode> {
+        if report.len() > self.lengths.get().feature {
+            return Err(ErrorCode::SIZE);
+        }
+        self.feature_report.map_or(Err(ErrorCode::BUSY), |buffer| {
+            buffer[..report.len()].copy_from_slice(report);
+            self.feature_report_len.set(report.len());
+            Ok(())
+        })
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for GenericHid<'a, U> {
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; 64],
+    ) -> Result<usize, (ErrorCode, &'static mut [u8; 64])> {
+        let len = self.lengths.get().input;
+        if len == 0 {
+            return Err((ErrorCode::OFF, send));
+        }
+
+        self.send_buffer.replace(send);
+        if self.configured.get() {
+            self.controller.endpoint_resume_in(self.endpoint);
+        }
+
+        Ok(len)
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+        match self.send_buffer.take() {
+            Some(buf) => Ok(buf),
+            None => Err(ErrorCode::BUSY),
+        }
+    }
+
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; 64],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; 64])> {
+        self.recv_buffer.replace(recv);
+        if self.configured.get() {
+            self.controller.endpoint_resume_out(self.endpoint);
+        }
+        Ok(())
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; 64], ErrorCode> {
+        match self.recv_buffer.take() {
+            Some(buf) => Ok(buf),
+            None => Err(ErrorCode::BUSY),
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for GenericHid<'a, U> {
+    fn interfaces(&self) -> (u8, u8) {
+        (self.interface, 1)
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+        let (interface_descriptor, endpoint_descriptors) = self.hid_descriptors();
+        f(&interface_descriptor);
+        f(&self.hid_descriptor);
+        for descriptor in endpoint_descriptors.iter() {
+            f(descriptor);
+        }
+    }
+
+    fn interface_descriptor(
+        &self,
+        _interface: u8,
+        descriptor_type: DescriptorType,
+    ) -> Option<&dyn Descriptor> {
+        match descriptor_type {
+            DescriptorType::HID => Some(&self.hid_descriptor),
+            DescriptorType::Report => Some(&self.report_descriptor),
+            _ => None,
+        }
+    }
+
+    fn enable(&'a self) {
+        self.controller
+            .endpoint_set_out_buffer(self.endpoint, &self.buffers[OUT_BUFFER].buf);
+        self.controller
+            .endpoint_set_in_buffer(self.endpoint, &self.buffers[IN_BUFFER].buf);
+        self.controller
+            .endpoint_in_out_enable(TransferType::Interrupt, self.endpoint);
+    }
+
+    fn bus_reset(&'a self) {
+        self.configured.set(false);
+        self.ctrl_state.set(CtrlState::Idle);
+    }
+
+    fn configured(&'a self) {
+        self.configured.set(true);
+        // Send or receive reports queued before the host configured the
+        // device.
+        if self.send_buffer.is_some() {
+            self.controller.endpoint_resume_in(self.endpoint);
+        }
+        if self.recv_buffer.is_some() {
+            self.controller.endpoint_resume_out(self.endpoint);
+        }
+    }
+
+    /// Handle `GET_REPORT` for the feature report and `SET_REPORT` for
+    /// output and feature reports. The idle rate and protocol are accepted
+    /// and ignored.
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        match setup.request_type.request_type() {
+            RequestType::Class => {}
+            _ => return self.stall(),
+        }
+        let [report_id, report_type] = setup.value.to_le_bytes();
+        let report_type = ReportType::from_u8(report_type);
+        let lengths = self.lengths.get();
+        let state = match (setup.request_code, report_type) {
+            (HID_GET_REPORT, Some(ReportType::Feature)) => {
+                let len = self.feature_report_len.get();
+                let matches = self.feature_report.map_or(false, |report| {
+                    len > 0 && (!lengths.report_ids || report[0] == report_id)
+                });
+                if !matches {
+                    return self.stall();
+                }
+                CtrlState::GetReport {
+                    len: cmp::min(len, setup.length as usize),
+                }
+            }
+            (HID_SET_REPORT, Some(report_type @ (ReportType::Output | ReportType::Feature))) => {
+                let len = setup.length as usize;
+                if len == 0 || len > lengths.get(report_type) {
+                    return self.stall();
+                }
+                CtrlState::SetReport {
+                    report_type,
+                    len,
+                    received: 0,
+                }
+            }
+            (HID_SET_IDLE | HID_SET_PROTOCOL, _) => CtrlState::Idle,
+            _ => return self.stall(),
+        };
+        self.ctrl_state.set(state);
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    fn ctrl_in(&'a self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::GetReport { len } => {
+                self.feature_report
+                    .map_or(hil::usb::CtrlInResult::Error, |report| {
+                        // Reports fit in one packet.
+                        for (cell, byte) in packet.iter().zip(report[..len].iter()) {
+                            cell.set(*byte);
+                        }
+                        self.ctrl_state.set(CtrlState::Idle);
+                        hil::usb::CtrlInResult::Packet(len, true)
+                    })
+            }
+            _ => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    fn ctrl_out(
+        &'a self,
+        packet: &[VolatileCell<u8>],
+        packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::SetReport {
+                report_type,
+                len,
+                received,
+            } => self
+                .ctrl_report
+                .map_or(hil::usb::CtrlOutResult::Halted, |report| {
+                    let count = cmp::min(packet_bytes as usize, len - received);
+                    for (byte, cell) in report[received..received + count]
+                        .iter_mut()
+                        .zip(packet.iter())
+                    {
+                        *byte = cell.get();
+                    }
+                    self.ctrl_state.set(CtrlState::SetReport {
+                        report_type,
+                        len,
+                        received: received + count,
+                    });
+                    hil::usb::CtrlOutResult::Ok
+                }),
+            _ => hil::usb::CtrlOutResult::Halted,
+        }
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        if let CtrlState::SetReport {
+            report_type,
+            received,
+            ..
+        } = self.ctrl_state.replace(CtrlState::Idle)
+        {
+            self.ctrl_report.map(|report| {
+                self.reports_client.map(|client| {
+                    client.report_set(report_type, &report[..received]);
+                });
+            });
+        }
+    }
+
+    /// Send the pending input report, with the length of the input reports
+    /// of the descriptor.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                self.send_buffer
+                    .take()
+                    .map_or(hil::usb::InResult::Delay, |buf| {
+                        let len = self.lengths.get().input;
+                        let packet = &self.buffers[IN_BUFFER].buf;
+                        for i in 0..len {
+                            packet[i].set(buf[i]);
+                        }
+
+                        // Put the TX buffer back so we can keep sending from it.
+                        self.send_buffer.replace(buf);
+
+                        hil::usb::InResult::Packet(len)
+                    })
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                hil::usb::InResult::Error
+            }
+        }
+    }
+
+    /// Receive an output report.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                // Apply back pressure until we get a receive call.
+                self.recv_buffer
+                    .take()
+                    .map_or(hil::usb::OutResult::Delay, |buf| {
+                        let copy_length = cmp::min(packet_bytes as usize, buf.len());
+                        let packet = &self.buffers[OUT_BUFFER].buf;
+                        for i in 0..copy_length {
+                            buf[i] = packet[i].get();
+                        }
+
+                        self.client.map(move |client| {
+                            client.packet_received(Ok(()), buf, endpoint);
+                        });
+
+                        hil::usb::OutResult::Ok
+                    })
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                hil::usb::OutResult::Error
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        self.send_buffer.take().map(|buf| {
+            self.client.map(move |client| {
+                client.packet_transmitted(Ok(()), buf, endpoint);
+            });
+        });
+    }
+}
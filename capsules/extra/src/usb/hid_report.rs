@@ -0,0 +1,596 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! HID report descriptors.
+//!
+//! `ReportDescriptorBuilder` writes report descriptors item by item, and
+//! `parse()` checks a report descriptor, for example one an app registers at
+//! runtime, and finds the length of the reports it describes.
+//!
+//! Only short items are supported, as defined in section 6.2.2 of the
+//! [HID specification](https://www.usb.org/sites/default/files/hid1_11.pdf).
+//!
+//! ```rust,ignore
+//! // A three button mouse.
+//! let mut buffer = [0; 64];
+//! let len = ReportDescriptorBuilder::new(&mut buffer)
+//!     .usage_page(0x01) // Generic Desktop
+//!     .usage(0x02) // Mouse
+//!     .collection(Collection::Application)
+//!     .usage(0x01) // Pointer
+//!     .collection(Collection::Physical)
+//!     .usage_page(0x09) // Buttons
+//!     .usage_minimum(1)
+//!     .usage_maximum(3)
+//!     .logical_minimum(0)
+//!     .logical_maximum(1)
+//!     .report_size(1)
+//!     .report_count(3)
+//!     .input(main_flags::DATA_VARIABLE_ABSOLUTE)
+//!     .report_size(5)
+//!     .report_count(1)
+//!     .input(main_flags::CONSTANT)
+//!     .usage_page(0x01) // Generic Desktop
+//!     .usage(0x30) // X
+//!     .usage(0x31) // Y
+//!     .logical_minimum(-127)
+//!     .logical_maximum(127)
+//!     .report_size(8)
+//!     .report_count(2)
+//!     .input(main_flags::DATA_VARIABLE_RELATIVE)
+//!     .end_collection()
+//!     .end_collection()
+//!     .finish()?;
+//! ```
+
+use kernel::ErrorCode;
+
+/// The longest report supported, which fits in one packet of a full speed
+/// interrupt endpoint, including the report ID.
+pub const MAX_REPORT_LEN: usize = 64;
+
+/// How many different report IDs a descriptor can use.
+const MAX_REPORT_IDS: usize = 8;
+
+/// How deep the global item state can be pushed.
+const MAX_PUSH_DEPTH: usize = 4;
+
+/// Flags of the Input, Output and Feature main items.
+pub mod main_flags {
+    pub const DATA: u32 = 0x00;
+    pub const CONSTANT: u32 = 0x01;
+    pub const ARRAY: u32 = 0x00;
+    pub const VARIABLE: u32 = 0x02;
+    pub const ABSOLUTE: u32 = 0x00;
+    pub const RELATIVE: u32 = 0x04;
+    pub const WRAP: u32 = 0x08;
+    pub const NON_LINEAR: u32 = 0x10;
+    pub const NO_PREFERRED: u32 = 0x20;
+    pub const NULL_STATE: u32 = 0x40;
+    pub const VOLATILE: u32 = 0x80;
+    pub const BUFFERED_BYTES: u32 = 0x100;
+
+    pub const DATA_VARIABLE_ABSOLUTE: u32 = DATA | VARIABLE | ABSOLUTE;
+    pub const DATA_VARIABLE_RELATIVE: u32 = DATA | VARIABLE | RELATIVE;
+}
+
+// Item prefixes, without the size bits.
+const INPUT: u8 = 0x80;
+const OUTPUT: u8 = 0x90;
+const FEATURE: u8 = 0xb0;
+const COLLECTION: u8 = 0xa0;
+const END_COLLECTION: u8 = 0xc0;
+const USAGE_PAGE: u8 = 0x04;
+const LOGICAL_MINIMUM: u8 = 0x14;
+const LOGICAL_MAXIMUM: u8 = 0x24;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_ID: u8 = 0x84;
+const REPORT_COUNT: u8 = 0x94;
+const PUSH: u8 = 0xa4;
+const POP: u8 = 0xb4;
+const USAGE: u8 = 0x08;
+const USAGE_MINIMUM: u8 = 0x18;
+const USAGE_MAXIMUM: u8 = 0x28;
+const LONG_ITEM: u8 = 0xfe;
+
+/// Types of collections.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Collection {
+    Physical = 0x00,
+    Application = 0x01,
+    Logical = 0x02,
+    Report = 0x03,
+    NamedArray = 0x04,
+    UsageSwitch = 0x05,
+    UsageModifier = 0x06,
+}
+
+/// Types of reports, as used in the HID class requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+impl ReportType {
+    pub fn from_u8(value: u8) -> Option<ReportType> {
+        match value {
+            1 => Some(ReportType::Input),
+            2 => Some(ReportType::Output),
+            3 => Some(ReportType::Feature),
+            _ => None,
+        }
+    }
+}
+
+/// Writes a report descriptor into a buffer.
+///
+/// The first error, such as running out of space or closing a collection
+/// that is not open, is kept and returned by `finish()`.
+pub struct ReportDescriptorBuilder<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+    depth: usize,
+    error: Option<ErrorCode>,
+}
+
+impl<'b> ReportDescriptorBuilder<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> Self {
+        Self {
+            buffer,
+            len: 0,
+            depth: 0,
+            error: None,
+        }
+    }
+
+    /// Write an item with `prefix` and `data`, encoded in `size` bytes.
+    fn item(&mut self, prefix: u8, data: u32, size: usize) -> &mut Self {
+        if self.error.is_some() {
+            return self;
+        }
+        if self.len + 1 + size > self.buffer.len() {
+            self.error = Some(ErrorCode::SIZE);
+            return self;
+        }
+        let size_code = match size {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        self.buffer[self.len] = prefix | size_code;
+        self.buffer[self.len + 1..self.len + 1 + size].copy_from_slice(&data.to_le_bytes()[..size]);
+        self.len += 1 + size;
+        self
+    }
+
+    fn unsigned(&mut self, prefix: u8, data: u32) -> &mut Self {
+        let size = if data <= 0xff {
+            1
+        } else if data <= 0xffff {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, data, size)
+    }
+
+    fn signed(&mut self, prefix: u8, data: i32) -> &mut Self {
+        let size = if i8::try_from(data).is_ok() {
+            1
+        } else if i16::try_from(data).is_ok() {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, data as u32, size)
+    }
+
+    pub fn usage_page(&mut self, page: u16) -> &mut Self {
+        self.unsigned(USAGE_PAGE, page as u32)
+    }
+
+    pub fn usage(&mut self, usage: u16) -> &mut Self {
+        self.unsigned(USAGE, usage as u32)
+    }
+
+    pub fn usage_minimum(&mut self, usage: u16) -> &mut Self {
+        self.unsigned(USAGE_MINIMUM, usage as u32)
+    }
+
+    pub fn usage_maximum(&mut self, usage: u16) -> &mut Self {
+        self.unsigned(USAGE_MAXIMUM, usage as u32)
+    }
+
+    pub fn logical_minimum(&mut self, value: i32) -> &mut Self {
+        self.signed(LOGICAL_MINIMUM, value)
+    }
+
+    pub fn logical_maximum(&mut self, value: i32) -> &mut Self {
+        self.signed(LOGICAL_MAXIMUM, value)
+    }
+
+    /// Size of each field of the following main items, in bits.
+    pub fn report_size(&mut self, bits: u32) -> &mut Self {
+        self.unsigned(REPORT_SIZE, bits)
+    }
+
+    pub fn report_count(&mut self, count: u32) -> &mut Self {
+        self.unsigned(REPORT_COUNT, count)
+    }
+
+    /// Report ID of the following main items, which must not be 0.
+    pub fn report_id(&mut self, id: u8) -> &mut Self {
+        if id == 0 && self.error.is_none() {
+            self.error = Some(ErrorCode::INVAL);
+        }
+        self.unsigned(REPORT_ID, id as u32)
+    }
+
+    pub fn collection(&mut self, collection: Collection) -> &mut Self {
+        self.depth += 1;
+        self.unsigned(COLLECTION, collection as u32)
+    }
+
+    pub fn end_collection(&mut self) -> &mut Self {
+        if self.depth == 0 {
+            if self.error.is_none() {
+                self.error = Some(ErrorCode::INVAL);
+            }
+            return self;
+        }
+        self.depth -= 1;
+        self.item(END_COLLECTION, 0, 0)
+    }
+
+    /// Add an Input item with `flags` from `main_flags`.
+    pub fn input(&mut self, flags: u32) -> &mut Self {
+        self.unsigned(INPUT, flags)
+    }
+
+    /// Add an Output item with `flags` from `main_flags`.
+    pub fn output(&mut self, flags: u32) -> &mut Self {
+        self.unsigned(OUTPUT, flags)
+    }
+
+    /// Add a Feature item with `flags` from `main_flags`.
+    pub fn feature(&mut self, flags: u32) -> &mut Self {
+        self.unsigned(FEATURE, flags)
+    }
+
+    /// Returns the length of the descriptor.
+    ///
+    /// Returns `SIZE` if the buffer is too small, and `INVAL` if collections
+    /// are not balanced or a report ID is 0.
+    pub fn finish(&self) -> Result<usize, ErrorCode> {
+        match self.error {
+            Some(e) => Err(e),
+            None if self.depth != 0 => Err(ErrorCode::INVAL),
+            None => Ok(self.len),
+        }
+    }
+}
+
+/// The length in bytes of the longest report of each type a descriptor
+/// describes, including the report ID. 0 if there is no report of the type.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReportLengths {
+    pub input: usize,
+    pub output: usize,
+    pub feature: usize,
+    /// Whether the reports start with a report ID.
+    pub report_ids: bool,
+}
+
+impl ReportLengths {
+    pub fn get(&self, report_type: ReportType) -> usize {
+        match report_type {
+            ReportType::Input => self.input,
+            ReportType::Output => self.output,
+            ReportType::Feature => self.feature,
+        }
+    }
+}
+
+/// The global items that determine the layout of the reports.
+#[derive(Copy, Clone, Default)]
+struct Globals {
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+/// Check a report descriptor, and return the length of its reports.
+///
+/// Returns `INVAL` if the descriptor is malformed: an item is cut off,
+/// collections are not balanced, there is no collection, a report ID is 0,
+/// or some reports have an ID and others not. Returns `NOSUPPORT` for long
+/// items, and `SIZE` if a report is longer than `MAX_REPORT_LEN` or too many
+/// report IDs are used.
+pub fn parse(descriptor: &[u8]) -> Result<ReportLengths, ErrorCode> {
+    let mut globals = Globals::default();
+    let mut stack = [Globals::default(); MAX_PUSH_DEPTH];
+    let mut stack_depth = 0;
+    let mut depth = 0;
+    let mut collections = 0;
+    // Bits of the input, output and feature report with each report ID.
+    let mut reports = [(0u8, [0u32; 3]); MAX_REPORT_IDS];
+    let mut num_reports = 0;
+    let mut report_ids = None;
+
+    let mut offset = 0;
+    while offset < descriptor.len() {
+        let prefix = descriptor[offset];
+        if prefix == LONG_ITEM {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            size => size as usize,
+        };
+        let data_bytes = descriptor
+            .get(offset + 1..offset + 1 + size)
+            .ok_or(ErrorCode::INVAL)?;
+        let mut data = [0; 4];
+        data[..size].copy_from_slice(data_bytes);
+        let data = u32::from_le_bytes(data);
+        offset += 1 + size;
+
+        match prefix & 0xfc {
+            INPUT | OUTPUT | FEATURE => {
+                let uses_id = globals.report_id != 0;
+                if *report_ids.get_or_insert(uses_id) != uses_id {
+                    return Err(ErrorCode::INVAL);
+                }
+                let index = match reports[..num_reports]
+                    .iter()
+                    .position(|(id, _)| *id == globals.report_id)
+                {
+                    Some(index) => index,
+                    None if num_reports < MAX_REPORT_IDS => {
+                        reports[num_reports].0 = globals.report_id;
+                        num_reports += 1;
+                        num_reports - 1
+                    }
+                    None => return Err(ErrorCode::SIZE),
+                };
+                let report_type = match prefix & 0xfc {
+                    INPUT => 0,
+                    OUTPUT => 1,
+                    _ => 2,
+                };
+                let bits = globals
+                    .report_size
+                    .checked_mul(globals.report_count)
+                    .and_then(|bits| bits.checked_add(reports[index].1[report_type]))
+                    .filter(|bits| *bits as usize <= MAX_REPORT_LEN * 8)
+                    .ok_or(ErrorCode::SIZE)?;
+                reports[index].1[report_type] = bits;
+            }
+            COLLECTION => {
+                depth += 1;
+                collections += 1;
+            }
+            END_COLLECTION => {
+                if depth == 0 {
+                    return Err(ErrorCode::INVAL);
+                }
+                depth -= 1;
+            }
+            REPORT_SIZE => globals.report_size = data,
+            REPORT_COUNT => globals.report_count = data,
+            REPORT_ID => {
+                if data == 0 || data > 0xff {
+                    return Err(ErrorCode::INVAL);
+                }
+                globals.report_id = data as u8;
+            }
+            PUSH => {
+                if stack_depth == MAX_PUSH_DEPTH {
+                    return Err(ErrorCode::SIZE);
+                }
+                stack[stack_depth] = globals;
+                stack_depth += 1;
+            }
+            POP => {
+                if stack_depth == 0 {
+                    return Err(ErrorCode::INVAL);
+                }
+                stack_depth -= 1;
+                globals = stack[stack_depth];
+            }
+            // Usages, logical and physical ranges, units and so on do not
+            // change the layout of the reports.
+            _ => {}
+        }
+    }
+
+    if depth != 0 || collections == 0 {
+        return Err(ErrorCode::INVAL);
+    }
+
+    let report_ids = report_ids.unwrap_or(false);
+    let id_len = usize::from(report_ids);
+    let mut lengths = ReportLengths {
+        report_ids,
+        ..ReportLengths::default()
+    };
+    for (_, bits) in reports[..num_reports].iter() {
+        let bytes = bits.map(|bits| (bits as usize).div_ceil(8));
+        let [input, output, feature] =
+            bytes.map(|bytes| if bytes > 0 { bytes + id_len } else { 0 });
+        if input.max(output).max(feature) > MAX_REPORT_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        lengths.input = lengths.input.max(input);
+        lengths.output = lengths.output.max(output);
+        lengths.feature = lengths.feature.max(feature);
+    }
+    Ok(lengths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The boot keyboard descriptor, from appendix B.1 of the HID
+    /// specification.
+    const KEYBOARD: &[u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x75, 0x01, 0x95, 0x08, 0x05, 0x07, 0x19, 0xe0, 0x29,
+        0xe7, 0x15, 0x00, 0x25, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05,
+        0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91,
+        0x01, 0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65,
+        0x81, 0x00, 0xc0,
+    ];
+
+    #[test]
+    fn builder_matches_boot_keyboard() {
+        let mut buffer = [0; 128];
+        let len = ReportDescriptorBuilder::new(&mut buffer)
+            .usage_page(0x01)
+            .usage(0x06)
+            .collection(Collection::Application)
+            .report_size(1)
+            .report_count(8)
+            .usage_page(0x07)
+            .usage_minimum(0xe0)
+            .usage_maximum(0xe7)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .input(main_flags::DATA_VARIABLE_ABSOLUTE)
+            .report_count(1)
+            .report_size(8)
+            .input(main_flags::CONSTANT)
+            .report_count(5)
+            .report_size(1)
+            .usage_page(0x08)
+            .usage_minimum(1)
+            .usage_maximum(5)
+            .output(main_flags::DATA_VARIABLE_ABSOLUTE)
+            .report_count(1)
+            .report_size(3)
+            .output(main_flags::CONSTANT)
+            .report_count(6)
+            .report_size(8)
+            .logical_minimum(0)
+            .logical_maximum(101)
+            .usage_page(0x07)
+            .usage_minimum(0)
+            .usage_maximum(101)
+            .input(main_flags::DATA | main_flags::ARRAY)
+            .end_collection()
+            .finish()
+            .unwrap();
+        assert_eq!(&buffer[..len], KEYBOARD);
+    }
+
+    #[test]
+    fn builder_encodes_signed_values() {
+        let mut buffer = [0; 16];
+        let len = ReportDescriptorBuilder::new(&mut buffer)
+            .logical_minimum(-127)
+            .logical_maximum(255)
+            .logical_minimum(-40000)
+            .finish()
+            .unwrap();
+        assert_eq!(
+            &buffer[..len],
+            &[0x15, 0x81, 0x26, 0xff, 0x00, 0x17, 0xc0, 0x63, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn builder_errors() {
+        let mut buffer = [0; 4];
+        let mut builder = ReportDescriptorBuilder::new(&mut buffer);
+        builder.usage_page(0xff00).usage(1);
+        assert_eq!(builder.finish(), Err(ErrorCode::SIZE));
+
+        let mut buffer = [0; 16];
+        let mut builder = ReportDescriptorBuilder::new(&mut buffer);
+        builder.collection(Collection::Application);
+        assert_eq!(builder.finish(), Err(ErrorCode::INVAL));
+        builder.end_collection().end_collection();
+        assert_eq!(builder.finish(), Err(ErrorCode::INVAL));
+    }
+
+    #[test]
+    fn parse_boot_keyboard() {
+        assert_eq!(
+            parse(KEYBOARD),
+            Ok(ReportLengths {
+                input: 8,
+                output: 1,
+                feature: 0,
+                report_ids: false,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_report_ids() {
+        let mut buffer = [0; 64];
+        let len = ReportDescriptorBuilder::new(&mut buffer)
+            .usage_page(0xff00)
+            .usage(1)
+            .collection(Collection::Application)
+            .report_size(8)
+            .report_id(1)
+            .report_count(4)
+            .input(main_flags::DATA_VARIABLE_ABSOLUTE)
+            .report_id(2)
+            .report_count(16)
+            .input(main_flags::DATA_VARIABLE_ABSOLUTE)
+            .report_count(2)
+            .feature(main_flags::DATA_VARIABLE_ABSOLUTE)
+            .end_collection()
+            .finish()
+            .unwrap();
+        assert_eq!(
+            parse(&buffer[..len]),
+            Ok(ReportLengths {
+                input: 17,
+                output: 0,
+                feature: 3,
+                report_ids: true,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_rejects_malformed() {
+        // No collection.
+        assert_eq!(
+            parse(&[0x75, 0x08, 0x95, 0x01, 0x81, 0x02]),
+            Err(ErrorCode::INVAL)
+        );
+        // Unbalanced collections.
+        assert_eq!(parse(&[0xa1, 0x01]), Err(ErrorCode::INVAL));
+        assert_eq!(parse(&[0xa1, 0x01, 0xc0, 0xc0]), Err(ErrorCode::INVAL));
+        // Item cut off.
+        assert_eq!(
+            parse(&[0xa1, 0x01, 0xc0, 0x26, 0xff]),
+            Err(ErrorCode::INVAL)
+        );
+        // Report ID 0.
+        assert_eq!(
+            parse(&[0xa1, 0x01, 0x85, 0x00, 0xc0]),
+            Err(ErrorCode::INVAL)
+        );
+        // A report without an ID before one with an ID.
+        assert_eq!(
+            parse(&[0xa1, 0x01, 0x75, 0x08, 0x95, 0x01, 0x81, 0x02, 0x85, 0x01, 0x81, 0x02, 0xc0]),
+            Err(ErrorCode::INVAL)
+        );
+        // Long item.
+        assert_eq!(parse(&[0xfe, 0x00, 0x00]), Err(ErrorCode::NOSUPPORT));
+        // Report longer than a packet.
+        assert_eq!(
+            parse(&[0xa1, 0x01, 0x75, 0x08, 0x95, 0x41, 0x81, 0x02, 0xc0]),
+            Err(ErrorCode::SIZE)
+        );
+    }
+}
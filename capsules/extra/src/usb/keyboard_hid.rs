@@ -3,9 +3,15 @@
 // Copyright Tock Contributors 2023.
 
 //! Keyboard USB HID device
+//!
+//! The keyboard can either be the only client of the USB controller (see
+//! `KeyboardHid::new()`), or one function of a composite device (see
+//! `KeyboardHid::new_function()` and the `composite` module).
 
+use super::composite::UsbFunction;
 use super::descriptors;
 use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
 use super::descriptors::DescriptorType;
 use super::descriptors::EndpointAddress;
 use super::descriptors::EndpointDescriptor;
@@ -14,6 +20,8 @@ use super::descriptors::HIDDescriptor;
 use super::descriptors::HIDSubordinateDescriptor;
 use super::descriptors::InterfaceDescriptor;
 use super::descriptors::ReportDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
 use super::descriptors::TransferDirection;
 use super::usbc_client_ctrl::ClientCtrl;
 
@@ -21,11 +29,17 @@ use kernel::hil;
 use kernel::hil::usb::TransferType;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::cells::TakeCell;
+use kernel::utilities::cells::VolatileCell;
 use kernel::ErrorCode;
 
 /// Use 1 Interrupt transfer IN/OUT endpoint
 const ENDPOINT_NUM: usize = 1;
 
+/// HID class specific requests.
+const HID_SET_REPORT: u8 = 0x09;
+const HID_SET_IDLE: u8 = 0x0a;
+const HID_SET_PROTOCOL: u8 = 0x0b;
+
 const IN_BUFFER: usize = 0;
 
 static LANGUAGES: &[u16; 1] = &[
@@ -100,6 +114,11 @@ pub struct KeyboardHid<'a, U: 'a> {
     /// 64 byte buffers for each endpoint.
     buffers: [Buffer64; N_ENDPOINTS],
 
+    /// Number of the HID interface.
+    interface: u8,
+    /// Interrupt IN endpoint for the key reports.
+    endpoint: usize,
+
     client: OptionalCell<&'a dyn hil::usb_hid::Client<'a, [u8; 64]>>,
 
     /// A buffer to hold the data we want to send
@@ -113,23 +132,26 @@ impl<'a, U: hil::usb::UsbController<'a>> KeyboardHid<'a, U> {
         product_id: u16,
         strings: &'static [&'static str; 3],
     ) -> Self {
-        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
-            interface_number: 0,
-            interface_class: 0x03,    // HID
-            interface_subclass: 0x01, // Boot subclass
-            interface_protocol: 0x01, // Keyboard
-            ..InterfaceDescriptor::default()
-        }];
+        Self::new_internal(controller, vendor_id, product_id, strings, 0, ENDPOINT_NUM)
+    }
 
-        let endpoints: &[&[EndpointDescriptor]] = &[&[EndpointDescriptor {
-            endpoint_address: EndpointAddress::new_const(
-                ENDPOINT_NUM,
-                TransferDirection::DeviceToHost,
-            ),
-            transfer_type: TransferType::Interrupt,
-            max_packet_size: 8,
-            interval: 10,
-        }]];
+    /// Create a keyboard function for a composite device, using the interface
+    /// `interface` and the interrupt IN endpoint `endpoint`.
+    pub fn new_function(controller: &'a U, interface: u8, endpoint: usize) -> Self {
+        Self::new_internal(controller, 0, 0, &["", "", ""], interface, endpoint)
+    }
+
+    fn new_internal(
+        controller: &'a U,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        interface: u8,
+        endpoint: usize,
+    ) -> Self {
+        let (interface_descriptor, endpoint_descriptor) = keyboard_descriptors(interface, endpoint);
+        let interfaces: &mut [InterfaceDescriptor] = &mut [interface_descriptor];
+        let endpoints: &[&[EndpointDescriptor]] = &[&[endpoint_descriptor]];
 
         let (device_descriptor_buffer, other_descriptor_buffer) =
             descriptors::create_descriptor_buffers(
@@ -164,6 +186,8 @@ impl<'a, U: hil::usb::UsbController<'a>> KeyboardHid<'a, U> {
                 strings,
             ),
             buffers: [Buffer64::default()],
+            interface,
+            endpoint,
             client: OptionalCell::empty(),
             send_buffer: TakeCell::empty(),
         }
@@ -177,6 +201,37 @@ impl<'a, U: hil::usb::UsbController<'a>> KeyboardHid<'a, U> {
     pub fn set_client(&'a self, client: &'a dyn hil::usb_hid::Client<'a, [u8; 64]>) {
         self.client.set(client);
     }
+
+    /// Set up the interrupt IN endpoint.
+    fn enable_endpoints(&'a self) {
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint, &self.buffers[IN_BUFFER].buf);
+        self.controller()
+            .endpoint_in_out_enable(TransferType::Interrupt, self.endpoint);
+    }
+}
+
+/// Build the interface and endpoint descriptors of the keyboard.
+fn keyboard_descriptors(
+    interface: u8,
+    endpoint: usize,
+) -> (InterfaceDescriptor, EndpointDescriptor) {
+    (
+        InterfaceDescriptor {
+            interface_number: interface,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x01, // Boot subclass
+            interface_protocol: 0x01, // Keyboard
+            num_endpoints: 1,
+            ..InterfaceDescriptor::default()
+        },
+        EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(endpoint, TransferDirection::DeviceToHost),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: 8,
+            interval: 10,
+        },
+    )
 }
 
 impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for KeyboardHid<'a, U> {
@@ -187,7 +242,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb_hid::UsbHid<'a, [u8; 64]> for
         let len = send.len();
 
         self.send_buffer.replace(send);
-        self.controller().endpoint_resume_in(ENDPOINT_NUM);
+        self.controller().endpoint_resume_in(self.endpoint);
 
         Ok(len)
     }
@@ -219,10 +274,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for KeyboardHid<'a
         self.client_ctrl.enable();
 
         // Setup buffers for IN data transfer.
-        self.controller()
-            .endpoint_set_in_buffer(ENDPOINT_NUM, &self.buffers[IN_BUFFER].buf);
-        self.controller()
-            .endpoint_in_out_enable(TransferType::Interrupt, ENDPOINT_NUM);
+        self.enable_endpoints();
     }
 
     fn attach(&'a self) {
@@ -321,3 +373,73 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for KeyboardHid<'a
         });
     }
 }
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbFunction<'a> for KeyboardHid<'a, U> {
+    fn interfaces(&self) -> (u8, u8) {
+        (self.interface, 1)
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+        let (interface_descriptor, endpoint_descriptor) =
+            keyboard_descriptors(self.interface, self.endpoint);
+        f(&interface_descriptor);
+        f(&HID_DESCRIPTOR);
+        f(&endpoint_descriptor);
+    }
+
+    fn interface_descriptor(
+        &self,
+        _interface: u8,
+        descriptor_type: DescriptorType,
+    ) -> Option<&dyn Descriptor> {
+        match descriptor_type {
+            DescriptorType::HID => Some(&HID_DESCRIPTOR),
+            DescriptorType::Report => Some(&REPORT),
+            _ => None,
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    /// Accept the HID requests to set the idle rate, the protocol and the
+    /// LED output report, and ignore their values.
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        match (setup.request_type.request_type(), setup.request_code) {
+            (RequestType::Class, HID_SET_REPORT | HID_SET_IDLE | HID_SET_PROTOCOL) => {
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => hil::usb::CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    fn ctrl_out(
+        &'a self,
+        _packet: &[VolatileCell<u8>],
+        _packet_bytes: u32,
+    ) -> hil::usb::CtrlOutResult {
+        hil::usb::CtrlOutResult::Ok
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
@@ -3,9 +3,16 @@
 // Copyright Tock Contributors 2022.
 
 pub mod cdc;
+pub mod cdc_ecm;
+pub mod composite;
 pub mod ctap;
+pub mod ctaphid;
 pub mod descriptors;
+pub mod dfu;
+pub mod generic_hid;
+pub mod hid_report;
 pub mod keyboard_hid;
+pub mod msc;
 pub mod usb_user;
 pub mod usbc_client;
 pub mod usbc_client_ctrl;
@@ -0,0 +1,1517 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Mass Storage Class device for USB
+//!
+//! Exports a block storage device, for example an SD card or a region of
+//! flash, to the host as a USB drive. This implements the Bulk-Only Transport
+//! (BOT) with the subset of the SCSI transparent command set that hosts use
+//! for a removable disk:
+//!
+//! - TEST UNIT READY, REQUEST SENSE and INQUIRY
+//! - MODE SENSE(6) and MODE SENSE(10), without mode pages
+//! - START STOP UNIT, PREVENT ALLOW MEDIUM REMOVAL, VERIFY(10) and
+//!   SYNCHRONIZE CACHE(10), which succeed without doing anything
+//! - READ FORMAT CAPACITIES and READ CAPACITY(10)
+//! - READ(10) and WRITE(10)
+//!
+//! A Command Block Wrapper that is not valid stalls both bulk endpoints until
+//! the host performs a Reset Recovery, as required by BOT 6.6.1.
+//!
+//! The device has a single logical unit. The host owns the file system on the
+//! block device while it is mounted, so the kernel must not write to the
+//! block device at the same time. `set_write_protected()` exports the device
+//! read only, which lets the kernel keep writing, for example to a log, while
+//! the host only reads.
+//!
+//! The mass storage device can either be the only client of the USB
+//! controller (see `MassStorage::new()`), or one function of a composite
+//! device (see `MassStorage::new_function()` and the `composite` module).
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let data_buffer = static_init!([u8; 512], [0; 512]);
+//! let msc = static_init!(
+//!     capsules_extra::usb::msc::MassStorage<'static, nrf52::usbd::Usbd, SDCard>,
+//!     capsules_extra::usb::msc::MassStorage::new(
+//!         &nrf52840_peripherals.usbd,
+//!         sdcard,
+//!         data_buffer,
+//!         0x1915,
+//!         0x503a,
+//!         STRINGS,
+//!     )
+//! );
+//! nrf52840_peripherals.usbd.set_client(msc);
+//! sdcard.set_client(msc);
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::composite::UsbFunction;
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::Descriptor;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::hil;
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+use kernel::hil::usb::TransferType;
+use kernel::utilities::cells::TakeCell;
+use kernel::utilities::cells::VolatileCell;
+use kernel::ErrorCode;
+
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 1;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 2;
+
+/// Max packet size of the bulk endpoints.
+const MAX_PACKET_SIZE: usize = 64;
+
+static LANGUAGES: &[u16; 1] = &[
+    0x0409, // English (United States)
+];
+/// Max packet size specified by spec
+pub const MAX_CTRL_PACKET_SIZE: u8 = 64;
+
+/// Suggested size of the data buffer, one block of an SD card.
+pub const DATA_BUFFER_LEN: usize = 512;
+
+/// Class specific requests.
+const GET_MAX_LUN: u8 = 0xfe;
+const BULK_ONLY_RESET: u8 = 0xff;
+
+/// Command Block Wrapper: signature "USBC" and length.
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LEN: usize = 31;
+/// Command Status Wrapper: signature "USBS" and length.
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LEN: usize = 13;
+
+/// SCSI operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+
+/// Response to INQUIRY: a removable direct access device.
+static INQUIRY_DATA: [u8; 36] = [
+    0x00, // Direct access block device
+    0x80, // Removable
+    0x04, // SPC-2
+    0x02, // Response data format
+    31,   // Additional length
+    0x00, 0x00, 0x00, // No optional features
+    b'T', b'o', b'c', b'k', b' ', b' ', b' ', b' ', // Vendor
+    b'M', b'a', b's', b's', b' ', b'S', b't', b'o', // Product
+    b'r', b'a', b'g', b'e', b' ', b' ', b' ', b' ', //
+    b'1', b'.', b'0', b' ', // Revision
+];
+
+/// SCSI sense key and additional sense code, reported by REQUEST SENSE after
+/// a command failed.
+#[derive(Copy, Clone)]
+struct Sense {
+    key: u8,
+    asc: u8,
+}
+
+const NO_SENSE: Sense = Sense {
+    key: 0x00,
+    asc: 0x00,
+};
+const MEDIUM_NOT_PRESENT: Sense = Sense {
+    key: 0x02,
+    asc: 0x3a,
+};
+const WRITE_ERROR: Sense = Sense {
+    key: 0x03,
+    asc: 0x0c,
+};
+const UNRECOVERED_READ_ERROR: Sense = Sense {
+    key: 0x03,
+    asc: 0x11,
+};
+const INVALID_COMMAND: Sense = Sense {
+    key: 0x05,
+    asc: 0x20,
+};
+const LBA_OUT_OF_RANGE: Sense = Sense {
+    key: 0x05,
+    asc: 0x21,
+};
+const INVALID_FIELD_IN_CDB: Sense = Sense {
+    key: 0x05,
+    asc: 0x24,
+};
+const WRITE_PROTECTED: Sense = Sense {
+    key: 0x07,
+    asc: 0x27,
+};
+
+/// Status of a command in the Command Status Wrapper.
+#[derive(Copy, Clone, PartialEq)]
+enum CommandStatus {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+}
+
+/// States of the Bulk-Only Transport.
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for a Command Block Wrapper from the host.
+    Command,
+    /// Sending bytes `offset..len` of the data buffer to the host.
+    DataIn { offset: usize, len: usize },
+    /// Receiving bytes `offset..len` of the data buffer from the host.
+    DataOut { offset: usize, len: usize },
+    /// Dropping the `remaining` bytes of data the host sends for a command
+    /// that failed.
+    Discard { remaining: usize },
+    /// Waiting for the block device to read or write.
+    Busy,
+    /// Sending a zero length packet to end the data stage early.
+    ZeroLengthPacket { sent: bool },
+    /// Sending the Command Status Wrapper.
+    Status { sent: bool },
+    /// Both bulk endpoints are stalled after an invalid Command Block
+    /// Wrapper, until the host performs a Reset Recovery.
+    Stalled,
+}
+
+/// States of the Control Endpoint related to mass storage.
+#[derive(Copy, Clone, PartialEq)]
+enum CtrlState {
+    Idle,
+    /// Host has sent a GET_MAX_LUN request.
+    GetMaxLun,
+}
+
+/// Implementation of the USB Mass Storage Class Bulk-Only Transport.
+pub struct MassStorage<'a, U: 'a, B: BlockStorage<'a>> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// The block device exported to the host.
+    storage: &'a B,
+
+    /// 64 byte buffer for the IN endpoint.
+    in_buffer: Buffer64,
+    /// 64 byte buffer for the OUT endpoint.
+    out_buffer: Buffer64,
+
+    /// Number of the mass storage interface.
+    interface: u8,
+    /// Endpoint for transferring data from us to the host.
+    endpoint_in: usize,
+    /// Endpoint for transferring data from the host to us.
+    endpoint_out: usize,
+
+    /// Current state of the Control Endpoint.
+    ctrl_state: Cell<CtrlState>,
+    /// Current state of the Bulk-Only Transport.
+    state: Cell<State>,
+
+    /// Buffer for responses and for the blocks read or written.
+    data_buffer: TakeCell<'static, [u8]>,
+    /// Length of `data_buffer`.
+    data_buffer_len: usize,
+
+    /// Tag of the current command, echoed in the status.
+    tag: Cell<u32>,
+    /// Number of bytes the host expects to transfer for the current command.
+    host_length: Cell<usize>,
+    /// True if the host expects data from us for the current command.
+    host_direction_in: Cell<bool>,
+    /// Number of data bytes of the current command transferred so far.
+    processed: Cell<usize>,
+    /// Status of the current command.
+    status: Cell<CommandStatus>,
+    /// Sense data of the last failed command.
+    sense: Cell<Sense>,
+
+    /// Next block to read or write for READ(10) and WRITE(10).
+    block: Cell<u64>,
+    /// Number of blocks left to read or write.
+    blocks_remaining: Cell<usize>,
+    /// Number of blocks in the data buffer.
+    chunk_blocks: Cell<usize>,
+
+    /// Reject writes from the host.
+    write_protected: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, B: BlockStorage<'a>> MassStorage<'a, U, B> {
+    pub fn new(
+        controller: &'a U,
+        storage: &'a B,
+        data_buffer: &'static mut [u8],
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        Self::new_internal(
+            controller,
+            storage,
+            data_buffer,
+            vendor_id,
+            product_id,
+            strings,
+            0,
+            ENDPOINT_IN_NUM,
+            ENDPOINT_OUT_NUM,
+        )
+    }
+
+    /// Create a mass storage function for a composite device, using the
+    /// interface `interface` and the given bulk endpoints.
+    pub fn new_function(
+        controller: &'a U,
+        storage: &'a B,
+        data_buffer: &'static mut [u8],
+        interface: u8,
+        endpoint_in: usize,
+        endpoint_out: usize,
+    ) -> Self {
+        Self::new_internal(
+            controller,
+            storage,
+            data_buffer,
+            0,
+            0,
+            &["", "", ""],
+            interface,
+            endpoint_in,
+            endpoint_out,
+        )
+    }
+
+    fn new_internal(
+        controller: &'a U,
+        storage: &'a B,
+        data_buffer: &'static mut [u8],
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        interface: u8,
+        endpoint_in: usize,
+        endpoint_out: usize,
+    ) -> Self {
+        let (interface_descriptor, endpoint_descriptors) =
+            msc_descriptors(interface, endpoint_in, endpoint_out);
+        let interfaces: &mut [InterfaceDescriptor] = &mut [interface_descriptor];
+        let endpoints: &[&[EndpointDescriptor]] = &[&endpoint_descriptors];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id,
+                    product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    max_packet_size_ep0: MAX_CTRL_PACKET_SIZE,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor::default(),
+                interfaces,
+                endpoints,
+                None, // No HID descriptor
+                None, // No CDC descriptor
+            );
+
+        let data_buffer_len = data_buffer.len();
+        Self {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            storage,
+            in_buffer: Buffer64::default(),
+            out_buffer: Buffer64::default(),
+            interface,
+            endpoint_in,
+            endpoint_out,
+            ctrl_state: Cell::new(CtrlState::Idle),
+            state: Cell::new(State::Command),
+            data_buffer: TakeCell::new(data_buffer),
+            data_buffer_len,
+            tag: Cell::new(0),
+            host_length: Cell::new(0),
+            host_direction_in: Cell::new(false),
+            processed: Cell::new(0),
+            status: Cell::new(CommandStatus::Passed),
+            sense: Cell::new(NO_SENSE),
+            block: Cell::new(0),
+            blocks_remaining: Cell::new(0),
+            chunk_blocks: Cell::new(0),
+            write_protected: Cell::new(false),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Report the device as write protected to the host, and reject writes.
+    pub fn set_write_protected(&self, write_protected: bool) {
+        self.write_protected.set(write_protected);
+    }
+
+    /// Set up the bulk IN and OUT endpoints.
+    fn enable_endpoints(&'a self) {
+        self.controller()
+            .endpoint_set_in_buffer(self.endpoint_in, &self.in_buffer.buf);
+        self.controller()
+            .endpoint_set_out_buffer(self.endpoint_out, &self.out_buffer.buf);
+        if self.endpoint_in == self.endpoint_out {
+            self.controller()
+                .endpoint_in_out_enable(TransferType::Bulk, self.endpoint_in);
+        } else {
+            self.controller()
+                .endpoint_in_enable(TransferType::Bulk, self.endpoint_in);
+            self.controller()
+                .endpoint_out_enable(TransferType::Bulk, self.endpoint_out);
+        }
+    }
+
+    /// Forget the current command and wait for the next one.
+    fn reset(&self) {
+        self.state.set(State::Command);
+        self.blocks_remaining.set(0);
+    }
+
+    /// Handle a mass storage class request. Returns false if the request is
+    /// not one we support.
+    fn handle_class_request(&self, setup_data: &SetupData) -> bool {
+        match setup_data.request_type.request_type() {
+            RequestType::Class => {}
+            _ => return false,
+        }
+        match setup_data.request_code {
+            GET_MAX_LUN => {
+                self.ctrl_state.set(CtrlState::GetMaxLun);
+                true
+            }
+            BULK_ONLY_RESET => {
+                self.reset();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Write the response to a GET_MAX_LUN request into `packet`.
+    fn max_lun(&self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::GetMaxLun => {
+                // We only have logical unit 0.
+                packet[0].set(0);
+                hil::usb::CtrlInResult::Packet(1, true)
+            }
+            CtrlState::Idle => hil::usb::CtrlInResult::Error,
+        }
+    }
+
+    /// True if the block device can be used, for example a card is inserted.
+    fn ready(&self) -> bool {
+        self.storage.block_count() > 0
+    }
+
+    /// Number of blocks that fit in the data buffer.
+    fn buffer_blocks(&self) -> usize {
+        let block_size = self.storage.block_size();
+        if block_size == 0 {
+            0
+        } else {
+            self.data_buffer_len / block_size
+        }
+    }
+
+    /// Parse a Command Block Wrapper received from the host and execute the
+    /// command. Returns false if the Command Block Wrapper is not valid.
+    fn receive_command(&self, packet_bytes: usize) -> bool {
+        let packet = &self.out_buffer.buf;
+        let get_u32 = |i: usize| {
+            u32::from_le_bytes([
+                packet[i].get(),
+                packet[i + 1].get(),
+                packet[i + 2].get(),
+                packet[i + 3].get(),
+            ])
+        };
+        let command_length = packet[14].get() as usize;
+        if packet_bytes != CBW_LEN
+            || get_u32(0) != CBW_SIGNATURE
+            || packet[13].get() != 0
+            || command_length == 0
+            || command_length > 16
+        {
+            // Not a valid command for logical unit 0.
+            return false;
+        }
+
+        self.tag.set(get_u32(4));
+        self.host_length.set(get_u32(8) as usize);
+        self.host_direction_in.set(packet[12].get() & 0x80 != 0);
+        self.processed.set(0);
+        self.status.set(CommandStatus::Passed);
+
+        let mut command = [0; 16];
+        for (i, byte) in command.iter_mut().enumerate().take(command_length) {
+            *byte = packet[15 + i].get();
+        }
+        self.execute(&command);
+        true
+    }
+
+    /// Stall both bulk endpoints until the host performs a Reset Recovery
+    /// (a Bulk-Only Mass Storage Reset, BOT 6.6.1).
+    fn stall(&self) {
+        self.state.set(State::Stalled);
+        self.controller().endpoint_resume_in(self.endpoint_in);
+    }
+
+    /// Execute a SCSI command.
+    fn execute(&self, command: &[u8; 16]) {
+        let get_u16 = |i: usize| u16::from_be_bytes([command[i], command[i + 1]]) as usize;
+
+        if command[0] != REQUEST_SENSE {
+            self.sense.set(NO_SENSE);
+        }
+        match command[0] {
+            TEST_UNIT_READY => {
+                if self.ready() {
+                    self.no_data();
+                } else {
+                    self.fail(MEDIUM_NOT_PRESENT);
+                }
+            }
+            REQUEST_SENSE => {
+                let sense = self.sense.replace(NO_SENSE);
+                let data = [
+                    0x70, // Current error, fixed format
+                    0x00, sense.key, 0x00, 0x00, 0x00, 0x00, 10, // Additional length
+                    0x00, 0x00, 0x00, 0x00, sense.asc, 0x00, 0x00, 0x00, 0x00, 0x00,
+                ];
+                self.data_in(&data, command[4] as usize);
+            }
+            INQUIRY => {
+                if command[1] & 0x01 != 0 {
+                    // Vital product data pages are not supported.
+                    self.fail(INVALID_FIELD_IN_CDB);
+                } else {
+                    self.data_in(&INQUIRY_DATA, get_u16(3));
+                }
+            }
+            MODE_SENSE_6 => {
+                let data = [3, 0x00, self.device_specific_parameter(), 0x00];
+                self.data_in(&data, command[4] as usize);
+            }
+            MODE_SENSE_10 => {
+                let data = [
+                    0x00,
+                    6,
+                    0x00,
+                    self.device_specific_parameter(),
+                    0x00,
+                    0x00,
+                    0x00,
+                    0x00,
+                ];
+                self.data_in(&data, get_u16(7));
+            }
+            START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL | VERIFY_10 | SYNCHRONIZE_CACHE_10 => {
+                self.no_data();
+            }
+            READ_FORMAT_CAPACITIES => {
+                if !self.ready() {
+                    self.fail(MEDIUM_NOT_PRESENT);
+                    return;
+                }
+                let blocks = self.last_block().saturating_add(1).to_be_bytes();
+                let block_size = (self.storage.block_size() as u32).to_be_bytes();
+                let data = [
+                    0x00,
+                    0x00,
+                    0x00,
+                    8, // Capacity list length
+                    blocks[0],
+                    blocks[1],
+                    blocks[2],
+                    blocks[3],
+                    0x02, // Formatted media
+                    block_size[1],
+                    block_size[2],
+                    block_size[3],
+                ];
+                self.data_in(&data, get_u16(7));
+            }
+            READ_CAPACITY_10 => {
+                if !self.ready() {
+                    self.fail(MEDIUM_NOT_PRESENT);
+                    return;
+                }
+                let last_block = self.last_block().to_be_bytes();
+                let block_size = (self.storage.block_size() as u32).to_be_bytes();
+                let mut data = [0; 8];
+                data[..4].copy_from_slice(&last_block);
+                data[4..].copy_from_slice(&block_size);
+                self.data_in(&data, data.len());
+            }
+            READ_10 | WRITE_10 => {
+                let block = u32::from_be_bytes([command[2], command[3], command[4], command[5]]);
+                self.start_transfer(command[0] == WRITE_10, block as u64, get_u16(7));
+            }
+            _ => self.fail(INVALID_COMMAND),
+        }
+    }
+
+    /// Number of the last block, as reported by READ CAPACITY(10).
+    fn last_block(&self) -> u32 {
+        cmp::min(self.storage.block_count() - 1, u32::MAX as u64) as u32
+    }
+
+    /// The device specific parameter of the mode parameter header, which holds
+    /// the write protect bit.
+    fn device_specific_parameter(&self) -> u8 {
+        if self.write_protected.get() {
+            0x80
+        } else {
+            0x00
+        }
+    }
+
+    /// Send the response `data` to the host, at most `allocation_length`
+    /// bytes of it.
+    fn data_in(&self, data: &[u8], allocation_length: usize) {
+        if self.host_length.get() == 0 || !self.host_direction_in.get() {
+            // The host does not expect data from us.
+            self.status.set(CommandStatus::PhaseError);
+            self.no_data();
+            return;
+        }
+
+        let len = cmp::min(
+            cmp::min(data.len(), allocation_length),
+            self.host_length.get(),
+        );
+        let copied = self.data_buffer.map_or(false, |buffer| {
+            if buffer.len() < len {
+                return false;
+            }
+            buffer[..len].copy_from_slice(&data[..len]);
+            true
+        });
+        if !copied {
+            self.status.set(CommandStatus::Failed);
+            self.no_data();
+            return;
+        }
+        self.state.set(State::DataIn { offset: 0, len });
+        self.controller().endpoint_resume_in(self.endpoint_in);
+    }
+
+    /// Finish a command without a data stage of our own. If the host expects
+    /// data anyway, the data stage is ended early or the host's data dropped.
+    fn no_data(&self) {
+        if self.host_length.get() == 0 {
+            self.send_status();
+        } else if self.host_direction_in.get() {
+            self.end_data_in();
+        } else {
+            self.state.set(State::Discard {
+                remaining: self.host_length.get(),
+            });
+        }
+    }
+
+    /// Fail the current command with `sense`.
+    fn fail(&self, sense: Sense) {
+        self.sense.set(sense);
+        self.status.set(CommandStatus::Failed);
+        self.no_data();
+    }
+
+    /// Start READ(10) or WRITE(10) of `count` blocks starting with `block`.
+    fn start_transfer(&self, write: bool, block: u64, count: usize) {
+        if !self.ready() {
+            self.fail(MEDIUM_NOT_PRESENT);
+            return;
+        }
+        if block + count as u64 > self.storage.block_count() {
+            self.fail(LBA_OUT_OF_RANGE);
+            return;
+        }
+        if write && self.write_protected.get() {
+            self.fail(WRITE_PROTECTED);
+            return;
+        }
+        if self.buffer_blocks() == 0 {
+            self.fail(INVALID_FIELD_IN_CDB);
+            return;
+        }
+        if count * self.storage.block_size() != self.host_length.get()
+            || (count > 0 && self.host_direction_in.get() == write)
+        {
+            // The host expects a different amount of data or direction.
+            self.status.set(CommandStatus::PhaseError);
+            self.no_data();
+            return;
+        }
+        if count == 0 {
+            self.send_status();
+            return;
+        }
+
+        self.block.set(block);
+        self.blocks_remaining.set(count);
+        if write {
+            let len = self.next_chunk() * self.storage.block_size();
+            self.state.set(State::DataOut { offset: 0, len });
+        } else {
+            self.read_chunk();
+        }
+    }
+
+    /// Set the number of blocks of the next chunk of a transfer, and return
+    /// it.
+    fn next_chunk(&self) -> usize {
+        let blocks = cmp::min(self.blocks_remaining.get(), self.buffer_blocks());
+        self.chunk_blocks.set(blocks);
+        blocks
+    }
+
+    /// Read the next chunk of blocks into the data buffer.
+    fn read_chunk(&self) {
+        let blocks = self.next_chunk();
+        self.state.set(State::Busy);
+
+        if self.status.get() == CommandStatus::Passed {
+            if let Some(buffer) = self.data_buffer.take() {
+                match self.storage.read_blocks(buffer, self.block.get(), blocks) {
+                    Ok(()) => return,
+                    Err((_, buffer)) => {
+                        self.data_buffer.replace(buffer);
+                    }
+                }
+            }
+        }
+        self.chunk_read(Err(ErrorCode::FAIL));
+    }
+
+    /// A chunk of blocks was read into the data buffer, send it to the host.
+    ///
+    /// After a read failed, the remaining data is sent as zeros so the host
+    /// receives the amount of data it expects, and the command fails.
+    fn chunk_read(&self, result: Result<(), ErrorCode>) {
+        let len = self.chunk_blocks.get() * self.storage.block_size();
+        if result.is_err() {
+            if self.status.get() == CommandStatus::Passed {
+                self.sense.set(UNRECOVERED_READ_ERROR);
+                self.status.set(CommandStatus::Failed);
+            }
+            self.data_buffer.map(|buffer| buffer[..len].fill(0));
+        }
+
+        self.block
+            .set(self.block.get() + self.chunk_blocks.get() as u64);
+        self.blocks_remaining
+            .set(self.blocks_remaining.get() - self.chunk_blocks.get());
+        self.state.set(State::DataIn { offset: 0, len });
+        self.controller().endpoint_resume_in(self.endpoint_in);
+    }
+
+    /// Write the chunk of blocks received from the host.
+    fn write_chunk(&self) -> Result<(), ErrorCode> {
+        self.state.set(State::Busy);
+        let buffer = self.data_buffer.take().ok_or(ErrorCode::FAIL)?;
+        self.storage
+            .write_blocks(buffer, self.block.get(), self.chunk_blocks.get())
+            .map_err(|(e, buffer)| {
+                self.data_buffer.replace(buffer);
+                e
+            })
+    }
+
+    /// A write failed, drop the rest of the data from the host and fail the
+    /// command.
+    fn write_failed(&self) {
+        self.sense.set(WRITE_ERROR);
+        self.status.set(CommandStatus::Failed);
+        self.blocks_remaining.set(0);
+        let remaining = self.host_length.get() - self.processed.get();
+        if remaining > 0 {
+            self.state.set(State::Discard { remaining });
+        } else {
+            self.send_status();
+        }
+    }
+
+    /// All data we have for the host is sent. End the data stage with a zero
+    /// length packet if the host expects more and the last packet was full,
+    /// then send the status.
+    fn end_data_in(&self) {
+        let processed = self.processed.get();
+        if processed < self.host_length.get() && processed % MAX_PACKET_SIZE == 0 {
+            self.state.set(State::ZeroLengthPacket { sent: false });
+            self.controller().endpoint_resume_in(self.endpoint_in);
+        } else {
+            self.send_status();
+        }
+    }
+
+    fn send_status(&self) {
+        self.state.set(State::Status { sent: false });
+        self.controller().endpoint_resume_in(self.endpoint_in);
+    }
+
+    /// Write the Command Status Wrapper into the IN packet.
+    fn write_status(&self) {
+        let residue = self.host_length.get().saturating_sub(self.processed.get()) as u32;
+        let packet = &self.in_buffer.buf;
+        let fields = [
+            CSW_SIGNATURE.to_le_bytes(),
+            self.tag.get().to_le_bytes(),
+            residue.to_le_bytes(),
+        ];
+        for (cell, byte) in packet.iter().zip(fields.iter().flatten()) {
+            cell.set(*byte);
+        }
+        packet[12].set(self.status.get() as u8);
+    }
+}
+
+/// Build the interface and endpoint descriptors of the mass storage device.
+fn msc_descriptors(
+    interface: u8,
+    endpoint_in: usize,
+    endpoint_out: usize,
+) -> (InterfaceDescriptor, [EndpointDescriptor; 2]) {
+    (
+        InterfaceDescriptor {
+            interface_number: interface,
+            interface_class: 0x08,    // Mass storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-Only Transport
+            num_endpoints: 2,
+            ..InterfaceDescriptor::default()
+        },
+        [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    endpoint_in,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: MAX_PACKET_SIZE as u16,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    endpoint_out,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: MAX_PACKET_SIZE as u16,
+                interval: 0,
+            },
+        ],
+    )
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, B: BlockStorage<'a>> hil::usb::Client<'a>
+    for MassStorage<'a, U, B>
+{
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.enable_endpoints();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let handled = SetupData::get(&self.client_ctrl.ctrl_buffer.buf)
+            .is_some_and(|setup_data| self.handle_class_request(&setup_data));
+        if handled {
+            hil::usb::CtrlSetupResult::Ok
+        } else {
+            self.client_ctrl.ctrl_setup(endpoint)
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::GetMaxLun => self.max_lun(&self.client_ctrl.ctrl_buffer.buf),
+            CtrlState::Idle => self.client_ctrl.ctrl_in(endpoint),
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk IN transaction.
+    ///
+    /// This sends the next packet of data or the status to the host.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        if !matches!(transfer_type, TransferType::Bulk) {
+            return hil::usb::InResult::Error;
+        }
+        match self.state.get() {
+            State::DataIn { offset, len } if offset < len => {
+                let packet = &self.in_buffer.buf;
+                let to_send = cmp::min(packet.len(), len - offset);
+                self.data_buffer.map(|buffer| {
+                    for (cell, byte) in packet.iter().zip(&buffer[offset..offset + to_send]) {
+                        cell.set(*byte);
+                    }
+                });
+                self.state.set(State::DataIn {
+                    offset: offset + to_send,
+                    len,
+                });
+                self.processed.set(self.processed.get() + to_send);
+                hil::usb::InResult::Packet(to_send)
+            }
+            State::ZeroLengthPacket { sent: false } => {
+                self.state.set(State::ZeroLengthPacket { sent: true });
+                hil::usb::InResult::Packet(0)
+            }
+            State::Status { sent: false } => {
+                self.write_status();
+                self.state.set(State::Status { sent: true });
+                hil::usb::InResult::Packet(CSW_LEN)
+            }
+            State::Stalled => hil::usb::InResult::Error,
+            _ => hil::usb::InResult::Delay,
+        }
+    }
+
+    /// Handle a Bulk OUT transaction.
+    ///
+    /// This receives commands and the data of WRITE(10). While the blocks
+    /// received are written, further packets are delayed.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        _endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        if !matches!(transfer_type, TransferType::Bulk) {
+            return hil::usb::OutResult::Error;
+        }
+        let packet_bytes = packet_bytes as usize;
+        match self.state.get() {
+            State::Command => {
+                if self.receive_command(packet_bytes) {
+                    hil::usb::OutResult::Ok
+                } else {
+                    self.stall();
+                    hil::usb::OutResult::Error
+                }
+            }
+            State::DataOut { offset, len } => {
+                let received = cmp::min(packet_bytes, len - offset);
+                let packet = &self.out_buffer.buf;
+                self.data_buffer.map(|buffer| {
+                    for (byte, cell) in buffer[offset..offset + received].iter_mut().zip(packet) {
+                        *byte = cell.get();
+                    }
+                });
+                self.processed.set(self.processed.get() + received);
+
+                if offset + received < len {
+                    self.state.set(State::DataOut {
+                        offset: offset + received,
+                        len,
+                    });
+                    hil::usb::OutResult::Ok
+                } else {
+                    match self.write_chunk() {
+                        // Hold off the host until the blocks are written.
+                        Ok(()) => hil::usb::OutResult::Delay,
+                        Err(_) => {
+                            self.write_failed();
+                            hil::usb::OutResult::Ok
+                        }
+                    }
+                }
+            }
+            State::Discard { remaining } => {
+                let remaining = remaining.saturating_sub(packet_bytes);
+                if remaining == 0 {
+                    self.send_status();
+                } else {
+                    self.state.set(State::Discard { remaining });
+                }
+                hil::usb::OutResult::Ok
+            }
+            State::Stalled => hil::usb::OutResult::Error,
+            _ => hil::usb::OutResult::Ok,
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        match self.state.get() {
+            State::DataIn { offset, len } if offset < len => {
+                self.controller().endpoint_resume_in(self.endpoint_in);
+            }
+            State::DataIn { .. } => {
+                if self.blocks_remaining.get() > 0 {
+                    self.read_chunk();
+                } else {
+                    self.end_data_in();
+                }
+            }
+            State::ZeroLengthPacket { sent: true } => self.send_status(),
+            State::Status { sent: true } => self.state.set(State::Command),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, B: BlockStorage<'a>> UsbFunction<'a>
+    for MassStorage<'a, U, B>
+{
+    fn interfaces(&self) -> (u8, u8) {
+        (self.interface, 1)
+    }
+
+    fn has_endpoint(&self, endpoint: usize) -> bool {
+        endpoint == self.endpoint_in || endpoint == self.endpoint_out
+    }
+
+    fn descriptors(&self, f: &mut dyn FnMut(&dyn Descriptor)) {
+        let (interface_descriptor, endpoint_descriptors) =
+            msc_descriptors(self.interface, self.endpoint_in, self.endpoint_out);
+        f(&interface_descriptor);
+        for descriptor in endpoint_descriptors.iter() {
+            f(descriptor);
+        }
+    }
+
+    fn enable(&'a self) {
+        self.enable_endpoints();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    fn ctrl_setup(&'a self, setup: &SetupData) -> hil::usb::CtrlSetupResult {
+        if self.handle_class_request(setup) {
+            hil::usb::CtrlSetupResult::Ok
+        } else {
+            hil::usb::CtrlSetupResult::ErrNonstandardRequest
+        }
+    }
+
+    fn ctrl_in(&'a self, packet: &[VolatileCell<u8>]) -> hil::usb::CtrlInResult {
+        self.max_lun(packet)
+    }
+
+    fn ctrl_status_complete(&'a self) {
+        self.ctrl_state.set(CtrlState::Idle);
+    }
+
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        hil::usb::Client::packet_in(self, transfer_type, endpoint)
+    }
+
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        hil::usb::Client::packet_out(self, transfer_type, endpoint, packet_bytes)
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        hil::usb::Client::packet_transmitted(self, endpoint)
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>, B: BlockStorage<'a>> BlockStorageClient
+    for MassStorage<'a, U, B>
+{
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.data_buffer.replace(buffer);
+        if self.state.get() == State::Busy {
+            self.chunk_read(result);
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>) {
+        self.data_buffer.replace(buffer);
+        if self.state.get() == State::Busy {
+            if result.is_err() {
+                self.write_failed();
+            } else {
+                self.block
+                    .set(self.block.get() + self.chunk_blocks.get() as u64);
+                self.blocks_remaining
+                    .set(self.blocks_remaining.get() - self.chunk_blocks.get());
+                if self.blocks_remaining.get() > 0 {
+                    let len = self.next_chunk() * self.storage.block_size();
+                    self.state.set(State::DataOut { offset: 0, len });
+                } else {
+                    self.send_status();
+                }
+            }
+        }
+
+        // Accept packets from the host again.
+        self.controller().endpoint_resume_out(self.endpoint_out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use hil::usb::{Client, DeviceSpeed, InResult, OutResult};
+    use kernel::utilities::cells::OptionalCell;
+    use std::boxed::Box;
+    use std::vec;
+    use std::vec::Vec;
+
+    const BLOCK_SIZE: usize = 512;
+    const BLOCKS: usize = 8;
+
+    /// A USB controller that only counts resumed endpoints.
+    #[derive(Default)]
+    struct Usbd {
+        resumed_out: Cell<usize>,
+    }
+
+    impl<'a> hil::usb::UsbController<'a> for Usbd {
+        fn set_client(&self, _client: &'a dyn hil::usb::Client<'a>) {}
+        fn endpoint_set_ctrl_buffer(&self, _buf: &'a [VolatileCell<u8>]) {}
+        fn endpoint_set_in_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+        fn endpoint_set_out_buffer(&self, _endpoint: usize, _buf: &'a [VolatileCell<u8>]) {}
+        fn enable_as_device(&self, _speed: DeviceSpeed) {}
+        fn attach(&self) {}
+        fn detach(&self) {}
+        fn set_address(&self, _addr: u16) {}
+        fn enable_address(&self) {}
+        fn endpoint_in_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_in_out_enable(&self, _transfer_type: TransferType, _endpoint: usize) {}
+        fn endpoint_resume_in(&self, _endpoint: usize) {}
+        fn endpoint_resume_out(&self, _endpoint: usize) {
+            self.resumed_out.set(self.resumed_out.get() + 1);
+        }
+    }
+
+    /// A block device in RAM that completes operations when the test asks.
+    struct Disk {
+        data: RefCell<Vec<u8>>,
+        /// The buffer and `(write, block, count)` of the pending operation.
+        buffer: TakeCell<'static, [u8]>,
+        operation: Cell<Option<(bool, u64, usize)>>,
+        client: OptionalCell<&'static dyn BlockStorageClient>,
+    }
+
+    impl Disk {
+        fn start(
+            &self,
+            write: bool,
+            buffer: &'static mut [u8],
+            block: u64,
+            count: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            if block as usize + count > BLOCKS || buffer.len() < count * BLOCK_SIZE {
+                return Err((ErrorCode::INVAL, buffer));
+            }
+            self.buffer.replace(buffer);
+            self.operation.set(Some((write, block, count)));
+            Ok(())
+        }
+
+        /// Complete the pending operation. Returns false if there is none.
+        fn complete(&self) -> bool {
+            let Some((write, block, count)) = self.operation.take() else {
+                return false;
+            };
+            let buffer = self.buffer.take().unwrap();
+            let range = block as usize * BLOCK_SIZE..(block as usize + count) * BLOCK_SIZE;
+            let len = count * BLOCK_SIZE;
+            self.client.map(|client| {
+                if write {
+                    self.data.borrow_mut()[range].copy_from_slice(&buffer[..len]);
+                    client.write_complete(buffer, Ok(()));
+                } else {
+                    buffer[..len].copy_from_slice(&self.data.borrow()[range]);
+                    client.read_complete(buffer, Ok(()));
+                }
+            });
+            true
+        }
+    }
+
+    impl BlockStorage<'static> for Disk {
+        fn set_client(&self, client: &'static dyn BlockStorageClient) {
+            self.client.set(client);
+        }
+        fn block_size(&self) -> usize {
+            BLOCK_SIZE
+        }
+        fn block_count(&self) -> u64 {
+            BLOCKS as u64
+        }
+        fn read_blocks(
+            &self,
+            buffer: &'static mut [u8],
+            block: u64,
+            count: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.start(false, buffer, block, count)
+        }
+        fn write_blocks(
+            &self,
+            buffer: &'static mut [u8],
+            block: u64,
+            count: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.start(true, buffer, block, count)
+        }
+    }
+
+    struct Harness {
+        usbd: &'static Usbd,
+        disk: &'static Disk,
+        msc: &'static MassStorage<'static, Usbd, Disk>,
+    }
+
+    /// The Command Status Wrapper of a command.
+    #[derive(Debug, PartialEq)]
+    struct Csw {
+        tag: u32,
+        residue: u32,
+        status: u8,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let usbd = Box::leak(Box::<Usbd>::default());
+            let data = (0..BLOCKS * BLOCK_SIZE).map(|i| (i / 7) as u8).collect();
+            let disk = Box::leak(Box::new(Disk {
+                data: RefCell::new(data),
+                buffer: TakeCell::empty(),
+                operation: Cell::new(None),
+                client: OptionalCell::empty(),
+            }));
+            let msc = Box::leak(Box::new(MassStorage::new(
+                usbd,
+                disk,
+                Box::leak(vec![0; DATA_BUFFER_LEN].into_boxed_slice()),
+                0x1915,
+                0x503a,
+                &["Tock", "Mass Storage", "1"],
+            )));
+            BlockStorage::set_client(disk, msc);
+            Harness { usbd, disk, msc }
+        }
+
+        /// Send an OUT packet to the bulk endpoint.
+        fn send(&self, packet: &[u8]) -> OutResult {
+            for (cell, byte) in self.msc.out_buffer.buf.iter().zip(packet) {
+                cell.set(*byte);
+            }
+            Client::packet_out(
+                self.msc,
+                TransferType::Bulk,
+                ENDPOINT_OUT_NUM,
+                packet.len() as u32,
+            )
+        }
+
+        /// Send a Command Block Wrapper for logical unit 0.
+        fn command(&self, tag: u32, length: u32, direction_in: bool, cdb: &[u8]) -> OutResult {
+            self.send(&cbw(tag, length, direction_in, cdb))
+        }
+
+        /// Receive IN packets until the status, and return the data and the
+        /// status.
+        fn receive(&self) -> (Vec<u8>, Csw) {
+            let mut data = Vec::new();
+            loop {
+                self.disk.complete();
+                let len = match Client::packet_in(self.msc, TransferType::Bulk, ENDPOINT_IN_NUM) {
+                    InResult::Packet(len) => len,
+                    result => panic!("no status, {:?}", result),
+                };
+                let packet: Vec<u8> = self.msc.in_buffer.buf[..len]
+                    .iter()
+                    .map(|cell| cell.get())
+                    .collect();
+                let is_status = matches!(self.msc.state.get(), State::Status { .. });
+                Client::packet_transmitted(self.msc, ENDPOINT_IN_NUM);
+                if is_status {
+                    assert_eq!(len, CSW_LEN);
+                    let get_u32 = |i: usize| {
+                        u32::from_le_bytes([packet[i], packet[i + 1], packet[i + 2], packet[i + 3]])
+                    };
+                    assert_eq!(get_u32(0), CSW_SIGNATURE);
+                    let csw = Csw {
+                        tag: get_u32(4),
+                        residue: get_u32(8),
+                        status: packet[12],
+                    };
+                    return (data, csw);
+                }
+                data.extend_from_slice(&packet);
+            }
+        }
+
+        /// Perform a Bulk-Only Mass Storage Reset.
+        fn bulk_only_reset(&self) {
+            let packet = [0x21, BULK_ONLY_RESET, 0, 0, 0, 0, 0, 0].map(VolatileCell::new);
+            let setup = SetupData::get(&packet).unwrap();
+            assert!(matches!(
+                UsbFunction::ctrl_setup(self.msc, &setup),
+                hil::usb::CtrlSetupResult::Ok
+            ));
+        }
+
+        fn sense(&self) -> (u8, u8) {
+            self.command(99, 18, true, &[REQUEST_SENSE, 0, 0, 0, 18, 0]);
+            let (data, csw) = self.receive();
+            assert_eq!(csw.status, 0);
+            (data[2], data[12])
+        }
+    }
+
+    fn cbw(tag: u32, length: u32, direction_in: bool, cdb: &[u8]) -> [u8; CBW_LEN] {
+        let mut packet = [0; CBW_LEN];
+        packet[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        packet[4..8].copy_from_slice(&tag.to_le_bytes());
+        packet[8..12].copy_from_slice(&length.to_le_bytes());
+        packet[12] = if direction_in { 0x80 } else { 0x00 };
+        packet[14] = cdb.len() as u8;
+        packet[15..15 + cdb.len()].copy_from_slice(cdb);
+        packet
+    }
+
+    /// A READ(10) or WRITE(10) command block.
+    fn transfer_cdb(opcode: u8, block: u32, count: u16) -> [u8; 10] {
+        let block = block.to_be_bytes();
+        let count = count.to_be_bytes();
+        [
+            opcode, 0, block[0], block[1], block[2], block[3], 0, count[0], count[1], 0,
+        ]
+    }
+
+    #[test]
+    fn invalid_cbw_stalls_until_reset() {
+        let h = Harness::new();
+        let valid = cbw(1, 0, false, &[TEST_UNIT_READY, 0, 0, 0, 0, 0]);
+
+        let mut bad_signature = valid;
+        bad_signature[0] = 0;
+        let mut bad_lun = valid;
+        bad_lun[13] = 1;
+        let mut bad_length = valid;
+        bad_length[14] = 17;
+        for packet in [&bad_signature[..], &bad_lun, &bad_length, &valid[..30]] {
+            assert!(matches!(h.send(packet), OutResult::Error));
+            assert!(matches!(
+                Client::packet_in(h.msc, TransferType::Bulk, ENDPOINT_IN_NUM),
+                InResult::Error
+            ));
+            // A valid command does not clear the stall.
+            assert!(matches!(h.send(&valid), OutResult::Error));
+            assert!(matches!(
+                Client::packet_in(h.msc, TransferType::Bulk, ENDPOINT_IN_NUM),
+                InResult::Error
+            ));
+
+            h.bulk_only_reset();
+            assert!(matches!(h.send(&valid), OutResult::Ok));
+            let (_, csw) = h.receive();
+            assert_eq!(
+                csw,
+                Csw {
+                    tag: 1,
+                    residue: 0,
+                    status: 0
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn read() {
+        let h = Harness::new();
+        let len = 3 * BLOCK_SIZE;
+        h.command(7, len as u32, true, &transfer_cdb(READ_10, 4, 3));
+        let (data, csw) = h.receive();
+        assert_eq!(data, h.disk.data.borrow()[4 * BLOCK_SIZE..7 * BLOCK_SIZE]);
+        assert_eq!(
+            csw,
+            Csw {
+                tag: 7,
+                residue: 0,
+                status: 0
+            }
+        );
+    }
+
+    #[test]
+    fn read_out_of_range() {
+        let h = Harness::new();
+        let len = 2 * BLOCK_SIZE as u32;
+        h.command(7, len, true, &transfer_cdb(READ_10, BLOCKS as u32 - 1, 2));
+        let (data, csw) = h.receive();
+        assert!(data.is_empty());
+        assert_eq!(
+            csw,
+            Csw {
+                tag: 7,
+                residue: len,
+                status: 1
+            }
+        );
+        assert_eq!(h.sense(), (LBA_OUT_OF_RANGE.key, LBA_OUT_OF_RANGE.asc));
+    }
+
+    #[test]
+    fn write() {
+        let h = Harness::new();
+        let blocks: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| !(i as u8)).collect();
+        h.command(8, blocks.len() as u32, false, &transfer_cdb(WRITE_10, 2, 2));
+        for (i, packet) in blocks.chunks(MAX_PACKET_SIZE).enumerate() {
+            let result = h.send(packet);
+            if (i + 1) * MAX_PACKET_SIZE % DATA_BUFFER_LEN == 0 {
+                // The host waits while the block is written.
+                assert!(matches!(result, OutResult::Delay));
+                let resumed = h.usbd.resumed_out.get();
+                assert!(h.disk.complete());
+                assert_eq!(h.usbd.resumed_out.get(), resumed + 1);
+            } else {
+                assert!(matches!(result, OutResult::Ok));
+            }
+        }
+        let (_, csw) = h.receive();
+        assert_eq!(
+            csw,
+            Csw {
+                tag: 8,
+                residue: 0,
+                status: 0
+            }
+        );
+        assert_eq!(h.disk.data.borrow()[2 * BLOCK_SIZE..4 * BLOCK_SIZE], blocks);
+    }
+
+    #[test]
+    fn write_out_of_range() {
+        let h = Harness::new();
+        let original = h.disk.data.borrow().clone();
+        let len = BLOCK_SIZE as u32;
+        h.command(9, len, false, &transfer_cdb(WRITE_10, BLOCKS as u32, 1));
+        // The data is dropped.
+        for _ in 0..BLOCK_SIZE / MAX_PACKET_SIZE {
+            assert!(matches!(h.send(&[0; MAX_PACKET_SIZE]), OutResult::Ok));
+        }
+        assert!(!h.disk.complete());
+        let (_, csw) = h.receive();
+        assert_eq!(
+            csw,
+            Csw {
+                tag: 9,
+                residue: len,
+                status: 1
+            }
+        );
+        assert_eq!(*h.disk.data.borrow(), original);
+        assert_eq!(h.sense(), (LBA_OUT_OF_RANGE.key, LBA_OUT_OF_RANGE.asc));
+    }
+
+    #[test]
+    fn residue() {
+        let h = Harness::new();
+        // The host allows more data than the INQUIRY response has.
+        h.command(10, 64, true, &[INQUIRY, 0, 0, 0, 64, 0]);
+        let (data, csw) = h.receive();
+        assert_eq!(data, INQUIRY_DATA);
+        assert_eq!(
+            csw,
+            Csw {
+                tag: 10,
+                residue: 64 - INQUIRY_DATA.len() as u32,
+                status: 0
+            }
+        );
+
+        // The host expects data for a command without a data stage.
+        h.command(11, 64, true, &[TEST_UNIT_READY, 0, 0, 0, 0, 0]);
+        let (data, csw) = h.receive();
+        assert!(data.is_empty());
+        assert_eq!(
+            csw,
+            Csw {
+                tag: 11,
+                residue: 64,
+                status: 0
+            }
+        );
+    }
+}
@@ -4,6 +4,13 @@
 
 //! Provides userspace with access to USB HID devices with a simple syscall
 //! interface.
+//!
+//! If the device supports it (see `set_reports()`), apps can also register
+//! the report descriptor of the device at runtime, set the feature report the
+//! host reads with `GET_REPORT`, and receive the output and feature reports
+//! the host sends with `SET_REPORT`.
+
+use core::cmp;
 
 use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
 use kernel::hil::usb_hid;
@@ -12,12 +19,30 @@ use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::utilities::cells::{OptionalCell, TakeCell};
 use kernel::{ErrorCode, ProcessId};
 
+use crate::usb::generic_hid::{HidReports, HidReportsClient};
+use crate::usb::hid_report::ReportType;
+
+/// The longest report descriptor apps can register.
+pub const MAX_DESCRIPTOR_LEN: usize = 256;
+
+/// Ids for read-only allow buffers
+mod ro_allow {
+    /// Report descriptor to register.
+    pub const DESCRIPTOR: usize = 0;
+    /// Feature report for `GET_REPORT` requests.
+    pub const FEATURE: usize = 1;
+    /// The number of allow buffers the kernel stores for this grant
+    pub const COUNT: u8 = 2;
+}
+
 /// Ids for read-write allow buffers
 mod rw_allow {
     pub const RECV: usize = 0;
     pub const SEND: usize = 1;
+    /// Output or feature report received with `SET_REPORT`.
+    pub const REPORT: usize = 2;
     /// The number of allow buffers the kernel stores for this grant
-    pub const COUNT: u8 = 2;
+    pub const COUNT: u8 = 3;
 }
 
 #[derive(Default)]
@@ -26,9 +51,17 @@ pub struct App {}
 pub struct UsbHidDriver<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> {
     usb: &'a U,
 
-    app: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
+    app: Grant<
+        App,
+        UpcallCount<1>,
+        AllowRoCount<{ ro_allow::COUNT }>,
+        AllowRwCount<{ rw_allow::COUNT }>,
+    >,
     processid: OptionalCell<ProcessId>,
 
+    /// The device, if its report descriptor can be set at runtime.
+    reports: OptionalCell<&'a dyn HidReports<'a>>,
+
     send_buffer: TakeCell<'static, [u8; 64]>,
     recv_buffer: TakeCell<'static, [u8; 64]>,
 }
@@ -38,16 +71,58 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> UsbHidDriver<'a, U> {
         usb: &'a U,
         send_buffer: &'static mut [u8; 64],
         recv_buffer: &'static mut [u8; 64],
-        grant: Grant<App, UpcallCount<1>, AllowRoCount<0>, AllowRwCount<{ rw_allow::COUNT }>>,
+        grant: Grant<
+            App,
+            UpcallCount<1>,
+            AllowRoCount<{ ro_allow::COUNT }>,
+            AllowRwCount<{ rw_allow::COUNT }>,
+        >,
     ) -> UsbHidDriver<'a, U> {
         UsbHidDriver {
             usb,
             app: grant,
             processid: OptionalCell::empty(),
+            reports: OptionalCell::empty(),
             send_buffer: TakeCell::new(send_buffer),
             recv_buffer: TakeCell::new(recv_buffer),
         }
     }
+
+    /// Let apps set the report descriptor and feature report of `reports`,
+    /// and receive the reports the host sends to it with control transfers.
+    /// This driver must be the client of `reports`.
+    pub fn set_reports(&self, reports: &'a dyn HidReports<'a>) {
+        self.reports.set(reports);
+    }
+
+    /// Pass the first `len` bytes of the read-only allow buffer `allow_num`
+    /// to `f`.
+    fn with_allowed<R>(
+        &self,
+        processid: ProcessId,
+        allow_num: usize,
+        len: usize,
+        f: impl FnOnce(&dyn HidReports<'a>, &[u8]) -> Result<R, ErrorCode>,
+    ) -> Result<R, ErrorCode> {
+        let reports = self.reports.get().ok_or(ErrorCode::NOSUPPORT)?;
+        self.app
+            .enter(processid, |_, kernel_data| {
+                kernel_data
+                    .get_readonly_processbuffer(allow_num)
+                    .and_then(|buffer| {
+                        buffer.enter(|data| {
+                            if len > data.len() || len > MAX_DESCRIPTOR_LEN {
+                                return Err(ErrorCode::SIZE);
+                            }
+                            let mut copy = [0; MAX_DESCRIPTOR_LEN];
+                            data[..len].copy_to_slice(&mut copy[..len]);
+                            f(reports, &copy[..len])
+                        })
+                    })
+                    .unwrap_or(Err(ErrorCode::RESERVE))
+            })
+            .unwrap_or_else(|err| Err(err.into()))
+    }
 }
 
 impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> usb_hid::UsbHid<'a, [u8; 64]> for UsbHidDriver<'a, U> {
@@ -115,6 +190,27 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> usb_hid::Client<'a, [u8; 64]> for Usb
     }
 }
 
+impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> HidReportsClient for UsbHidDriver<'a, U> {
+    fn report_set(&self, report_type: ReportType, report: &[u8]) {
+        self.processid.map(|id| {
+            let _ = self.app.enter(id, |_app, kernel_data| {
+                let _ = kernel_data
+                    .get_readwrite_processbuffer(rw_allow::REPORT)
+                    .and_then(|dest| {
+                        dest.mut_enter(|dest| {
+                            let len = cmp::min(dest.len(), report.len());
+                            dest[..len].copy_from_slice(&report[..len]);
+                        })
+                    });
+
+                kernel_data
+                    .schedule_upcall(0, (2, report_type as usize, report.len()))
+                    .ok();
+            });
+        });
+    }
+}
+
 impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> SyscallDriver for UsbHidDriver<'a, U> {
     // Subscribe to UsbHidDriver events.
     //
@@ -124,11 +220,13 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> SyscallDriver for UsbHidDriver<'a, U>
     //        The callback signature is `fn(direction: u32)`
     //        `fn(0)` indicates a packet was received
     //        `fn(1)` indicates a packet was transmitted
+    //        `fn(2, report_type, len)` indicates the host sent an output or
+    //        feature report with a control transfer
 
     fn command(
         &self,
         command_num: usize,
-        _data1: usize,
+        data1: usize,
         _data2: usize,
         processid: ProcessId,
     ) -> CommandReturn {
@@ -278,6 +376,31 @@ impl<'a, U: usb_hid::UsbHid<'a, [u8; 64]>> SyscallDriver for UsbHidDriver<'a, U>
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Register the first `data1` bytes of the descriptor buffer as
+            // the report descriptor. Returns the length of the input and
+            // output reports it describes.
+            6 => {
+                self.processid.set(processid);
+                match self.with_allowed(processid, ro_allow::DESCRIPTOR, data1, |reports, data| {
+                    reports.set_report_descriptor(data)
+                }) {
+                    Ok(lengths) => {
+                        CommandReturn::success_u32_u32(lengths.input as u32, lengths.output as u32)
+                    }
+                    Err(e) => CommandReturn::failure(e),
+                }
+            }
+
+            // Set the feature report to the first `data1` bytes of the
+            // feature buffer.
+            7 => {
+                self.processid.set(processid);
+                self.with_allowed(processid, ro_allow::FEATURE, data1, |reports, data| {
+                    reports.set_feature_report(data)
+                })
+                .into()
+            }
+
             // default
             _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
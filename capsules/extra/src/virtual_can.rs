@@ -0,0 +1,445 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Virtualize a CAN peripheral.
+//!
+//! `MuxCan` shares one CAN peripheral between several users, each of which
+//! gets a `VirtualCan` device implementing the CAN HIL.
+//!
+//! - Messages of the devices are sent one after another.
+//! - Every message the peripheral receives is passed to all devices that are
+//!   receiving. The peripheral receives while at least one device does.
+//! - The bus configuration and the controller state are shared, so a device
+//!   that configures, enables or disables the peripheral does so for all
+//!   devices. Every device with a controller client is told about changes of
+//!   the controller state.
+//!
+//! The peripheral receives without hardware filters, as required by
+//! `hil::can::Receive`, so the devices have to filter identifiers themselves.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let mux_can = static_init!(
+//!     MuxCan<'static, stm32f429zi::can::Can<'static>>,
+//!     MuxCan::new(&peripherals.can1, rx_buffer)
+//! );
+//! mux_can.register();
+//! kernel::hil::can::Controller::set_client(&peripherals.can1, Some(mux_can));
+//! kernel::hil::can::Transmit::set_client(&peripherals.can1, Some(mux_can));
+//! kernel::hil::can::Receive::set_client(&peripherals.can1, Some(mux_can));
+//!
+//! let can_device = static_init!(
+//!     VirtualCan<'static, stm32f429zi::can::Can<'static>>,
+//!     VirtualCan::new(mux_can)
+//! );
+//! can_device.setup();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::hil::can;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+#[derive(Copy, Clone, PartialEq)]
+enum ReceiveState {
+    /// The peripheral does not receive and the mux holds its buffer.
+    Idle,
+    Receiving,
+    /// Waiting for the peripheral to return the buffer.
+    Stopping,
+}
+
+pub struct MuxCan<'a, C, const N: usize = { can::STANDARD_CAN_PACKET_SIZE }>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    can: &'a C,
+    devices: List<'a, VirtualCan<'a, C, N>>,
+    inflight: OptionalCell<&'a VirtualCan<'a, C, N>>,
+    rx_buffer: TakeCell<'static, [u8; N]>,
+    receive_state: Cell<ReceiveState>,
+    deferred_call: DeferredCall,
+}
+
+impl<'a, C, const N: usize> MuxCan<'a, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    pub fn new(can: &'a C, rx_buffer: &'static mut [u8; N]) -> Self {
+        Self {
+            can,
+            devices: List::new(),
+            inflight: OptionalCell::empty(),
+            rx_buffer: TakeCell::new(rx_buffer),
+            receive_state: Cell::new(ReceiveState::Idle),
+            deferred_call: DeferredCall::new(),
+        }
+    }
+
+    fn do_next_send(&self) {
+        while self.inflight.is_none() {
+            let Some(device) = self
+                .devices
+                .iter()
+                .find(|device| device.tx_buffer.is_some())
+            else {
+                return;
+            };
+            let Some((id, len)) = device.tx_message.take() else {
+                return;
+            };
+            device
+                .tx_buffer
+                .take()
+                .map(|buffer| match self.can.send(id, buffer, len) {
+                    Ok(()) => self.inflight.set(device),
+                    Err((_, buffer)) => {
+                        device.tx_client.map(|client| {
+                            client.transmit_complete(Err(can::Error::Transmission), buffer)
+                        });
+                    }
+                });
+        }
+    }
+
+    fn start_receiving(&self) -> Result<(), ErrorCode> {
+        if self.receive_state.get() != ReceiveState::Idle {
+            // A peripheral that is stopping restarts once it stopped.
+            return Ok(());
+        }
+        let buffer = self.rx_buffer.take().ok_or(ErrorCode::FAIL)?;
+        match self.can.start_receive_process(buffer) {
+            Ok(()) => {
+                self.receive_state.set(ReceiveState::Receiving);
+                Ok(())
+            }
+            Err((err, buffer)) => {
+                self.rx_buffer.replace(buffer);
+                Err(err)
+            }
+        }
+    }
+
+    fn stop_receiving_if_unused(&self) {
+        let unused = !self.devices.iter().any(|device| device.receiving.get());
+        if unused
+            && self.receive_state.get() == ReceiveState::Receiving
+            && self.can.stop_receive().is_ok()
+        {
+            self.receive_state.set(ReceiveState::Stopping);
+        }
+    }
+}
+
+impl<C, const N: usize> DeferredCallClient for MuxCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn handle_deferred_call(&self) {
+        for device in self.devices.iter() {
+            if device.stopping.replace(false) {
+                device.rx_buffer.take().map(|buffer| {
+                    device.rx_client.map(|client| client.stopped(buffer));
+                });
+            }
+        }
+    }
+
+    fn register(&'static self) {
+        self.deferred_call.register(self);
+    }
+}
+
+impl<C, const N: usize> can::ControllerClient for MuxCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn state_changed(&self, state: can::State) {
+        for device in self.devices.iter() {
+            device
+                .controller_client
+                .map(|client| client.state_changed(state));
+        }
+    }
+
+    fn enabled(&self, status: Result<(), ErrorCode>) {
+        for device in self.devices.iter() {
+            device
+                .controller_client
+                .map(|client| client.enabled(status));
+        }
+    }
+
+    fn disabled(&self, status: Result<(), ErrorCode>) {
+        for device in self.devices.iter() {
+            device
+                .controller_client
+                .map(|client| client.disabled(status));
+        }
+    }
+}
+
+impl<C, const N: usize> can::TransmitClient<N> for MuxCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn transmit_complete(&self, status: Result<(), can::Error>, buffer: &'static mut [u8; N]) {
+        self.inflight.take().map(|device| {
+            device
+                .tx_client
+                .map(|client| client.transmit_complete(status, buffer));
+        });
+        self.do_next_send();
+    }
+}
+
+impl<C, const N: usize> can::ReceiveClient<N> for MuxCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn message_received(
+        &self,
+        id: can::Id,
+        buffer: &mut [u8; N],
+        len: usize,
+        status: Result<(), can::Error>,
+    ) {
+        for device in self.devices.iter() {
+            if device.receiving.get() {
+                device.rx_buffer.map(|device_buffer| {
+                    device_buffer.copy_from_slice(buffer);
+                    device.rx_client.map(|client| {
+                        client.message_received(id, device_buffer, len, status);
+                    });
+                });
+            }
+        }
+    }
+
+    fn stopped(&self, buffer: &'static mut [u8; N]) {
+        self.rx_buffer.replace(buffer);
+        self.receive_state.set(ReceiveState::Idle);
+        if self.devices.iter().any(|device| device.receiving.get()) {
+            let _ = self.start_receiving();
+        }
+    }
+}
+
+pub struct VirtualCan<'a, C, const N: usize = { can::STANDARD_CAN_PACKET_SIZE }>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    mux: &'a MuxCan<'a, C, N>,
+    next: ListLink<'a, VirtualCan<'a, C, N>>,
+    tx_buffer: TakeCell<'static, [u8; N]>,
+    tx_message: Cell<Option<(can::Id, usize)>>,
+    rx_buffer: TakeCell<'static, [u8; N]>,
+    receiving: Cell<bool>,
+    stopping: Cell<bool>,
+    controller_client: OptionalCell<&'static dyn can::ControllerClient>,
+    tx_client: OptionalCell<&'static dyn can::TransmitClient<N>>,
+    rx_client: OptionalCell<&'static dyn can::ReceiveClient<N>>,
+}
+
+impl<'a, C, const N: usize> VirtualCan<'a, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    pub fn new(mux: &'a MuxCan<'a, C, N>) -> Self {
+        Self {
+            mux,
+            next: ListLink::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_message: Cell::new(None),
+            rx_buffer: TakeCell::empty(),
+            receiving: Cell::new(false),
+            stopping: Cell::new(false),
+            controller_client: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Must be called right after `static_init!()`.
+    pub fn setup(&'a self) {
+        self.mux.devices.push_head(self);
+    }
+}
+
+impl<'a, C, const N: usize> ListNode<'a, VirtualCan<'a, C, N>> for VirtualCan<'a, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn next(&'a self) -> &'a ListLink<'a, VirtualCan<'a, C, N>> {
+        &self.next
+    }
+}
+
+impl<C, const N: usize> can::Configure for VirtualCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    const MIN_BIT_TIMINGS: can::BitTiming = C::MIN_BIT_TIMINGS;
+    const MAX_BIT_TIMINGS: can::BitTiming = C::MAX_BIT_TIMINGS;
+    const SYNC_SEG: u8 = C::SYNC_SEG;
+
+    fn set_bitrate(&self, bitrate: u32) -> Result<(), ErrorCode> {
+        self.mux.can.set_bitrate(bitrate)
+    }
+
+    fn set_bit_timing(&self, bit_timing: can::BitTiming) -> Result<(), ErrorCode> {
+        self.mux.can.set_bit_timing(bit_timing)
+    }
+
+    fn set_operation_mode(&self, mode: can::OperationMode) -> Result<(), ErrorCode> {
+        self.mux.can.set_operation_mode(mode)
+    }
+
+    fn get_bit_timing(&self) -> Result<can::BitTiming, ErrorCode> {
+        self.mux.can.get_bit_timing()
+    }
+
+    fn get_operation_mode(&self) -> Result<can::OperationMode, ErrorCode> {
+        self.mux.can.get_operation_mode()
+    }
+
+    fn set_automatic_retransmission(&self, automatic: bool) -> Result<(), ErrorCode> {
+        self.mux.can.set_automatic_retransmission(automatic)
+    }
+
+    fn set_wake_up(&self, wake_up: bool) -> Result<(), ErrorCode> {
+        self.mux.can.set_wake_up(wake_up)
+    }
+
+    fn get_automatic_retransmission(&self) -> Result<bool, ErrorCode> {
+        self.mux.can.get_automatic_retransmission()
+    }
+
+    fn get_wake_up(&self) -> Result<bool, ErrorCode> {
+        self.mux.can.get_wake_up()
+    }
+
+    fn receive_fifo_count(&self) -> usize {
+        self.mux.can.receive_fifo_count()
+    }
+}
+
+impl<C, const N: usize> can::ConfigureFd for VirtualCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::ConfigureFd + can::Controller,
+{
+    fn set_payload_bit_timing(&self, payload_bit_timing: can::BitTiming) -> Result<(), ErrorCode> {
+        self.mux.can.set_payload_bit_timing(payload_bit_timing)
+    }
+
+    fn get_payload_bit_timing(&self) -> Result<can::BitTiming, ErrorCode> {
+        self.mux.can.get_payload_bit_timing()
+    }
+
+    fn get_frame_size() -> usize {
+        C::get_frame_size()
+    }
+}
+
+impl<C, const N: usize> can::Controller for VirtualCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn set_client(&self, client: Option<&'static dyn can::ControllerClient>) {
+        self.controller_client.insert(client);
+    }
+
+    fn enable(&self) -> Result<(), ErrorCode> {
+        self.mux.can.enable()
+    }
+
+    fn disable(&self) -> Result<(), ErrorCode> {
+        self.mux.can.disable()
+    }
+
+    fn get_state(&self) -> Result<can::State, ErrorCode> {
+        self.mux.can.get_state()
+    }
+}
+
+impl<C, const N: usize> can::Transmit<N> for VirtualCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn set_client(&self, client: Option<&'static dyn can::TransmitClient<N>>) {
+        self.tx_client.insert(client);
+    }
+
+    fn send(
+        &self,
+        id: can::Id,
+        buffer: &'static mut [u8; N],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8; N])> {
+        if self.tx_buffer.is_some()
+            || self
+                .mux
+                .inflight
+                .map_or(false, |device| core::ptr::eq(device, self))
+        {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if self.mux.inflight.is_none() {
+            // Report errors of an idle peripheral right away.
+            if let Some(device) = self
+                .mux
+                .devices
+                .iter()
+                .find(|device| core::ptr::eq(*device, self))
+            {
+                self.mux.can.send(id, buffer, len)?;
+                self.mux.inflight.set(device);
+                return Ok(());
+            }
+        }
+        self.tx_buffer.replace(buffer);
+        self.tx_message.set(Some((id, len)));
+        Ok(())
+    }
+}
+
+impl<C, const N: usize> can::Receive<N> for VirtualCan<'_, C, N>
+where
+    C: can::Transmit<N> + can::Receive<N> + can::Configure + can::Controller,
+{
+    fn set_client(&self, client: Option<&'static dyn can::ReceiveClient<N>>) {
+        self.rx_client.insert(client);
+    }
+
+    fn start_receive_process(
+        &self,
+        buffer: &'static mut [u8; N],
+    ) -> Result<(), (ErrorCode, &'static mut [u8; N])> {
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if let Err(err) = self.mux.start_receiving() {
+            return Err((err, buffer));
+        }
+        self.rx_buffer.replace(buffer);
+        self.receiving.set(true);
+        Ok(())
+    }
+
+    fn stop_receive(&self) -> Result<(), ErrorCode> {
+        if !self.receiving.get() {
+            return Err(ErrorCode::OFF);
+        }
+        self.receiving.set(false);
+        self.stopping.set(true);
+        self.mux.deferred_call.set();
+        self.mux.stop_receiving_if_unused();
+        Ok(())
+    }
+}
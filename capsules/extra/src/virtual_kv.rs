@@ -32,6 +32,7 @@
 //!    hil::flash
 //! ```
 
+use core::cell::Cell;
 use kernel::collections::list::{List, ListLink, ListNode};
 
 use kernel::hil::kv;
@@ -49,6 +50,8 @@ enum Operation {
     Add,
     Update,
     GarbageCollect,
+    NextKey,
+    GetStats,
 }
 
 pub struct VirtualKVPermissions<'a, V: kv::KVPermissions<'a>> {
@@ -61,6 +64,7 @@ pub struct VirtualKVPermissions<'a, V: kv::KVPermissions<'a>> {
     key: MapCell<SubSliceMut<'static, u8>>,
     value: MapCell<SubSliceMut<'static, u8>>,
     valid_ids: OptionalCell<StoragePermissions>,
+    cursor: Cell<usize>,
 }
 
 impl<'a, V: kv::KVPermissions<'a>> ListNode<'a, VirtualKVPermissions<'a, V>>
@@ -81,6 +85,7 @@ impl<'a, V: kv::KVPermissions<'a>> VirtualKVPermissions<'a, V> {
             key: MapCell::empty(),
             value: MapCell::empty(),
             valid_ids: OptionalCell::empty(),
+            cursor: Cell::new(0),
         }
     }
 
@@ -235,6 +240,45 @@ impl<'a, V: kv::KVPermissions<'a>> kv::KVPermissions<'a> for VirtualKVPermission
         self.mux_kv.do_next_op(false)
     }
 
+    fn next_key(
+        &self,
+        cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+        permissions: StoragePermissions,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    > {
+        if self.operation.is_some() {
+            return Err((key, value, ErrorCode::BUSY));
+        }
+
+        self.operation.set(Operation::NextKey);
+        self.valid_ids.set(permissions);
+        self.cursor.set(cursor);
+        self.key.replace(key);
+        self.value.replace(value);
+
+        self.mux_kv
+            .do_next_op(false)
+            .map_err(|e| (self.key.take().unwrap(), self.value.take().unwrap(), e))
+    }
+
+    fn get_stats(&self) -> Result<(), ErrorCode> {
+        if self.operation.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        self.operation.set(Operation::GetStats);
+
+        self.mux_kv.do_next_op(false)
+    }
+
     fn header_size(&self) -> usize {
         self.mux_kv.kv.header_size()
     }
@@ -283,6 +327,27 @@ impl<'a, V: kv::KVPermissions<'a>> MuxKVPermissions<'a, V> {
                     };
                 }
 
+                // Neither does GetStats
+                if op == Operation::GetStats {
+                    return match self.kv.get_stats() {
+                        Ok(()) => {
+                            self.inflight.set(node);
+                            Ok(())
+                        }
+                        Err(e) => {
+                            node.operation.clear();
+                            if async_op {
+                                node.client.map(move |cb| {
+                                    cb.stats_complete(Err(e));
+                                });
+                                Ok(())
+                            } else {
+                                Err(e)
+                            }
+                        }
+                    };
+                }
+
                 node.key.take().map_or(Ok(()), |key| match op {
                     Operation::Get => node.value.take().map_or(Ok(()), |value| {
                         node.valid_ids.map_or(Ok(()), |perms| {
@@ -397,7 +462,31 @@ impl<'a, V: kv::KVPermissions<'a>> MuxKVPermissions<'a, V> {
                                 }
                             })
                     }
-                    Operation::GarbageCollect => Err(ErrorCode::NOSUPPORT),
+                    Operation::NextKey => node.value.take().map_or(Ok(()), |value| {
+                        node.valid_ids.map_or(Ok(()), |perms| {
+                            let cursor = node.cursor.get();
+                            match self.kv.next_key(cursor, key, value, perms) {
+                                Ok(()) => {
+                                    self.inflight.set(node);
+                                    Ok(())
+                                }
+                                Err((key, value, e)) => {
+                                    node.operation.clear();
+                                    if async_op {
+                                        node.client.map(move |cb| {
+                                            cb.next_key_complete(Err(e), cursor, key, value);
+                                        });
+                                        Ok(())
+                                    } else {
+                                        node.key.replace(key);
+                                        node.value.replace(value);
+                                        Err(e)
+                                    }
+                                }
+                            }
+                        })
+                    }),
+                    Operation::GarbageCollect | Operation::GetStats => Err(ErrorCode::NOSUPPORT),
                 })
             })
         })
@@ -490,4 +579,32 @@ impl<'a, V: kv::KVPermissions<'a>> kv::KVClient for MuxKVPermissions<'a, V> {
 
         let _ = self.do_next_op(true);
     }
+
+    fn next_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) {
+        self.inflight.take().map(|node| {
+            node.operation.clear();
+            node.client.map(move |cb| {
+                cb.next_key_complete(result, cursor, key, value);
+            });
+        });
+
+        let _ = self.do_next_op(true);
+    }
+
+    fn stats_complete(&self, result: Result<kv::KVStats, ErrorCode>) {
+        self.inflight.take().map(|node| {
+            node.operation.clear();
+            node.client.map(move |cb| {
+                cb.stats_complete(result);
+            });
+        });
+
+        let _ = self.do_next_op(true);
+    }
 }
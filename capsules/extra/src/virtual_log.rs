@@ -0,0 +1,1121 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Virtualize a persistent log.
+//!
+//! `MuxLog` shares one log (for instance a `capsules_extra::log::Log` on a storage volume) between
+//! several independent logical logs. Every `VirtualLog` has a log ID, and each entry it appends
+//! is stored with a small header containing that ID and, if the virtual log has a timestamp
+//! source, the time the entry was appended. Reading from a `VirtualLog` skips the entries of all
+//! other logs, so kernel subsystems and the userspace log driver can each keep their own event
+//! history on the same volume.
+//!
+//! All logical logs share the capacity of the underlying log, so in a circular log a busy log
+//! also pushes the oldest entries of the other logs out. Entry IDs are the entry IDs of the
+//! underlying log and keep their ordering across all logical logs.
+//!
+//! In addition to the `hil::log` interface, a `VirtualLog` supports:
+//!     * Filters:  Reads can be restricted to the entries of another log, of any log, or to
+//!                 entries with a timestamp within a range. See `LogFilter`.
+//!     * Seeking by timestamp: `seek_timestamp()` moves the read position to the first entry
+//!                 with a timestamp at or after a given time.
+//!
+//! Timestamps are milliseconds from a `LogTimestamp` source. `AlarmTimestamp` counts the time
+//! since boot with an alarm, while `DateTimeTimestamp` counts the time since the Unix epoch based
+//! on a real time clock. Time since boot starts over after a reboot, so those timestamps only
+//! order the entries written during one boot.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//!     let mux_log = static_init!(
+//!         capsules_extra::virtual_log::MuxLog<'static, Log>,
+//!         capsules_extra::virtual_log::MuxLog::new(log, &mut MUX_LOG_BUFFER)
+//!     );
+//!     log.set_read_client(mux_log);
+//!     log.set_append_client(mux_log);
+//!
+//!     let uptime = static_init!(
+//!         capsules_extra::virtual_log::AlarmTimestamp<'static, Alarm>,
+//!         capsules_extra::virtual_log::AlarmTimestamp::new(alarm)
+//!     );
+//!     let events = static_init!(
+//!         capsules_extra::virtual_log::VirtualLog<'static, Log>,
+//!         capsules_extra::virtual_log::VirtualLog::new(mux_log, EVENTS_LOG_ID, Some(uptime))
+//!     );
+//!     events.setup();
+//!     events.set_read_client(events_client);
+//!     events.set_append_client(events_client);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::hil::date_time::{DateTime, DateTimeClient, DateTimeValues};
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::hil::time::{Frequency, Ticks, Time};
+use kernel::storage_permissions::StoragePermissions;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Size of the log ID at the start of an entry.
+const LOG_ID_SIZE: usize = 4;
+/// Size of the timestamp following the log ID.
+const TIMESTAMP_SIZE: usize = 8;
+/// Size of the header stored in front of the data of each entry.
+pub const HEADER_SIZE: usize = LOG_ID_SIZE + TIMESTAMP_SIZE;
+
+/// Timestamp stored for entries appended without a known time.
+const NO_TIMESTAMP: u64 = u64::MAX;
+
+/// A source of entry timestamps.
+pub trait LogTimestamp {
+    /// Returns the current time in milliseconds, or `None` if it is not known.
+    fn timestamp(&self) -> Option<u64>;
+}
+
+/// Milliseconds since the source was created (normally during boot), based on a `Time` such as
+/// an `Alarm`.
+///
+/// The ticks elapsed since the previous timestamp are added to a 64 bit count, so timestamps keep
+/// increasing when the hardware counter wraps around as long as a timestamp is taken at least
+/// once per wrap around period.
+pub struct AlarmTimestamp<'a, T: Time> {
+    time: &'a T,
+    /// Counter value when the last timestamp was taken.
+    last: Cell<T::Ticks>,
+    /// Ticks elapsed until the last timestamp was taken.
+    ticks: Cell<u64>,
+}
+
+impl<'a, T: Time> AlarmTimestamp<'a, T> {
+    pub fn new(time: &'a T) -> AlarmTimestamp<'a, T> {
+        AlarmTimestamp {
+            time,
+            last: Cell::new(time.now()),
+            ticks: Cell::new(0),
+        }
+    }
+}
+
+impl<T: Time> LogTimestamp for AlarmTimestamp<'_, T> {
+    fn timestamp(&self) -> Option<u64> {
+        let now = self.time.now();
+        let elapsed = now.wrapping_sub(self.last.get());
+        self.last.set(now);
+        self.ticks.set(self.ticks.get() + elapsed.into_u32() as u64);
+        Some(self.ticks.get() * 1000 / T::Frequency::frequency() as u64)
+    }
+}
+
+/// Milliseconds since the Unix epoch, based on a real time clock.
+///
+/// The clock is read once after `start()` is called, afterwards the time advances with the time
+/// since boot from `uptime`. Until the clock has been read no timestamps are available.
+pub struct DateTimeTimestamp<'a, D: DateTime<'a>, T: Time> {
+    date_time: &'a D,
+    uptime: AlarmTimestamp<'a, T>,
+    /// Unix time and time since boot when the clock was read.
+    base: OptionalCell<(u64, u64)>,
+}
+
+impl<'a, D: DateTime<'a>, T: Time> DateTimeTimestamp<'a, D, T> {
+    pub fn new(date_time: &'a D, time: &'a T) -> DateTimeTimestamp<'a, D, T> {
+        DateTimeTimestamp {
+            date_time,
+            uptime: AlarmTimestamp::new(time),
+            base: OptionalCell::empty(),
+        }
+    }
+
+    /// Read the real time clock. Has to be called again if the clock is set to a new time.
+    pub fn start(&self) -> Result<(), ErrorCode> {
+        self.date_time.get_date_time()
+    }
+}
+
+impl<'a, D: DateTime<'a>, T: Time> DateTimeClient for DateTimeTimestamp<'a, D, T> {
+    fn get_date_time_done(&self, datetime: Result<DateTimeValues, ErrorCode>) {
+        if let Some(unix_time) = datetime.ok().as_ref().and_then(unix_time_ms) {
+            self.base
+                .set((unix_time, self.uptime.timestamp().unwrap_or(0)));
+        }
+    }
+
+    fn set_date_time_done(&self, _result: Result<(), ErrorCode>) {}
+}
+
+impl<'a, D: DateTime<'a>, T: Time> LogTimestamp for DateTimeTimestamp<'a, D, T> {
+    fn timestamp(&self) -> Option<u64> {
+        let uptime = self.uptime.timestamp()?;
+        self.base
+            .get()
+            .map(|(unix_time, base_uptime)| unix_time + (uptime - base_uptime))
+    }
+}
+
+/// Converts a date and time to milliseconds since the Unix epoch. Returns `None` for dates
+/// before 1970.
+fn unix_time_ms(date_time: &DateTimeValues) -> Option<u64> {
+    if date_time.year < 1970 {
+        return None;
+    }
+
+    // Count years from March, so that a leap day is the last day of its year.
+    let month = date_time.month as u64 + 1;
+    let year = date_time.year as u64 - u64::from(month <= 2);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + date_time.day as u64 - 1;
+    // 719468 is the number of days from 0000-03-01 to 1970-01-01.
+    let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year - 719_468;
+
+    let seconds = days * 86_400
+        + date_time.hour as u64 * 3_600
+        + date_time.minute as u64 * 60
+        + date_time.seconds as u64;
+    Some(seconds * 1000)
+}
+
+/// Selects which entries reads from a `VirtualLog` return.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct LogFilter {
+    /// Only return entries of the log with this ID, or of every log if `None`.
+    pub log_id: Option<u32>,
+    /// Only return entries with a timestamp in the range `[start, end)`. Entries without a
+    /// timestamp are only returned if this is `None`.
+    pub time: Option<(u64, u64)>,
+}
+
+/// Information about the entry returned by the last successful read.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EntryInfo {
+    /// ID of the entry within the underlying log.
+    pub entry_id: usize,
+    /// ID of the log the entry belongs to.
+    pub log_id: u32,
+    /// Time the entry was appended, if it was known.
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Idle,
+    Read(usize),
+    Seek(usize),
+    SeekTimestamp(u64),
+    Append(usize),
+    Sync,
+    Erase,
+}
+
+/// Shares one log between several `VirtualLog`s and serializes their requests.
+pub struct MuxLog<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> {
+    log: &'a L,
+    users: List<'a, VirtualLog<'a, L>>,
+    inflight: OptionalCell<&'a VirtualLog<'a, L>>,
+    /// Buffer for entries including their header.
+    buffer: TakeCell<'static, [u8]>,
+    /// Size of `buffer`.
+    buffer_size: usize,
+    /// ID of the entry being read from the underlying log.
+    entry_id: Cell<usize>,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> MuxLog<'a, L> {
+    /// Creates a mux for `log`. The size of `buffer` limits the size of entries, including the
+    /// `HEADER_SIZE` bytes of the header.
+    pub fn new(log: &'a L, buffer: &'static mut [u8]) -> MuxLog<'a, L> {
+        MuxLog {
+            log,
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+            buffer_size: buffer.len(),
+            buffer: TakeCell::new(buffer),
+            entry_id: Cell::new(0),
+        }
+    }
+
+    /// Starts the operation of `user` right away if the log is idle, otherwise it is started when
+    /// the operations before it are done. Only errors of operations started right away are
+    /// returned.
+    fn do_op(&self, user: &VirtualLog<'a, L>) -> Result<(), ErrorCode> {
+        if self.inflight.is_some() {
+            return Ok(());
+        }
+        self.users
+            .iter()
+            .find(|node| core::ptr::eq(*node, user))
+            .map_or(Err(ErrorCode::RESERVE), |user| self.start(user))
+    }
+
+    /// Starts the next pending operation, reporting the failure of any operation that cannot be
+    /// started to its client.
+    fn do_next_op(&self) {
+        while self.inflight.is_none() {
+            let Some(user) = self
+                .users
+                .iter()
+                .find(|user| user.operation.get() != Op::Idle)
+            else {
+                return;
+            };
+            if let Err(error) = self.start(user) {
+                self.complete(user, 0, false, Err(error));
+            }
+        }
+    }
+
+    fn start(&self, user: &'a VirtualLog<'a, L>) -> Result<(), ErrorCode> {
+        self.inflight.set(user);
+        let result = match user.operation.get() {
+            Op::Read(_) => {
+                // Entries before the start of the log were overwritten or erased.
+                let entry_id = user.read_entry_id.get();
+                if entry_id < self.log.log_start() || entry_id > self.log.log_end() {
+                    user.read_entry_id.set(self.log.log_start());
+                }
+                if self.log.next_read_entry_id() != user.read_entry_id.get() {
+                    self.log.seek(user.read_entry_id.get())
+                } else {
+                    self.read_next()
+                }
+            }
+            // Scan the entire log, the search continues after the seek completes.
+            Op::SeekTimestamp(_) => self.log.seek(self.log.log_start()),
+            Op::Seek(entry_id) => self.log.seek(entry_id),
+            Op::Append(length) => self
+                .buffer
+                .take()
+                .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                    buffer[..LOG_ID_SIZE].copy_from_slice(&user.log_id.get().to_le_bytes());
+                    buffer[LOG_ID_SIZE..HEADER_SIZE]
+                        .copy_from_slice(&user.append_timestamp.get().to_le_bytes());
+                    user.buffer.map(|data| {
+                        buffer[HEADER_SIZE..HEADER_SIZE + length].copy_from_slice(&data[..length]);
+                    });
+                    self.log
+                        .append(buffer, HEADER_SIZE + length)
+                        .map_err(|(error, buffer)| {
+                            self.buffer.replace(buffer);
+                            error
+                        })
+                }),
+            Op::Sync => self.log.sync(),
+            Op::Erase => self.log.erase(),
+            Op::Idle => Ok(()),
+        };
+        if result.is_err() {
+            self.inflight.clear();
+        }
+        result
+    }
+
+    /// Reads the next entry of the underlying log into the mux buffer.
+    fn read_next(&self) -> Result<(), ErrorCode> {
+        self.entry_id.set(self.log.next_read_entry_id());
+        self.buffer
+            .take()
+            .map_or(Err(ErrorCode::RESERVE), |buffer| {
+                let length = buffer.len();
+                self.log.read(buffer, length).map_err(|(error, buffer)| {
+                    self.buffer.replace(buffer);
+                    error
+                })
+            })
+    }
+
+    /// Handles an entry read from the underlying log while reading or seeking by timestamp.
+    fn entry_read(&self, user: &'a VirtualLog<'a, L>, length: usize) {
+        let header = self.buffer.map_or(None, |buffer| {
+            if length < HEADER_SIZE {
+                // Not written through the mux.
+                return None;
+            }
+            let log_id = u32::from_le_bytes(buffer[..LOG_ID_SIZE].try_into().ok()?);
+            let timestamp = u64::from_le_bytes(buffer[LOG_ID_SIZE..HEADER_SIZE].try_into().ok()?);
+            Some((log_id, timestamp))
+        });
+
+        match (user.operation.get(), header) {
+            (Op::Read(max_length), Some((log_id, timestamp)))
+                if user.accepts(log_id, timestamp, true) =>
+            {
+                let data_length = length - HEADER_SIZE;
+                if data_length > max_length {
+                    // Leave the entry to be read with a larger buffer.
+                    user.read_entry_id.set(self.entry_id.get());
+                    self.complete(user, 0, false, Err(ErrorCode::SIZE));
+                    return;
+                }
+                user.buffer.map(|data| {
+                    self.buffer.map(|buffer| {
+                        data[..data_length].copy_from_slice(&buffer[HEADER_SIZE..length]);
+                    });
+                });
+                user.read_entry_id.set(self.log.next_read_entry_id());
+                user.last_read.set(EntryInfo {
+                    entry_id: self.entry_id.get(),
+                    log_id,
+                    timestamp: (timestamp != NO_TIMESTAMP).then_some(timestamp),
+                });
+                self.complete(user, data_length, false, Ok(()));
+            }
+            (Op::SeekTimestamp(time), Some((log_id, timestamp)))
+                if timestamp != NO_TIMESTAMP
+                    && timestamp >= time
+                    && user.accepts(log_id, timestamp, false) =>
+            {
+                user.read_entry_id.set(self.entry_id.get());
+                self.complete(user, 0, false, Ok(()));
+            }
+            _ => {
+                if let Err(error) = self.read_next() {
+                    self.read_failed(user, error);
+                }
+            }
+        }
+    }
+
+    /// Finishes a read or seek by timestamp that could not read another entry.
+    fn read_failed(&self, user: &'a VirtualLog<'a, L>, error: ErrorCode) {
+        match (user.operation.get(), error) {
+            // Reached the end of the log, don't look at the skipped entries again.
+            (Op::Read(_), ErrorCode::FAIL) => {
+                user.read_entry_id.set(self.log.next_read_entry_id());
+                self.complete(user, 0, false, Err(ErrorCode::FAIL));
+            }
+            // No entry is that new, continue with the entries appended next.
+            (Op::SeekTimestamp(_), ErrorCode::FAIL) => {
+                user.read_entry_id.set(self.log.log_end());
+                self.complete(user, 0, false, Ok(()));
+            }
+            _ => self.complete(user, 0, false, Err(error)),
+        }
+    }
+
+    /// Ends the operation of `user` and calls its client.
+    fn complete(
+        &self,
+        user: &'a VirtualLog<'a, L>,
+        length: usize,
+        records_lost: bool,
+        error: Result<(), ErrorCode>,
+    ) {
+        self.inflight.clear();
+        let operation = user.operation.replace(Op::Idle);
+        match operation {
+            Op::Read(_) => {
+                user.buffer.take().map(|buffer| {
+                    user.read_client
+                        .map(move |client| client.read_done(buffer, length, error));
+                });
+            }
+            Op::Seek(_) | Op::SeekTimestamp(_) => {
+                user.read_client.map(|client| client.seek_done(error));
+            }
+            Op::Append(_) => {
+                user.buffer.take().map(|buffer| {
+                    user.append_client
+                        .map(move |client| client.append_done(buffer, length, records_lost, error));
+                });
+            }
+            Op::Sync => {
+                user.append_client.map(|client| client.sync_done(error));
+            }
+            Op::Erase => {
+                user.append_client.map(|client| client.erase_done(error));
+            }
+            Op::Idle => {}
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogReadClient for MuxLog<'a, L> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: Result<(), ErrorCode>) {
+        self.buffer.replace(buffer);
+        self.inflight.map(|user| match error {
+            Ok(()) => self.entry_read(user, length),
+            Err(error) => self.read_failed(user, error),
+        });
+        self.do_next_op();
+    }
+
+    fn seek_done(&self, error: Result<(), ErrorCode>) {
+        self.inflight.map(|user| match user.operation.get() {
+            Op::Seek(entry_id) => {
+                if error.is_ok() {
+                    user.read_entry_id.set(entry_id);
+                }
+                self.complete(user, 0, false, error);
+            }
+            Op::Read(_) | Op::SeekTimestamp(_) => {
+                if let Err(error) = error.and_then(|()| self.read_next()) {
+                    self.read_failed(user, error);
+                }
+            }
+            _ => {}
+        });
+        self.do_next_op();
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogWriteClient for MuxLog<'a, L> {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        _length: usize,
+        records_lost: bool,
+        error: Result<(), ErrorCode>,
+    ) {
+        self.buffer.replace(buffer);
+        self.inflight.map(|user| {
+            if let Op::Append(length) = user.operation.get() {
+                self.complete(user, length, records_lost, error);
+            }
+        });
+        self.do_next_op();
+    }
+
+    fn sync_done(&self, error: Result<(), ErrorCode>) {
+        self.inflight
+            .map(|user| self.complete(user, 0, false, error));
+        self.do_next_op();
+    }
+
+    fn erase_done(&self, error: Result<(), ErrorCode>) {
+        self.inflight
+            .map(|user| self.complete(user, 0, false, error));
+        self.do_next_op();
+    }
+}
+
+/// A logical log stored within the log of a `MuxLog`.
+///
+/// Appended entries are tagged with the log ID of the virtual log. By default reads only return
+/// the entries with this log ID, which can be changed with `set_filter()`.
+pub struct VirtualLog<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> {
+    mux: &'a MuxLog<'a, L>,
+    next: ListLink<'a, VirtualLog<'a, L>>,
+    /// ID that appended entries are tagged with.
+    log_id: Cell<u32>,
+    /// Source of timestamps for appended entries.
+    timestamp: Option<&'a dyn LogTimestamp>,
+    /// Entries that reads return.
+    filter: Cell<LogFilter>,
+    /// If set, entries of logs these permissions do not allow to read are skipped.
+    permissions: OptionalCell<StoragePermissions>,
+    read_client: OptionalCell<&'a dyn LogReadClient>,
+    append_client: OptionalCell<&'a dyn LogWriteClient>,
+
+    operation: Cell<Op>,
+    /// Client buffer of the current read or append.
+    buffer: TakeCell<'static, [u8]>,
+    /// Entry ID of the next entry to read.
+    read_entry_id: Cell<usize>,
+    /// The entry returned by the last read.
+    last_read: OptionalCell<EntryInfo>,
+    /// Timestamp of the entry being appended.
+    append_timestamp: Cell<u64>,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> ListNode<'a, VirtualLog<'a, L>>
+    for VirtualLog<'a, L>
+{
+    fn next(&'a self) -> &'a ListLink<'a, VirtualLog<'a, L>> {
+        &self.next
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> VirtualLog<'a, L> {
+    pub fn new(
+        mux: &'a MuxLog<'a, L>,
+        log_id: u32,
+        timestamp: Option<&'a dyn LogTimestamp>,
+    ) -> VirtualLog<'a, L> {
+        VirtualLog {
+            mux,
+            next: ListLink::empty(),
+            log_id: Cell::new(log_id),
+            timestamp,
+            filter: Cell::new(LogFilter {
+                log_id: Some(log_id),
+                time: None,
+            }),
+            permissions: OptionalCell::empty(),
+            read_client: OptionalCell::empty(),
+            append_client: OptionalCell::empty(),
+            operation: Cell::new(Op::Idle),
+            buffer: TakeCell::empty(),
+            read_entry_id: Cell::new(0),
+            last_read: OptionalCell::empty(),
+            append_timestamp: Cell::new(NO_TIMESTAMP),
+        }
+    }
+
+    /// Registers the virtual log with its mux.
+    pub fn setup(&'a self) {
+        self.mux.users.push_head(self);
+    }
+
+    /// Returns the ID that appended entries are tagged with.
+    pub fn log_id(&self) -> u32 {
+        self.log_id.get()
+    }
+
+    /// Sets the ID that appended entries are tagged with. This does not change the filter.
+    pub fn set_log_id(&self, log_id: u32) {
+        self.log_id.set(log_id);
+    }
+
+    /// Returns the filter for the entries that reads return.
+    pub fn filter(&self) -> LogFilter {
+        self.filter.get()
+    }
+
+    /// Sets the filter for the entries that reads and seeks by timestamp return. Seeking by
+    /// timestamp ignores the time range of the filter.
+    pub fn set_filter(&self, filter: LogFilter) {
+        self.filter.set(filter);
+    }
+
+    /// Only return entries of logs whose ID `permissions` allow reading, in addition to the
+    /// filter. `None` removes the restriction.
+    pub fn set_read_permissions(&self, permissions: Option<StoragePermissions>) {
+        self.permissions.insert(permissions);
+    }
+
+    /// Sets the read position without validating it or waiting for a callback. This allows
+    /// several readers to share one virtual log by keeping their own read position. Positions
+    /// that are no longer within the log continue reading with the oldest entry.
+    pub fn set_read_position(&self, entry_id: usize) {
+        self.read_entry_id.set(entry_id);
+    }
+
+    /// Returns the entry returned by the last successful read.
+    pub fn last_read(&self) -> Option<EntryInfo> {
+        self.last_read.get()
+    }
+
+    /// Maximum size of the entries that can be appended.
+    pub fn max_entry_size(&self) -> usize {
+        self.mux.buffer_size.saturating_sub(HEADER_SIZE)
+    }
+
+    /// Seek to the oldest entry matching the filter with a timestamp at or after `time`, or to
+    /// the end of the log if there is none. `seek_done` is called when done.
+    pub fn seek_timestamp(&self, time: u64) -> Result<(), ErrorCode> {
+        if self.operation.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        } else if self.read_client.is_none() {
+            return Err(ErrorCode::RESERVE);
+        }
+        self.operation.set(Op::SeekTimestamp(time));
+        self.mux.do_op(self).inspect_err(|_| {
+            self.operation.set(Op::Idle);
+        })
+    }
+
+    /// Whether reads return an entry of log `log_id` with timestamp `timestamp`.
+    fn accepts(&self, log_id: u32, timestamp: u64, check_time: bool) -> bool {
+        let filter = self.filter.get();
+        self.permissions.map_or(true, |permissions| {
+            permissions.check_read_permission(log_id)
+        }) && filter.log_id.map_or(true, |id| id == log_id)
+            && (!check_time
+                || filter
+                    .time
+                    .map_or(true, |(start, end)| timestamp >= start && timestamp < end))
+    }
+
+    /// Starts an operation that takes a client buffer.
+    fn buffer_op(
+        &self,
+        operation: Op,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.operation.set(operation);
+        self.buffer.replace(buffer);
+        self.mux.do_op(self).map_err(|error| {
+            self.operation.set(Op::Idle);
+            (error, self.buffer.take().unwrap())
+        })
+    }
+
+    /// Starts an operation without a client buffer.
+    fn op(&self, operation: Op) -> Result<(), ErrorCode> {
+        if self.operation.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        } else if self.append_client.is_none() {
+            return Err(ErrorCode::RESERVE);
+        }
+        self.operation.set(operation);
+        self.mux.do_op(self).inspect_err(|_| {
+            self.operation.set(Op::Idle);
+        })
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogRead<'a> for VirtualLog<'a, L> {
+    type EntryID = usize;
+
+    fn set_read_client(&'a self, read_client: &'a dyn LogReadClient) {
+        self.read_client.set(read_client);
+    }
+
+    /// Read the next entry matching the filter. Entries that don't match are skipped.
+    /// `ErrorCode`s used:
+    ///     * `FAIL`: reached end of log, nothing to read.
+    ///     * `BUSY`: virtual log busy with another operation.
+    ///     * `INVAL`: provided client buffer is too small.
+    ///     * `RESERVE`: no read client set.
+    ///     * `SIZE`: buffer not large enough to contain the entry being read.
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.operation.get() != Op::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        } else if buffer.len() < length {
+            return Err((ErrorCode::INVAL, buffer));
+        } else if self.read_client.is_none() {
+            return Err((ErrorCode::RESERVE, buffer));
+        }
+        self.buffer_op(Op::Read(length), buffer)
+    }
+
+    fn log_start(&self) -> Self::EntryID {
+        self.mux.log.log_start()
+    }
+
+    fn log_end(&self) -> Self::EntryID {
+        self.mux.log.log_end()
+    }
+
+    fn next_read_entry_id(&self) -> Self::EntryID {
+        self.read_entry_id.get()
+    }
+
+    fn seek(&self, entry_id: Self::EntryID) -> Result<(), ErrorCode> {
+        if self.operation.get() != Op::Idle {
+            return Err(ErrorCode::BUSY);
+        } else if entry_id < self.log_start() || entry_id > self.log_end() {
+            return Err(ErrorCode::INVAL);
+        } else if self.read_client.is_none() {
+            return Err(ErrorCode::RESERVE);
+        }
+        self.operation.set(Op::Seek(entry_id));
+        self.mux.do_op(self).inspect_err(|_| {
+            self.operation.set(Op::Idle);
+        })
+    }
+
+    /// Capacity of the underlying log, which is shared by all virtual logs.
+    fn get_size(&self) -> usize {
+        self.mux.log.get_size()
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogWrite<'a> for VirtualLog<'a, L> {
+    fn set_append_client(&'a self, append_client: &'a dyn LogWriteClient) {
+        self.append_client.set(append_client);
+    }
+
+    /// Append an entry tagged with the log ID and the current time.
+    /// `ErrorCode`s used:
+    ///     * `BUSY`: virtual log busy with another operation.
+    ///     * `INVAL`: provided client buffer is too small.
+    ///     * `SIZE`: entry too large for the mux buffer.
+    ///     * `RESERVE`: no append client set.
+    fn append(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.operation.get() != Op::Idle {
+            return Err((ErrorCode::BUSY, buffer));
+        } else if buffer.len() < length {
+            return Err((ErrorCode::INVAL, buffer));
+        } else if length > self.max_entry_size() {
+            return Err((ErrorCode::SIZE, buffer));
+        } else if self.append_client.is_none() {
+            return Err((ErrorCode::RESERVE, buffer));
+        }
+        self.append_timestamp.set(
+            self.timestamp
+                .and_then(|timestamp| timestamp.timestamp())
+                .unwrap_or(NO_TIMESTAMP),
+        );
+        self.buffer_op(Op::Append(length), buffer)
+    }
+
+    /// Sync the underlying log, including the entries of all other virtual logs.
+    fn sync(&self) -> Result<(), ErrorCode> {
+        self.op(Op::Sync)
+    }
+
+    /// Erase the underlying log. This erases the entries of all virtual logs.
+    fn erase(&self) -> Result<(), ErrorCode> {
+        self.op(Op::Erase)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use kernel::hil::date_time::{DayOfWeek, Month};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    /// A callback of the fake log that is made when it is serviced.
+    enum Done {
+        Read(&'static mut [u8], usize),
+        Seek,
+        Append(&'static mut [u8], usize),
+    }
+
+    /// An in-memory log whose entry IDs are the indexes of the entries.
+    struct RamLog {
+        entries: RefCell<Vec<Vec<u8>>>,
+        read_entry_id: Cell<usize>,
+        pending: RefCell<Option<Done>>,
+        read_client: OptionalCell<&'static dyn LogReadClient>,
+        append_client: OptionalCell<&'static dyn LogWriteClient>,
+    }
+
+    impl RamLog {
+        /// Makes the pending callback, if any.
+        fn service(&self) -> bool {
+            let Some(done) = self.pending.take() else {
+                return false;
+            };
+            match done {
+                Done::Read(buffer, length) => self
+                    .read_client
+                    .map(|client| client.read_done(buffer, length, Ok(()))),
+                Done::Seek => self.read_client.map(|client| client.seek_done(Ok(()))),
+                Done::Append(buffer, length) => self
+                    .append_client
+                    .map(|client| client.append_done(buffer, length, false, Ok(()))),
+            };
+            true
+        }
+    }
+
+    impl LogRead<'static> for RamLog {
+        type EntryID = usize;
+
+        fn set_read_client(&self, read_client: &'static dyn LogReadClient) {
+            self.read_client.set(read_client);
+        }
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            let entries = self.entries.borrow();
+            let Some(entry) = entries.get(self.read_entry_id.get()) else {
+                return Err((ErrorCode::FAIL, buffer));
+            };
+            if entry.len() > length {
+                return Err((ErrorCode::SIZE, buffer));
+            }
+            buffer[..entry.len()].copy_from_slice(entry);
+            self.read_entry_id.set(self.read_entry_id.get() + 1);
+            self.pending.replace(Some(Done::Read(buffer, entry.len())));
+            Ok(())
+        }
+
+        fn log_start(&self) -> usize {
+            0
+        }
+
+        fn log_end(&self) -> usize {
+            self.entries.borrow().len()
+        }
+
+        fn next_read_entry_id(&self) -> usize {
+            self.read_entry_id.get()
+        }
+
+        fn seek(&self, entry_id: usize) -> Result<(), ErrorCode> {
+            self.read_entry_id.set(entry_id);
+            self.pending.replace(Some(Done::Seek));
+            Ok(())
+        }
+
+        fn get_size(&self) -> usize {
+            4096
+        }
+    }
+
+    impl LogWrite<'static> for RamLog {
+        fn set_append_client(&self, append_client: &'static dyn LogWriteClient) {
+            self.append_client.set(append_client);
+        }
+
+        fn append(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+        ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+            self.entries.borrow_mut().push(buffer[..length].to_vec());
+            self.pending.replace(Some(Done::Append(buffer, length)));
+            Ok(())
+        }
+
+        fn sync(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+
+        fn erase(&self) -> Result<(), ErrorCode> {
+            Err(ErrorCode::NOSUPPORT)
+        }
+    }
+
+    struct Clock(Cell<u64>);
+
+    impl LogTimestamp for Clock {
+        fn timestamp(&self) -> Option<u64> {
+            Some(self.0.get())
+        }
+    }
+
+    struct Client {
+        buffer: TakeCell<'static, [u8]>,
+        read: RefCell<Option<Result<Vec<u8>, ErrorCode>>>,
+        seek: Cell<Option<Result<(), ErrorCode>>>,
+    }
+
+    impl LogReadClient for Client {
+        fn read_done(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+            error: Result<(), ErrorCode>,
+        ) {
+            self.read
+                .replace(Some(error.map(|()| buffer[..length].to_vec())));
+            self.buffer.replace(buffer);
+        }
+
+        fn seek_done(&self, error: Result<(), ErrorCode>) {
+            self.seek.set(Some(error));
+        }
+    }
+
+    impl LogWriteClient for Client {
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            _length: usize,
+            _records_lost: bool,
+            error: Result<(), ErrorCode>,
+        ) {
+            assert_eq!(error, Ok(()));
+            self.buffer.replace(buffer);
+        }
+
+        fn sync_done(&self, _error: Result<(), ErrorCode>) {}
+
+        fn erase_done(&self, _error: Result<(), ErrorCode>) {}
+    }
+
+    type TestLog = VirtualLog<'static, RamLog>;
+
+    fn mux() -> (&'static RamLog, &'static MuxLog<'static, RamLog>) {
+        let log = Box::leak(Box::new(RamLog {
+            entries: RefCell::new(Vec::new()),
+            read_entry_id: Cell::new(0),
+            pending: RefCell::new(None),
+            read_client: OptionalCell::empty(),
+            append_client: OptionalCell::empty(),
+        }));
+        let mux = Box::leak(Box::new(MuxLog::new(log, Box::leak(Box::new([0; 64])))));
+        log.set_read_client(mux);
+        log.set_append_client(mux);
+        (log, mux)
+    }
+
+    fn virtual_log(
+        mux: &'static MuxLog<'static, RamLog>,
+        log_id: u32,
+        clock: Option<&'static Clock>,
+    ) -> (&'static TestLog, &'static Client) {
+        let clock = clock.map(|clock| clock as &dyn LogTimestamp);
+        let log = Box::leak(Box::new(VirtualLog::new(mux, log_id, clock)));
+        let client = Box::leak(Box::new(Client {
+            buffer: TakeCell::new(Box::leak(Box::new([0; 32]))),
+            read: RefCell::new(None),
+            seek: Cell::new(None),
+        }));
+        log.setup();
+        log.set_read_client(client);
+        log.set_append_client(client);
+        (log, client)
+    }
+
+    fn run(log: &RamLog) {
+        while log.service() {}
+    }
+
+    fn append(log: &TestLog, client: &Client, data: &[u8]) {
+        let buffer = client.buffer.take().unwrap();
+        buffer[..data.len()].copy_from_slice(data);
+        assert!(log.append(buffer, data.len()).is_ok());
+    }
+
+    fn read(ram_log: &RamLog, log: &TestLog, client: &Client) -> Result<Vec<u8>, ErrorCode> {
+        let buffer = client.buffer.take().unwrap();
+        let length = buffer.len();
+        if let Err((error, buffer)) = log.read(buffer, length) {
+            client.buffer.replace(buffer);
+            return Err(error);
+        }
+        run(ram_log);
+        client.read.take().unwrap()
+    }
+
+    fn seek_timestamp(ram_log: &RamLog, log: &TestLog, client: &Client, time: u64) {
+        assert_eq!(log.seek_timestamp(time), Ok(()));
+        run(ram_log);
+        assert_eq!(client.seek.take(), Some(Ok(())));
+    }
+
+    #[test]
+    fn test_logs_are_separate() {
+        let (ram_log, mux) = mux();
+        let (log_a, client_a) = virtual_log(mux, 1, None);
+        let (log_b, client_b) = virtual_log(mux, 2, None);
+
+        // The append of `log_b` waits for the one of `log_a`.
+        append(log_a, client_a, b"a0");
+        append(log_b, client_b, b"b0");
+        run(ram_log);
+        append(log_a, client_a, b"a1");
+        run(ram_log);
+        assert_eq!(ram_log.entries.borrow().len(), 3);
+        assert_eq!(ram_log.entries.borrow()[1][..HEADER_SIZE], {
+            let mut header = [0xFF; HEADER_SIZE];
+            header[..LOG_ID_SIZE].copy_from_slice(&2u32.to_le_bytes());
+            header
+        });
+
+        assert_eq!(read(ram_log, log_a, client_a), Ok(b"a0".to_vec()));
+        assert_eq!(read(ram_log, log_b, client_b), Ok(b"b0".to_vec()));
+        assert_eq!(read(ram_log, log_a, client_a), Ok(b"a1".to_vec()));
+        assert_eq!(
+            log_a.last_read(),
+            Some(EntryInfo {
+                entry_id: 2,
+                log_id: 1,
+                timestamp: None,
+            })
+        );
+        assert_eq!(read(ram_log, log_a, client_a), Err(ErrorCode::FAIL));
+        assert_eq!(read(ram_log, log_b, client_b), Err(ErrorCode::FAIL));
+        assert_eq!(log_b.next_read_entry_id(), 3);
+
+        // Reading every log returns the entries of both.
+        log_b.set_read_position(0);
+        log_b.set_filter(LogFilter::default());
+        assert_eq!(read(ram_log, log_b, client_b), Ok(b"a0".to_vec()));
+        assert_eq!(read(ram_log, log_b, client_b), Ok(b"b0".to_vec()));
+    }
+
+    #[test]
+    fn test_timestamp_filter_and_seek() {
+        let (ram_log, mux) = mux();
+        let clock: &'static Clock = Box::leak(Box::new(Clock(Cell::new(0))));
+        let (log_a, client_a) = virtual_log(mux, 1, Some(clock));
+        let (log_b, client_b) = virtual_log(mux, 2, Some(clock));
+        let (log_c, client_c) = virtual_log(mux, 3, None);
+
+        for (time, log, client) in [
+            (10, log_a, client_a),
+            (15, log_b, client_b),
+            (20, log_a, client_a),
+            (25, log_b, client_b),
+            (30, log_a, client_a),
+            (35, log_c, client_c),
+            (40, log_a, client_a),
+        ] {
+            clock.0.set(time);
+            append(log, client, &time.to_le_bytes());
+            run(ram_log);
+        }
+
+        log_a.set_filter(LogFilter {
+            log_id: Some(1),
+            time: Some((20, 40)),
+        });
+        assert_eq!(
+            read(ram_log, log_a, client_a),
+            Ok(20u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(log_a.last_read().unwrap().timestamp, Some(20));
+        assert_eq!(
+            read(ram_log, log_a, client_a),
+            Ok(30u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(read(ram_log, log_a, client_a), Err(ErrorCode::FAIL));
+
+        // Seeking only stops at entries of the filtered log.
+        seek_timestamp(ram_log, log_a, client_a, 25);
+        assert_eq!(log_a.next_read_entry_id(), 4);
+        log_a.set_filter(LogFilter::default());
+        seek_timestamp(ram_log, log_a, client_a, 25);
+        assert_eq!(log_a.next_read_entry_id(), 3);
+        seek_timestamp(ram_log, log_a, client_a, 41);
+        assert_eq!(log_a.next_read_entry_id(), 7);
+
+        // Entries without a timestamp never match a time range.
+        log_c.set_filter(LogFilter {
+            log_id: None,
+            time: Some((30, 40)),
+        });
+        assert_eq!(
+            read(ram_log, log_c, client_c),
+            Ok(30u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(read(ram_log, log_c, client_c), Err(ErrorCode::FAIL));
+    }
+
+    #[test]
+    fn test_unix_time() {
+        let date_time = |year, month, day, hour, minute, seconds| DateTimeValues {
+            year,
+            month,
+            day,
+            day_of_week: DayOfWeek::Monday,
+            hour,
+            minute,
+            seconds,
+        };
+        assert_eq!(
+            unix_time_ms(&date_time(1970, Month::January, 1, 0, 0, 0)),
+            Some(0)
+        );
+        assert_eq!(
+            unix_time_ms(&date_time(2000, Month::March, 1, 0, 0, 0)),
+            Some(951_868_800_000)
+        );
+        assert_eq!(
+            unix_time_ms(&date_time(2024, Month::February, 29, 12, 34, 56)),
+            Some(1_709_210_096_000)
+        );
+        assert_eq!(
+            unix_time_ms(&date_time(2100, Month::December, 31, 23, 59, 59)),
+            Some(4_133_980_799_000)
+        );
+        assert_eq!(
+            unix_time_ms(&date_time(1969, Month::December, 31, 23, 59, 59)),
+            None
+        );
+    }
+}
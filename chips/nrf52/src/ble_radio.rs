@@ -36,12 +36,22 @@
 //! * Payload - 2 to 255 bytes
 //!
 //! * CRC - 3 bytes
+//!
+//! ### Connections
+//!
+//! Besides the advertising interface, the radio implements
+//! `ble_connection::BleConnectionRadio`. There the radio uses shortcuts to
+//! switch from receiving to transmitting (and back) T_IFS after a packet
+//! ended, and the END interrupt only has to swap the packet pointer and the
+//! shortcuts and ask the client for the response. Received packets stay in
+//! `PAYLOAD`, responses are sent from `LINK_PAYLOAD`.
 
 use core::cell::Cell;
 use core::ptr::addr_of;
 use core::ptr::addr_of_mut;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_connection;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::cells::TakeCell;
 use kernel::utilities::registers::interfaces::{Readable, Writeable};
@@ -532,8 +542,24 @@ register_bitfields! [u32,
     ]
 ];
 
-static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
-    [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+static mut PAYLOAD: [u8; ble_connection::MAX_PDU_LEN] = [0x00; ble_connection::MAX_PDU_LEN];
+
+static mut LINK_PAYLOAD: [u8; ble_connection::MAX_PDU_LEN] = [0x00; ble_connection::MAX_PDU_LEN];
+
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.1 Inter Frame Space
+const T_IFS_US: u32 = 150;
+
+/// What the radio does for `ble_connection::BleConnectionRadio`.
+#[derive(Copy, Clone, PartialEq)]
+enum LinkMode {
+    /// Not used for a connection, interrupts are for the advertising
+    /// interface.
+    Off,
+    /// Transmitting a PDU from `LINK_PAYLOAD`, then listening.
+    Transmitting,
+    /// Receiving a PDU into `PAYLOAD`, then answering it.
+    Receiving,
+}
 
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
@@ -541,6 +567,8 @@ pub struct Radio<'a> {
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
     buffer: TakeCell<'static, [u8]>,
+    connection_client: OptionalCell<&'a dyn ble_connection::ConnectionClient>,
+    link_mode: Cell<LinkMode>,
 }
 
 impl<'a> Radio<'a> {
@@ -551,6 +579,8 @@ impl<'a> Radio<'a> {
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            connection_client: OptionalCell::empty(),
+            link_mode: Cell::new(LinkMode::Off),
         }
     }
 
@@ -602,6 +632,11 @@ impl<'a> Radio<'a> {
     pub fn handle_interrupt(&self) {
         self.disable_all_interrupts();
 
+        if self.link_mode.get() != LinkMode::Off {
+            self.handle_link_interrupt();
+            return;
+        }
+
         if self.registers.event_ready.is_set(Event::READY) {
             self.registers.event_ready.write(Event::READY::CLEAR);
             self.registers.event_end.write(Event::READY::CLEAR);
@@ -659,6 +694,113 @@ impl<'a> Radio<'a> {
         self.enable_interrupts();
     }
 
+    fn handle_link_interrupt(&self) {
+        self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_address.write(Event::READY::CLEAR);
+        self.registers.event_payload.write(Event::READY::CLEAR);
+        if !self.registers.event_end.is_set(Event::READY) {
+            self.registers.intenset.write(Interrupt::END::SET);
+            return;
+        }
+        self.registers.event_end.write(Event::READY::CLEAR);
+
+        match self.link_mode.get() {
+            LinkMode::Transmitting => {
+                // The shortcuts already switch the radio to receive, which
+                // reads the packet pointer only once it has ramped up.
+                self.registers.packetptr.set(addr_of!(PAYLOAD) as u32);
+                self.registers.shorts.write(
+                    Shortcut::READY_START::SET
+                        + Shortcut::END_DISABLE::SET
+                        + Shortcut::DISABLED_TXEN::SET,
+                );
+                self.link_mode.set(LinkMode::Receiving);
+                self.registers.intenset.write(Interrupt::END::SET);
+                self.connection_client
+                    .map(|client| client.pdu_transmitted());
+            }
+            LinkMode::Receiving => {
+                let crc_ok = self.registers.crcstatus.is_set(Event::READY);
+                self.registers.packetptr.set(addr_of!(LINK_PAYLOAD) as u32);
+                self.registers.shorts.write(
+                    Shortcut::READY_START::SET
+                        + Shortcut::END_DISABLE::SET
+                        + Shortcut::DISABLED_RXEN::SET,
+                );
+                let response_len = unsafe {
+                    let received = &*addr_of!(PAYLOAD);
+                    let len = (2 + received[1] as usize).min(received.len());
+                    self.connection_client.map_or(None, |client| {
+                        client.pdu_received(
+                            &received[..len],
+                            crc_ok,
+                            &mut *addr_of_mut!(LINK_PAYLOAD),
+                        )
+                    })
+                };
+                if self.link_mode.get() == LinkMode::Off {
+                    // The client stopped the radio.
+                } else if response_len.is_some() {
+                    self.link_mode.set(LinkMode::Transmitting);
+                    self.registers.intenset.write(Interrupt::END::SET);
+                } else {
+                    // Abort the transmission the shortcuts already started
+                    // and listen for the next PDU instead.
+                    self.registers
+                        .shorts
+                        .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                    self.registers.event_disabled.write(Event::READY::CLEAR);
+                    self.registers.task_disable.write(Task::ENABLE::SET);
+                    while !self.registers.event_disabled.is_set(Event::READY) {}
+                    self.registers.event_disabled.write(Event::READY::CLEAR);
+                    self.registers.packetptr.set(addr_of!(PAYLOAD) as u32);
+                    self.registers.shorts.write(
+                        Shortcut::READY_START::SET
+                            + Shortcut::END_DISABLE::SET
+                            + Shortcut::DISABLED_TXEN::SET,
+                    );
+                    self.registers.task_rxen.write(Task::ENABLE::SET);
+                    self.registers.intenset.write(Interrupt::END::SET);
+                }
+            }
+            LinkMode::Off => {}
+        }
+    }
+
+    fn link_stop(&self) {
+        self.link_mode.set(LinkMode::Off);
+        self.registers.shorts.set(0);
+        self.disable_all_interrupts();
+        self.registers.task_disable.write(Task::ENABLE::SET);
+        self.radio_off();
+    }
+
+    /// Configure the radio for a connection or connectable advertising
+    /// packets on `channel`.
+    fn link_initialize(&self, channel: RadioChannel, access_address: u32, crc_init: u32) {
+        self.radio_on();
+        self.ble_set_tx_power();
+        self.ble_set_channel_rate();
+        self.ble_set_channel_freq(channel);
+        self.ble_set_data_whitening(channel);
+        self.set_tx_address();
+        self.set_rx_address();
+        self.ble_set_packet_config();
+
+        // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
+        // The most significant byte is the prefix, the other three the base.
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
+
+        self.ble_set_crc_config();
+        self.registers.crcinit.set(crc_init & 0x00ff_ffff);
+        self.registers.tifs.set(T_IFS_US);
+
+        self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_end.write(Event::READY::CLEAR);
+        self.registers.event_disabled.write(Event::READY::CLEAR);
+    }
+
     pub fn enable_interrupts(&self) {
         self.registers.intenset.write(
             Interrupt::READY::SET
@@ -818,6 +960,65 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 }
 
+impl<'a> ble_connection::BleConnectionRadio<'a> for Radio<'a> {
+    fn set_connection_client(&self, client: &'a dyn ble_connection::ConnectionClient) {
+        self.connection_client.set(client);
+    }
+
+    fn transmit(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+        pdu: &[u8],
+    ) -> Result<(), ErrorCode> {
+        if pdu.len() < 2 || pdu.len() > ble_connection::MAX_PDU_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        if self.link_mode.get() != LinkMode::Off {
+            self.link_stop();
+        }
+        unsafe {
+            (*addr_of_mut!(LINK_PAYLOAD))[..pdu.len()].copy_from_slice(pdu);
+        }
+        self.link_initialize(channel, access_address, crc_init);
+        self.registers.packetptr.set(addr_of!(LINK_PAYLOAD) as u32);
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_RXEN::SET,
+        );
+        self.link_mode.set(LinkMode::Transmitting);
+        self.registers.intenset.write(Interrupt::END::SET);
+        self.registers.task_txen.write(Task::ENABLE::SET);
+        Ok(())
+    }
+
+    fn listen(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+    ) -> Result<(), ErrorCode> {
+        if self.link_mode.get() != LinkMode::Off {
+            self.link_stop();
+        }
+        self.link_initialize(channel, access_address, crc_init);
+        self.registers.packetptr.set(addr_of!(PAYLOAD) as u32);
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_TXEN::SET,
+        );
+        self.link_mode.set(LinkMode::Receiving);
+        self.registers.intenset.write(Interrupt::END::SET);
+        self.registers.task_rxen.write(Task::ENABLE::SET);
+        Ok(())
+    }
+
+    fn stop(&self) {
+        if self.link_mode.get() != LinkMode::Off {
+            self.link_stop();
+        }
+    }
+}
+
 impl ble_advertising::BleConfig for Radio<'_> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
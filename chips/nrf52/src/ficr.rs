@@ -337,6 +337,18 @@ impl Ficr {
         }
     }
 
+    /// Returns if this part restricts flash accesses with the ACL
+    /// peripheral. The nRF52832 uses the older BPROT peripheral instead.
+    pub(crate) fn has_flash_acl(&self) -> bool {
+        self.part() != Part::N52832
+    }
+
+    /// Size of the code flash in bytes.
+    pub(crate) fn code_size(&self) -> usize {
+        self.registers.codepagesize.read(CodePageSize::CODEPAGESIZE) as usize
+            * self.registers.codesize.read(CodeSize::CODESIZE) as usize
+    }
+
     pub(crate) fn variant(&self) -> Variant {
         // If you update this, make sure to update
         // `has_updated_approtect_logic()` as well.
@@ -5,6 +5,11 @@
 //! Non-Volatile Memory Controller
 //!
 //! Used in order read and write to internal flash.
+//!
+//! Also implements `hil::flash::FlashProtection` to lock regions of flash
+//! until the next reset. The nRF52833 and nRF52840 use the ACL peripheral for
+//! this, which supports 8 regions. The nRF52832 uses the BPROT peripheral,
+//! which has one bit for every 4 kB block of flash.
 
 use core::cell::Cell;
 use core::ops::{Index, IndexMut};
@@ -18,9 +23,17 @@ use kernel::utilities::registers::{register_bitfields, ReadOnly, ReadWrite};
 use kernel::utilities::StaticRef;
 use kernel::ErrorCode;
 
+use crate::ficr;
+
 const NVMC_BASE: StaticRef<NvmcRegisters> =
     unsafe { StaticRef::new(0x4001E400 as *const NvmcRegisters) };
 
+const ACL_BASE: StaticRef<AclRegisters> =
+    unsafe { StaticRef::new(0x4001E000 as *const AclRegisters) };
+
+const BPROT_BASE: StaticRef<BprotRegisters> =
+    unsafe { StaticRef::new(0x40000000 as *const BprotRegisters) };
+
 #[repr(C)]
 struct NvmcRegisters {
     /// Ready flag
@@ -60,7 +73,79 @@ struct NvmcRegisters {
     pub imiss: ReadWrite<u32, CacheMiss::Register>,
 }
 
+/// Access control list, on the nRF52833 and nRF52840.
+///
+/// Section 6.1 of <https://infocenter.nordicsemi.com/pdf/nRF52840_PS_v1.1.pdf>
+#[repr(C)]
+struct AclRegisters {
+    /// Reserved
+    _reserved0: [u32; 512],
+    /// Access control regions
+    /// Address: 0x800 - 0x880
+    acl: [AclRegion; ACL_REGIONS],
+}
+
+#[repr(C)]
+struct AclRegion {
+    /// Start address of the region, must be page aligned. Can only be
+    /// written once per reset.
+    addr: ReadWrite<u32>,
+    /// Size of the region in bytes, must be a multiple of the page size. The
+    /// region is disabled while the size is 0.
+    size: ReadWrite<u32>,
+    /// Access permissions of the region.
+    perm: ReadWrite<u32, AclPermissions::Register>,
+    _reserved: u32,
+}
+
+/// Block protection, on the nRF52832.
+///
+/// Section 17 of <https://infocenter.nordicsemi.com/pdf/nRF52832_PS_v1.4.pdf>
+#[repr(C)]
+struct BprotRegisters {
+    /// Reserved
+    _reserved0: [u32; 384],
+    /// Protection bits for blocks 0 to 63
+    /// Address: 0x600 - 0x608
+    config0_1: [ReadWrite<u32>; 2],
+    /// Keep the protection when a debugger is connected
+    /// Address: 0x608 - 0x60C
+    disableindebug: ReadWrite<u32, DisableInDebug::Register>,
+    _reserved1: u32,
+    /// Protection bits for blocks 64 to 127
+    /// Address: 0x610 - 0x618
+    config2_3: [ReadWrite<u32>; 2],
+}
+
+impl BprotRegisters {
+    /// The configuration register with the protection bit of `block`.
+    fn config(&self, block: usize) -> &ReadWrite<u32> {
+        match block / 32 {
+            n @ (0 | 1) => &self.config0_1[n],
+            n => &self.config2_3[n - 2],
+        }
+    }
+}
+
 register_bitfields! [u32,
+    AclPermissions [
+        /// Block writes and erases of the region
+        WRITE OFFSET(1) NUMBITS(1) [
+            Enable = 0,
+            Disable = 1
+        ],
+        /// Block reads of the region
+        READ OFFSET(2) NUMBITS(1) [
+            Enable = 0,
+            Disable = 1
+        ]
+    ],
+    DisableInDebug [
+        DISABLEINDEBUG OFFSET(0) NUMBITS(1) [
+            Enabled = 0,
+            Disabled = 1
+        ]
+    ],
     /// Ready flag
     Ready [
         /// NVMC is ready or busy
@@ -145,6 +230,12 @@ register_bitfields! [u32,
 
 const PAGE_SIZE: usize = 4096;
 
+/// Number of regions of the ACL peripheral.
+const ACL_REGIONS: usize = 8;
+
+/// Number of 4 kB blocks the BPROT peripheral can protect.
+const BPROT_BLOCKS: usize = 128;
+
 /// This is a wrapper around a u8 array that is sized to a single page for the
 /// nrf. Users of this module must pass an object of this type to use the
 /// `hil::flash::Flash` interface.
@@ -202,6 +293,8 @@ pub enum FlashState {
 
 pub struct Nvmc {
     registers: StaticRef<NvmcRegisters>,
+    acl: StaticRef<AclRegisters>,
+    bprot: StaticRef<BprotRegisters>,
     client: OptionalCell<&'static dyn hil::flash::Client<Nvmc>>,
     buffer: TakeCell<'static, NrfPage>,
     state: Cell<FlashState>,
@@ -212,6 +305,8 @@ impl Nvmc {
     pub fn new() -> Self {
         Self {
             registers: NVMC_BASE,
+            acl: ACL_BASE,
+            bprot: BPROT_BASE,
             client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
             state: Cell::new(FlashState::Ready),
@@ -391,6 +486,104 @@ impl hil::flash::Flash for Nvmc {
     }
 }
 
+impl hil::flash::FlashProtection for Nvmc {
+    fn protect(
+        &self,
+        start: usize,
+        length: usize,
+        lifetime: hil::flash::ProtectionLifetime,
+    ) -> Result<core::ops::Range<usize>, ErrorCode> {
+        // Both ACL and BPROT are cleared by a reset.
+        if lifetime != hil::flash::ProtectionLifetime::UntilReset {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let end = start.checked_add(length).ok_or(ErrorCode::INVAL)?;
+        let factory_config = ficr::Ficr::new();
+        if end > factory_config.code_size() {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Only lock the pages fully inside of the range.
+        let first_page = start.div_ceil(PAGE_SIZE);
+        let last_page = end / PAGE_SIZE;
+        if first_page >= last_page {
+            return Err(ErrorCode::INVAL);
+        }
+        let locked = first_page * PAGE_SIZE..last_page * PAGE_SIZE;
+
+        if factory_config.has_flash_acl() {
+            // Regions can only be configured once, so use the first one that
+            // is still disabled.
+            let region = self
+                .acl
+                .acl
+                .iter()
+                .find(|region| region.size.get() == 0)
+                .ok_or(ErrorCode::NOMEM)?;
+            region.addr.set(locked.start as u32);
+            region.size.set(locked.len() as u32);
+            region.perm.write(AclPermissions::WRITE::Disable);
+
+            if region.size.get() != locked.len() as u32 {
+                return Err(ErrorCode::FAIL);
+            }
+        } else {
+            if last_page > BPROT_BLOCKS {
+                return Err(ErrorCode::INVAL);
+            }
+            // By default BPROT is disabled while a debugger is connected.
+            self.bprot
+                .disableindebug
+                .write(DisableInDebug::DISABLEINDEBUG::Enabled);
+            // Writing 0 to a protection bit has no effect, so the other
+            // blocks keep their protection.
+            for block in first_page..last_page {
+                self.bprot.config(block).set(1 << (block % 32));
+            }
+        }
+
+        Ok(locked)
+    }
+
+    fn is_protected(&self, start: usize, length: usize) -> bool {
+        let end = match start.checked_add(length) {
+            Some(end) => end,
+            None => return false,
+        };
+        let factory_config = ficr::Ficr::new();
+        if end > factory_config.code_size() {
+            return false;
+        }
+
+        // Every page overlapping with the range has to be locked.
+        let first_page = start / PAGE_SIZE;
+        let last_page = end.div_ceil(PAGE_SIZE);
+        if factory_config.has_flash_acl() {
+            (first_page..last_page).all(|page| {
+                let address = (page * PAGE_SIZE) as u32;
+                self.acl.acl.iter().any(|region| {
+                    region.perm.is_set(AclPermissions::WRITE)
+                        && address >= region.addr.get()
+                        && address - region.addr.get() < region.size.get()
+                })
+            })
+        } else {
+            last_page <= BPROT_BLOCKS
+                && (first_page..last_page)
+                    .all(|block| self.bprot.config(block).get() & (1 << (block % 32)) != 0)
+        }
+    }
+
+    fn protection_block(&self, address: usize) -> Option<core::ops::Range<usize>> {
+        // ACL regions and BPROT blocks both cover whole pages.
+        if address >= ficr::Ficr::new().code_size() {
+            return None;
+        }
+        let start = address - address % PAGE_SIZE;
+        Some(start..start + PAGE_SIZE)
+    }
+}
+
 impl DeferredCallClient for Nvmc {
     fn handle_deferred_call(&self) {
         self.handle_interrupt();
@@ -9,6 +9,7 @@
 //! # Features
 //!
 //! - [x] Configuring latency based on the system clock frequency
+//! - [x] Write protection of flash sectors through the option bytes
 //!
 //! # Missing features
 //!
@@ -32,14 +33,30 @@
 //! let flash_latency = flash.get_latency() as usize;
 //! debug!("Current flash latency is {}", flash_latency);
 //! ```
+//!
+//! ## Write protect the first 64 kB of flash
+//!
+//! The write protection is stored in the nWRP bits of the option bytes, so it
+//! stays in place after a reset. Only `ProtectionLifetime::Permanent` is
+//! supported. The protection is not irreversible: any code that unlocks the
+//! option bytes with their key, for example a bootloader, and a debugger can
+//! set the nWRP bits again. This driver never does. Only the sectors fully
+//! inside the requested range are protected.
+//!
+//! ```rust,ignore
+//! use kernel::hil::flash::{FlashProtection, ProtectionLifetime};
+//!
+//! flash.protect(0x0800_0000, 0x10000, ProtectionLifetime::Permanent)?;
+//! ```
 
 use crate::chip_specific::flash::FlashChipSpecific as FlashChipSpecificTrait;
 use crate::chip_specific::flash::FlashLatency16;
 use crate::chip_specific::flash::RegisterToFlashLatency;
 
 use kernel::debug;
-use kernel::utilities::registers::interfaces::{ReadWriteable, Readable};
-use kernel::utilities::registers::{register_bitfields, ReadWrite, WriteOnly};
+use kernel::hil;
+use kernel::utilities::registers::interfaces::{ReadWriteable, Readable, Writeable};
+use kernel::utilities::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
 use kernel::utilities::StaticRef;
 use kernel::ErrorCode;
 
@@ -148,7 +165,10 @@ register_bitfields![u32,
         RDP OFFSET(8) NUMBITS(8) [],
         /// Not write protect
         // NOTE: The length of this bit field varies with the chip model
-        nWRP OFFSET(16) NUMBITS(12) []
+        nWRP OFFSET(16) NUMBITS(12) [],
+        /// Selection of protection mode of nWRP bits
+        // NOTE: This bit is not available on all chip models
+        SPRMOD OFFSET(31) NUMBITS(1) []
     ],
     OPTCR1 [
         /// Not write protect
@@ -161,9 +181,47 @@ register_bitfields![u32,
 const FLASH_BASE: StaticRef<FlashRegisters> =
     unsafe { StaticRef::new(0x40023C00 as *const FlashRegisters) };
 
+// Flash size data register, in kB. All chip models have it at the same address.
+const FLASH_SIZE_DATA: StaticRef<ReadOnly<u16>> =
+    unsafe { StaticRef::new(0x1FFF7A22 as *const ReadOnly<u16>) };
+
+/// Start of the main flash memory.
+const FLASH_START: usize = 0x0800_0000;
+
+/// Keys to unlock the option control register.
+const OPTKEY1: u32 = 0x08192A3B;
+const OPTKEY2: u32 = 0x4C5D6E7F;
+
+/// Number of sectors in a bank.
+const BANK_SECTORS: usize = 12;
+
+/// Size of a bank.
+const BANK_SIZE: usize = 0x10_0000;
+
+/// Number of sectors that can be write protected.
+#[cfg(feature = "stm32f429")]
+const MAX_SECTORS: usize = 2 * BANK_SECTORS;
+#[cfg(not(feature = "stm32f429"))]
+const MAX_SECTORS: usize = BANK_SECTORS;
+
+/// Offset of `sector` from the start of the flash.
+///
+/// Every bank starts with four 16 kB sectors, followed by one 64 kB sector
+/// and seven 128 kB sectors.
+// NOTE: This is the single bank layout. The dual bank mode of 1 MB chips
+// (DB1M) is not supported.
+fn sector_offset(sector: usize) -> usize {
+    let bank = (sector / BANK_SECTORS) * BANK_SIZE;
+    match sector % BANK_SECTORS {
+        s @ 0..=4 => bank + s * 0x4000,
+        s => bank + (s - 4) * 0x2_0000,
+    }
+}
+
 /// Main Flash struct
 pub struct Flash<FlashChipSpecific> {
     registers: StaticRef<FlashRegisters>,
+    flash_size: StaticRef<ReadOnly<u16>>,
     _marker: PhantomData<FlashChipSpecific>,
 }
 
@@ -172,6 +230,7 @@ impl<FlashChipSpecific: FlashChipSpecificTrait> Flash<FlashChipSpecific> {
     pub(crate) fn new() -> Self {
         Self {
             registers: FLASH_BASE,
+            flash_size: FLASH_SIZE_DATA,
             _marker: PhantomData,
         }
     }
@@ -211,6 +270,138 @@ impl<FlashChipSpecific: FlashChipSpecificTrait> Flash<FlashChipSpecific> {
     pub(crate) fn get_latency(&self) -> FlashChipSpecific::FlashLatency {
         FlashChipSpecific::FlashLatency::convert_register_to_enum(self.read_latency_from_register())
     }
+
+    /// Number of sectors of the flash memory of this chip.
+    fn sectors(&self) -> usize {
+        let size = self.flash_size.get() as usize * 1024;
+        (0..MAX_SECTORS)
+            .take_while(|&sector| sector_offset(sector) < size)
+            .count()
+    }
+
+    /// Returns the write protection bits of all sectors. A cleared bit means
+    /// the sector is protected.
+    fn read_nwrp(&self) -> u32 {
+        #[allow(unused_mut)]
+        let mut nwrp = self.registers.optcr.read(OPTCR::nWRP);
+        #[cfg(feature = "stm32f429")]
+        {
+            nwrp |= self.registers.optcr1.read(OPTCR1::nWRP) << BANK_SECTORS;
+        }
+        nwrp
+    }
+
+    fn wait_for_operation(&self) {
+        while self.registers.sr.is_set(SR::BSY) {}
+    }
+
+    /// Program the write protection bits into the option bytes.
+    fn write_nwrp(&self, nwrp: u32) -> Result<(), ErrorCode> {
+        self.wait_for_operation();
+        if self.registers.optcr.is_set(OPTCR::OPTLOCK) {
+            self.registers.optkeyr.set(OPTKEY1);
+            self.registers.optkeyr.set(OPTKEY2);
+        }
+
+        #[cfg(feature = "stm32f429")]
+        self.registers
+            .optcr1
+            .modify(OPTCR1::nWRP.val(nwrp >> BANK_SECTORS));
+        self.registers
+            .optcr
+            .modify(OPTCR::nWRP.val(nwrp & ((1 << BANK_SECTORS) - 1)));
+        self.registers.optcr.modify(OPTCR::OPTSTRT::SET);
+        self.wait_for_operation();
+
+        self.registers.optcr.modify(OPTCR::OPTLOCK::SET);
+
+        if self.read_nwrp() != nwrp {
+            return Err(ErrorCode::FAIL);
+        }
+        Ok(())
+    }
+
+    /// Returns the write protection bits of the sectors overlapping with
+    /// `start..end`, or the sectors fully inside of it if `inner`.
+    fn sector_mask(&self, start: usize, end: usize, inner: bool) -> Option<u32> {
+        let sectors = self.sectors();
+        let flash_end = FLASH_START + sector_offset(sectors);
+        if start < FLASH_START || end > flash_end || start >= end {
+            return None;
+        }
+
+        let mut mask = 0;
+        for sector in 0..sectors {
+            let sector_start = FLASH_START + sector_offset(sector);
+            let sector_end = FLASH_START + sector_offset(sector + 1);
+            let selected = if inner {
+                sector_start >= start && sector_end <= end
+            } else {
+                sector_start < end && sector_end > start
+            };
+            if selected {
+                mask |= 1 << sector;
+            }
+        }
+        Some(mask)
+    }
+}
+
+impl<FlashChipSpecific: FlashChipSpecificTrait> hil::flash::FlashProtection
+    for Flash<FlashChipSpecific>
+{
+    fn protect(
+        &self,
+        start: usize,
+        length: usize,
+        lifetime: hil::flash::ProtectionLifetime,
+    ) -> Result<core::ops::Range<usize>, ErrorCode> {
+        // The write protection is stored in the option bytes, so it can not
+        // be dropped on reset. Reprogramming the option bytes can clear it,
+        // see the module documentation.
+        if lifetime != hil::flash::ProtectionLifetime::Permanent {
+            return Err(ErrorCode::NOSUPPORT);
+        }
+        let end = start.checked_add(length).ok_or(ErrorCode::INVAL)?;
+        let mask = self
+            .sector_mask(start, end, true)
+            .filter(|&mask| mask != 0)
+            .ok_or(ErrorCode::INVAL)?;
+
+        // With SPRMOD set the nWRP bits select sectors for proprietary code
+        // readout protection instead, and can not be used for this.
+        if self.registers.optcr.is_set(OPTCR::SPRMOD) {
+            return Err(ErrorCode::FAIL);
+        }
+
+        // Only program the option bytes if something changes, they have
+        // limited endurance.
+        let nwrp = self.read_nwrp();
+        if nwrp & mask != 0 {
+            self.write_nwrp(nwrp & !mask)?;
+        }
+
+        let first = mask.trailing_zeros() as usize;
+        let last = (u32::BITS - mask.leading_zeros()) as usize;
+        Ok(FLASH_START + sector_offset(first)..FLASH_START + sector_offset(last))
+    }
+
+    fn is_protected(&self, start: usize, length: usize) -> bool {
+        let mask = match start
+            .checked_add(length)
+            .and_then(|end| self.sector_mask(start, end, false))
+        {
+            Some(mask) => mask,
+            None => return false,
+        };
+        !self.registers.optcr.is_set(OPTCR::SPRMOD) && self.read_nwrp() & mask == 0
+    }
+
+    fn protection_block(&self, address: usize) -> Option<core::ops::Range<usize>> {
+        let offset = address.checked_sub(FLASH_START)?;
+        let sector = (0..self.sectors()).find(|&sector| offset < sector_offset(sector + 1))?;
+        Some(FLASH_START + sector_offset(sector)..FLASH_START + sector_offset(sector + 1))
+    }
 }
 
 /// Tests for the STM32F4xx flash driver.
@@ -0,0 +1,82 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Interface for radios that can take part in Bluetooth Low Energy
+//! connections.
+//!
+//! A BLE link layer has to answer a received packet exactly 150 µs (T_IFS)
+//! after it ends, which is too tight to first hand the packet to a capsule
+//! and then start a new transmission. A radio implementing this trait
+//! therefore switches between receiving and transmitting on its own: after
+//! every received packet it asks the client for the response, which the
+//! radio sends T_IFS later, and after every transmitted packet it listens
+//! for the next one.
+//!
+//! The radio never stops listening by itself. The link layer closes receive
+//! windows and connection events with its own timer by calling `stop()`.
+//!
+//! PDUs passed through this interface start with the two byte PDU header and
+//! do not include the access address or the CRC.
+
+use crate::hil::ble_advertising::RadioChannel;
+use crate::ErrorCode;
+
+/// Access address of the advertising channels.
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8E89BED6;
+
+/// CRC initialization value of the advertising channels.
+pub const ADVERTISING_CRC_INIT: u32 = 0x555555;
+
+/// Longest PDU (header and payload) the radio has to handle.
+pub const MAX_PDU_LEN: usize = 2 + 255;
+
+pub trait BleConnectionRadio<'a> {
+    fn set_connection_client(&self, client: &'a dyn ConnectionClient);
+
+    /// Transmit `pdu` on `channel` with `access_address` and `crc_init`, and
+    /// listen for a response T_IFS after it ends.
+    ///
+    /// This is how an advertiser sends a connectable advertisement and waits
+    /// for a scan or connection request.
+    fn transmit(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+        pdu: &[u8],
+    ) -> Result<(), ErrorCode>;
+
+    /// Listen on `channel` for packets with `access_address`, and answer
+    /// them with the responses the client provides.
+    ///
+    /// This is how a peripheral opens the receive window of a connection
+    /// event.
+    fn listen(
+        &self,
+        channel: RadioChannel,
+        access_address: u32,
+        crc_init: u32,
+    ) -> Result<(), ErrorCode>;
+
+    /// Stop transmitting or receiving and turn the radio off.
+    fn stop(&self);
+}
+
+pub trait ConnectionClient {
+    /// A PDU was received.
+    ///
+    /// `crc_ok` is false if the PDU failed its CRC check, in which case its
+    /// contents cannot be trusted. To answer the PDU, the client writes the
+    /// response to `response` and returns its length; the radio sends it
+    /// T_IFS after the received PDU ended. Returning `None` sends nothing
+    /// and keeps listening for the next PDU. The client may call `stop()`
+    /// from here, but not `transmit()` or `listen()`.
+    ///
+    /// This is called with the radio already switching to transmit, so it
+    /// must return as soon as possible.
+    fn pdu_received(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> Option<usize>;
+
+    /// A PDU was transmitted, and the radio now listens for the next PDU.
+    fn pdu_transmitted(&self);
+}
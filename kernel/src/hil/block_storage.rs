@@ -0,0 +1,63 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Interface for block storage devices.
+//!
+//! Block storage devices, such as SD cards or RAM disks, are read and written
+//! in whole blocks of a fixed size. Blocks are numbered from 0 to
+//! `block_count() - 1`.
+
+use crate::errorcode::ErrorCode;
+
+/// Interface for reading and writing blocks of a block storage device.
+pub trait BlockStorage<'a> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient);
+
+    /// Returns the size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Returns the number of blocks of the device, or 0 if the device is not
+    /// ready to be used, for example because no card is inserted.
+    fn block_count(&self) -> u64;
+
+    /// Read `count` blocks starting with block `block` in to `buffer`. The
+    /// buffer must hold at least `count` blocks.
+    ///
+    /// On success `read_complete` is called. On error the buffer is returned
+    /// with one of:
+    /// - `RESERVE` if the device is not ready.
+    /// - `BUSY` if another operation is in progress.
+    /// - `INVAL` if the blocks are outside of the device or the buffer is too
+    ///   short.
+    /// - `NOSUPPORT` if the device can not read `count` blocks at once.
+    fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Write `count` blocks from `buffer` starting with block `block`. The
+    /// buffer must hold at least `count` blocks.
+    ///
+    /// On success `write_complete` is called. Errors are the same as for
+    /// `read_blocks`.
+    fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        block: u64,
+        count: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+/// Client interface for block storage devices.
+pub trait BlockStorageClient {
+    /// Called when a read started with `read_blocks` is finished. `result` is
+    /// `Err(FAIL)` if the device failed to read the blocks.
+    fn read_complete(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+
+    /// Called when a write started with `write_blocks` is finished. `result`
+    /// is `Err(FAIL)` if the device failed to write the blocks.
+    fn write_complete(&self, buffer: &'static mut [u8], result: Result<(), ErrorCode>);
+}
@@ -0,0 +1,69 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Interface for Ethernet adapters.
+//!
+//! An Ethernet adapter sends and receives whole Ethernet frames, starting
+//! with the destination MAC address and without the frame check sequence.
+//! Adapters can be physical Ethernet controllers, or virtual ones such as a
+//! USB CDC-ECM link to a host.
+
+use crate::errorcode::ErrorCode;
+
+/// Length of an Ethernet MAC address.
+pub const MAC_ADDRESS_LEN: usize = 6;
+
+/// Length of the Ethernet header: destination and source MAC address and the
+/// EtherType.
+pub const HEADER_LEN: usize = 14;
+
+/// Maximum length of an Ethernet frame without the frame check sequence, for
+/// a payload of 1500 bytes.
+pub const MAX_FRAME_LEN: usize = 1514;
+
+/// Transmit and receive Ethernet frames.
+pub trait EthernetAdapterDatapath<'a> {
+    fn set_client(&self, client: &'a dyn EthernetAdapterDatapathClient);
+
+    /// Start passing received frames to the client.
+    fn enable_receive(&self);
+
+    /// Stop passing received frames to the client. Frames received while
+    /// receive is disabled are dropped.
+    fn disable_receive(&self);
+
+    /// Transmit the first `len` bytes of `frame_buffer` as one frame.
+    ///
+    /// On success `tx_done` is called with the buffer and
+    /// `transmission_identifier`, which the client can use to tell its
+    /// transmissions apart. On error the buffer is returned with one of:
+    /// - `BUSY` if the adapter can not take another frame now.
+    /// - `SIZE` if the frame is longer than the adapter supports.
+    /// - `OFF` if the link is down.
+    fn transmit_frame(
+        &self,
+        frame_buffer: &'static mut [u8],
+        len: u16,
+        transmission_identifier: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+/// Client interface for Ethernet adapters.
+pub trait EthernetAdapterDatapathClient {
+    /// A frame started with `transmit_frame` was sent, or failed with `err`.
+    /// `timestamp` is the time the frame was sent, if the adapter supports
+    /// timestamps.
+    fn tx_done(
+        &self,
+        err: Result<(), ErrorCode>,
+        frame_buffer: &'static mut [u8],
+        len: u16,
+        transmission_identifier: usize,
+        timestamp: Option<u64>,
+    );
+
+    /// A frame was received. `timestamp` is the time the frame was received,
+    /// if the adapter supports timestamps.
+    fn rx_frame(&self, frame: &[u8], timestamp: Option<u64>);
+}
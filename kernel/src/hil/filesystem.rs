@@ -0,0 +1,148 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2024.
+
+//! Interface for file systems.
+//!
+//! Files are grouped in namespaces, identified by a `u32`. Files in different
+//! namespaces are separate, even if they have the same name, which allows
+//! giving each application its own set of files. How namespaces are stored
+//! depends on the file system.
+//!
+//! A file that has been opened is described by a `File`, which is kept by the
+//! user of the file system and passed to every operation on the file. It holds
+//! the position of the next read or write, which `seek` changes. Operations
+//! return the updated `File` when they complete. Files are not closed, every
+//! completed write is stored on the device.
+
+use crate::errorcode::ErrorCode;
+
+/// Maximum length of a file name in bytes.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// How `open` treats existing and missing files.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OpenMode {
+    /// Open an existing file.
+    Existing,
+    /// Open a file, creating an empty file if it does not exist.
+    Create,
+    /// Open a file and remove its contents, creating it if it does not exist.
+    Truncate,
+}
+
+/// Name and size of a file, as returned by `list`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileInfo {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    /// Size of the file in bytes.
+    pub size: u32,
+}
+
+impl FileInfo {
+    /// Creates a `FileInfo`, `name` is truncated to `MAX_NAME_LEN` bytes.
+    pub fn new(name: &[u8], size: u32) -> FileInfo {
+        let name_len = core::cmp::min(name.len(), MAX_NAME_LEN);
+        let mut info = FileInfo {
+            name: [0; MAX_NAME_LEN],
+            name_len,
+            size,
+        };
+        info.name[..name_len].copy_from_slice(&name[..name_len]);
+        info
+    }
+
+    /// Returns the name of the file.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// Interface for file systems. Only one operation can be in progress at a
+/// time, others return `BUSY`.
+///
+/// Operations that refer to a missing file fail with `NOSUPPORT`.
+pub trait FileSystem<'a> {
+    /// State of an open file.
+    type File: Copy;
+
+    fn set_client(&self, client: &'a dyn FileSystemClient<Self::File>);
+
+    /// Open the file `name` in `namespace`. `open_done` is called with the
+    /// file, positioned at its start.
+    ///
+    /// Returns `INVAL` if the file system can not store files named `name`.
+    fn open(&self, namespace: u32, name: &[u8], mode: OpenMode) -> Result<(), ErrorCode>;
+
+    /// Read up to `length` bytes of `file` at its position in to `buffer`.
+    /// `read_done` is called with the number of bytes read, which is less than
+    /// `length` if the end of the file is reached.
+    fn read(
+        &self,
+        file: Self::File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Write `length` bytes of `buffer` to `file` at its position, extending
+    /// the file if necessary. `write_done` is called with the number of bytes
+    /// written, which is less than `length` if the device is full.
+    fn write(
+        &self,
+        file: Self::File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Remove the file `name` from `namespace`. Open `File`s of it must not be
+    /// used afterwards.
+    fn delete(&self, namespace: u32, name: &[u8]) -> Result<(), ErrorCode>;
+
+    /// Find the file with index `index` of the files in `namespace`.
+    /// `list_done` fails with `NOSUPPORT` if there are `index` or fewer files.
+    ///
+    /// Files keep their index until files are created or deleted.
+    fn list(&self, namespace: u32, index: usize) -> Result<(), ErrorCode>;
+
+    /// Returns `file` with its position set to `position`, or `INVAL` if it is
+    /// after the end of the file.
+    fn seek(&self, file: Self::File, position: u32) -> Result<Self::File, ErrorCode>;
+
+    /// Returns the position of `file`.
+    fn position(&self, file: &Self::File) -> u32;
+
+    /// Returns the size of `file` in bytes.
+    fn size(&self, file: &Self::File) -> u32;
+}
+
+/// Client interface for file systems.
+pub trait FileSystemClient<F> {
+    /// Called when an `open` is finished.
+    fn open_done(&self, result: Result<F, ErrorCode>);
+
+    /// Called when a `read` is finished, with the file at its new position.
+    fn read_done(
+        &self,
+        file: F,
+        buffer: &'static mut [u8],
+        length: usize,
+        result: Result<(), ErrorCode>,
+    );
+
+    /// Called when a `write` is finished, with the file at its new position.
+    /// If `result` is an error, `length` bytes were written before the error.
+    fn write_done(
+        &self,
+        file: F,
+        buffer: &'static mut [u8],
+        length: usize,
+        result: Result<(), ErrorCode>,
+    );
+
+    /// Called when a `delete` is finished.
+    fn delete_done(&self, result: Result<(), ErrorCode>);
+
+    /// Called when a `list` is finished.
+    fn list_done(&self, result: Result<FileInfo, ErrorCode>);
+}
@@ -146,3 +146,61 @@ pub trait Client<F: Flash> {
     /// Flash erase complete.
     fn erase_complete(&self, result: Result<(), Error>);
 }
+
+/// How long a flash region stays protected after it has been locked.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProtectionLifetime {
+    /// The region can not be written or erased until the chip is reset.
+    UntilReset,
+
+    /// The region stays protected after a reset. This interface never
+    /// unlocks it, but depending on the chip it can be unlocked again by
+    /// software that reprograms the protection settings directly (for
+    /// example the option bytes of the STM32F4), only with a debugger, or not
+    /// at all.
+    Permanent,
+}
+
+/// Write-protection of flash regions.
+///
+/// Once a region is locked, writes and erases of that region fail, so
+/// software that can reach the flash controller (for example a capsule or
+/// the application loader) can not modify it. There is no way to unlock a
+/// region through this interface.
+///
+/// Flash controllers protect blocks of a chip specific size. Only the blocks
+/// fully inside the requested range are locked, so that a request never
+/// protects flash outside of it.
+pub trait FlashProtection {
+    /// Prevent writes and erases of the flash in `start..start + length`.
+    /// `start` is the absolute address of the region in the address space of
+    /// the chip.
+    ///
+    /// On success, returns the range of addresses that was locked, which is
+    /// the requested range shrunk to the protection blocks of the chip.
+    ///
+    /// ## Return values
+    ///
+    /// - `Ok(range)`: The range is now protected.
+    /// - `Err(ErrorCode::INVAL)`: The range is not in the flash, or does not
+    ///   contain a whole protection block.
+    /// - `Err(ErrorCode::NOSUPPORT)`: The chip does not support the
+    ///   `lifetime`.
+    /// - `Err(ErrorCode::NOMEM)`: The chip has no protection regions left.
+    /// - `Err(ErrorCode::FAIL)`: The flash controller did not accept the
+    ///   configuration.
+    fn protect(
+        &self,
+        start: usize,
+        length: usize,
+        lifetime: ProtectionLifetime,
+    ) -> Result<core::ops::Range<usize>, ErrorCode>;
+
+    /// Returns true if every block of flash overlapping with
+    /// `start..start + length` is protected.
+    fn is_protected(&self, start: usize, length: usize) -> bool;
+
+    /// Returns the addresses of the protection block that contains
+    /// `address`, or `None` if `address` is not in the flash.
+    fn protection_block(&self, address: usize) -> Option<core::ops::Range<usize>>;
+}
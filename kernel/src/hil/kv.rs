@@ -39,6 +39,21 @@ use crate::storage_permissions::StoragePermissions;
 use crate::utilities::leasable_buffer::SubSliceMut;
 use crate::ErrorCode;
 
+/// Storage usage of a key-value store.
+///
+/// All sizes are in bytes and include any per-object overhead of the store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KVStats {
+    /// The number of stored keys.
+    pub keys: usize,
+    /// Space used by stored objects.
+    pub used: usize,
+    /// Space used by deleted objects that garbage collection can reclaim.
+    pub reclaimable: usize,
+    /// Space available for new objects.
+    pub free: usize,
+}
+
 /// Callback trait for KV stores.
 ///
 /// Implement this trait and use `set_client()` to receive callbacks.
@@ -155,6 +170,39 @@ pub trait KVClient {
     ///   - `FAIL`: An internal error occurred and the operation cannot be
     ///     completed.
     fn garbage_collection_complete(&self, result: Result<(), ErrorCode>);
+
+    /// This callback is called when the next key operation completes.
+    ///
+    /// ### Return Values
+    ///
+    /// - `result`: `Ok(())` on success
+    /// - `Err(ErrorCode)` on error. Valid `ErrorCode`s:
+    ///   - `SIZE`: The value is longer than the provided buffer. The amount of
+    ///     the value that fits in the buffer is provided.
+    ///   - `NOSUPPORT`: There are no more keys.
+    ///   - `FAIL`: An internal error occurred and the operation cannot be
+    ///     completed.
+    /// - `cursor`: The cursor to pass to `next_key()` to continue the
+    ///   enumeration.
+    /// - `key`: The key buffer, holding the key as stored by the store.
+    /// - `value`: The value buffer.
+    fn next_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    );
+
+    /// This callback is called when the statistics operation completes.
+    ///
+    /// ### Return Values
+    ///
+    /// - `result`: The storage usage on success, `Err(ErrorCode)` on error.
+    ///   Valid `ErrorCode`s:
+    ///   - `FAIL`: An internal error occurred and the operation cannot be
+    ///     completed.
+    fn stats_complete(&self, result: Result<KVStats, ErrorCode>);
 }
 
 /// Key-Value interface with permissions.
@@ -343,6 +391,55 @@ pub trait KVPermissions<'a> {
     ///     completed.
     fn garbage_collect(&self) -> Result<(), ErrorCode>;
 
+    /// Find the next key in the key-value store.
+    ///
+    /// Keys are enumerated by starting with a `cursor` of 0 and passing the
+    /// cursor returned in each callback until `NOSUPPORT` is returned. Stores
+    /// that hash keys return the hashed keys. Only keys the caller has
+    /// permission to read are returned.
+    ///
+    /// ### Arguments
+    ///
+    /// - `cursor`: Where to continue the enumeration.
+    /// - `key`: Where the found key will be stored.
+    /// - `value`: Where the value of the found key will be stored.
+    /// - `permissions`: The read/write/modify permissions for this access.
+    ///
+    /// ### Return
+    ///
+    /// - On success returns `Ok(())`. A callback will be issued.
+    /// - On error, returns the buffers and:
+    ///   - `BUSY`: An operation is already in progress.
+    ///   - `NOSUPPORT`: The store cannot enumerate its keys.
+    ///   - `FAIL`: An internal error occurred and the operation cannot be
+    ///     completed.
+    fn next_key(
+        &self,
+        cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+        permissions: StoragePermissions,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    >;
+
+    /// Retrieve the storage usage of the underlying Key/Value store.
+    ///
+    /// ### Return
+    ///
+    /// - On success returns `Ok(())`. A callback will be issued.
+    /// - On error:
+    ///   - `BUSY`: An operation is already in progress.
+    ///   - `NOSUPPORT`: The store cannot report its usage.
+    ///   - `FAIL`: An internal error occurred and the operation cannot be
+    ///     completed.
+    fn get_stats(&self) -> Result<(), ErrorCode>;
+
     /// Returns the length of the key-value store's header in bytes.
     ///
     /// Room for this header must be accommodated in a `set`, `add`, or `update`
@@ -516,4 +613,51 @@ pub trait KV<'a> {
     ///   - `FAIL`: An internal error occurred and the operation cannot be
     ///     completed.
     fn garbage_collect(&self) -> Result<(), ErrorCode>;
+
+    /// Find the next key in the key-value store.
+    ///
+    /// Keys are enumerated by starting with a `cursor` of 0 and passing the
+    /// cursor returned in each callback until `NOSUPPORT` is returned. Stores
+    /// that hash keys return the hashed keys.
+    ///
+    /// ### Arguments
+    ///
+    /// - `cursor`: Where to continue the enumeration.
+    /// - `key`: Where the found key will be stored.
+    /// - `value`: Where the value of the found key will be stored.
+    ///
+    /// ### Return
+    ///
+    /// - On success returns `Ok(())`. A callback will be issued.
+    /// - On error, returns the buffers and:
+    ///   - `BUSY`: An operation is already in progress.
+    ///   - `SIZE`: The key buffer is too small to hold a key.
+    ///   - `NOSUPPORT`: The store cannot enumerate its keys.
+    ///   - `FAIL`: An internal error occurred and the operation cannot be
+    ///     completed.
+    fn next_key(
+        &self,
+        cursor: usize,
+        key: SubSliceMut<'static, u8>,
+        value: SubSliceMut<'static, u8>,
+    ) -> Result<
+        (),
+        (
+            SubSliceMut<'static, u8>,
+            SubSliceMut<'static, u8>,
+            ErrorCode,
+        ),
+    >;
+
+    /// Retrieve the storage usage of the Key/Value store.
+    ///
+    /// ### Return
+    ///
+    /// - On success returns `Ok(())`. A callback will be issued.
+    /// - On error:
+    ///   - `BUSY`: An operation is already in progress.
+    ///   - `NOSUPPORT`: The store cannot report its usage.
+    ///   - `FAIL`: An internal error occurred and the operation cannot be
+    ///     completed.
+    fn get_stats(&self) -> Result<(), ErrorCode>;
 }
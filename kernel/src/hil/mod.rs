@@ -7,6 +7,8 @@
 pub mod adc;
 pub mod analog_comparator;
 pub mod ble_advertising;
+pub mod ble_connection;
+pub mod block_storage;
 pub mod bus8080;
 pub mod buzzer;
 pub mod can;
@@ -16,6 +18,8 @@ pub mod date_time;
 pub mod digest;
 pub mod eic;
 pub mod entropy;
+pub mod ethernet;
+pub mod filesystem;
 pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
@@ -27,6 +27,7 @@ pub use crate::process_binary::ProcessBinary;
 pub use crate::process_checker::AcceptedCredential;
 pub use crate::process_checker::{ProcessCheckerMachine, ProcessCheckerMachineClient};
 pub use crate::process_loading::load_processes;
+pub use crate::process_loading::protect_loaded_processes;
 pub use crate::process_loading::ProcessLoadError;
 pub use crate::process_loading::SequentialProcessLoaderMachine;
 pub use crate::process_loading::{ProcessLoadingAsync, ProcessLoadingAsyncClient};
@@ -218,6 +219,14 @@ impl ProcessId {
             Some(process.get_storage_permissions())
         })
     }
+
+    /// Get the number of bytes of nonvolatile storage the process requested
+    /// in its TBF header. Returns `None` if the process did not request any.
+    pub fn get_nonvolatile_storage_size(&self) -> Option<u32> {
+        self.kernel.process_map_or(None, *self, |process| {
+            process.get_nonvolatile_storage_size()
+        })
+    }
 }
 
 /// A compressed form of an Application Identifier.
@@ -665,6 +674,14 @@ pub trait Process {
     /// Returns `None` if the process has no storage permissions.
     fn get_storage_permissions(&self) -> storage_permissions::StoragePermissions;
 
+    /// Get the number of bytes of nonvolatile storage the process requested
+    /// in its TBF header.
+    ///
+    /// Returns `None` if the process did not request nonvolatile storage.
+    fn get_nonvolatile_storage_size(&self) -> Option<u32> {
+        None
+    }
+
     // mpu
 
     /// Configure the MPU to use the process's allocated regions.
@@ -14,11 +14,13 @@
 
 use core::cell::Cell;
 use core::fmt;
+use core::ops::Range;
 
 use crate::capabilities::ProcessManagementCapability;
 use crate::config;
 use crate::debug;
 use crate::deferred_call::{DeferredCall, DeferredCallClient};
+use crate::hil::flash::{FlashProtection, ProtectionLifetime};
 use crate::kernel::Kernel;
 use crate::platform::chip::Chip;
 use crate::process::{Process, ShortId};
@@ -167,6 +169,83 @@ pub fn load_processes<C: Chip>(
     Ok(())
 }
 
+/// Lock the flash of all processes in `procs`, so that their code can not be
+/// changed while they are running. Writeable flash regions of the processes
+/// are not locked.
+///
+/// `load_processes()` runs every process without checking credentials, so
+/// boards that load processes with it call this afterwards to lock the
+/// processes they run. `SequentialProcessLoaderMachine` instead locks the
+/// processes with an accepted credential, see
+/// `SequentialProcessLoaderMachine::set_flash_protection()`.
+pub fn protect_loaded_processes(
+    procs: &[Option<&'static dyn Process>],
+    protection: &dyn FlashProtection,
+    lifetime: ProtectionLifetime,
+) {
+    protect_processes(procs.iter().flatten(), protection, lifetime);
+}
+
+/// Lock the flash of `processes`, except for their writeable flash regions.
+fn protect_processes<'p>(
+    processes: impl Iterator<Item = &'p &'static dyn Process>,
+    protection: &dyn FlashProtection,
+    lifetime: ProtectionLifetime,
+) {
+    // Processes are stored back-to-back in flash, so merge the flash of
+    // consecutive processes to use as few protection regions as possible.
+    let mut pending: Option<Range<usize>> = None;
+    for process in processes {
+        let addresses = process.get_addresses();
+        let writeable_regions = (0..process.number_writeable_flash_regions())
+            .map(|index| process.get_writeable_flash_region(index))
+            .map(|(offset, size)| {
+                addresses.flash_start + offset..addresses.flash_start + offset + size
+            });
+
+        let mut start = addresses.flash_start;
+        while start < addresses.flash_end {
+            // Lock up to the next writeable region, then continue
+            // after it.
+            let next_writeable = writeable_regions
+                .clone()
+                .filter(|region| region.end > start)
+                .min_by_key(|region| region.start)
+                .unwrap_or(addresses.flash_end..addresses.flash_end);
+            let end = next_writeable.start.max(start).min(addresses.flash_end);
+            if start < end {
+                pending = match pending.take() {
+                    Some(range) if range.end == start => Some(range.start..end),
+                    range => {
+                        range.map(|range| protect_flash(protection, range, lifetime));
+                        Some(start..end)
+                    }
+                };
+            }
+            start = next_writeable.end;
+        }
+    }
+    if let Some(range) = pending {
+        protect_flash(protection, range, lifetime);
+    }
+}
+
+fn protect_flash(
+    protection: &dyn FlashProtection,
+    range: Range<usize>,
+    lifetime: ProtectionLifetime,
+) {
+    let result = protection.protect(range.start, range.len(), lifetime);
+    if config::CONFIG.debug_load_processes {
+        debug!(
+            "Loading: protecting flash={:#010X}-{:#010X}: {:?}",
+            range.start,
+            range.end - 1,
+            result
+        );
+    }
+}
+
 /// Helper function to load processes from flash into an array of active
 /// processes. This is the default template for loading processes, but a board
 /// is able to create its own `load_processes()` function and use that instead.
@@ -496,6 +575,8 @@ pub struct SequentialProcessLoaderMachine<'a, C: Chip + 'static, D: ProcessStand
     storage_policy: &'static dyn ProcessStandardStoragePermissionsPolicy<C, D>,
     /// Current mode of the loading machine.
     state: OptionalCell<SequentialProcessLoaderMachineState>,
+    /// Flash protection to lock the flash of verified processes with.
+    flash_protection: OptionalCell<(&'a dyn FlashProtection, ProtectionLifetime)>,
 }
 
 impl<C: Chip, D: ProcessStandardDebug> SequentialProcessLoaderMachine<'_, C, D> {
@@ -530,6 +611,7 @@ impl<C: Chip, D: ProcessStandardDebug> SequentialProcessLoaderMachine<'_, C, D>
             fault_policy,
             storage_policy,
             state: OptionalCell::empty(),
+            flash_protection: OptionalCell::empty(),
         }
     }
 
@@ -792,6 +874,10 @@ impl<C: Chip, D: ProcessStandardDebug> SequentialProcessLoaderMachine<'_, C, D>
         }
         self.proc_binaries.put(proc_binaries);
 
+        self.flash_protection.map(|(protection, lifetime)| {
+            self.protect_verified_processes(protection, lifetime);
+        });
+
         // We have iterated all discovered `ProcessBinary`s and loaded what we
         // could so now we can signal that process loading is finished.
         self.client.map(|client| {
@@ -802,6 +888,25 @@ impl<C: Chip, D: ProcessStandardDebug> SequentialProcessLoaderMachine<'_, C, D>
         Ok(())
     }
 
+    /// Lock the flash of all loaded processes with an accepted credential, so
+    /// that their code can not be changed while they are running.
+    fn protect_verified_processes(
+        &self,
+        protection: &dyn FlashProtection,
+        lifetime: ProtectionLifetime,
+    ) {
+        self.procs.map(|procs| {
+            protect_processes(
+                procs
+                    .iter()
+                    .flatten()
+                    .filter(|process| process.get_credential().is_some()),
+                protection,
+                lifetime,
+            );
+        });
+    }
+
     /// Check if `pb1` is blocked from running by `pb2`.
     ///
     /// `pb2` blocks `pb1` if:
@@ -871,6 +976,22 @@ impl<C: Chip, D: ProcessStandardDebug> SequentialProcessLoaderMachine<'_, C, D>
     }
 }
 
+impl<'a, C: Chip, D: ProcessStandardDebug> SequentialProcessLoaderMachine<'a, C, D> {
+    /// Lock the flash of processes with an accepted credential after they
+    /// are loaded, so that their code can not be modified until the
+    /// protection ends.
+    ///
+    /// This must be set before the kernel starts running deferred calls, as
+    /// processes are loaded from a deferred call after `start()`.
+    pub fn set_flash_protection(
+        &self,
+        protection: &'a dyn FlashProtection,
+        lifetime: ProtectionLifetime,
+    ) {
+        self.flash_protection.set((protection, lifetime));
+    }
+}
+
 impl<'a, C: Chip, D: ProcessStandardDebug> ProcessLoadingAsync<'a>
     for SequentialProcessLoaderMachine<'a, C, D>
 {
@@ -751,6 +751,10 @@ impl<C: Chip, D: 'static + ProcessStandardDebug> Process for ProcessStandard<'_,
         self.storage_permissions
     }
 
+    fn get_nonvolatile_storage_size(&self) -> Option<u32> {
+        self.header.get_nonvolatile_storage_size()
+    }
+
     fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
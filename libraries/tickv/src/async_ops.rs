@@ -125,11 +125,17 @@
 //! If the data isn't ready (multiple reads might occur) then the `NotReady`
 //! error types can still be used.
 //!
+//! Operations that write more than one object, like appending a value that is
+//! chained over multiple objects, return a `WriteNotReady` error after each
+//! write except the last one. `continue_operation()` then has to be called
+//! once the write has completed. `is_busy()` returns true while this is the
+//! case.
+//!
 
 use crate::error_codes::ErrorCode;
 use crate::flash_controller::FlashController;
 use crate::success_codes::SuccessCode;
-use crate::tickv::{State, TicKV};
+use crate::tickv::{KeyEntry, State, StorageStats, TicKV};
 use core::cell::Cell;
 
 /// The return type from the continue operation
@@ -149,6 +155,9 @@ pub struct AsyncTicKV<'a, C: FlashController<S>, const S: usize> {
     key: Cell<Option<u64>>,
     value: Cell<Option<&'static mut [u8]>>,
     value_length: Cell<usize>,
+    cursor: Cell<usize>,
+    key_entry: Cell<Option<KeyEntry>>,
+    stats: Cell<Option<StorageStats>>,
 }
 
 impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
@@ -164,6 +173,9 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
             key: Cell::new(None),
             value: Cell::new(None),
             value_length: Cell::new(0),
+            cursor: Cell::new(0),
+            key_entry: Cell::new(None),
+            stats: Cell::new(None),
         }
     }
 
@@ -308,6 +320,74 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
         }
     }
 
+    /// Finds the next valid key in flash storage.
+    ///
+    /// `cursor`: Where to start searching, 0 or the `next` field of the
+    ///           previous `KeyEntry`.
+    /// `buf`: A buffer to store the value of the key to.
+    ///
+    /// Once the operation has completed the found key can be retrieved
+    /// with `key_entry()`.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn get_next_key(
+        &self,
+        cursor: usize,
+        buf: &'static mut [u8],
+    ) -> Result<SuccessCode, (&'static mut [u8], ErrorCode)> {
+        self.key_entry.set(None);
+        match self.tickv.get_next_key(cursor, buf) {
+            Ok(_code) => {
+                // Ok is a problem, since that means no asynchronous operations
+                // were called, which means our client will never get a
+                // callback. We need to error.
+                Err((buf, ErrorCode::ReadFail))
+            }
+            Err(e) => match e {
+                ErrorCode::ReadNotReady(_) => {
+                    self.cursor.set(cursor);
+                    self.value.replace(Some(buf));
+                    Ok(SuccessCode::Queued)
+                }
+                _ => Err((buf, e)),
+            },
+        }
+    }
+
+    /// Collects statistics about the space used in flash storage.
+    ///
+    /// Once the operation has completed the statistics can be retrieved
+    /// with `stats()`.
+    ///
+    /// On success a `SuccessCode` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn get_stats(&self) -> Result<SuccessCode, ErrorCode> {
+        self.stats.set(None);
+        match self.tickv.get_stats() {
+            Ok(_code) => Err(ErrorCode::ReadFail),
+            Err(ErrorCode::ReadNotReady(_)) => Ok(SuccessCode::Queued),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The key found by the last completed `get_next_key()` operation.
+    pub fn key_entry(&self) -> Option<KeyEntry> {
+        self.key_entry.get()
+    }
+
+    /// The statistics collected by the last completed `get_stats()`
+    /// operation.
+    pub fn stats(&self) -> Option<StorageStats> {
+        self.stats.get()
+    }
+
+    /// Returns true if the last operation has to be continued with
+    /// `continue_operation()` once the async operation has completed.
+    pub fn is_busy(&self) -> bool {
+        self.tickv.is_busy()
+    }
+
     /// Copy data from `read_buffer` argument to the internal read_buffer.
     /// This should be used to copy the data that the implementation wanted
     /// to read when calling `read_region` after the async operation has
@@ -319,7 +399,10 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
     }
 
     /// Continue the last operation after the async operation has completed.
-    /// This should be called from a read/erase complete callback.
+    /// This should be called from a read/erase complete callback. It should
+    /// also be called from a write complete callback if `is_busy()` returns
+    /// true, as operations that write multiple objects, such as appending a
+    /// chained value, continue after each write.
     /// NOTE: If called from a read callback, `set_read_buffer` should be
     /// called first to update the data.
     ///
@@ -362,6 +445,25 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
                 Ok(bytes_freed) => (Ok(SuccessCode::Complete), bytes_freed),
                 Err(e) => (Err(e), 0),
             },
+            State::GetNextKey(_) => {
+                let buf = self.value.take().unwrap();
+                let ret = self.tickv.get_next_key(self.cursor.get(), buf);
+                self.value.replace(Some(buf));
+                match ret {
+                    Ok((s, entry)) => {
+                        self.key_entry.set(Some(entry));
+                        (Ok(s), entry.copied_length)
+                    }
+                    Err(e) => (Err(e), 0),
+                }
+            }
+            State::GetStats(_) => match self.tickv.get_stats() {
+                Ok((s, stats)) => {
+                    self.stats.set(Some(stats));
+                    (Ok(s), 0)
+                }
+                Err(e) => (Err(e), 0),
+            },
             _ => unreachable!(),
         };
 
@@ -371,11 +473,9 @@ impl<'a, C: FlashController<S>, const S: usize> AsyncTicKV<'a, C, S> {
                 (ret, self.value.take(), length)
             }
             Err(e) => match e {
-                ErrorCode::ReadNotReady(_) | ErrorCode::EraseNotReady(_) => (ret, None, 0),
-                ErrorCode::WriteNotReady(_) => {
-                    self.tickv.state.set(State::None);
-                    (ret, None, 0)
-                }
+                ErrorCode::ReadNotReady(_)
+                | ErrorCode::WriteNotReady(_)
+                | ErrorCode::EraseNotReady(_) => (ret, None, 0),
                 _ => {
                     self.tickv.state.set(State::None);
                     (ret, self.value.take(), length)
@@ -395,7 +495,9 @@ mod tests {
         use crate::error_codes::ErrorCode;
         use crate::flash_controller::FlashController;
         use crate::success_codes::SuccessCode;
-        use crate::tickv::{HASH_OFFSET, LEN_OFFSET, MAIN_KEY, VERSION, VERSION_OFFSET};
+        use crate::tickv::{
+            HASH_OFFSET, LEN_OFFSET, MAIN_KEY, REGION_HEADER_LENGTH, VERSION, VERSION_OFFSET,
+        };
         use core::hash::{Hash, Hasher};
         use core::ptr::addr_of_mut;
         use std::cell::Cell;
@@ -421,10 +523,10 @@ mod tests {
             assert_eq!(buf[HASH_OFFSET + 7], 0x44);
 
             // Check the check hash
-            assert_eq!(buf[HASH_OFFSET + 8], 0xbb);
-            assert_eq!(buf[HASH_OFFSET + 9], 0x32);
-            assert_eq!(buf[HASH_OFFSET + 10], 0x74);
-            assert_eq!(buf[HASH_OFFSET + 11], 0x1d);
+            assert_eq!(buf[HASH_OFFSET + 8], 0x3e);
+            assert_eq!(buf[HASH_OFFSET + 9], 0xa7);
+            assert_eq!(buf[HASH_OFFSET + 10], 0x40);
+            assert_eq!(buf[HASH_OFFSET + 11], 0x13);
         }
 
         fn check_region_one(buf: &[u8]) {
@@ -451,10 +553,10 @@ mod tests {
             assert_eq!(buf[42], 0x23);
 
             // Check the check hash
-            assert_eq!(buf[43], 0xfd);
-            assert_eq!(buf[44], 0x24);
-            assert_eq!(buf[45], 0xf0);
-            assert_eq!(buf[46], 0x07);
+            assert_eq!(buf[43], 0x54);
+            assert_eq!(buf[44], 0x72);
+            assert_eq!(buf[45], 0xf4);
+            assert_eq!(buf[46], 0x31);
         }
 
         fn check_region_two(buf: &[u8]) {
@@ -481,10 +583,10 @@ mod tests {
             assert_eq!(buf[42], 0x23);
 
             // Check the check hash
-            assert_eq!(buf[43], 0x1b);
-            assert_eq!(buf[44], 0x53);
-            assert_eq!(buf[45], 0xf9);
-            assert_eq!(buf[46], 0x54);
+            assert_eq!(buf[43], 0xb2);
+            assert_eq!(buf[44], 0x05);
+            assert_eq!(buf[45], 0xfd);
+            assert_eq!(buf[46], 0x62);
         }
 
         fn get_hashed_key(unhashed_key: &[u8]) -> u64 {
@@ -578,9 +680,7 @@ mod tests {
             fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
                 println!("Erase region: {}", region_number);
 
-                let mut local_buf = self.buf.borrow_mut()[region_number];
-
-                for d in local_buf.iter_mut() {
+                for d in self.buf.borrow_mut()[region_number].iter_mut() {
                     *d = 0xFF;
                 }
 
@@ -822,11 +922,13 @@ mod tests {
                 ret = r;
             }
 
-            static mut VALUE: [u8; 32] = [0x23; 32];
+            // The largest value that isn't chained in a 64 byte region, so
+            // that each object fills most of a region.
+            static mut VALUE: [u8; 30] = [0x23; 30];
             static mut BUF: [u8; 32] = [0; 32];
 
             println!("Add key 0x1000");
-            let ret = unsafe { tickv.append_key(0x1000, &mut *addr_of_mut!(VALUE), 32) };
+            let ret = unsafe { tickv.append_key(0x1000, &mut *addr_of_mut!(VALUE), 30) };
             match ret {
                 Ok(SuccessCode::Queued) => {
                     // There is no actual delay in the test, just continue now
@@ -838,7 +940,7 @@ mod tests {
             }
 
             println!("Add key 0x2000");
-            let ret = unsafe { tickv.append_key(0x2000, &mut *addr_of_mut!(VALUE), 32) };
+            let ret = unsafe { tickv.append_key(0x2000, &mut *addr_of_mut!(VALUE), 30) };
             match ret {
                 Ok(SuccessCode::Queued) => {
                     // There is no actual delay in the test, just continue now
@@ -857,7 +959,7 @@ mod tests {
             }
 
             println!("Add key 0x3000");
-            let ret = unsafe { tickv.append_key(0x3000, &mut *addr_of_mut!(VALUE), 32) };
+            let ret = unsafe { tickv.append_key(0x3000, &mut *addr_of_mut!(VALUE), 30) };
             match ret {
                 Ok(SuccessCode::Queued) => {
                     // There is no actual delay in the test, just continue now
@@ -1179,13 +1281,176 @@ mod tests {
             let ret =
                 unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut *addr_of_mut!(VALUE), 32) };
             match ret {
-                Ok(SuccessCode::Queued) => {
+                Ok(SuccessCode::Queued) => loop {
                     // There is no actual delay in the test, just continue now
+                    flash_ctrl_callback(&tickv);
+                    match tickv.continue_operation().0 {
+                        Err(ErrorCode::ReadNotReady(_)) => {}
+                        ret => {
+                            ret.unwrap();
+                            break;
+                        }
+                    }
+                },
+                _ => unreachable!("ret: {:?}", ret),
+            }
+
+            // Region 61 has been erased once, so the key is added to the
+            // region next to it that has never been erased.
+            let buf = tickv.tickv.controller.buf.borrow();
+            assert_eq!(buf[61][LEN_OFFSET], 0x90);
+            assert_eq!(buf[61][REGION_HEADER_LENGTH], 0xFF);
+            assert_eq!(buf[62][VERSION_OFFSET], VERSION);
+            assert_eq!(buf[62][LEN_OFFSET], 0x80);
+        }
+
+        #[test]
+        fn test_get_next_key_and_stats() {
+            let mut read_buf: [u8; 1024] = [0; 1024];
+            let mut hash_function = DefaultHasher::new();
+            MAIN_KEY.hash(&mut hash_function);
+
+            let tickv = AsyncTicKV::<FlashCtrl<1024>, 1024>::new(
+                FlashCtrl::new(true),
+                &mut read_buf,
+                0x10000,
+            );
+
+            let mut ret = tickv.initialise(hash_function.finish());
+            while ret.is_err() {
+                flash_ctrl_callback(&tickv);
+
+                // There is no actual delay in the test, just continue now
+                let (r, _buf, _len) = tickv.continue_operation();
+                ret = r;
+            }
+
+            static mut VALUE: [u8; 32] = [0x23; 32];
+            static mut BUF: [u8; 32] = [0; 32];
+
+            println!("Add key ONE");
+            let ret =
+                unsafe { tickv.append_key(get_hashed_key(b"ONE"), &mut *addr_of_mut!(VALUE), 32) };
+            match ret {
+                Ok(SuccessCode::Queued) => {
                     flash_ctrl_callback(&tickv);
                     tickv.continue_operation().0.unwrap();
                 }
-                _ => unreachable!("ret: {:?}", ret),
+                _ => unreachable!(),
+            }
+
+            println!("Enumerate keys");
+            let mut cursor = 0;
+            let mut keys = 0;
+            loop {
+                let buf = unsafe { &mut *addr_of_mut!(BUF) };
+                assert_eq!(tickv.get_next_key(cursor, buf), Ok(SuccessCode::Queued));
+                let (res, buf, len) = loop {
+                    flash_ctrl_callback(&tickv);
+                    match tickv.continue_operation() {
+                        (Err(ErrorCode::ReadNotReady(_)), _, _) => {}
+                        r => break r,
+                    }
+                };
+                assert!(buf.is_some());
+                if res == Err(ErrorCode::KeyNotFound) {
+                    break;
+                }
+                assert_eq!(res, Ok(SuccessCode::Complete));
+
+                let entry = tickv.key_entry().unwrap();
+                if keys == 1 {
+                    assert_eq!(entry.hashed_key, get_hashed_key(b"ONE"));
+                    assert_eq!(len, 32);
+                }
+                keys += 1;
+                cursor = entry.next;
+            }
+            assert_eq!(keys, 2);
+
+            println!("Get stats");
+            assert_eq!(tickv.get_stats(), Ok(SuccessCode::Queued));
+            loop {
+                flash_ctrl_callback(&tickv);
+                match tickv.continue_operation().0 {
+                    Err(ErrorCode::ReadNotReady(_)) => {}
+                    res => {
+                        assert_eq!(res, Ok(SuccessCode::Complete));
+                        break;
+                    }
+                }
             }
+            let stats = tickv.stats().unwrap();
+            assert_eq!(stats.keys, 2);
+            assert_eq!(stats.used, 15 + 47);
+            assert_eq!(stats.reclaimable, 0);
+            assert_eq!(stats.free, 0x10000 - 15 - 47);
+        }
+
+        #[test]
+        fn test_append_chained() {
+            let mut read_buf: [u8; 256] = [0; 256];
+            let mut hash_function = DefaultHasher::new();
+            MAIN_KEY.hash(&mut hash_function);
+
+            let tickv = AsyncTicKV::<FlashCtrl<256>, 256>::new(
+                FlashCtrl::new(false),
+                &mut read_buf,
+                256 * 64,
+            );
+
+            let mut ret = tickv.initialise(hash_function.finish());
+            while ret.is_err() {
+                flash_ctrl_callback(&tickv);
+
+                // There is no actual delay in the test, just continue now
+                let (r, _buf, _len) = tickv.continue_operation();
+                ret = r;
+            }
+
+            // Chained over 3 objects in 256 byte regions
+            static mut VALUE: [u8; 600] = [0x23; 600];
+            static mut BUF: [u8; 600] = [0; 600];
+
+            println!("Add key BIG");
+            let ret =
+                unsafe { tickv.append_key(get_hashed_key(b"BIG"), &mut *addr_of_mut!(VALUE), 600) };
+            assert_eq!(ret, Ok(SuccessCode::Queued));
+            let mut writes = 0;
+            loop {
+                flash_ctrl_callback(&tickv);
+                if let FlashCtrlAction::Write = tickv.tickv.controller.get_waiting_action() {
+                    writes += 1;
+                }
+                match tickv.continue_operation().0 {
+                    Err(ErrorCode::ReadNotReady(_)) | Err(ErrorCode::WriteNotReady(_)) => {
+                        // The operation continues after the read or after
+                        // writing each fragment.
+                        assert!(tickv.is_busy());
+                    }
+                    ret => {
+                        assert_eq!(ret, Ok(SuccessCode::Queued));
+                        break;
+                    }
+                }
+            }
+            // The head object is written by the last call
+            assert_eq!(writes, 2);
+            assert!(!tickv.is_busy());
+
+            println!("Get key BIG");
+            let ret = unsafe { tickv.get_key(get_hashed_key(b"BIG"), &mut *addr_of_mut!(BUF)) };
+            assert_eq!(ret, Ok(SuccessCode::Queued));
+            let (ret, buf, len) = loop {
+                flash_ctrl_callback(&tickv);
+                match tickv.continue_operation() {
+                    (Err(ErrorCode::ReadNotReady(_)), _, _) => {}
+                    r => break r,
+                }
+            };
+            assert_eq!(ret, Ok(SuccessCode::Complete));
+            assert_eq!(len, 600);
+            assert_eq!(buf.unwrap(), [0x23; 600]);
         }
     }
 }
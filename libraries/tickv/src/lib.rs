@@ -166,6 +166,21 @@
 //!
 //! You can then use the `get_key()` function to get the key back from flash.
 //!
+//! # Enumerating keys
+//!
+//! The hashed keys of all valid objects can be listed with `get_next_key()`,
+//! and `get_stats()` reports how much space is used, free or can be reclaimed
+//! by garbage collection. The original (unhashed) keys are not stored, so only
+//! the hashed keys can be listed.
+//!
+//! ```rust,ignore
+//! let mut cursor = 0;
+//! while let Ok((_, entry)) = tickv.get_next_key(cursor, &mut buf) {
+//!     // `entry.hashed_key` is stored in `entry.region`.
+//!     cursor = entry.next;
+//! }
+//! ```
+//!
 //! # Collisions
 //!
 //! TicKV will prevent a new key/value pair with a colliding hash of the key to be
@@ -182,6 +197,10 @@
 //! before it has completed then the operation probably did not complete and
 //! that data is lost.
 //!
+//! Values that are chained over multiple objects are only found once all of
+//! the objects have been written, so an interrupted `append_key()` never
+//! leaves a partial value behind.
+//!
 //! To help reduce this time to be as short as possible the `FlashController`
 //! is synchronous. Although flash writes can take a considerable amount of time
 //! and this will stall the application, this still seems like a good idea
@@ -199,12 +218,25 @@
 //!
 //! TicKV stores the version when adding objects to the flash storage.
 //!
-//! TicKV is currently version 0.
+//! TicKV is currently version 2.
 //!
 //!  * Version 0
 //!    * Version 0 is a draft version. It should NOT be used for important data!
 //!      Version 0 maintains no backwards compatible support and could change at
 //!      any time.
+//!  * Version 1
+//!    * Objects are stored in the region selected by their hashed key, or the
+//!      closest region with space. Values have to fit in a single region.
+//!  * Version 2
+//!    * Adds region headers that count how often each region has been
+//!      erased. New objects are spread over the least worn regions near the
+//!      region selected by their hashed key.
+//!    * Values that don't fit in a region are chained over multiple objects.
+//!    * Version 1 objects can still be read and invalidated, so version 1
+//!      stores are upgraded in place without erasing anything. Downgrading
+//!      to version 1 is not supported.
+//!
+//! See `SPEC.md` for the details of the storage format.
 //!
 
 #![no_std]
@@ -228,6 +260,7 @@ pub use crate::flash_controller::FlashController;
 #[doc(inline)]
 pub use crate::tickv::TicKV;
 pub use crate::tickv::MAIN_KEY;
+pub use crate::tickv::{KeyEntry, StorageStats};
 
 // This is used to run the tests on a host
 #[cfg(test)]
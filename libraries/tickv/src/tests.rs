@@ -29,10 +29,10 @@ fn check_region_main(buf: &[u8]) {
     assert_eq!(buf[HASH_OFFSET + 7], 0x44);
 
     // Check the check hash
-    assert_eq!(buf[HASH_OFFSET + 8], 0xbb);
-    assert_eq!(buf[HASH_OFFSET + 9], 0x32);
-    assert_eq!(buf[HASH_OFFSET + 10], 0x74);
-    assert_eq!(buf[HASH_OFFSET + 11], 0x1d);
+    assert_eq!(buf[HASH_OFFSET + 8], 0x3e);
+    assert_eq!(buf[HASH_OFFSET + 9], 0xa7);
+    assert_eq!(buf[HASH_OFFSET + 10], 0x40);
+    assert_eq!(buf[HASH_OFFSET + 11], 0x13);
 }
 
 fn check_region_one(buf: &[u8]) {
@@ -59,10 +59,10 @@ fn check_region_one(buf: &[u8]) {
     assert_eq!(buf[42], 0x23);
 
     // Check the check hash
-    assert_eq!(buf[43], 0xfd);
-    assert_eq!(buf[44], 0x24);
-    assert_eq!(buf[45], 0xf0);
-    assert_eq!(buf[46], 0x07);
+    assert_eq!(buf[43], 0x54);
+    assert_eq!(buf[44], 0x72);
+    assert_eq!(buf[45], 0xf4);
+    assert_eq!(buf[46], 0x31);
 }
 
 fn check_region_one_zeroed(buf: &[u8]) {
@@ -123,10 +123,10 @@ fn check_region_two(buf: &[u8]) {
     assert_eq!(buf[42], 0x23);
 
     // Check the check hash
-    assert_eq!(buf[43], 0x1b);
-    assert_eq!(buf[44], 0x53);
-    assert_eq!(buf[45], 0xf9);
-    assert_eq!(buf[46], 0x54);
+    assert_eq!(buf[43], 0xb2);
+    assert_eq!(buf[44], 0x05);
+    assert_eq!(buf[45], 0xfd);
+    assert_eq!(buf[46], 0x62);
 }
 
 fn get_hashed_key(unhashed_key: &[u8]) -> u64 {
@@ -305,9 +305,7 @@ mod store_flast_ctrl {
 
         fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
             println!("Erase region: {}", region_number);
-            let mut local_buf = self.buf.borrow_mut()[region_number];
-
-            for d in local_buf.iter_mut() {
+            for d in self.buf.borrow_mut()[region_number].iter_mut() {
                 *d = 0xFF;
             }
 
@@ -527,6 +525,95 @@ mod store_flast_ctrl {
         println!("Add Key ONE");
         tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
     }
+
+    #[test]
+    fn test_get_next_key() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        let hash = hash_function.finish();
+
+        let tickv = TicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+        tickv.initialise(hash).unwrap();
+
+        let value: [u8; 32] = [0x23; 32];
+        let mut buf: [u8; 16] = [0; 16];
+
+        println!("Add Keys ONE and TWO");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+        tickv.append_key(get_hashed_key(b"TWO"), &value).unwrap();
+
+        println!("Enumerate keys");
+        let (_, entry) = tickv.get_next_key(0, &mut buf).unwrap();
+        assert_eq!(entry.hashed_key, hash);
+        assert_eq!(entry.region, 4);
+        assert_eq!(entry.value_length, 0);
+
+        let (_, entry) = tickv.get_next_key(entry.next, &mut buf).unwrap();
+        assert_eq!(entry.hashed_key, get_hashed_key(b"TWO"));
+        assert_eq!(entry.region, 38);
+        assert_eq!(entry.value_length, 32);
+        // Only the start of the value fits in the buffer
+        assert_eq!(buf, [0x23; 16]);
+
+        let (_, entry) = tickv.get_next_key(entry.next, &mut buf).unwrap();
+        assert_eq!(entry.hashed_key, get_hashed_key(b"ONE"));
+        assert_eq!(entry.region, 61);
+
+        assert_eq!(
+            tickv.get_next_key(entry.next, &mut buf),
+            Err(ErrorCode::KeyNotFound)
+        );
+
+        println!("Delete Key TWO");
+        tickv.invalidate_key(get_hashed_key(b"TWO")).unwrap();
+
+        println!("Invalidated keys are skipped");
+        let (_, entry) = tickv.get_next_key(0, &mut buf).unwrap();
+        let (_, entry) = tickv.get_next_key(entry.next, &mut buf).unwrap();
+        assert_eq!(entry.hashed_key, get_hashed_key(b"ONE"));
+
+        println!("A cursor inside an object starts at the next object");
+        let (_, entry) = tickv.get_next_key(4 * 1024 + 1, &mut buf).unwrap();
+        assert_eq!(entry.hashed_key, get_hashed_key(b"ONE"));
+    }
+
+    #[test]
+    fn test_get_stats() {
+        let mut read_buf: [u8; 1024] = [0; 1024];
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        let hash = hash_function.finish();
+
+        let tickv = TicKV::<FlashCtrl, 1024>::new(FlashCtrl::new(), &mut read_buf, 0x10000);
+        tickv.initialise(hash).unwrap();
+
+        let value: [u8; 32] = [0x23; 32];
+
+        println!("Stats of an empty store");
+        let (_, stats) = tickv.get_stats().unwrap();
+        assert_eq!(stats.keys, 1);
+        assert_eq!(stats.used, 15);
+        assert_eq!(stats.reclaimable, 0);
+        assert_eq!(stats.free, 0x10000 - 15);
+
+        println!("Add Key ONE");
+        tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+
+        let (_, stats) = tickv.get_stats().unwrap();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.used, 15 + 47);
+        assert_eq!(stats.free, 0x10000 - 15 - 47);
+
+        println!("Delete Key ONE");
+        tickv.invalidate_key(get_hashed_key(b"ONE")).unwrap();
+
+        let (_, stats) = tickv.get_stats().unwrap();
+        assert_eq!(stats.keys, 1);
+        assert_eq!(stats.used, 15);
+        assert_eq!(stats.reclaimable, 47);
+        assert_eq!(stats.free, 0x10000 - 15 - 47);
+    }
 }
 
 mod no_check_store_flast_ctrl {
@@ -571,9 +658,7 @@ mod no_check_store_flast_ctrl {
 
         fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
             println!("Erase region: {}", region_number);
-            let mut local_buf = self.buf.borrow_mut()[region_number];
-
-            for d in local_buf.iter_mut() {
+            for d in self.buf.borrow_mut()[region_number].iter_mut() {
                 *d = 0xFF;
             }
 
@@ -666,3 +751,547 @@ mod no_check_store_flast_ctrl {
         );
     }
 }
+
+/// Tests using a flash controller that can simulate a power loss
+mod power_loss_flash_ctrl {
+    use super::*;
+    use crate::crc32;
+    use crate::success_codes::SuccessCode;
+    use crate::tickv::{OLDEST_SUPPORTED_VERSION, REGION_HEADER_LENGTH, WEAR_LEVELING_WINDOW};
+    use std::vec::Vec;
+
+    const REGIONS: usize = 16;
+
+    // An example FlashCtrl implementation
+    struct FlashCtrl {
+        buf: RefCell<[[u8; 256]; REGIONS]>,
+        // The number of writes and erases that complete before the power is
+        // lost. Nothing is changed on the flash after that.
+        ops_left: Cell<Option<usize>>,
+    }
+
+    impl FlashCtrl {
+        fn new() -> Self {
+            Self {
+                buf: RefCell::new([[0xFF; 256]; REGIONS]),
+                ops_left: Cell::new(None),
+            }
+        }
+
+        /// Lose the power after `ops` more writes or erases.
+        fn lose_power_after(&self, ops: usize) {
+            self.ops_left.set(Some(ops));
+        }
+
+        /// Restore the power, with the flash contents at the time the
+        /// power was lost.
+        fn reboot(&self) -> Self {
+            Self {
+                buf: RefCell::new(*self.buf.borrow()),
+                ops_left: Cell::new(None),
+            }
+        }
+
+        fn powered(&self) -> bool {
+            match self.ops_left.get() {
+                Some(0) => false,
+                Some(ops) => {
+                    self.ops_left.set(Some(ops - 1));
+                    true
+                }
+                None => true,
+            }
+        }
+
+        /// The erase count in the region header of `region`.
+        fn erase_count(&self, region: usize) -> Option<u32> {
+            let buf = self.buf.borrow();
+            if buf[region][LEN_OFFSET] & 0xF0 != 0x90 {
+                return None;
+            }
+            let mut count = [0; 4];
+            count.copy_from_slice(&buf[region][HASH_OFFSET + 8..HASH_OFFSET + 12]);
+            Some(u32::from_be_bytes(count))
+        }
+    }
+
+    impl FlashController<256> for FlashCtrl {
+        fn read_region(&self, region_number: usize, buf: &mut [u8; 256]) -> Result<(), ErrorCode> {
+            println!("Read from region: {}", region_number);
+
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = self.buf.borrow()[region_number][i]
+            }
+
+            Ok(())
+        }
+
+        fn write(&self, address: usize, buf: &[u8]) -> Result<(), ErrorCode> {
+            if !self.powered() {
+                println!("Power lost before write to address: {:#x}", address);
+                return Err(ErrorCode::WriteFail);
+            }
+
+            println!(
+                "Write to address: {:#x}, region: {}",
+                address,
+                address / 256
+            );
+
+            for (i, d) in buf.iter().enumerate() {
+                self.buf.borrow_mut()[address / 256][(address % 256) + i] = *d;
+            }
+
+            Ok(())
+        }
+
+        fn erase_region(&self, region_number: usize) -> Result<(), ErrorCode> {
+            if !self.powered() {
+                println!("Power lost before erase of region: {}", region_number);
+                return Err(ErrorCode::EraseFail);
+            }
+
+            println!("Erase region: {}", region_number);
+            for d in self.buf.borrow_mut()[region_number].iter_mut() {
+                *d = 0xFF;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn main_key_hash() -> u64 {
+        let mut hash_function = DefaultHasher::new();
+        MAIN_KEY.hash(&mut hash_function);
+        hash_function.finish()
+    }
+
+    /// A value that is chained over 3 objects in 256 byte regions.
+    fn large_value(seed: u8) -> [u8; 600] {
+        let mut value = [0; 600];
+        for (i, b) in value.iter_mut().enumerate() {
+            *b = (i as u8).wrapping_mul(7).wrapping_add(seed);
+        }
+        value
+    }
+
+    /// Write a version 1 object to the flash, as version 1 of TicKV would.
+    fn write_v1_object(flash: &FlashCtrl, region: usize, offset: usize, hash: u64, value: &[u8]) {
+        let total_length = 11 + value.len() + 4;
+        let mut object = vec![
+            OLDEST_SUPPORTED_VERSION,
+            0x80 | (total_length >> 8) as u8,
+            total_length as u8,
+        ];
+        object.extend_from_slice(&hash.to_be_bytes());
+        object.extend_from_slice(value);
+
+        let check_sum = crc32::Crc32::new();
+        check_sum.update(&object);
+        object.extend_from_slice(&check_sum.finalise().to_ne_bytes());
+
+        flash.buf.borrow_mut()[region][offset..offset + total_length].copy_from_slice(&object);
+    }
+
+    #[test]
+    fn test_chained_value() {
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(), &mut read_buf, 256 * REGIONS);
+        tickv.initialise(main_key_hash()).unwrap();
+
+        let value = large_value(0);
+        let mut buf: [u8; 600] = [0; 600];
+        let mut small_buf: [u8; 100] = [0; 100];
+
+        println!("Add key BIG");
+        tickv.append_key(get_hashed_key(b"BIG"), &value).unwrap();
+
+        println!("Get key BIG");
+        assert_eq!(
+            tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+            Ok((SuccessCode::Complete, 600))
+        );
+        assert_eq!(buf, value);
+
+        println!("Get key BIG with a small buffer");
+        assert_eq!(
+            tickv.get_key(get_hashed_key(b"BIG"), &mut small_buf),
+            Err(ErrorCode::BufferTooSmall(600))
+        );
+        assert_eq!(small_buf, value[..100]);
+
+        println!("Add key BIG again");
+        assert_eq!(
+            tickv.append_key(get_hashed_key(b"BIG"), &value),
+            Err(ErrorCode::KeyAlreadyExists)
+        );
+
+        // The fragments aren't keys
+        let (_, stats) = tickv.get_stats().unwrap();
+        assert_eq!(stats.keys, 2);
+
+        let mut cursor = 0;
+        let mut keys = 0;
+        while let Ok((_, entry)) = tickv.get_next_key(cursor, &mut small_buf) {
+            if entry.hashed_key == get_hashed_key(b"BIG") {
+                assert_eq!(entry.value_length, 600);
+                assert_eq!(entry.copied_length, 100);
+                assert_eq!(small_buf, value[..100]);
+            }
+            keys += 1;
+            cursor = entry.next;
+        }
+        assert_eq!(keys, 2);
+
+        println!("Zeroise key BIG");
+        tickv.zeroise_key(get_hashed_key(b"BIG")).unwrap();
+        assert_eq!(
+            tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+            Err(ErrorCode::KeyNotFound)
+        );
+
+        let (_, stats) = tickv.get_stats().unwrap();
+        assert_eq!(stats.keys, 1);
+        assert_eq!(stats.used, 15);
+        assert_eq!(stats.reclaimable, 600 + 4 + 3 * 15);
+
+        println!("Garbage collect");
+        assert_eq!(tickv.garbage_collect(), Ok(3 * 256));
+    }
+
+    #[test]
+    fn test_power_loss_chained_append() {
+        let value = large_value(0);
+        let new_value = large_value(1);
+        let small_value: [u8; 32] = [0x23; 32];
+
+        // Appending the value writes 2 fragments and then the head object.
+        for ops in 0..=3 {
+            println!("Lose power after {} writes", ops);
+
+            let mut read_buf: [u8; 256] = [0; 256];
+            let tickv =
+                TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(), &mut read_buf, 256 * REGIONS);
+            tickv.initialise(main_key_hash()).unwrap();
+            tickv
+                .append_key(get_hashed_key(b"ONE"), &small_value)
+                .unwrap();
+
+            tickv.controller.lose_power_after(ops);
+            let ret = tickv.append_key(get_hashed_key(b"BIG"), &value);
+            if ops < 3 {
+                assert_eq!(ret, Err(ErrorCode::WriteFail));
+            } else {
+                assert_eq!(ret, Ok(SuccessCode::Written));
+            }
+
+            let mut read_buf: [u8; 256] = [0; 256];
+            let tickv = TicKV::<FlashCtrl, 256>::new(
+                tickv.controller.reboot(),
+                &mut read_buf,
+                256 * REGIONS,
+            );
+            tickv.initialise(main_key_hash()).unwrap();
+
+            let mut buf: [u8; 600] = [0; 600];
+            tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+            assert_eq!(buf[..32], small_value);
+
+            if ops < 3 {
+                // The value is only found once all of it has been written
+                assert_eq!(
+                    tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+                    Err(ErrorCode::KeyNotFound)
+                );
+
+                // Writing the value again replaces the stale fragments
+                tickv
+                    .append_key(get_hashed_key(b"BIG"), &new_value)
+                    .unwrap();
+                assert_eq!(
+                    tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+                    Ok((SuccessCode::Complete, 600))
+                );
+                assert_eq!(buf, new_value);
+
+                let (_, stats) = tickv.get_stats().unwrap();
+                assert_eq!(stats.keys, 3);
+            } else {
+                assert_eq!(
+                    tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+                    Ok((SuccessCode::Complete, 600))
+                );
+                assert_eq!(buf, value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_power_loss_chained_invalidate() {
+        let value = large_value(0);
+
+        // Invalidating the value writes the head object and then the 2
+        // fragments.
+        for ops in 0..=3 {
+            println!("Lose power after {} writes", ops);
+
+            let mut read_buf: [u8; 256] = [0; 256];
+            let tickv =
+                TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(), &mut read_buf, 256 * REGIONS);
+            tickv.initialise(main_key_hash()).unwrap();
+            tickv.append_key(get_hashed_key(b"BIG"), &value).unwrap();
+
+            tickv.controller.lose_power_after(ops);
+            let ret = tickv.invalidate_key(get_hashed_key(b"BIG"));
+            if ops < 3 {
+                assert_eq!(ret, Err(ErrorCode::WriteFail));
+            } else {
+                assert_eq!(ret, Ok(SuccessCode::Written));
+            }
+
+            let mut read_buf: [u8; 256] = [0; 256];
+            let tickv = TicKV::<FlashCtrl, 256>::new(
+                tickv.controller.reboot(),
+                &mut read_buf,
+                256 * REGIONS,
+            );
+            tickv.initialise(main_key_hash()).unwrap();
+
+            let mut buf: [u8; 600] = [0; 600];
+            if ops == 0 {
+                assert_eq!(
+                    tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+                    Ok((SuccessCode::Complete, 600))
+                );
+                tickv.invalidate_key(get_hashed_key(b"BIG")).unwrap();
+            }
+
+            // The key is removed once the head object is invalidated
+            assert_eq!(
+                tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+                Err(ErrorCode::KeyNotFound)
+            );
+
+            tickv.append_key(get_hashed_key(b"BIG"), &value).unwrap();
+            assert_eq!(
+                tickv.get_key(get_hashed_key(b"BIG"), &mut buf),
+                Ok((SuccessCode::Complete, 600))
+            );
+            assert_eq!(buf, value);
+        }
+    }
+
+    #[test]
+    fn test_power_loss_garbage_collect() {
+        let value: [u8; 32] = [0x23; 32];
+
+        // Garbage collection erases the region with key ONE and then writes
+        // the region header.
+        for ops in 0..=2 {
+            println!("Lose power after {} writes", ops);
+
+            let mut read_buf: [u8; 256] = [0; 256];
+            let tickv =
+                TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(), &mut read_buf, 256 * REGIONS);
+            tickv.initialise(main_key_hash()).unwrap();
+            tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+            tickv.append_key(get_hashed_key(b"TWO"), &value).unwrap();
+            tickv.invalidate_key(get_hashed_key(b"ONE")).unwrap();
+
+            tickv.controller.lose_power_after(ops);
+            let ret = tickv.garbage_collect();
+            match ops {
+                0 => assert_eq!(ret, Err(ErrorCode::EraseFail)),
+                1 => assert_eq!(ret, Err(ErrorCode::WriteFail)),
+                _ => assert_eq!(ret, Ok(256)),
+            }
+
+            let mut read_buf: [u8; 256] = [0; 256];
+            let tickv = TicKV::<FlashCtrl, 256>::new(
+                tickv.controller.reboot(),
+                &mut read_buf,
+                256 * REGIONS,
+            );
+            tickv.initialise(main_key_hash()).unwrap();
+
+            let mut buf: [u8; 32] = [0; 32];
+            assert_eq!(
+                tickv.get_key(get_hashed_key(b"ONE"), &mut buf),
+                Err(ErrorCode::KeyNotFound)
+            );
+            tickv.get_key(get_hashed_key(b"TWO"), &mut buf).unwrap();
+
+            // A region erased without its header being written counts as
+            // never erased.
+            let erased: Vec<usize> = (0..REGIONS)
+                .filter(|r| tickv.controller.erase_count(*r).is_some())
+                .collect();
+            match ops {
+                0 | 1 => assert!(erased.is_empty()),
+                _ => assert_eq!(erased.len(), 1),
+            }
+
+            // Finish the garbage collection after the reboot
+            let freed = tickv.garbage_collect().unwrap();
+            assert_eq!(freed, if ops == 0 { 256 } else { 0 });
+
+            tickv.append_key(get_hashed_key(b"ONE"), &value).unwrap();
+            tickv.get_key(get_hashed_key(b"ONE"), &mut buf).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_power_loss_garbage_collect_repair() {
+        let main_region = (main_key_hash() as usize & 0xFFFF) % REGIONS;
+        let region = |hash: u64| (hash as usize & 0xFFFF) % REGIONS;
+
+        // Two keys with the same region, away from the main key
+        let keys: Vec<u64> = (0..)
+            .map(|i: u32| get_hashed_key(&i.to_be_bytes()))
+            .filter(|hash| region(*hash) + 1 < REGIONS && region(*hash).abs_diff(main_region) > 1)
+            .take(64)
+            .collect();
+        let one = keys[0];
+        let two = *keys[1..]
+            .iter()
+            .find(|hash| region(**hash) == region(one))
+            .unwrap();
+
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(), &mut read_buf, 256 * REGIONS);
+        tickv.initialise(main_key_hash()).unwrap();
+
+        // Key ONE fills its region, so key TWO is stored in the next one
+        tickv.append_key(one, &[0x23; 200]).unwrap();
+        tickv.append_key(two, &[0x45; 32]).unwrap();
+        tickv.invalidate_key(one).unwrap();
+
+        // Lose the power after erasing the region of key ONE, before its
+        // region header is written
+        tickv.controller.lose_power_after(1);
+        assert_eq!(tickv.garbage_collect(), Err(ErrorCode::WriteFail));
+        assert_eq!(tickv.controller.erase_count(region(one)), None);
+
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv =
+            TicKV::<FlashCtrl, 256>::new(tickv.controller.reboot(), &mut read_buf, 256 * REGIONS);
+        tickv.initialise(main_key_hash()).unwrap();
+
+        // The empty region gets a region header again, so key TWO is found
+        assert_eq!(tickv.controller.erase_count(region(one)), Some(0));
+        let mut buf: [u8; 32] = [0; 32];
+        assert_eq!(
+            tickv.get_key(two, &mut buf),
+            Ok((SuccessCode::Complete, 32))
+        );
+        assert_eq!(tickv.get_key(one, &mut buf), Err(ErrorCode::KeyNotFound));
+    }
+
+    #[test]
+    fn test_upgrade_from_v1() {
+        let value: [u8; 32] = [0x23; 32];
+        let flash = FlashCtrl::new();
+        let region = |hash: u64| (hash as usize & 0xFFFF) % REGIONS;
+
+        // A store written by version 1 of TicKV
+        write_v1_object(&flash, region(main_key_hash()), 0, main_key_hash(), &[]);
+        let one = get_hashed_key(b"ONE");
+        let offset = if region(one) == region(main_key_hash()) {
+            15
+        } else {
+            0
+        };
+        write_v1_object(&flash, region(one), offset, one, &value);
+
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = TicKV::<FlashCtrl, 256>::new(flash, &mut read_buf, 256 * REGIONS);
+
+        // The store is used as is, nothing is erased
+        tickv.initialise(main_key_hash()).unwrap();
+
+        let mut buf: [u8; 32] = [0; 32];
+        assert_eq!(
+            tickv.get_key(one, &mut buf),
+            Ok((SuccessCode::Complete, 32))
+        );
+        assert_eq!(buf, value);
+
+        println!("Add key TWO");
+        tickv.append_key(get_hashed_key(b"TWO"), &value).unwrap();
+        tickv.get_key(get_hashed_key(b"TWO"), &mut buf).unwrap();
+
+        // New objects are written with the current version
+        let (_, stats) = tickv.get_stats().unwrap();
+        assert_eq!(stats.keys, 3);
+        let two = get_hashed_key(b"TWO").to_be_bytes();
+        let flash_buf = tickv.controller.buf.borrow();
+        let (region, offset) = (0..REGIONS)
+            .find_map(|r| {
+                flash_buf[r]
+                    .windows(8)
+                    .position(|w| w == two)
+                    .map(|offset| (r, offset - HASH_OFFSET))
+            })
+            .unwrap();
+        assert_eq!(flash_buf[region][offset + VERSION_OFFSET], VERSION);
+        drop(flash_buf);
+
+        println!("Delete key ONE");
+        tickv.invalidate_key(one).unwrap();
+        assert_eq!(tickv.get_key(one, &mut buf), Err(ErrorCode::KeyNotFound));
+
+        tickv.garbage_collect().unwrap();
+        tickv.get_key(get_hashed_key(b"TWO"), &mut buf).unwrap();
+        assert_eq!(tickv.get_key(one, &mut buf), Err(ErrorCode::KeyNotFound));
+    }
+
+    #[test]
+    fn test_wear_leveling() {
+        let value: [u8; 64] = [0x23; 64];
+        let main_region = (main_key_hash() as usize & 0xFFFF) % REGIONS;
+
+        // Use a key far from the main key, so that all of the regions in
+        // its wear leveling window can be erased. The window does not wrap
+        // around at the ends of the flash.
+        let key = (0..REGIONS as u32)
+            .map(|i| get_hashed_key(&i.to_be_bytes()))
+            .find(|hash| {
+                let region = (*hash as usize & 0xFFFF) % REGIONS;
+                region.abs_diff(main_region) > WEAR_LEVELING_WINDOW
+            })
+            .unwrap();
+
+        let mut read_buf: [u8; 256] = [0; 256];
+        let tickv = TicKV::<FlashCtrl, 256>::new(FlashCtrl::new(), &mut read_buf, 256 * REGIONS);
+        tickv.initialise(main_key_hash()).unwrap();
+
+        // Keep updating the same key
+        for i in 0..100 {
+            if i > 0 {
+                tickv.invalidate_key(key).unwrap();
+            }
+            tickv.append_key(key, &value).unwrap();
+            tickv.garbage_collect().unwrap();
+        }
+
+        let mut buf: [u8; 64] = [0; 64];
+        assert_eq!(
+            tickv.get_key(key, &mut buf),
+            Ok((SuccessCode::Complete, 64))
+        );
+
+        // The erases are spread over the regions in the window
+        let counts: Vec<u32> = (0..REGIONS)
+            .filter_map(|r| tickv.controller.erase_count(r))
+            .collect();
+        println!("Erase counts: {:?}", counts);
+        assert_eq!(counts.len(), WEAR_LEVELING_WINDOW);
+        let min = counts.iter().min().unwrap();
+        let max = counts.iter().max().unwrap();
+        assert!(max - min <= 1);
+
+        // Each erase makes space for 3 objects after the region header
+        assert_eq!(REGION_HEADER_LENGTH + 3 * 79, 256);
+        assert!(counts.iter().sum::<u32>() >= 30);
+    }
+}
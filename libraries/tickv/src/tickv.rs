@@ -11,7 +11,17 @@ use crate::success_codes::SuccessCode;
 use core::cell::Cell;
 
 /// The current version of TicKV
-pub const VERSION: u8 = 1;
+pub const VERSION: u8 = 2;
+
+/// The oldest version of TicKV objects that can still be read. Stores
+/// written by this version are upgraded in place, see the `Versions`
+/// section of the crate documentation.
+pub const OLDEST_SUPPORTED_VERSION: u8 = 1;
+
+/// The number of regions, starting from the region selected by the key
+/// hash, that are searched for the least worn region when adding an
+/// object.
+pub const WEAR_LEVELING_WINDOW: usize = 4;
 
 #[derive(Clone, Copy, PartialEq)]
 pub(crate) enum InitState {
@@ -23,18 +33,37 @@ pub(crate) enum InitState {
     EraseComplete,
     /// Trying to read a region while appending a key
     AppendKeyReadRegion(usize),
+    /// Reading a region while checking for regions emptied by an
+    /// interrupted garbage collection, with which of the previous regions
+    /// are empty and which of the following regions have to be in use
+    CheckRegion(usize, u32, u32),
+    /// Writing the region header of a region emptied by an interrupted
+    /// garbage collection, the check starts again once it has completed
+    RepairRegion,
 }
 
 #[derive(Clone, Copy, PartialEq)]
 pub(crate) enum KeyState {
     /// Trying to read the key from a region
     ReadRegion(usize),
+    /// Writing an object to a region, the operation continues with the
+    /// next object once the write has completed
+    WriteRegion(usize),
 }
 
 #[derive(Clone, Copy, PartialEq)]
 pub(crate) enum RubbishState {
     ReadRegion(usize, usize),
-    EraseRegion(usize, usize),
+    /// Erasing a region, with the erase count for the new region header
+    EraseRegion(usize, usize, u32),
+    /// Writing the region header of an erased region
+    WriteHeader(usize, usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum StatsState {
+    /// Trying to read a region, with the statistics of the previous regions
+    ReadRegion(usize, StorageStats),
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -55,6 +84,68 @@ pub(crate) enum State {
     ZeroiseKey(KeyState),
     /// Running garbage collection
     GarbageCollect(RubbishState),
+    /// Enumerating keys
+    GetNextKey(KeyState),
+    /// Collecting storage statistics
+    GetStats(StatsState),
+}
+
+/// How far an operation on a key has got. A key can be stored in multiple
+/// objects, which can each be in any of the regions near the region
+/// selected by their hash, so this is kept across async `FlashController`
+/// calls.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub(crate) struct Progress {
+    /// The number of objects of the key that have been handled.
+    step: usize,
+    /// The number of fragments of a chained value.
+    fragments: usize,
+    /// The position of the region being searched in the probe order.
+    probe: usize,
+    /// The least worn region with space for the new object found so far,
+    /// and its erase count.
+    best: Option<(usize, u32)>,
+    /// The region the new object will be written to.
+    target: Option<usize>,
+    /// The region currently in the read buffer.
+    loaded: Option<usize>,
+    /// The length of the value.
+    total: usize,
+    /// The number of bytes of the value that have been read.
+    done: usize,
+}
+
+/// A valid object found by `get_next_key()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEntry {
+    /// The hashed key of the object.
+    pub hashed_key: u64,
+    /// The region the object is stored in.
+    pub region: usize,
+    /// The length of the object's value.
+    pub value_length: usize,
+    /// The number of bytes of the value copied into the buffer. This is
+    /// less than `value_length` if the buffer is too small or the value
+    /// is chained over multiple objects.
+    pub copied_length: usize,
+    /// The cursor to pass to `get_next_key()` to find the following key.
+    pub next: usize,
+}
+
+/// Storage usage returned by `get_stats()`.
+///
+/// All sizes are in bytes and include the object headers and check sums.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StorageStats {
+    /// The number of valid keys.
+    pub keys: usize,
+    /// Space used by valid objects and region headers.
+    pub used: usize,
+    /// Space used by invalidated objects. This is freed once garbage
+    /// collection can erase the region the objects are in.
+    pub reclaimable: usize,
+    /// Space that has not been written to yet.
+    pub free: usize,
 }
 
 /// The struct storing all of the TicKV information.
@@ -64,32 +155,57 @@ pub struct TicKV<'a, C: FlashController<S>, const S: usize> {
     flash_size: usize,
     pub(crate) read_buffer: Cell<Option<&'a mut [u8; S]>>,
     pub(crate) state: Cell<State>,
+    progress: Cell<Progress>,
 }
 
-/// This is the current object header used for TicKV objects
+/// The object header of an object found in a region
+#[derive(Clone, Copy)]
 struct ObjectHeader {
-    version: u8,
     // In reality this is a u4.
     flags: u8,
     // In reality this is a u12.
-    len: u16,
+    len: usize,
     hashed_key: u64,
 }
 
-pub(crate) const FLAGS_VALID: u8 = 8;
-
 impl ObjectHeader {
-    fn new(hashed_key: u64, len: u16) -> Self {
-        assert!(len < 0xFFF);
-        Self {
-            version: VERSION,
-            flags: FLAGS_VALID,
-            len,
-            hashed_key,
-        }
+    fn is_valid(&self) -> bool {
+        self.flags & FLAGS_VALID == FLAGS_VALID
     }
+
+    /// Region headers and fragments of chained values aren't keys
+    fn is_key(&self) -> bool {
+        self.flags & (FLAGS_REGION | FLAGS_FRAGMENT) == 0
+    }
+}
+
+/// What was found when walking the objects in a region.
+#[derive(Clone, Copy, Default)]
+struct RegionInfo {
+    /// The erase count from the region header, 0 if there isn't one.
+    erase_count: u32,
+    /// The region has been written to since it was last erased.
+    used: bool,
+    /// The region contains objects other than the region header.
+    objects: bool,
+    /// The region contains valid objects other than the region header.
+    valid: bool,
+    /// The offset where a new object can be written, `S` if nothing more
+    /// can be written to the region.
+    end: usize,
+    /// The offset and header of the object that was searched for.
+    found: Option<(usize, ObjectHeader)>,
 }
 
+/// The object is valid, this is cleared when the object is invalidated
+pub(crate) const FLAGS_VALID: u8 = 8;
+/// The value continues in a fragment
+pub(crate) const FLAGS_CHAINED: u8 = 4;
+/// The object is a fragment of a chained value
+pub(crate) const FLAGS_FRAGMENT: u8 = 2;
+/// The object is a region header
+pub(crate) const FLAGS_REGION: u8 = 1;
+
 // A list of offsets into the ObjectHeader
 pub(crate) const VERSION_OFFSET: usize = 0;
 pub(crate) const LEN_OFFSET: usize = 1;
@@ -97,6 +213,13 @@ pub(crate) const HASH_OFFSET: usize = 3;
 pub(crate) const HEADER_LENGTH: usize = HASH_OFFSET + 8;
 pub(crate) const CHECK_SUM_LEN: usize = 4;
 
+/// The largest object that fits in the 12-bit length field
+const MAX_OBJECT_LENGTH: usize = 0xFFE;
+/// The length of the value length at the start of a chained value
+const CHAIN_LENGTH_LEN: usize = 4;
+/// The length of a region header, which stores a `u32` erase count
+pub(crate) const REGION_HEADER_LENGTH: usize = HEADER_LENGTH + 4 + CHECK_SUM_LEN;
+
 /// The main key. A hashed version of this should be passed to
 /// `initialise()`.
 pub const MAIN_KEY: &[u8; 15] = b"tickv-super-key";
@@ -115,16 +238,25 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
             flash_size,
             read_buffer: Cell::new(Some(read_buffer)),
             state: Cell::new(State::None),
+            progress: Cell::new(Progress::default()),
         }
     }
 
+    /// Returns true if an operation is waiting on an async
+    /// `FlashController` call and has to be continued once it completes.
+    pub fn is_busy(&self) -> bool {
+        self.state.get() != State::None
+    }
+
     /// This function setups the flash region to be used as a key-value store.
     /// If the region is already initialised this won't make any changes.
     ///
     /// `hashed_main_key`: The u64 hash of the const string `MAIN_KEY`.
     ///
     /// If the specified region has not already been setup for TicKV
-    /// the entire region will be erased.
+    /// the entire region will be erased. Otherwise every region is read to
+    /// repair any damage left by a garbage collection that was interrupted
+    /// by a power loss, see `repair_regions()`.
     ///
     /// On success nothing will be returned.
     /// On error a `ErrorCode` will be returned.
@@ -135,13 +267,21 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
             State::None => self.get_key(hashed_main_key, &mut buf),
             State::Init(state) => match state {
                 InitState::GetKeyReadRegion(_) => self.get_key(hashed_main_key, &mut buf),
+                // We already found the main key
+                InitState::CheckRegion(..) | InitState::RepairRegion => {
+                    Ok((SuccessCode::Complete, 0))
+                }
                 _ => Err(ErrorCode::EraseNotReady(0)),
             },
             _ => unreachable!(),
         };
 
         match key_ret {
-            Ok((ret, _len)) => Ok(ret),
+            Ok((ret, _len)) => {
+                self.repair_regions()?;
+                self.state.set(State::None);
+                Ok(ret)
+            }
             Err(e) => {
                 match e {
                     ErrorCode::ReadNotReady(reg) => {
@@ -175,6 +315,7 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                                 }
 
                                 self.state.set(State::Init(InitState::EraseComplete));
+                                self.progress.set(Progress::default());
                             }
                             _ => {}
                         }
@@ -204,6 +345,87 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         }
     }
 
+    /// Repair the regions emptied by a garbage collection that was
+    /// interrupted before the region header was written.
+    ///
+    /// A region emptied by garbage collection keeps its region header, so
+    /// the search for an object continues past it, but a region without a
+    /// header looks like it has never been used and ends the search. If a
+    /// valid object is stored after an empty region in the wear leveling
+    /// window of its hashed key, the region must have been in use when the
+    /// object was added, so it gets a region header with an erase count of
+    /// 0 again.
+    ///
+    /// The regions are walked in order, remembering which of the previous
+    /// `WEAR_LEVELING_WINDOW - 1` regions are empty and which of the
+    /// following ones must be in use.
+    fn repair_regions(&self) -> Result<(), ErrorCode> {
+        let (start, mut empty, mut needed, loaded) = match self.state.get() {
+            // The region we were waiting on is in the read buffer
+            State::Init(InitState::CheckRegion(reg, empty, needed)) => (reg, empty, needed, true),
+            _ => (0, 0, 0, false),
+        };
+
+        for region in start..(self.flash_size / S) {
+            let region_data = self.read_buffer.take().unwrap();
+            if !loaded || region != start {
+                if let Err(e) = self.controller.read_region(region, region_data) {
+                    self.read_buffer.replace(Some(region_data));
+                    if let ErrorCode::ReadNotReady(reg) = e {
+                        self.state
+                            .set(State::Init(InitState::CheckRegion(reg, empty, needed)));
+                    }
+                    return Err(e);
+                }
+            }
+
+            let is_empty = region_data.iter().all(|b| *b == 0xFF);
+            let mut offset: usize = 0;
+
+            while let Ok(Some(header)) = self.object_at(region_data, offset) {
+                if header.flags & FLAGS_REGION == 0
+                    && Self::object_valid(region_data, offset, header)
+                {
+                    let home = self.get_region(header.hashed_key);
+                    let window =
+                        (0..WEAR_LEVELING_WINDOW).map_while(|p| self.probe_region(home, p));
+
+                    // Only objects stored in their window depend on the
+                    // regions before them in it.
+                    if window.clone().any(|r| r == region) {
+                        for r in window.take_while(|r| *r != region) {
+                            if r > region {
+                                needed |= 1 << (r - region - 1);
+                            } else if empty & 1 << (region - r - 1) != 0 {
+                                empty &= !(1 << (region - r - 1));
+                                if let Err(e) = self.write_region_header(
+                                    r,
+                                    0,
+                                    State::Init(InitState::RepairRegion),
+                                ) {
+                                    self.read_buffer.replace(Some(region_data));
+                                    return Err(e);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                offset += header.len;
+            }
+            self.read_buffer.replace(Some(region_data));
+
+            if is_empty && needed & 1 == 1 {
+                self.write_region_header(region, 0, State::Init(InitState::RepairRegion))?;
+            }
+
+            empty = empty << 1 | (is_empty && needed & 1 == 0) as u32;
+            needed >>= 1;
+        }
+
+        Ok(())
+    }
+
     /// Get region number from a hashed key
     fn get_region(&self, hash: u64) -> usize {
         assert_ne!(hash, 0xFFFF_FFFF_FFFF_FFFF);
@@ -262,620 +484,695 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
         None
     }
 
-    /// Find a key in some loaded region data.
+    /// Get the region at position `probe` of the probe order starting at
+    /// `region`. The probe order is `region`, `region + 1`, `region - 1`,
+    /// `region + 2`, ... skipping regions outside of the flash.
     ///
-    /// On success return the offset in the region_data where the key is and the
-    /// total length of the key.
-    /// On failure return a bool indicating if the caller should keep looking in
-    /// neighboring regions and the error code.
-    fn find_key_offset(
-        &self,
-        hash: u64,
-        region_data: &[u8],
-    ) -> Result<(usize, u16), (bool, ErrorCode)> {
-        // Determine the total size of our payload
+    /// Returns None if there aren't any more in range.
+    fn probe_region(&self, region: usize, probe: usize) -> Option<usize> {
+        let mut region_offset: isize = 0;
 
-        // Split the hash
-        let hash = hash.to_ne_bytes();
+        for _ in 0..probe {
+            region_offset = self.increment_region_offset(region, region_offset)?;
+        }
 
-        let mut offset: usize = 0;
-        let mut empty: bool = true;
+        Some((region as isize + region_offset) as usize)
+    }
 
-        loop {
-            if offset + HEADER_LENGTH >= S {
-                // We have reached the end of the region
-                return Err((false, ErrorCode::KeyNotFound));
-            }
+    /// Get the hashed key of object `index` of a key. Object 0 is the head
+    /// object, which uses the hashed key itself. The fragments of a chained
+    /// value are numbered from 1.
+    fn object_hash(hash: u64, index: usize) -> u64 {
+        if index == 0 {
+            return hash;
+        }
 
-            // Check to see if we have data
-            if *region_data
-                .get(offset + VERSION_OFFSET)
-                .ok_or((false, ErrorCode::KeyNotFound))?
-                != 0xFF
-            {
-                // Mark that this region isn't empty
-                empty = false;
-
-                // We found a version, check that we support it
-                if *region_data
-                    .get(offset + VERSION_OFFSET)
-                    .ok_or((false, ErrorCode::KeyNotFound))?
-                    != VERSION
-                {
-                    return Err((false, ErrorCode::UnsupportedVersion));
-                }
+        let fragment = hash.rotate_left((index as u32).wrapping_mul(8))
+            ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
 
-                // Find this entries length
-                let total_length = ((*region_data
-                    .get(offset + LEN_OFFSET)
-                    .ok_or((false, ErrorCode::CorruptData))?
-                    as u16)
-                    & !0xF0)
-                    << 8
-                    | *region_data
-                        .get(offset + LEN_OFFSET + 1)
-                        .ok_or((false, ErrorCode::CorruptData))? as u16;
-
-                // Check to see if all fields are just 0
-                if total_length == 0 {
-                    // We found something invalid here
-                    return Err((false, ErrorCode::KeyNotFound));
-                }
+        // These aren't valid hashed keys
+        if fragment == 0 || fragment == 0xFFFF_FFFF_FFFF_FFFF {
+            fragment ^ 1
+        } else {
+            fragment
+        }
+    }
 
-                // Check to see if the entry has been deleted
-                if *region_data
-                    .get(offset + LEN_OFFSET)
-                    .ok_or((false, ErrorCode::CorruptData))?
-                    & 0x80
-                    != 0x80
-                {
-                    // Increment our offset by the length and repeat the loop
-                    offset += total_length as usize;
-                    continue;
-                }
+    /// The longest value that is stored in a single object. Longer values
+    /// are chained over multiple objects.
+    fn max_value_length(&self) -> usize {
+        core::cmp::min(S - REGION_HEADER_LENGTH, MAX_OBJECT_LENGTH) - HEADER_LENGTH - CHECK_SUM_LEN
+    }
 
-                // We have found a valid entry, see if it is ours.
-                if *region_data
-                    .get(offset + HASH_OFFSET)
-                    .ok_or((false, ErrorCode::CorruptData))?
-                    != *hash.get(7).ok_or((false, ErrorCode::CorruptData))?
-                    || *region_data
-                        .get(offset + HASH_OFFSET + 1)
-                        .ok_or((false, ErrorCode::CorruptData))?
-                        != *hash.get(6).ok_or((false, ErrorCode::CorruptData))?
-                    || *region_data
-                        .get(offset + HASH_OFFSET + 2)
-                        .ok_or((false, ErrorCode::CorruptData))?
-                        != *hash.get(5).ok_or((false, ErrorCode::CorruptData))?
-                    || *region_data
-                        .get(offset + HASH_OFFSET + 3)
-                        .ok_or((false, ErrorCode::CorruptData))?
-                        != *hash.get(4).ok_or((false, ErrorCode::CorruptData))?
-                    || *region_data
-                        .get(offset + HASH_OFFSET + 4)
-                        .ok_or((false, ErrorCode::CorruptData))?
-                        != *hash.get(3).ok_or((false, ErrorCode::CorruptData))?
-                    || *region_data
-                        .get(offset + HASH_OFFSET + 5)
-                        .ok_or((false, ErrorCode::CorruptData))?
-                        != *hash.get(2).ok_or((false, ErrorCode::CorruptData))?
-                    || *region_data
-                        .get(offset + HASH_OFFSET + 6)
-                        .ok_or((false, ErrorCode::CorruptData))?
-                        != *hash.get(1).ok_or((false, ErrorCode::CorruptData))?
-                    || *region_data
-                        .get(offset + HASH_OFFSET + 7)
-                        .ok_or((false, ErrorCode::CorruptData))?
-                        != *hash.first().ok_or((false, ErrorCode::CorruptData))?
-                {
-                    // Increment our offset by the length and repeat the loop
-                    offset += total_length as usize;
-                    continue;
-                }
+    /// The number of fragments used to store a value of `length` bytes.
+    fn fragments(&self, length: usize) -> usize {
+        let max = self.max_value_length();
 
-                // If we get here we have found out value (assuming no collisions)
-                return Ok((offset, total_length));
-            } else {
-                // We hit the end.
-                return Err((!empty, ErrorCode::KeyNotFound));
-            }
+        if length <= max {
+            return 0;
         }
-    }
 
-    /// Appends the key/value pair to flash storage.
-    ///
-    /// `hash`: A hashed key. This key will be used in future to retrieve
-    ///         or remove the `value`.
-    /// `value`: A buffer containing the data to be stored to flash.
-    ///
-    /// On success nothing will be returned.
-    /// On error a `ErrorCode` will be returned.
-    pub fn append_key(&self, hash: u64, value: &[u8]) -> Result<SuccessCode, ErrorCode> {
-        let region = self.get_region(hash);
-        let check_sum = crc32::Crc32::new();
+        // The head object also stores the length of the value
+        let rest = length - (max - CHAIN_LENGTH_LEN);
+        rest.div_ceil(max)
+    }
 
-        // Length not including check sum
-        let package_length = HEADER_LENGTH + value.len();
-        let object_length = HEADER_LENGTH + value.len() + CHECK_SUM_LEN;
+    /// The part of a value of `length` bytes that is stored in object
+    /// `index`, as a start and end offset into the value.
+    fn chunk(&self, length: usize, index: usize) -> (usize, usize) {
+        let max = self.max_value_length();
 
-        if object_length > 0xFFF {
-            return Err(ErrorCode::ObjectTooLarge);
+        if self.fragments(length) == 0 {
+            return (0, length);
         }
 
-        // Create the header:
-        let header = ObjectHeader::new(hash, object_length as u16);
+        let head = max - CHAIN_LENGTH_LEN;
+        if index == 0 {
+            (0, head)
+        } else {
+            let start = head + (index - 1) * max;
+            (start, core::cmp::min(start + max, length))
+        }
+    }
 
-        let mut region_offset: isize = 0;
+    /// Move on to the next object of a key.
+    fn next_object(&self) {
+        let mut progress = self.progress.get();
+        progress.step += 1;
+        progress.probe = 0;
+        progress.best = None;
+        progress.target = None;
+        self.progress.set(progress);
+    }
 
-        loop {
-            let new_region = match self.state.get() {
-                State::None => (region as isize + region_offset) as usize,
-                State::Init(state) => {
-                    match state {
-                        InitState::AppendKeyReadRegion(reg) => reg,
-                        _ => {
-                            // Get the data from that region
-                            (region as isize + region_offset) as usize
-                        }
-                    }
-                }
-                State::AppendKey(key_state) => match key_state {
-                    KeyState::ReadRegion(reg) => reg,
-                },
-                _ => unreachable!(),
-            };
+    /// Read `region` into the read buffer, unless it is already there, and
+    /// return the read buffer.
+    ///
+    /// `state` is used to save the state of the operation if the read
+    /// completes asynchronously.
+    fn read_region(
+        &self,
+        region: usize,
+        state: fn(KeyState) -> State,
+    ) -> Result<&'a mut [u8; S], ErrorCode> {
+        let mut progress = self.progress.get();
+        let region_data = self.read_buffer.take().unwrap();
 
-            let region_data = self.read_buffer.take().unwrap();
-            if self.state.get() != State::AppendKey(KeyState::ReadRegion(new_region))
-                && self.state.get() != State::Init(InitState::AppendKeyReadRegion(new_region))
-            {
-                match self.controller.read_region(new_region, region_data) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        self.read_buffer.replace(Some(region_data));
-                        if let ErrorCode::ReadNotReady(reg) = e {
-                            self.state.set(State::AppendKey(KeyState::ReadRegion(reg)));
-                        }
-                        return Err(e);
-                    }
-                };
-            }
+        if progress.loaded != Some(region) {
+            progress.loaded = None;
 
-            if self.find_key_offset(hash, region_data).is_ok() {
-                // Check to make sure we don't already have this key
+            if let Err(e) = self.controller.read_region(region, region_data) {
                 self.read_buffer.replace(Some(region_data));
-                return Err(ErrorCode::KeyAlreadyExists);
+                if let ErrorCode::ReadNotReady(reg) = e {
+                    // The data will be in the read buffer when the operation
+                    // is continued.
+                    progress.loaded = Some(reg);
+                    self.state.set(state(KeyState::ReadRegion(reg)));
+                }
+                self.progress.set(progress);
+                return Err(e);
             }
 
-            let mut offset: usize = 0;
+            progress.loaded = Some(region);
+            self.progress.set(progress);
+        }
 
-            loop {
-                if offset + package_length >= S {
-                    // We have reached the end of the region
-                    // We will need to try the next region
+        Ok(region_data)
+    }
 
-                    // Replace the buffer
-                    self.read_buffer.replace(Some(region_data));
+    /// Parse the object header at `offset` in some loaded region data.
+    ///
+    /// Returns `None` if there is no object at `offset`.
+    fn object_at(
+        &self,
+        region_data: &[u8],
+        offset: usize,
+    ) -> Result<Option<ObjectHeader>, ErrorCode> {
+        if offset + HEADER_LENGTH >= S {
+            // We have reached the end of the region
+            return Ok(None);
+        }
 
-                    region_offset = new_region as isize - region as isize;
-                    match self.increment_region_offset(region, region_offset) {
-                        Some(o) => {
-                            region_offset = o;
-                            self.state.set(State::None);
-                        }
-                        None => {
-                            return Err(ErrorCode::FlashFull);
-                        }
-                    }
-                    break;
-                }
+        let version = *region_data
+            .get(offset + VERSION_OFFSET)
+            .ok_or(ErrorCode::CorruptData)?;
+        if version == 0xFF {
+            // We hit the end of valid data
+            return Ok(None);
+        }
+        if !(OLDEST_SUPPORTED_VERSION..=VERSION).contains(&version) {
+            return Err(ErrorCode::UnsupportedVersion);
+        }
 
-                // Check to see if we have data
-                if *region_data
-                    .get(offset + VERSION_OFFSET)
-                    .ok_or(ErrorCode::KeyNotFound)?
-                    != 0xFF
-                {
-                    // We found a version, check that we support it
-                    if *region_data
-                        .get(offset + VERSION_OFFSET)
-                        .ok_or(ErrorCode::KeyNotFound)?
-                        != VERSION
-                    {
-                        self.read_buffer.replace(Some(region_data));
-                        return Err(ErrorCode::UnsupportedVersion);
-                    }
+        let len_flags = *region_data
+            .get(offset + LEN_OFFSET)
+            .ok_or(ErrorCode::CorruptData)?;
+        let total_length = ((len_flags as u16 & !0xF0) << 8
+            | *region_data
+                .get(offset + LEN_OFFSET + 1)
+                .ok_or(ErrorCode::CorruptData)? as u16) as usize;
+
+        if total_length == 0 {
+            // All fields are 0, there is nothing valid after this
+            return Ok(None);
+        }
+        if total_length < HEADER_LENGTH + CHECK_SUM_LEN {
+            return Err(ErrorCode::CorruptData);
+        }
 
-                    // Find this entries length
-                    let total_length = ((*region_data
-                        .get(offset + LEN_OFFSET)
-                        .ok_or(ErrorCode::CorruptData)?
-                        as u16)
-                        & !0xF0)
-                        << 8
-                        | *region_data
-                            .get(offset + LEN_OFFSET + 1)
-                            .ok_or(ErrorCode::CorruptData)? as u16;
-
-                    // Increment our offset by the length and repeat the loop
-                    offset += total_length as usize;
-                    continue;
-                }
+        let mut hash = [0; 8];
+        hash.copy_from_slice(
+            region_data
+                .get(offset + HASH_OFFSET..offset + HASH_OFFSET + 8)
+                .ok_or(ErrorCode::CorruptData)?,
+        );
+
+        Ok(Some(ObjectHeader {
+            flags: len_flags >> 4,
+            len: total_length,
+            hashed_key: u64::from_be_bytes(hash),
+        }))
+    }
 
-                // If we get here we have found an empty spot
-                // Double check that there is no valid hash
+    /// Get the value of the object at `offset` in some loaded region data.
+    fn object_value(
+        region_data: &[u8],
+        offset: usize,
+        header: ObjectHeader,
+    ) -> Result<&[u8], ErrorCode> {
+        region_data
+            .get(offset + HEADER_LENGTH..offset + header.len - CHECK_SUM_LEN)
+            .ok_or(ErrorCode::CorruptData)
+    }
 
-                // Check to see if the entire header is 0xFFFF_FFFF_FFFF_FFFF
-                // To avoid operating on 64-bit values check every 8 bytes at a time
-                if *region_data
-                    .get(offset + HASH_OFFSET)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
-                if *region_data
-                    .get(offset + HASH_OFFSET + 1)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
-                if *region_data
-                    .get(offset + HASH_OFFSET + 2)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
-                if *region_data
-                    .get(offset + HASH_OFFSET + 3)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
-                if *region_data
-                    .get(offset + HASH_OFFSET + 4)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
-                if *region_data
-                    .get(offset + HASH_OFFSET + 5)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
-                if *region_data
-                    .get(offset + HASH_OFFSET + 6)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
-                if *region_data
-                    .get(offset + HASH_OFFSET + 7)
-                    .ok_or(ErrorCode::CorruptData)?
-                    != 0xFF
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::CorruptData);
-                }
+    /// Check the check sum of the object at `offset` in some loaded region
+    /// data.
+    fn check_sum_valid(
+        region_data: &[u8],
+        offset: usize,
+        header: ObjectHeader,
+    ) -> Result<bool, ErrorCode> {
+        let object = region_data
+            .get(offset..offset + header.len)
+            .ok_or(ErrorCode::CorruptData)?;
+        let (data, stored) = object.split_at(header.len - CHECK_SUM_LEN);
 
-                // If we get here we have found an empty spot
-
-                // Copy in new header
-                // This is a little painful, but avoids any unsafe Rust
-                *region_data
-                    .get_mut(offset + VERSION_OFFSET)
-                    .ok_or(ErrorCode::RegionFull)? = header.version;
-                *region_data
-                    .get_mut(offset + LEN_OFFSET)
-                    .ok_or(ErrorCode::RegionFull)? =
-                    (header.len >> 8) as u8 & 0x0F | (header.flags << 4) & 0xF0;
-                *region_data
-                    .get_mut(offset + LEN_OFFSET + 1)
-                    .ok_or(ErrorCode::RegionFull)? = (header.len & 0xFF) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key >> 56) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET + 1)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key >> 48) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET + 2)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key >> 40) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET + 3)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key >> 32) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET + 4)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key >> 24) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET + 5)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key >> 16) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET + 6)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key >> 8) as u8;
-                *region_data
-                    .get_mut(offset + HASH_OFFSET + 7)
-                    .ok_or(ErrorCode::RegionFull)? = (header.hashed_key) as u8;
-
-                // Hash the new header data
-                check_sum.update(
-                    region_data
-                        .get(offset + VERSION_OFFSET..=offset + HASH_OFFSET + 7)
-                        .ok_or(ErrorCode::CorruptData)?,
-                );
+        let check_sum = crc32::Crc32::new();
+        check_sum.update(data);
 
-                // Copy the value
-                let slice = region_data
-                    .get_mut((offset + HEADER_LENGTH)..(offset + package_length))
-                    .ok_or(ErrorCode::ObjectTooLarge)?;
-                slice.copy_from_slice(value);
-
-                // Include the value in the hash
-                check_sum.update(value);
-
-                // Append a Check Hash
-                let check_sum = check_sum.finalise();
-                let slice = region_data
-                    .get_mut((offset + package_length)..(offset + package_length + CHECK_SUM_LEN))
-                    .ok_or(ErrorCode::ObjectTooLarge)?;
-                slice.copy_from_slice(&check_sum.to_ne_bytes());
-
-                // Write the data back to the region
-                if let Err(e) = self.controller.write(
-                    S * new_region + offset,
-                    region_data
-                        .get(offset..(offset + package_length + CHECK_SUM_LEN))
-                        .ok_or(ErrorCode::ObjectTooLarge)?,
-                ) {
-                    self.read_buffer.replace(Some(region_data));
-                    match e {
-                        ErrorCode::WriteNotReady(_) => return Ok(SuccessCode::Queued),
-                        _ => return Err(e),
-                    }
-                }
+        Ok(stored == check_sum.finalise().to_ne_bytes())
+    }
 
-                self.read_buffer.replace(Some(region_data));
-                return Ok(SuccessCode::Written);
-            }
+    /// Check that the object at `offset` in some loaded region data is
+    /// valid and was written completely. An object whose write was
+    /// interrupted fails its check sum, and is treated like an invalidated
+    /// object.
+    fn object_valid(region_data: &[u8], offset: usize, header: ObjectHeader) -> bool {
+        header.is_valid() && Self::check_sum_valid(region_data, offset, header) == Ok(true)
+    }
+
+    /// Get the total length of a value from its head object and the
+    /// number of fragments it is chained over.
+    fn value_length(
+        &self,
+        region_data: &[u8],
+        offset: usize,
+        header: ObjectHeader,
+    ) -> Result<(usize, usize), ErrorCode> {
+        let value = Self::object_value(region_data, offset, header)?;
+
+        if header.flags & FLAGS_CHAINED == 0 {
+            return Ok((value.len(), 0));
         }
+
+        let length = value
+            .get(..CHAIN_LENGTH_LEN)
+            .ok_or(ErrorCode::CorruptData)?;
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+
+        Ok((length, self.fragments(length)))
     }
 
-    /// Retrieves the value from flash storage.
-    ///
-    /// - `hash`: A hashed key.
-    /// - `buf`: A buffer to store the value to.
-    ///
-    /// On success a `SuccessCode` will be returned and the length of the value
-    /// for the corresponding key. On error a `ErrorCode` will be returned.
+    /// Write an object to the start of `buf`. The value of the object is the
+    /// concatenation of `parts`.
     ///
-    /// If a power loss occurs before success is returned the data is assumed to
-    /// be lost.
-    pub fn get_key(&self, hash: u64, buf: &mut [u8]) -> Result<(SuccessCode, usize), ErrorCode> {
-        let region = self.get_region(hash);
+    /// Returns the total length of the object.
+    fn encode_object(
+        buf: &mut [u8],
+        flags: u8,
+        hashed_key: u64,
+        parts: &[&[u8]],
+    ) -> Result<usize, ErrorCode> {
+        let value_length: usize = parts.iter().map(|part| part.len()).sum();
+        let total_length = HEADER_LENGTH + value_length + CHECK_SUM_LEN;
 
-        let mut region_offset: isize = 0;
+        if total_length > MAX_OBJECT_LENGTH {
+            return Err(ErrorCode::ObjectTooLarge);
+        }
 
-        loop {
-            let check_sum = crc32::Crc32::new();
-            let new_region = match self.state.get() {
-                State::None => (region as isize + region_offset) as usize,
-                State::Init(state) => {
-                    match state {
-                        InitState::GetKeyReadRegion(reg) => reg,
-                        _ => {
-                            // Get the data from that region
-                            (region as isize + region_offset) as usize
-                        }
-                    }
-                }
-                State::GetKey(key_state) => match key_state {
-                    KeyState::ReadRegion(reg) => reg,
-                },
-                _ => unreachable!(),
-            };
+        let object = buf.get_mut(..total_length).ok_or(ErrorCode::RegionFull)?;
 
-            // Get the data from that region
-            let region_data = self.read_buffer.take().unwrap();
-            if self.state.get() != State::GetKey(KeyState::ReadRegion(new_region))
-                && self.state.get() != State::Init(InitState::GetKeyReadRegion(new_region))
-            {
-                match self.controller.read_region(new_region, region_data) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        self.read_buffer.replace(Some(region_data));
-                        if let ErrorCode::ReadNotReady(reg) = e {
-                            self.state.set(State::GetKey(KeyState::ReadRegion(reg)));
-                        }
-                        return Err(e);
-                    }
-                };
-            }
+        object[VERSION_OFFSET] = VERSION;
+        object[LEN_OFFSET] = (total_length >> 8) as u8 & 0x0F | (flags << 4) & 0xF0;
+        object[LEN_OFFSET + 1] = (total_length & 0xFF) as u8;
+        object[HASH_OFFSET..HEADER_LENGTH].copy_from_slice(&hashed_key.to_be_bytes());
 
-            match self.find_key_offset(hash, region_data) {
-                Ok((offset, total_length)) => {
-                    // Add the header data to the check hash
-                    check_sum.update(
-                        region_data
-                            .get(offset..(HEADER_LENGTH + offset))
-                            .ok_or(ErrorCode::ObjectTooLarge)?,
-                    );
-
-                    // The size of the stored object's actual data;
-                    let value_length = total_length as usize - HEADER_LENGTH - CHECK_SUM_LEN;
-
-                    // Make sure if will fit in the buffer
-                    if buf.len() < value_length {
-                        // The entire value is not going to fit,
-                        // Let's still copy in what we can and return an error
-                        for i in 0..buf.len() {
-                            *buf.get_mut(i)
-                                .ok_or(ErrorCode::BufferTooSmall(value_length))? = *region_data
-                                .get(offset + HEADER_LENGTH + i)
-                                .ok_or(ErrorCode::BufferTooSmall(value_length))?;
-                        }
+        let mut offset = HEADER_LENGTH;
+        for part in parts {
+            object[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
 
-                        self.read_buffer.replace(Some(region_data));
-                        return Err(ErrorCode::BufferTooSmall(value_length));
-                    }
+        // Append a Check Hash
+        let check_sum = crc32::Crc32::new();
+        check_sum.update(&object[..offset]);
+        object[offset..].copy_from_slice(&check_sum.finalise().to_ne_bytes());
 
-                    // Copy in the value
-                    for i in 0..value_length {
-                        *buf.get_mut(i)
-                            .ok_or(ErrorCode::BufferTooSmall(value_length))? = *region_data
-                            .get(offset + HEADER_LENGTH + i)
-                            .ok_or(ErrorCode::CorruptData)?;
-                        check_sum.update(&[*buf.get(i).ok_or(ErrorCode::CorruptData)?])
-                    }
+        Ok(total_length)
+    }
 
-                    // Check the hash
-                    let check_sum = check_sum.finalise();
-                    let check_sum = check_sum.to_ne_bytes();
-
-                    if *check_sum.get(3).ok_or(ErrorCode::InvalidCheckSum)?
-                        != *region_data
-                            .get(offset + total_length as usize - 1)
-                            .ok_or(ErrorCode::InvalidCheckSum)?
-                        || *check_sum.get(2).ok_or(ErrorCode::InvalidCheckSum)?
-                            != *region_data
-                                .get(offset + total_length as usize - 2)
-                                .ok_or(ErrorCode::InvalidCheckSum)?
-                        || *check_sum.get(1).ok_or(ErrorCode::InvalidCheckSum)?
-                            != *region_data
-                                .get(offset + total_length as usize - 3)
-                                .ok_or(ErrorCode::InvalidCheckSum)?
-                        || *check_sum.first().ok_or(ErrorCode::InvalidCheckSum)?
-                            != *region_data
-                                .get(offset + total_length as usize - 4)
-                                .ok_or(ErrorCode::InvalidCheckSum)?
-                    {
-                        self.read_buffer.replace(Some(region_data));
-                        return Err(ErrorCode::InvalidCheckSum);
-                    }
+    /// Walk the objects in some loaded region data.
+    ///
+    /// If `find` is set the offset and header of the first valid object
+    /// with that hashed key that is (or isn't) a fragment is returned.
+    fn scan_region(
+        &self,
+        region_data: &[u8],
+        find: Option<(u64, bool)>,
+    ) -> Result<RegionInfo, ErrorCode> {
+        // Anything left behind by an interrupted erase also counts.
+        let mut info = RegionInfo {
+            used: region_data.iter().any(|b| *b != 0xFF),
+            ..RegionInfo::default()
+        };
+        let mut offset: usize = 0;
 
-                    self.read_buffer.replace(Some(region_data));
-                    return Ok((SuccessCode::Complete, value_length));
-                }
-                Err((cont, e)) => {
-                    self.read_buffer.replace(Some(region_data));
+        while let Some(header) = self.object_at(region_data, offset)? {
+            // An interrupted write can leave anything behind, including
+            // what looks like a region header. That still uses the region.
+            if offset == 0
+                && header.flags & FLAGS_REGION == FLAGS_REGION
+                && header.len == REGION_HEADER_LENGTH
+                && Self::check_sum_valid(region_data, offset, header)?
+            {
+                let count = Self::object_value(region_data, offset, header)?;
+                info.erase_count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]);
+            } else {
+                info.objects = true;
 
-                    if cont {
-                        region_offset = new_region as isize - region as isize;
-                        match self.increment_region_offset(region, region_offset) {
-                            Some(o) => {
-                                region_offset = o;
-                                self.state.set(State::None);
-                            }
-                            None => {
-                                return Err(e);
-                            }
+                if Self::object_valid(region_data, offset, header) {
+                    info.valid = true;
+
+                    if let Some((hash, fragment)) = find {
+                        if info.found.is_none()
+                            && header.hashed_key == hash
+                            && (header.flags & FLAGS_FRAGMENT == FLAGS_FRAGMENT) == fragment
+                        {
+                            info.found = Some((offset, header));
                         }
-                    } else {
-                        return Err(e);
                     }
                 }
             }
+
+            // Increment our offset by the length and repeat the loop
+            offset += header.len;
         }
+
+        // Only use the space after the last object if it hasn't been
+        // written to, for example by a write or an erase that was
+        // interrupted.
+        info.end = match region_data.get(offset..) {
+            Some(rest) if rest.iter().all(|b| *b == 0xFF) => offset,
+            _ => S,
+        };
+
+        Ok(info)
     }
 
-    /// Invalidates the key in flash storage
-    ///
-    /// `hash`: A hashed key.
+    /// Find a valid object in the regions it could be stored in.
     ///
-    /// On success nothing will be returned.
-    /// On error a `ErrorCode` will be returned.
+    /// The object is searched for in the probe order of its hashed key,
+    /// starting with the region saved in the progress. The search stops at
+    /// a region that has never been used, as nothing is ever stored after
+    /// one. Outside of the wear leveling window it also stops at regions
+    /// without any objects, like version 1 of TicKV does.
     ///
-    /// If a power loss occurs before success is returned the data is
-    /// assumed to be lost.
-    pub fn invalidate_key(&self, hash: u64) -> Result<SuccessCode, ErrorCode> {
-        let region = self.get_region(hash);
+    /// On success the object's region is left in the read buffer and the
+    /// region, offset and header of the object are returned.
+    fn find_object(
+        &self,
+        hash: u64,
+        fragment: bool,
+        state: fn(KeyState) -> State,
+    ) -> Result<(usize, usize, ObjectHeader), ErrorCode> {
+        let home = self.get_region(hash);
 
-        let mut region_offset: isize = 0;
+        loop {
+            let probe = self.progress.get().probe;
+            let region = self
+                .probe_region(home, probe)
+                .ok_or(ErrorCode::KeyNotFound)?;
+
+            let region_data = self.read_region(region, state)?;
+            let info = self.scan_region(region_data, Some((hash, fragment)));
+            self.read_buffer.replace(Some(region_data));
+            let info = info?;
+
+            if let Some((offset, header)) = info.found {
+                return Ok((region, offset, header));
+            }
+
+            if !info.used || (probe + 1 >= WEAR_LEVELING_WINDOW && !info.objects) {
+                return Err(ErrorCode::KeyNotFound);
+            }
+
+            let mut progress = self.progress.get();
+            progress.probe += 1;
+            self.progress.set(progress);
+            self.state.set(State::None);
+        }
+    }
+
+    /// Find the region to add a new object of `object_length` bytes to.
+    ///
+    /// The least worn region in the wear leveling window with enough space
+    /// is used. If none of them have enough space the first region after
+    /// the window with enough space is used. Regions are searched in the
+    /// same order as `find_object()`, so the object will be found again.
+    ///
+    /// If a valid object with the same hashed key is found
+    /// `KeyAlreadyExists` is returned. Fragments are the exception, a
+    /// valid fragment is left behind by an append that was interrupted, so
+    /// it is invalidated instead.
+    fn place_object(
+        &self,
+        hash: u64,
+        fragment: bool,
+        object_length: usize,
+        state: fn(KeyState) -> State,
+    ) -> Result<usize, ErrorCode> {
+        let home = self.get_region(hash);
 
         loop {
-            // Get the data from that region
-            let new_region = match self.state.get() {
-                State::None => (region as isize + region_offset) as usize,
-                State::InvalidateKey(key_state) => match key_state {
-                    KeyState::ReadRegion(reg) => reg,
-                },
-                _ => unreachable!(),
+            let progress = self.progress.get();
+            if let Some(target) = progress.target {
+                return Ok(target);
+            }
+
+            let region = match self.probe_region(home, progress.probe) {
+                Some(region) => region,
+                None => {
+                    let (region, _count) = progress.best.ok_or(ErrorCode::FlashFull)?;
+                    let mut progress = self.progress.get();
+                    progress.target = Some(region);
+                    self.progress.set(progress);
+                    continue;
+                }
             };
 
-            // Get the data from that region
-            let region_data = self.read_buffer.take().unwrap();
-            if self.state.get() != State::InvalidateKey(KeyState::ReadRegion(new_region)) {
-                match self.controller.read_region(new_region, region_data) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        self.read_buffer.replace(Some(region_data));
-                        if let ErrorCode::ReadNotReady(reg) = e {
-                            self.state
-                                .set(State::InvalidateKey(KeyState::ReadRegion(reg)));
-                        }
-                        return Err(e);
+            let region_data = self.read_region(region, state)?;
+            let info = match self.scan_region(region_data, Some((hash, fragment))) {
+                Ok(info) => info,
+                Err(e) => {
+                    self.read_buffer.replace(Some(region_data));
+                    return Err(e);
+                }
+            };
+
+            if let Some((offset, _header)) = info.found {
+                if !fragment {
+                    // Check to make sure we don't already have this key
+                    self.read_buffer.replace(Some(region_data));
+                    return Err(ErrorCode::KeyAlreadyExists);
+                }
+
+                // Invalidate the stale fragment and then check this region
+                // again.
+                region_data[offset + LEN_OFFSET] &= !(FLAGS_VALID << 4);
+                let ret = self.controller.write(
+                    S * region + offset + LEN_OFFSET,
+                    &region_data[offset + LEN_OFFSET..offset + LEN_OFFSET + 1],
+                );
+                self.read_buffer.replace(Some(region_data));
+
+                match ret {
+                    Ok(()) => continue,
+                    Err(ErrorCode::WriteNotReady(address)) => {
+                        self.state.set(state(KeyState::WriteRegion(region)));
+                        return Err(ErrorCode::WriteNotReady(address));
                     }
-                };
+                    Err(e) => return Err(e),
+                }
             }
+            self.read_buffer.replace(Some(region_data));
 
-            match self.find_key_offset(hash, region_data) {
-                Ok((offset, _data_len)) => {
-                    // We found a key, let's delete it
-                    *region_data
-                        .get_mut(offset + LEN_OFFSET)
-                        .ok_or(ErrorCode::CorruptData)? &= !0x80;
-
-                    if let Err(e) = self.controller.write(
-                        S * new_region + offset + LEN_OFFSET,
-                        region_data
-                            .get(offset + LEN_OFFSET..offset + LEN_OFFSET + 1)
-                            .ok_or(ErrorCode::ObjectTooLarge)?,
-                    ) {
-                        self.read_buffer.replace(Some(region_data));
-                        match e {
-                            ErrorCode::WriteNotReady(_) => return Ok(SuccessCode::Queued),
-                            _ => return Err(e),
-                        }
-                    }
+            let mut progress = self.progress.get();
+            if info.end + object_length <= S
+                && progress
+                    .best
+                    .map_or(true, |(_region, count)| info.erase_count < count)
+            {
+                progress.best = Some((region, info.erase_count));
+            }
 
-                    self.read_buffer.replace(Some(region_data));
-                    return Ok(SuccessCode::Written);
+            match progress.best {
+                // Nothing is stored after a region that has never been used
+                // and no region is less worn than one that has never been
+                // erased.
+                Some((region, count))
+                    if !info.used || count == 0 || progress.probe + 1 >= WEAR_LEVELING_WINDOW =>
+                {
+                    progress.target = Some(region);
                 }
-                Err((cont, e)) => {
-                    self.read_buffer.replace(Some(region_data));
+                // The object doesn't even fit in an empty region.
+                None if !info.used => return Err(ErrorCode::ObjectTooLarge),
+                _ => {
+                    progress.probe += 1;
+                    self.state.set(State::None);
+                }
+            }
+            self.progress.set(progress);
+        }
+    }
 
-                    if cont {
-                        region_offset = new_region as isize - region as isize;
-                        match self.increment_region_offset(region, region_offset) {
-                            Some(o) => {
-                                region_offset = o;
-                                self.state.set(State::None);
-                            }
-                            None => {
-                                return Err(e);
-                            }
-                        }
-                    } else {
-                        return Err(e);
+    /// Appends the key/value pair to flash storage.
+    ///
+    /// `hash`: A hashed key. This key will be used in future to retrieve
+    ///         or remove the `value`.
+    /// `value`: A buffer containing the data to be stored to flash.
+    ///
+    /// Values that don't fit in a single object are chained over multiple
+    /// objects. The fragments of the value are written first and the head
+    /// object is written last, so a value is only found once all of it has
+    /// been written.
+    ///
+    /// When using an async `FlashController` the operation has to be
+    /// continued after each write that returns `WriteNotReady` as an error.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn append_key(&self, hash: u64, value: &[u8]) -> Result<SuccessCode, ErrorCode> {
+        if self.state.get() == State::None {
+            self.progress.set(Progress::default());
+        }
+
+        let length = u32::try_from(value.len()).map_err(|_| ErrorCode::ObjectTooLarge)?;
+        let fragments = self.fragments(value.len());
+
+        loop {
+            let progress = self.progress.get();
+
+            if fragments > 0 && progress.step == 0 {
+                // Make sure that the key doesn't exist before writing any
+                // fragments.
+                match self.find_object(hash, false, State::AppendKey) {
+                    Ok(_) => return Err(ErrorCode::KeyAlreadyExists),
+                    Err(ErrorCode::KeyNotFound) => {
+                        self.next_object();
+                        continue;
                     }
+                    Err(e) => return Err(e),
                 }
             }
+
+            // Write the fragments from the last one to the first one, then
+            // the head object.
+            let index = if fragments == 0 {
+                0
+            } else {
+                fragments + 1 - progress.step
+            };
+            let (start, end) = self.chunk(value.len(), index);
+            let chunk = value.get(start..end).ok_or(ErrorCode::ObjectTooLarge)?;
+            let length = length.to_be_bytes();
+            let head_parts = [&length[..], chunk];
+            let fragment_parts = [chunk];
+
+            let (flags, parts): (u8, &[&[u8]]) = if index > 0 {
+                let chained = if index < fragments { FLAGS_CHAINED } else { 0 };
+                (FLAGS_VALID | FLAGS_FRAGMENT | chained, &fragment_parts)
+            } else if fragments > 0 {
+                (FLAGS_VALID | FLAGS_CHAINED, &head_parts)
+            } else {
+                (FLAGS_VALID, &fragment_parts)
+            };
+            let object_hash = Self::object_hash(hash, index);
+            let object_length =
+                HEADER_LENGTH + parts.iter().map(|part| part.len()).sum::<usize>() + CHECK_SUM_LEN;
+
+            if object_length > MAX_OBJECT_LENGTH {
+                return Err(ErrorCode::ObjectTooLarge);
+            }
+
+            let region =
+                self.place_object(object_hash, index > 0, object_length, State::AppendKey)?;
+            let region_data = self.read_region(region, State::AppendKey)?;
+            let ret = self.write_object(region_data, region, flags, object_hash, parts);
+            self.read_buffer.replace(Some(region_data));
+
+            match ret {
+                Ok(()) if index == 0 => return Ok(SuccessCode::Written),
+                Ok(()) => self.next_object(),
+                Err(ErrorCode::WriteNotReady(_)) if index == 0 => return Ok(SuccessCode::Queued),
+                Err(ErrorCode::WriteNotReady(address)) => {
+                    self.next_object();
+                    self.state
+                        .set(State::AppendKey(KeyState::WriteRegion(region)));
+                    return Err(ErrorCode::WriteNotReady(address));
+                }
+                Err(e) => return Err(e),
+            }
         }
     }
 
+    /// Write a new object after the last object of the region in
+    /// `region_data`.
+    fn write_object(
+        &self,
+        region_data: &mut [u8; S],
+        region: usize,
+        flags: u8,
+        hashed_key: u64,
+        parts: &[&[u8]],
+    ) -> Result<(), ErrorCode> {
+        let offset = self.scan_region(region_data, None)?.end;
+        let length = Self::encode_object(
+            region_data.get_mut(offset..).ok_or(ErrorCode::RegionFull)?,
+            flags,
+            hashed_key,
+            parts,
+        )?;
+
+        // Write the data back to the region
+        self.controller
+            .write(S * region + offset, &region_data[offset..offset + length])
+    }
+
+    /// Retrieves the value from flash storage.
+    ///
+    /// - `hash`: A hashed key.
+    /// - `buf`: A buffer to store the value to.
+    ///
+    /// On success a `SuccessCode` will be returned and the length of the value
+    /// for the corresponding key. On error a `ErrorCode` will be returned.
+    ///
+    /// If a power loss occurs before success is returned the data is assumed to
+    /// be lost.
+    pub fn get_key(&self, hash: u64, buf: &mut [u8]) -> Result<(SuccessCode, usize), ErrorCode> {
+        if self.state.get() == State::None {
+            self.progress.set(Progress::default());
+        }
+
+        loop {
+            let step = self.progress.get().step;
+            let (_region, offset, header) =
+                match self.find_object(Self::object_hash(hash, step), step > 0, State::GetKey) {
+                    Ok(found) => found,
+                    // The head object is only written once all of the
+                    // fragments have been written.
+                    Err(ErrorCode::KeyNotFound) if step > 0 => return Err(ErrorCode::CorruptData),
+                    Err(e) => return Err(e),
+                };
+
+            let region_data = self.read_buffer.take().unwrap();
+            let ret = self.copy_value(region_data, offset, header, buf);
+            self.read_buffer.replace(Some(region_data));
+
+            let progress = self.progress.get();
+            if !ret? {
+                self.next_object();
+                continue;
+            }
+
+            if buf.len() < progress.total {
+                // The entire value is not going to fit, what fits has been
+                // copied in.
+                return Err(ErrorCode::BufferTooSmall(progress.total));
+            }
+
+            return Ok((SuccessCode::Complete, progress.total));
+        }
+    }
+
+    /// Copy the part of a value stored in the object at `offset` into
+    /// `buf`.
+    ///
+    /// Returns true once the entire value has been copied, or once `buf`
+    /// is full.
+    fn copy_value(
+        &self,
+        region_data: &[u8],
+        offset: usize,
+        header: ObjectHeader,
+        buf: &mut [u8],
+    ) -> Result<bool, ErrorCode> {
+        let mut progress = self.progress.get();
+
+        if !Self::check_sum_valid(region_data, offset, header)? {
+            return Err(ErrorCode::InvalidCheckSum);
+        }
+
+        let mut value = Self::object_value(region_data, offset, header)?;
+        if progress.step == 0 {
+            progress.total = self.value_length(region_data, offset, header)?.0;
+            if header.flags & FLAGS_CHAINED == FLAGS_CHAINED {
+                value = &value[CHAIN_LENGTH_LEN..];
+            }
+        }
+
+        if progress.done + value.len() > progress.total
+            || (header.flags & FLAGS_CHAINED == 0 && progress.done + value.len() != progress.total)
+        {
+            return Err(ErrorCode::CorruptData);
+        }
+
+        if let Some(dest) = buf.get_mut(progress.done..) {
+            let copy_length = core::cmp::min(dest.len(), value.len());
+            dest[..copy_length].copy_from_slice(&value[..copy_length]);
+        }
+        progress.done += value.len();
+        self.progress.set(progress);
+
+        Ok(progress.done == progress.total || progress.done >= buf.len())
+    }
+
+    /// Invalidates the key in flash storage
+    ///
+    /// `hash`: A hashed key.
+    ///
+    /// The head object of a chained value is invalidated first, so the key
+    /// is removed even if a power loss occurs before all of the fragments
+    /// have been invalidated.
+    ///
+    /// On success nothing will be returned.
+    /// On error a `ErrorCode` will be returned.
+    ///
+    /// If a power loss occurs before success is returned the data is
+    /// assumed to be lost.
+    pub fn invalidate_key(&self, hash: u64) -> Result<SuccessCode, ErrorCode> {
+        self.remove_key(hash, false, State::InvalidateKey)
+    }
+
     /// Zeroises the key in flash storage.
     ///
     /// This is similar to the `invalidate_key()` function, but instead will
@@ -883,7 +1180,8 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     /// not remove the header, as that is required for garbage collection
     /// later on, so the length and hashed key will still be preserved.
     ///
-    /// The values will be changed by a single write operation to the flash.
+    /// The values will be changed by a single write operation to the flash
+    /// for each object the value is stored in.
     /// The values are not securley overwritten to make restoring data
     /// difficult.
     ///
@@ -900,87 +1198,98 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
     /// If a power loss occurs before success is returned the data is
     /// assumed to be lost.
     pub fn zeroise_key(&self, hash: u64) -> Result<SuccessCode, ErrorCode> {
-        let region = self.get_region(hash);
+        self.remove_key(hash, true, State::ZeroiseKey)
+    }
 
-        let mut region_offset: isize = 0;
+    /// Invalidate, and optionally zeroise, all of the objects of a key.
+    fn remove_key(
+        &self,
+        hash: u64,
+        zeroise: bool,
+        state: fn(KeyState) -> State,
+    ) -> Result<SuccessCode, ErrorCode> {
+        if self.state.get() == State::None {
+            self.progress.set(Progress::default());
+        }
 
         loop {
-            // Get the data from that region
-            let new_region = match self.state.get() {
-                State::None => (region as isize + region_offset) as usize,
-                State::ZeroiseKey(key_state) => match key_state {
-                    KeyState::ReadRegion(reg) => reg,
-                },
-                _ => unreachable!(),
+            let progress = self.progress.get();
+            let (region, offset, header) = match self.find_object(
+                Self::object_hash(hash, progress.step),
+                progress.step > 0,
+                state,
+            ) {
+                Ok(found) => found,
+                // The fragment has already been removed, for example by an
+                // invalidate that was interrupted.
+                Err(ErrorCode::KeyNotFound) if progress.step > 0 => {
+                    if progress.step >= progress.fragments {
+                        return Ok(SuccessCode::Complete);
+                    }
+                    self.next_object();
+                    continue;
+                }
+                Err(e) => return Err(e),
             };
 
-            // Get the data from that region
             let region_data = self.read_buffer.take().unwrap();
-            if self.state.get() != State::ZeroiseKey(KeyState::ReadRegion(new_region)) {
-                match self.controller.read_region(new_region, region_data) {
-                    Ok(()) => {}
-                    Err(e) => {
-                        self.read_buffer.replace(Some(region_data));
-                        if let ErrorCode::ReadNotReady(reg) = e {
-                            self.state.set(State::ZeroiseKey(KeyState::ReadRegion(reg)));
-                        }
-                        return Err(e);
-                    }
-                };
+            let ret = self.remove_object(region_data, region, offset, header, zeroise);
+            self.read_buffer.replace(Some(region_data));
+
+            let progress = self.progress.get();
+            let last = progress.step >= progress.fragments;
+            match ret {
+                Ok(()) if last => return Ok(SuccessCode::Written),
+                Ok(()) => self.next_object(),
+                Err(ErrorCode::WriteNotReady(_)) if last => return Ok(SuccessCode::Queued),
+                Err(ErrorCode::WriteNotReady(address)) => {
+                    self.next_object();
+                    self.state.set(state(KeyState::WriteRegion(region)));
+                    return Err(ErrorCode::WriteNotReady(address));
+                }
+                Err(e) => return Err(e),
             }
+        }
+    }
 
-            match self.find_key_offset(hash, region_data) {
-                Ok((offset, data_len)) => {
-                    // We found a key, let's delete it
-                    *region_data
-                        .get_mut(offset + LEN_OFFSET)
-                        .ok_or(ErrorCode::CorruptData)? &= !0x80;
-
-                    // Replace Value with 0s
-                    for i in HEADER_LENGTH..(data_len as usize + HEADER_LENGTH) {
-                        *region_data
-                            .get_mut(offset + i)
-                            .ok_or(ErrorCode::RegionFull)? = 0;
-                    }
-
-                    let write_len = data_len as usize;
-
-                    if let Err(e) = self.controller.write(
-                        S * new_region + offset,
-                        region_data
-                            .get(offset..offset + write_len)
-                            .ok_or(ErrorCode::ObjectTooLarge)?,
-                    ) {
-                        self.read_buffer.replace(Some(region_data));
-                        match e {
-                            ErrorCode::WriteNotReady(_) => return Ok(SuccessCode::Queued),
-                            _ => return Err(e),
-                        }
-                    }
+    /// Invalidate, and optionally zeroise, the object at `offset` of the
+    /// region in `region_data`.
+    fn remove_object(
+        &self,
+        region_data: &mut [u8; S],
+        region: usize,
+        offset: usize,
+        header: ObjectHeader,
+        zeroise: bool,
+    ) -> Result<(), ErrorCode> {
+        let mut progress = self.progress.get();
+        if progress.step == 0 {
+            progress.fragments = self.value_length(region_data, offset, header)?.1;
+            self.progress.set(progress);
+        }
 
-                    self.read_buffer.replace(Some(region_data));
-                    return Ok(SuccessCode::Written);
-                }
-                Err((cont, e)) => {
-                    self.read_buffer.replace(Some(region_data));
+        // We found a key, let's delete it
+        *region_data
+            .get_mut(offset + LEN_OFFSET)
+            .ok_or(ErrorCode::CorruptData)? &= !(FLAGS_VALID << 4);
+
+        let (start, end) = if zeroise {
+            // Replace Value with 0s
+            region_data
+                .get_mut(offset + HEADER_LENGTH..offset + header.len)
+                .ok_or(ErrorCode::CorruptData)?
+                .fill(0);
+            (offset, offset + header.len)
+        } else {
+            (offset + LEN_OFFSET, offset + LEN_OFFSET + 1)
+        };
 
-                    if cont {
-                        region_offset = new_region as isize - region as isize;
-                        match self.increment_region_offset(region, region_offset) {
-                            Some(o) => {
-                                region_offset = o;
-                                self.state.set(State::None);
-                            }
-                            None => {
-                                return Err(e);
-                            }
-                        }
-                    } else {
-                        return Err(e);
-                    }
-                }
-            }
-        }
+        self.controller.write(
+            S * region + start,
+            region_data
+                .get(start..end)
+                .ok_or(ErrorCode::ObjectTooLarge)?,
+        )
     }
 
     fn garbage_collect_region(
@@ -1008,80 +1317,19 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
             };
         }
 
-        let mut entry_found = false;
-        let mut offset: usize = 0;
-
-        loop {
-            if offset >= S {
-                // We have reached the end of the region without finding a
-                // valid object. All entries must be marked for deletion then.
-                break;
-            }
-
-            // Check to see if we have data
-            if *region_data
-                .get(offset + VERSION_OFFSET)
-                .ok_or(ErrorCode::KeyNotFound)?
-                != 0xFF
-            {
-                // We found a version, check that we support it
-                if *region_data
-                    .get(offset + VERSION_OFFSET)
-                    .ok_or(ErrorCode::KeyNotFound)?
-                    != VERSION
-                {
-                    self.read_buffer.replace(Some(region_data));
-                    return Err(ErrorCode::UnsupportedVersion);
-                }
-
-                entry_found = true;
-
-                // Find this entries length
-                let total_length = ((*region_data
-                    .get(offset + LEN_OFFSET)
-                    .ok_or(ErrorCode::CorruptData)? as u16)
-                    & !0xF0)
-                    << 8
-                    | *region_data
-                        .get(offset + LEN_OFFSET + 1)
-                        .ok_or(ErrorCode::CorruptData)? as u16;
-
-                // Check to see if the entry has been deleted
-                if *region_data
-                    .get(offset + LEN_OFFSET)
-                    .ok_or(ErrorCode::CorruptData)?
-                    & 0x80
-                    != 0x80
-                {
-                    // The entry has been deleted, this region might be ready
-                    // for erasure.
-                    // Increment our offset by the length and repeat the loop
-                    offset += total_length as usize;
-                    continue;
-                }
+        let info = self.scan_region(region_data, None);
+        self.read_buffer.replace(Some(region_data));
+        let info = info?;
 
-                // We have found a valid entry!
-                // Don't perform an erase!
-                self.read_buffer.replace(Some(region_data));
-                return Ok(0);
-            } else {
-                // We hit the end of valid data.
-                // The possible outcomes:
-                //    * The region is empty, we don't need to do anything
-                //    * The region has entries, all of which are marked for
-                //      deletion
-                if !entry_found {
-                    // We didn't find anything, don't bother erasing an empty region.
-                    self.read_buffer.replace(Some(region_data));
-                    return Ok(0);
-                }
-                break;
-            }
+        // Only erase regions without valid objects. Don't bother erasing a
+        // region with nothing but a region header, but do erase one left
+        // behind by an interrupted erase.
+        if info.valid || (!info.objects && info.end < S) {
+            return Ok(0);
         }
 
-        self.read_buffer.replace(Some(region_data));
-
         // If we got down here, the region is ready to be erased.
+        let erase_count = info.erase_count.saturating_add(1);
 
         if let Err(e) = self.controller.erase_region(region) {
             if let ErrorCode::EraseNotReady(reg) = e {
@@ -1089,16 +1337,58 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     .set(State::GarbageCollect(RubbishState::EraseRegion(
                         reg,
                         flash_freed + S,
+                        erase_count,
                     )));
             }
             return Err(e);
         }
 
+        self.write_region_header(
+            region,
+            erase_count,
+            State::GarbageCollect(RubbishState::WriteHeader(region, flash_freed + S)),
+        )?;
+
         Ok(S)
     }
 
+    /// Write the region header recording the erase count of a region that
+    /// has just been erased.
+    ///
+    /// If a power loss occurs before the header is written the region is
+    /// left without a header, which is the same as an erase count of 0.
+    ///
+    /// `state` is the state to continue from if the write completes
+    /// asynchronously.
+    fn write_region_header(
+        &self,
+        region: usize,
+        erase_count: u32,
+        state: State,
+    ) -> Result<(), ErrorCode> {
+        let mut header = [0xFF; REGION_HEADER_LENGTH];
+        Self::encode_object(
+            &mut header,
+            FLAGS_VALID | FLAGS_REGION,
+            0,
+            &[&erase_count.to_be_bytes()],
+        )?;
+
+        match self.controller.write(S * region, &header) {
+            Ok(()) => Ok(()),
+            Err(ErrorCode::WriteNotReady(address)) => {
+                self.state.set(state);
+                Err(ErrorCode::WriteNotReady(address))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     /// Perform a garbage collection on TicKV
     ///
+    /// Each erased region gets a region header with its erase count, which
+    /// is used to spread new objects over the least worn regions.
+    ///
     /// On success the number of bytes freed will be returned.
     /// On error a `ErrorCode` will be returned.
     pub fn garbage_collect(&self) -> Result<usize, ErrorCode> {
@@ -1111,8 +1401,18 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
                     flash_freed += ff;
                     reg
                 }
-                // We already erased region reg, so move to the next one
-                RubbishState::EraseRegion(reg, ff) => {
+                // We already erased region reg, write its header and then
+                // move to the next one
+                RubbishState::EraseRegion(reg, ff, erase_count) => {
+                    self.write_region_header(
+                        reg,
+                        erase_count,
+                        State::GarbageCollect(RubbishState::WriteHeader(reg, ff)),
+                    )?;
+                    flash_freed += ff;
+                    reg + 1
+                }
+                RubbishState::WriteHeader(reg, ff) => {
                     flash_freed += ff;
                     reg + 1
                 }
@@ -1129,4 +1429,189 @@ impl<'a, C: FlashController<S>, const S: usize> TicKV<'a, C, S> {
 
         Ok(flash_freed)
     }
+
+    /// Get the length of the value of a key from its head object, and the
+    /// part of the value stored in the head object.
+    fn key_value<'b>(
+        &self,
+        region_data: &'b [u8],
+        offset: usize,
+        header: ObjectHeader,
+    ) -> Result<(usize, &'b [u8]), ErrorCode> {
+        let (value_length, _fragments) = self.value_length(region_data, offset, header)?;
+        let value = Self::object_value(region_data, offset, header)?;
+
+        if header.flags & FLAGS_CHAINED == FLAGS_CHAINED {
+            Ok((value_length, &value[CHAIN_LENGTH_LEN..]))
+        } else {
+            Ok((value_length, value))
+        }
+    }
+
+    /// Finds the next valid key in flash storage.
+    ///
+    /// Keys are returned in the order they are stored, region by region.
+    /// This can be used to enumerate all of the stored keys by starting
+    /// with a `cursor` of 0 and then passing the `next` field of each
+    /// returned `KeyEntry` until `KeyNotFound` is returned.
+    ///
+    /// `cursor`: Where to start searching.
+    /// `buf`: A buffer to store the value of the key to. If the value is
+    ///        longer than `buf` only the start of it is copied. Only the
+    ///        part of a chained value stored in its head object is copied.
+    ///
+    /// The check sum of the value is not verified, use `get_key()` to
+    /// retrieve a verified copy of the value.
+    ///
+    /// On success a `SuccessCode` and the `KeyEntry` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn get_next_key(
+        &self,
+        cursor: usize,
+        buf: &mut [u8],
+    ) -> Result<(SuccessCode, KeyEntry), ErrorCode> {
+        let num_region = self.flash_size / S;
+        let mut region = match self.state.get() {
+            State::None => cursor / S,
+            State::GetNextKey(KeyState::ReadRegion(reg)) => reg,
+            _ => unreachable!(),
+        };
+
+        while region < num_region {
+            // Get the data from that region
+            let region_data = self.read_buffer.take().unwrap();
+            if self.state.get() != State::GetNextKey(KeyState::ReadRegion(region)) {
+                match self.controller.read_region(region, region_data) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        self.read_buffer.replace(Some(region_data));
+                        if let ErrorCode::ReadNotReady(reg) = e {
+                            self.state.set(State::GetNextKey(KeyState::ReadRegion(reg)));
+                        }
+                        return Err(e);
+                    }
+                };
+            }
+
+            // Only objects at or after the cursor are returned. Walk the
+            // region from the start so that a cursor that doesn't point at
+            // an object can't be used to parse the middle of a value.
+            let start = if region == cursor / S { cursor % S } else { 0 };
+            let mut offset: usize = 0;
+
+            loop {
+                match self.object_at(region_data, offset) {
+                    Ok(Some(header)) => {
+                        if header.is_key()
+                            && offset >= start
+                            && Self::object_valid(region_data, offset, header)
+                        {
+                            let value = self.key_value(region_data, offset, header);
+                            let (value_length, copied_length) = match value {
+                                Ok((value_length, value)) => {
+                                    let copied_length = core::cmp::min(value.len(), buf.len());
+                                    buf[..copied_length].copy_from_slice(&value[..copied_length]);
+                                    (value_length, copied_length)
+                                }
+                                Err(e) => {
+                                    self.read_buffer.replace(Some(region_data));
+                                    return Err(e);
+                                }
+                            };
+
+                            self.read_buffer.replace(Some(region_data));
+                            return Ok((
+                                SuccessCode::Complete,
+                                KeyEntry {
+                                    hashed_key: header.hashed_key,
+                                    region,
+                                    value_length,
+                                    copied_length,
+                                    next: S * region + offset + header.len,
+                                },
+                            ));
+                        }
+
+                        // Increment our offset by the length and repeat the loop
+                        offset += header.len;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.read_buffer.replace(Some(region_data));
+                        return Err(e);
+                    }
+                }
+            }
+
+            // Nothing left in this region, try the next one
+            self.read_buffer.replace(Some(region_data));
+            self.state.set(State::None);
+            region += 1;
+        }
+
+        Err(ErrorCode::KeyNotFound)
+    }
+
+    /// Collects statistics about the space used in flash storage.
+    ///
+    /// On success a `SuccessCode` and the `StorageStats` will be returned.
+    /// On error a `ErrorCode` will be returned.
+    pub fn get_stats(&self) -> Result<(SuccessCode, StorageStats), ErrorCode> {
+        let num_region = self.flash_size / S;
+        let (start, mut stats) = match self.state.get() {
+            State::None => (0, StorageStats::default()),
+            State::GetStats(stats_state) => match stats_state {
+                StatsState::ReadRegion(reg, stats) => (reg, stats),
+            },
+            _ => unreachable!(),
+        };
+
+        for region in start..num_region {
+            // Get the data from that region
+            let region_data = self.read_buffer.take().unwrap();
+            if self.state.get() != State::GetStats(StatsState::ReadRegion(region, stats)) {
+                match self.controller.read_region(region, region_data) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        self.read_buffer.replace(Some(region_data));
+                        if let ErrorCode::ReadNotReady(reg) = e {
+                            self.state
+                                .set(State::GetStats(StatsState::ReadRegion(reg, stats)));
+                        }
+                        return Err(e);
+                    }
+                };
+            }
+
+            let mut offset: usize = 0;
+
+            loop {
+                match self.object_at(region_data, offset) {
+                    Ok(Some(header)) => {
+                        if Self::object_valid(region_data, offset, header) {
+                            if header.is_key() {
+                                stats.keys += 1;
+                            }
+                            stats.used += header.len;
+                        } else {
+                            stats.reclaimable += header.len;
+                        }
+                        offset += header.len;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.read_buffer.replace(Some(region_data));
+                        return Err(e);
+                    }
+                }
+            }
+
+            stats.free += S.saturating_sub(offset);
+
+            self.read_buffer.replace(Some(region_data));
+            self.state.set(State::None);
+        }
+
+        Ok((SuccessCode::Complete, stats))
+    }
 }
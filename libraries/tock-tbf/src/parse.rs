@@ -129,6 +129,7 @@ pub fn parse_tbf_header(
                 let mut storage_permissions_pointer: Option<&'static [u8]> = None;
                 let mut kernel_version: Option<types::TbfHeaderV2KernelVersion> = None;
                 let mut short_id: Option<types::TbfHeaderV2ShortId> = None;
+                let mut nonvolatile_storage: Option<types::TbfHeaderV2NonvolatileStorage> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -273,6 +274,22 @@ pub fn parse_tbf_header(
                             }
                         }
 
+                        types::TbfHeaderTypes::TbfHeaderNonvolatileStorage => {
+                            let entry_len = mem::size_of::<types::TbfHeaderV2NonvolatileStorage>();
+                            if tlv_header.length as usize == entry_len {
+                                nonvolatile_storage = Some(
+                                    remaining
+                                        .get(0..entry_len)
+                                        .ok_or(types::TbfParseError::NotEnoughFlash)?
+                                        .try_into()?,
+                                );
+                            } else {
+                                return Err(types::TbfParseError::BadTlvEntry(
+                                    tlv_header.tipe as usize,
+                                ));
+                            }
+                        }
+
                         _ => {}
                     }
 
@@ -297,6 +314,7 @@ pub fn parse_tbf_header(
                     storage_permissions: storage_permissions_pointer,
                     kernel_version,
                     short_id,
+                    nonvolatile_storage,
                 };
 
                 Ok(types::TbfHeader::TbfHeaderV2(tbf_header))
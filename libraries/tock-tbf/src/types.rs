@@ -131,6 +131,7 @@ pub enum TbfHeaderTypes {
     TbfHeaderKernelVersion = 8,
     TbfHeaderProgram = 9,
     TbfHeaderShortId = 10,
+    TbfHeaderNonvolatileStorage = 11,
     TbfFooterCredentials = 128,
 
     /// Some field in the header that we do not understand. Since the TLV format
@@ -250,6 +251,29 @@ pub struct TbfHeaderV2ShortId {
     short_id: Option<core::num::NonZeroU32>,
 }
 
+/// The v2 nonvolatile storage request for apps.
+///
+/// Header to specify how many bytes of the userspace nonvolatile storage
+/// region the app needs. The value of the TLV is a single little endian `u32`
+/// holding the size in bytes.
+///
+/// The `nonvolatile_storage_driver` capsule allocates the region when the
+/// process first accesses the storage, keyed by the write ID of the process's
+/// storage permissions, so a process without a write ID cannot use it.
+/// Processes without this header get a region of the capsule's default size
+/// (`DEFAULT_APP_REGION_SIZE`). A request is refused with `NOMEM` if the region does not fit in
+/// the remaining storage. Once allocated a region keeps its size, so a later
+/// version of the process with a different size still gets the original
+/// region.
+///
+/// TLV type `11` must be allocated for this header in the TBF specification
+/// and in the tools that write and parse TBF headers (elf2tab and tockloader)
+/// before it is used, so that they agree on its number and layout.
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2NonvolatileStorage {
+    size: u32,
+}
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TbfFooterV2CredentialsType {
     Reserved = 0,
@@ -330,6 +354,7 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             8 => Ok(TbfHeaderTypes::TbfHeaderKernelVersion),
             9 => Ok(TbfHeaderTypes::TbfHeaderProgram),
             10 => Ok(TbfHeaderTypes::TbfHeaderShortId),
+            11 => Ok(TbfHeaderTypes::TbfHeaderNonvolatileStorage),
             128 => Ok(TbfHeaderTypes::TbfFooterCredentials),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
@@ -588,6 +613,20 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2ShortId {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2NonvolatileStorage {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2NonvolatileStorage, Self::Error> {
+        Ok(TbfHeaderV2NonvolatileStorage {
+            size: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
 impl core::convert::TryFrom<&'static [u8]> for TbfFooterV2Credentials {
     type Error = TbfParseError;
 
@@ -661,6 +700,7 @@ pub struct TbfHeaderV2 {
     pub(crate) storage_permissions: Option<&'static [u8]>,
     pub(crate) kernel_version: Option<TbfHeaderV2KernelVersion>,
     pub(crate) short_id: Option<TbfHeaderV2ShortId>,
+    pub(crate) nonvolatile_storage: Option<TbfHeaderV2NonvolatileStorage>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -1022,4 +1062,13 @@ impl TbfHeader {
             _ => None,
         }
     }
+
+    /// Return the number of bytes of nonvolatile storage the application
+    /// requested, if it was specified in the TBF header.
+    pub fn get_nonvolatile_storage_size(&self) -> Option<u32> {
+        match self {
+            TbfHeader::TbfHeaderV2(hd) => hd.nonvolatile_storage.map(|ns| ns.size),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,171 @@
+#!/usr/bin/env python3
+
+# Licensed under the Apache License, Version 2.0 or the MIT License.
+# SPDX-License-Identifier: Apache-2.0 OR MIT
+# Copyright Tock Contributors 2024.
+
+"""
+Converts the record stream of the IEEE 802.15.4 sniffer capsule
+(capsules/extra/src/ieee802154/sniffer.rs) into a PCAP file.
+
+Usage: ieee802154_pcap.py [-b BAUD] INPUT OUTPUT
+
+INPUT is a file or serial device with the sniffer's UART output, or `-` for
+stdin. OUTPUT is the PCAP file to write, or `-` for stdout. Packets are
+flushed as they arrive, so the capture can be watched live:
+
+    tools/ieee802154_pcap.py /dev/ttyACM0 - | wireshark -k -i -
+
+If INPUT is a serial device and pyserial is installed, it is opened at BAUD
+(default 115200); otherwise the device must be configured beforehand, e.g.
+with `stty -F /dev/ttyACM0 115200 raw`.
+
+Frames are written with link type LINKTYPE_IEEE802_15_4_TAP (283), which
+carries the LQI of every frame. Use --no-tap to write plain
+LINKTYPE_IEEE802_15_4_NOFCS (230) frames.
+"""
+
+import argparse
+import struct
+import sys
+
+RECORD_SYNC = b"\xc5\x15"
+RECORD_VERSION = 2
+RECORD_HEADER = struct.Struct("<2sBBHIIBHB")
+MAX_FRAME_LEN = 127
+
+FLAG_CRC_VALID = 1 << 0
+
+LINKTYPE_IEEE802_15_4_NOFCS = 230
+LINKTYPE_IEEE802_15_4_TAP = 283
+
+# TAP TLV types
+TAP_FCS_TYPE = 0
+TAP_LQI = 10
+
+TAP_FCS_NONE = 0
+
+
+def open_input(path, baud):
+    if path == "-":
+        return sys.stdin.buffer
+    try:
+        import serial
+
+        if path.startswith("/dev/"):
+            return serial.Serial(path, baud)
+    except ImportError:
+        pass
+    return open(path, "rb")
+
+
+def open_output(path):
+    if path == "-":
+        return sys.stdout.buffer
+    return open(path, "wb")
+
+
+def tap_header(lqi):
+    tlvs = struct.pack("<HHB3x", TAP_FCS_TYPE, 1, TAP_FCS_NONE)
+    tlvs += struct.pack("<HHB3x", TAP_LQI, 1, lqi)
+    return struct.pack("<BBH", 0, 0, 4 + len(tlvs)) + tlvs
+
+
+class Unwrapper:
+    """Turns the sniffer's wrapping tick counter into seconds since the
+    first record. Assumes records arrive more often than the counter wraps."""
+
+    def __init__(self):
+        self.first = None
+        self.last = None
+        self.offset = 0
+
+    def seconds(self, ticks, frequency, width):
+        if self.first is None:
+            self.first = ticks
+            self.last = ticks
+        if ticks < self.last:
+            self.offset += 1 << width
+        self.last = ticks
+        return (self.offset + ticks - self.first) / frequency
+
+
+def records(stream):
+    """Yields (header fields, frame) for every record in the stream,
+    resynchronizing on the sync bytes after corrupted data."""
+    buf = b""
+    while True:
+        chunk = stream.read(1) if hasattr(stream, "in_waiting") else stream.read1(4096)
+        if not chunk:
+            return
+        buf += chunk
+        while True:
+            start = buf.find(RECORD_SYNC)
+            if start < 0:
+                buf = buf[-1:]
+                break
+            buf = buf[start:]
+            if len(buf) < RECORD_HEADER.size:
+                break
+            fields = RECORD_HEADER.unpack_from(buf)
+            version, length, frequency = fields[1], fields[3], fields[5]
+            if version != RECORD_VERSION or length > MAX_FRAME_LEN or frequency == 0:
+                # Not a real record; skip the false sync.
+                buf = buf[1:]
+                continue
+            end = RECORD_HEADER.size + length
+            if len(buf) < end:
+                break
+            yield fields, buf[RECORD_HEADER.size : end]
+            buf = buf[end:]
+
+
+def convert(source, out, tap=True):
+    """Writes a PCAP file with the frames of the record stream `source`."""
+    linktype = LINKTYPE_IEEE802_15_4_TAP if tap else LINKTYPE_IEEE802_15_4_NOFCS
+    out.write(struct.pack("<IHHiIII", 0xA1B2C3D4, 2, 4, 0, 0, 65535, linktype))
+    out.flush()
+
+    clock = Unwrapper()
+    for fields, frame in records(source):
+        _, _, flags, _, ticks, frequency, lqi, dropped, width = fields
+        if dropped:
+            print("sniffer dropped {} frame(s)".format(dropped), file=sys.stderr)
+        if not flags & FLAG_CRC_VALID:
+            print("frame with invalid FCS", file=sys.stderr)
+        if tap:
+            frame = tap_header(lqi) + frame
+        seconds = clock.seconds(ticks, frequency, width)
+        out.write(
+            struct.pack(
+                "<IIII",
+                int(seconds),
+                int((seconds % 1) * 1000000),
+                len(frame),
+                len(frame),
+            )
+        )
+        out.write(frame)
+        out.flush()
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Convert sniffer output to PCAP.")
+    parser.add_argument("input")
+    parser.add_argument("output")
+    parser.add_argument("-b", "--baud", type=int, default=115200)
+    parser.add_argument(
+        "--no-tap", action="store_true", help="write frames without TAP metadata"
+    )
+    args = parser.parse_args()
+
+    source = open_input(args.input, args.baud)
+    out = open_output(args.output)
+    try:
+        convert(source, out, tap=not args.no_tap)
+    except KeyboardInterrupt:
+        pass
+
+
+if __name__ == "__main__":
+    main()
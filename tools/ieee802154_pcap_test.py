@@ -0,0 +1,102 @@
+#!/usr/bin/env python3
+
+# Licensed under the Apache License, Version 2.0 or the MIT License.
+# SPDX-License-Identifier: Apache-2.0 OR MIT
+# Copyright Tock Contributors 2024.
+
+"""
+Tests for ieee802154_pcap.py. Run with `python3 tools/ieee802154_pcap_test.py`.
+
+Records are built byte by byte the way `Sniffer::enqueue_record` in
+capsules/extra/src/ieee802154/sniffer.rs builds them.
+"""
+
+import io
+import struct
+import unittest
+
+import ieee802154_pcap as pcap
+
+
+def sniffer_record(
+    frame, timestamp, frequency=32768, lqi=0, rssi=None, crc_valid=True, dropped=0, width=24
+):
+    header = bytearray(19)
+    header[0:2] = b"\xc5\x15"
+    header[2] = 1
+    header[3] = (1 if crc_valid else 0) | (2 if rssi is not None else 0)
+    header[4:6] = len(frame).to_bytes(2, "little")
+    header[6:10] = timestamp.to_bytes(4, "little")
+    header[10:14] = frequency.to_bytes(4, "little")
+    header[14] = lqi
+    header[15] = (rssi or 0) & 0xFF
+    header[16:18] = dropped.to_bytes(2, "little")
+    header[18] = width
+    return bytes(header) + frame
+
+
+def read_pcap(data):
+    magic, _, _, _, _, _, linktype = struct.unpack_from("<IHHiIII", data)
+    assert magic == 0xA1B2C3D4
+    packets = []
+    pos = 24
+    while pos < len(data):
+        sec, usec, incl_len, _ = struct.unpack_from("<IIII", data, pos)
+        pos += 16
+        packets.append((sec + usec / 1000000, data[pos : pos + incl_len]))
+        pos += incl_len
+    return linktype, packets
+
+
+def convert(stream, tap):
+    out = io.BytesIO()
+    pcap.convert(io.BytesIO(stream), out, tap=tap)
+    return read_pcap(out.getvalue())
+
+
+class RecordTest(unittest.TestCase):
+    def test_header_layout(self):
+        self.assertEqual(pcap.RECORD_HEADER.size, 19)
+
+    def test_round_trip(self):
+        frames = [bytes(range(10)), b"\x41\x88" + bytes(125), b""]
+        stream = b"".join(
+            sniffer_record(frame, timestamp=1000 + 16384 * i, lqi=200 + i)
+            for i, frame in enumerate(frames)
+        )
+        linktype, packets = convert(stream, tap=False)
+        self.assertEqual(linktype, pcap.LINKTYPE_IEEE802_15_4_NOFCS)
+        self.assertEqual([frame for _, frame in packets], frames)
+        self.assertEqual([time for time, _ in packets], [0.0, 0.5, 1.0])
+
+    def test_fields(self):
+        stream = sniffer_record(b"abc", timestamp=7, lqi=42, rssi=-60, dropped=3)
+        fields, frame = next(pcap.records(io.BytesIO(stream)))
+        _, version, flags, length, ticks, frequency, lqi, rssi, dropped, width = fields
+        self.assertEqual(frame, b"abc")
+        self.assertEqual((version, flags, length), (1, 3, 3))
+        self.assertEqual((ticks, frequency, width), (7, 32768, 24))
+        self.assertEqual((lqi, rssi, dropped), (42, -60, 3))
+
+    def test_tap_metadata(self):
+        _, packets = convert(sniffer_record(b"xyz", timestamp=0, lqi=99), tap=True)
+        (_, frame) = packets[0]
+        self.assertEqual(frame[-3:], b"xyz")
+        self.assertEqual(frame[:-3], pcap.tap_header(99, None))
+
+    def test_timestamp_wrap(self):
+        width = 24
+        stream = sniffer_record(b"a", timestamp=(1 << width) - 32768, width=width)
+        stream += sniffer_record(b"b", timestamp=16384, width=width)
+        _, packets = convert(stream, tap=False)
+        self.assertEqual([time for time, _ in packets], [0.0, 1.5])
+
+    def test_resynchronizes_after_garbage(self):
+        stream = b"\x00\xc5\x15\x07" + sniffer_record(b"ok", timestamp=0)
+        stream += sniffer_record(b"ok", timestamp=0)[:10]
+        _, packets = convert(stream, tap=False)
+        self.assertEqual([frame for _, frame in packets], [b"ok"])
+
+
+if __name__ == "__main__":
+    unittest.main()